@@ -0,0 +1,69 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+// 
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+// 
+// Author: tickbh
+// -----
+// Created Date: 2024/02/27 10:35:02
+
+// 对比同步写入与缓冲写入访问日志的吞吐量
+// cargo run --release --example log_bench
+
+use std::time::{Duration, Instant};
+
+use log::{Level, Log, Record};
+use log4rs::{append::{file::FileAppender, Append}, encode::pattern::PatternEncoder};
+use wmproxy::log::BufferAppender;
+
+const LINES: usize = 200_000;
+
+fn bench(name: &str, appender: Box<dyn Append>) {
+    let now = Instant::now();
+    for i in 0..LINES {
+        let _ = appender.append(
+            &Record::builder()
+                .level(Level::Info)
+                .args(format_args!("127.0.0.1 GET /index.html?id={} status: 200", i))
+                .build(),
+        );
+    }
+    let enqueue = now.elapsed();
+    drop(appender);
+    println!(
+        "{}: 写入{}行, 请求线程耗时{:?}, 全部落盘耗时{:?}",
+        name,
+        LINES,
+        enqueue,
+        now.elapsed()
+    );
+}
+
+fn main() {
+    let dir = std::env::temp_dir();
+    let sync_path = dir.join("wmproxy_bench_sync.log");
+    let buffer_path = dir.join("wmproxy_bench_buffer.log");
+    let _ = std::fs::remove_file(&sync_path);
+    let _ = std::fs::remove_file(&buffer_path);
+
+    let sync = FileAppender::builder()
+        .encoder(Box::new(PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} {m}{n}")))
+        .build(&sync_path)
+        .unwrap();
+    bench("同步写入", Box::new(sync));
+
+    let buffer = BufferAppender::new(
+        &buffer_path,
+        Box::new(PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} {m}{n}")),
+        64 * 1024,
+        Duration::from_secs(1),
+    )
+    .unwrap();
+    bench("缓冲写入", Box::new(buffer));
+
+    let _ = std::fs::remove_file(&sync_path);
+    let _ = std::fs::remove_file(&buffer_path);
+}
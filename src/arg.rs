@@ -26,10 +26,10 @@ use wenmeng::Client;
 use crate::{
     option::proxy_config,
     reverse::{HttpConfig, LocationConfig, ServerConfig, UpstreamConfig},
-    ConfigHeader, ConfigLog, ConfigOption, FileServer, ProxyConfig, ProxyResult,
+    ConfigBasicAuth, ConfigHeader, ConfigLog, ConfigOption, FileServer, ProxyConfig, ProxyResult,
 };
 use crate::{reverse::StreamConfig, WrapVecAddr};
-use crate::{ConfigDuration, WrapAddr};
+use crate::{ConfigDuration, SelfSigned, WrapAddr};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -38,12 +38,18 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 struct Shared {
     /// 输入控制台的监听地址
     #[bpaf(
-        fallback(WrapAddr(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8837))),
+        fallback(WrapAddr::new(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8837))),
         display_fallback
     )]
     pub(crate) control: WrapAddr,
     /// 禁用默认输出
     pub(crate) disable_stdout: bool,
+    /// 主日志写入的文件, 可附带`buffer_size=64k flush_interval=1s`
+    #[bpaf(long)]
+    pub(crate) log_file: Option<String>,
+    /// 安静模式, 仅输出错误日志
+    #[bpaf(long)]
+    pub(crate) quiet: bool,
     /// 禁用控制微端
     pub(crate) disable_control: bool,
     /// 后台运行
@@ -63,17 +69,20 @@ struct Shared {
 #[derive(Debug, Clone, Bpaf)]
 #[allow(dead_code)]
 struct RunConfig {
-    /// 配置文件路径
-    #[bpaf(short, long)]
-    pub(crate) config: String,
+    /// 配置文件路径, 可多次指定, 按顺序合并, 后面的覆盖前面的
+    #[bpaf(short, long, argument("CONFIG"), some("需要配置文件路径"))]
+    pub(crate) config: Vec<String>,
 }
 
 #[derive(Debug, Clone, Bpaf)]
 #[allow(dead_code)]
 struct CheckConfig {
-    /// 配置文件路径
-    #[bpaf(short, long)]
-    pub(crate) config: String,
+    /// 配置文件路径, 可多次指定, 按顺序合并, 后面的覆盖前面的
+    #[bpaf(short, long, argument("CONFIG"), some("需要配置文件路径"))]
+    pub(crate) config: Vec<String>,
+    /// 输出合并后的配置
+    #[bpaf(long)]
+    pub(crate) dump: bool,
 }
 
 #[derive(Debug, Clone, Bpaf)]
@@ -100,6 +109,18 @@ struct ReloadConfig {
     pub(crate) url: Option<String>,
 }
 
+#[derive(Debug, Clone, Bpaf)]
+#[allow(dead_code)]
+struct ReopenConfig {
+    /// 配置文件路径
+    #[bpaf(short, long)]
+    pub(crate) config: Option<String>,
+
+    /// 控制微端地址
+    #[bpaf(short, long)]
+    pub(crate) url: Option<String>,
+}
+
 #[derive(Debug, Clone, Bpaf)]
 #[allow(dead_code)]
 struct FileServerConfig {
@@ -109,7 +130,7 @@ struct FileServerConfig {
     #[bpaf(
         short,
         long,
-        fallback(WrapVecAddr(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8869)])),
+        fallback(WrapVecAddr::new(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8869)])),
         display_fallback
     )]
     /// 监听地址
@@ -121,12 +142,18 @@ struct FileServerConfig {
     pub cert: Option<String>,
     /// ssl证书key
     pub key: Option<String>,
+    /// 自动生成自签名证书, 仅用于本地测试
+    #[bpaf(long)]
+    pub(crate) self_signed: bool,
     /// 域名地址
     #[bpaf(short, long)]
     pub(crate) domain: Option<String>,
     /// 是否支持目录
     #[bpaf(short, long)]
     pub(crate) browse: bool,
+    /// 访问目录时依次查找的index文件, 可指定多个, 默认为index.html及index.htm
+    #[bpaf(long)]
+    pub(crate) index: Vec<String>,
     /// 设置robots.txt返回
     #[bpaf(long)]
     pub(crate) robots: Option<String>,
@@ -142,6 +169,24 @@ struct FileServerConfig {
     /// 通过"Access-Control-Allow-Origin"标头启用 CORS
     #[bpaf(long, fallback(false))]
     pub(crate) cors: bool,
+    /// 存在.gz/.br预压缩文件且客户端支持时直接返回预压缩文件
+    #[bpaf(long, fallback(false))]
+    pub(crate) try_precompressed: bool,
+    /// 访问需通过Basic Auth验证, 如"user:pass", 可指定多个
+    #[bpaf(long)]
+    pub(crate) auth: Vec<ConfigBasicAuth>,
+    /// Basic Auth验证失败时返回的realm
+    #[bpaf(long)]
+    pub(crate) auth_realm: Option<String>,
+    /// 允许访问以.开头的文件及目录, 默认返回404
+    #[bpaf(long)]
+    pub(crate) show_dotfiles: bool,
+    /// 禁止访问的路径, 支持*通配, 如"*.bak"或"/private/*", 可指定多个
+    #[bpaf(long)]
+    pub(crate) deny_patterns: Vec<String>,
+    /// 允许访问root之下的符号链接, 链接的目标仍需在root之内
+    #[bpaf(long)]
+    pub(crate) follow_symlinks: bool,
     /// 头部信息修改如 "proxy x-forward-for {client_ip}"
     #[bpaf(short('H'), long)]
     pub(crate) header: Vec<ConfigHeader>,
@@ -157,7 +202,7 @@ struct ReverseProxyConfig {
     #[bpaf(
         short,
         long,
-        fallback(WrapVecAddr(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8869)])),
+        fallback(WrapVecAddr::new(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8869)])),
         display_fallback
     )]
     pub(crate) from: WrapVecAddr,
@@ -185,7 +230,7 @@ struct WsProxyConfig {
     #[bpaf(
         short,
         long,
-        fallback(WrapVecAddr(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8869)])),
+        fallback(WrapVecAddr::new(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8869)])),
         display_fallback
     )]
     pub(crate) from: WrapVecAddr,
@@ -217,6 +262,7 @@ enum Command {
     Run(RunConfig),
     Stop(StopConfig),
     Reload(ReloadConfig),
+    Reopen(ReopenConfig),
     Check(CheckConfig),
     FileServer(FileServerConfig),
     ReverseProxy(ReverseProxyConfig),
@@ -249,6 +295,12 @@ fn parse_command() -> impl Parser<(Command, Shared)> {
         .command("reload")
         .help("进行重载配置");
 
+    let reopen = reopen_config().map(Command::Reopen);
+    let reopen = construct!(reopen, shared())
+        .to_options()
+        .command("reopen")
+        .help("重新打开日志文件, 用于日志切割后");
+
     let action = proxy_config().map(Command::Proxy);
     let action = construct!(action, shared())
         .to_options()
@@ -283,6 +335,7 @@ fn parse_command() -> impl Parser<(Command, Shared)> {
         run,
         stop,
         reload,
+        reopen,
         check,
         file_config,
         reverse_config,
@@ -291,7 +344,66 @@ fn parse_command() -> impl Parser<(Command, Shared)> {
     ])
 }
 
-fn read_config_from_path(path: &String) -> ProxyResult<ConfigOption> {
+/// 按顺序读取多个配置文件并合并, 合并规则:
+/// * 表(如`[http]`)按键递归合并
+/// * 列表(如`[[http.server]]`)将后面文件的元素追加到末尾
+/// * 其它的值及类型不一致时, 后面文件的值覆盖前面的
+fn read_config_from_paths(paths: &[String]) -> io::Result<ConfigOption> {
+    if paths.len() == 1 {
+        return read_config_from_path(&paths[0]);
+    }
+    let mut value = serde_json::Value::Object(Default::default());
+    for path in paths {
+        merge_config_value(&mut value, read_config_value(path)?);
+    }
+    let option = serde_json::from_value::<ConfigOption>(value).map_err(|e| {
+        println!("合并配置文件错误: {}", e);
+        io::Error::other("merge config error")
+    })?;
+    Ok(option)
+}
+
+fn read_config_value(path: &String) -> io::Result<serde_json::Value> {
+    let path = PathBuf::from(path);
+    let mut file = File::open(&path)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let extension = path.extension().unwrap_or_default().to_string_lossy().to_string();
+    let value = match &*extension {
+        "yaml" => serde_yaml::from_str::<serde_json::Value>(&contents).map_err(|e| {
+            println!("解析文件错误: {}", e);
+            io::Error::other("parse yaml error")
+        })?,
+        "toml" => toml::from_str::<serde_json::Value>(&contents).map_err(|e| {
+            println!("解析文件错误: {}", e);
+            io::Error::other("parse toml error")
+        })?,
+        _ => {
+            return Err(io::Error::other("unknow format error"));
+        }
+    };
+    Ok(value)
+}
+
+fn merge_config_value(base: &mut serde_json::Value, value: serde_json::Value) {
+    use serde_json::Value;
+    match (base, value) {
+        (Value::Object(base), Value::Object(value)) => {
+            for (k, v) in value {
+                match base.get_mut(&k) {
+                    Some(b) => merge_config_value(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(value)) => base.extend(value),
+        (base, value) => *base = value,
+    }
+}
+
+fn read_config_from_path(path: &String) -> io::Result<ConfigOption> {
     let path = PathBuf::from(path);
     let mut file = File::open(&path)?;
     let mut contents = String::new();
@@ -300,15 +412,14 @@ fn read_config_from_path(path: &String) -> ProxyResult<ConfigOption> {
     let option = match &*extension {
         "yaml" => serde_yaml::from_str::<ConfigOption>(&contents).map_err(|e| {
             println!("解析文件错误: {}", e);
-            io::Error::new(io::ErrorKind::Other, "parse yaml error")
+            io::Error::other("parse yaml error")
         })?,
         "toml" => toml::from_str::<ConfigOption>(&contents).map_err(|e| {
             println!("解析文件错误: {}", e);
-            io::Error::new(io::ErrorKind::Other, "parse toml error")
+            io::Error::other("parse toml error")
         })?,
         _ => {
-            let e = io::Error::new(io::ErrorKind::Other, "unknow format error");
-            return Err(e.into());
+            return Err(io::Error::other("unknow format error"));
         }
     };
     Ok(option)
@@ -381,6 +492,8 @@ pub async fn parse_env() -> ProxyResult<ConfigOption> {
     option.default_level = shared.default_level;
     option.disable_control = shared.disable_control;
     option.disable_stdout = shared.disable_stdout;
+    option.log_file = shared.log_file.clone();
+    option.quiet = shared.quiet;
     option.pidfile = shared.pidfile.clone();
     option.control = shared.control.0;
     if shared.verbose {
@@ -392,8 +505,13 @@ pub async fn parse_env() -> ProxyResult<ConfigOption> {
             option.after_load_option()?;
             return Ok(option);
         }
-        Command::Check(config) => match read_config_from_path(&config.config) {
-            Ok(_) => {
+        Command::Check(config) => match read_config_from_paths(&config.config)
+            .and_then(|o| o.check_log_files().map(|_| o))
+        {
+            Ok(o) => {
+                if config.dump {
+                    println!("{}", serde_json::to_string_pretty(&o).unwrap_or_default());
+                }
                 println!("配置文件正确");
                 exit(0);
             }
@@ -403,10 +521,17 @@ pub async fn parse_env() -> ProxyResult<ConfigOption> {
             }
         },
         Command::Run(config) => {
-            let mut option = read_config_from_path(&config.config)?;
+            let mut option = read_config_from_paths(&config.config)?;
             if shared.verbose {
                 option.default_level = Some(LevelFilter::Trace);
             }
+            // 命令行的日志参数优先于配置文件
+            if shared.log_file.is_some() {
+                option.log_file = shared.log_file.clone();
+            }
+            if shared.quiet {
+                option.quiet = true;
+            }
             option.after_load_option()?;
             return Ok(option);
         }
@@ -469,21 +594,51 @@ pub async fn parse_env() -> ProxyResult<ConfigOption> {
             }
             exit(0);
         }
+        Command::Reopen(config) => {
+            let url = if let Some(config) = config.config {
+                let option = read_config_from_path(&config)?;
+                format!("http://{}", option.control)
+            } else if let Some(url) = config.url {
+                url
+            } else {
+                println!("必须传入参数config或者url之一");
+                exit(0);
+            };
+
+            let mut url = Url::parse(url.into_bytes())?;
+            url.path = "/reopen-logs".to_string();
+
+            let req = Request::builder().method("GET").url(url.clone()).body("")?;
+            let client = Client::builder().http2(false).url(url)?.connect().await?;
+
+            let (mut recv, _sender) = client.send2(req.into_type()).await?;
+            let res = recv.recv().await.unwrap()?;
+            if res.status() == 200 {
+                println!("重新打开日志成功!");
+            } else {
+                println!("重新打开日志失败: 微端响应:{}!", res.status());
+            }
+            exit(0);
+        }
         Command::FileServer(file) => {
             let mut http = HttpConfig::new();
             let mut server = ServerConfig::new(file.listen.clone());
             if file.listen_ssl.is_some() {
                 server.bind_ssl = file.listen_ssl.unwrap();
-                if file.cert.is_none() || file.key.is_none() {
-                    println!("配置ssl监听但未配置证书");
-                    exit(0);
+                if file.self_signed {
+                    server.cert = Some(SelfSigned::AUTO.to_string());
+                } else {
+                    if file.cert.is_none() || file.key.is_none() {
+                        println!("配置ssl监听但未配置证书");
+                        exit(0);
+                    }
+                    // if file.domain.is_none() {
+                    //     println!("配置ssl监听未配置域名");
+                    //     exit(0);
+                    // }
+                    server.cert = file.cert;
+                    server.key = file.key;
                 }
-                // if file.domain.is_none() {
-                //     println!("配置ssl监听未配置域名");
-                //     exit(0);
-                // }
-                server.cert = file.cert;
-                server.key = file.key;
                 server.comm.domain = file.domain;
             }
             
@@ -492,6 +647,15 @@ pub async fn parse_env() -> ProxyResult<ConfigOption> {
             file_server.robots = file.robots;
             file_server.cache_time = file.cache_time;
             file_server.cors = file.cors;
+            file_server.try_precompressed = file.try_precompressed;
+            file_server.auth = file.auth;
+            file_server.auth_realm = file.auth_realm;
+            file_server.hide_dotfiles = !file.show_dotfiles;
+            file_server.deny_patterns = file.deny_patterns;
+            file_server.follow_symlinks = file.follow_symlinks;
+            if !file.index.is_empty() {
+                file_server.index = file.index;
+            }
             file_server.path404 = file.path404;
             location.headers = file.header;
             location.file_server = Some(file_server);
@@ -576,3 +740,53 @@ pub async fn parse_env() -> ProxyResult<ConfigOption> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::read_config_from_paths;
+
+    #[test]
+    fn merge_config_files() {
+        let dir = std::env::temp_dir();
+        let base = dir.join(format!("wmproxy_base_{}.toml", std::process::id()));
+        let over = dir.join(format!("wmproxy_prod_{}.yaml", std::process::id()));
+        std::fs::write(
+            &base,
+            r#"
+control = "127.0.0.1:8837"
+quiet = true
+
+[http]
+[[http.server]]
+bind_addr = "127.0.0.1:19180"
+bind_ssl = ""
+up_name = "base"
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            &over,
+            r#"
+control: "127.0.0.1:9837"
+http:
+  server:
+    - bind_addr: "127.0.0.1:19181"
+      bind_ssl: ""
+      up_name: "prod"
+"#,
+        )
+        .unwrap();
+        let paths = vec![base.display().to_string(), over.display().to_string()];
+        let option = read_config_from_paths(&paths);
+        let _ = std::fs::remove_file(&base);
+        let _ = std::fs::remove_file(&over);
+        let option = option.unwrap();
+        // 后面的值覆盖前面的, 未配置的值保留
+        assert_eq!(option.control, "127.0.0.1:9837".parse().unwrap());
+        assert!(option.quiet);
+        // 列表追加到末尾
+        let http = option.http.unwrap();
+        let names = http.server.iter().map(|s| s.up_name.clone()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["base".to_string(), "prod".to_string()]);
+    }
+}
@@ -15,12 +15,15 @@ use std::{
     io,
     net::{SocketAddr, ToSocketAddrs},
     sync::RwLock,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use lazy_static::lazy_static;
+use serde::Serialize;
 use tokio::net::TcpStream;
 
+use crate::{ConfigUpstreamProxy, NetInterface, SocketBuffer, TcpFastOpen};
+
 lazy_static! {
     static ref HEALTH_CHECK: RwLock<HealthCheck> = RwLock::new(HealthCheck::new(60, 3, 2));
 }
@@ -39,6 +42,47 @@ struct HealthRecord {
     rise_times: usize,
     /// 当前的状态
     failed: bool,
+    /// 最后一次记录结果的时间
+    last_check: Option<SystemTime>,
+    /// 最后一次状态变化的时间
+    last_change: Option<SystemTime>,
+    /// 正在使用该地址的连接数
+    in_flight: usize,
+}
+
+/// 上游地址的健康状态, 用于控制端展示
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthStatus {
+    /// 当前的状态, up|down|half-open
+    pub state: &'static str,
+    /// 正在处理的连接数, 流转发为连接数, http为等待返回头的请求数
+    pub in_flight: usize,
+    /// 当前连续失败的次数
+    pub fails: usize,
+    /// 最后一次记录结果的时间戳(秒)
+    pub last_check: Option<u64>,
+    /// 最后一次状态变化的时间戳(秒)
+    pub last_change: Option<u64>,
+}
+
+/// 占用上游地址的连接数, 释放时归还
+pub struct InFlightGuard {
+    addr: SocketAddr,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Ok(mut h) = HEALTH_CHECK.write() {
+            if let Some(value) = h.health_map.get_mut(&self.addr) {
+                value.in_flight = value.in_flight.saturating_sub(1);
+            }
+        }
+    }
+}
+
+fn timestamp(time: &Option<SystemTime>) -> Option<u64> {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
 }
 
 impl HealthRecord {
@@ -50,13 +94,24 @@ impl HealthRecord {
             fall_times: 0,
             rise_times: 0,
             failed: false,
+            last_check: None,
+            last_change: None,
+            in_flight: 0,
+        }
+    }
+
+    /// 修改状态, 变化时记录时间
+    fn set_failed(&mut self, failed: bool) {
+        if self.failed != failed {
+            self.failed = failed;
+            self.last_change = Some(SystemTime::now());
         }
     }
 
     pub fn clear_status(&mut self) {
         self.fall_times = 0;
         self.rise_times = 0;
-        self.failed = false;
+        self.set_failed(false);
     }
 }
 
@@ -157,6 +212,7 @@ impl HealthCheck {
             if !h.health_map.contains_key(&addr) {
                 let mut health = HealthRecord::new(h.fail_timeout);
                 health.fall_times = 1;
+                health.last_check = Some(SystemTime::now());
                 h.health_map.insert(addr, health);
             } else {
                 let max_fails = h.max_fails;
@@ -166,11 +222,12 @@ impl HealthCheck {
                     value.clear_status();
                 }
                 value.last_record = Instant::now();
+                value.last_check = Some(SystemTime::now());
                 value.fall_times += 1;
                 value.rise_times = 0;
 
                 if value.fall_times >= max_fails {
-                    value.failed = true;
+                    value.set_failed(true);
                 }
             }
         }
@@ -183,6 +240,7 @@ impl HealthCheck {
             if !h.health_map.contains_key(&addr) {
                 let mut health = HealthRecord::new(h.fail_timeout);
                 health.rise_times = 1;
+                health.last_check = Some(SystemTime::now());
                 h.health_map.insert(addr, health);
             } else {
                 let min_rises = h.min_rises;
@@ -192,21 +250,71 @@ impl HealthCheck {
                     value.clear_status();
                 }
                 value.last_record = Instant::now();
+                value.last_check = Some(SystemTime::now());
                 value.rise_times += 1;
                 value.fall_times = 0;
 
                 if value.rise_times >= min_rises {
-                    value.failed = false;
+                    value.set_failed(false);
                 }
             }
         }
     }
 
+    /// 记录正在使用该地址的连接, 返回的句柄需跟随连接的生命周期
+    pub fn track(addr: SocketAddr) -> InFlightGuard {
+        if let Ok(mut h) = HEALTH_CHECK.write() {
+            let fail_timeout = h.fail_timeout;
+            h.health_map
+                .entry(addr)
+                .or_insert_with(|| HealthRecord::new(fail_timeout))
+                .in_flight += 1;
+        }
+        InFlightGuard { addr }
+    }
+
+    /// 获取地址的健康状态, 判定规则同`check_fall_down`
+    /// 失败超过恢复时间后将重新尝试, 或者已有成功但未达到上线次数时为half-open
+    pub fn status(addr: &SocketAddr, fail_timeout: &Duration, fall_times: &usize, rise_times: &usize) -> HealthStatus {
+        let mut status = HealthStatus {
+            state: "up",
+            in_flight: 0,
+            fails: 0,
+            last_check: None,
+            last_change: None,
+        };
+        let h = match HEALTH_CHECK.read() {
+            Ok(h) => h,
+            Err(_) => return status,
+        };
+        let value = match h.health_map.get(addr) {
+            Some(value) => value,
+            None => return status,
+        };
+        status.in_flight = value.in_flight;
+        status.fails = value.fall_times;
+        status.last_check = timestamp(&value.last_check);
+        status.last_change = timestamp(&value.last_change);
+        let down = &value.fall_times >= fall_times || value.failed;
+        status.state = if !down || &value.rise_times >= rise_times {
+            "up"
+        } else if Instant::now().duration_since(value.last_record) > *fail_timeout || value.rise_times > 0 {
+            "half-open"
+        } else {
+            "down"
+        };
+        status
+    }
+
     // 将TcpStream::connect函数替换成这个函数，将自动启用被动健康检查
     pub async fn connect<A>(addr: &A) -> io::Result<TcpStream>
     where
         A: ToSocketAddrs,
     {
+        // 配置了收发缓冲区时需在connect之前设置
+        if SocketBuffer::is_enable() {
+            return Self::connect_socket(addr, None).await;
+        }
         let addrs = addr.to_socket_addrs()?;
         let mut last_err = None;
 
@@ -242,19 +350,202 @@ impl HealthCheck {
         }))
     }
 
-    
+
+    /// 以客户端的源地址与远端建立连接(透明代理), 需要CAP_NET_ADMIN权限
+    /// 并且需要配置相应的策略路由, 使远端的回包能回到本机, 如:
+    /// ```text
+    /// iptables -t mangle -A PREROUTING -p tcp -s <upstream> --sport <port> -j MARK --set-mark 1
+    /// ip rule add fwmark 1 lookup 100
+    /// ip route add local 0.0.0.0/0 dev lo table 100
+    /// ```
+    pub async fn connect_transparent(
+        addr: &SocketAddr,
+        source: SocketAddr,
+        connect: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        match connect {
+            None => Self::connect_transparent_inner(addr, source).await,
+            Some(connect) => match tokio::time::timeout(connect, Self::connect_transparent_inner(addr, source)).await {
+                Ok(s) => s,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timeout")),
+            },
+        }
+    }
+
+    #[cfg(all(feature = "tproxy", target_os = "linux"))]
+    async fn connect_transparent_inner(addr: &SocketAddr, source: SocketAddr) -> io::Result<TcpStream> {
+        use socket2::{Domain, Socket, Type};
+        if Self::is_fall_down(addr) {
+            return Err(io::Error::other("health check falldown"));
+        }
+        if addr.is_ipv4() != source.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "transparent source and upstream address family not match",
+            ));
+        }
+        let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+        socket.set_ip_transparent(true).map_err(|e| {
+            log::error!("设置IP_TRANSPARENT失败, 透明代理需要CAP_NET_ADMIN权限: {:?}", e);
+            io::Error::new(e.kind(), format!("set IP_TRANSPARENT failed: {}", e))
+        })?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::new(source.ip(), 0).into())?;
+        SocketBuffer::apply(&socket);
+        let socket = tokio::net::TcpSocket::from_std_stream(socket.into());
+        log::trace!("尝试以源地址{source}与远端{addr}建立透明连接");
+        match socket.connect(*addr).await {
+            Ok(stream) => {
+                Self::add_rise_up(*addr);
+                Ok(stream)
+            }
+            Err(e) => {
+                log::trace!("与远端{addr}建立透明连接失败, 原因: {:?}", e);
+                Self::add_fall_down(*addr);
+                Err(e)
+            }
+        }
+    }
+
+    #[cfg(not(all(feature = "tproxy", target_os = "linux")))]
+    async fn connect_transparent_inner(_addr: &SocketAddr, _source: SocketAddr) -> io::Result<TcpStream> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "transparent proxy need linux and feature `tproxy`",
+        ))
+    }
+
+    /// 以指定的本地地址或网卡与远端建立连接, `local_bind`为None且未开启TFO时与`connect`一致
+    /// 配置了SO_RCVBUF/SO_SNDBUF时同样通过socket2建立连接
+    pub async fn connect_bind<A>(addr: &A, local_bind: Option<&str>) -> io::Result<TcpStream>
+    where
+        A: ToSocketAddrs,
+    {
+        if local_bind.is_none() && !TcpFastOpen::is_connect_enable() {
+            return Self::connect(addr).await;
+        }
+        Self::connect_socket(addr, local_bind).await
+    }
+
+    /// 通过socket2建立连接, 在connect之前设置本地绑定及socket选项
+    async fn connect_socket<A>(addr: &A, local_bind: Option<&str>) -> io::Result<TcpStream>
+    where
+        A: ToSocketAddrs,
+    {
+        use socket2::{Domain, Socket, Type};
+        let addrs = addr.to_socket_addrs()?;
+        let mut last_err = None;
+        for addr in addrs {
+            if Self::is_fall_down(&addr) {
+                last_err = Some(io::Error::other("health check falldown"));
+                continue;
+            }
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+            socket.set_nonblocking(true)?;
+            if let Some(local_bind) = local_bind {
+                let (local, device) = NetInterface::local_bind(local_bind, &addr);
+                if let Some(name) = &device {
+                    NetInterface::bind_device(&socket, name)?;
+                }
+                socket.bind(&local.into())?;
+            }
+            TcpFastOpen::apply_connect(&socket);
+            SocketBuffer::apply(&socket);
+            let socket = tokio::net::TcpSocket::from_std_stream(socket.into());
+            log::trace!("尝试以本地{:?}与远端{addr}建立连接", local_bind);
+            match socket.connect(addr).await {
+                Ok(stream) => {
+                    Self::add_rise_up(addr);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    log::trace!("与远端{addr}建立连接失败, 原因: {:?}", e);
+                    Self::add_fall_down(addr);
+                    last_err = Some(e)
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any address",
+            )
+        }))
+    }
+
     // 将TcpStream::connect函数替换成这个函数，将自动启用被动健康检查
     pub async fn connect_timeout<A>(addr: &A, connect: Option<Duration>) -> io::Result<TcpStream>
     where
         A: ToSocketAddrs,
     {
-        if connect.is_none() {
-            HealthCheck::connect(addr).await
-        } else {
-            match tokio::time::timeout(connect.unwrap(), HealthCheck::connect(addr)).await {
+        Self::connect_timeout_bind(addr, connect, None).await
+    }
+
+    /// 连接上游, 配置了出口代理时经出口代理建立隧道, 否则与`connect_timeout_bind`一致
+    pub async fn connect_upstream(
+        addr: &str,
+        connect: Option<Duration>,
+        local_bind: Option<&str>,
+        upstream_proxy: Option<&ConfigUpstreamProxy>,
+    ) -> io::Result<TcpStream> {
+        match upstream_proxy {
+            Some(proxy) => proxy.connect(addr, connect, local_bind).await,
+            None => HealthCheck::connect_timeout_bind(&addr, connect, local_bind).await,
+        }
+    }
+
+    pub async fn connect_timeout_bind<A>(
+        addr: &A,
+        connect: Option<Duration>,
+        local_bind: Option<&str>,
+    ) -> io::Result<TcpStream>
+    where
+        A: ToSocketAddrs,
+    {
+        if let Some(connect) = connect {
+            match tokio::time::timeout(connect, HealthCheck::connect_bind(addr, local_bind)).await {
                 Ok(s) => s,
-                Err(_) => return Err(io::Error::new(io::ErrorKind::NotConnected, "timeout")),
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timeout")),
             }
+        } else {
+            HealthCheck::connect_bind(addr, local_bind).await
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use super::HealthCheck;
+
+    #[test]
+    fn status_transition() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let timeout = Duration::from_secs(30);
+        let status = HealthCheck::status(&addr, &timeout, &2, &2);
+        assert_eq!(status.state, "up");
+        assert!(status.last_check.is_none());
+
+        let guard = HealthCheck::track(addr);
+        assert_eq!(HealthCheck::status(&addr, &timeout, &2, &2).in_flight, 1);
+        drop(guard);
+        assert_eq!(HealthCheck::status(&addr, &timeout, &2, &2).in_flight, 0);
+
+        for _ in 0..3 {
+            HealthCheck::add_fall_down(addr);
+        }
+        let status = HealthCheck::status(&addr, &timeout, &2, &2);
+        assert_eq!(status.state, "down");
+        assert_eq!(status.fails, 3);
+        assert!(status.last_check.is_some() && status.last_change.is_some());
+        // 超过恢复时间后重新尝试
+        assert_eq!(HealthCheck::status(&addr, &Duration::ZERO, &2, &2).state, "half-open");
+
+        HealthCheck::add_rise_up(addr);
+        assert_eq!(HealthCheck::status(&addr, &timeout, &2, &2).state, "half-open");
+        HealthCheck::add_rise_up(addr);
+        assert_eq!(HealthCheck::status(&addr, &timeout, &2, &2).state, "up");
+    }
+}
@@ -14,5 +14,5 @@
 mod health;
 mod active;
 
-pub use health::HealthCheck;
+pub use health::{HealthCheck, HealthStatus, InFlightGuard};
 pub use active::{ActiveHealth, OneHealth};
\ No newline at end of file
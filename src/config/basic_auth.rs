@@ -0,0 +1,103 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/27 10:12:45
+
+use std::{fmt::Display, io, str::FromStr};
+
+use base64::{engine::general_purpose, Engine};
+
+/// Basic Auth的账号密码, 格式为`user:pass`, 密码中可含`:`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigBasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl ConfigBasicAuth {
+    /// 检查`Authorization`头是否与其中一组账号密码相符, 比较耗时与内容无关, 避免按耗时猜测密码
+    pub fn check(list: &[ConfigBasicAuth], value: Option<&str>) -> bool {
+        let Some(value) = value else {
+            return false;
+        };
+        let Some((scheme, token)) = value.trim().split_once(' ') else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return false;
+        }
+        let Ok(data) = general_purpose::STANDARD.decode(token.trim()) else {
+            return false;
+        };
+        // 不提前结束, 与每一组均做比较
+        let mut matched = false;
+        for auth in list {
+            let expect = format!("{}:{}", auth.username, auth.password);
+            matched |= Self::constant_eq(&data, expect.as_bytes());
+        }
+        matched
+    }
+
+    /// 耗时只与长度相关的比较
+    pub(crate) fn constant_eq(a: &[u8], b: &[u8]) -> bool {
+        if a.len() != b.len() {
+            return false;
+        }
+        a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+}
+
+impl FromStr for ConfigBasicAuth {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((username, password)) if !username.is_empty() => Ok(ConfigBasicAuth {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("错误的账号密码配置, 应为user:pass:{}", s),
+            )),
+        }
+    }
+}
+
+impl Display for ConfigBasicAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}:{}", self.username, self.password))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigBasicAuth;
+
+    #[test]
+    fn check_basic_auth() {
+        let list = vec![
+            "wmproxy:wmproxy".parse::<ConfigBasicAuth>().unwrap(),
+            "admin:a:b".parse::<ConfigBasicAuth>().unwrap(),
+        ];
+        assert_eq!(list[1].password, "a:b");
+        assert_eq!(list[1].to_string(), "admin:a:b");
+        assert!(":pass".parse::<ConfigBasicAuth>().is_err());
+        assert!("user".parse::<ConfigBasicAuth>().is_err());
+
+        // wmproxy:wmproxy, admin:a:b
+        assert!(ConfigBasicAuth::check(&list, Some("Basic d21wcm94eTp3bXByb3h5")));
+        assert!(ConfigBasicAuth::check(&list, Some("basic YWRtaW46YTpi")));
+        // wmproxy:wrong
+        assert!(!ConfigBasicAuth::check(&list, Some("Basic d21wcm94eTp3cm9uZw==")));
+        assert!(!ConfigBasicAuth::check(&list, Some("Bearer d21wcm94eTp3bXByb3h5")));
+        assert!(!ConfigBasicAuth::check(&list, None));
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/06 09:32:17
+
+use std::{fmt::Display, io, str::FromStr};
+
+use crate::ConfigSize;
+
+/// 请求body的预读缓存, 缓存后的body可用于重试其它上游及带body的流量复制
+///
+/// 配置格式为`size=1m memory=64k over=pass`, size为最多缓存的大小, memory为内存中缓存的大小,
+/// 超出memory的部分写入临时文件, 超出size时over为pass则不缓存直接转发, 为reject则返回`413`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigBodyBuffer {
+    /// 最多缓存的字节数
+    pub size: u64,
+    /// 内存中缓存的字节数, 超出后写入临时文件
+    pub memory: u64,
+    /// 超出size时是否返回`413`
+    pub reject: bool,
+}
+
+impl ConfigBodyBuffer {
+    pub const DEFAULT_SIZE: u64 = 1024 * 1024;
+    pub const DEFAULT_MEMORY: u64 = 64 * 1024;
+
+    pub fn new(size: u64, memory: u64, reject: bool) -> Self {
+        Self {
+            size,
+            memory,
+            reject,
+        }
+    }
+}
+
+impl Default for ConfigBodyBuffer {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_SIZE, Self::DEFAULT_MEMORY, false)
+    }
+}
+
+impl FromStr for ConfigBodyBuffer {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut buffer = Self::default();
+        for v in s.split_whitespace() {
+            let kv = v.split('=').map(|k| k.trim()).collect::<Vec<&str>>();
+            if kv.len() != 2 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("未知的body_buffer配置:{}", v),
+                ));
+            }
+            match kv[0] {
+                "size" => buffer.size = ConfigSize::from_str(kv[1])?.0,
+                "memory" => buffer.memory = ConfigSize::from_str(kv[1])?.0,
+                "over" => match kv[1] {
+                    "pass" => buffer.reject = false,
+                    "reject" => buffer.reject = true,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("未知的body_buffer处理方式:{}", kv[1]),
+                        ))
+                    }
+                },
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("未知的body_buffer配置:{}", v),
+                    ))
+                }
+            }
+        }
+        Ok(buffer)
+    }
+}
+
+impl Display for ConfigBodyBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "size={} memory={} over={}",
+            ConfigSize::new(self.size),
+            ConfigSize::new(self.memory),
+            if self.reject { "reject" } else { "pass" }
+        ))
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/15 09:46:12
+
+use std::{fmt::Display, io, str::FromStr, time::Duration};
+
+use crate::{ConfigDuration, ConfigSize};
+
+/// 按body匹配location时预读的请求body, 预读的数据仍将完整的转发给上游
+///
+/// 配置格式为`size=1k timeout=1s`, size为最多预读的字节数, 不超过64k,
+/// timeout为等待数据的最长时间, 超时后以已读取的部分进行匹配
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigBodyPeek {
+    /// 最多预读的字节数
+    pub size: u64,
+    /// 等待数据的最长时间
+    pub timeout: Duration,
+}
+
+impl ConfigBodyPeek {
+    pub const DEFAULT_SIZE: u64 = 1024;
+    pub const MAX_SIZE: u64 = 64 * 1024;
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn new(size: u64, timeout: Duration) -> Self {
+        Self { size, timeout }
+    }
+}
+
+impl Default for ConfigBodyPeek {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_SIZE, Self::DEFAULT_TIMEOUT)
+    }
+}
+
+impl FromStr for ConfigBodyPeek {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut peek = Self::default();
+        for v in s.split_whitespace() {
+            let kv = v.split('=').map(|k| k.trim()).collect::<Vec<&str>>();
+            if kv.len() != 2 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("未知的body_peek配置:{}", v),
+                ));
+            }
+            match kv[0] {
+                "size" => peek.size = ConfigSize::from_str(kv[1])?.0,
+                "timeout" => peek.timeout = ConfigDuration::from_str(kv[1])?.0,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("未知的body_peek配置:{}", v),
+                    ))
+                }
+            }
+        }
+        if peek.size == 0 || peek.size > Self::MAX_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("body_peek的size需在1至{}之间", Self::MAX_SIZE),
+            ));
+        }
+        Ok(peek)
+    }
+}
+
+impl Display for ConfigBodyPeek {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "size={} timeout={}",
+            ConfigSize::new(self.size),
+            ConfigDuration::new(self.timeout)
+        ))
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/03 10:12:45
+
+use std::{fmt::Display, io, str::FromStr, time::Duration};
+
+use wenmeng::Rate;
+
+use crate::{ConfigDuration, ConfigRate, ConfigSize};
+
+/// 超出新建连接速率后的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnLimitAction {
+    /// 接受后立即正常关闭(FIN)
+    Close,
+    /// 接受后直接重置(RST), 不做正常的挥手
+    Drop,
+}
+
+/// 按来源IP限制每个周期内新建的连接数, 在accept之后、协议处理之前生效
+///
+/// 配置格式为`limit=10m rate=100r/s action=close`,
+/// limit为最多记录的IP个数, rate为每个IP每个周期内可新建的连接数, action为超出后的处理方式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigConnLimit {
+    /// 最多记录的IP个数, 超出后淘汰已过周期或最久未新建连接的IP
+    pub limit: u64,
+    /// 每个IP的新建连接速率
+    pub rate: Rate,
+    /// 超出速率后的处理方式
+    pub action: ConnLimitAction,
+}
+
+impl ConfigConnLimit {
+    /// 默认最多记录的IP个数
+    pub const DEFAULT_LIMIT: u64 = 10 * 1024 * 1024;
+
+    pub fn new(limit: u64, rate: Rate, action: ConnLimitAction) -> Self {
+        Self {
+            limit,
+            rate,
+            action,
+        }
+    }
+}
+
+impl FromStr for ConnLimitAction {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "close" => Ok(ConnLimitAction::Close),
+            "drop" => Ok(ConnLimitAction::Drop),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("未知的conn_limit处理方式:{}", s),
+            )),
+        }
+    }
+}
+
+impl Display for ConnLimitAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnLimitAction::Close => f.write_str("close"),
+            ConnLimitAction::Drop => f.write_str("drop"),
+        }
+    }
+}
+
+impl FromStr for ConfigConnLimit {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limit = Self::DEFAULT_LIMIT;
+        let mut rate = None;
+        let mut action = ConnLimitAction::Close;
+        for v in s.split_whitespace() {
+            let kv = v.split('=').map(|k| k.trim()).collect::<Vec<&str>>();
+            if kv.len() != 2 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("未知的conn_limit配置:{}", v),
+                ));
+            }
+            match kv[0] {
+                "limit" => limit = ConfigSize::from_str(kv[1])?.0,
+                "rate" => rate = Some(ConfigRate::from_str(kv[1])?.0),
+                "action" => action = kv[1].parse()?,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("未知的conn_limit配置:{}", v),
+                    ))
+                }
+            }
+        }
+        match rate {
+            Some(rate) if rate.nums > 0 && rate.per > Duration::ZERO && limit > 0 => {
+                Ok(Self::new(limit, rate, action))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "conn_limit需配置大于0的rate, 如rate=100r/s",
+            )),
+        }
+    }
+}
+
+impl Display for ConfigConnLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "limit={} rate={}r/{} action={}",
+            ConfigSize::new(self.limit),
+            self.rate.nums,
+            ConfigDuration::new(self.rate.per),
+            self.action
+        ))
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/15 14:21:37
+
+use std::{fmt::Display, io, str::FromStr};
+
+use socket2::SockRef;
+use tokio::net::TcpStream;
+
+/// IP头中的DSCP/ToS标记, 供网络设备按流量分类(QoS), 保存的值为ToS字节
+///
+/// * DSCP的名称, 如`ef`(低延迟, 适用于交互流量), `af11`~`af43`, `cs0`~`cs7`, `le`
+/// * DSCP的数值, 0~63, 如`46`
+/// * `tos=N`直接指定ToS字节, 0~255, 支持`0x`前缀, 如`tos=0x10`
+///
+/// IPv4通过IP_TOS设置, IPv6通过IPV6_TCLASS设置, IPv6仅支持Linux/macOS/BSD,
+/// 不支持的平台或设置失败时仅输出日志, 不影响连接
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigDscp(pub u8);
+
+impl ConfigDscp {
+    pub fn new_dscp(dscp: u8) -> Self {
+        Self(dscp << 2)
+    }
+
+    /// DSCP的值, 为ToS的高6位
+    pub fn dscp(&self) -> u8 {
+        self.0 >> 2
+    }
+
+    fn parse_name(name: &str) -> Option<u8> {
+        let name = name.to_ascii_lowercase();
+        match &*name {
+            "ef" => return Some(46),
+            "le" => return Some(1),
+            _ => {}
+        }
+        if let Some(v) = name.strip_prefix("cs") {
+            return v.parse::<u8>().ok().filter(|v| *v <= 7).map(|v| v * 8);
+        }
+        let v = name.strip_prefix("af")?.as_bytes();
+        match v {
+            [class @ b'1'..=b'4', drop @ b'1'..=b'3'] => {
+                Some((class - b'0') * 8 + (drop - b'0') * 2)
+            }
+            _ => None,
+        }
+    }
+
+    fn to_name(dscp: u8) -> Option<String> {
+        match dscp {
+            46 => Some("ef".to_string()),
+            1 => Some("le".to_string()),
+            v if v % 8 == 0 => Some(format!("cs{}", v / 8)),
+            v if (1..=4).contains(&(v / 8)) && [2, 4, 6].contains(&(v % 8)) => {
+                Some(format!("af{}{}", v / 8, v % 8 / 2))
+            }
+            _ => None,
+        }
+    }
+
+    /// 设置连接的DSCP/ToS, 设置失败或平台不支持时仅输出日志
+    pub fn apply(&self, stream: &TcpStream) {
+        let is_ipv6 = stream.local_addr().map(|a| a.is_ipv6()).unwrap_or(false);
+        if let Err(e) = Self::set_tos(&SockRef::from(stream), self.0, is_ipv6) {
+            log::warn!("设置连接的ToS={:#04x}失败: {:?}", self.0, e);
+        }
+    }
+
+    /// 未配置时不做处理
+    pub fn apply_option(dscp: &Option<ConfigDscp>, stream: &TcpStream) {
+        if let Some(dscp) = dscp {
+            dscp.apply(stream);
+        }
+    }
+
+    #[cfg(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    fn set_tos(socket: &SockRef, tos: u8, is_ipv6: bool) -> io::Result<()> {
+        if is_ipv6 {
+            socket.set_tclass_v6(tos as u32)
+        } else {
+            socket.set_tos(tos as u32)
+        }
+    }
+
+    #[cfg(not(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )))]
+    fn set_tos(socket: &SockRef, tos: u8, is_ipv6: bool) -> io::Result<()> {
+        if is_ipv6 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "当前平台不支持设置IPv6的TCLASS",
+            ));
+        }
+        socket.set_tos(tos as u32)
+    }
+
+    /// socket中当前的ToS字节
+    #[cfg(all(test, target_os = "linux"))]
+    fn get_tos(stream: &TcpStream) -> io::Result<u8> {
+        let socket = SockRef::from(stream);
+        let tos = if stream.local_addr()?.is_ipv6() {
+            socket.tclass_v6()?
+        } else {
+            socket.tos()?
+        };
+        Ok(tos as u8)
+    }
+}
+
+impl FromStr for ConfigDscp {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let err = || io::Error::new(io::ErrorKind::InvalidInput, format!("无效的dscp配置:{}", s));
+        if let Some(tos) = s.strip_prefix("tos=") {
+            let tos = match tos.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => tos.parse::<u8>(),
+            };
+            return tos.map(ConfigDscp).map_err(|_| err());
+        }
+        let dscp = match s.parse::<u8>() {
+            Ok(v) => v,
+            Err(_) => Self::parse_name(s).ok_or_else(err)?,
+        };
+        if dscp > 63 {
+            return Err(err());
+        }
+        Ok(Self::new_dscp(dscp))
+    }
+}
+
+impl Display for ConfigDscp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 & 0x03 != 0 {
+            return f.write_fmt(format_args!("tos={:#04x}", self.0));
+        }
+        match Self::to_name(self.dscp()) {
+            Some(name) => f.write_str(&name),
+            None => f.write_fmt(format_args!("{}", self.dscp())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigDscp;
+
+    #[test]
+    fn parse_dscp() {
+        for (s, tos, display) in [
+            ("ef", 0xb8, "ef"),
+            ("AF41", 34 << 2, "af41"),
+            ("cs1", 8 << 2, "cs1"),
+            ("le", 1 << 2, "le"),
+            ("46", 0xb8, "ef"),
+            ("13", 13 << 2, "13"),
+            ("tos=0x10", 0x10, "4"),
+            ("af12", 12 << 2, "af12"),
+            ("tos=3", 3, "tos=0x03"),
+        ] {
+            let dscp = s.parse::<ConfigDscp>().unwrap();
+            assert_eq!(dscp.0, tos, "{}", s);
+            assert_eq!(format!("{}", dscp), display);
+            assert_eq!(display.parse::<ConfigDscp>().unwrap(), dscp);
+        }
+        for s in ["64", "af51", "cs8", "tos=256", "fast"] {
+            assert!(s.parse::<ConfigDscp>().is_err(), "{}", s);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn apply_tos() {
+        use crate::Helper;
+        let dscp = "ef".parse::<ConfigDscp>().unwrap();
+        let listener = Helper::bind("127.0.0.1:0").await.unwrap();
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accept, _) = listener.accept().await.unwrap();
+        dscp.apply(&stream);
+        ConfigDscp::apply_option(&Some(ConfigDscp::new_dscp(10)), &accept);
+        assert_eq!(ConfigDscp::get_tos(&stream).unwrap(), 0xb8);
+        assert_eq!(ConfigDscp::get_tos(&accept).unwrap(), 10 << 2);
+
+        if let Ok(listener) = Helper::bind("[::1]:0").await {
+            let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            dscp.apply(&stream);
+            assert_eq!(ConfigDscp::get_tos(&stream).unwrap(), 0xb8);
+        }
+    }
+}
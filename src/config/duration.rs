@@ -48,6 +48,10 @@ impl FromStr for ConfigDuration {
             let new = s.trim_end_matches("ms");
             let s = new.parse::<u64>().ok().unwrap_or(1u64);
             Duration::new(0, (s * 1000_000) as u32)
+        } else if s.ends_with("d") {
+            let new = s.trim_end_matches("d");
+            let s = new.parse::<u64>().unwrap_or(1u64);
+            Duration::new(s * 86400, 0)
         } else if s.ends_with("h") {
             let new = s.trim_end_matches("h");
             let s = new.parse::<u64>().unwrap_or(1u64);
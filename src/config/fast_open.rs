@@ -0,0 +1,195 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/09 15:26:03
+
+use std::{
+    io,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use socket2::Socket;
+
+/// 监听端口的TFO队列长度, 0表示未开启
+static LISTEN_QUEUE: AtomicU32 = AtomicU32::new(0);
+/// 连接上游时是否开启TFO
+static CONNECT: AtomicBool = AtomicBool::new(false);
+
+/// TCP Fast Open, 在握手的SYN包中携带数据, 减少一个RTT
+///
+/// * Linux: 监听端设置`TCP_FASTOPEN`为队列长度; 连接端设置`TCP_FASTOPEN_CONNECT`(4.11+),
+///   首个write的数据随SYN发出. 需内核参数`net.ipv4.tcp_fastopen`开启对应的位, 1为客户端, 2为服务端
+/// * macOS: 监听端的`TCP_FASTOPEN`仅为开关, 队列长度由`net.inet.tcp.fastopen_backlog`决定;
+///   连接端须使用`connectx`发起连接, 此处不支持, 将忽略连接端的配置
+/// * 其它平台不支持, 配置后仅输出日志
+///
+/// 内核未授予时(如参数未开启或连接端无cookie)将退回普通的握手, 不影响连接的建立
+pub struct TcpFastOpen;
+
+impl TcpFastOpen {
+    pub fn set_config(listen_queue: Option<u32>, connect: bool) {
+        LISTEN_QUEUE.store(listen_queue.unwrap_or(0), Ordering::Relaxed);
+        CONNECT.store(connect, Ordering::Relaxed);
+        if listen_queue.is_some() {
+            Self::check_sysctl(2, "server");
+        }
+        if connect {
+            Self::check_sysctl(1, "client");
+        }
+    }
+
+    /// 连接上游时是否需要开启TFO
+    pub fn is_connect_enable() -> bool {
+        CONNECT.load(Ordering::Relaxed)
+    }
+
+    /// 监听端在listen之前调用, 未开启时不做处理, 设置失败仅输出日志
+    pub fn apply_listener(socket: &Socket) {
+        let queue = LISTEN_QUEUE.load(Ordering::Relaxed);
+        if queue == 0 {
+            return;
+        }
+        if let Err(e) = Self::set_listener(socket, queue) {
+            log::warn!("监听端开启TCP Fast Open失败, 将使用普通握手: {:?}", e);
+        }
+    }
+
+    /// 连接端在connect之前调用, 未开启时不做处理, 设置失败仅输出日志
+    pub fn apply_connect(socket: &Socket) {
+        if !Self::is_connect_enable() {
+            return;
+        }
+        if let Err(e) = Self::set_connect(socket) {
+            log::warn!("连接端开启TCP Fast Open失败, 将使用普通握手: {:?}", e);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn set_listener(socket: &Socket, queue: u32) -> io::Result<()> {
+        Self::setsockopt(socket, libc::TCP_FASTOPEN, queue as libc::c_int)?;
+        // 内核可能未按设置的值生效, 读取回来确认
+        let granted = Self::getsockopt(socket, libc::TCP_FASTOPEN)?;
+        if granted <= 0 {
+            return Err(io::Error::other("kernel not granted tcp fastopen"));
+        }
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    fn set_listener(socket: &Socket, _queue: u32) -> io::Result<()> {
+        Self::setsockopt(socket, libc::TCP_FASTOPEN, 1)
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios"
+    )))]
+    fn set_listener(_socket: &Socket, _queue: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "tcp fastopen only support linux and macos",
+        ))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn set_connect(socket: &Socket) -> io::Result<()> {
+        Self::setsockopt(socket, libc::TCP_FASTOPEN_CONNECT, 1)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn set_connect(_socket: &Socket) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "tcp fastopen connect only support linux",
+        ))
+    }
+
+    #[cfg(unix)]
+    fn setsockopt(socket: &Socket, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn getsockopt(socket: &Socket, name: libc::c_int) -> io::Result<libc::c_int> {
+        use std::os::fd::AsRawFd;
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(value)
+    }
+
+    /// 检查内核参数是否开启了对应的位, 未开启时设置能成功但不会生效
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn check_sysctl(bit: u32, side: &str) {
+        let value = std::fs::read_to_string("/proc/sys/net/ipv4/tcp_fastopen")
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok());
+        match value {
+            Some(v) if v & bit != 0 => {}
+            Some(v) => log::warn!(
+                "已配置{}的TCP Fast Open, 但内核参数net.ipv4.tcp_fastopen={}未开启, 将使用普通握手",
+                side,
+                v
+            ),
+            None => log::warn!("无法读取内核参数net.ipv4.tcp_fastopen, {}的TCP Fast Open可能不生效", side),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn check_sysctl(_bit: u32, _side: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TcpFastOpen;
+    use crate::{ConfigOption, Helper};
+
+    #[tokio::test]
+    async fn bind_with_fastopen() {
+        let option = toml::from_str::<ConfigOption>("tcp_fastopen = 64\ntcp_fastopen_connect = true").unwrap();
+        assert_eq!(option.tcp_fastopen, Some(64));
+        assert!(option.tcp_fastopen_connect);
+
+        // 内核未授予时同样可以正常监听及连接
+        TcpFastOpen::set_config(option.tcp_fastopen, option.tcp_fastopen_connect);
+        let listener = Helper::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = crate::HealthCheck::connect_bind(&addr, None).await.unwrap();
+        let (accept, _) = listener.accept().await.unwrap();
+        assert_eq!(accept.peer_addr().unwrap(), stream.local_addr().unwrap());
+        TcpFastOpen::set_config(None, false);
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/18 10:04:31
+
+use std::{fmt::Display, io, net::IpAddr, str::FromStr};
+
+/// 转发给上游时添加的代理头
+///
+/// * `xff`: 添加`X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host`
+/// * `forwarded`: 添加RFC 7239的`Forwarded`
+/// * `both`: 同时添加, `none`: 均不添加
+/// * `by=_wmproxy`: `Forwarded`中的by, 未配置时不添加
+///
+/// 如`forwarded by=_edge`, 追加的值同样可通过`{forwarded_for}`, `{forwarded}`引用
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConfigForwarded {
+    pub xff: bool,
+    pub forwarded: bool,
+    pub by: Option<String>,
+}
+
+/// 是否为token的字符, 其它的值需用引号包含
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+fn quote(value: &str) -> String {
+    if !value.is_empty() && value.chars().all(is_tchar) {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace(['"', '\\'], ""))
+    }
+}
+
+impl ConfigForwarded {
+    /// 将`X-Forwarded-For`中的地址转成`for=`的值, IPv6及带端口的地址需加引号
+    pub fn node(value: &str) -> String {
+        match value.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+            _ => quote(value),
+        }
+    }
+
+    /// 生成当前代理的`Forwarded`的值
+    pub fn element(&self, peer: &str, proto: &str, host: Option<&str>) -> String {
+        let mut value = format!("for={}", Self::node(peer));
+        if let Some(by) = &self.by {
+            value += &format!(";by={}", quote(by));
+        }
+        if let Some(host) = host.filter(|h| !h.is_empty()) {
+            value += &format!(";host={}", quote(host));
+        }
+        if !proto.is_empty() {
+            value += &format!(";proto={}", proto);
+        }
+        value
+    }
+
+    /// 取`Forwarded`中每一跳的`for=`, 转成`X-Forwarded-For`中的形式
+    ///
+    /// 多个同名的头合并时以`;`分隔, 故每个`for=`均视为新的一跳
+    pub fn parse_for(value: &str) -> Vec<String> {
+        value
+            .split([',', ';'])
+            .filter_map(|pair| {
+                let (k, v) = pair.split_once('=')?;
+                if !k.trim().eq_ignore_ascii_case("for") {
+                    return None;
+                }
+                let v = v.trim().trim_matches('"');
+                // 不带端口的IPv6地址去掉括号
+                match v.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+                    Some(ip) => Some(ip.to_string()),
+                    None => Some(v.to_string()),
+                }
+            })
+            .collect()
+    }
+}
+
+impl FromStr for ConfigForwarded {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for v in s.split_whitespace() {
+            match v {
+                "xff" => config.xff = true,
+                "forwarded" => config.forwarded = true,
+                "both" => {
+                    config.xff = true;
+                    config.forwarded = true;
+                }
+                "none" => {
+                    config.xff = false;
+                    config.forwarded = false;
+                }
+                _ => match v.strip_prefix("by=") {
+                    Some(by) if !by.is_empty() => config.by = Some(by.to_string()),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("未知的forwarded_headers配置:{}", v),
+                        ))
+                    }
+                },
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl Display for ConfigForwarded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mode = match (self.xff, self.forwarded) {
+            (true, true) => "both",
+            (true, false) => "xff",
+            (false, true) => "forwarded",
+            (false, false) => "none",
+        };
+        f.write_str(mode)?;
+        if let Some(by) = &self.by {
+            write!(f, " by={}", by)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigForwarded;
+
+    #[test]
+    fn parse_forwarded_header() {
+        let config = "forwarded by=_edge".parse::<ConfigForwarded>().unwrap();
+        assert!(config.forwarded && !config.xff);
+        assert_eq!(format!("{}", config).parse::<ConfigForwarded>().unwrap(), config);
+        assert!("both".parse::<ConfigForwarded>().unwrap().xff);
+        assert!("xff by=".parse::<ConfigForwarded>().is_err());
+
+        assert_eq!(
+            config.element("2001:db8::1", "https", Some("example.com:8443")),
+            "for=\"[2001:db8::1]\";by=_edge;host=\"example.com:8443\";proto=https"
+        );
+        // 多跳的Forwarded, 包含合并后的同名头
+        let value = "for=192.0.2.60;proto=http;by=203.0.113.43, for=\"[2001:db8:cafe::17]:4711\", For=unknown;for=\"[2001:db8::2]\"";
+        assert_eq!(
+            ConfigForwarded::parse_for(value),
+            vec!["192.0.2.60", "[2001:db8:cafe::17]:4711", "unknown", "2001:db8::2"]
+        );
+    }
+}
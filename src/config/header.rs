@@ -85,6 +85,21 @@ impl ConfigHeader {
             val,
         }
     }
+
+    /// 以layer中的头覆盖base中同方向的同名头, 如上游的配置覆盖location的配置
+    pub fn merge(base: &[ConfigHeader], layer: &[ConfigHeader]) -> Vec<ConfigHeader> {
+        let mut headers = base
+            .iter()
+            .filter(|h| {
+                !layer
+                    .iter()
+                    .any(|l| l.is_proxy == h.is_proxy && l.key.eq_ignore_ascii_case(&h.key))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        headers.extend_from_slice(layer);
+        headers
+    }
 }
 
 impl FromStr for ConfigHeader {
@@ -191,4 +206,24 @@ mod tests {
             "val 1"
         );
     }
+
+    #[test]
+    fn merge_header() {
+        let parse = |list: &[&str]| {
+            list.iter()
+                .map(|s| crate::ConfigHeader::from_str(s).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let base = parse(&["proxy X-Api-Key a", "proxy X-Trace 1", "X-Api-Key a"]);
+        let layer = parse(&["proxy x-api-key b"]);
+        let merged = crate::ConfigHeader::merge(&base, &layer);
+        // 仅覆盖同方向的同名头
+        assert_eq!(
+            merged
+                .iter()
+                .map(|h| (h.is_proxy, &*h.key, &*h.val))
+                .collect::<Vec<_>>(),
+            vec![(true, "X-Trace", "1"), (false, "X-Api-Key", "a"), (true, "x-api-key", "b")]
+        );
+    }
 }
@@ -0,0 +1,154 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/04 09:26:11
+
+use std::{fmt::Display, io, str::FromStr};
+
+use webparse::Request;
+
+use crate::ConfigSize;
+
+/// 请求头的大小限制, 类似nginx的`large_client_header_buffers`
+///
+/// 配置格式为`count=128 size=16k total=64k`, 未配置的项使用默认值
+/// 在收到客户端请求及转发给上游前均会校验, 超出时返回`431`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigHeaderLimit {
+    /// 最多的请求头个数
+    pub count: usize,
+    /// 单个请求头的最大字节数, 按`name: value`计算
+    pub size: usize,
+    /// 请求行及所有请求头的总字节数
+    pub total: usize,
+}
+
+impl ConfigHeaderLimit {
+    pub const DEFAULT_COUNT: usize = 128;
+    pub const DEFAULT_SIZE: usize = 16 * 1024;
+    pub const DEFAULT_TOTAL: usize = 64 * 1024;
+
+    pub fn new(count: usize, size: usize, total: usize) -> Self {
+        Self { count, size, total }
+    }
+
+    /// 校验请求是否在限制之内
+    pub fn check<T: webparse::Serialize>(&self, req: &Request<T>) -> bool {
+        let headers = req.headers();
+        if headers.len() > self.count {
+            return false;
+        }
+        // 请求行, 如`GET /path?query HTTP/1.1`
+        let url = req.url();
+        let mut total = req.method().as_str().len()
+            + url.path.len()
+            + url.query.as_ref().map(|q| q.len() + 1).unwrap_or(0)
+            + req.version().as_str().len()
+            + 2;
+        for (name, value) in headers.iter() {
+            let size = name.as_bytes().len() + value.as_bytes().len() + 2;
+            if size > self.size {
+                return false;
+            }
+            total += size;
+        }
+        total <= self.total
+    }
+}
+
+impl Default for ConfigHeaderLimit {
+    fn default() -> Self {
+        Self::new(
+            Self::DEFAULT_COUNT,
+            Self::DEFAULT_SIZE,
+            Self::DEFAULT_TOTAL,
+        )
+    }
+}
+
+impl FromStr for ConfigHeaderLimit {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limit = Self::default();
+        for v in s.split_whitespace() {
+            let kv = v.split('=').map(|k| k.trim()).collect::<Vec<&str>>();
+            if kv.len() != 2 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("未知的header_limit配置:{}", v),
+                ));
+            }
+            let value = ConfigSize::from_str(kv[1])?.0 as usize;
+            match kv[0] {
+                "count" => limit.count = value,
+                "size" => limit.size = value,
+                "total" => limit.total = value,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("未知的header_limit配置:{}", v),
+                    ))
+                }
+            }
+        }
+        Ok(limit)
+    }
+}
+
+impl Display for ConfigHeaderLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "count={} size={} total={}",
+            self.count, self.size, self.total
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigHeaderLimit;
+    use webparse::Request;
+
+    fn build_request(value: &str) -> Request<String> {
+        let mut req = Request::builder()
+            .url("/a?b")
+            .header("x-test", value.to_string())
+            .body(String::new())
+            .unwrap();
+        // 去掉默认附带的User-Agent, 方便计算大小
+        req.headers_mut().remove(&"User-Agent");
+        req
+    }
+
+    #[test]
+    fn boundary_size() {
+        let limit = "count=2 size=16 total=48".parse::<ConfigHeaderLimit>().unwrap();
+        assert_eq!(format!("{}", limit), "count=2 size=16 total=48");
+        assert!("count=2 lines=1".parse::<ConfigHeaderLimit>().is_err());
+
+        // `x-test: ` 占8个字节, 值为8个字节时刚好达到单个头的上限
+        let req = build_request("12345678");
+        assert!(limit.check(&req));
+        assert!(!limit.check(&build_request("123456789")));
+
+        // 请求行`GET /a?b HTTP/1.1`为17个字节, 加上一个头刚好为33个字节
+        let limit = ConfigHeaderLimit::new(2, 16, 33);
+        assert!(limit.check(&req));
+        let limit = ConfigHeaderLimit::new(2, 16, 32);
+        assert!(!limit.check(&req));
+
+        let mut req = req;
+        req.headers_mut().insert("x-more", "1");
+        assert!(ConfigHeaderLimit::new(2, 16, 1024).check(&req));
+        req.headers_mut().insert("x-other", "1");
+        assert!(!ConfigHeaderLimit::new(2, 16, 1024).check(&req));
+    }
+}
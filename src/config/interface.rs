@@ -0,0 +1,193 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/01 15:32:08
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use local_ip_address::list_afinet_netifas;
+use socket2::Socket;
+
+/// 按网卡名称绑定, 仅Linux下支持
+/// * 监听地址以`@`开头, 如`@eth0:8080`, 启动时解析成该网卡当前的所有地址, 网卡无地址时监听`0.0.0.0`
+/// * 上游的`local_bind`填写网卡名称, 如`eth0`, 连接时绑定该网卡的地址
+///
+/// 两者绑定时都会设置`SO_BINDTODEVICE`, 需要`CAP_NET_RAW`权限, 其它平台会返回错误
+pub struct NetInterface;
+
+impl NetInterface {
+    /// 解析`@eth0:8080`格式的地址, 返回网卡名称及剩余的端口部分
+    pub fn split_device(s: &str) -> Option<(&str, &str)> {
+        let s = s.strip_prefix('@')?;
+        match s.find(':') {
+            Some(idx) => Some((&s[..idx], &s[idx + 1..])),
+            None => Some((s, "")),
+        }
+    }
+
+    /// 获取网卡当前的地址
+    pub fn resolve(name: &str) -> Vec<IpAddr> {
+        match list_afinet_netifas() {
+            Ok(list) => list
+                .into_iter()
+                .filter(|(n, _)| n == name)
+                .map(|(_, ip)| ip)
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// 将网卡解析成对应端口的监听地址
+    pub fn resolve_listen(name: &str, port: u16) -> Vec<SocketAddr> {
+        let mut ips = Self::resolve(name);
+        if ips.is_empty() {
+            ips.push(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        }
+        ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect()
+    }
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fn bind_device(socket: &Socket, name: &str) -> io::Result<()> {
+        socket.bind_device(Some(name.as_bytes())).map_err(|e| {
+            log::error!("绑定网卡{}失败, 请确认网卡存在且拥有CAP_NET_RAW权限: {:?}", name, e);
+            io::Error::new(e.kind(), format!("bind to device {} failed: {}", name, e))
+        })
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    pub fn bind_device(_socket: &Socket, name: &str) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("bind to device {} only support linux, please use ip address", name),
+        ))
+    }
+
+    /// 将IPv6地址的scope转化成网卡序号, scope可为序号或者网卡名称, 如`eth0`
+    pub fn scope_id(scope: &str) -> Option<u32> {
+        if let Ok(id) = scope.parse::<u32>() {
+            return Some(id);
+        }
+        Self::name_to_index(scope)
+    }
+
+    #[cfg(unix)]
+    fn name_to_index(name: &str) -> Option<u32> {
+        let name = std::ffi::CString::new(name).ok()?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn name_to_index(_name: &str) -> Option<u32> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn index_exists(id: u32) -> bool {
+        let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+        !unsafe { libc::if_indextoname(id, buf.as_mut_ptr()) }.is_null()
+    }
+
+    #[cfg(not(unix))]
+    fn index_exists(_id: u32) -> bool {
+        true
+    }
+
+    /// 绑定前校验链路本地地址的scope对应的网卡是否存在
+    pub fn check_scope(addr: &SocketAddr) -> io::Result<()> {
+        match addr {
+            SocketAddr::V6(v6) if v6.scope_id() != 0 && !Self::index_exists(v6.scope_id()) => {
+                log::error!("地址{}的网卡序号{}不存在", addr, v6.scope_id());
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("scope id {} of {} not found", v6.scope_id(), addr),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 获取上游连接的本地绑定地址, `local_bind`可以为ip或者网卡名称
+    /// 返回本地地址与需要绑定的网卡
+    pub fn local_bind(local_bind: &str, remote: &SocketAddr) -> (SocketAddr, Option<String>) {
+        if let Ok(ip) = local_bind.parse::<IpAddr>() {
+            return (SocketAddr::new(ip, 0), None);
+        }
+        let ip = Self::resolve(local_bind)
+            .into_iter()
+            .find(|ip| ip.is_ipv4() == remote.is_ipv4())
+            .unwrap_or(if remote.is_ipv4() {
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            } else {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            });
+        (SocketAddr::new(ip, 0), Some(local_bind.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::NetInterface;
+    use crate::{WrapAddr, WrapVecAddr};
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn listen_by_device() {
+        assert_eq!(NetInterface::split_device("@eth0:8080"), Some(("eth0", "8080")));
+        assert_eq!(NetInterface::split_device("127.0.0.1:8080"), None);
+
+        let addrs = "@lo:18920".parse::<WrapVecAddr>().unwrap();
+        assert!(!addrs.0.is_empty());
+        for addr in &addrs.0 {
+            assert_eq!(addr.port(), 18920);
+            assert_eq!(addrs.device(addr), Some("lo"));
+        }
+        assert_eq!(addrs.to_string(), "@lo:18920");
+        assert!("@lo:abc".parse::<WrapVecAddr>().is_err());
+
+        // 网卡记录在解析后的地址中, 同一地址不绑定网卡时不受之前的解析影响
+        let plain = addrs.0[0].to_string().parse::<WrapVecAddr>().unwrap();
+        assert_eq!(plain.device(&addrs.0[0]), None);
+
+        let range = "@lo:18921-18922".parse::<WrapVecAddr>().unwrap();
+        assert!(range.0.iter().all(|a| range.device(a) == Some("lo")));
+        let addr = "@lo:18923".parse::<WrapAddr>().unwrap();
+        assert_eq!(addr.1.as_deref(), Some("lo"));
+        assert_eq!(addr.to_string(), "@lo:18923");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scoped_ipv6() {
+        let id = NetInterface::scope_id("lo").unwrap();
+        let addr = "[fe80::1%lo]:8080".parse::<WrapAddr>().unwrap();
+        match addr.0 {
+            SocketAddr::V6(v6) => assert_eq!(v6.scope_id(), id),
+            _ => unreachable!(),
+        }
+        assert!(NetInterface::check_scope(&addr.0).is_ok());
+
+        // 以序号输出, 重新解析后地址不变
+        let addrs = format!("{} [fe80::2%{}]:8081", addr, id).parse::<WrapVecAddr>().unwrap();
+        assert_eq!(addrs.0[0], addr.0);
+        assert_eq!(addrs.to_string().parse::<WrapVecAddr>().unwrap().0, addrs.0);
+
+        assert!("[fe80::1%wmnotexist]:8080".parse::<WrapVecAddr>().is_err());
+        let missing = "[fe80::1%65000]:8080".parse::<WrapAddr>().unwrap();
+        assert!(NetInterface::check_scope(&missing.0).is_err());
+    }
+}
@@ -32,6 +32,15 @@ impl ConfigLog {
         }
     }
 
+    /// 关闭日志, 如location中配置`access_log = "off"`不记录该location的访问日志
+    pub fn off() -> Self {
+        Self::new("off".to_string(), String::new(), log::Level::Trace)
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.name == "off" && self.format.is_empty()
+    }
+
     pub fn as_error(&mut self) {
         if !self.format.is_empty() {
             if let Ok(level) = log::Level::from_str(&self.format.to_ascii_lowercase()) {
@@ -46,7 +55,9 @@ impl ConfigLog {
 
 impl Display for ConfigLog {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.format.is_empty() {
+        if self.is_off() {
+            f.write_str("off")
+        } else if self.format.is_empty() {
             f.write_fmt(format_args!("{} {}", self.name, self.level))
         } else {
             if self.level != log::Level::Trace {
@@ -62,6 +73,9 @@ impl FromStr for ConfigLog {
     type Err=ProxyError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "off" {
+            return Ok(Self::off());
+        }
         let v: Vec<&str> = s.split(' ').collect();
         if v.len() < 2 {
             return Err(ProxyError::Extension("名称的格式间必须有空格"));
@@ -0,0 +1,63 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/04 14:05:37
+
+use std::{fmt::Display, io, str::FromStr};
+
+use webparse::Method;
+
+/// 请求方法的集合, 以空格或逗号做间隔, 如`GET HEAD POST`
+/// 保留配置的顺序, 同时支持TRACK等非标准的方法
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MethodSets(pub Vec<String>);
+
+impl MethodSets {
+    /// 仅配置禁止列表时, 在该列表的基础上生成`Allow`头
+    pub const COMMON: [&'static str; 7] = ["GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS", "PATCH"];
+
+    pub fn contains(&self, method: &Method) -> bool {
+        self.0.iter().any(|m| m == method.as_str())
+    }
+
+    pub fn contains_str(&self, method: &str) -> bool {
+        self.0.iter().any(|m| m == method)
+    }
+}
+
+impl FromStr for MethodSets {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut vals = vec![];
+        for v in s.split(|c: char| c == ',' || c.is_whitespace()) {
+            if v.is_empty() {
+                continue;
+            }
+            if !v.bytes().all(|b| b.is_ascii_alphabetic() || b == b'-' || b == b'_') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("未知的请求方法:{}", v),
+                ));
+            }
+            let v = v.to_uppercase();
+            if !vals.contains(&v) {
+                vals.push(v);
+            }
+        }
+        Ok(MethodSets(vals))
+    }
+}
+
+impl Display for MethodSets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0.join(", "))
+    }
+}
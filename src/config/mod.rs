@@ -18,6 +18,19 @@ mod header;
 mod rate;
 mod ip_sets;
 mod wrap;
+mod interface;
+mod conn_limit;
+mod header_limit;
+mod method_sets;
+mod body_buffer;
+mod body_peek;
+mod write_pressure;
+mod port_map;
+mod fast_open;
+mod sock_buffer;
+mod dscp;
+mod forwarded;
+mod basic_auth;
 
 use std::{str::FromStr, fmt::{Display, self}, marker::PhantomData};
 
@@ -28,6 +41,19 @@ pub use self::header::{ConfigHeader, HeaderOper};
 pub use self::rate::ConfigRate;
 pub use self::ip_sets::*;
 pub use self::wrap::*;
+pub use self::interface::NetInterface;
+pub use self::conn_limit::{ConfigConnLimit, ConnLimitAction};
+pub use self::header_limit::ConfigHeaderLimit;
+pub use self::method_sets::MethodSets;
+pub use self::body_buffer::ConfigBodyBuffer;
+pub use self::body_peek::ConfigBodyPeek;
+pub use self::write_pressure::ConfigWritePressure;
+pub use self::port_map::ConfigPortMap;
+pub use self::fast_open::TcpFastOpen;
+pub use self::sock_buffer::SocketBuffer;
+pub use self::dscp::ConfigDscp;
+pub use self::forwarded::ConfigForwarded;
+pub use self::basic_auth::ConfigBasicAuth;
 
 use serde::{Serializer, Deserializer, de::{Visitor, Error, self}};
 use serde_with::{SerializeAs, DeserializeAs};
@@ -0,0 +1,105 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/09 15:22:08
+
+use std::{collections::BTreeMap, fmt::Display, io, str::FromStr};
+
+/// 监听端口到上游端口的映射, 用于绑定端口范围的端口转发
+///
+/// 配置格式为`offset=1000`, 上游端口为监听端口加上偏移, 可为负数,
+/// 或为`9000=80 9001=8080`逐个指定, 两者同时配置时优先使用指定的端口
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigPortMap {
+    /// 端口的偏移
+    pub offset: i32,
+    /// 指定的端口映射
+    pub ports: BTreeMap<u16, u16>,
+}
+
+impl ConfigPortMap {
+    /// 获取监听端口对应的上游端口, 超出端口范围时返回None
+    pub fn map(&self, port: u16) -> Option<u16> {
+        if let Some(p) = self.ports.get(&port) {
+            return Some(*p);
+        }
+        u16::try_from(port as i32 + self.offset).ok()
+    }
+}
+
+impl FromStr for ConfigPortMap {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |v: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("未知的port_map配置:{}", v),
+            )
+        };
+        let mut map = Self::default();
+        for v in s.split_whitespace() {
+            let kv = v.split('=').map(|k| k.trim()).collect::<Vec<&str>>();
+            if kv.len() != 2 {
+                return Err(err(v));
+            }
+            if kv[0] == "offset" {
+                map.offset = kv[1].parse::<i32>().map_err(|_| err(v))?;
+            } else {
+                let from = kv[0].parse::<u16>().map_err(|_| err(v))?;
+                let to = kv[1].parse::<u16>().map_err(|_| err(v))?;
+                map.ports.insert(from, to);
+            }
+        }
+        Ok(map)
+    }
+}
+
+impl Display for ConfigPortMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut values = vec![];
+        if self.offset != 0 {
+            values.push(format!("offset={}", self.offset));
+        }
+        for (from, to) in &self.ports {
+            values.push(format!("{}={}", from, to));
+        }
+        f.write_str(&values.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigPortMap;
+    use crate::WrapVecAddr;
+
+    #[test]
+    fn port_range() {
+        let addrs = "127.0.0.1:9000-9002".parse::<WrapVecAddr>().unwrap();
+        let ports = addrs.0.iter().map(|a| a.port()).collect::<Vec<u16>>();
+        assert_eq!(ports, vec![9000, 9001, 9002]);
+        let addrs = "127.0.0.1:9000-:9001".parse::<WrapVecAddr>().unwrap();
+        assert_eq!(addrs.0.len(), 2);
+        // 过大或反向的范围
+        assert!("127.0.0.1:9000-20000".parse::<WrapVecAddr>().is_err());
+        assert!("127.0.0.1:9010-9000".parse::<WrapVecAddr>().is_err());
+    }
+
+    #[test]
+    fn port_map() {
+        let map = "offset=-8920 9001=8080".parse::<ConfigPortMap>().unwrap();
+        assert_eq!(format!("{}", map), "offset=-8920 9001=8080");
+        assert_eq!(map.map(9000), Some(80));
+        assert_eq!(map.map(9001), Some(8080));
+        assert_eq!(map.map(8000), None);
+        assert!("offset=abc".parse::<ConfigPortMap>().is_err());
+        assert!("9001".parse::<ConfigPortMap>().is_err());
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/11 15:20:46
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use socket2::Socket;
+
+use crate::ConfigSize;
+
+/// SO_RCVBUF的大小, 0表示使用系统默认
+static RECV_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// SO_SNDBUF的大小, 0表示使用系统默认
+static SEND_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// TCP的收发缓冲区大小, 高延迟高带宽的链路(如远距离的内网穿透)中默认的缓冲区将限制吞吐
+///
+/// * 监听端在listen之前设置, accept的连接继承监听端的大小, 窗口扩大因子在握手时确定, 需在此之前设置
+/// * 连接端在connect之前设置, 作用于连接上游及连接中心服务端
+/// * Linux下设置的值将被内核翻倍, 且不超过`net.core.rmem_max`/`net.core.wmem_max`,
+///   超出时不报错而是被截断, 同时设置后将关闭该方向的自动调整(tcp_moderate_rcvbuf)
+pub struct SocketBuffer;
+
+impl SocketBuffer {
+    pub fn set_config(recv: Option<ConfigSize>, send: Option<ConfigSize>) {
+        let recv = recv.map(|v| v.0 as usize).unwrap_or(0);
+        let send = send.map(|v| v.0 as usize).unwrap_or(0);
+        RECV_SIZE.store(recv, Ordering::Relaxed);
+        SEND_SIZE.store(send, Ordering::Relaxed);
+        if recv > 0 {
+            Self::check_sysctl("rmem_max", recv);
+        }
+        if send > 0 {
+            Self::check_sysctl("wmem_max", send);
+        }
+    }
+
+    /// 是否配置了收发缓冲区
+    pub fn is_enable() -> bool {
+        RECV_SIZE.load(Ordering::Relaxed) > 0 || SEND_SIZE.load(Ordering::Relaxed) > 0
+    }
+
+    /// 在listen或connect之前调用, 未配置时不做处理, 设置失败仅输出日志
+    pub fn apply(socket: &Socket) {
+        let recv = RECV_SIZE.load(Ordering::Relaxed);
+        if recv > 0 {
+            if let Err(e) = socket.set_recv_buffer_size(recv) {
+                log::warn!("设置SO_RCVBUF={}失败: {:?}", recv, e);
+            }
+        }
+        let send = SEND_SIZE.load(Ordering::Relaxed);
+        if send > 0 {
+            if let Err(e) = socket.set_send_buffer_size(send) {
+                log::warn!("设置SO_SNDBUF={}失败: {:?}", send, e);
+            }
+        }
+    }
+
+    /// 超过内核的上限时将被截断, 输出日志以便调整内核参数
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn check_sysctl(name: &str, size: usize) {
+        let max = std::fs::read_to_string(format!("/proc/sys/net/core/{}", name))
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok());
+        if let Some(max) = max {
+            if size > max {
+                log::warn!(
+                    "配置的缓冲区大小{}超过内核参数net.core.{}={}, 将被截断, 需调大该内核参数",
+                    size,
+                    name,
+                    max
+                );
+            }
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn check_sysctl(_name: &str, _size: usize) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SocketBuffer;
+    use crate::{ConfigOption, HealthCheck, Helper};
+
+    #[tokio::test]
+    async fn apply_buffer_size() {
+        let option =
+            toml::from_str::<ConfigOption>("recv_buffer_size = \"64k\"\nsend_buffer_size = \"32k\"").unwrap();
+        assert_eq!(option.recv_buffer_size.as_ref().unwrap().0, 64 * 1024);
+
+        SocketBuffer::set_config(option.recv_buffer_size.clone(), option.send_buffer_size.clone());
+        let listener = Helper::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = HealthCheck::connect(&addr).await.unwrap();
+        let (accept, _) = listener.accept().await.unwrap();
+        SocketBuffer::set_config(None, None);
+
+        // Linux下内核将设置的值翻倍, 且可能被rmem_max截断, 仅确认已生效
+        let stream = socket2::SockRef::from(&stream);
+        let accept = socket2::SockRef::from(&accept);
+        assert!(stream.send_buffer_size().unwrap() >= 32 * 1024);
+        assert!(accept.recv_buffer_size().unwrap() > 0);
+    }
+}
@@ -11,13 +11,24 @@
 // Created Date: 2024/01/25 02:13:35
 
 use std::{
-    fmt::Display, net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr}, str::FromStr
+    collections::HashMap, fmt::Display, io, net::{IpAddr, Ipv4Addr, SocketAddr}, str::FromStr
 };
 
 use local_ip_address::{local_ip, local_ipv6};
 
-fn parse_socker_addr(s: &str) -> Result<Vec<SocketAddr>, AddrParseError> {
-    if s.starts_with(":") {
+use super::NetInterface;
+
+fn invalid_addr(s: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("无效的地址:{}", s))
+}
+
+/// 解析单个地址, 以网卡名称配置时同时返回需绑定的网卡
+fn parse_socker_addr(s: &str) -> io::Result<(Vec<SocketAddr>, Option<String>)> {
+    let parse = |s: &str| s.parse::<SocketAddr>().map_err(|_| invalid_addr(s));
+    if let Some((name, port)) = NetInterface::split_device(s) {
+        let port = port.parse::<u16>().map_err(|_| invalid_addr(s))?;
+        Ok((NetInterface::resolve_listen(name, port), Some(name.to_string())))
+    } else if s.starts_with(":") {
         let port = s.trim_start_matches(':');
         let mut results = vec![];
         if let Ok(port) = port.parse::<u16>() {
@@ -29,30 +40,53 @@ fn parse_socker_addr(s: &str) -> Result<Vec<SocketAddr>, AddrParseError> {
             }
             results.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port));
         } else {
-            results.push(format!("127.0.0.1{s}").parse::<SocketAddr>()?);
+            results.push(parse(&format!("127.0.0.1{s}"))?);
+        }
+        Ok((results, None))
+    } else if let Some(idx) = s.find('%') {
+        // 带scope的IPv6地址, 如`[fe80::1%eth0]:8869`, 网卡名称转化成序号
+        let end = s[idx..].find(']').map(|e| idx + e).unwrap_or(s.len());
+        let scope = &s[idx + 1..end];
+        match NetInterface::scope_id(scope) {
+            Some(id) => Ok((vec![parse(&format!("{}%{}{}", &s[..idx], id, &s[end..]))?], None)),
+            None => {
+                log::error!("地址{}中的网卡{}不存在", s, scope);
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("地址{}中的网卡{}不存在", s, scope),
+                ))
+            }
         }
-        Ok(results)
     } else {
-        let addr = s.parse::<SocketAddr>()?;
-        Ok(vec![addr])
+        Ok((vec![parse(s)?], None))
     }
 }
 
-/// 地址类包装
-#[derive(Debug, Clone, Copy)]
-pub struct WrapAddr(pub SocketAddr);
+/// 地址类包装, 以`@eth0:8080`配置时记录需绑定的网卡
+#[derive(Debug, Clone)]
+pub struct WrapAddr(pub SocketAddr, pub Option<String>);
+
+impl WrapAddr {
+    pub fn new(addr: SocketAddr) -> Self {
+        WrapAddr(addr, None)
+    }
+}
 
 impl FromStr for WrapAddr {
-    type Err = AddrParseError;
+    type Err = io::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(WrapAddr(parse_socker_addr(s)?[0]))
+        let (addrs, device) = parse_socker_addr(s)?;
+        Ok(WrapAddr(addrs[0], device))
     }
 }
 
 impl Display for WrapAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{}", self.0))
+        match &self.1 {
+            Some(name) => f.write_fmt(format_args!("@{}:{}", name, self.0.port())),
+            None => f.write_fmt(format_args!("{}", self.0)),
+        }
     }
 }
 
@@ -68,62 +102,119 @@ impl Display for WrapAddr {
 ///   - `:8869-:8871` 解析成 ipv4 127.0.0.1 端口 8869 - 8871 三个端口地址 及 ipv4 192.168.0.100 端口 8869 - 8871 三个端口地址，总共6个端口地址
 ///   - `127.0.0.1:8869-:8871` 解析成 ipv4 127.0.0.1 端口 8869 - 8871 三个端口地址 总共3个端口地址
 ///   - `127.0.0.1:8869-192.168.0.100:8871` 解析成 ipv4 127.0.0.1 端口 8869 - 8871 三个端口地址 总共3个端口地址，忽略后面的地址，只接受端口号
+///   - `127.0.0.1:8869-8871` 同上, 结束地址可只写端口号
+///   - 范围最多包含1024个端口
 /// 
+/// * 以`@`开头的网卡名称, 仅Linux下支持
+///   - `@eth0:8869` 解析成网卡eth0当前的所有地址 端口 8869，并通过`SO_BINDTODEVICE`绑定到该网卡
+///
+/// * 带scope的IPv6链路本地地址
+///   - `[fe80::1%eth0]:8869` 解析成网卡eth0的序号作为scope id, 也可直接填写序号如`[fe80::1%2]:8869`
+///
 /// * 手动多个地址，可以空格或者`,`做间隔
 ///   - `127.0.0.1:8869 127.0.0.1:8899 192.168.0.100:8899` 就相应的解析成三个端口地址
 #[derive(Debug, Clone)]
-pub struct WrapVecAddr(pub Vec<SocketAddr>);
+pub struct WrapVecAddr(pub Vec<SocketAddr>, pub HashMap<SocketAddr, String>);
 impl FromStr for WrapVecAddr {
-    type Err = AddrParseError;
+    type Err = io::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |e: io::Error| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("地址{}解析失败:{}", s, e))
+        };
         // 范围的如:8080-:8090, 表示11端口
         if s.contains("-") {
             let vals = s
                 .split(&['-'])
                 .filter(|s| !s.is_empty())
                 .collect::<Vec<&str>>();
-            let start = parse_socker_addr(vals[0])?;
+            let (start, device) = parse_socker_addr(vals[0]).map_err(invalid)?;
             if vals.len() != 2 {
-                return Ok(WrapVecAddr(start));
+                Ok(WrapVecAddr::with_device(start, device))
             } else {
-                let end = parse_socker_addr(vals[1])?;
+                // 结束地址可只写端口号, 如`127.0.0.1:9000-9010`
+                let end = match vals[1].trim_start_matches(':').parse::<u16>() {
+                    Ok(port) => port,
+                    Err(_) => parse_socker_addr(vals[1]).map_err(invalid)?.0[0].port(),
+                };
+                let begin = start[0].port();
+                if begin > end || (end - begin) as usize >= Self::MAX_PORT_RANGE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("端口范围{}无效, 最多支持{}个端口", s, Self::MAX_PORT_RANGE),
+                    ));
+                }
                 let mut results = vec![];
-                for port in start[0].port()..=end[1].port() {
+                for port in begin..=end {
                     for idx in &start {
-                        let mut addr = idx.clone();
+                        let mut addr = *idx;
                         addr.set_port(port);
                         results.push(addr);
                     }
                 }
-                return Ok(WrapVecAddr(results));
+                Ok(WrapVecAddr::with_device(results, device))
             }
         } else {
             let vals = s
                 .split(&[',', ' '])
                 .filter(|s| !s.is_empty())
                 .collect::<Vec<&str>>();
-            let mut results = vec![];
+            let mut results = WrapVecAddr::empty();
             for s in vals {
-                results.extend(parse_socker_addr(s)?);
+                let (addrs, device) = parse_socker_addr(s).map_err(invalid)?;
+                results.extend(WrapVecAddr::with_device(addrs, device));
             }
-            Ok(WrapVecAddr(results))
+            Ok(results)
         }
     }
 }
 
 impl Display for WrapVecAddr {
+    /// 绑定网卡的地址输出成`@eth0:8080`, 重新解析时按网卡当前的地址解析
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut values = vec![];
+        let mut values: Vec<String> = vec![];
         for a in &self.0 {
-            values.push(format!("{}", a));
+            let value = match self.1.get(a) {
+                Some(name) => format!("@{}:{}", name, a.port()),
+                None => format!("{}", a),
+            };
+            if !values.contains(&value) {
+                values.push(value);
+            }
         }
         f.write_str(&values.join(","))
     }
 }
 
 impl WrapVecAddr {
+    /// 端口范围最多包含的端口数, 防止误配置时绑定过多的端口
+    pub const MAX_PORT_RANGE: usize = 1024;
+
+    pub fn new(addrs: Vec<SocketAddr>) -> Self {
+        WrapVecAddr(addrs, HashMap::new())
+    }
+
     pub fn empty() -> Self {
-        WrapVecAddr(vec![])
+        Self::new(vec![])
+    }
+
+    fn with_device(addrs: Vec<SocketAddr>, device: Option<String>) -> Self {
+        let mut devices = HashMap::new();
+        if let Some(name) = device {
+            for addr in &addrs {
+                devices.insert(*addr, name.clone());
+            }
+        }
+        WrapVecAddr(addrs, devices)
+    }
+
+    fn extend(&mut self, other: WrapVecAddr) {
+        self.0.extend(other.0);
+        self.1.extend(other.1);
+    }
+
+    /// 获取该地址需要绑定的网卡
+    pub fn device(&self, addr: &SocketAddr) -> Option<&str> {
+        self.1.get(addr).map(|s| s.as_str())
     }
 
     pub fn is_empty(&self) -> bool {
@@ -0,0 +1,87 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/07 09:40:26
+
+use std::{fmt::Display, io, str::FromStr, time::Duration};
+
+use crate::{ConfigDuration, ConfigSize};
+
+/// 隧道写入积压的监控, 对端过慢时待写入的数据将持续堆积
+///
+/// 配置格式为`warn=4m sustain=10s limit=16m`, 待写入的数据超过warn并持续sustain后输出告警,
+/// 配置limit后待写入的数据超过limit时暂停接收新的数据, 以限制内存的占用
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigWritePressure {
+    /// 告警的阈值
+    pub warn: usize,
+    /// 持续超过阈值多久后告警
+    pub sustain: Duration,
+    /// 暂停接收新数据的阈值
+    pub limit: Option<usize>,
+}
+
+impl ConfigWritePressure {
+    pub const DEFAULT_WARN: usize = 4 * 1024 * 1024;
+    pub const DEFAULT_SUSTAIN: Duration = Duration::from_secs(10);
+}
+
+impl Default for ConfigWritePressure {
+    fn default() -> Self {
+        Self {
+            warn: Self::DEFAULT_WARN,
+            sustain: Self::DEFAULT_SUSTAIN,
+            limit: None,
+        }
+    }
+}
+
+impl FromStr for ConfigWritePressure {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pressure = Self::default();
+        for v in s.split_whitespace() {
+            let kv = v.split('=').map(|k| k.trim()).collect::<Vec<&str>>();
+            if kv.len() != 2 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("未知的write_pressure配置:{}", v),
+                ));
+            }
+            match kv[0] {
+                "warn" => pressure.warn = ConfigSize::from_str(kv[1])?.0 as usize,
+                "sustain" => pressure.sustain = ConfigDuration::from_str(kv[1])?.0,
+                "limit" => pressure.limit = Some(ConfigSize::from_str(kv[1])?.0 as usize),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("未知的write_pressure配置:{}", v),
+                    ))
+                }
+            }
+        }
+        Ok(pressure)
+    }
+}
+
+impl Display for ConfigWritePressure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "warn={} sustain={}",
+            ConfigSize::new(self.warn as u64),
+            ConfigDuration::new(self.sustain)
+        ))?;
+        if let Some(limit) = self.limit {
+            f.write_fmt(format_args!(" limit={}", ConfigSize::new(limit as u64)))?;
+        }
+        Ok(())
+    }
+}
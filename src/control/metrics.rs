@@ -0,0 +1,810 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/13 15:27:08
+
+use std::{
+    fmt::Display,
+    io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[cfg(feature = "metrics-statsd")]
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+#[cfg(any(feature = "metrics-statsd", feature = "metrics-otlp"))]
+use tokio::sync::Mutex;
+
+use crate::{
+    data::{CertData, ConnData, ConnLimitData, HandshakeData, TagData},
+    reverse::{Admission, ConcurrencyLimit, ConfigCompress, ConnectLimit, PipelineNotify, RetryBudget, SseBridge, WsLimit},
+    CenterServer, ConfigDuration, LocalPool, WritePressure,
+};
+#[cfg(any(feature = "metrics-statsd", feature = "metrics-otlp"))]
+use crate::{ProxyError, ProxyResult};
+
+#[cfg(any(feature = "metrics-statsd", feature = "metrics-otlp"))]
+use super::ControlServer;
+
+fn default_prefix() -> String {
+    "wmproxy".to_string()
+}
+
+fn default_interval() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(10))
+}
+
+/// 指标的输出方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetricsBackend {
+    /// 由`/metrics`拉取, 默认方式
+    #[default]
+    Prometheus,
+    /// 定时以UDP推送到statsd, 需开启`metrics-statsd`特性
+    Statsd,
+    /// 定时以OTLP/HTTP(json)推送, 需开启`metrics-otlp`特性
+    Otlp,
+}
+
+impl FromStr for MetricsBackend {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.trim().to_ascii_lowercase() {
+            "prometheus" => Ok(MetricsBackend::Prometheus),
+            "statsd" => Ok(MetricsBackend::Statsd),
+            "otlp" => Ok(MetricsBackend::Otlp),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("未知的metrics backend:{}", s),
+            )),
+        }
+    }
+}
+
+impl Display for MetricsBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetricsBackend::Prometheus => f.write_str("prometheus"),
+            MetricsBackend::Statsd => f.write_str("statsd"),
+            MetricsBackend::Otlp => f.write_str("otlp"),
+        }
+    }
+}
+
+/// 指标的输出配置, prometheus由`/metrics`拉取, statsd及otlp按interval定时推送
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub backend: MetricsBackend,
+    /// 推送的地址, statsd如`127.0.0.1:8125`, otlp如`http://127.0.0.1:4318/v1/metrics`
+    pub endpoint: Option<String>,
+    /// 推送的间隔
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_interval")]
+    pub interval: ConfigDuration,
+    /// 指标名的前缀, prometheus以`_`连接, statsd及otlp以`.`连接
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            backend: MetricsBackend::default(),
+            endpoint: None,
+            interval: default_interval(),
+            prefix: default_prefix(),
+        }
+    }
+}
+
+impl MetricsConfig {
+    /// 检查推送方式的特性及地址
+    pub fn check(&self) -> io::Result<()> {
+        let err = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        match self.backend {
+            MetricsBackend::Prometheus => return Ok(()),
+            MetricsBackend::Statsd if !cfg!(feature = "metrics-statsd") => {
+                return err("statsd需要开启metrics-statsd特性")
+            }
+            MetricsBackend::Otlp if !cfg!(feature = "metrics-otlp") => {
+                return err("otlp需要开启metrics-otlp特性")
+            }
+            _ => {}
+        }
+        if self.endpoint.is_none() {
+            return err("metrics推送需要配置endpoint");
+        }
+        if self.interval.0.is_zero() {
+            return err("metrics的interval不能为0");
+        }
+        Ok(())
+    }
+}
+
+/// 指标的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    /// 只增的计数
+    Counter,
+    /// 当前值
+    Gauge,
+    /// 分布统计, value为总次数
+    Histogram,
+}
+
+/// 延时类的默认分桶上界, 单位毫秒
+pub const LATENCY_BUCKETS: &[u64] = &[5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// 分布统计的采集结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramValue {
+    /// 分桶的上界, 单位毫秒
+    pub bounds: &'static [u64],
+    /// 小于等于各上界的累计次数
+    pub counts: Vec<u64>,
+    /// 所有耗时的总和, 单位微秒
+    pub sum_us: u64,
+}
+
+impl HistogramValue {
+    /// 各分桶的次数(非累计), 最后一个为超出所有上界的次数
+    #[cfg(any(feature = "metrics-statsd", feature = "metrics-otlp"))]
+    fn bucket_counts(&self, total: u64) -> Vec<u64> {
+        let mut last = 0;
+        let mut list = vec![];
+        for c in self.counts.iter().chain([&total]) {
+            list.push(c - last);
+            last = *c;
+        }
+        list
+    }
+}
+
+/// 延时的分布统计, 由MetricsRegistry注册, 同名的共享同一份数据
+pub struct Histogram {
+    name: &'static str,
+    bounds: &'static [u64],
+    /// 各分桶的次数, 最后一个为超出所有上界的次数
+    buckets: Vec<AtomicU64>,
+    /// 所有耗时的总和, 单位微秒
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn new(name: &'static str, bounds: &'static [u64]) -> Self {
+        Self {
+            name,
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    /// 记录一次耗时
+    pub fn observe(&self, cost: Duration) {
+        let us = cost.as_micros() as u64;
+        let index = self
+            .bounds
+            .iter()
+            .position(|b| us <= b * 1000)
+            .unwrap_or(self.bounds.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    fn value(&self) -> MetricValue {
+        let mut total = 0;
+        let mut counts = vec![];
+        for (i, b) in self.buckets.iter().enumerate() {
+            total += b.load(Ordering::Relaxed);
+            if i < self.bounds.len() {
+                counts.push(total);
+            }
+        }
+        let mut value = MetricValue::new(self.name, MetricKind::Histogram, total);
+        value.histogram = Some(HistogramValue {
+            bounds: self.bounds,
+            counts,
+            sum_us: self.sum_us.load(Ordering::Relaxed),
+        });
+        value
+    }
+}
+
+lazy_static! {
+    /// 已注册的分布统计, 按注册的顺序输出
+    static ref HISTOGRAMS: std::sync::Mutex<Vec<Arc<Histogram>>> = std::sync::Mutex::new(vec![]);
+}
+
+/// 采集的单个指标, 名字不含前缀
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricValue {
+    pub name: &'static str,
+    pub kind: MetricKind,
+    pub value: u64,
+    /// 区分同名指标的标签
+    pub labels: Vec<(&'static str, String)>,
+    /// 分布统计的分桶数据
+    pub histogram: Option<HistogramValue>,
+}
+
+impl MetricValue {
+    fn new(name: &'static str, kind: MetricKind, value: u64) -> Self {
+        Self { name, kind, value, labels: vec![], histogram: None }
+    }
+
+    fn with_label(mut self, key: &'static str, value: String) -> Self {
+        self.labels.push((key, value));
+        self
+    }
+}
+
+/// 所有指标的采集, prometheus的拉取及statsd/otlp的推送均基于同一份采集结果
+pub struct MetricsRegistry;
+
+impl MetricsRegistry {
+    /// 处理请求的耗时, 从收到请求到生成返回头
+    pub const REQUEST_DURATION: &'static str = "request_duration_seconds";
+    /// 与上游建立连接的耗时
+    pub const UPSTREAM_CONNECT_DURATION: &'static str = "upstream_connect_duration_seconds";
+
+    /// 获取或注册延时的分布统计, 同名的共享同一份数据
+    pub fn histogram(name: &'static str) -> Arc<Histogram> {
+        let mut list = HISTOGRAMS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(h) = list.iter().find(|h| h.name == name) {
+            return h.clone();
+        }
+        let h = Arc::new(Histogram::new(name, LATENCY_BUCKETS));
+        list.push(h.clone());
+        h
+    }
+
+    /// 记录一次耗时到对应的分布统计
+    pub fn observe(name: &'static str, cost: Duration) {
+        Self::histogram(name).observe(cost);
+    }
+
+    /// 采集当前的指标, services及uptime由控制端提供
+    pub fn collect(services: i32, uptime: u64) -> Vec<MetricValue> {
+        use MetricKind::*;
+        let mut list = vec![
+            MetricValue::new("services", Gauge, services.max(0) as u64),
+            MetricValue::new("connections", Gauge, ConnData::list().len() as u64),
+            MetricValue::new("uptime_seconds", Counter, uptime),
+            MetricValue::new("conn_limit_dropped_total", Counter, ConnLimitData::drop_count()),
+            MetricValue::new("tls_handshakes_active", Gauge, HandshakeData::active_count() as u64),
+            MetricValue::new("tls_handshakes_queued", Gauge, HandshakeData::queued_count() as u64),
+            MetricValue::new("tls_handshake_dropped_total", Counter, HandshakeData::drop_count()),
+            MetricValue::new("tunnels", Gauge, CenterServer::tunnel_count() as u64),
+            MetricValue::new("tunnel_rejected_total", Counter, CenterServer::reject_count()),
+            MetricValue::new("tunnel_write_high_water_bytes", Gauge, WritePressure::high_water()),
+            MetricValue::new("tunnel_write_backpressure_total", Counter, WritePressure::warn_count()),
+            MetricValue::new("admission_inflight_requests", Gauge, Admission::in_flight_total() as u64),
+            MetricValue::new("admission_shedding", Gauge, Admission::shedding_total() as u64),
+            MetricValue::new("admission_shed_total", Counter, Admission::shed_total()),
+            MetricValue::new("location_inflight_requests", Gauge, ConcurrencyLimit::in_flight_total() as u64),
+            MetricValue::new("location_queued_requests", Gauge, ConcurrencyLimit::queued_total() as u64),
+            MetricValue::new("location_rejected_requests_total", Counter, ConcurrencyLimit::rejected_total() as u64),
+            MetricValue::new("sse_bridge_sessions", Gauge, SseBridge::session_count() as u64),
+            MetricValue::new("ws_connections", Gauge, WsLimit::connection_total() as u64),
+            MetricValue::new("ws_rejected_total", Counter, WsLimit::rejected_total() as u64),
+            MetricValue::new("compress_active", Gauge, ConfigCompress::active_count() as u64),
+            MetricValue::new("compress_degraded_total", Counter, ConfigCompress::degrade_count()),
+            MetricValue::new("compress_skipped_total", Counter, ConfigCompress::skip_count()),
+            MetricValue::new("pipeline_rejected_total", Counter, PipelineNotify::rejected_total()),
+            MetricValue::new("local_pool_reuse_total", Counter, LocalPool::reuse_count()),
+            MetricValue::new("local_pool_miss_total", Counter, LocalPool::miss_count()),
+            MetricValue::new("local_pool_stale_total", Counter, LocalPool::stale_count()),
+            MetricValue::new("local_pool_idle", Gauge, LocalPool::idle_count() as u64),
+        ];
+        for (name, left) in CertData::expiry_list() {
+            list.push(MetricValue::new("cert_expiry_seconds", Gauge, left).with_label("cn", name));
+        }
+        // 同名的指标需相邻
+        let budgets = RetryBudget::list();
+        for (name, tokens, _) in &budgets {
+            list.push(MetricValue::new("retry_budget_tokens", Gauge, *tokens).with_label("upstream", name.clone()));
+        }
+        for (name, _, exhausted) in budgets {
+            list.push(MetricValue::new("retry_budget_exhausted_total", Counter, exhausted).with_label("upstream", name));
+        }
+        let connects = ConnectLimit::list();
+        for (addr, pending, _) in &connects {
+            list.push(MetricValue::new("upstream_pending_connects", Gauge, *pending as u64).with_label("upstream", addr.to_string()));
+        }
+        for (addr, _, rejected) in connects {
+            list.push(MetricValue::new("upstream_connect_rejected_total", Counter, rejected).with_label("upstream", addr.to_string()));
+        }
+        for (tag, count) in TagData::request_list() {
+            list.push(MetricValue::new("server_requests_total", Counter, count).with_label("server_tag", tag));
+        }
+        if let Ok(histograms) = HISTOGRAMS.lock() {
+            list.extend(histograms.iter().map(|h| h.value()));
+        }
+        list
+    }
+
+    /// prometheus的文本格式
+    pub fn to_prometheus(list: &[MetricValue], prefix: &str) -> String {
+        let mut data = String::new();
+        let mut last = "";
+        for m in list {
+            let kind = match m.kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Gauge => "gauge",
+                MetricKind::Histogram => "histogram",
+            };
+            let name = Self::full_name(prefix, "_", m.name);
+            // 同名不同标签的指标仅输出一次类型
+            if last != m.name {
+                data.push_str(&format!("# TYPE {} {}\n", name, kind));
+                last = m.name;
+            }
+            if let Some(h) = &m.histogram {
+                let buckets = h
+                    .bounds
+                    .iter()
+                    .map(|b| (*b as f64 / 1000.0).to_string())
+                    .zip(h.counts.iter().copied())
+                    .chain([("+Inf".to_string(), m.value)]);
+                for (le, count) in buckets {
+                    let labels = Self::prometheus_labels(&m.labels, Some(("le", le)));
+                    data.push_str(&format!("{}_bucket{} {}\n", name, labels, count));
+                }
+                let labels = Self::prometheus_labels(&m.labels, None);
+                data.push_str(&format!("{}_sum{} {}\n", name, labels, h.sum_us as f64 / 1_000_000.0));
+                data.push_str(&format!("{}_count{} {}\n", name, labels, m.value));
+            } else {
+                let labels = Self::prometheus_labels(&m.labels, None);
+                data.push_str(&format!("{}{} {}\n", name, labels, m.value));
+            }
+        }
+        data
+    }
+
+    /// prometheus的标签, 无标签时为空
+    fn prometheus_labels(labels: &[(&'static str, String)], extra: Option<(&str, String)>) -> String {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| (*k, v.as_str()))
+            .chain(extra.as_ref().map(|(k, v)| (*k, v.as_str())))
+            .map(|(k, v)| format!("{}=\"{}\"", k, Self::escape_label(v)))
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        }
+    }
+
+    fn escape_label(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+
+    fn full_name(prefix: &str, sep: &str, name: &str) -> String {
+        if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}{}{}", prefix, sep, name)
+        }
+    }
+}
+
+/// 定时推送指标, counter在statsd中以两次推送的差值发送, otlp中以累计值发送
+#[cfg(any(feature = "metrics-statsd", feature = "metrics-otlp"))]
+pub struct MetricsPusher {
+    /// 上次推送的counter值
+    #[cfg(feature = "metrics-statsd")]
+    last: HashMap<String, u64>,
+    /// 首次推送的时间, 作为otlp累计值的起始时间
+    #[cfg(feature = "metrics-otlp")]
+    start_nanos: u128,
+}
+
+#[cfg(any(feature = "metrics-statsd", feature = "metrics-otlp"))]
+impl MetricsPusher {
+    /// 单个UDP包的最大长度, 避免超过常见的MTU被分片
+    #[cfg(feature = "metrics-statsd")]
+    const MAX_PACKET: usize = 1400;
+
+    fn new() -> Self {
+        Self {
+            #[cfg(feature = "metrics-statsd")]
+            last: HashMap::new(),
+            #[cfg(feature = "metrics-otlp")]
+            start_nanos: Self::now_nanos(),
+        }
+    }
+
+    #[cfg(feature = "metrics-otlp")]
+    fn now_nanos() -> u128 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0)
+    }
+
+    /// 按控制端的当前配置定时推送, 配置重新加载后下一次推送生效
+    pub fn spawn(control: Arc<Mutex<ControlServer>>) {
+        tokio::spawn(async move {
+            let mut pusher = MetricsPusher::new();
+            loop {
+                let (config, list) = {
+                    let value = control.lock().await;
+                    let list = MetricsRegistry::collect(value.count, value.start.elapsed().as_secs());
+                    (value.option.metrics.clone(), list)
+                };
+                let config = config.unwrap_or_default();
+                if config.backend != MetricsBackend::Prometheus {
+                    if let Err(e) = pusher.push(&config, &list).await {
+                        log::warn!("推送metrics到{:?}失败:{:?}", config.endpoint, e);
+                    }
+                }
+                tokio::time::sleep(config.interval.0).await;
+            }
+        });
+    }
+
+    pub async fn push(&mut self, config: &MetricsConfig, list: &[MetricValue]) -> ProxyResult<()> {
+        let endpoint = match &config.endpoint {
+            Some(endpoint) => endpoint,
+            None => return Ok(()),
+        };
+        match config.backend {
+            #[cfg(feature = "metrics-statsd")]
+            MetricsBackend::Statsd => {
+                let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+                socket.connect(endpoint).await?;
+                for packet in self.encode_statsd(list, &config.prefix) {
+                    socket.send(packet.as_bytes()).await?;
+                }
+            }
+            #[cfg(feature = "metrics-otlp")]
+            MetricsBackend::Otlp => {
+                let body = self.encode_otlp(list, &config.prefix);
+                Self::send_otlp(endpoint, body).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// statsd的文本格式, 多个指标以`\n`分隔合并到同一个包中
+    #[cfg(feature = "metrics-statsd")]
+    pub fn encode_statsd(&mut self, list: &[MetricValue], prefix: &str) -> Vec<String> {
+        let mut packets = vec![];
+        let mut packet = String::new();
+        for m in list {
+            let name = MetricsRegistry::full_name(prefix, ".", m.name);
+            let mut lines = vec![];
+            match (m.kind, &m.histogram) {
+                (MetricKind::Gauge, _) => lines.push((format!("{}:{}|g", name, m.value), m.labels.clone())),
+                // 分布统计拆为次数, 耗时总和(毫秒)及各分桶的计数
+                (MetricKind::Histogram, Some(h)) => {
+                    let count = self.delta(&format!("{}.count", name), &m.labels, m.value);
+                    lines.push((format!("{}.count:{}|c", name, count), m.labels.clone()));
+                    let sum = self.delta(&format!("{}.sum_ms", name), &m.labels, h.sum_us / 1000);
+                    lines.push((format!("{}.sum_ms:{}|c", name, sum), m.labels.clone()));
+                    for (bound, value) in h.bounds.iter().zip(h.bucket_counts(m.value)) {
+                        let mut labels = m.labels.clone();
+                        labels.push(("le", bound.to_string()));
+                        let value = self.delta(&format!("{}.bucket", name), &labels, value);
+                        lines.push((format!("{}.bucket:{}|c", name, value), labels));
+                    }
+                }
+                _ => {
+                    // 同名不同标签的指标分别计算差值
+                    let delta = self.delta(m.name, &m.labels, m.value);
+                    lines.push((format!("{}:{}|c", name, delta), m.labels.clone()));
+                }
+            };
+            for (mut line, labels) in lines {
+                // 以DogStatsD的格式附带标签
+                if !labels.is_empty() {
+                    let tags = labels.iter().map(|(k, v)| format!("{}:{}", k, v)).collect::<Vec<_>>();
+                    line.push_str(&format!("|#{}", tags.join(",")));
+                }
+                if !packet.is_empty() && packet.len() + line.len() + 1 > Self::MAX_PACKET {
+                    packets.push(std::mem::take(&mut packet));
+                }
+                if !packet.is_empty() {
+                    packet.push('\n');
+                }
+                packet.push_str(&line);
+            }
+        }
+        if !packet.is_empty() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /// 与上次推送的差值, 计数被重置时为当前值
+    #[cfg(feature = "metrics-statsd")]
+    fn delta(&mut self, name: &str, labels: &[(&'static str, String)], value: u64) -> u64 {
+        let key = format!("{}{:?}", name, labels);
+        let last = self.last.insert(key, value).unwrap_or(0);
+        if value >= last {
+            value - last
+        } else {
+            value
+        }
+    }
+
+    /// OTLP/HTTP的json格式, counter为累计的单调Sum, 分布统计为累计的Histogram, 其它为Gauge
+    #[cfg(feature = "metrics-otlp")]
+    pub fn encode_otlp(&self, list: &[MetricValue], prefix: &str) -> String {
+        let now = Self::now_nanos().to_string();
+        let start = self.start_nanos.to_string();
+        let metrics = list
+            .iter()
+            .map(|m| {
+                let name = MetricsRegistry::full_name(prefix, ".", m.name);
+                let attributes = m
+                    .labels
+                    .iter()
+                    .map(|(k, v)| serde_json::json!({ "key": k, "value": { "stringValue": v } }))
+                    .collect::<Vec<_>>();
+                if let Some(h) = &m.histogram {
+                    return serde_json::json!({
+                        "name": name,
+                        "histogram": {
+                            "aggregationTemporality": 2,
+                            "dataPoints": [{
+                                "count": m.value.to_string(),
+                                "sum": h.sum_us as f64 / 1_000_000.0,
+                                "bucketCounts": h.bucket_counts(m.value).iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+                                "explicitBounds": h.bounds.iter().map(|b| *b as f64 / 1000.0).collect::<Vec<_>>(),
+                                "startTimeUnixNano": start,
+                                "timeUnixNano": now,
+                                "attributes": attributes
+                            }]
+                        }
+                    });
+                }
+                match m.kind {
+                    MetricKind::Gauge | MetricKind::Histogram => serde_json::json!({
+                        "name": name,
+                        "gauge": {
+                            "dataPoints": [{
+                                "asInt": m.value.to_string(),
+                                "timeUnixNano": now,
+                                "attributes": attributes
+                            }]
+                        }
+                    }),
+                    MetricKind::Counter => serde_json::json!({
+                        "name": name,
+                        "sum": {
+                            "aggregationTemporality": 2,
+                            "isMonotonic": true,
+                            "dataPoints": [{
+                                "asInt": m.value.to_string(),
+                                "startTimeUnixNano": start,
+                                "timeUnixNano": now,
+                                "attributes": attributes
+                            }]
+                        }
+                    }),
+                }
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({
+            "resourceMetrics": [{
+                "resource": {
+                    "attributes": [{ "key": "service.name", "value": { "stringValue": "wmproxy" } }]
+                },
+                "scopeMetrics": [{
+                    "scope": { "name": "wmproxy", "version": env!("CARGO_PKG_VERSION") },
+                    "metrics": metrics
+                }]
+            }]
+        })
+        .to_string()
+    }
+
+    #[cfg(feature = "metrics-otlp")]
+    async fn send_otlp(endpoint: &str, body: String) -> ProxyResult<()> {
+        use webparse::{HeaderName, Request, Url};
+        use wenmeng::{Body, Client};
+
+        let mut url = endpoint
+            .parse::<Url>()
+            .map_err(|_| ProxyError::Extension("otlp的endpoint无效"))?;
+        if url.port.is_none() {
+            url.port = Some(if url.scheme.is_https() { 443 } else { 80 });
+        }
+        let connect = url
+            .get_connect_url()
+            .ok_or(ProxyError::Extension("otlp的endpoint无效"))?;
+        let req = Request::builder()
+            .method(webparse::Method::Post)
+            .url(url.clone())
+            .header(HeaderName::HOST, connect.clone())
+            .header(HeaderName::CONTENT_TYPE, "application/json")
+            .header(HeaderName::CONTENT_LENGTH, body.len())
+            .body(Body::new_text(body))
+            .map_err(|_| ProxyError::Extension("build otlp request error"))?;
+        let stream = crate::HealthCheck::connect(&connect).await?;
+        let client = if url.scheme.is_https() {
+            Client::builder().url(url)?.connect_tls_by_stream(stream).await?
+        } else {
+            Client::builder().connect_by_stream(stream).await?
+        };
+        let mut res = client.send_now(req).await?;
+        res.body_mut().wait_all().await;
+        if !res.status().is_success() {
+            log::warn!("推送otlp的返回状态码为{}", res.status());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{Histogram, MetricKind, MetricValue, MetricsBackend, MetricsConfig, MetricsRegistry};
+
+    fn sample() -> Vec<MetricValue> {
+        vec![
+            MetricValue::new("connections", MetricKind::Gauge, 3),
+            MetricValue::new("conn_limit_dropped_total", MetricKind::Counter, 10),
+        ]
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let data = MetricsRegistry::to_prometheus(&sample(), "wmproxy");
+        assert_eq!(
+            data,
+            "# TYPE wmproxy_connections gauge\nwmproxy_connections 3\n# TYPE wmproxy_conn_limit_dropped_total counter\nwmproxy_conn_limit_dropped_total 10\n"
+        );
+        let list = MetricsRegistry::collect(1, 5);
+        assert!(list.iter().any(|m| m.name == "uptime_seconds" && m.value == 5));
+
+        // 同名指标按标签区分
+        let list = vec![
+            MetricValue::new("cert_expiry_seconds", MetricKind::Gauge, 86400).with_label("cn", "a.com".to_string()),
+            MetricValue::new("cert_expiry_seconds", MetricKind::Gauge, 0).with_label("cn", "b\"c".to_string()),
+        ];
+        assert_eq!(
+            MetricsRegistry::to_prometheus(&list, ""),
+            "# TYPE cert_expiry_seconds gauge\ncert_expiry_seconds{cn=\"a.com\"} 86400\ncert_expiry_seconds{cn=\"b\\\"c\"} 0\n"
+        );
+
+        let config = toml::from_str::<MetricsConfig>("backend = \"statsd\"\nprefix = \"edge\"").unwrap();
+        assert_eq!(config.backend, MetricsBackend::Statsd);
+        assert_eq!(config.interval.0.as_secs(), 10);
+        // 未配置endpoint或未开启特性时无法推送
+        assert!(config.check().is_err());
+        assert!(MetricsConfig::default().check().is_ok());
+        assert!("influx".parse::<MetricsBackend>().is_err());
+    }
+
+    fn latency() -> MetricValue {
+        let h = Histogram::new("latency_seconds", &[10, 100]);
+        h.observe(Duration::from_millis(3));
+        h.observe(Duration::from_millis(10));
+        h.observe(Duration::from_millis(50));
+        h.observe(Duration::from_millis(500));
+        h.value()
+    }
+
+    #[test]
+    fn histogram_text() {
+        let value = latency();
+        assert_eq!(value.value, 4);
+        let h = value.histogram.as_ref().unwrap();
+        assert_eq!(h.counts, vec![2, 3]);
+        assert_eq!(h.sum_us, 563_000);
+        assert_eq!(
+            MetricsRegistry::to_prometheus(&[value], "wmproxy"),
+            "# TYPE wmproxy_latency_seconds histogram\n\
+            wmproxy_latency_seconds_bucket{le=\"0.01\"} 2\n\
+            wmproxy_latency_seconds_bucket{le=\"0.1\"} 3\n\
+            wmproxy_latency_seconds_bucket{le=\"+Inf\"} 4\n\
+            wmproxy_latency_seconds_sum 0.563\n\
+            wmproxy_latency_seconds_count 4\n"
+        );
+
+        // 同名的共享同一份数据, 并出现在采集结果中
+        let a = MetricsRegistry::histogram("test_shared_seconds");
+        let b = MetricsRegistry::histogram("test_shared_seconds");
+        assert!(Arc::ptr_eq(&a, &b));
+        MetricsRegistry::observe("test_shared_seconds", Duration::from_millis(1));
+        let list = MetricsRegistry::collect(1, 5);
+        let m = list.iter().find(|m| m.name == "test_shared_seconds").unwrap();
+        assert_eq!((m.kind, m.value), (MetricKind::Histogram, 1));
+    }
+
+    #[cfg(feature = "metrics-statsd")]
+    #[tokio::test]
+    async fn statsd_push() {
+        use super::MetricsPusher;
+
+        let mut pusher = MetricsPusher::new();
+        let packets = pusher.encode_statsd(&sample(), "edge");
+        assert_eq!(packets, vec!["edge.connections:3|g\nedge.conn_limit_dropped_total:10|c".to_string()]);
+        // counter发送与上次的差值
+        let mut list = sample();
+        list[1].value = 14;
+        let packets = pusher.encode_statsd(&list, "edge");
+        assert_eq!(packets[0], "edge.connections:3|g\nedge.conn_limit_dropped_total:4|c");
+
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config = MetricsConfig::default();
+        config.backend = MetricsBackend::Statsd;
+        config.endpoint = Some(server.local_addr().unwrap().to_string());
+        config.prefix = "edge".to_string();
+        assert!(config.check().is_ok());
+        list[1].value = 20;
+        pusher.push(&config, &list).await.unwrap();
+        let mut buf = [0u8; 1500];
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"edge.connections:3|g\nedge.conn_limit_dropped_total:6|c");
+
+        // 同名不同标签的counter分别计算差值
+        let tagged = |a: u64, b: u64| {
+            vec![
+                MetricValue::new("server_requests_total", MetricKind::Counter, a).with_label("server_tag", "a".to_string()),
+                MetricValue::new("server_requests_total", MetricKind::Counter, b).with_label("server_tag", "b".to_string()),
+            ]
+        };
+        pusher.encode_statsd(&tagged(5, 7), "");
+        let packets = pusher.encode_statsd(&tagged(6, 10), "");
+        assert_eq!(packets[0], "server_requests_total:1|c|#server_tag:a\nserver_requests_total:3|c|#server_tag:b");
+
+        // 分布统计拆为次数, 总和及各分桶的计数
+        let packets = pusher.encode_statsd(&[latency()], "");
+        assert_eq!(
+            packets[0],
+            "latency_seconds.count:4|c\nlatency_seconds.sum_ms:563|c\n\
+            latency_seconds.bucket:2|c|#le:10\nlatency_seconds.bucket:1|c|#le:100"
+        );
+    }
+
+    #[cfg(feature = "metrics-otlp")]
+    #[test]
+    fn otlp_json() {
+        use super::MetricsPusher;
+
+        let pusher = MetricsPusher::new();
+        let data = pusher.encode_otlp(&sample(), "edge");
+        let value: serde_json::Value = serde_json::from_str(&data).unwrap();
+        let metrics = &value["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "edge.connections");
+        assert_eq!(metrics[0]["gauge"]["dataPoints"][0]["asInt"], "3");
+        assert_eq!(metrics[1]["sum"]["isMonotonic"], true);
+        assert_eq!(metrics[1]["sum"]["dataPoints"][0]["asInt"], "10");
+
+        let data = pusher.encode_otlp(&[latency()], "");
+        let value: serde_json::Value = serde_json::from_str(&data).unwrap();
+        let point = &value["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0]["histogram"]["dataPoints"][0];
+        assert_eq!(point["count"], "4");
+        assert_eq!(point["bucketCounts"], serde_json::json!(["2", "1", "1"]));
+        assert_eq!(point["explicitBounds"], serde_json::json!([0.01, 0.1]));
+    }
+}
@@ -10,6 +10,12 @@
 // -----
 // Created Date: 2023/10/25 03:36:28
 
+mod admin;
+mod metrics;
 mod server;
 
+pub use admin::{AdminConfig, ControlRole};
+pub use metrics::{Histogram, HistogramValue, MetricKind, MetricValue, MetricsBackend, MetricsConfig, MetricsRegistry};
+#[cfg(any(feature = "metrics-statsd", feature = "metrics-otlp"))]
+pub use metrics::MetricsPusher;
 pub use server::ControlServer;
\ No newline at end of file
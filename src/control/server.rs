@@ -10,9 +10,9 @@
 // -----
 // Created Date: 2023/10/25 03:36:36
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 
-use crate::{arg, ConfigOption, Helper, ProxyResult, WMCore};
+use crate::{arg, data::{ConnData, PauseData, ShutdownData}, reverse::{ConfigDebugDump, ConfigDuplicate, ConfigFault}, CenterState, ConfigOption, Helper, MetricsRegistry, ProxyResult, WMCore};
 use async_trait::async_trait;
 use tokio::{
     net::TcpListener,
@@ -21,32 +21,65 @@ use tokio::{
         Mutex,
     },
 };
+use tokio_rustls::TlsAcceptor;
 use webparse::{HeaderName, Request, Response};
 use wenmeng::{Body, HttpTrait, ProtResult, RecvRequest, RecvResponse, Server};
 
+use super::{AdminConfig, ControlRole};
+
 /// 控制端，可以对配置进行热更新
 pub struct ControlServer {
     /// 控制端当前的配置文件，如果部分修改将直接修改数据进行重启
-    option: ConfigOption,
+    pub(super) option: ConfigOption,
     /// 通知服务进行关闭的Sender，服务相关如果收到该消息则停止Accept
     server_sender_close: Option<Sender<()>>,
     /// 通知中心服务的Sender，每个服务拥有一个该Sender，可反向通知中控关闭
     control_sender_close: Sender<()>,
     /// 通知中心服务的Receiver，收到一次则将当前的引用计数-1，如果为0则表示需要关闭服务器
     control_receiver_close: Option<Receiver<()>>,
+    /// 当前服务退出时移交内网穿透状态的Sender, 热加载且proxy的配置未变更时设置
+    center_handover: Arc<Mutex<Option<Sender<CenterState>>>>,
     /// 服务的引用计数
-    count: i32,
+    pub(super) count: i32,
+    /// 控制端的启动时间
+    pub(super) start: Instant,
 }
 
 struct Operate {
     control: Arc<Mutex<ControlServer>>,
+    /// 监听端口的角色, 决定可访问的路由
+    role: ControlRole,
+    /// 管理端口的验证信息
+    admin: Option<AdminConfig>,
 }
 #[async_trait]
 impl HttpTrait for Operate {
     async fn operate(&mut self, req: &mut RecvRequest) -> ProtResult<RecvResponse> {
         // body的内容可能重新解密又再重新再加过密, 后续可考虑直接做数据
+        // path()包含查询参数, 路由只按url中的路径匹配
+        if !self.role.is_allow(&req.url().path) {
+            return Ok(Response::status404()
+                .body("路由不存在")
+                .unwrap()
+                .into_type());
+        }
+        if let Some(admin) = &self.admin {
+            if !admin.check_auth(req) {
+                return Ok(Response::builder()
+                    .status(401)
+                    .header("WWW-Authenticate", "Basic realm=\"wmproxy\"")
+                    .body("")
+                    .unwrap()
+                    .into_type());
+            }
+        }
         let mut value = ControlServer::inner_operate(req, &mut self.control).await?;
-        value.headers_mut().insert("server", "wmproxy");
+        match &self.control.lock().await.option.http {
+            Some(http) => http.comm.rewrite_response_server(&mut value),
+            None => {
+                value.headers_mut().insert("server", "wmproxy");
+            }
+        }
         Ok(value)
     }
 }
@@ -59,36 +92,99 @@ impl ControlServer {
             server_sender_close: None,
             control_sender_close: sender,
             control_receiver_close: Some(receiver),
+            center_handover: Arc::new(Mutex::new(None)),
             count: 0,
+            start: Instant::now(),
         }
     }
 
     pub async fn start_serve(mut self) -> ProxyResult<()> {
         let option = self.option.clone();
-        self.inner_start_server(option).await?;
-        Self::start_control(Arc::new(Mutex::new(self))).await?;
+        self.inner_start_server(option, false).await?;
+        let control = Arc::new(Mutex::new(self));
+        Self::listen_terminate(control.clone());
+        #[cfg(any(feature = "metrics-statsd", feature = "metrics-otlp"))]
+        crate::MetricsPusher::spawn(control.clone());
+        Self::start_control(control.clone()).await?;
+        // 服务已停止监听, 等待剩余的连接结束
+        let timeout = control.lock().await.option.shutdown_timeout.clone();
+        if let Some(timeout) = timeout {
+            ShutdownData::drain(timeout.into()).await;
+        }
         Ok(())
     }
 
+    /// 收到SIGTERM时与`/stop`一致, 通知服务退出
+    #[cfg(unix)]
+    fn listen_terminate(control: Arc<Mutex<ControlServer>>) {
+        use tokio::signal::unix::{signal, SignalKind};
+        tokio::spawn(async move {
+            let mut term = match signal(SignalKind::terminate()) {
+                Ok(term) => term,
+                Err(e) => {
+                    log::warn!("监听SIGTERM信号失败:{:?}", e);
+                    return;
+                }
+            };
+            while term.recv().await.is_some() {
+                log::info!("收到SIGTERM信号, 准备退出");
+                if let Some(sender) = &control.lock().await.server_sender_close {
+                    let _ = sender.send(()).await;
+                }
+            }
+        });
+    }
+
+    #[cfg(not(unix))]
+    fn listen_terminate(_control: Arc<Mutex<ControlServer>>) {}
+
     pub async fn do_restart_serve(&mut self) -> ProxyResult<()> {
         let option = arg::parse_env().await?;
         Helper::try_init_log(&option);
-        self.inner_start_server(option).await?;
+        // 内网穿透的配置未变更时保留已连接的客户端, 仅重建其它的服务
+        let keep_center = Self::is_same_center(&self.option, &option);
+        self.inner_start_server(option.clone(), keep_center).await?;
+        // 记录当前的配置, 重新打开日志时按此配置
+        self.option = option;
         Ok(())
     }
 
-    async fn inner_start_server(&mut self, option: ConfigOption) -> ProxyResult<()> {
+    /// 内网穿透相关的配置是否一致, 仅映射不同时同样保留, 由客户端通知服务端变更的映射
+    pub(crate) fn is_same_center(old: &ConfigOption, new: &ConfigOption) -> bool {
+        let value = |option: &ConfigOption| {
+            option.proxy.clone().map(|mut proxy| {
+                proxy.mappings.clear();
+                serde_json::to_value(&proxy).ok()
+            })
+        };
+        old.proxy.is_some() && value(old) == value(new)
+    }
+
+    async fn inner_start_server(&mut self, option: ConfigOption, keep_center: bool) -> ProxyResult<()> {
         let sender = self.control_sender_close.clone();
         let (sender_no_listen, receiver_no_listen) = channel::<()>(1);
+        let mut proxy = WMCore::new(option);
+        if keep_center && self.server_sender_close.is_some() {
+            let (center_sender, center_receiver) = channel::<CenterState>(1);
+            *self.center_handover.lock().await = Some(center_sender);
+            proxy.set_center_receiver(center_receiver);
+        }
+        // 先完成监听的绑定, 失败时返回错误, 原有的服务继续运行
+        proxy.ready_serve().await?;
         let sender_close = self.server_sender_close.take();
+        // 每个服务拥有独立的移交位置, 由下一次热加载决定是否移交
+        let handover = Arc::new(Mutex::new(None));
+        self.center_handover = handover.clone();
         // 每次启动的时候将让控制计数+1
         self.count += 1;
         tokio::spawn(async move {
-            let mut proxy = WMCore::new(option);
             // 将上一个进程的关闭权限交由下一个服务，只有等下一个服务准备完毕的时候才能关闭上一个服务
-            if let Err(e) = proxy.start_serve(receiver_no_listen, sender_close).await {
+            if let Err(e) = proxy.run_serve(receiver_no_listen, sender_close).await {
                 log::info!("处理失败服务进程失败: {:?}", e);
             }
+            if let Some(center_sender) = handover.lock().await.take() {
+                let _ = center_sender.send(proxy.take_center()).await;
+            }
             // 每次退出的时候将让控制计数-1，减到0则退出
             let _ = sender.send(()).await;
         });
@@ -101,15 +197,36 @@ impl ControlServer {
         data: &mut Arc<Mutex<ControlServer>>,
     ) -> ProtResult<Response<Body>> {
         let mut value = data.lock().await;
-        match &**req.path() {
+        let path = req.url().path.clone();
+        match &*path {
             "/reload" => {
-                // 将重新启动服务器
-                let _ = value.do_restart_serve().await;
+                // 将重新启动服务器, 失败时保留原有的服务
+                if let Err(e) = value.do_restart_serve().await {
+                    log::warn!("重新加载配置失败:{:?}", e);
+                    return Ok(Response::status500()
+                        .body("重新加载配置失败")
+                        .unwrap()
+                        .into_type());
+                }
                 return Ok(Response::text()
                     .body("重新加载配置成功")
                     .unwrap()
                     .into_type());
             }
+            "/reopen-logs" => {
+                // 仅重新打开日志文件, 不重启服务
+                if !Helper::reopen_logs(&value.option) {
+                    return Ok(Response::status500()
+                        .body("日志未初始化")
+                        .unwrap()
+                        .into_type());
+                }
+                log::info!("已重新打开日志文件");
+                return Ok(Response::text()
+                    .body("重新打开日志成功")
+                    .unwrap()
+                    .into_type());
+            }
             "/stop" => {
                 // 通知控制端关闭，控制端阻塞主线程，如果控制端退出后进程退出
                 if let Some(sender) = &value.server_sender_close {
@@ -117,6 +234,158 @@ impl ControlServer {
                 }
                 return Ok(Response::text().body("关闭进程成功").unwrap().into_type());
             }
+            "/pause" | "/resume" => {
+                // 暂停时所有监听停止接收新连接, 已建立的连接继续处理, 可在发布前等待连接结束
+                let paused = path == "/pause";
+                PauseData::set_paused(paused);
+                let msg = if paused { "已暂停接收新连接" } else { "已恢复接收新连接" };
+                return Ok(Response::text().body(msg).unwrap().into_type());
+            }
+            "/status" => {
+                let status = serde_json::json!({
+                    "services": value.count,
+                    "connections": ConnData::list().len(),
+                    "uptime": value.start.elapsed().as_secs(),
+                    "paused": PauseData::is_paused(),
+                });
+                return Ok(Response::text()
+                    .header(HeaderName::CONTENT_TYPE, "application/json; charset=utf-8")
+                    .body(status.to_string())
+                    .unwrap()
+                    .into_type());
+            }
+            "/metrics" => {
+                let prefix = value
+                    .option
+                    .metrics
+                    .as_ref()
+                    .map(|m| m.prefix.clone())
+                    .unwrap_or_else(|| "wmproxy".to_string());
+                let list = MetricsRegistry::collect(value.count, value.start.elapsed().as_secs());
+                let data = MetricsRegistry::to_prometheus(&list, &prefix);
+                return Ok(Response::text()
+                    .header(HeaderName::CONTENT_TYPE, "text/plain; version=0.0.4")
+                    .body(data)
+                    .unwrap()
+                    .into_type());
+            }
+            "/connections" => {
+                if let Ok(data) = serde_json::to_string_pretty(&ConnData::list()) {
+                    return Ok(Response::text()
+                        .header(HeaderName::CONTENT_TYPE, "application/json; charset=utf-8")
+                        .body(data)
+                        .unwrap()
+                        .into_type());
+                }
+            }
+            "/duplicate" => {
+                // 流量复制的总开关, 带enable参数时修改, 否则返回当前状态
+                match Self::query_value(req, "enable").as_deref() {
+                    Some("true") | Some("1") => ConfigDuplicate::set_enable(true),
+                    Some("false") | Some("0") => ConfigDuplicate::set_enable(false),
+                    Some(_) => {
+                        return Ok(Response::text()
+                            .status(400)
+                            .body("enable参数仅支持true/false/1/0")
+                            .unwrap()
+                            .into_type());
+                    }
+                    None => {}
+                }
+                let status = if ConfigDuplicate::is_enable() {
+                    "流量复制已开启"
+                } else {
+                    "流量复制已停止, 进行中的复制请求已中止"
+                };
+                return Ok(Response::text().body(status).unwrap().into_type());
+            }
+            "/upstreams" => {
+                // 所有上游的健康状态, 可通过name参数过滤
+                let name = Self::query_value(req, "name");
+                let list = value
+                    .option
+                    .get_upstreams()
+                    .iter()
+                    .filter(|u| name.as_ref().map(|n| n == &u.name).unwrap_or(true))
+                    .map(|u| u.status())
+                    .collect::<Vec<_>>();
+                let data = serde_json::to_string_pretty(&list).unwrap_or_default();
+                return Ok(Response::text()
+                    .header(HeaderName::CONTENT_TYPE, "application/json; charset=utf-8")
+                    .body(data)
+                    .unwrap()
+                    .into_type());
+            }
+            "/debug-dump" => {
+                // 运行时开启调试输出, rule为location的规则, 不带rule时作用于所有的location
+                // enable=reset时移除运行时的开关, 恢复为配置中的值
+                let rule = Self::query_value(req, "rule");
+                let enable = match Self::query_value(req, "enable").as_deref() {
+                    Some("true") | Some("1") => Some(Some(true)),
+                    Some("false") | Some("0") => Some(Some(false)),
+                    Some("reset") => Some(None),
+                    Some(_) => {
+                        return Ok(Response::text()
+                            .status(400)
+                            .body("enable参数仅支持true/false/1/0")
+                            .unwrap()
+                            .into_type());
+                    }
+                    None => None,
+                };
+                if let Some(enable) = enable {
+                    ConfigDebugDump::set_enable(rule.as_deref(), enable);
+                }
+                let data = serde_json::json!(ConfigDebugDump::switch_list());
+                return Ok(Response::text()
+                    .header(HeaderName::CONTENT_TYPE, "application/json; charset=utf-8")
+                    .body(data.to_string())
+                    .unwrap()
+                    .into_type());
+            }
+            "/fault-inject" => {
+                // 运行时开启测试用的故障注入, 仅对配置了fault的location生效, 不带rule时作用于所有的location
+                // enable=reset时移除运行时的开关, 恢复为配置中的值
+                let rule = Self::query_value(req, "rule");
+                let enable = match Self::query_value(req, "enable").as_deref() {
+                    Some("true") | Some("1") => Some(Some(true)),
+                    Some("false") | Some("0") => Some(Some(false)),
+                    Some("reset") => Some(None),
+                    Some(_) => {
+                        return Ok(Response::text()
+                            .status(400)
+                            .body("enable参数仅支持true/false/1/0")
+                            .unwrap()
+                            .into_type());
+                    }
+                    None => None,
+                };
+                if let Some(enable) = enable {
+                    ConfigFault::set_enable(rule.as_deref(), enable);
+                }
+                let data = serde_json::json!(ConfigFault::switch_list());
+                return Ok(Response::text()
+                    .header(HeaderName::CONTENT_TYPE, "application/json; charset=utf-8")
+                    .body(data.to_string())
+                    .unwrap()
+                    .into_type());
+            }
+            "/close-connection" => {
+                // 强制关闭指定id的连接，id来源于/connections列表
+                let id = Self::query_value(req, "id").and_then(|v| v.parse::<u64>().ok());
+                if let Some(id) = id {
+                    if ConnData::close(id) {
+                        return Ok(Response::text()
+                            .body("关闭连接成功")
+                            .unwrap()
+                            .into_type());
+                    }
+                }
+                return Ok(Response::status404()
+                    .body("连接不存在")
+                    .unwrap()
+                    .into_type());
+            }
             "/now" => {
                 if let Ok(data) = serde_json::to_string_pretty(&value.option) {
                     return Ok(Response::text()
@@ -128,8 +397,6 @@ impl ControlServer {
             }
             _ => {}
         };
-        if req.path() == "/reload" {}
-
         if req.path() == "/stop" {
             // 通知控制端关闭，控制端阻塞主线程，如果控制端退出后进程退出
             if let Some(sender) = &value.server_sender_close {
@@ -144,6 +411,17 @@ impl ControlServer {
             .into_type());
     }
 
+    fn query_value(req: &Request<Body>, key: &str) -> Option<String> {
+        let query = req.url().query.as_ref()?;
+        for kv in query.split('&') {
+            let mut kv = kv.splitn(2, '=');
+            if kv.next() == Some(key) {
+                return kv.next().map(|v| v.to_string());
+            }
+        }
+        None
+    }
+
     async fn receiver_await(receiver: &mut Option<Receiver<()>>) -> Option<()> {
         if receiver.is_some() {
             receiver.as_mut().unwrap().recv().await
@@ -154,7 +432,70 @@ impl ControlServer {
         }
     }
 
+    /// 独立的管理端口, 仅提供查看相关的路由
+    async fn start_admin(control: Arc<Mutex<ControlServer>>, admin: AdminConfig) -> ProxyResult<()> {
+        let accept = admin.get_tls_accept()?;
+        log::info!("管理端口绑定：{:?}，提供管理功能。", admin.bind_addr);
+        let strict = control.lock().await.option.strict_bind;
+        let listener = match TcpListener::bind(admin.bind_addr).await {
+            Ok(listener) => listener,
+            Err(e) => return Ok(Helper::bind_failed(admin.bind_addr, e, strict)?),
+        };
+        tokio::spawn(async move {
+            loop {
+                let (conn, addr) = match listener.accept().await {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                let cc = control.clone();
+                let accept = accept.clone();
+                let admin = admin.clone();
+                tokio::spawn(async move {
+                    let operate = Box::new(Operate {
+                        control: cc,
+                        role: ControlRole::Admin,
+                        admin: Some(admin),
+                    });
+                    let ret = Self::serve_conn(conn, addr, accept, operate).await;
+                    if let Err(e) = ret {
+                        log::info!("管理端口：处理信息时发生错误：{:?}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    async fn serve_conn(
+        conn: tokio::net::TcpStream,
+        addr: std::net::SocketAddr,
+        accept: Option<TlsAcceptor>,
+        operate: Box<Operate>,
+    ) -> ProxyResult<()> {
+        if let Some(accept) = accept {
+            let stream = accept.accept(conn).await?;
+            let mut server = Server::new(stream, Some(addr));
+            server.set_callback_http(operate);
+            server.incoming().await?;
+        } else {
+            let mut server = Server::new(conn, Some(addr));
+            server.set_callback_http(operate);
+            server.incoming().await?;
+        }
+        Ok(())
+    }
+
     pub async fn start_control(control: Arc<Mutex<ControlServer>>) -> ProxyResult<()> {
+        let (admin, role) = {
+            let value = control.lock().await;
+            match value.option.admin.clone() {
+                Some(admin) => (Some(admin), ControlRole::Control),
+                None => (None, ControlRole::All),
+            }
+        };
+        if let Some(admin) = admin {
+            Self::start_admin(control.clone(), admin).await?;
+        }
         let listener = {
             let value = &mut control.lock().await;
             if value.option.disable_control {
@@ -164,12 +505,11 @@ impl ControlServer {
             }
             log::info!("控制端口绑定：{:?}，提供中控功能。", value.option.control);
             match TcpListener::bind(value.option.control).await {
-                Ok(tcp) => tcp,
-                Err(_) => {
-                    log::info!("控制端口绑定失败：{}，请配置不同端口。", value.option.control);
-                    let pending = std::future::pending();
-                    let () = pending.await;
-                    return Ok(());
+                Ok(tcp) => Some(tcp),
+                Err(e) => {
+                    // 未绑定控制端口时仅等待服务退出
+                    Helper::bind_failed(value.option.control, e, value.option.strict_bind)?;
+                    None
                 }
             }
         };
@@ -181,13 +521,15 @@ impl ControlServer {
             };
 
             tokio::select! {
-                Ok((conn, addr)) = listener.accept() => {
+                Ok((conn, addr)) = async { listener.as_ref().unwrap().accept().await }, if listener.is_some() => {
                     log::info!("控制端口请求：{:?}，开始处理。", addr);
                     let cc = control.clone();
                     tokio::spawn(async move {
                         let mut server = Server::new(conn, Some(addr));
                         server.set_callback_http(Box::new(Operate {
-                            control: cc
+                            control: cc,
+                            role,
+                            admin: None,
                         }));
                         if let Err(e) = server.incoming().await {
                             log::info!("控制中心：处理信息时发生错误：{:?}", e);
@@ -210,3 +552,109 @@ impl ControlServer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{io::AsyncWriteExt, sync::Mutex};
+    use wenmeng::Server;
+
+    use super::{ControlServer, Operate};
+    use crate::{
+        control::ControlRole,
+        test_util::{load_http, read_full_response},
+        ConfigOption, ProtFrame, TransStream,
+    };
+
+    async fn request(path: &str) -> (String, String) {
+        request_with(ConfigOption::default(), path).await
+    }
+
+    /// 以HTTP请求访问控制端的路由, 返回状态行所在的返回头及body
+    async fn request_with(option: ConfigOption, path: &str) -> (String, String) {
+        let control = Arc::new(Mutex::new(ControlServer::new(option)));
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut server = Server::new(stream, None);
+            server.set_callback_http(Box::new(Operate {
+                control,
+                role: ControlRole::All,
+                admin: None,
+            }));
+            let _ = server.incoming().await;
+        });
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        client.write_all(req.as_bytes()).await.unwrap();
+        read_full_response(&mut client).await
+    }
+
+    #[tokio::test]
+    async fn close_connection_by_query() {
+        let (stream, _peer) = tokio::io::duplex(1024);
+        let (in_sender, _in_receiver) = tokio::sync::mpsc::channel::<ProtFrame>(10);
+        let (_out_sender, out_receiver) = tokio::sync::mpsc::channel::<ProtFrame>(10);
+        let trans = TransStream::new(stream, 1, in_sender, out_receiver);
+        let id = trans.conn_id();
+
+        let (head, body) = request(&format!("/close-connection?id={}", id)).await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert_eq!(body, "关闭连接成功");
+        let (head, body) = request(&format!("/close-connection?id={}", id)).await;
+        assert!(head.starts_with("http/1.1 404"), "{}", head);
+        assert_eq!(body, "连接不存在");
+    }
+
+    #[tokio::test]
+    async fn duplicate_by_query() {
+        let (head, body) = request("/duplicate?enable=maybe").await;
+        assert!(head.starts_with("http/1.1 400"), "{}", head);
+        assert_eq!(body, "enable参数仅支持true/false/1/0");
+        let (head, body) = request("/duplicate?enable=true").await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert_eq!(body, "流量复制已开启");
+    }
+
+    #[tokio::test]
+    async fn debug_dump_by_query() {
+        let (head, body) = request("/debug-dump?rule=/control-dump&enable=true").await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        let list: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(list["/control-dump"], true);
+        let (_, body) = request("/debug-dump?rule=/control-dump&enable=reset").await;
+        let list: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(list.get("/control-dump").is_none());
+    }
+
+    #[tokio::test]
+    async fn upstreams_by_query() {
+        let mut option = ConfigOption::default();
+        option.http = Some(load_http(
+            r#"
+            [[upstream]]
+            name = "a"
+            server = [{ addr = "127.0.0.1:8080" }]
+            [[upstream]]
+            name = "b"
+            server = [{ addr = "127.0.0.1:8081" }]
+            "#,
+        ));
+        let (head, body) = request_with(option, "/upstreams?name=b").await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        let list: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(list.as_array().unwrap().len(), 1);
+        assert_eq!(list[0]["name"], "b");
+        assert_eq!(list[0]["servers"][0]["addr"], "127.0.0.1:8081");
+    }
+
+    #[tokio::test]
+    async fn fault_inject_by_query() {
+        let (head, body) = request("/fault-inject?rule=/control-fault&enable=true").await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        let list: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(list["/control-fault"], true);
+        let (_, body) = request("/fault-inject?rule=/control-fault&enable=reset").await;
+        let list: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(list.get("/control-fault").is_none());
+    }
+}
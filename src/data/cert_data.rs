@@ -0,0 +1,141 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/22 15:08:31
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use rustls::pki_types::CertificateDer;
+
+use crate::CertLoader;
+
+lazy_static! {
+    // 已加载的证书, CN对应到期时间的unix时间戳
+    static ref CERTS: RwLock<HashMap<String, i64>> = RwLock::new(HashMap::new());
+}
+
+/// 默认到期前14天开始告警
+const DEFAULT_WARN: u64 = 14 * 24 * 3600;
+/// 每天检查一次
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// 到期前开始告警的秒数
+static WARN_SECS: AtomicU64 = AtomicU64::new(DEFAULT_WARN);
+/// 是否已启动定时检查
+static CHECKING: AtomicBool = AtomicBool::new(false);
+
+/// 已加载TLS证书的到期检查, 剩余时间不足时输出警告, 并以`cert_expiry_seconds`提供给metrics
+pub struct CertData;
+
+impl CertData {
+    /// 设置到期前开始告警的时间, 未配置时为14天
+    pub fn set_config(warn: Option<Duration>) {
+        let secs = warn.map(|w| w.as_secs()).unwrap_or(DEFAULT_WARN);
+        WARN_SECS.store(secs, Ordering::Relaxed);
+    }
+
+    /// 记录证书链中的首个证书, 相同CN的证书以最后加载的为准
+    pub fn register(certs: &[CertificateDer<'_>]) {
+        let cert = match certs.first() {
+            Some(cert) => cert,
+            None => return,
+        };
+        match CertLoader::parse_expiry(cert) {
+            Ok((name, not_after)) => {
+                if let Ok(mut certs) = CERTS.write() {
+                    certs.insert(name, not_after);
+                }
+            }
+            Err(e) => log::warn!("解析证书的到期时间失败:{:?}", e),
+        }
+    }
+
+    /// 各证书距离到期的秒数, 已过期时为0, 按CN排序
+    pub fn expiry_list() -> Vec<(String, u64)> {
+        let now = chrono::Utc::now().timestamp();
+        let mut list = match CERTS.read() {
+            Ok(certs) => certs
+                .iter()
+                .map(|(name, not_after)| (name.clone(), (not_after - now).max(0) as u64))
+                .collect::<Vec<_>>(),
+            Err(_) => vec![],
+        };
+        list.sort();
+        list
+    }
+
+    /// 检查所有的证书, 返回已到告警时间的证书的CN
+    pub fn check() -> Vec<String> {
+        let warn = WARN_SECS.load(Ordering::Relaxed);
+        let mut names = vec![];
+        for (name, left) in Self::expiry_list() {
+            if left == 0 {
+                log::warn!("证书{}已过期, 请尽快更新", name);
+            } else if left <= warn {
+                log::warn!("证书{}将在{}天{}小时后过期, 请及时更新", name, left / 86400, left % 86400 / 3600);
+            } else {
+                continue;
+            }
+            names.push(name);
+        }
+        names
+    }
+
+    /// 启动每天的定时检查, 重复调用时仅启动一次
+    pub fn spawn_check() {
+        if CHECKING.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        tokio::spawn(async {
+            loop {
+                Self::check();
+                tokio::time::sleep(CHECK_INTERVAL).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::CertData;
+    use crate::SelfSigned;
+
+    #[test]
+    fn near_expiry_warning() {
+        let (near, _) = SelfSigned::build("near.expiry.test", &[], 3).unwrap();
+        let (far, _) = SelfSigned::build("far.expiry.test", &[], 365).unwrap();
+        CertData::register(&near);
+        CertData::register(&far);
+
+        let list = CertData::expiry_list();
+        let left = |name: &str| list.iter().find(|(n, _)| n == name).unwrap().1;
+        assert!((2 * 86400..=3 * 86400).contains(&left("near.expiry.test")));
+        assert!(left("far.expiry.test") > 364 * 86400);
+
+        // 默认14天内告警
+        let names = CertData::check();
+        assert!(names.contains(&"near.expiry.test".to_string()));
+        assert!(!names.contains(&"far.expiry.test".to_string()));
+
+        CertData::set_config(Some(Duration::from_secs(86400)));
+        assert!(!CertData::check().contains(&"near.expiry.test".to_string()));
+        CertData::set_config(None);
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/26 10:21:37
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+
+lazy_static! {
+    // 静态全局的活跃连接列表
+    static ref GLOBAL_CONN: RwLock<HashMap<u64, ConnInfo>> = RwLock::new(HashMap::new());
+}
+
+/// 连接的自增id, 从1开始
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 单个活跃连接的信息
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnInfo {
+    /// 注册表中的唯一id
+    pub id: u64,
+    /// 连接的类型, 如trans/virtual
+    pub kind: &'static str,
+    /// 内网穿透中绑定的sock_map
+    pub sock_map: u64,
+    /// 开始的时间戳(秒)
+    pub start: u64,
+    /// 关闭的句柄, 取消后连接将被强制关闭
+    #[serde(skip)]
+    token: CancellationToken,
+}
+
+/// 注册到全局的连接句柄, 析构的时候自动从注册表中移除
+#[derive(Debug)]
+pub struct ConnGuard {
+    id: u64,
+    token: CancellationToken,
+}
+
+impl ConnGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        if let Ok(mut guard) = GLOBAL_CONN.write() {
+            guard.remove(&self.id);
+        }
+    }
+}
+
+pub struct ConnData;
+
+impl ConnData {
+    /// 注册一个新的连接, 返回的句柄需跟随连接的生命周期
+    pub fn register(kind: &'static str, sock_map: u64) -> ConnGuard {
+        let id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
+        let token = CancellationToken::new();
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if let Ok(mut guard) = GLOBAL_CONN.write() {
+            guard.insert(
+                id,
+                ConnInfo {
+                    id,
+                    kind,
+                    sock_map,
+                    start,
+                    token: token.clone(),
+                },
+            );
+        }
+        ConnGuard { id, token }
+    }
+
+    /// 获取当前所有的活跃连接, 按id排序
+    pub fn list() -> Vec<ConnInfo> {
+        let mut list = match GLOBAL_CONN.read() {
+            Ok(guard) => guard.values().cloned().collect::<Vec<_>>(),
+            Err(_) => vec![],
+        };
+        list.sort_by_key(|c| c.id);
+        list
+    }
+
+    /// 强制关闭指定id的连接, 如果id不存在返回false
+    pub fn close(id: u64) -> bool {
+        if let Ok(mut guard) = GLOBAL_CONN.write() {
+            if let Some(info) = guard.remove(&id) {
+                info.token.cancel();
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnData;
+    use crate::{ProtFrame, TransStream};
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn close_listed_connection() {
+        let (stream, _peer) = tokio::io::duplex(1024);
+        let (in_sender, _in_receiver) = channel::<ProtFrame>(10);
+        let (_out_sender, out_receiver) = channel::<ProtFrame>(10);
+        let trans = TransStream::new(stream, 1, in_sender, out_receiver);
+        let id = trans.conn_id();
+        assert!(ConnData::list().iter().any(|c| c.id == id));
+
+        let handle = tokio::spawn(trans.copy_wait());
+        assert!(ConnData::close(id));
+        assert!(handle.await.unwrap().is_err());
+        assert!(!ConnData::list().iter().any(|c| c.id == id));
+        assert!(!ConnData::close(id));
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/03 10:40:18
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::{ConfigConnLimit, ConnLimitAction};
+
+lazy_static! {
+    // 静态全局的新建连接限制, 未配置时为None
+    static ref GLOBAL_CONN_LIMIT: Mutex<Option<ConnLimitData>> = Mutex::new(None);
+}
+
+/// 因超出新建连接速率而被关闭的连接数
+static CONN_LIMIT_DROP: AtomicU64 = AtomicU64::new(0);
+
+struct InnerConn {
+    /// 当前周期的开始时间
+    start: Instant,
+    /// 当前周期内新建的连接数
+    nums: u64,
+    /// 最后一次新建连接的时间, 记录已满时淘汰最久未使用的IP
+    last: Instant,
+}
+
+/// 按来源IP记录新建连接的数据
+pub struct ConnLimitData {
+    config: ConfigConnLimit,
+    /// 记录所有的ip数据的限制情况
+    ips: HashMap<IpAddr, InnerConn>,
+    /// 最后清理IP的时间
+    last_remove: Instant,
+}
+
+impl ConnLimitData {
+    pub fn new(config: ConfigConnLimit) -> Self {
+        Self {
+            config,
+            ips: HashMap::new(),
+            last_remove: Instant::now(),
+        }
+    }
+
+    pub fn try_remove_unuse(&mut self) {
+        // 未超过限制数
+        if self.ips.len() < self.config.limit as usize / 10 {
+            return;
+        }
+
+        let now = Instant::now();
+        let per = self.config.rate.per;
+        // 未超过当前时间轮回的100倍
+        if now - self.last_remove < 100 * per {
+            return;
+        }
+        self.last_remove = now;
+        self.ips.retain(|_, v| now - v.start <= 50 * per);
+    }
+
+    /// 记录一个新的连接, 返回是否允许通过
+    pub fn inner_recv_new_conn(&mut self, ip: IpAddr) -> bool {
+        self.try_remove_unuse();
+        let now = Instant::now();
+        if let Some(inner) = self.ips.get_mut(&ip) {
+            if now - inner.start >= self.config.rate.per {
+                inner.start = now;
+                inner.nums = 0;
+            }
+            inner.nums += 1;
+            inner.last = now;
+            return inner.nums <= self.config.rate.nums;
+        }
+        if self.ips.len() >= self.config.limit as usize && !self.evict(now) {
+            return false;
+        }
+        self.ips.insert(ip, InnerConn { start: now, nums: 1, last: now });
+        true
+    }
+
+    /// 记录已满时腾出位置, 优先清理已过周期的IP, 否则淘汰最久未新建连接的IP
+    fn evict(&mut self, now: Instant) -> bool {
+        let per = self.config.rate.per;
+        self.ips.retain(|_, v| now - v.start < per);
+        if self.ips.len() < self.config.limit as usize {
+            return true;
+        }
+        let oldest = self.ips.iter().min_by_key(|(_, v)| v.last).map(|(ip, _)| *ip);
+        match oldest {
+            Some(ip) => {
+                self.ips.remove(&ip);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 替换全局的配置, 配置未变化时保留已记录的数据
+    pub fn set_config(config: Option<ConfigConnLimit>) {
+        if let Ok(mut guard) = GLOBAL_CONN_LIMIT.lock() {
+            match (config, &*guard) {
+                (Some(config), Some(data)) if data.config == config => {}
+                (config, _) => *guard = config.map(Self::new),
+            }
+        }
+    }
+
+    /// 检查该IP的新建连接, 超出限制时返回对应的处理方式
+    pub fn recv_new_conn(ip: IpAddr) -> Option<ConnLimitAction> {
+        let mut guard = GLOBAL_CONN_LIMIT.lock().ok()?;
+        let data = guard.as_mut()?;
+        if data.inner_recv_new_conn(ip) {
+            return None;
+        }
+        CONN_LIMIT_DROP.fetch_add(1, Ordering::Relaxed);
+        Some(data.config.action)
+    }
+
+    /// 因超出新建连接速率而被关闭的连接总数
+    pub fn drop_count() -> u64 {
+        CONN_LIMIT_DROP.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::ConnLimitData;
+    use crate::{ConfigConnLimit, ConnLimitAction};
+
+    #[test]
+    fn limit_per_ip() {
+        let config = "limit=2 rate=3r/10s action=drop"
+            .parse::<ConfigConnLimit>()
+            .unwrap();
+        assert_eq!(config.action, ConnLimitAction::Drop);
+        assert!("action=close".parse::<ConfigConnLimit>().is_err());
+
+        let mut data = ConnLimitData::new(config);
+        let ip1: IpAddr = "127.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "127.0.0.2".parse().unwrap();
+        for _ in 0..3 {
+            assert!(data.inner_recv_new_conn(ip1));
+        }
+        assert!(!data.inner_recv_new_conn(ip1));
+        assert!(data.inner_recv_new_conn(ip2));
+        data.ips.get_mut(&ip1).unwrap().last -= std::time::Duration::from_secs(1);
+        // 超出记录的IP个数后淘汰最久未使用的IP, 新的IP仍可连接
+        assert!(data.inner_recv_new_conn("127.0.0.3".parse().unwrap()));
+        assert_eq!(data.ips.len(), 2);
+        assert!(!data.ips.contains_key(&ip1));
+        assert!(data.ips.contains_key(&ip2));
+    }
+
+    #[test]
+    fn full_table_evicts_expired() {
+        let config = "limit=2 rate=1r/s".parse::<ConfigConnLimit>().unwrap();
+        let mut data = ConnLimitData::new(config);
+        let ip1: IpAddr = "127.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "127.0.0.2".parse().unwrap();
+        let ip3: IpAddr = "127.0.0.3".parse().unwrap();
+        assert!(data.inner_recv_new_conn(ip1));
+        assert!(data.inner_recv_new_conn(ip2));
+        // ip1的周期已过, 记录已满时优先清理
+        data.ips.get_mut(&ip1).unwrap().start -= std::time::Duration::from_secs(2);
+        assert!(!data.inner_recv_new_conn(ip2));
+        assert!(data.inner_recv_new_conn(ip3));
+        assert!(!data.ips.contains_key(&ip1));
+        // ip2仍在周期内, 限制的计数不会因淘汰而丢失
+        assert!(!data.inner_recv_new_conn(ip2));
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/16 10:12:45
+
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+lazy_static! {
+    // 静态全局的TLS握手并发限制, 未配置时为None
+    static ref GLOBAL_HANDSHAKE: Mutex<Option<Arc<HandshakeData>>> = Mutex::new(None);
+}
+
+/// 当前正在进行的TLS握手数
+static HANDSHAKE_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+/// 当前排队等待握手的连接数
+static HANDSHAKE_QUEUED: AtomicUsize = AtomicUsize::new(0);
+/// 因排队已满而被关闭的连接数
+static HANDSHAKE_DROP: AtomicU64 = AtomicU64::new(0);
+
+/// TLS握手的并发限制, 防止大量新建连接时握手耗尽CPU
+///
+/// 超过并发数的握手排队等待, 排队已满时直接关闭连接
+pub struct HandshakeData {
+    max: usize,
+    /// 最大的排队数
+    queue_len: usize,
+    semaphore: Arc<Semaphore>,
+    /// 当前的排队数
+    queued: AtomicUsize,
+}
+
+/// 占用的握手名额, 握手结束后释放
+pub struct HandshakePermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        HANDSHAKE_ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 离开排队时减少计数, 等待中的连接被关闭时同样生效
+struct QueueGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+        HANDSHAKE_QUEUED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl HandshakeData {
+    /// 未配置排队数时为并发数的4倍
+    pub fn new(max: usize, queue_len: Option<usize>) -> Self {
+        Self {
+            max,
+            queue_len: queue_len.unwrap_or(max.saturating_mul(4)),
+            semaphore: Arc::new(Semaphore::new(max)),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// 获取握手名额, 无空闲名额时排队等待, 排队已满返回None
+    pub async fn inner_acquire(&self) -> Option<HandshakePermit> {
+        let permit = match self.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if self.queued.fetch_add(1, Ordering::Relaxed) >= self.queue_len {
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+                    HANDSHAKE_DROP.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                HANDSHAKE_QUEUED.fetch_add(1, Ordering::Relaxed);
+                let _guard = QueueGuard(&self.queued);
+                self.semaphore.clone().acquire_owned().await.ok()?
+            }
+        };
+        HANDSHAKE_ACTIVE.fetch_add(1, Ordering::Relaxed);
+        Some(HandshakePermit {
+            _permit: Some(permit),
+        })
+    }
+
+    /// 替换全局的配置, 配置未变化时保留正在使用的名额
+    pub fn set_config(max: Option<usize>, queue_len: Option<usize>) {
+        // 为0时视为不限制
+        let max = max.filter(|m| *m > 0);
+        if let Ok(mut guard) = GLOBAL_HANDSHAKE.lock() {
+            let new_len = max.map(|m| queue_len.unwrap_or(m.saturating_mul(4)));
+            match (max, &*guard) {
+                (Some(max), Some(data)) if data.max == max && Some(data.queue_len) == new_len => {}
+                (max, _) => *guard = max.map(|max| Arc::new(Self::new(max, queue_len))),
+            }
+        }
+    }
+
+    /// 按全局配置获取握手名额, 未配置时不做限制, 返回None时需关闭该连接
+    pub async fn acquire() -> Option<HandshakePermit> {
+        let data = GLOBAL_HANDSHAKE.lock().ok()?.clone();
+        match data {
+            Some(data) => data.inner_acquire().await,
+            None => {
+                HANDSHAKE_ACTIVE.fetch_add(1, Ordering::Relaxed);
+                Some(HandshakePermit { _permit: None })
+            }
+        }
+    }
+
+    /// 所有监听当前正在进行的握手数
+    pub fn active_count() -> usize {
+        HANDSHAKE_ACTIVE.load(Ordering::Relaxed)
+    }
+
+    /// 所有监听当前排队等待握手的连接数
+    pub fn queued_count() -> usize {
+        HANDSHAKE_QUEUED.load(Ordering::Relaxed)
+    }
+
+    /// 因排队已满而被关闭的连接总数
+    pub fn drop_count() -> u64 {
+        HANDSHAKE_DROP.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::HandshakeData;
+
+    #[tokio::test]
+    async fn limit_concurrent_handshakes() {
+        let limit = Arc::new(HandshakeData::new(4, Some(16)));
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let mut tasks = vec![];
+        // 同时到达的握手超过并发数及排队数之和
+        for _ in 0..32 {
+            let (limit, running, peak) = (limit.clone(), running.clone(), peak.clone());
+            tasks.push(tokio::spawn(async move {
+                let _permit = match limit.inner_acquire().await {
+                    Some(permit) => permit,
+                    None => return false,
+                };
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                // 模拟握手的耗时
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                true
+            }));
+        }
+        let mut done = 0;
+        for t in tasks {
+            if t.await.unwrap() {
+                done += 1;
+            }
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 4);
+        assert_eq!(done, 20);
+        assert!(HandshakeData::drop_count() >= 12);
+        assert_eq!(limit.semaphore.available_permits(), 4);
+        assert_eq!(limit.queued.load(Ordering::SeqCst), 0);
+    }
+}
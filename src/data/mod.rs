@@ -11,6 +11,20 @@
 // Created Date: 2023/11/28 10:14:24
 
 
+mod cert_data;
 mod limit_req_data;
+mod conn_data;
+mod conn_limit_data;
+mod handshake_data;
+mod pause_data;
+mod shutdown_data;
+mod tag_data;
 
-pub use limit_req_data::{LimitReqData, LimitResult};
\ No newline at end of file
+pub use cert_data::CertData;
+pub use limit_req_data::{LimitReqData, LimitResult};
+pub use conn_data::{ConnData, ConnGuard};
+pub use conn_limit_data::ConnLimitData;
+pub use handshake_data::HandshakeData;
+pub use pause_data::PauseData;
+pub use shutdown_data::{ShutdownData, ShutdownStream};
+pub use tag_data::TagData;
\ No newline at end of file
@@ -0,0 +1,122 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/21 09:36:18
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+use tokio::sync::Notify;
+
+lazy_static! {
+    // 暂停状态变化时唤醒等待中的监听
+    static ref PAUSE_CHANGED: Notify = Notify::new();
+}
+
+/// 是否已暂停接收新连接
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// 维护时暂停所有监听接收新的连接, 已建立的连接不受影响, 恢复后继续接收
+pub struct PauseData;
+
+impl PauseData {
+    pub fn is_paused() -> bool {
+        PAUSED.load(Ordering::Relaxed)
+    }
+
+    /// 设置暂停状态, 返回之前的状态
+    pub fn set_paused(paused: bool) -> bool {
+        let old = PAUSED.swap(paused, Ordering::Relaxed);
+        if old != paused {
+            log::info!("{}接收新连接", if paused { "暂停" } else { "恢复" });
+            PAUSE_CHANGED.notify_waiters();
+        }
+        old
+    }
+
+    /// 等待状态变为paused
+    async fn wait_state(paused: bool) {
+        loop {
+            // 先注册再检查, 防止错过检查后的通知
+            let notified = PAUSE_CHANGED.notified();
+            if Self::is_paused() == paused {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// 等待恢复接收新连接, 未暂停时立即返回
+    pub async fn wait_resume() {
+        Self::wait_state(false).await
+    }
+
+    /// 等待进入暂停状态
+    pub async fn wait_pause() {
+        Self::wait_state(true).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::PauseData;
+    use crate::Helper;
+
+    async fn echo(stream: &mut TcpStream, wait: Duration) -> bool {
+        let mut buf = [0u8; 4];
+        stream.write_all(b"ping").await.is_ok()
+            && matches!(
+                tokio::time::timeout(wait, stream.read_exact(&mut buf)).await,
+                Ok(Ok(_))
+            )
+    }
+
+    #[tokio::test]
+    async fn pause_accept() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = Helper::tcp_accept(&listener).await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n) = stream.read(&mut buf).await {
+                        if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        let mut exist = TcpStream::connect(addr).await.unwrap();
+        assert!(echo(&mut exist, Duration::from_secs(2)).await);
+
+        assert!(!PauseData::set_paused(true));
+        // 暂停期间新连接不被接收, 已建立的连接正常处理
+        let mut new = TcpStream::connect(addr).await.unwrap();
+        assert!(!echo(&mut new, Duration::from_millis(300)).await);
+        assert!(echo(&mut exist, Duration::from_secs(2)).await);
+
+        // 恢复后继续接收排队中的连接
+        assert!(PauseData::set_paused(false));
+        let mut buf = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(2), new.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(echo(&mut new, Duration::from_secs(2)).await);
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/10 10:08:37
+
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::task::AtomicWaker;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::ConnData;
+
+lazy_static! {
+    // 所有接收的客户端连接, 强制关闭时唤醒对应的读写
+    static ref GLOBAL_ACTIVE: RwLock<HashMap<u64, Arc<AtomicWaker>>> = RwLock::new(HashMap::new());
+}
+
+/// 连接的自增id
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// 强制关闭的次数, 连接创建时记录, 之后的强制关闭将使该连接失效
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// 退出时的连接处理, 停止监听后等待连接结束, 超时后强制关闭剩余的连接
+pub struct ShutdownData;
+
+impl ShutdownData {
+    /// 当前未结束的客户端连接数
+    pub fn active_count() -> usize {
+        GLOBAL_ACTIVE.read().map(|g| g.len()).unwrap_or(0)
+    }
+
+    /// 强制关闭当前所有的连接, with_tunnel时包括内网穿透中的连接, 返回关闭的连接数
+    fn force_close(with_tunnel: bool) -> usize {
+        GENERATION.fetch_add(1, Ordering::Relaxed);
+        let mut count = 0;
+        if let Ok(guard) = GLOBAL_ACTIVE.read() {
+            for waker in guard.values() {
+                waker.wake();
+            }
+            count += guard.len();
+        }
+        if with_tunnel {
+            for conn in ConnData::list() {
+                if ConnData::close(conn.id) {
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    /// 等待所有连接结束, 超过timeout后强制关闭, 返回强制关闭的连接数
+    pub async fn drain(timeout: Duration) -> usize {
+        Self::drain_with(timeout, true).await
+    }
+
+    async fn drain_with(timeout: Duration, with_tunnel: bool) -> usize {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut left = Self::active_count();
+            if with_tunnel {
+                left += ConnData::list().len();
+            }
+            if left == 0 {
+                log::info!("所有连接已结束, 退出进程");
+                return 0;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            log::trace!("等待{}条连接结束", left);
+            tokio::time::sleep((deadline - now).min(Duration::from_millis(100))).await;
+        }
+        let count = Self::force_close(with_tunnel);
+        log::warn!("等待连接结束超时{:?}, 强制关闭{}条连接", timeout, count);
+        count
+    }
+}
+
+/// 记录客户端连接, 强制关闭后读写均返回错误, 以便处理的任务尽快结束
+pub struct ShutdownStream<T> {
+    io: T,
+    id: u64,
+    generation: u64,
+    waker: Arc<AtomicWaker>,
+}
+
+impl<T> ShutdownStream<T> {
+    pub fn new(io: T) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let waker = Arc::new(AtomicWaker::new());
+        if let Ok(mut guard) = GLOBAL_ACTIVE.write() {
+            guard.insert(id, waker.clone());
+        }
+        Self {
+            io,
+            id,
+            generation: GENERATION.load(Ordering::Relaxed),
+            waker,
+        }
+    }
+
+    /// 取内部的连接, 用于在TLS握手前peek数据
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    fn check_closed(&self, cx: &mut Context<'_>) -> io::Result<()> {
+        self.waker.register(cx.waker());
+        if GENERATION.load(Ordering::Relaxed) != self.generation {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "force closed by shutdown",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<T> Drop for ShutdownStream<T> {
+    fn drop(&mut self) {
+        if let Ok(mut guard) = GLOBAL_ACTIVE.write() {
+            guard.remove(&self.id);
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ShutdownStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.check_closed(cx)?;
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ShutdownStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.check_closed(cx)?;
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.check_closed(cx)?;
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tokio::io::AsyncReadExt;
+
+    use super::{ShutdownData, ShutdownStream};
+
+    #[tokio::test]
+    async fn drain_force_close() {
+        // 客户端一直不关闭的连接
+        let (_client, server) = tokio::io::duplex(1024);
+        let mut stream = ShutdownStream::new(server);
+        let handle = tokio::spawn(async move {
+            let mut buf = [0u8; 10];
+            stream.read(&mut buf).await
+        });
+        assert!(ShutdownData::active_count() >= 1);
+
+        let now = Instant::now();
+        // 不关闭内网穿透的连接, 以免影响其它的测试
+        let count = ShutdownData::drain_with(Duration::from_millis(200), false).await;
+        assert!(now.elapsed() >= Duration::from_millis(200));
+        assert!(count >= 1);
+        let ret = tokio::time::timeout(Duration::from_secs(1), handle).await.unwrap().unwrap();
+        assert!(ret.is_err());
+
+        // 强制关闭后新建的连接不受影响
+        let (_client, server) = tokio::io::duplex(1024);
+        let stream = ShutdownStream::new(server);
+        let mut cx = std::task::Context::from_waker(futures::task::noop_waker_ref());
+        assert!(stream.check_closed(&mut cx).is_ok());
+    }
+}
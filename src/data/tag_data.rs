@@ -0,0 +1,60 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/23 14:52:18
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    // 各服务标签已处理的请求数
+    static ref REQUESTS: RwLock<HashMap<String, AtomicU64>> = RwLock::new(HashMap::new());
+}
+
+/// 按服务的标签统计, 以`server_tag`标签提供给metrics
+pub struct TagData;
+
+impl TagData {
+    /// 记录该标签处理了一个请求
+    pub fn add_request(tag: &str) {
+        if let Ok(requests) = REQUESTS.read() {
+            if let Some(count) = requests.get(tag) {
+                count.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        if let Ok(mut requests) = REQUESTS.write() {
+            requests
+                .entry(tag.to_string())
+                .or_insert_with(|| AtomicU64::new(0))
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// 各标签已处理的请求数, 按标签排序
+    pub fn request_list() -> Vec<(String, u64)> {
+        let mut list = match REQUESTS.read() {
+            Ok(requests) => requests
+                .iter()
+                .map(|(tag, count)| (tag.clone(), count.load(Ordering::Relaxed)))
+                .collect::<Vec<_>>(),
+            Err(_) => vec![],
+        };
+        list.sort();
+        list
+    }
+}
@@ -15,25 +15,30 @@ use std::{
     collections::{HashMap, HashSet},
     fs::{remove_file, File},
     io::{self, Read, Write},
-    net::{SocketAddr, ToSocketAddrs},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::Path,
     process::id,
     str::FromStr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use crate::{
-    log::{writer::simple::SimpleWriter, Encode, PatternEncoder, ProxyRecord},
+    log::{writer::simple::SimpleWriter, BufferAppender, Encode, PatternEncoder, ProxyRecord},
     prot::{ProtFrame, ProtFrameHeader},
-    ConfigHeader, ConfigLog, ConfigOption, HeaderOper, ProxyResult,
+    data::{ConnLimitData, PauseData}, ConfigDuration, ConfigHeader, ConfigLog, ConfigOption, ConfigSize, HeaderOper, ConnLimitAction, NetInterface, ProxyResult, SocketBuffer, TcpFastOpen,
 };
 use lazy_static::lazy_static;
 use log::{log_enabled, Level, LevelFilter, Record};
 use log4rs::{
-    append::{console::ConsoleAppender, file::FileAppender},
+    append::{
+        console::{ConsoleAppender, Target},
+        file::FileAppender,
+    },
     config::{Appender, Logger, Root},
 };
 use regex::Regex;
-use socket2::{Domain, Socket, Type};
+use socket2::{Domain, SockRef, Socket, Type};
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use webparse::{http2::frame::read_u24, BinaryMut, Buf, Request, Response, Serialize};
 use wenmeng::{Body, HeaderHelper};
@@ -87,6 +92,14 @@ impl Helper {
 
     /// 可端口复用的绑定方式，该端口可能被多个进程同时使用
     pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+        Self::bind_with_device(addr, None).await
+    }
+
+    /// 同`bind`, device不为空时通过`SO_BINDTODEVICE`绑定该网卡
+    pub async fn bind_with_device<A: ToSocketAddrs>(
+        addr: A,
+        device: Option<&str>,
+    ) -> io::Result<TcpListener> {
         let addrs = addr.to_socket_addrs()?;
         let mut last_err = None;
         for addr in addrs {
@@ -103,7 +116,13 @@ impl Helper {
             let _ = socket.set_only_v6(false);
             socket.set_reuse_address(true)?;
             Self::set_reuse_port(&socket, true)?;
+            if let Some(name) = device {
+                NetInterface::bind_device(&socket, name)?;
+            }
+            NetInterface::check_scope(&addr)?;
             socket.bind(&addr.into())?;
+            TcpFastOpen::apply_listener(&socket);
+            SocketBuffer::apply(&socket);
             match socket.listen(128) {
                 Ok(_) => {
                     let listener: std::net::TcpListener = socket.into();
@@ -124,8 +143,29 @@ impl Helper {
         }))
     }
 
+    /// 绑定配置中的监听, 失败时按`bind_failed`处理, 跳过时返回None
+    pub async fn bind_listener<A: ToSocketAddrs + std::fmt::Debug>(
+        addr: A,
+        device: Option<&str>,
+        strict: bool,
+    ) -> io::Result<Option<TcpListener>> {
+        match Self::bind_with_device(&addr, device).await {
+            Ok(listener) => Ok(Some(listener)),
+            Err(e) => Self::bind_failed(addr, e, strict).map(|_| None),
+        }
+    }
+
+    /// 监听失败, strict时返回带有该地址的错误, 否则记录日志后跳过该监听
+    pub fn bind_failed<A: std::fmt::Debug>(addr: A, e: io::Error, strict: bool) -> io::Result<()> {
+        if strict {
+            return Err(io::Error::new(e.kind(), format!("监听{:?}失败:{}", addr, e)));
+        }
+        log::warn!("监听{:?}失败:{}, 跳过该监听继续启动", addr, e);
+        Ok(())
+    }
+
     /// 可端口复用的绑定方式，该端口可能被多个进程同时使用
-    pub async fn bind_upd<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+    pub async fn bind_upd<A: ToSocketAddrs>(addr: A, device: Option<&str>) -> io::Result<UdpSocket> {
         let addrs = addr.to_socket_addrs()?;
         let last_err = None;
         for addr in addrs {
@@ -134,6 +174,10 @@ impl Helper {
             let _ = socket.set_only_v6(false);
             socket.set_reuse_address(true)?;
             Self::set_reuse_port(&socket, true)?;
+            if let Some(name) = device {
+                NetInterface::bind_device(&socket, name)?;
+            }
+            NetInterface::check_scope(&addr)?;
             socket.bind(&addr.into())?;
             let listener: std::net::UdpSocket = socket.into();
             return UdpSocket::from_std(listener);
@@ -166,35 +210,132 @@ impl Helper {
         Ok(false)
     }
 
+    /// 日志配置中的文件路径, 格式为 `路径 [等级] [buffer_size=64k] [flush_interval=1s]`
+    pub fn log_file_path(spec: &str) -> &str {
+        spec.split(' ').find(|s| !s.is_empty()).unwrap_or(spec)
+    }
+
+    /// 检查日志文件是否可写入, 不存在的目录将自动创建
+    pub fn check_log_file(spec: &str) -> io::Result<()> {
+        let path = Path::new(Self::log_file_path(spec));
+        let ret = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent),
+            _ => Ok(()),
+        };
+        ret.and_then(|_| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(|_| ())
+        })
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("无法打开日志文件{}:{}", path.display(), e),
+            )
+        })
+    }
+
+    /// 解析日志配置中的等级及缓冲参数, 缓冲参数无效或为0时返回错误
+    /// 格式为 `路径 [等级] [buffer_size=64k] [flush_interval=1s]`
+    pub fn parse_log_spec(spec: &str) -> io::Result<(Level, Option<usize>, Option<Duration>)> {
+        let vals: Vec<&str> = spec.split(' ').filter(|s| !s.is_empty()).collect();
+        let invalid = |v: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("日志配置{}中的{}无效", spec, v),
+            )
+        };
+        let mut level = Level::Info;
+        let mut buffer_size = None;
+        let mut flush_interval = None;
+        for v in vals.iter().skip(1) {
+            if let Some(size) = v.strip_prefix("buffer_size=") {
+                match ConfigSize::from_str(size) {
+                    Ok(s) if s.0 > 0 => buffer_size = Some(s.0 as usize),
+                    _ => return Err(invalid(v)),
+                }
+            } else if let Some(interval) = v.strip_prefix("flush_interval=") {
+                // ConfigDuration解析失败时默认为1, 此处要求为数字加单位
+                let unit = interval.trim_start_matches(|c: char| c.is_ascii_digit());
+                if unit.len() == interval.len() || !["", "ms", "s", "min", "h", "d"].contains(&unit) {
+                    return Err(invalid(v));
+                }
+                match ConfigDuration::from_str(interval) {
+                    Ok(d) if !d.0.is_zero() => flush_interval = Some(d.0),
+                    _ => return Err(invalid(v)),
+                }
+            } else if let Ok(l) = Level::from_str(v) {
+                level = l;
+            }
+        }
+        Ok((level, buffer_size, flush_interval))
+    }
+
+    /// 根据配置生成写入文件的日志输出, 返回配置的等级及输出
+    fn build_file_appender(spec: &str) -> (Level, io::Result<Box<dyn log4rs::append::Append>>) {
+        let (level, buffer_size, flush_interval) = match Self::parse_log_spec(spec) {
+            Ok(v) => v,
+            Err(e) => return (Level::Info, Err(e)),
+        };
+        let path = Self::log_file_path(spec).to_string();
+        // 设置默认的匹配类型打印时间信息
+        let parttern =
+            log4rs::encode::pattern::PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} {m}{n}");
+        let appender: io::Result<Box<dyn log4rs::append::Append>> =
+            if buffer_size.is_some() || flush_interval.is_some() {
+                // 配置了缓冲则由独立线程异步写入
+                BufferAppender::new(
+                    path,
+                    Box::new(parttern),
+                    buffer_size.unwrap_or(BufferAppender::DEFAULT_BUFFER_SIZE),
+                    flush_interval.unwrap_or(BufferAppender::DEFAULT_FLUSH_INTERVAL),
+                )
+                .map(|a| Box::new(a) as Box<dyn log4rs::append::Append>)
+            } else {
+                FileAppender::builder()
+                    .encoder(Box::new(parttern))
+                    .build(path)
+                    .map(|a| Box::new(a) as Box<dyn log4rs::append::Append>)
+            };
+        (level, appender)
+    }
+
+    /// 日志文件无法打开时输出到stderr, 防止日志丢失
+    fn build_file_appender_or_stderr(spec: &str) -> (Level, Box<dyn log4rs::append::Append>) {
+        let (level, appender) = Self::build_file_appender(spec);
+        match appender {
+            Ok(appender) => (level, appender),
+            Err(e) => {
+                eprintln!(
+                    "无法打开日志文件{}:{}, 改为输出到stderr",
+                    Self::log_file_path(spec),
+                    e
+                );
+                let parttern =
+                    log4rs::encode::pattern::PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} {m}{n}");
+                let stderr = ConsoleAppender::builder()
+                    .target(Target::Stderr)
+                    .encoder(Box::new(parttern))
+                    .build();
+                (level, Box::new(stderr))
+            }
+        }
+    }
+
     /// 尝试初始化, 如果已初始化则重新加载
     pub fn try_init_log(option: &ConfigOption) {
         let log_names = option.get_log_names();
         let mut log_config = log4rs::config::Config::builder();
         let mut root = Root::builder();
         for (name, path) in log_names {
-            let (path, level) = {
-                let vals: Vec<&str> = path.split(' ').collect();
-                if vals.len() == 1 {
-                    (path, Level::Info)
-                } else {
-                    (
-                        vals[0].to_string(),
-                        Level::from_str(vals[1]).ok().unwrap_or(Level::Info),
-                    )
-                }
-            };
-            // 设置默认的匹配类型打印时间信息
-            let parttern =
-                log4rs::encode::pattern::PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} {m}{n}");
-            let appender = FileAppender::builder()
-                .encoder(Box::new(parttern))
-                .build(path)
-                .unwrap();
+            let (level, appender) = Self::build_file_appender_or_stderr(&path);
             if name == "default" {
                 root = root.appender(name.clone());
             }
             log_config =
-                log_config.appender(Appender::builder().build(name.clone(), Box::new(appender)));
+                log_config.appender(Appender::builder().build(name.clone(), appender));
             log_config = log_config.logger(
                 Logger::builder()
                     .appender(name.clone())
@@ -204,15 +345,26 @@ impl Helper {
             );
         }
 
+        // 主日志写入文件, 与访问日志相互独立
+        if let Some(log_file) = &option.log_file {
+            let (_, appender) = Self::build_file_appender_or_stderr(log_file);
+            log_config = log_config.appender(Appender::builder().build("log_file", appender));
+            root = root.appender("log_file");
+        }
+
         if !option.disable_stdout {
             let stdout: ConsoleAppender = ConsoleAppender::builder().build();
             log_config = log_config.appender(Appender::builder().build("stdout", Box::new(stdout)));
             root = root.appender("stdout");
         }
 
-        let log_config = log_config
-            .build(root.build(option.default_level.unwrap_or(LevelFilter::Trace)))
-            .unwrap();
+        // 安静模式仅输出错误日志
+        let level = if option.quiet {
+            LevelFilter::Error
+        } else {
+            option.default_level.unwrap_or(LevelFilter::Trace)
+        };
+        let log_config = log_config.build(root.build(level)).unwrap();
         // 检查静态变量中是否存在handle可能在多线程中,需加锁
         if LOG4RS_HANDLE.lock().unwrap().is_some() {
             LOG4RS_HANDLE
@@ -227,6 +379,17 @@ impl Helper {
         }
     }
 
+    /// 重新打开所有的日志文件(主日志及访问日志), 用于外部logrotate切割日志后, 无需重新加载配置
+    /// 新的配置整体替换后旧的输出才析构, 已缓冲未写入的日志将写入切割前的文件, 不会丢失
+    /// 日志未初始化时返回false
+    pub fn reopen_logs(option: &ConfigOption) -> bool {
+        if LOG4RS_HANDLE.lock().unwrap().is_none() {
+            return false;
+        }
+        Self::try_init_log(option);
+        true
+    }
+
     pub fn format_req(req: &Request<Body>, formats: &str) -> String {
         let pw = FORMAT_PATTERN_CACHE.with(|m| {
             if !m.borrow().contains_key(&formats) {
@@ -364,7 +527,7 @@ impl Helper {
         access: &Option<ConfigLog>,
         req: &Request<Body>,
     ) {
-        if let Some(access) = access {
+        if let Some(access) = access.as_ref().filter(|a| !a.is_off()) {
             if let Some(formats) = log_formats.get(&access.format) {
                 // 需要先判断是否该日志已开启, 如果未开启直接写入将浪费性能
                 if log_enabled!(target: &access.name, access.level) {
@@ -485,7 +648,23 @@ impl Helper {
     }
 
     pub async fn tcp_accept(listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
-        let (s, a) = listener.accept().await?;
+        let (s, a) = loop {
+            // 暂停期间不接收新的连接, 由系统的backlog排队
+            PauseData::wait_resume().await;
+            let (s, a) = tokio::select! {
+                r = listener.accept() => r?,
+                _ = PauseData::wait_pause() => continue,
+            };
+            match ConnLimitData::recv_new_conn(a.ip()) {
+                None => break (s, a),
+                Some(action) => {
+                    log::trace!("客户端{a}新建连接超出速率限制, 处理方式:{action}");
+                    if action == ConnLimitAction::Drop {
+                        let _ = SockRef::from(&s).set_linger(Some(Duration::ZERO));
+                    }
+                }
+            }
+        };
         if let Ok(l) = listener.local_addr() {
             log::trace!("收到客户端建立连接{a} -> {l}");
         } else {
@@ -513,6 +692,28 @@ impl Helper {
         ((server_id as u64) << 32) + (sock_map as u64)
     }
 
+    /// 生成PROXY protocol v1的头, 未知来源地址时为`PROXY UNKNOWN`
+    pub fn build_proxy_protocol(src: Option<SocketAddr>, dst: SocketAddr) -> String {
+        let src = match src {
+            Some(src) => src,
+            None => return "PROXY UNKNOWN\r\n".to_string(),
+        };
+        // 两端的地址族需一致, 不一致时统一转成ipv6
+        let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V6(d)) => (IpAddr::V6(s.to_ipv6_mapped()), IpAddr::V6(d)),
+            (IpAddr::V6(s), IpAddr::V4(d)) => (IpAddr::V6(s), IpAddr::V6(d.to_ipv6_mapped())),
+            (s, d) => (s, d),
+        };
+        format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if src_ip.is_ipv4() { "TCP4" } else { "TCP6" },
+            src_ip,
+            dst_ip,
+            src.port(),
+            dst.port()
+        )
+    }
+
     // pub async fn udp_recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<usize> {
     //     let (s, addr) = socket.recv_from(&mut buf).await?;
     //     unsafe {
@@ -523,10 +724,233 @@ impl Helper {
 
 #[cfg(test)]
 mod tests {
-    use crate::Helper;
+    use crate::{ConfigOption, Helper};
     use webparse::Request;
     use wenmeng::Body;
 
+    /// 全局日志的测试需串行执行
+    static GLOBAL_LOG: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn quiet_log_file() {
+        let _lock = GLOBAL_LOG.lock().unwrap_or_else(|e| e.into_inner());
+        let path = std::env::temp_dir().join(format!("wmproxy_main_{}.log", std::process::id()));
+        let mut option = ConfigOption::default();
+        option.disable_stdout = true;
+        option.quiet = true;
+        option.log_file = Some(path.to_string_lossy().to_string());
+        Helper::try_init_log(&option);
+        log::warn!("quiet warn");
+        log::error!("quiet error");
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("quiet error"));
+        assert!(!content.contains("quiet warn"));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reopen_after_rotate() {
+        let _lock = GLOBAL_LOG.lock().unwrap_or_else(|e| e.into_inner());
+        let path = std::env::temp_dir().join(format!("wmproxy_reopen_{}.log", std::process::id()));
+        let rotated = path.with_extension("log.1");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&rotated);
+        let mut option = ConfigOption::default();
+        option.disable_stdout = true;
+        option.default_level = Some(log::LevelFilter::Info);
+        // 缓冲的日志在重新打开时写入切割前的文件
+        option.log_file = Some(format!("{} buffer_size=64k flush_interval=1h", path.display()));
+        Helper::try_init_log(&option);
+        log::info!("before rotate");
+
+        // 模拟logrotate将文件改名后通知重新打开
+        std::fs::rename(&path, &rotated).unwrap();
+        assert!(Helper::reopen_logs(&option));
+        log::info!("after rotate");
+        log::logger().flush();
+
+        let old = std::fs::read_to_string(&rotated).unwrap();
+        let new = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&rotated);
+        assert!(old.contains("before rotate") && !old.contains("after rotate"));
+        assert!(new.contains("after rotate") && !new.contains("before rotate"));
+    }
+
+    #[test]
+    fn location_access_log() {
+        let _lock = GLOBAL_LOG.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = std::env::temp_dir().join(format!("wmproxy_location_log_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (access, assets) = (dir.join("access.log"), dir.join("assets.log"));
+        let config = format!(
+            r#"
+            disable_stdout = true
+            [http]
+            access_log = "access main"
+            [http.log_format]
+            main = "{{url}}"
+            [http.log_names]
+            access = "{} trace"
+            assets = "{} trace"
+            [[http.server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[http.server.location]]
+            rule = "/healthz"
+            access_log = "off"
+            [[http.server.location]]
+            rule = "/static"
+            access_log = "assets main"
+            [[http.server.location]]
+            rule = "/"
+            "#,
+            access.display(),
+            assets.display()
+        );
+        let mut option = toml::from_str::<ConfigOption>(&config).unwrap();
+        option.http.as_mut().unwrap().after_load_option().unwrap();
+        Helper::try_init_log(&option);
+        let locations = &option.http.as_ref().unwrap().server[0].location;
+        assert_eq!(locations[0].comm.access_log.as_ref().unwrap().to_string(), "off");
+        for (l, url) in locations.iter().zip(["/healthz", "/static/a.js", "/index"]) {
+            let req = Request::builder().url(url).body(Body::empty()).unwrap();
+            Helper::log_acess(&l.comm.log_format, &l.comm.access_log, &req);
+        }
+        log::logger().flush();
+
+        // off的location不记录, 其它location记录到各自的文件
+        let access = std::fs::read_to_string(&access).unwrap();
+        let assets = std::fs::read_to_string(&assets).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(!access.contains("/healthz") && !assets.contains("/healthz"));
+        assert!(assets.contains("/static/a.js") && !access.contains("/static/a.js"));
+        assert!(access.contains("/index"));
+    }
+
+    #[test]
+    fn server_tag_log_metrics() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{control::MetricsRegistry, test_util::connect_http};
+
+        let _lock = GLOBAL_LOG.lock().unwrap_or_else(|e| e.into_inner());
+        let path = std::env::temp_dir().join(format!("wmproxy_server_tag_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = format!(
+            r#"
+            disable_stdout = true
+            [http]
+            access_log = "access main"
+            [http.log_format]
+            main = "{{server_tag}} {{path}}"
+            [http.log_names]
+            access = "{} trace"
+            [[http.server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "shop.test"
+            tag = "shop"
+            [[http.server.location]]
+            rule = "/"
+            static_response = "ok"
+            [[http.server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "blog.test"
+            tag = "blog"
+            [[http.server.location]]
+            rule = "/"
+            static_response = "ok"
+            "#,
+            path.display()
+        );
+        let mut option = toml::from_str::<ConfigOption>(&config).unwrap();
+        option.http.as_mut().unwrap().after_load_option().unwrap();
+        Helper::try_init_log(&option);
+        let servers = option.http.as_ref().unwrap().convert_server_config();
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            for (host, url) in [("shop.test", "/cart"), ("blog.test", "/post")] {
+                let mut client = connect_http(servers.clone()).await;
+                let req = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", url, host);
+                client.write_all(req.as_bytes()).await.unwrap();
+                let mut buf = [0u8; 1024];
+                assert!(client.read(&mut buf).await.unwrap() > 0);
+            }
+        });
+        log::logger().flush();
+
+        // 日志及指标按请求所在的server区分
+        let content = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(content.contains("shop /cart"), "{}", content);
+        assert!(content.contains("blog /post"), "{}", content);
+        let list = MetricsRegistry::collect(0, 0);
+        for tag in ["shop", "blog"] {
+            assert!(list.iter().any(|m| m.name == "server_requests_total"
+                && m.labels == vec![("server_tag", tag.to_string())]
+                && m.value >= 1));
+        }
+    }
+
+    #[test]
+    fn log_file_missing_dir() {
+        let dir = std::env::temp_dir().join(format!("wmproxy_logs_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("sub").join("access.log");
+        let mut option = ConfigOption::default();
+        option.log_file = Some(format!("{} buffer_size=4k", path.display()));
+        // 不存在的目录自动创建
+        option.check_log_files().unwrap();
+        assert!(path.exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn log_file_unwritable() {
+        // 父路径为普通文件时无法创建目录, 不受运行用户权限的影响
+        let file = std::env::temp_dir().join(format!("wmproxy_notdir_{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let path = file.join("access.log");
+        let mut option = ConfigOption::default();
+        option.log_file = Some(path.display().to_string());
+        let err = format!("{:?}", option.check_log_files().unwrap_err());
+        assert!(err.contains("无法打开日志文件"));
+        assert!(err.contains(&*path.display().to_string()));
+
+        // 配置了回退时不报错, 由stderr输出
+        option.log_fallback_stderr = true;
+        option.check_log_files().unwrap();
+        let (_, appender) = Helper::build_file_appender(&path.display().to_string());
+        assert!(appender.is_err());
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn log_buffer_invalid() {
+        let (level, size, interval) =
+            Helper::parse_log_spec("logs/a.log trace buffer_size=4k flush_interval=2s").unwrap();
+        assert_eq!(level, log::Level::Trace);
+        assert_eq!(size, Some(4096));
+        assert_eq!(interval, Some(std::time::Duration::from_secs(2)));
+
+        for spec in [
+            "logs/a.log buffer_size=abc",
+            "logs/a.log buffer_size=0",
+            "logs/a.log flush_interval=0s",
+            "logs/a.log flush_interval=1x",
+            "logs/a.log flush_interval=s",
+        ] {
+            let err = format!("{:?}", Helper::parse_log_spec(spec).unwrap_err());
+            assert!(err.contains("无效"), "{}", err);
+        }
+        // 配置了回退时仍检查缓冲参数
+        let mut option = ConfigOption::default();
+        option.log_fallback_stderr = true;
+        option.log_file = Some("logs/a.log buffer_size=abc".to_string());
+        assert!(option.check_log_files().is_err());
+    }
+
     fn build_request() -> Request<Body> {
         Request::builder()
             .url("http://127.0.0.1/test/root?query=1&a=b")
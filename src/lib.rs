@@ -30,18 +30,27 @@ mod plugins;
 pub mod log;
 mod data;
 pub mod arg;
+mod self_signed;
+mod cert_loader;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 pub use error::{ProxyResult, ProxyError};
 pub use flag::Flag;
 pub use option::{ProxyConfig, Builder, ConfigOption};
-pub use wmcore::WMCore;
+pub use wmcore::{CenterState, WMCore};
 pub use proxy::http::ProxyHttp;
 pub use proxy::socks5::ProxySocks5;
+pub use proxy::{AuthCache, AuthFuture, AuthHandler, ConfigUpstreamProxy, Credentials, ProxyAuth, ProxyServer};
+#[cfg(feature = "connect-udp")]
+pub use proxy::ConnectUdp;
 pub use streams::*;
 pub use helper::Helper;
-pub use prot::{ProtFrame, ProtFrameHeader, ProtClose, ProtData, ProtCreate};
+pub use prot::{CloseCode, ProtFrame, ProtFrameHeader, ProtClose, ProtData, ProtCreate};
 pub use mapping::*;
 pub use check::*;
 pub use control::*;
 pub use config::*;
-pub use plugins::*;
\ No newline at end of file
+pub use plugins::*;
+pub use self_signed::SelfSigned;
+pub use cert_loader::CertLoader;
\ No newline at end of file
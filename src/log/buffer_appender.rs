@@ -0,0 +1,231 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/27 09:12:45
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::{Log, Metadata, Record};
+use log4rs::encode::{writer::simple::SimpleWriter, Encode};
+
+enum BufferCmd {
+    Line(Vec<u8>),
+    Flush(Sender<()>),
+}
+
+/// 带缓冲的文件日志
+/// 请求线程只负责将格式化后的日志放入队列, 由独立的线程写入磁盘
+/// 缓冲区满或者到达刷新间隔时写入文件, 析构时会将剩余的数据全部写入
+/// 队列有上限, 磁盘写入过慢导致队列满时丢弃新的日志, 并在文件中记录丢弃的条数
+#[derive(Debug)]
+pub struct BufferAppender {
+    encoder: Box<dyn Encode>,
+    sender: Option<SyncSender<BufferCmd>>,
+    handle: Option<JoinHandle<()>>,
+    /// 累计丢弃的日志条数
+    dropped: Arc<AtomicU64>,
+}
+
+impl BufferAppender {
+    /// 默认的缓冲区大小
+    pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+    /// 默认的刷新间隔
+    pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+    /// 默认队列中最多等待写入的日志条数
+    pub const DEFAULT_QUEUE_SIZE: usize = 16 * 1024;
+
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        encoder: Box<dyn Encode>,
+        buffer_size: usize,
+        flush_interval: Duration,
+    ) -> io::Result<Self> {
+        Self::with_queue_size(path, encoder, buffer_size, flush_interval, Self::DEFAULT_QUEUE_SIZE)
+    }
+
+    pub fn with_queue_size<P: AsRef<Path>>(
+        path: P,
+        encoder: Box<dyn Encode>,
+        buffer_size: usize,
+        flush_interval: Duration,
+        queue_size: usize,
+    ) -> io::Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = mpsc::sync_channel::<BufferCmd>(queue_size.max(1));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let counter = dropped.clone();
+        let handle = std::thread::Builder::new()
+            .name("wmproxy-log".to_string())
+            .spawn(move || {
+                let mut writer = BufWriter::with_capacity(buffer_size.max(1), file);
+                let mut reported = 0;
+                // 记录上次之后新丢弃的条数
+                let mut report = |writer: &mut BufWriter<_>| {
+                    let total = counter.load(Ordering::Relaxed);
+                    if total > reported {
+                        let _ = writeln!(writer, "日志写入过慢, 已丢弃{}条日志", total - reported);
+                        reported = total;
+                    }
+                };
+                loop {
+                    report(&mut writer);
+                    match receiver.recv_timeout(flush_interval) {
+                        Ok(BufferCmd::Line(line)) => {
+                            let _ = writer.write_all(&line);
+                        }
+                        Ok(BufferCmd::Flush(ack)) => {
+                            let _ = writer.flush();
+                            let _ = ack.send(());
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            let _ = writer.flush();
+                        }
+                        Err(RecvTimeoutError::Disconnected) => {
+                            break;
+                        }
+                    }
+                }
+                report(&mut writer);
+                let _ = writer.flush();
+            })?;
+        Ok(Self {
+            encoder,
+            sender: Some(sender),
+            handle: Some(handle),
+            dropped,
+        })
+    }
+
+    /// 因队列已满累计丢弃的日志条数
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Log for BufferAppender {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        let mut buf = vec![];
+        if self.encoder.encode(&mut SimpleWriter(&mut buf), record).is_err() {
+            return;
+        }
+        if let Some(sender) = &self.sender {
+            if let Err(TrySendError::Full(_)) = sender.try_send(BufferCmd::Line(buf)) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// 等待写入线程将已入队的日志全部写入
+    fn flush(&self) {
+        let (ack, wait) = mpsc::channel();
+        if let Some(sender) = &self.sender {
+            if sender.send(BufferCmd::Flush(ack)).is_ok() {
+                let _ = wait.recv();
+            }
+        }
+    }
+}
+
+impl Drop for BufferAppender {
+    fn drop(&mut self) {
+        // 关闭队列后写入线程会写完剩余数据再退出
+        let _ = self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferAppender;
+    use log::{Level, Log, Record};
+    use log4rs::encode::pattern::PatternEncoder;
+    use std::time::Duration;
+
+    #[test]
+    fn flush_on_drop() {
+        let path = std::env::temp_dir().join(format!("wmproxy_buffer_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let appender = BufferAppender::new(
+            &path,
+            Box::new(PatternEncoder::new("{m}{n}")),
+            1024 * 1024,
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+        for i in 0..1000 {
+            appender.log(
+                &Record::builder()
+                    .level(Level::Info)
+                    .args(format_args!("line {}", i))
+                    .build(),
+            );
+        }
+        drop(appender);
+        let content = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(content.lines().count(), 1000);
+        assert_eq!(content.lines().last(), Some("line 999"));
+    }
+    #[test]
+    fn drop_when_full() {
+        let path = std::env::temp_dir().join(format!("wmproxy_buffer_full_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let appender = BufferAppender::with_queue_size(
+            &path,
+            Box::new(PatternEncoder::new("{m}{n}")),
+            1024 * 1024,
+            Duration::from_secs(3600),
+            1,
+        )
+        .unwrap();
+        for i in 0..10000 {
+            appender.log(
+                &Record::builder()
+                    .level(Level::Info)
+                    .args(format_args!("line {}", i))
+                    .build(),
+            );
+        }
+        let dropped = appender.dropped();
+        drop(appender);
+        let content = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        // 写入的与丢弃的条数合计为全部日志
+        let written = content.lines().filter(|l| l.starts_with("line ")).count() as u64;
+        let reported: u64 = content
+            .lines()
+            .filter_map(|l| l.strip_prefix("日志写入过慢, 已丢弃"))
+            .map(|l| l.trim_end_matches("条日志").parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(reported, dropped);
+        assert_eq!(written + dropped, 10000);
+    }
+}
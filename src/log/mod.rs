@@ -12,9 +12,11 @@
 
 // cribbed to a large extent from log4rs
 
+mod buffer_appender;
 mod pattern;
 mod proxy_record;
 
+pub use self::buffer_appender::*;
 pub use self::pattern::*;
 pub use self::proxy_record::*;
 
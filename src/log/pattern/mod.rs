@@ -498,6 +498,8 @@ impl<'a> From<Piece<'a>> for Chunk {
                 }
                 "client_ip" => no_args(&formatter.args, parameters, FormattedChunk::ClientIp),
                 "client_user" => no_args(&formatter.args, parameters, FormattedChunk::ClientUser),
+                "ja3" => no_args(&formatter.args, parameters, FormattedChunk::Ja3),
+                "server_tag" => no_args(&formatter.args, parameters, FormattedChunk::ServerTag),
                 "url" => no_args(&formatter.args, parameters, FormattedChunk::Url),
                 "path" => no_args(&formatter.args, parameters, FormattedChunk::Path),
                 "query" => no_args(&formatter.args, parameters, FormattedChunk::Query),
@@ -510,6 +512,7 @@ impl<'a> From<Piece<'a>> for Chunk {
                 "cookie" => no_args(&formatter.args, parameters, FormattedChunk::Cookie),
                 "ssl_protocol" => no_args(&formatter.args, parameters, FormattedChunk::SslProtocol),
                 "ssl_cipher" => no_args(&formatter.args, parameters, FormattedChunk::SslCipher),
+                "ssl_sni" => no_args(&formatter.args, parameters, FormattedChunk::SslSni),
                 "up_addr" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamAddr),
                 "request_time" => no_args(&formatter.args, parameters, FormattedChunk::RequestTime),
                 "up_response_time" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamResponseTime),
@@ -573,6 +576,8 @@ enum FormattedChunk {
     /// for request or response
     ClientIp,
     ClientUser,
+    Ja3,
+    ServerTag,
     Url,
     Path,
     Query,
@@ -583,6 +588,7 @@ enum FormattedChunk {
     Cookie,
     SslProtocol,
     SslCipher,
+    SslSni,
     UpstreamStatus,
     BodyBytesSent,
     UpstreamAddr,
@@ -661,6 +667,33 @@ impl FormattedChunk {
             FormattedChunk::ClientUser => {
                 Ok(())
             }
+            FormattedChunk::Ja3 => {
+                if let Some(req) = record.req {
+                    match req.headers().system_get("{ja3}") {
+                        Some(ja3) => w.write(ja3.as_bytes())?,
+                        None => w.write("-".as_bytes())?,
+                    };
+                }
+                Ok(())
+            }
+            FormattedChunk::ServerTag => {
+                if let Some(req) = record.req {
+                    match req.headers().system_get("{server_tag}") {
+                        Some(tag) => w.write(tag.as_bytes())?,
+                        None => w.write("-".as_bytes())?,
+                    };
+                }
+                Ok(())
+            }
+            FormattedChunk::SslSni => {
+                if let Some(req) = record.req {
+                    match req.headers().system_get("{ssl_sni}") {
+                        Some(sni) => w.write(sni.as_bytes())?,
+                        None => w.write("-".as_bytes())?,
+                    };
+                }
+                Ok(())
+            }
             FormattedChunk::Url => {
                 if let Some(req) = record.req {
                     w.write_fmt(format_args!("{}", req.url()))?;
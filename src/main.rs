@@ -20,6 +20,8 @@ async fn run_main() -> ProxyResult<()> {
     let _ = Helper::try_create_pidfile(&pidfile);
     let control = ControlServer::new(option);
     control.start_serve().await?;
+    // 正常退出时将缓冲中的日志全部写入
+    log::logger().flush();
     let _ = Helper::try_remove_pidfile(&pidfile);
     Ok(())
 }
@@ -29,5 +31,6 @@ async fn run_main() -> ProxyResult<()> {
 async fn main() {
     if let Err(e) = run_main().await {
         println!("运行wmproxy发生错误:{:?}", e);
+        std::process::exit(1);
     }
 }
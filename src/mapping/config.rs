@@ -34,6 +34,17 @@ pub struct MappingConfig {
     #[serde_as(as = "Vec<DisplayFromStr>")]
     #[serde(default = "Vec::new")]
     pub headers: Vec<ConfigHeader>,
+    /// 隧道拥塞时的写入优先级, 数值越大越优先, 如ssh等交互式映射可调高
+    #[serde(default)]
+    pub priority: u8,
+    /// 连接本地服务时先发送PROXY protocol v1头, 使本地服务获取到真实的客户端地址
+    /// 仅在客户端生效, 本地服务需支持该协议
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// 复用连接本地服务的连接, 仅适用于HTTP等连接可承载多次独立请求的协议
+    /// 仅在客户端生效, 与proxy_protocol同时开启时不复用
+    #[serde(default)]
+    pub pool_local: bool,
 }
 
 impl MappingConfig {
@@ -44,6 +55,9 @@ impl MappingConfig {
             local_addr: None,
             domain,
             headers,
+            priority: 0,
+            proxy_protocol: false,
+            pool_local: false,
         }
     }
 
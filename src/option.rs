@@ -30,8 +30,10 @@ use tokio::net::TcpListener;
 use tokio_rustls::{rustls, TlsAcceptor};
 
 use crate::{
+    data::CertData,
     reverse::{HttpConfig, StreamConfig, UpstreamConfig},
-    CenterClient, Flag, Helper, MappingConfig, OneHealth, ProxyError, ProxyResult, WrapAddr,
+    AdminConfig, AuthCache, AuthHandler, CenterClient, ConfigConnLimit, ConfigDscp, ConfigDuration, ConfigSize, ConfigWritePressure, Flag, Helper, MappingConfig, MetricsConfig, OneHealth, ProxyAuth, ProxyError, ProxyResult,
+    WrapAddr,
 };
 
 pub struct Builder {
@@ -69,14 +71,14 @@ impl Builder {
 
     pub fn bind(self, addr: SocketAddr) -> Builder {
         self.and_then(|mut proxy| {
-            proxy.bind = Some(WrapAddr(addr));
+            proxy.bind = Some(WrapAddr::new(addr));
             Ok(proxy)
         })
     }
 
     pub fn center_addr(self, addr: SocketAddr) -> Builder {
         self.and_then(|mut proxy| {
-            proxy.center_addr = Some(WrapAddr(addr));
+            proxy.center_addr = Some(WrapAddr::new(addr));
             Ok(proxy)
         })
     }
@@ -172,6 +174,20 @@ impl Builder {
         })
     }
 
+    pub fn auth_handler(self, auth_handler: Option<AuthHandler>) -> Builder {
+        self.map(|mut proxy| {
+            proxy.auth_handler = auth_handler;
+            proxy
+        })
+    }
+
+    pub fn max_tunnels(self, max_tunnels: Option<usize>) -> Builder {
+        self.map(|mut proxy| {
+            proxy.max_tunnels = max_tunnels;
+            proxy
+        })
+    }
+
     pub fn mapping(self, mapping: MappingConfig) -> Builder {
         self.and_then(|mut proxy| {
             proxy.mappings.push(mapping);
@@ -188,6 +204,15 @@ impl Builder {
         }
     }
 
+    fn map<F>(self, func: F) -> Self
+    where
+        F: FnOnce(ProxyConfig) -> ProxyConfig,
+    {
+        Builder {
+            inner: self.inner.map(func),
+        }
+    }
+
     pub fn into_value(self) -> ProxyResult<ProxyConfig> {
         self.inner
     }
@@ -263,12 +288,52 @@ pub struct ProxyConfig {
     pub(crate) key: Option<String>,
     #[serde(default)]
     pub(crate) mappings: Vec<MappingConfig>,
+    /// 隧道写入积压的告警及流控, 如`warn=4m sustain=10s limit=16m`
+    #[bpaf(long)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub(crate) write_pressure: Option<ConfigWritePressure>,
+    /// 重连服务端的初始间隔, 每次失败后翻倍, 默认为1s
+    #[bpaf(long)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub(crate) reconnect_base: Option<ConfigDuration>,
+    /// 重连服务端的最大间隔, 不能小于初始间隔, 默认为30s
+    #[bpaf(long)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub(crate) reconnect_max: Option<ConfigDuration>,
+    /// 连续重连失败的最大次数, 超过后不再重连, 0表示无限次
+    #[bpaf(fallback(0), display_fallback, long)]
+    #[serde(default)]
+    pub(crate) max_reconnect_attempts: u32,
+    /// 隧道连接的DSCP/ToS标记, 如`ef`将交互的隧道流量标记为低延迟, 作用于中心服务端接收及客户端发起的连接
+    #[bpaf(long)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub(crate) dscp: Option<ConfigDscp>,
+    /// 中心服务端同时连接的内网穿透客户端上限, 超出时告知客户端后关闭连接, 未配置或0时不限制
+    #[bpaf(long)]
+    #[serde(default)]
+    pub(crate) max_tunnels: Option<usize>,
+    /// 自定义的代理验证回调, 仅可通过代码设置
+    #[bpaf(pure(None))]
+    #[serde(skip)]
+    pub(crate) auth_handler: Option<AuthHandler>,
+    /// 验证回调的结果缓存, 克隆后共享, 重新加载配置后重新生成
+    #[bpaf(pure(Default::default()))]
+    #[serde(skip)]
+    pub(crate) auth_cache: AuthCache,
 }
 
 pub fn default_control_port() -> SocketAddr {
     "127.0.0.1:8837".parse().unwrap()
 }
 
+fn default_strict_bind() -> bool {
+    true
+}
+
 pub fn default_pidfile() -> String {
     "wmproxy.pid".to_string()
 }
@@ -286,14 +351,64 @@ pub struct ConfigOption {
     pub(crate) stream: Option<StreamConfig>,
     #[serde(default = "default_control_port")]
     pub(crate) control: SocketAddr,
+    /// 独立的管理端口, 配置后控制端口仅保留生命周期的控制
+    #[serde(default)]
+    pub(crate) admin: Option<AdminConfig>,
+    /// 指标的输出方式, 默认仅由`/metrics`拉取prometheus格式
+    #[serde(default)]
+    pub(crate) metrics: Option<MetricsConfig>,
     #[serde(default)]
     pub(crate) disable_stdout: bool,
+    /// 主日志写入的文件, 格式同访问日志, 如`logs/wmproxy.log buffer_size=64k flush_interval=1s`
+    #[serde(default)]
+    pub(crate) log_file: Option<String>,
+    /// 安静模式, 主日志仅输出错误, 不影响访问日志
+    #[serde(default)]
+    pub(crate) quiet: bool,
+    /// 日志文件无法打开时改为输出到stderr, 默认启动时报错
+    #[serde(default)]
+    pub(crate) log_fallback_stderr: bool,
     #[serde(default)]
     pub(crate) disable_control: bool,
+    /// 启动时任一监听绑定失败则退出, 为false时记录日志后跳过该监听, 以成功绑定的监听继续运行
+    #[serde(default = "default_strict_bind")]
+    pub(crate) strict_bind: bool,
     #[serde(default="default_pidfile")]
     pub pidfile: String,
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub(crate) default_level: Option<LevelFilter>,
+    /// 按来源IP限制新建连接的速率, 如`rate=100r/s action=close`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub(crate) conn_limit: Option<ConfigConnLimit>,
+    /// 同时进行的TLS握手数, 超过时排队等待, 防止大量新建连接时握手耗尽CPU
+    #[serde(default)]
+    pub(crate) max_concurrent_handshakes: Option<usize>,
+    /// 等待TLS握手的最大排队数, 排队已满时关闭连接, 未配置时为max_concurrent_handshakes的4倍
+    #[serde(default)]
+    pub(crate) handshake_queue_len: Option<usize>,
+    /// 监听端口开启TCP Fast Open, 值为TFO的队列长度, 如`tcp_fastopen = 256`
+    #[serde(default)]
+    pub(crate) tcp_fastopen: Option<u32>,
+    /// 连接上游时开启TCP Fast Open, 仅Linux支持
+    #[serde(default)]
+    pub(crate) tcp_fastopen_connect: bool,
+    /// 监听及连接的SO_RCVBUF大小, 如`recv_buffer_size = "4m"`, 未配置时使用系统默认
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub(crate) recv_buffer_size: Option<ConfigSize>,
+    /// 监听及连接的SO_SNDBUF大小, 如`send_buffer_size = "4m"`, 未配置时使用系统默认
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub(crate) send_buffer_size: Option<ConfigSize>,
+    /// 退出时等待连接结束的最长时间, 超时后强制关闭剩余的连接, 未配置时立即退出
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub(crate) shutdown_timeout: Option<ConfigDuration>,
+    /// TLS证书到期前多久开始每天告警, 如`cert_expiry_warn = "14d"`, 未配置时为14天
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub(crate) cert_expiry_warn: Option<ConfigDuration>,
 }
 
 impl Default for ConfigOption {
@@ -303,10 +418,25 @@ impl Default for ConfigOption {
             http: Default::default(),
             stream: Default::default(),
             control: default_control_port(),
+            admin: None,
+            metrics: None,
             disable_stdout: Default::default(),
+            log_file: None,
+            quiet: false,
+            log_fallback_stderr: false,
             disable_control: Default::default(),
+            strict_bind: default_strict_bind(),
             default_level: None,
             pidfile: default_pidfile(),
+            conn_limit: None,
+            max_concurrent_handshakes: None,
+            handshake_queue_len: None,
+            tcp_fastopen: None,
+            tcp_fastopen_connect: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            shutdown_timeout: None,
+            cert_expiry_warn: None,
         }
     }
 }
@@ -317,7 +447,7 @@ impl Default for ProxyConfig {
             server_id: 0,
             flag: Flag::HTTP | Flag::HTTPS | Flag::SOCKS5,
             // mode: "client".to_string(),
-            bind: Some(WrapAddr(default_bind_addr())),
+            bind: Some(WrapAddr::new(default_bind_addr())),
             center_addr: None,
             server: None,
             username: None,
@@ -338,21 +468,71 @@ impl Default for ProxyConfig {
             key: None,
 
             mappings: vec![],
+            write_pressure: None,
+            reconnect_base: None,
+            reconnect_max: None,
+            max_reconnect_attempts: 0,
+            dscp: None,
+            max_tunnels: None,
+            auth_handler: None,
+            auth_cache: AuthCache::default(),
         }
     }
 }
 
 impl ProxyConfig {
+    pub const DEFAULT_RECONNECT_BASE: Duration = Duration::from_secs(1);
+    pub const DEFAULT_RECONNECT_MAX: Duration = Duration::from_secs(30);
+
     pub fn builder() -> Builder {
         Builder::new()
     }
 
+    /// 重连服务端的初始间隔
+    pub fn reconnect_base(&self) -> Duration {
+        self.reconnect_base
+            .as_ref()
+            .map(|d| d.0)
+            .unwrap_or(Self::DEFAULT_RECONNECT_BASE)
+    }
+
+    /// 重连服务端的最大间隔, 未配置时不小于初始间隔
+    pub fn reconnect_max(&self) -> Duration {
+        self.reconnect_max
+            .as_ref()
+            .map(|d| d.0)
+            .unwrap_or(Self::DEFAULT_RECONNECT_MAX.max(self.reconnect_base()))
+    }
+
+    /// 检查重连的配置是否合法
+    pub fn check_reconnect(&self) -> io::Result<()> {
+        if self.reconnect_max() < self.reconnect_base() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "重连的最大间隔不能小于初始间隔"));
+        }
+        Ok(())
+    }
+
+    /// 设置自定义的代理验证回调, 静态账号密码不匹配时交由回调验证
+    pub fn set_auth_handler(&mut self, auth_handler: Option<AuthHandler>) {
+        self.auth_handler = auth_handler;
+    }
+
+    /// 代理的验证方式, 所有连接共享同一份回调的验证缓存
+    pub fn build_auth(&self) -> ProxyAuth {
+        let mut auth = ProxyAuth::new(self.username.clone(), self.password.clone());
+        auth.set_handler(self.auth_handler.clone());
+        auth.set_cache(self.auth_cache.clone());
+        auth
+    }
+
     fn load_certs(path: &Option<String>) -> io::Result<Vec<CertificateDer<'static>>> {
         if let Some(path) = path {
             let file = File::open(path)?;
             let mut reader = BufReader::new(file);
             let certs = rustls_pemfile::certs(&mut reader);
-            Ok(certs.into_iter().collect::<Result<Vec<_>, _>>()?)
+            let certs = certs.into_iter().collect::<Result<Vec<_>, _>>()?;
+            CertData::register(&certs);
+            Ok(certs)
         } else {
             let cert = br"-----BEGIN CERTIFICATE-----
 MIIF+zCCBOOgAwIBAgIQCkkcvmucB5JXt9JAehuNqTANBgkqhkiG9w0BAQsFADBu
@@ -567,6 +747,7 @@ cR+nZ6DRmzKISbcN9/m8I7xNWwU2cglrYa4NCHguQSrTefhRoZAfl8BEOW1rJVGC
 
     pub async fn bind(
         &self,
+        strict: bool,
     ) -> ProxyResult<(
         Option<TlsAcceptor>,
         Option<Arc<ClientConfig>>,
@@ -605,15 +786,15 @@ cR+nZ6DRmzKISbcN9/m8I7xNWwU2cglrYa4NCHguQSrTefhRoZAfl8BEOW1rJVGC
                 center_client = Some(center);
             }
         }
-        let client_listener = if let Some(bind) = self.bind {
+        let client_listener = if let Some(bind) = &self.bind {
             log::info!("绑定代理：{:?}，提供代理功能。", bind.0);
-            Some(Helper::bind(bind.0).await?)
+            Helper::bind_listener(bind.0, bind.1.as_deref(), strict).await?
         } else {
             None
         };
-        let center_listener = if let Some(center) = self.center_addr {
+        let center_listener = if let Some(center) = &self.center_addr {
             log::info!("绑定代理：{:?}，提供中心代理功能。", center.0);
-            Some(Helper::bind(center.0).await?)
+            Helper::bind_listener(center.0, center.1.as_deref(), strict).await?
         } else {
             None
         };
@@ -628,6 +809,7 @@ cR+nZ6DRmzKISbcN9/m8I7xNWwU2cglrYa4NCHguQSrTefhRoZAfl8BEOW1rJVGC
 
     pub async fn bind_map(
         &self,
+        strict: bool,
     ) -> ProxyResult<(
         Option<TcpListener>,
         Option<TcpListener>,
@@ -642,11 +824,11 @@ cR+nZ6DRmzKISbcN9/m8I7xNWwU2cglrYa4NCHguQSrTefhRoZAfl8BEOW1rJVGC
         let mut map_accept = None;
         if let Some(ls) = &self.map_http_bind {
             log::info!("内网穿透，http绑定：{:?}，提供http内网功能。", ls);
-            http_listener = Some(Helper::bind(ls).await?);
+            http_listener = Helper::bind_listener(ls, None, strict).await?;
         };
         if let Some(ls) = &self.map_https_bind {
             log::info!("内网穿透，https绑定：{:?}，提供https内网功能。", ls);
-            https_listener = Some(Helper::bind(ls).await?);
+            https_listener = Helper::bind_listener(ls, None, strict).await?;
         };
 
         if https_listener.is_some() {
@@ -659,12 +841,12 @@ cR+nZ6DRmzKISbcN9/m8I7xNWwU2cglrYa4NCHguQSrTefhRoZAfl8BEOW1rJVGC
 
         if let Some(ls) = &self.map_tcp_bind {
             log::info!("内网穿透，tcp绑定：{:?}，提供tcp内网功能。", ls);
-            tcp_listener = Some(Helper::bind(ls).await?);
+            tcp_listener = Helper::bind_listener(ls, None, strict).await?;
         };
 
         if let Some(ls) = &self.map_proxy_bind {
             log::info!("内网穿透，tcp绑定：{:?}，提供tcp内网功能。", ls);
-            proxy_listener = Some(Helper::bind(ls).await?);
+            proxy_listener = Helper::bind_listener(ls, None, strict).await?;
         };
 
         Ok((
@@ -693,6 +875,14 @@ impl ConfigOption {
     }
 
     pub fn after_load_option(&mut self) -> ProxyResult<()> {
+        self.check_admin_addr()?;
+        self.check_log_files()?;
+        if let Some(metrics) = &self.metrics {
+            metrics.check()?;
+        }
+        if let Some(proxy) = &self.proxy {
+            proxy.check_reconnect()?;
+        }
         if let Some(http) = &mut self.http {
             http.after_load_option()?;
         }
@@ -702,6 +892,68 @@ impl ConfigOption {
         Ok(())
     }
 
+    /// 检查所有日志的配置及文件是否可写入, 配置了log_fallback_stderr时无法写入的日志将输出到stderr
+    pub fn check_log_files(&self) -> io::Result<()> {
+        let log_names = self.get_log_names();
+        for path in log_names.values().chain(self.log_file.iter()) {
+            Helper::parse_log_spec(path)?;
+        }
+        if self.log_fallback_stderr {
+            return Ok(());
+        }
+        for path in log_names.values().chain(self.log_file.iter()) {
+            Helper::check_log_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// 检查管理端口是否与其它监听端口冲突
+    fn check_admin_addr(&self) -> io::Result<()> {
+        let admin = match &self.admin {
+            Some(admin) => admin.bind_addr,
+            None => return Ok(()),
+        };
+        fn is_overlap(a: &SocketAddr, b: &SocketAddr) -> bool {
+            a.port() == b.port()
+                && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+        }
+        let mut addrs = vec![];
+        if !self.disable_control {
+            addrs.push(self.control);
+        }
+        if let Some(proxy) = &self.proxy {
+            for a in [&proxy.bind, &proxy.center_addr].into_iter().flatten() {
+                addrs.push(a.0);
+            }
+            for a in [
+                &proxy.map_http_bind,
+                &proxy.map_https_bind,
+                &proxy.map_tcp_bind,
+                &proxy.map_proxy_bind,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                addrs.push(*a);
+            }
+        }
+        if let Some(http) = &self.http {
+            for s in &http.server {
+                addrs.extend(s.bind_addr.0.iter());
+                addrs.extend(s.bind_ssl.0.iter());
+            }
+        }
+        if let Some(stream) = &self.stream {
+            for s in &stream.server {
+                addrs.extend(s.bind_addr.0.iter());
+            }
+        }
+        if addrs.iter().any(|a| is_overlap(a, &admin)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "管理端口与其它监听端口冲突"));
+        }
+        Ok(())
+    }
+
     fn try_add_upstream(
         result: &mut Vec<OneHealth>,
         already: &mut HashSet<SocketAddr>,
@@ -756,6 +1008,34 @@ impl ConfigOption {
         result
     }
 
+    /// 获取所有配置的上游, 同名的上游仅保留首个
+    pub fn get_upstreams(&self) -> Vec<UpstreamConfig> {
+        let mut result: Vec<UpstreamConfig> = vec![];
+        let mut add = |list: &Vec<UpstreamConfig>| {
+            for upstream in list {
+                if !result.iter().any(|u| u.name == upstream.name) {
+                    result.push(upstream.clone());
+                }
+            }
+        };
+        if let Some(http) = &self.http {
+            add(&http.upstream);
+            for s in &http.server {
+                add(&s.upstream);
+                for l in &s.location {
+                    add(&l.upstream);
+                }
+            }
+        }
+        if let Some(stream) = &self.stream {
+            add(&stream.upstream);
+            for s in &stream.server {
+                add(&s.upstream);
+            }
+        }
+        result
+    }
+
     pub fn get_log_names(&self) -> HashMap<String, String> {
         let mut names = HashMap::new();
         if let Some(http) = &self.http {
@@ -24,8 +24,8 @@ use tokio::fs::File;
 use webparse::{BinaryMut, Buf, HeaderName, Method, Response, StatusCode, Url};
 
 use crate::plugins::calc_file_size;
-use crate::reverse::CommonConfig;
-use crate::ConfigDuration;
+use crate::reverse::{CommonConfig, ConfigCompress, Encoding};
+use crate::{ConfigBasicAuth, ConfigDuration, ConfigSize, Helper};
 
 lazy_static! {
     static ref DEFAULT_MIMETYPE: HashMap<&'static str, &'static str> = {
@@ -105,10 +105,18 @@ fn default_hide() -> Vec<String> {
     vec![]
 }
 
+fn default_hide_dotfiles() -> bool {
+    true
+}
+
 fn default_index() -> Vec<String> {
     vec!["index.html".to_string(), "index.htm".to_string()]
 }
 
+fn default_try_precompressed() -> bool {
+    true
+}
+
 fn default_precompressed() -> Vec<String> {
     vec!["gzip".to_string(), "br".to_string()]
 }
@@ -133,19 +141,47 @@ pub struct FileServer {
     pub path404: Option<String>,
     #[serde(default = "default_hide")]
     pub hide: Vec<String>,
+    /// 是否禁止访问以`.`开头的文件及目录, 如`.git/config`, `.env`, 默认开启
+    #[serde(default = "default_hide_dotfiles")]
+    pub hide_dotfiles: bool,
+    /// 是否允许root之下的符号链接, 开启时链接的目标仍需在root之内, 默认关闭
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// 禁止访问的路径, 支持`*`通配, 不含`/`的按每一级的名称匹配如`*.bak`, 含`/`的按相对于root的路径匹配如`/private/*`
+    #[serde(default)]
+    pub deny_patterns: Vec<String>,
+    /// 访问目录时依次查找的index文件, 均不存在时按browse返回目录列表或拒绝访问
     #[serde(default = "default_index")]
     pub index: Vec<String>,
     #[serde(default = "default_status")]
     pub status: u16,
+    /// 是否查找文件旁的`.gz`/`.br`预压缩文件并直接返回, 配置文件中默认开启
+    #[serde(default = "default_try_precompressed")]
+    pub try_precompressed: bool,
     #[serde(default = "default_precompressed")]
     pub precompressed: Vec<String>,
     #[serde(default)]
     pub disable_compress: bool,
+    /// 可压缩的文件(html/css/js/json/svg等)超过该大小时按客户端的`Accept-Encoding`压缩返回, 默认为1k
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub compression_min_size: Option<ConfigSize>,
     #[serde(default)]
     pub browse: bool,
+    /// 未开启目录访问且目录下无index文件时返回的状态码, 如`403`或`404`, 未配置时同status
+    pub directory_deny_status: Option<u16>,
+    /// 未开启目录访问且目录下无index文件时返回的页面, 与path404相互独立
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub directory_deny_page: Option<String>,
     /// 通过"Access-Control-Allow-Origin"标头启用 CORS
     #[serde(default)]
     pub cors: bool,
+    /// 访问需通过Basic Auth验证的账号密码, 如`["user:pass"]`, 可配置多组, 为空时不验证
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    pub auth: Vec<ConfigBasicAuth>,
+    /// 验证失败时`WWW-Authenticate`中的realm, 默认为wmproxy
+    pub auth_realm: Option<String>,
     #[serde(default = "CommonConfig::new")]
     pub comm: CommonConfig,
 }
@@ -185,6 +221,9 @@ impl FileServer {
             root: if root.len() > 0 { Some(root) } else { None },
             prefix,
             hide: vec![],
+            hide_dotfiles: true,
+            deny_patterns: vec![],
+            follow_symlinks: false,
             default_mimetype: default_mimetype(),
             ext_mimetype: HashMap::new(),
             cache_time: None,
@@ -192,10 +231,16 @@ impl FileServer {
             path404: None,
             index: default_index(),
             status: 404,
-            precompressed: vec![],
+            try_precompressed: false,
+            precompressed: default_precompressed(),
             disable_compress: false,
+            compression_min_size: None,
             browse: true,
+            directory_deny_status: None,
+            directory_deny_page: None,
             cors: false,
+            auth: vec![],
+            auth_realm: None,
             comm: CommonConfig::new(),
         };
         config.fix_default();
@@ -235,6 +280,145 @@ impl FileServer {
         false
     }
 
+    /// 配置了账号密码时验证请求的`Authorization`, 未通过返回401
+    fn check_auth(&self, req: &RecvRequest) -> Option<RecvResponse> {
+        if self.auth.is_empty() {
+            return None;
+        }
+        let value = req.headers().get_str_value(&HeaderName::AUTHORIZATION);
+        if ConfigBasicAuth::check(&self.auth, value.as_deref()) {
+            return None;
+        }
+        let realm = self.auth_realm.as_deref().unwrap_or("wmproxy").replace('"', "");
+        let body = "Unauthorized";
+        Some(
+            Response::builder()
+                .version(req.version())
+                .status(401)
+                .header(HeaderName::WWW_AUTHENTICATE, format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm))
+                .header(HeaderName::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .header(HeaderName::CONTENT_LENGTH, body.len())
+                .body(Body::new_text(body.to_string()))
+                .unwrap(),
+        )
+    }
+
+    /// 访问目录但路径不以`/`结尾时重定向到以`/`结尾的路径, 保留请求参数
+    fn ret_redirect_dir(req: &RecvRequest) -> RecvResponse {
+        let mut location = format!("{}/", req.path());
+        if let Some(query) = &req.url().query {
+            location += "?";
+            location += query;
+        }
+        Response::builder()
+            .version(req.version())
+            .status(301)
+            .header(HeaderName::LOCATION, location)
+            .header(HeaderName::CONTENT_LENGTH, 0usize)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// 去除路径中的`.`及`..`, 返回以`/`开头的路径, 保留结尾的`/`, `\\`同样视为分隔符,
+    /// `..`超出根目录或含有`\0`时返回None
+    fn normalize_path(path: &str) -> Option<String> {
+        if path.contains('\0') {
+            return None;
+        }
+        let mut parts = vec![];
+        for part in path.split(['/', '\\']) {
+            match part {
+                "" | "." => continue,
+                ".." => {
+                    parts.pop()?;
+                }
+                _ => parts.push(part),
+            }
+        }
+        let mut href = "/".to_string() + &parts.join("/");
+        if path.ends_with(['/', '\\']) && !parts.is_empty() {
+            href.push('/');
+        }
+        Some(href)
+    }
+
+    /// 按文件系统解析后的路径需在root之内, 未开启follow_symlinks时root之下的路径中不允许有符号链接,
+    /// 文件不存在时由后续按不存在处理
+    fn is_escape_path(&self, root_path: &Path, real_path: &Path) -> bool {
+        let (Ok(root), Ok(real)) = (root_path.canonicalize(), real_path.canonicalize()) else {
+            return false;
+        };
+        if !real.starts_with(&root) {
+            return true;
+        }
+        if self.follow_symlinks {
+            return false;
+        }
+        let Ok(relative) = real_path.strip_prefix(root_path) else {
+            return true;
+        };
+        let mut path = root_path.to_path_buf();
+        for component in relative.components() {
+            path.push(component);
+            match std::fs::symlink_metadata(&path) {
+                Ok(meta) if !meta.file_type().is_symlink() => {}
+                _ => return true,
+            }
+        }
+        false
+    }
+
+    /// 访问超出root的路径时返回403
+    fn ret_forbidden(req: &RecvRequest) -> RecvResponse {
+        let body = "Forbidden";
+        Response::builder()
+            .version(req.version())
+            .status(403)
+            .header(HeaderName::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .header(HeaderName::CONTENT_LENGTH, body.len())
+            .body(Body::new_text(body.to_string()))
+            .unwrap()
+    }
+
+    /// 以`.`开头的文件或目录(开启hide_dotfiles时)及匹配deny_patterns的路径不允许访问
+    pub fn is_deny_path(&self, href: &str) -> bool {
+        let names = href.split('/').filter(|v| !v.is_empty());
+        if self.hide_dotfiles && names.clone().any(|name| name.starts_with('.')) {
+            return true;
+        }
+        let is_match = |src: &str, pattern: &str| match pattern.contains('*') {
+            true => Helper::is_match(src, pattern),
+            false => src == pattern,
+        };
+        self.deny_patterns.iter().any(|pattern| match pattern.contains('/') {
+            true => is_match(href.trim_end_matches('/'), pattern.trim_end_matches('/')),
+            false => names.clone().any(|name| is_match(name, pattern)),
+        })
+    }
+
+    /// 访问无index文件的目录且未开启目录访问时的返回
+    async fn ret_directory_deny(&self, req: &mut RecvRequest) -> Response<Body> {
+        let status = self.directory_deny_status.unwrap_or(self.status);
+        if let Some(page) = &self.directory_deny_page {
+            let real_path = Path::new(page).to_owned();
+            if let Ok(Some(mut r)) = self.build_response_by_file(req, real_path).await {
+                if let Ok(status) = StatusCode::from_u16(status) {
+                    *r.status_mut() = status;
+                }
+                return r;
+            }
+        }
+        // 状态码与status不同时不返回path404的页面
+        if status != self.status {
+            return Response::builder()
+                .status(status)
+                .body("can't view parent file")
+                .unwrap()
+                .into_type();
+        }
+        self.ret_error_msg(req, "can't view parent file").await
+    }
+
     async fn ret_error_msg(&self, req: &mut RecvRequest, msg: &'static str) -> Response<Body> {
         if self.status == 404 && self.path404.is_some() {
             let real_path = Path::new(self.path404.as_ref().unwrap()).to_owned();
@@ -260,15 +444,108 @@ impl FileServer {
         }
     }
 
-    pub fn calc_etag(data: &Metadata) -> String {
-        let mut seconds = 0;
-        let len = data.len();
-        if let Ok(last) = data.modified() {
-            if let Ok(n) = last.duration_since(SystemTime::UNIX_EPOCH) {
-                seconds = n.as_secs();
+    /// 按修改时间及文件大小计算的ETag, 与nginx的格式一致
+    /// 文本类的文件压缩效果较好, 图片及压缩包等不再压缩
+    fn is_compressible(mimetype: &str) -> bool {
+        mimetype.starts_with("text/")
+            || ["javascript", "json", "xml", "svg"].iter().any(|v| mimetype.contains(v))
+    }
+
+    /// 按需压缩文件的返回, Range请求及HEAD不压缩
+    fn compress_file(&self, req: &RecvRequest, res: &mut RecvResponse, mimetype: &str, size: u64) {
+        let min_size = self.compression_min_size.as_ref().map(|s| s.0).unwrap_or(1024);
+        if self.disable_compress || !Self::is_compressible(mimetype) || size < min_size {
+            return;
+        }
+        res.headers_mut().insert(HeaderName::VARY, "Accept-Encoding");
+        if req.method() != &Method::Get
+            || res.status() != StatusCode::OK
+            || req.headers().contains(&HeaderName::RANGE)
+        {
+            return;
+        }
+        let default = ConfigCompress::default();
+        let compress = self.comm.compress.as_ref().unwrap_or(&default);
+        let encoding = match req.headers().get_str_value(&HeaderName::ACCEPT_ENCODING) {
+            Some(accept) if compress.enable => compress.negotiate(&accept),
+            _ => None,
+        };
+        if let Some(encoding) = encoding {
+            // 压缩后的内容与文件不再逐字节一致, ETag改为弱校验
+            if let Some(etag) = res.headers().get_str_value(&HeaderName::ETAG) {
+                if !etag.starts_with("W/") {
+                    res.headers_mut().insert(HeaderName::ETAG, format!("W/{}", etag));
+                }
+            }
+            compress.start(encoding, res);
+        }
+    }
+
+    /// 查找文件旁的`.gz`/`.br`预压缩文件, 按客户端`Accept-Encoding`的q值选择, q值相同时按precompressed的顺序,
+    /// 比原文件旧的预压缩文件视为过期不使用, 同时返回是否存在可用的预压缩文件
+    async fn find_precompressed(
+        &self,
+        req: &RecvRequest,
+        real_path: &Path,
+    ) -> (Option<(Encoding, File, Metadata)>, bool) {
+        if !self.try_precompressed || self.disable_compress {
+            return (None, false);
+        }
+        let modified = std::fs::metadata(real_path).ok().and_then(|m| Self::modified_secs(&m));
+        let mut found = vec![];
+        for pre in &self.precompressed {
+            let (encoding, ext) = match &**pre {
+                "gzip" => (Encoding::Gzip, "gz"),
+                "br" => (Encoding::Brotli, "br"),
+                _ => continue,
+            };
+            let mut path = real_path.as_os_str().to_owned();
+            path.push(".");
+            path.push(ext);
+            let Ok(metadata) = tokio::fs::metadata(&path).await else {
+                continue;
+            };
+            if !metadata.is_file() || Self::modified_secs(&metadata) < modified {
+                continue;
             }
+            found.push((encoding, PathBuf::from(path)));
+        }
+        if found.is_empty() {
+            return (None, false);
         }
-        format!("{:x}-{:x}", seconds, len)
+        let compress = ConfigCompress {
+            prefer: found.iter().map(|(encoding, _)| *encoding).collect(),
+            ..Default::default()
+        };
+        let encoding = match req.headers().get_str_value(&HeaderName::ACCEPT_ENCODING) {
+            Some(accept) => compress.negotiate(&accept),
+            None => None,
+        };
+        let Some((encoding, path)) = encoding.and_then(|e| found.into_iter().find(|(v, _)| *v == e)) else {
+            return (None, true);
+        };
+        let Ok(file) = File::open(path).await else {
+            return (None, true);
+        };
+        match file.metadata().await {
+            Ok(metadata) => (Some((encoding, file, metadata)), true),
+            Err(_) => (None, true),
+        }
+    }
+
+    pub fn calc_etag(data: &Metadata) -> String {
+        format!("\"{:x}-{:x}\"", Self::modified_secs(data).unwrap_or(0), data.len())
+    }
+
+    fn modified_secs(data: &Metadata) -> Option<u64> {
+        let last = data.modified().ok()?;
+        last.duration_since(SystemTime::UNIX_EPOCH).ok().map(|n| n.as_secs())
+    }
+
+    /// `If-None-Match`按弱比较匹配, 可为多个以`,`分隔的值或`*`
+    pub fn is_etag_match(value: &str, etag: &str) -> bool {
+        let etag = etag.trim_start_matches("W/");
+        value.split(',').map(|v| v.trim()).any(|v| v == "*" || v.trim_start_matches("W/") == etag)
     }
 
     pub fn to_rfc2822(utc: DateTime<Utc>) -> String {
@@ -294,51 +571,35 @@ impl FileServer {
 
         let application = self.get_mimetype(&extension);
         //查找是否有合适的预压缩文件
-        if let Some(accept) = req.headers().get_option_value(&HeaderName::ACCEPT_ENCODING) {
-            for pre in &self.precompressed {
-                // 得客户端发送支持该格式
-                if !accept.contains(pre.as_bytes()) {
-                    continue;
-                }
-                let mut new = real_path.clone();
-                new.as_mut_os_string().push(".");
-                match &**pre {
-                    "gzip" => new.as_mut_os_string().push("gz"),
-                    "br" => new.as_mut_os_string().push("br"),
-                    _ => continue,
-                };
-                // 如果预压缩文件存在
-                if new.exists() {
-                    let file = File::open(new).await?;
-                    let metadata = file.metadata().await?;
-                    if let Some(r) = self.try_cache(req, &metadata).await {
-                        return Ok(Some(r));
-                    }
-                    let data_size = metadata.len();
-                    let mut recv = Body::new_file(file, data_size);
-                    // recv.set_rate_limit(RateLimitLayer::new(10240, Duration::from_millis(100)));
-                    match &**pre {
-                        "gzip" => recv.set_compress_origin_gzip(),
-                        "br" => recv.set_compress_brotli(),
-                        _ => unreachable!(),
-                    }
-                    let builder = Response::builder()
-                        .version(req.version().clone())
-                        .status(200);
-                    let mut response = builder
-                        .header(HeaderName::CONTENT_ENCODING, pre.to_string())
-                        .header(
-                            HeaderName::CONTENT_TYPE,
-                            format!("{}; charset=utf-8", application),
-                        )
-                        .header(HeaderName::TRANSFER_ENCODING, "chunked")
-                        .body(recv)
-                        .map_err(|_err| io::Error::new(io::ErrorKind::Other, ""))?;
-                    self.after_file_response(req, &mut response, Some(&metadata))
-                        .await?;
-                    return Ok(Some(response));
-                }
+        let (sidecar, has_sidecar) = self.find_precompressed(req, &real_path).await;
+        if let Some((encoding, file, metadata)) = sidecar {
+            if let Some(r) = self.try_cache(req, &metadata).await {
+                return Ok(Some(r));
             }
+            let data_size = metadata.len();
+            let mut recv = Body::new_file(file, data_size);
+            // recv.set_rate_limit(RateLimitLayer::new(10240, Duration::from_millis(100)));
+            match encoding {
+                Encoding::Gzip => recv.set_compress_origin_gzip(),
+                _ => recv.set_compress_brotli(),
+            }
+            let builder = Response::builder()
+                .version(req.version())
+                .status(200);
+            let mut response = builder
+                .header(HeaderName::CONTENT_ENCODING, encoding.name())
+                .header(HeaderName::VARY, "Accept-Encoding")
+                .header(
+                    HeaderName::CONTENT_TYPE,
+                    format!("{}; charset=utf-8", application),
+                )
+                .header(HeaderName::TRANSFER_ENCODING, "chunked")
+                .body(recv)
+                .map_err(|_err| io::Error::other(""))?;
+            // ETag及Last-Modified按预压缩的文件计算
+            self.after_file_response(req, &mut response, Some(&metadata))
+                .await?;
+            return Ok(Some(response));
         }
 
         if !real_path.exists() {
@@ -361,40 +622,42 @@ impl FileServer {
             .header(HeaderName::TRANSFER_ENCODING, "chunked")
             .body(recv)
             .map_err(|_err| io::Error::new(io::ErrorKind::Other, ""))?;
+        if has_sidecar {
+            response.headers_mut().insert(HeaderName::VARY, "Accept-Encoding");
+        }
         self.after_file_response(req, &mut response, Some(&metadata))
             .await?;
+        self.compress_file(req, &mut response, &application, data_size);
         return Ok(Some(response));
     }
 
+    /// 文件未修改时返回304, 带`If-None-Match`时忽略`If-Modified-Since`, 仅处理GET及HEAD
     pub async fn try_cache(
         &self,
         req: &mut RecvRequest,
         metadata: &Metadata,
     ) -> Option<RecvResponse> {
-        if let Some(value) = req.headers().get_str_value(&"If-None-Match") {
-            if value == Self::calc_etag(metadata) {
-                let mut res = Response::builder().status(304).body(Body::empty()).unwrap();
-                let _ = self
-                    .after_file_response(req, &mut res, Some(metadata))
-                    .await;
-                return Some(res);
-            }
+        if req.method() != &Method::Get && req.method() != &Method::Head {
+            return None;
         }
-
-        if let Some(value) = req.headers().get_str_value(&"If-Modified-Since") {
-            if let Ok(last) = metadata.modified() {
-                if let Ok(n) = last.duration_since(SystemTime::UNIX_EPOCH) {
-                    if Self::calc_lastmodifed(&value) == n.as_secs() {
-                        let mut res = Response::builder().status(304).body(Body::empty()).unwrap();
-                        let _ = self
-                            .after_file_response(req, &mut res, Some(metadata))
-                            .await;
-                        return Some(res);
-                    }
+        let not_modified = match req.headers().get_str_value(&HeaderName::IF_NONE_MATCH) {
+            Some(value) => Self::is_etag_match(&value, &Self::calc_etag(metadata)),
+            None => match req.headers().get_str_value(&HeaderName::IF_MODIFIED_SINCE) {
+                Some(value) => {
+                    let since = Self::calc_lastmodifed(&value);
+                    Self::modified_secs(metadata).map(|m| since != 0 && m <= since).unwrap_or(false)
                 }
-            }
+                None => false,
+            },
+        };
+        if !not_modified {
+            return None;
         }
-        None
+        let mut res = Response::builder().status(304).body(Body::empty()).unwrap();
+        let _ = self
+            .after_file_response(req, &mut res, Some(metadata))
+            .await;
+        Some(res)
     }
 
     pub fn calc_bytes_range(val: &str, len: u64) -> Option<(u64, u64)> {
@@ -477,6 +740,10 @@ impl FileServer {
             }
             res.headers_mut()
                 .insert(HeaderName::ETAG, Self::calc_etag(&data));
+            // 304不返回内容, 无需处理Range及HEAD
+            if res.status().as_u16() == 304 {
+                return Ok(());
+            }
 
             let accept_range = req.headers().get_str_value(&HeaderName::ACCEPT_RANGES);
 
@@ -527,6 +794,10 @@ impl FileServer {
     }
 
     pub async fn deal_request(&self, req: &mut RecvRequest) -> ProtResult<Response<Body>> {
+        // 先于目录及文件的查找, 避免未验证时通过状态码判断文件是否存在
+        if let Some(res) = self.check_auth(req) {
+            return Ok(res);
+        }
         let mut path = req.path().clone();
         if path == "/robots.txt" && self.robots.is_some() {
             let robots = self.robots.clone().unwrap();
@@ -557,22 +828,31 @@ impl FileServer {
             root = CURRENT_DIR.clone();
         }
         let root_path = Path::new(&root);
-        let mut href = path.strip_prefix(&self.prefix).unwrap().to_string();
-        if !href.starts_with("/") {
-            href = "/".to_string() + &href;
+        // 必须保证不会跑出root设置的目录之外，如故意访问`../`之类的
+        let href = match Self::normalize_path(path.strip_prefix(&self.prefix).unwrap()) {
+            Some(href) => href,
+            None => return Ok(Self::ret_forbidden(req)),
+        };
+        // 在解码及去除`..`之后检查, 返回与文件不存在时一致
+        if self.is_deny_path(&href) {
+            return Ok(self.ret_error_msg(req, "can't view file").await);
         }
         let real_path = root.clone() + &href;
         let mut real_path = Path::new(&real_path).to_owned();
-        // 必须保证不会跑出root设置的目录之外，如故意访问`../`之类的
-        if !real_path.starts_with(root_path) || self.is_hide_path(root_path.as_ref()) {
-            return Ok(self.ret_error_msg(req, "can't view parent file").await);
+        // 符号链接等解析后超出root的同样拒绝
+        if self.is_escape_path(root_path, &real_path) {
+            return Ok(Self::ret_forbidden(req));
         }
 
         // 访问路径是目录，尝试是否有index的文件，如果有还是以文件访问
         if real_path.is_dir() {
+            // 目录需以`/`结尾, 否则index页面中的相对路径将按上一级目录解析
+            if !req.path().ends_with('/') {
+                return Ok(Self::ret_redirect_dir(req));
+            }
             for index in &self.index {
                 let new_path = real_path.join(index);
-                if new_path.exists() {
+                if new_path.is_file() && !self.is_escape_path(root_path, &new_path) {
                     real_path = new_path;
                     break;
                 }
@@ -582,7 +862,7 @@ impl FileServer {
         // 访问为目录，如果启用目录访问，则返回当前的文件夹的内容
         if real_path.is_dir() {
             if !self.browse {
-                return Ok(self.ret_error_msg(req, "can't view parent file").await);
+                return Ok(self.ret_directory_deny(req).await);
             }
             let mut binary = BinaryMut::new();
             binary.put_slice(HEAD_HTML_PRE.as_bytes());
@@ -596,12 +876,12 @@ impl FileServer {
             for entry in real_path.read_dir()? {
                 if let Ok(entry) = entry {
                     let path = entry.path();
-                    if self.is_hide_path(path.as_ref()) {
-                        continue;
-                    }
                     let new = path.strip_prefix(root_path).unwrap();
                     let value = "/".to_string() + new.to_str().unwrap();
                     let value = value.replace("\\", "/");
+                    if self.is_hide_path(path.as_ref()) || self.is_deny_path(&value) {
+                        continue;
+                    }
                     let op_ref = if path.is_dir() {
                         &mut folder_binary
                     } else {
@@ -677,3 +957,310 @@ impl FileServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use webparse::{BinaryMut, Buf, Request};
+    use wenmeng::Body;
+
+    use super::FileServer;
+
+    async fn request_dir(server: &FileServer) -> u16 {
+        let mut req = Request::builder()
+            .method("GET")
+            .url("/empty/")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.deal_request(&mut req).await.unwrap();
+        res.status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn directory_deny() {
+        let root = std::env::temp_dir().join(format!("wmproxy_file_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("empty")).unwrap();
+        let page = root.join("deny.html");
+        std::fs::write(&page, "deny page").unwrap();
+
+        let mut server = FileServer::new(root.to_string_lossy().to_string(), "".to_string());
+        server.set_browse(false);
+        // 默认与status保持一致
+        assert_eq!(request_dir(&server).await, 404);
+
+        server.directory_deny_status = Some(403);
+        assert_eq!(request_dir(&server).await, 403);
+
+        // 自定义页面以配置的状态码返回
+        server.directory_deny_page = Some(page.to_string_lossy().to_string());
+        let mut req = Request::builder()
+            .method("GET")
+            .url("/empty/")
+            .body(Body::empty())
+            .unwrap();
+        let mut res = server.deal_request(&mut req).await.unwrap();
+        assert_eq!(res.status().as_u16(), 403);
+        let mut body = BinaryMut::new();
+        res.body_mut().read_all(&mut body).await;
+        assert_eq!(body.chunk(), b"deny page");
+
+        server.directory_deny_status = Some(404);
+        assert_eq!(request_dir(&server).await, 404);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    async fn request_file(server: &FileServer, headers: &[(&str, &str)]) -> (u16, Option<String>, Vec<u8>) {
+        let mut builder = Request::builder().method("GET").url("/a.txt");
+        for (k, v) in headers {
+            builder = builder.header(k.to_string(), v.to_string());
+        }
+        let mut req = builder.body(Body::empty()).unwrap();
+        let mut res = server.deal_request(&mut req).await.unwrap();
+        let etag = res.headers().get_str_value(&"ETag");
+        let mut body = BinaryMut::new();
+        if res.status().as_u16() != 304 {
+            res.body_mut().read_all(&mut body).await;
+        }
+        (res.status().as_u16(), etag, body.chunk().to_vec())
+    }
+
+    #[tokio::test]
+    async fn conditional_request() {
+        let root = std::env::temp_dir().join(format!("wmproxy_etag_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let server = FileServer::new(root.to_string_lossy().to_string(), "".to_string());
+
+        let (status, etag, body) = request_file(&server, &[]).await;
+        assert_eq!((status, &body[..]), (200, &b"hello"[..]));
+        let etag = etag.unwrap();
+        assert!(etag.starts_with('"') && etag.ends_with('"'), "{}", etag);
+
+        let (status, _, body) = request_file(&server, &[("If-None-Match", &etag)]).await;
+        assert_eq!((status, body.len()), (304, 0));
+        let weak = format!("\"other\", W/{}", etag);
+        assert_eq!(request_file(&server, &[("If-None-Match", &weak)]).await.0, 304);
+        assert_eq!(request_file(&server, &[("If-None-Match", "\"other\"")]).await.0, 200);
+
+        // 修改时间不晚于If-Modified-Since时返回304
+        let future = FileServer::to_rfc2822(chrono::Utc::now() + chrono::Duration::days(1));
+        assert_eq!(request_file(&server, &[("If-Modified-Since", &future)]).await.0, 304);
+        let past = "Mon, 01 Jan 2001 00:00:00 GMT";
+        assert_eq!(request_file(&server, &[("If-Modified-Since", past)]).await.0, 200);
+        // If-None-Match不匹配时忽略If-Modified-Since
+        let headers = [("If-None-Match", "\"other\""), ("If-Modified-Since", &future)];
+        assert_eq!(request_file(&server, &headers).await.0, 200);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn compress_file() {
+        use std::io::Read;
+
+        let root = std::env::temp_dir().join(format!("wmproxy_gzip_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let html = "<p>hello wmproxy</p>\n".repeat(200);
+        std::fs::write(root.join("a.html"), &html).unwrap();
+        std::fs::write(root.join("small.html"), "<p>hi</p>").unwrap();
+        std::fs::write(root.join("a.png"), &html).unwrap();
+        let server = FileServer::new(root.to_string_lossy().to_string(), "".to_string());
+
+        let request = |path: &str, range: bool| {
+            let mut builder = Request::builder()
+                .method("GET")
+                .url(path.to_string())
+                .header("Accept-Encoding", "gzip, br");
+            if range {
+                builder = builder.header("Range", "bytes=0-9");
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let mut res = server.deal_request(&mut request("/a.html", false)).await.unwrap();
+        assert_eq!(res.headers().get_str_value(&"Content-Encoding").as_deref(), Some("gzip"));
+        assert_eq!(res.headers().get_str_value(&"Vary").as_deref(), Some("Accept-Encoding"));
+        assert!(res.headers().get_str_value(&"ETag").unwrap().starts_with("W/"));
+        let mut body = BinaryMut::new();
+        res.body_mut().read_all(&mut body).await;
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(body.chunk()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, html);
+
+        // 小文件, 非文本类型及Range请求不压缩
+        for (path, range) in [("/small.html", false), ("/a.png", false), ("/a.html", true)] {
+            let res = server.deal_request(&mut request(path, range)).await.unwrap();
+            assert_eq!(res.headers().get_str_value(&"Content-Encoding"), None, "{}", path);
+        }
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn precompressed_file() {
+        let root = std::env::temp_dir().join(format!("wmproxy_precompressed_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.js"), "console.log('wmproxy');").unwrap();
+        std::fs::write(root.join("app.js.gz"), "gzip data").unwrap();
+        std::fs::write(root.join("app.js.br"), "brotli").unwrap();
+        let mut server = FileServer::new(root.to_string_lossy().to_string(), "".to_string());
+        server.try_precompressed = true;
+
+        let request = |accept: &str, etag: Option<String>| {
+            let mut builder = Request::builder().method("GET").url("/app.js");
+            if !accept.is_empty() {
+                builder = builder.header("Accept-Encoding", accept.to_string());
+            }
+            if let Some(etag) = etag {
+                builder = builder.header("If-None-Match", etag);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let gz = std::fs::metadata(root.join("app.js.gz")).unwrap();
+        let res = server.deal_request(&mut request("gzip, br;q=0.5", None)).await.unwrap();
+        assert_eq!(res.headers().get_str_value(&"Content-Encoding").as_deref(), Some("gzip"));
+        assert!(res.headers().get_str_value(&"Content-Type").unwrap().starts_with("application/javascript"));
+        assert_eq!(res.headers().get_str_value(&"ETag"), Some(FileServer::calc_etag(&gz)));
+        let res = server.deal_request(&mut request("br", None)).await.unwrap();
+        assert_eq!(res.headers().get_str_value(&"Content-Encoding").as_deref(), Some("br"));
+
+        // 以预压缩文件的ETag验证缓存
+        let res = server.deal_request(&mut request("gzip", Some(FileServer::calc_etag(&gz)))).await.unwrap();
+        assert_eq!(res.status().as_u16(), 304);
+
+        // 客户端不支持或未开启时返回原文件
+        for accept in ["", "gzip;q=0, identity"] {
+            let res = server.deal_request(&mut request(accept, None)).await.unwrap();
+            assert_eq!(res.headers().get_str_value(&"Vary").as_deref(), Some("Accept-Encoding"));
+            assert_ne!(res.headers().get_str_value(&"Content-Encoding").as_deref(), Some("gzip"));
+        }
+        server.try_precompressed = false;
+        let res = server.deal_request(&mut request("gzip", None)).await.unwrap();
+        assert_ne!(res.headers().get_str_value(&"Content-Encoding").as_deref(), Some("gzip"));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn index_file() {
+        let root = std::env::temp_dir().join(format!("wmproxy_index_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs/default.html")).unwrap();
+        std::fs::write(root.join("docs/home.html"), "home").unwrap();
+        let mut server = FileServer::new(root.to_string_lossy().to_string(), "".to_string());
+        server.index = vec!["default.html".to_string(), "home.html".to_string()];
+
+        let get = |path: &str| Request::builder().method("GET").url(path.to_string()).body(Body::empty()).unwrap();
+        // 目录需以`/`结尾, 保留请求参数
+        let res = server.deal_request(&mut get("/docs?a=1")).await.unwrap();
+        assert_eq!(res.status().as_u16(), 301);
+        assert_eq!(res.headers().get_str_value(&"Location").as_deref(), Some("/docs/?a=1"));
+
+        // 同名的目录不作为index文件
+        let mut res = server.deal_request(&mut get("/docs/")).await.unwrap();
+        assert_eq!(res.status().as_u16(), 200);
+        let mut body = BinaryMut::new();
+        res.body_mut().read_all(&mut body).await;
+        assert_eq!(body.chunk(), b"home");
+
+        server.index = vec!["none.html".to_string()];
+        server.browse = false;
+        assert_eq!(server.deal_request(&mut get("/docs/")).await.unwrap().status().as_u16(), 404);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn basic_auth() {
+        let root = std::env::temp_dir().join(format!("wmproxy_auth_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "secret").unwrap();
+        let mut server = FileServer::new(root.to_string_lossy().to_string(), "".to_string());
+        server.auth = vec!["wmproxy:wmproxy".parse().unwrap(), "admin:123456".parse().unwrap()];
+        server.auth_realm = Some("files".to_string());
+
+        let request = |path: &str, auth: Option<&str>| {
+            let mut builder = Request::builder().method("GET").url(path.to_string());
+            if let Some(auth) = auth {
+                builder = builder.header("Authorization", auth.to_string());
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        // 文件及目录在验证前均不可见
+        for (path, auth) in [("/a.txt", None), ("/none.txt", None), ("/", Some("Basic d21wcm94eTp3cm9uZw=="))] {
+            let res = server.deal_request(&mut request(path, auth)).await.unwrap();
+            assert_eq!(res.status().as_u16(), 401, "{}", path);
+            assert_eq!(
+                res.headers().get_str_value(&"WWW-Authenticate").as_deref(),
+                Some("Basic realm=\"files\", charset=\"UTF-8\"")
+            );
+        }
+        // admin:123456
+        let res = server.deal_request(&mut request("/a.txt", Some("Basic YWRtaW46MTIzNDU2"))).await.unwrap();
+        assert_eq!(res.status().as_u16(), 200);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn deny_path() {
+        let root = std::env::temp_dir().join(format!("wmproxy_deny_{}", std::process::id()));
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(root.join("private")).unwrap();
+        std::fs::write(root.join(".git/config"), "git").unwrap();
+        std::fs::write(root.join(".env"), "env").unwrap();
+        std::fs::write(root.join("a.bak"), "bak").unwrap();
+        std::fs::write(root.join("a.txt"), "txt").unwrap();
+        std::fs::write(root.join("private/key"), "key").unwrap();
+        let mut server = FileServer::new(root.to_string_lossy().to_string(), "".to_string());
+        server.deny_patterns = vec!["*.bak".to_string(), "/private/*".to_string()];
+
+        let get = |path: &str| Request::builder().method("GET").url(path.to_string()).body(Body::empty()).unwrap();
+        for path in ["/.git/config", "/.env", "/a/..%2f.env", "/private/%2e/key", "/a.bak", "/private/key"] {
+            let res = server.deal_request(&mut get(path)).await.unwrap();
+            assert_eq!(res.status().as_u16(), 404, "{}", path);
+        }
+        assert_eq!(server.deal_request(&mut get("/a.txt")).await.unwrap().status().as_u16(), 200);
+
+        // 目录列表中不显示
+        let mut res = server.deal_request(&mut get("/")).await.unwrap();
+        let mut body = BinaryMut::new();
+        res.body_mut().read_all(&mut body).await;
+        let body = String::from_utf8_lossy(body.chunk()).to_string();
+        assert!(body.contains("a.txt") && !body.contains(".env") && !body.contains(".git") && !body.contains("a.bak"));
+
+        server.hide_dotfiles = false;
+        assert_eq!(server.deal_request(&mut get("/.env")).await.unwrap().status().as_u16(), 200);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn path_escape() {
+        let base = std::env::temp_dir().join(format!("wmproxy_escape_{}", std::process::id()));
+        let root = base.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(base.join("outside")).unwrap();
+        std::fs::write(base.join("outside/secret"), "secret").unwrap();
+        std::fs::write(root.join("sub/a.txt"), "a").unwrap();
+        std::os::unix::fs::symlink(base.join("outside"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(root.join("sub/a.txt"), root.join("inner.txt")).unwrap();
+        let mut server = FileServer::new(root.to_string_lossy().to_string(), "".to_string());
+
+        let get = |path: &str| Request::builder().method("GET").url(path.to_string()).body(Body::empty()).unwrap();
+        let status = |server: &FileServer, path: &str| {
+            let server = server.clone();
+            let mut req = get(path);
+            async move { server.deal_request(&mut req).await.unwrap().status().as_u16() }
+        };
+        for path in [
+            "/../../etc/passwd",
+            "/sub/%2e%2e/%2e%2e/etc/passwd",
+            "/..%2f..%2fetc%2fpasswd",
+            "/sub%5c..%5c..%5cetc%5cpasswd",
+            "/link/secret",
+            "/inner.txt",
+        ] {
+            assert_eq!(status(&server, path).await, 403, "{}", path);
+        }
+        assert_eq!(status(&server, "/sub/../sub/a.txt").await, 200);
+
+        // 允许符号链接时目标仍需在root之内
+        server.follow_symlinks = true;
+        assert_eq!(status(&server, "/inner.txt").await, 200);
+        assert_eq!(status(&server, "/link/secret").await, 403);
+        let _ = std::fs::remove_dir_all(&base);
+    }
+}
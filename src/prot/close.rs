@@ -19,39 +19,118 @@ use crate::{
 
 use super::{ProtFrameHeader, read_short_string, write_short_string};
 
+/// 关闭的原因, 客户端据此决定是否重连
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// 正常关闭, 旧版本未携带关闭码时也视为该值
+    Normal,
+    /// 认证失败, 重连也无法成功, 不再重连
+    AuthFailed,
+    /// 超出服务端的限额
+    QuotaExceeded,
+    /// 服务端关闭或重启, 稍后可重连
+    Shutdown,
+    /// 协议错误
+    ProtocolError,
+    /// 半关闭, 对端已不再发送数据, 另一方向仍可继续传输, 旧版本视为正常关闭
+    HalfClose,
+    /// 未知的关闭码, 由更新版本的对端发送
+    Unknown(u8),
+}
+
+impl CloseCode {
+    /// 收到该关闭码后是否还需再重连
+    pub fn is_reconnect(&self) -> bool {
+        !matches!(self, CloseCode::AuthFailed)
+    }
+}
+
+impl From<u8> for CloseCode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => CloseCode::Normal,
+            1 => CloseCode::AuthFailed,
+            2 => CloseCode::QuotaExceeded,
+            3 => CloseCode::Shutdown,
+            4 => CloseCode::ProtocolError,
+            5 => CloseCode::HalfClose,
+            v => CloseCode::Unknown(v),
+        }
+    }
+}
+
+impl From<CloseCode> for u8 {
+    fn from(value: CloseCode) -> Self {
+        match value {
+            CloseCode::Normal => 0,
+            CloseCode::AuthFailed => 1,
+            CloseCode::QuotaExceeded => 2,
+            CloseCode::Shutdown => 3,
+            CloseCode::ProtocolError => 4,
+            CloseCode::HalfClose => 5,
+            CloseCode::Unknown(v) => v,
+        }
+    }
+}
+
 /// 旧的Socket连接关闭, 接收到则关闭掉当前的连接
+///
+/// 关闭码追加在原因之后, 旧版本按帧长度解析时将忽略该字节
 #[derive(Debug)]
 pub struct ProtClose {
     sock_map: u64,
+    code: CloseCode,
     reason: String,
 }
 
 impl ProtClose {
     pub fn new(sock_map: u64) -> ProtClose {
-        ProtClose { sock_map, reason: String::new() }
+        Self::new_by_code(sock_map, CloseCode::Normal, String::new())
     }
 
     pub fn new_by_reason(sock_map: u64, reason: String) -> ProtClose {
-        ProtClose { sock_map, reason }
+        Self::new_by_code(sock_map, CloseCode::Normal, reason)
+    }
+
+    pub fn new_by_code(sock_map: u64, code: CloseCode, reason: String) -> ProtClose {
+        ProtClose {
+            sock_map,
+            code,
+            reason,
+        }
     }
 
     pub fn parse<T: Buf>(header: ProtFrameHeader, mut buf: T) -> ProxyResult<ProtClose> {
+        let remaining = buf.remaining();
         let reason = read_short_string(&mut buf)?;
+        // 旧版本不携带关闭码
+        let read = remaining - buf.remaining();
+        let code = if header.length as usize > read && buf.has_remaining() {
+            CloseCode::from(buf.get_u8())
+        } else {
+            CloseCode::Normal
+        };
         Ok(ProtClose {
             sock_map: header.sock_map(),
+            code,
             reason,
         })
     }
 
     pub fn encode<B: Buf + BufMut>(self, buf: &mut B) -> ProxyResult<usize> {
         let mut head = ProtFrameHeader::new(ProtKind::Close, ProtFlag::zero(), self.sock_map);
-        head.length = self.reason.as_bytes().len() as u32 + 1;
+        head.length = self.reason.len() as u32 + 2;
         let mut size = 0;
         size += head.encode(buf)?;
         size += write_short_string(buf, &self.reason)?;
+        size += buf.put_u8(self.code.into());
         Ok(size)
     }
 
+    pub fn code(&self) -> CloseCode {
+        self.code
+    }
+
     pub fn sock_map(&self) -> u64 {
         self.sock_map
     }
@@ -60,3 +139,69 @@ impl ProtClose {
         &self.reason
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use webparse::BinaryMut;
+
+    use super::{CloseCode, ProtClose};
+    use crate::{
+        prot::{write_short_string, ProtFlag, ProtKind},
+        Helper, ProtFrame, ProtFrameHeader,
+    };
+
+    fn decode(buf: &mut BinaryMut) -> ProtClose {
+        match Helper::decode_frame(buf).unwrap().unwrap() {
+            ProtFrame::Close(p) => p,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn code_round_trip() {
+        for code in [
+            CloseCode::Normal,
+            CloseCode::AuthFailed,
+            CloseCode::QuotaExceeded,
+            CloseCode::Shutdown,
+            CloseCode::ProtocolError,
+            CloseCode::HalfClose,
+            CloseCode::Unknown(200),
+        ] {
+            let mut buf = BinaryMut::new();
+            ProtClose::new_by_code(0, code, "closed".to_string())
+                .encode(&mut buf)
+                .unwrap();
+            // 后续的帧不受影响
+            ProtFrame::new_close(3).encode(&mut buf).unwrap();
+            let p = decode(&mut buf);
+            assert_eq!(p.code(), code);
+            assert_eq!(p.reason(), "closed");
+            let p = decode(&mut buf);
+            assert_eq!((p.sock_map(), p.code()), (3, CloseCode::Normal));
+            assert_eq!(CloseCode::from(u8::from(code)), code);
+        }
+        assert!(!CloseCode::AuthFailed.is_reconnect());
+        assert!(CloseCode::Shutdown.is_reconnect());
+    }
+
+    #[test]
+    fn parse_without_code() {
+        // 旧版本的格式, 仅有原因
+        let mut buf = BinaryMut::new();
+        let reason = "not verify so close";
+        let mut head = ProtFrameHeader::new(ProtKind::Close, ProtFlag::zero(), 0);
+        head.length = reason.len() as u32 + 1;
+        head.encode(&mut buf).unwrap();
+        write_short_string(&mut buf, reason).unwrap();
+        // 紧随的数据帧不能被当作关闭码
+        ProtFrame::new_data(1, vec![9]).encode(&mut buf).unwrap();
+        let p = decode(&mut buf);
+        assert_eq!(p.code(), CloseCode::Normal);
+        assert_eq!(p.reason(), reason);
+        assert!(matches!(
+            Helper::decode_frame(&mut buf).unwrap().unwrap(),
+            ProtFrame::Data(_)
+        ));
+    }
+}
@@ -10,6 +10,8 @@
 // -----
 // Created Date: 2023/09/22 10:28:28
 
+use std::net::SocketAddr;
+
 use webparse::{Buf, BufMut};
 
 use crate::{
@@ -21,12 +23,15 @@ use super::ProtFrameHeader;
 
 /// 新的Socket连接请求,
 /// 接收方创建一个虚拟链接来对应该Socket的读取写入
+///
+/// 域名之后可附带原始客户端的地址, 旧版本的接收方将忽略该部分
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct ProtCreate {
     sock_map: u64,
     mode: u8,
     domain: Option<String>,
+    client_addr: Option<SocketAddr>,
 }
 
 impl ProtCreate {
@@ -35,10 +40,13 @@ impl ProtCreate {
             sock_map,
             mode: 0,
             domain,
+            client_addr: None,
         }
     }
 
     pub fn parse<T: Buf>(header: ProtFrameHeader, mut buf: T) -> ProxyResult<ProtCreate> {
+        // buf中可能包含后续的帧, 以头部的长度为准
+        let total = header.length as usize;
         let length = buf.get_u8() as usize;
         let mut domain = None;
         if length > buf.remaining() {
@@ -47,11 +55,22 @@ impl ProtCreate {
         if length > 0 {
             let data = &buf.chunk()[..length];
             domain = Some(String::from_utf8_lossy(data).to_string());
+            buf.advance(length);
+        }
+        let mut client_addr = None;
+        if total > 1 + length {
+            let length = buf.get_u8() as usize;
+            if length > buf.remaining() {
+                return Err(crate::ProxyError::TooShort);
+            }
+            let data = &buf.chunk()[..length];
+            client_addr = String::from_utf8_lossy(data).parse::<SocketAddr>().ok();
         }
         Ok(ProtCreate {
             sock_map: header.sock_map(),
             mode: 0,
             domain,
+            client_addr,
         })
     }
 
@@ -66,13 +85,21 @@ impl ProtCreate {
             .as_ref()
             .map(|s| s.as_bytes().len() as u32)
             .unwrap_or(0);
+        let addr = self.client_addr.map(|a| a.to_string());
         head.length = 1 + domain_len;
+        if let Some(addr) = &addr {
+            head.length += 1 + addr.len() as u32;
+        }
         let mut size = 0;
         size += head.encode(buf)?;
         size += buf.put_u8(domain_len as u8);
         if let Some(d) = &self.domain {
             size += buf.put_slice(d.as_bytes());
         }
+        if let Some(addr) = &addr {
+            size += buf.put_u8(addr.len() as u8);
+            size += buf.put_slice(addr.as_bytes());
+        }
         Ok(size)
     }
 
@@ -83,4 +110,44 @@ impl ProtCreate {
     pub fn domain(&self) -> &Option<String> {
         &self.domain
     }
+
+    /// 设置原始客户端的地址, 接收方可据此发送PROXY protocol头
+    pub fn set_client_addr(&mut self, addr: Option<SocketAddr>) {
+        self.client_addr = addr;
+    }
+
+    pub fn client_addr(&self) -> &Option<SocketAddr> {
+        &self.client_addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::BinaryMut;
+
+    use crate::{Helper, ProtCreate, ProtFrame};
+
+    #[test]
+    fn client_addr_round_trip() {
+        let mut create = ProtCreate::new(3, Some("soft.wm-proxy.com".to_string()));
+        create.set_client_addr(Some("203.0.113.7:51234".parse().unwrap()));
+        let mut buf = BinaryMut::new();
+        ProtFrame::Create(create).encode(&mut buf).unwrap();
+        match Helper::decode_frame(&mut buf).unwrap().unwrap() {
+            ProtFrame::Create(p) => {
+                assert_eq!(p.domain(), &Some("soft.wm-proxy.com".to_string()));
+                assert_eq!(p.client_addr(), &Some("203.0.113.7:51234".parse().unwrap()));
+            }
+            _ => unreachable!(),
+        }
+
+        // 未附带地址时与旧版本的格式一致, 且不会读取到后续的帧
+        let mut buf = BinaryMut::new();
+        ProtFrame::new_create(4, None).encode(&mut buf).unwrap();
+        ProtFrame::new_data(4, b"127.0.0.1:80".to_vec()).encode(&mut buf).unwrap();
+        match Helper::decode_frame(&mut buf).unwrap().unwrap() {
+            ProtFrame::Create(p) => assert_eq!(p.client_addr(), &None),
+            _ => unreachable!(),
+        }
+    }
 }
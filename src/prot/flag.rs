@@ -23,6 +23,8 @@ bitflags! {
         const CLOSE = 0x4;
         /// 数据消息
         const DATA = 0x8;
+        /// 包体经过brotli压缩, 服务端在连接时以ACK的Mapping消息携带该位表示支持
+        const COMPRESS = 0x10;
     }
 }
 
@@ -74,6 +76,14 @@ impl ProtFlag {
         self.contains(ProtFlag::DATA)
     }
 
+    pub fn compress() -> ProtFlag {
+        ProtFlag::COMPRESS
+    }
+
+    pub fn is_compress(&self) -> bool {
+        self.contains(ProtFlag::COMPRESS)
+    }
+
     pub fn kind(&self) -> Self {
         let mut new = self.clone();
         new.set(ProtFlag::ACK, false);
@@ -15,7 +15,7 @@ use webparse::{Buf, http2::frame::{read_u24, encode_u24}, BufMut};
 
 use crate::{Helper, MappingConfig, ProxyResult};
 
-use super::{ProtCreate, ProtClose, ProtData, ProtFlag, ProtKind, ProtMapping, ProtToken};
+use super::{CloseCode, ProtCreate, ProtClose, ProtData, ProtFlag, ProtKind, ProtMapping, ProtToken};
 
 /// 协议相关头信息
 #[derive(Debug)]
@@ -148,6 +148,10 @@ impl ProtFrame {
         Self::Close(ProtClose::new_by_reason(sock_map, reason))
     }
 
+    pub fn new_close_code(sock_map: u64, code: CloseCode, reason: String) -> Self {
+        Self::Close(ProtClose::new_by_code(sock_map, code, reason))
+    }
+
     pub fn new_data(sock_map: u64, data: Vec<u8>) -> Self {
         Self::Data(ProtData::new(sock_map, data))
     }
@@ -174,6 +178,14 @@ impl ProtFrame {
         }
     }
 
+    /// 仅关闭了对端的写方向, 仍可继续发送数据
+    pub fn is_half_close(&self) -> bool {
+        match self {
+            ProtFrame::Close(s) => s.code() == CloseCode::HalfClose,
+            _ => false
+        }
+    }
+
     pub fn is_data(&self) -> bool {
         match self {
             ProtFrame::Data(_) => true,
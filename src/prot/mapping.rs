@@ -10,6 +10,8 @@
 // -----
 // Created Date: 2023/10/07 09:40:42
 
+use std::io::{self, Read, Write};
+
 use webparse::{Buf, BufMut, BinaryMut, must_have};
 
 use crate::{
@@ -21,21 +23,69 @@ use super::{ProtFrameHeader, read_short_string, write_short_string};
 
 /// 新的Socket连接请求, 
 /// 接收方创建一个虚拟链接来对应该Socket的读取写入
+///
+/// 映射较多时包体较大, 对端支持时可压缩发送: 服务端在连接建立时发送带`ACK|COMPRESS`标识的空Mapping,
+/// 客户端收到后, 包体超过`COMPRESS_THRESHOLD`时以brotli压缩并带上`COMPRESS`标识,
+/// 旧版本的客户端忽略服务端的Mapping消息, 旧版本的服务端不发送该消息, 客户端将以不压缩的方式发送
 #[derive(Debug)]
 pub struct ProtMapping {
     sock_map: u64,
+    flag: ProtFlag,
     pub mappings: Vec<MappingConfig>,
 }
 
 impl ProtMapping {
+    /// 包体超过该大小时才进行压缩
+    pub const COMPRESS_THRESHOLD: usize = 1024;
+    /// 解压后的最大大小, 防止异常的数据占用过多内存
+    const MAX_DECOMPRESS_SIZE: u64 = 16 * 1024 * 1024;
+
     pub fn new(sock_map: u64, mappings: Vec<MappingConfig>) -> Self {
         Self {
             sock_map,
+            flag: ProtFlag::zero(),
             mappings,
         }
     }
 
+    /// 服务端告知客户端支持压缩的Mapping
+    pub fn new_compress_ack() -> Self {
+        Self {
+            sock_map: 0,
+            flag: ProtFlag::ack() | ProtFlag::compress(),
+            mappings: vec![],
+        }
+    }
+
+    /// 是否为对端告知支持压缩的消息, 此消息不携带映射
+    pub fn is_compress_ack(&self) -> bool {
+        self.flag.is_ack() && self.flag.is_compress()
+    }
+
+    /// 编码时允许压缩, 仅在对端支持时设置
+    pub fn set_compress(&mut self, compress: bool) {
+        self.flag.set(ProtFlag::COMPRESS, compress);
+    }
+
     pub fn parse<T: Buf>(header: ProtFrameHeader, mut buf: T) -> ProxyResult<ProtMapping> {
+        let flag = header.flag();
+        let length = header.length as usize;
+        if flag.is_compress() && !flag.is_ack() {
+            must_have!(buf, length)?;
+            let mut data = vec![0u8; length];
+            buf.copy_to_slice(&mut data);
+            let mut origin = vec![];
+            brotli::Decompressor::new(&data[..], 4096)
+                .take(Self::MAX_DECOMPRESS_SIZE)
+                .read_to_end(&mut origin)?;
+            // 以解压后的数据按未压缩的格式解析
+            let mut origin_header = ProtFrameHeader::new(ProtKind::Mapping, ProtFlag::zero(), header.sock_map());
+            origin_header.length = origin.len() as u32;
+            let mut mapping = Self::parse(origin_header, BinaryMut::from(origin))?;
+            mapping.flag = flag;
+            return Ok(mapping);
+        }
+        let start = buf.remaining();
         must_have!(buf, 2)?;
         let len = buf.get_u16() as usize;
         let mut mappings = vec![];
@@ -57,17 +107,30 @@ impl ProtMapping {
             }
             mappings.push(MappingConfig::new(name, mode, domain, headers));
         }
+        // 优先级追加在帧尾, 旧版本的帧无此数据
+        if start - buf.remaining() + 2 <= length {
+            let len = buf.get_u16() as usize;
+            must_have!(buf, len)?;
+            for i in 0..len {
+                let priority = buf.get_u8();
+                if let Some(m) = mappings.get_mut(i) {
+                    m.priority = priority;
+                }
+            }
+        }
         Ok(ProtMapping {
             sock_map: header.sock_map(),
+            flag,
             mappings,
         })
     }
 
     pub fn encode<B: Buf + BufMut>(self, buf: &mut B) -> ProxyResult<usize> {
-        let mut head = ProtFrameHeader::new(ProtKind::Mapping, ProtFlag::zero(), self.sock_map);
+        let mut flag = self.flag;
 
         let mut cache_buf = BinaryMut::with_capacity(100);
         cache_buf.put_u16(self.mappings.len() as u16);
+        let priorities = self.mappings.iter().map(|m| m.priority).collect::<Vec<_>>();
         for m in self.mappings {
             write_short_string(&mut cache_buf, &m.name)?;
             write_short_string(&mut cache_buf, &m.mode)?;
@@ -80,6 +143,25 @@ impl ProtMapping {
                 write_short_string(&mut cache_buf, &value.val)?;
             }
         }
+        cache_buf.put_u16(priorities.len() as u16);
+        for p in priorities {
+            cache_buf.put_u8(p);
+        }
+        // 仅在压缩后更小时才发送压缩的数据
+        if flag.is_compress() && !flag.is_ack() {
+            let compressed = if cache_buf.remaining() > Self::COMPRESS_THRESHOLD {
+                Self::compress(cache_buf.chunk()).ok()
+            } else {
+                None
+            };
+            match compressed {
+                Some(data) if data.len() < cache_buf.remaining() => {
+                    cache_buf = BinaryMut::from(data);
+                }
+                _ => flag.set(ProtFlag::COMPRESS, false),
+            }
+        }
+        let mut head = ProtFrameHeader::new(ProtKind::Mapping, flag, self.sock_map);
         head.length = cache_buf.remaining() as u32;
         let mut size = 0;
         size += head.encode(buf)?;
@@ -87,6 +169,13 @@ impl ProtMapping {
         Ok(size)
     }
 
+    fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut writer = brotli::CompressorWriter::new(vec![], 4096, 9, 22);
+        writer.write_all(data)?;
+        writer.flush()?;
+        Ok(writer.into_inner())
+    }
+
     pub fn sock_map(&self) -> u64 {
         self.sock_map
     }
@@ -100,3 +189,77 @@ impl ProtMapping {
         self.mappings
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use webparse::BinaryMut;
+
+    use super::ProtMapping;
+    use crate::{ConfigHeader, HeaderOper, Helper, MappingConfig, ProtFrame};
+
+    fn build_mappings(count: usize) -> Vec<MappingConfig> {
+        (0..count)
+            .map(|i| {
+                let headers = vec![ConfigHeader::new(
+                    HeaderOper::Add,
+                    i % 2 == 0,
+                    "X-Mapping".to_string(),
+                    format!("mapping-{}", i),
+                )];
+                let mut m = MappingConfig::new(
+                    format!("web{}", i),
+                    "http".to_string(),
+                    format!("web{}.wm-proxy.com", i),
+                    headers,
+                );
+                m.priority = (i % 5) as u8;
+                m
+            })
+            .collect()
+    }
+
+    fn round_trip(mapping: ProtMapping) -> (usize, ProtMapping) {
+        let mut buf = BinaryMut::new();
+        let size = ProtFrame::Mapping(mapping).encode(&mut buf).unwrap();
+        match Helper::decode_frame(&mut buf).unwrap().unwrap() {
+            ProtFrame::Mapping(p) => (size, p),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn compress_round_trip() {
+        let origin = build_mappings(300);
+        let (plain_size, plain) = round_trip(ProtMapping::new(0, origin.clone()));
+        assert!(!plain.flag.is_compress());
+
+        let mut mapping = ProtMapping::new(0, origin.clone());
+        mapping.set_compress(true);
+        let (compress_size, compress) = round_trip(mapping);
+        assert!(compress.flag.is_compress());
+        assert!(compress_size < plain_size);
+
+        for p in [plain, compress] {
+            assert_eq!(p.mappings.len(), origin.len());
+            for (m, o) in p.mappings.iter().zip(origin.iter()) {
+                assert_eq!(m.name, o.name);
+                assert_eq!(m.domain, o.domain);
+                assert_eq!(m.priority, o.priority);
+                assert_eq!(m.headers.len(), 1);
+                assert_eq!(m.headers[0].val, o.headers[0].val);
+                assert_eq!(m.headers[0].is_proxy, o.headers[0].is_proxy);
+            }
+        }
+
+        // 包体较小时不压缩
+        let mut mapping = ProtMapping::new(0, build_mappings(2));
+        mapping.set_compress(true);
+        let (_, small) = round_trip(mapping);
+        assert!(!small.flag.is_compress());
+        assert_eq!(small.mappings.len(), 2);
+
+        let (_, ack) = round_trip(ProtMapping::new_compress_ack());
+        assert!(ack.is_compress_ack());
+        assert!(ack.mappings.is_empty());
+    }
+}
@@ -23,7 +23,7 @@ mod token;
 pub use flag::ProtFlag;
 pub use kind::ProtKind;
 pub use create::ProtCreate;
-pub use close::ProtClose;
+pub use close::{CloseCode, ProtClose};
 pub use data::ProtData;
 pub use mapping::ProtMapping;
 pub use token::ProtToken;
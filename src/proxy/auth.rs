@@ -0,0 +1,190 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/02 14:20:16
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::ConfigBasicAuth;
+
+/// 代理验证时客户端提交的账号信息
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new(username: String, password: String) -> Self {
+        Self { username, password }
+    }
+}
+
+pub type AuthFuture = Pin<Box<dyn Future<Output = bool> + Send>>;
+
+/// 自定义的验证回调, 可对接数据库或者token服务
+#[derive(Clone)]
+pub struct AuthHandler(Arc<dyn Fn(Credentials) -> AuthFuture + Send + Sync>);
+
+impl AuthHandler {
+    pub fn new<F, Fut>(f: F) -> Self
+    where
+        F: Fn(Credentials) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        AuthHandler(Arc::new(move |c| Box::pin(f(c))))
+    }
+}
+
+impl Debug for AuthHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AuthHandler")
+    }
+}
+
+/// 回调的验证结果及过期时间, 同一份代理配置下的所有连接共享
+#[derive(Debug, Clone, Default)]
+pub struct AuthCache(Arc<Mutex<HashMap<Credentials, (bool, Instant)>>>);
+
+/// 代理的验证方式
+/// 未设置回调时只校验静态的账号密码, 设置回调后静态账号仍然可用, 不匹配时再交由回调验证
+/// 回调的结果会短暂缓存, 防止频繁请求验证服务
+#[derive(Debug, Clone)]
+pub struct ProxyAuth {
+    username: Option<String>,
+    password: Option<String>,
+    handler: Option<AuthHandler>,
+    /// 回调的验证结果及过期时间, 克隆后共享
+    cache: AuthCache,
+}
+
+impl ProxyAuth {
+    /// 回调结果的缓存时间
+    pub const CACHE_TIME: Duration = Duration::from_secs(10);
+    /// 缓存数量超过该值时清理过期的数据, 仍超过时淘汰最早过期的
+    const CACHE_CLEAN_SIZE: usize = 1024;
+
+    pub fn new(username: Option<String>, password: Option<String>) -> Self {
+        Self {
+            username,
+            password,
+            handler: None,
+            cache: AuthCache::default(),
+        }
+    }
+
+    pub fn set_handler(&mut self, handler: Option<AuthHandler>) {
+        self.handler = handler;
+    }
+
+    /// 使用外部的缓存, 以便多个连接间共享回调的结果
+    pub fn set_cache(&mut self, cache: AuthCache) {
+        self.cache = cache;
+    }
+
+    /// 是否需要验证
+    pub fn is_required(&self) -> bool {
+        self.handler.is_some() || (self.username.is_some() && self.password.is_some())
+    }
+
+    fn check_static(&self, credentials: &Credentials) -> bool {
+        match (&self.username, &self.password) {
+            // 用户名与密码均做比较, 避免按耗时猜测
+            (Some(u), Some(p)) => {
+                ConfigBasicAuth::constant_eq(u.as_bytes(), credentials.username.as_bytes())
+                    & ConfigBasicAuth::constant_eq(p.as_bytes(), credentials.password.as_bytes())
+            }
+            _ => false,
+        }
+    }
+
+    pub async fn check(&self, credentials: Credentials) -> bool {
+        if self.check_static(&credentials) {
+            return true;
+        }
+        let handler = match &self.handler {
+            Some(handler) => handler,
+            None => return false,
+        };
+        if let Ok(cache) = self.cache.0.lock() {
+            if let Some((succ, expire)) = cache.get(&credentials) {
+                if *expire > Instant::now() {
+                    return *succ;
+                }
+            }
+        }
+        let succ = (handler.0)(credentials.clone()).await;
+        if let Ok(mut cache) = self.cache.0.lock() {
+            let now = Instant::now();
+            if cache.len() >= Self::CACHE_CLEAN_SIZE && !cache.contains_key(&credentials) {
+                cache.retain(|_, v| v.1 > now);
+                if cache.len() >= Self::CACHE_CLEAN_SIZE {
+                    let oldest = cache.iter().min_by_key(|(_, v)| v.1).map(|(k, _)| k.clone());
+                    if let Some(oldest) = oldest {
+                        cache.remove(&oldest);
+                    }
+                }
+            }
+            cache.insert(credentials, (succ, now + Self::CACHE_TIME));
+        }
+        succ
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthHandler, Credentials, ProxyAuth};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[tokio::test]
+    async fn handler_with_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let count = calls.clone();
+        let mut auth = ProxyAuth::new(Some("wmproxy".to_string()), Some("wmproxy".to_string()));
+        auth.set_handler(Some(AuthHandler::new(move |c: Credentials| {
+            count.fetch_add(1, Ordering::Relaxed);
+            async move { c.username == "db_user" && c.password == "db_pass" }
+        })));
+        assert!(auth.is_required());
+
+        // 静态账号无需经过回调
+        assert!(auth.check(Credentials::new("wmproxy".to_string(), "wmproxy".to_string())).await);
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        let user = Credentials::new("db_user".to_string(), "db_pass".to_string());
+        assert!(auth.check(user.clone()).await);
+        assert!(auth.clone().check(user).await);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        assert!(!auth.check_static(&Credentials::new("wmproxy".to_string(), "wmproxx".to_string())));
+        assert!(!auth.check(Credentials::new("db_user".to_string(), "wrong".to_string())).await);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+    #[tokio::test]
+    async fn cache_size_limit() {
+        let mut auth = ProxyAuth::new(None, None);
+        auth.set_handler(Some(AuthHandler::new(|_: Credentials| async { false })));
+        for i in 0..ProxyAuth::CACHE_CLEAN_SIZE + 100 {
+            assert!(!auth.check(Credentials::new(format!("user{}", i), "pass".to_string())).await);
+        }
+        // 未过期的缓存也不超过上限
+        assert_eq!(auth.cache.0.lock().unwrap().len(), ProxyAuth::CACHE_CLEAN_SIZE);
+    }
+}
@@ -0,0 +1,447 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/10 14:36:52
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{lookup_host, UdpSocket},
+    sync::mpsc::{channel, Sender},
+};
+use webparse::{Binary, BinaryMut, HeaderName, Method, Response};
+use wenmeng::{Body, RecvRequest, RecvResponse};
+
+/// 默认的UDP代理路径模板`/.well-known/masque/udp/{target_host}/{target_port}/`
+const MASQUE_PREFIX: &str = "/.well-known/masque/udp/";
+/// DATAGRAM的capsule类型
+const CAPSULE_DATAGRAM: u64 = 0x00;
+/// 单个capsule的最大长度, UDP数据最大为65527字节, 另加上context id
+const MAX_CAPSULE_LEN: u64 = 65535 + 8;
+
+/// CONNECT-UDP(RFC 9298)的UDP代理, 用于经HTTP代理转发QUIC/HTTP3的流量
+///
+/// HTTP/1.1为升级方式, 即`GET /.well-known/masque/udp/{host}/{port}/`并附带
+/// `Upgrade: connect-udp`及`Capsule-Protocol: ?1`, 成功后返回101, 之后以capsule(RFC 9297)的格式收发;
+/// HTTP/2为扩展CONNECT(RFC 8441), 即`:method=CONNECT :protocol=connect-udp`, 服务端会在SETTINGS中声明
+/// SETTINGS_ENABLE_CONNECT_PROTOCOL, 成功后返回200, capsule在该流的DATA帧中收发.
+/// 代理未提供HTTP/3的监听, 故不涉及HTTP/3;
+/// 仅转发context id为0的数据, 其它的context及未知的capsule类型将被丢弃,
+/// 目标无法解析或连接时返回400或502
+pub struct ConnectUdp;
+
+impl ConnectUdp {
+    pub const PROTOCOL: &'static str = "connect-udp";
+
+    /// 是否为CONNECT-UDP的请求, 是则返回目标的地址及端口
+    pub fn parse_target(req: &RecvRequest) -> Option<(String, u16)> {
+        let protocol = match req.method() {
+            Method::Get => req.headers().get_str_value(&HeaderName::UPGRADE)?,
+            // HTTP/2的扩展CONNECT
+            Method::Connect => req.headers().get_str_value(&":protocol")?,
+            _ => return None,
+        };
+        if !protocol.trim().eq_ignore_ascii_case(Self::PROTOCOL) {
+            return None;
+        }
+        let path = req.path().strip_prefix(MASQUE_PREFIX)?;
+        let mut iter = path.trim_end_matches('/').split('/');
+        let host = Self::percent_decode(iter.next()?);
+        let port = iter.next()?.parse::<u16>().ok()?;
+        if host.is_empty() || port == 0 || iter.next().is_some() {
+            return None;
+        }
+        Some((host, port))
+    }
+
+    /// IPv6的地址中`:`需编码为`%3A`
+    fn percent_decode(value: &str) -> String {
+        let bytes = value.as_bytes();
+        let mut result = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            // 末尾的`%3A`同样需要解码, 不完整的编码原样保留
+            if bytes[i] == b'%' {
+                let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+                if let Some(v) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    result.push(v);
+                    i += 3;
+                    continue;
+                }
+            }
+            result.push(bytes[i]);
+            i += 1;
+        }
+        String::from_utf8_lossy(&result).to_string()
+    }
+
+    /// 创建连接到目标的UDP
+    pub async fn bind(host: &str, port: u16) -> io::Result<UdpSocket> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addr = lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unknow udp target"))?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(socket)
+    }
+
+    /// 无法连接目标时的返回, 目标不合法为400, 其它为502
+    pub fn error_response(err: &io::Error) -> RecvResponse {
+        log::trace!("CONNECT-UDP连接目标失败:{:?}", err);
+        let status = if err.kind() == io::ErrorKind::InvalidInput {
+            400
+        } else {
+            502
+        };
+        Response::builder()
+            .status(status)
+            .body("")
+            .unwrap()
+            .into_type()
+    }
+
+    /// 建立成功的返回, HTTP/1.1为101升级, HTTP/2的扩展CONNECT为200
+    pub fn accept(req: &mut RecvRequest, socket: UdpSocket) -> (RecvResponse, Option<UdpSocket>) {
+        // 带`:protocol`的CONNECT仅出现在HTTP/2中
+        if req.method() != &Method::Connect {
+            return (Self::response(), Some(socket));
+        }
+        // 请求的body为客户端发来的capsule, 返回的body为发往客户端的capsule
+        let body = std::mem::take(req.body_mut());
+        let (sender, receiver) = channel(10);
+        tokio::spawn(async move {
+            if let Err(e) = Self::relay_body(body, sender, socket).await {
+                log::trace!("CONNECT-UDP转发结束:{:?}", e);
+            }
+        });
+        let res = Response::builder()
+            .status(200)
+            .header("Capsule-Protocol", "?1")
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap()
+            .into_type();
+        (res, None)
+    }
+
+    /// 升级成功的返回
+    pub fn response() -> RecvResponse {
+        Response::builder()
+            .status(101)
+            .header(HeaderName::CONNECTION, "Upgrade")
+            .header(HeaderName::UPGRADE, Self::PROTOCOL)
+            .header("Capsule-Protocol", "?1")
+            .body("")
+            .unwrap()
+            .into_type()
+    }
+
+    /// 在客户端的流及UDP之间转发数据, 任意一端关闭或出错时结束
+    pub async fn relay<T>(inbound: T, socket: UdpSocket) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (read, write) = tokio::io::split(inbound);
+        Self::relay_inner(read, CapsuleWriter::Io(write), socket).await
+    }
+
+    /// HTTP/2的流中转发, 请求的body为读取端, 返回的body为写入端
+    async fn relay_body(
+        body: Body,
+        sender: Sender<(bool, Binary)>,
+        socket: UdpSocket,
+    ) -> io::Result<()> {
+        let writer = CapsuleWriter::<tokio::io::Sink>::Body(sender);
+        Self::relay_inner(BodyReader(body), writer, socket).await
+    }
+
+    async fn relay_inner<R, W>(
+        mut inbound: R,
+        mut writer: CapsuleWriter<W>,
+        socket: UdpSocket,
+    ) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut read = Vec::new();
+        let mut buf = vec![0u8; 16384];
+        let mut udp_buf = vec![0u8; 65536];
+        let mut write = Vec::new();
+        loop {
+            tokio::select! {
+                n = inbound.read(&mut buf) => {
+                    let n = n?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    read.extend_from_slice(&buf[..n]);
+                    while let Some((kind, value)) = Self::decode_capsule(&mut read)? {
+                        if kind != CAPSULE_DATAGRAM {
+                            continue;
+                        }
+                        let mut value = &value[..];
+                        match Self::read_varint(&mut value) {
+                            Some(0) => {
+                                socket.send(value).await?;
+                            }
+                            _ => log::trace!("CONNECT-UDP丢弃未知的context数据"),
+                        }
+                    }
+                }
+                n = socket.recv(&mut udp_buf) => {
+                    let n = n?;
+                    write.clear();
+                    Self::encode_datagram(&udp_buf[..n], &mut write);
+                    writer.write(&write).await?;
+                }
+            }
+        }
+    }
+
+    /// 以context id为0的DATAGRAM capsule编码UDP数据
+    pub fn encode_datagram(payload: &[u8], buf: &mut Vec<u8>) {
+        Self::write_varint(CAPSULE_DATAGRAM, buf);
+        Self::write_varint(payload.len() as u64 + 1, buf);
+        Self::write_varint(0, buf);
+        buf.extend_from_slice(payload);
+    }
+
+    /// 解析一个完整的capsule, 数据不足时返回None
+    pub fn decode_capsule(buf: &mut Vec<u8>) -> io::Result<Option<(u64, Vec<u8>)>> {
+        let mut data = &buf[..];
+        let kind = match Self::read_varint(&mut data) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let len = match Self::read_varint(&mut data) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if len > MAX_CAPSULE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "capsule too large"));
+        }
+        if (data.len() as u64) < len {
+            return Ok(None);
+        }
+        let value = data[..len as usize].to_vec();
+        let used = buf.len() - data.len() + len as usize;
+        buf.drain(..used);
+        Ok(Some((kind, value)))
+    }
+
+    /// QUIC的变长整数(RFC 9000 16节)
+    fn read_varint(data: &mut &[u8]) -> Option<u64> {
+        let first = *data.first()?;
+        let len = 1usize << (first >> 6);
+        if data.len() < len {
+            return None;
+        }
+        let mut value = (first & 0x3f) as u64;
+        for b in &data[1..len] {
+            value = (value << 8) | *b as u64;
+        }
+        *data = &data[len..];
+        Some(value)
+    }
+
+    fn write_varint(value: u64, buf: &mut Vec<u8>) {
+        if value < 1 << 6 {
+            buf.push(value as u8);
+        } else if value < 1 << 14 {
+            buf.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes());
+        } else if value < 1 << 30 {
+            buf.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes());
+        } else {
+            buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes());
+        }
+    }
+}
+
+/// 请求的body作为capsule的读取端
+/// Body无数据时同样返回Ready, 未结束时转为等待, 读到0字节即表示已结束
+struct BodyReader(Body);
+
+impl AsyncRead for BodyReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.0).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == filled && !self.0.is_end() => Poll::Pending,
+            ret => ret,
+        }
+    }
+}
+
+/// capsule的写入端, HTTP/1.1为升级后的连接, HTTP/2为返回的body
+enum CapsuleWriter<W> {
+    Io(W),
+    Body(Sender<(bool, Binary)>),
+}
+
+impl<W: AsyncWrite + Unpin> CapsuleWriter<W> {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            CapsuleWriter::Io(w) => w.write_all(data).await,
+            CapsuleWriter::Body(sender) => sender
+                .send((false, Binary::from(data.to_vec())))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client closed")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UdpSocket,
+    };
+
+    use tokio::sync::mpsc::channel;
+    use webparse::{BinaryMut, Method, Request};
+    use wenmeng::{Body, Client, MaybeHttpsStream};
+
+    use super::{BodyReader, ConnectUdp};
+    use crate::{test_util::read_head, ProxyAuth, ProxyHttp};
+
+    /// UDP的回显服务, 返回端口
+    async fn run_echo() -> u16 {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (n, addr) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], addr).await.unwrap();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn capsule_codec() {
+        for v in [0u64, 63, 64, 16383, 16384, 1 << 30] {
+            let mut buf = vec![];
+            ConnectUdp::write_varint(v, &mut buf);
+            assert_eq!(ConnectUdp::read_varint(&mut &buf[..]), Some(v));
+        }
+        let mut buf = vec![];
+        ConnectUdp::encode_datagram(&[7u8; 100], &mut buf);
+        let mut part = buf[..50].to_vec();
+        assert!(ConnectUdp::decode_capsule(&mut part).unwrap().is_none());
+        let (kind, value) = ConnectUdp::decode_capsule(&mut buf).unwrap().unwrap();
+        assert_eq!(kind, 0);
+        assert_eq!(value.len(), 101);
+        assert_eq!(value[0], 0);
+        assert!(buf.is_empty());
+        assert_eq!(ConnectUdp::percent_decode("2001%3Adb8%3A%3A1"), "2001:db8::1");
+        assert_eq!(ConnectUdp::percent_decode("2001%3Adb8%3A"), "2001:db8:");
+        assert_eq!(ConnectUdp::percent_decode("%3A"), ":");
+        assert_eq!(ConnectUdp::percent_decode("a%3"), "a%3");
+    }
+
+    #[tokio::test]
+    async fn relay_datagram() {
+        let port = run_echo().await;
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let _ = ProxyHttp::process(&ProxyAuth::new(None, None), None, server).await;
+        });
+        let req = format!("GET /.well-known/masque/udp/127.0.0.1/{}/ HTTP/1.1\r\nHost: proxy\r\nConnection: Upgrade\r\nUpgrade: connect-udp\r\nCapsule-Protocol: ?1\r\n\r\n", port);
+        client.write_all(req.as_bytes()).await.unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("http/1.1 101"));
+        assert!(head.contains("capsule-protocol: ?1"));
+
+        let mut buf = vec![];
+        ConnectUdp::encode_datagram(b"quic initial", &mut buf);
+        client.write_all(&buf).await.unwrap();
+        let mut read = vec![];
+        let value = loop {
+            let mut b = [0u8; 64];
+            let n = client.read(&mut b).await.unwrap();
+            assert!(n > 0);
+            read.extend_from_slice(&b[..n]);
+            if let Some((_, value)) = ConnectUdp::decode_capsule(&mut read).unwrap() {
+                break value;
+            }
+        };
+        assert_eq!(&value[1..], b"quic initial");
+    }
+
+    #[tokio::test]
+    async fn relay_datagram_h2() {
+        let port = run_echo().await;
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let _ = ProxyHttp::process(&ProxyAuth::new(None, None), None, server).await;
+        });
+        let client = Client::new(
+            Client::builder().http2_only(true).value(),
+            MaybeHttpsStream::Http(client),
+        );
+        let (sender, receiver) = channel(10);
+        let req = Request::builder()
+            .method(Method::Connect)
+            .url(format!("http://proxy/.well-known/masque/udp/127.0.0.1/{}/", port))
+            .header(":protocol", "connect-udp")
+            .header("Capsule-Protocol", "?1")
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap();
+        let mut res = client.send_now(req).await.unwrap();
+        assert_eq!(res.status().as_u16(), 200);
+        let mut body = BodyReader(std::mem::take(res.body_mut()));
+
+        let mut buf = vec![];
+        ConnectUdp::encode_datagram(b"quic initial", &mut buf);
+        sender.send((false, buf.into())).await.unwrap();
+        let mut read = vec![];
+        let value = loop {
+            let mut b = [0u8; 64];
+            let n = body.read(&mut b).await.unwrap();
+            assert!(n > 0);
+            read.extend_from_slice(&b[..n]);
+            if let Some((_, value)) = ConnectUdp::decode_capsule(&mut read).unwrap() {
+                break value;
+            }
+        };
+        assert_eq!(&value[1..], b"quic initial");
+    }
+
+    #[tokio::test]
+    async fn bind_error_response() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let _ = ProxyHttp::process(&ProxyAuth::new(None, None), None, server).await;
+        });
+        // 无法解析的目标返回错误, 不能直接断开连接
+        let req = "GET /.well-known/masque/udp/unknown.invalid/443/ HTTP/1.1\r\nHost: proxy\r\nConnection: Upgrade\r\nUpgrade: connect-udp\r\nCapsule-Protocol: ?1\r\n\r\n";
+        client.write_all(req.as_bytes()).await.unwrap();
+        let mut b = [0u8; 32];
+        let n = client.read(&mut b).await.unwrap();
+        assert!(String::from_utf8_lossy(&b[..n]).starts_with("HTTP/1.1 502"));
+    }
+}
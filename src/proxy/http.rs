@@ -13,6 +13,8 @@
 use std::{io::Cursor, any::Any};
 
 use crate::{HealthCheck, ProxyError, ConfigHeader, Helper};
+
+use super::{Credentials, ProxyAuth};
 use async_trait::async_trait;
 use tokio::{io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf}, net::{TcpStream}, sync::mpsc::{Receiver, Sender}};
 use webparse::{BinaryMut, BufMut, Method, Response};
@@ -23,10 +25,8 @@ pub struct ProxyHttp {}
 
 /// http代理类处理类
 struct Operate {
-    /// 用户名
-    username: Option<String>,
-    /// 密码
-    password: Option<String>,
+    /// 代理的验证方式
+    auth: ProxyAuth,
     /// Stream类, https连接后给后续https使用
     stream: Option<TcpStream>,
     /// http代理keep-alive的复用
@@ -35,11 +35,14 @@ struct Operate {
     receiver: Option<Receiver<ProtResult<RecvResponse>>>,
     /// 代理http头处理改造
     headers: Option<Vec<ConfigHeader>>,
+    /// CONNECT-UDP升级后的UDP连接
+    #[cfg(feature = "connect-udp")]
+    udp: Option<tokio::net::UdpSocket>,
 }
 
 impl Operate {
     
-    pub fn check_basic_auth(&self, value: &str) -> bool
+    pub async fn check_basic_auth(&self, value: &str) -> bool
     {
         use base64::engine::general_purpose;
         use std::io::Read;
@@ -62,15 +65,28 @@ impl Operate {
             if up.len() != 2 {
                 return false;
             }
-            if up[0] == self.username.as_ref().unwrap() &&
-                up[1] == self.password.as_ref().unwrap() {
-                return true;
-            }
+            return self
+                .auth
+                .check(Credentials::new(up[0].to_string(), up[1].to_string()))
+                .await;
         }
 
         return false;
     }
 
+    /// 账号密码存在，将获取`Proxy-Authorization`进行校验，如果检验错误返回407协议
+    async fn check_proxy_auth(&self, request: &mut RecvRequest) -> bool {
+        if !self.auth.is_required() {
+            return true;
+        }
+        if let Some(auth) = request.headers_mut().remove(&"Proxy-Authorization") {
+            if let Some(val) = auth.as_string() {
+                return self.check_basic_auth(&val).await;
+            }
+        }
+        false
+    }
+
     fn deal_request(&self, req: &mut RecvRequest) -> ProtResult<()> {
         if let Some(headers) = &self.headers {
             // 复写Request的头文件信息
@@ -102,6 +118,19 @@ impl HttpTrait for Operate {
             }
             return Err(ProtError::Extension("already close by other"))
         }
+        #[cfg(feature = "connect-udp")]
+        if let Some((host, port)) = super::ConnectUdp::parse_target(request) {
+            if !self.check_proxy_auth(request).await {
+                return Ok(Response::builder().status(407).body("")?.into_type());
+            }
+            let socket = match super::ConnectUdp::bind(&host, port).await {
+                Ok(socket) => socket,
+                Err(e) => return Ok(super::ConnectUdp::error_response(&e)),
+            };
+            let (res, udp) = super::ConnectUdp::accept(request, socket);
+            self.udp = udp;
+            return Ok(res);
+        }
         // 获取要连接的对象
         let stream = if let Some(host) = request.get_connect_url() {
             match HealthCheck::connect(&host).await {
@@ -114,17 +143,8 @@ impl HttpTrait for Operate {
             return Err(ProtError::Extension("unknow tcp stream"));
         };
 
-        // 账号密码存在，将获取`Proxy-Authorization`进行校验，如果检验错误返回407协议
-        if self.username.is_some() && self.password.is_some() {
-            let mut is_auth = false;
-            if let Some(auth) = request.headers_mut().remove(&"Proxy-Authorization") {
-                if let Some(val) = auth.as_string() {
-                    is_auth = self.check_basic_auth(&val);
-                }
-            }
-            if !is_auth {
-                return Ok(Response::builder().status(407).body("")?.into_type());
-            }
+        if !self.check_proxy_auth(request).await {
+            return Ok(Response::builder().status(407).body("")?.into_type());
         }
 
         // 判断用户协议
@@ -152,6 +172,12 @@ impl HttpTrait for Operate {
         }
     }
 
+    /// CONNECT-UDP升级后不再处理HTTP请求, 后续为capsule数据
+    #[cfg(feature = "connect-udp")]
+    fn is_continue_next(&self) -> bool {
+        self.udp.is_none()
+    }
+
     fn as_any(&self) -> Option<&dyn Any> {
         Some(self)
     }
@@ -163,8 +189,7 @@ impl HttpTrait for Operate {
 
 impl ProxyHttp {
     pub async fn process<T>(
-        username: &Option<String>,
-        password: &Option<String>,
+        auth: &ProxyAuth,
         headers: Option<Vec<ConfigHeader>>,
         mut inbound: T,
     ) -> Result<(), ProxyError<T>>
@@ -200,17 +225,23 @@ impl ProxyHttp {
         let mut server = Server::new_by_cache(inbound, None, buffer);
         // 构建HTTP服务回调
         let operate = Operate {
-            username: username.clone(),
-            password: password.clone(),
+            auth: auth.clone(),
             stream: None,
             sender: None,
             receiver: None,
             headers,
+            #[cfg(feature = "connect-udp")]
+            udp: None,
         };
         server.set_max_req(max_req_num);
+        // 声明支持扩展CONNECT, 以便HTTP/2的CONNECT-UDP
+        #[cfg(feature = "connect-udp")]
+        server.set_http2_builder(wenmeng::Builder::new().enable_connect_protocol());
         server.set_callback_http(Box::new(operate));
         let _e = server.incoming().await?;
         let mut tcp_out = None;
+        #[cfg(feature = "connect-udp")]
+        let mut udp_out = None;
         {
             let mut operate = server.take_callback_http().unwrap();
             if let Some(v) = operate.as_any_mut() {
@@ -218,9 +249,18 @@ impl ProxyHttp {
                     if let Some(outbound) = v.stream.take() {
                         tcp_out = Some(outbound);
                     }
+                    #[cfg(feature = "connect-udp")]
+                    {
+                        udp_out = v.udp.take();
+                    }
                 }
             }
         }
+        #[cfg(feature = "connect-udp")]
+        if let Some(udp) = udp_out {
+            super::ConnectUdp::relay(server.into_io(), udp).await?;
+            return Ok(());
+        }
         if tcp_out.is_some() {
             let mut inbound = server.into_io();
             let _ = copy_bidirectional(&mut inbound, tcp_out.as_mut().unwrap()).await?;
@@ -1,6 +1,14 @@
 
+mod auth;
 pub mod http;
 pub mod socks5;
 mod server;
+mod outbound;
+#[cfg(feature = "connect-udp")]
+mod connect_udp;
 
+pub use auth::{AuthCache, AuthFuture, AuthHandler, Credentials, ProxyAuth};
 pub use server::ProxyServer;
+pub use outbound::ConfigUpstreamProxy;
+#[cfg(feature = "connect-udp")]
+pub use connect_udp::ConnectUdp;
@@ -5,27 +5,28 @@ use webparse::BinaryMut;
 
 use crate::{Flag, error::ProxyTypeResult, ProxyError, ProxyHttp, ProxySocks5, ConfigHeader};
 
+use super::ProxyAuth;
+
 /// 代理服务器类, 提供代理服务
 pub struct ProxyServer {
     flag: Flag,
-    username: Option<String>,
-    password: Option<String>,
+    /// 代理的验证方式
+    auth: ProxyAuth,
     udp_bind: Option<IpAddr>,
     headers: Option<Vec<ConfigHeader>>,
 }
 
 impl ProxyServer {
+    /// 验证方式由外部传入, 以便多个连接共享回调的验证缓存
     pub fn new(
         flag: Flag,
-        username: Option<String>,
-        password: Option<String>,
+        auth: ProxyAuth,
         udp_bind: Option<IpAddr>,
         headers: Option<Vec<ConfigHeader>>,
     ) -> Self {
         ProxyServer {
             flag,
-            username,
-            password,
+            auth,
             udp_bind,
             headers,
         }
@@ -68,7 +69,7 @@ impl ProxyServer {
         T: AsyncRead + AsyncWrite + Unpin,
     {
         if self.flag.contains(Flag::HTTP) || self.flag.contains(Flag::HTTPS) {
-            ProxyHttp::process(&self.username, &self.password, self.headers.take(), inbound).await
+            ProxyHttp::process(&self.auth, self.headers.take(), inbound).await
         } else {
             Err(ProxyError::Continue((None, inbound)))
         }
@@ -83,10 +84,60 @@ impl ProxyServer {
         T: AsyncRead + AsyncWrite + Unpin,
     {
         if self.flag.contains(Flag::SOCKS5) {
-            let mut sock = ProxySocks5::new(self.username, self.password, self.udp_bind);
+            let mut sock = ProxySocks5::new_by_auth(self.auth, self.udp_bind);
             sock.process(inbound, buffer).await
         } else {
             Err(ProxyError::Continue((buffer, inbound)))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use base64::{engine::general_purpose, Engine};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::ProxyServer;
+    use crate::{AuthHandler, Credentials, Flag, ProxyConfig};
+
+    #[tokio::test]
+    async fn auth_cache_shared_between_connections() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let count = calls.clone();
+        let option = ProxyConfig::builder()
+            .flag(Flag::HTTP)
+            .auth_handler(Some(AuthHandler::new(move |_: Credentials| {
+                count.fetch_add(1, Ordering::Relaxed);
+                async move { false }
+            })))
+            .into_value()
+            .unwrap();
+
+        // 目标地址需可连接, 验证在连接之后进行
+        let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = target.local_addr().unwrap();
+        let token = general_purpose::STANDARD.encode("db_user:wrong");
+        for _ in 0..2 {
+            // 每个连接都按配置重新生成验证方式, 与wmcore中的处理一致
+            let server = ProxyServer::new(option.flag, option.build_auth(), None, None);
+            let (mut client, inbound) = tokio::io::duplex(1024);
+            tokio::spawn(async move {
+                let _ = server.deal_proxy(inbound).await;
+            });
+            let req = format!(
+                "GET http://{addr}/ HTTP/1.1\r\nHost: {addr}\r\nProxy-Authorization: Basic {token}\r\n\r\n"
+            );
+            client.write_all(req.as_bytes()).await.unwrap();
+            let mut buf = [0u8; 64];
+            let n = client.read(&mut buf).await.unwrap();
+            assert!(String::from_utf8_lossy(&buf[..n]).contains("407"));
+        }
+        // 第二个连接命中第一个连接的缓存
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+}
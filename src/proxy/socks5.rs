@@ -15,6 +15,7 @@ use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
 };
 
+use super::{Credentials, ProxyAuth};
 use crate::{error::ProxyTypeResult, HealthCheck, ProxyError, ProxyResult};
 use tokio::{
     io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
@@ -26,8 +27,8 @@ use webparse::{BinaryMut, Buf, BufMut};
 
 /// socks5代理类处理流程
 pub struct ProxySocks5 {
-    username: Option<String>,
-    password: Option<String>,
+    /// 代理的验证方式
+    auth: ProxyAuth,
     bind_ip: Option<IpAddr>,
 }
 
@@ -46,11 +47,11 @@ impl ProxySocks5 {
         password: Option<String>,
         bind_ip: Option<IpAddr>,
     ) -> Self {
-        Self {
-            username,
-            password,
-            bind_ip,
-        }
+        Self::new_by_auth(ProxyAuth::new(username, password), bind_ip)
+    }
+
+    pub fn new_by_auth(auth: ProxyAuth, bind_ip: Option<IpAddr>) -> Self {
+        Self { auth, bind_ip }
     }
 
     /// 读取的信息, 并返回验证方法, 如果没有用户密码则表示无需认证
@@ -92,22 +93,17 @@ impl ProxySocks5 {
         }
         let user_len = buffer.get_u8() as usize;
         let _ = ProxySocks5::read_len(stream, buffer, user_len).await?;
-        if let Some(v) = &self.username {
-            if user_len == 0 || v.as_bytes() != &buffer.chunk()[0..user_len] {
-                return Ok(false);
-            }
-            buffer.advance(user_len);
-        }
+        let username = String::from_utf8_lossy(&buffer.chunk()[0..user_len]).to_string();
+        buffer.advance(user_len);
         let _ = ProxySocks5::read_len(stream, buffer, 1).await?;
         let pass_len = buffer.get_u8() as usize;
         let _ = ProxySocks5::read_len(stream, buffer, pass_len).await?;
-        if let Some(v) = &self.password {
-            if pass_len == 0 || v.as_bytes() != &buffer.chunk()[0..pass_len] {
-                return Ok(false);
-            }
-            buffer.advance(pass_len);
+        let password = String::from_utf8_lossy(&buffer.chunk()[0..pass_len]).to_string();
+        buffer.advance(pass_len);
+        if user_len == 0 || pass_len == 0 {
+            return Ok(false);
         }
-        Ok(true)
+        Ok(self.auth.check(Credentials::new(username, password)).await)
     }
 
     /// 读取至少长度为size的大小的字节数, 如果足够则返回Ok(())
@@ -328,7 +324,7 @@ impl ProxySocks5 {
     }
 
     pub fn is_user_password(&self) -> bool {
-        self.username.is_some() && self.password.is_some()
+        self.auth.is_required()
     }
 
     /// +----+-----+-------+------+----------+----------+
@@ -0,0 +1,351 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/18 10:26:37
+
+use std::{
+    fmt::Display,
+    io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use webparse::{Request, Response};
+use wenmeng::Body;
+
+use crate::ConfigDuration;
+
+/// 所有server当前准入处理中的请求数
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// 当前处于过载状态的server数
+static SHEDDING: AtomicUsize = AtomicUsize::new(0);
+/// 因过载被拒绝的请求数
+static SHED: AtomicU64 = AtomicU64::new(0);
+
+/// 请求头中表示高优先级的值
+const HIGH_VALUES: [&str; 2] = ["high", "1"];
+
+/// server的过载准入控制, 过载时拒绝新到达的普通请求, 已在处理的请求不受影响
+///
+/// 配置格式为`max=200 reserve=20 latency=500ms priority=X-Priority high=/api/pay,/login retry_after=5s`
+/// * max: 同时处理的最大请求数, 超过时所有请求返回503
+/// * reserve: 为高优先级请求保留的名额, 处理数达到`max - reserve`后只接受高优先级的请求, 默认为max的1/10
+/// * latency: 响应时间的目标值, 平均的响应时间超过后只接受高优先级的请求, 无请求处理时仍放行一个以更新响应时间
+/// * priority: 表示优先级的请求头, 值为`high`或`1`时为高优先级
+/// * high: 高优先级的路径前缀, 以`,`分隔
+/// * retry_after: 拒绝时返回的`Retry-After`, 默认为1s
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConfigAdmission {
+    pub max: Option<usize>,
+    pub reserve: Option<usize>,
+    pub latency: Option<Duration>,
+    pub priority: Option<String>,
+    pub high: Vec<String>,
+    pub retry_after: Option<Duration>,
+}
+
+impl ConfigAdmission {
+    /// 该请求是否为高优先级
+    pub fn is_high<T: webparse::Serialize>(&self, req: &Request<T>) -> bool {
+        if let Some(header) = &self.priority {
+            if let Some(value) = req.headers().get_str_value(header) {
+                if HIGH_VALUES.contains(&&*value.trim().to_lowercase()) {
+                    return true;
+                }
+            }
+        }
+        let path = req.path();
+        self.high.iter().any(|p| path.starts_with(p))
+    }
+
+    /// 普通请求可用的名额
+    fn normal_limit(&self) -> Option<usize> {
+        let max = self.max?;
+        let reserve = self.reserve.unwrap_or(max / 10).min(max);
+        Some(max - reserve)
+    }
+}
+
+impl FromStr for ConfigAdmission {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = ConfigAdmission::default();
+        for v in s.split_whitespace() {
+            let err = || io::Error::new(io::ErrorKind::InvalidInput, format!("错误的admission配置:{}", v));
+            let (key, value) = v.split_once('=').ok_or_else(err)?;
+            match key {
+                "max" => config.max = Some(value.parse::<usize>().ok().filter(|v| *v > 0).ok_or_else(err)?),
+                "reserve" => config.reserve = Some(value.parse::<usize>().map_err(|_| err())?),
+                "latency" => config.latency = Some(ConfigDuration::from_str(value)?.0),
+                "priority" if !value.is_empty() => config.priority = Some(value.to_string()),
+                "high" => {
+                    config.high = value
+                        .split(',')
+                        .filter(|p| !p.is_empty())
+                        .map(|p| p.to_string())
+                        .collect()
+                }
+                "retry_after" => config.retry_after = Some(ConfigDuration::from_str(value)?.0),
+                _ => return Err(err()),
+            }
+        }
+        if config.max.is_none() && config.latency.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("admission需配置max或latency:{}", s),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+impl Display for ConfigAdmission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut values = vec![];
+        if let Some(max) = self.max {
+            values.push(format!("max={}", max));
+        }
+        if let Some(reserve) = self.reserve {
+            values.push(format!("reserve={}", reserve));
+        }
+        if let Some(latency) = self.latency {
+            values.push(format!("latency={}", ConfigDuration::new(latency)));
+        }
+        if let Some(priority) = &self.priority {
+            values.push(format!("priority={}", priority));
+        }
+        if !self.high.is_empty() {
+            values.push(format!("high={}", self.high.join(",")));
+        }
+        if let Some(retry) = self.retry_after {
+            values.push(format!("retry_after={}", ConfigDuration::new(retry)));
+        }
+        f.write_str(&values.join(" "))
+    }
+}
+
+/// 准入控制的运行状态, 重载配置时将重新创建
+#[derive(Debug)]
+pub struct Admission {
+    config: ConfigAdmission,
+    in_flight: AtomicUsize,
+    /// 平滑后的响应时间, 单位为微秒
+    latency: AtomicU64,
+    shedding: AtomicBool,
+}
+
+/// 准入的请求, 释放时归还名额并记录响应时间
+#[derive(Debug)]
+pub struct AdmissionGuard {
+    admission: Arc<Admission>,
+    start: Instant,
+}
+
+impl Drop for AdmissionGuard {
+    fn drop(&mut self) {
+        self.admission.in_flight.fetch_sub(1, Ordering::Relaxed);
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+        self.admission.record(self.start.elapsed());
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if self.shedding.load(Ordering::Relaxed) {
+            SHEDDING.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Admission {
+    pub fn new(config: ConfigAdmission) -> Self {
+        Self {
+            config,
+            in_flight: AtomicUsize::new(0),
+            latency: AtomicU64::new(0),
+            shedding: AtomicBool::new(false),
+        }
+    }
+
+    /// 记录响应时间, 以新值占1/5的权重平滑
+    fn record(&self, elapsed: Duration) {
+        let sample = elapsed.as_micros() as u64;
+        let old = self.latency.load(Ordering::Relaxed);
+        let value = if old == 0 { sample } else { (old * 4 + sample) / 5 };
+        self.latency.store(value, Ordering::Relaxed);
+    }
+
+    /// 平滑后的响应时间
+    pub fn latency(&self) -> Duration {
+        Duration::from_micros(self.latency.load(Ordering::Relaxed))
+    }
+
+    /// 当前处理中的请求数
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// 当前是否处于过载状态
+    pub fn is_shedding(&self) -> bool {
+        self.shedding.load(Ordering::Relaxed)
+    }
+
+    fn set_shedding(&self, shedding: bool) {
+        if self.shedding.swap(shedding, Ordering::Relaxed) != shedding {
+            if shedding {
+                log::warn!("请求处理已过载, 开始拒绝普通优先级的请求");
+                SHEDDING.fetch_add(1, Ordering::Relaxed);
+            } else {
+                log::warn!("请求处理已恢复, 停止拒绝请求");
+                SHEDDING.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// 判断是否接受该请求, 过载时返回带`Retry-After`的503
+    pub fn admit<T: webparse::Serialize>(
+        self: &Arc<Self>,
+        req: &Request<T>,
+    ) -> Result<AdmissionGuard, Box<Response<Body>>> {
+        let current = self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let slow = match self.config.latency {
+            Some(latency) => self.latency() > latency,
+            None => false,
+        };
+        let full = self.config.max.map(|max| current >= max).unwrap_or(false);
+        let busy = self
+            .config
+            .normal_limit()
+            .map(|limit| current >= limit)
+            .unwrap_or(false);
+        // 响应变慢时仍在无请求处理时放行一个, 以便更新响应时间
+        let overload = busy || (slow && current > 0);
+        self.set_shedding(overload);
+        if full || (overload && !self.config.is_high(req)) {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            SHED.fetch_add(1, Ordering::Relaxed);
+            let retry = self.config.retry_after.unwrap_or(Duration::from_secs(1));
+            let res = Response::status503()
+                .header("Retry-After", retry.as_secs().max(1).to_string())
+                .body("server overloaded")
+                .unwrap()
+                .into_type();
+            return Err(Box::new(res));
+        }
+        IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        Ok(AdmissionGuard {
+            admission: self.clone(),
+            start: Instant::now(),
+        })
+    }
+
+    /// 所有server当前准入处理中的请求数
+    pub fn in_flight_total() -> usize {
+        IN_FLIGHT.load(Ordering::Relaxed)
+    }
+
+    /// 当前处于过载状态的server数
+    pub fn shedding_total() -> usize {
+        SHEDDING.load(Ordering::Relaxed)
+    }
+
+    /// 因过载被拒绝的请求数
+    pub fn shed_total() -> u64 {
+        SHED.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use webparse::Request;
+
+    use super::{Admission, ConfigAdmission};
+
+    fn build_request(path: &str, priority: Option<&str>) -> Request<String> {
+        let mut builder = Request::builder().url(path);
+        if let Some(p) = priority {
+            builder = builder.header("X-Priority", p.to_string());
+        }
+        builder.body(String::new()).unwrap()
+    }
+
+    #[test]
+    fn parse_admission() {
+        let config = "max=10 reserve=2 latency=500ms priority=X-Priority high=/pay,/login retry_after=5s"
+            .parse::<ConfigAdmission>()
+            .unwrap();
+        assert_eq!(config.max, Some(10));
+        assert_eq!(config.normal_limit(), Some(8));
+        assert_eq!(config.latency, Some(Duration::from_millis(500)));
+        assert_eq!(config.high, vec!["/pay".to_string(), "/login".to_string()]);
+        assert_eq!(format!("{}", config).parse::<ConfigAdmission>().unwrap(), config);
+        assert_eq!("max=20".parse::<ConfigAdmission>().unwrap().normal_limit(), Some(18));
+
+        assert!(config.is_high(&build_request("/pay/order", None)));
+        assert!(config.is_high(&build_request("/", Some("HIGH"))));
+        assert!(!config.is_high(&build_request("/", Some("low"))));
+        for s in ["", "max=0", "reserve=1", "max=10 size=1", "max"] {
+            assert!(s.parse::<ConfigAdmission>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn shed_by_in_flight() {
+        let config = "max=4 reserve=1 priority=X-Priority retry_after=5s"
+            .parse::<ConfigAdmission>()
+            .unwrap();
+        let admission = Arc::new(Admission::new(config));
+        let mut guards = vec![];
+        for _ in 0..3 {
+            guards.push(admission.admit(&build_request("/", None)).unwrap());
+        }
+        // 普通请求已用完名额, 新到达的被拒绝, 已处理中的不受影响
+        let res = admission.admit(&build_request("/", None)).unwrap_err();
+        assert_eq!(res.status().as_u16(), 503);
+        assert_eq!(res.headers().get_str_value(&"Retry-After"), Some("5".to_string()));
+        assert!(admission.is_shedding());
+        assert!(Admission::shed_total() >= 1);
+        assert_eq!(admission.in_flight(), 3);
+
+        // 高优先级使用保留的名额, 达到max后同样拒绝
+        let high = admission.admit(&build_request("/", Some("high"))).unwrap();
+        assert!(admission.admit(&build_request("/", Some("high"))).is_err());
+
+        drop(high);
+        guards.pop();
+        assert!(admission.admit(&build_request("/", None)).is_ok());
+        assert!(!admission.is_shedding());
+    }
+
+    #[test]
+    fn shed_by_latency() {
+        let config = "latency=10ms high=/pay".parse::<ConfigAdmission>().unwrap();
+        let admission = Arc::new(Admission::new(config));
+        admission.record(Duration::from_millis(100));
+        let first = admission.admit(&build_request("/", None)).unwrap();
+        // 响应时间超过目标值后, 有请求处理中时只接受高优先级的请求
+        assert!(admission.admit(&build_request("/", None)).is_err());
+        assert!(admission.is_shedding());
+        let _pay = admission.admit(&build_request("/pay", None)).unwrap();
+        drop(first);
+        for _ in 0..30 {
+            admission.record(Duration::from_millis(1));
+        }
+        assert!(admission.admit(&build_request("/", None)).is_ok());
+        assert!(!admission.is_shedding());
+    }
+}
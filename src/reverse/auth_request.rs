@@ -0,0 +1,489 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/06 15:12:40
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use webparse::{HeaderName, Method, Request, Response, Url};
+use wenmeng::{Body, Client, ProtError, ProtResult, TimeoutLayer};
+
+use crate::{ConfigBodyBuffer, ConfigDuration, HealthCheck, Helper};
+
+use super::{BodyBuffer, BufferResult};
+
+/// 发往鉴权服务时不复制的请求头
+const SKIP_HEADERS: [&str; 6] = [
+    "Host",
+    "Content-Length",
+    "Transfer-Encoding",
+    "Expect",
+    "Connection",
+    "Upgrade",
+];
+
+/// 鉴权服务的结果
+#[derive(Debug, Clone)]
+struct AuthDecision {
+    status: u16,
+    /// 需复制的响应头
+    headers: Vec<(String, String)>,
+}
+
+/// 外部鉴权的子请求, 类似nginx的`auth_request`
+///
+/// 在转发给上游前先将请求发往鉴权服务, 返回2xx则继续处理, 并可将鉴权服务返回的头复制到发往上游的请求中,
+/// 返回401或403则直接返回给客户端, 其它的状态码返回500
+///
+/// 配置格式为`url=http://127.0.0.1:8000/auth method=GET body=off copy=X-User,X-Role cache=30s key={client_ip}`,
+/// method默认与原请求相同, body为on时需配置body_buffer, cache为鉴权结果的缓存时间,
+/// key为缓存的键, 默认为客户端ip、请求方法、原始uri及`Authorization`和`Cookie`头,
+/// copy中的头会先从客户端的请求中移除, 仅使用鉴权服务返回的值, 防止客户端伪造
+#[derive(Debug, Clone)]
+pub struct ConfigAuthRequest {
+    /// 鉴权服务的地址
+    pub url: Url,
+    /// 子请求的方法, 为空则与原请求一致
+    pub method: Option<Method>,
+    /// 是否将原请求的body发往鉴权服务
+    pub body: bool,
+    /// 鉴权通过后复制到上游请求的头
+    pub copy: Vec<String>,
+    /// 鉴权结果的缓存时间
+    pub cache: Option<Duration>,
+    /// 缓存的键, 格式同log_format
+    pub key: Option<String>,
+    /// 缓存的鉴权结果, 克隆后共享
+    caches: Arc<Mutex<HashMap<String, (Instant, AuthDecision)>>>,
+}
+
+impl ConfigAuthRequest {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            method: None,
+            body: false,
+            copy: vec![],
+            cache: None,
+            key: None,
+            caches: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn cache_key(&self, req: &Request<Body>) -> String {
+        if let Some(key) = &self.key {
+            return Helper::format_req(req, key);
+        }
+        let headers = req.headers();
+        format!(
+            "{}|{}|{}|{}|{}",
+            headers.system_get("{client_ip}").cloned().unwrap_or_default(),
+            req.method().as_str(),
+            Self::original_uri(req),
+            headers
+                .get_str_value(&HeaderName::AUTHORIZATION)
+                .unwrap_or_default(),
+            headers.get_str_value(&HeaderName::COOKIE).unwrap_or_default()
+        )
+    }
+
+    /// 原请求的路径及参数, 即`X-Original-URI`
+    fn original_uri(req: &Request<Body>) -> String {
+        let url = req.url();
+        match &url.query {
+            Some(query) => format!("{}?{}", url.path, query),
+            None => url.path.clone(),
+        }
+    }
+
+    fn get_cache(&self, key: &str) -> Option<AuthDecision> {
+        let mut caches = self.caches.lock().ok()?;
+        match caches.get(key) {
+            Some((expire, decision)) if &Instant::now() < expire => Some(decision.clone()),
+            Some(_) => {
+                caches.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn set_cache(&self, key: String, decision: &AuthDecision) {
+        let ttl = match self.cache {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        if let Ok(mut caches) = self.caches.lock() {
+            let now = Instant::now();
+            // 清理过期的数据, 防止无限增长
+            if caches.len() > 10240 {
+                caches.retain(|_, v| v.0 > now);
+            }
+            caches.insert(key, (now + ttl, decision.clone()));
+        }
+    }
+
+    /// 向鉴权服务校验请求, 通过时返回None, 否则返回需直接返回客户端的响应
+    pub async fn check(
+        &self,
+        req: &mut Request<Body>,
+        body_buffer: &Option<ConfigBodyBuffer>,
+        proxy_timeout: Option<TimeoutLayer>,
+    ) -> ProtResult<Option<Response<Body>>> {
+        // 需复制的头只能来自鉴权服务, 客户端携带的一律移除
+        for name in &self.copy {
+            req.headers_mut().remove(name);
+        }
+        let key = self.cache_key(req);
+        let decision = match self.get_cache(&key) {
+            Some(decision) => decision,
+            None => {
+                let decision = self.send_auth(req, body_buffer, proxy_timeout).await?;
+                if matches!(decision.status, 200..=299 | 401 | 403) {
+                    self.set_cache(key, &decision);
+                }
+                decision
+            }
+        };
+        match decision.status {
+            200..=299 => {
+                for (name, value) in decision.headers {
+                    req.headers_mut().insert(name, value);
+                }
+                Ok(None)
+            }
+            401 | 403 => {
+                let mut builder = Response::text().status(decision.status);
+                // 401时保留鉴权服务返回的认证方式
+                for (name, value) in decision.headers {
+                    if name.eq_ignore_ascii_case("WWW-Authenticate") {
+                        builder = builder.header(name, value);
+                    }
+                }
+                let body = if decision.status == 401 {
+                    "Unauthorized"
+                } else {
+                    "Forbidden"
+                };
+                Ok(Some(builder.body(body).unwrap().into_type()))
+            }
+            413 => Ok(Some(
+                Response::text()
+                    .status(413)
+                    .body("Payload Too Large")
+                    .unwrap()
+                    .into_type(),
+            )),
+            status => {
+                log::warn!("鉴权服务返回了未知的状态码:{}", status);
+                Ok(Some(
+                    Response::status500()
+                        .body("auth request error")
+                        .unwrap()
+                        .into_type(),
+                ))
+            }
+        }
+    }
+
+    async fn send_auth(
+        &self,
+        req: &mut Request<Body>,
+        body_buffer: &Option<ConfigBodyBuffer>,
+        proxy_timeout: Option<TimeoutLayer>,
+    ) -> ProtResult<AuthDecision> {
+        let method = self.method.clone().unwrap_or(req.method().clone());
+        let mut builder = Request::builder()
+            .method(method)
+            .url(self.url.clone());
+        for (name, value) in req.headers().iter() {
+            if SKIP_HEADERS
+                .iter()
+                .any(|h| h.as_bytes().eq_ignore_ascii_case(name.as_bytes()))
+            {
+                continue;
+            }
+            builder = builder.header(name.clone(), value.clone());
+        }
+        builder = builder
+            .header("X-Original-URI", Self::original_uri(req))
+            .header("X-Original-Method", req.method().as_str().to_string());
+
+        let mut body = Body::empty();
+        if self.body && BodyBuffer::is_need_buffer(req) {
+            // 带body的子请求需缓存body, 之后转发给上游时复用缓存
+            if let Some(config) = body_buffer {
+                match BodyBuffer::read_request(req, config).await? {
+                    BufferResult::Buffered(buffer) => {
+                        builder =
+                            builder.header(HeaderName::CONTENT_LENGTH, buffer.len().to_string());
+                        body = buffer.to_body().await?;
+                        req.extensions_mut().insert(buffer);
+                    }
+                    BufferResult::Reject => {
+                        return Ok(AuthDecision {
+                            status: 413,
+                            headers: vec![],
+                        })
+                    }
+                    BufferResult::Pass => {}
+                }
+            }
+        }
+        let mut auth = builder
+            .body(body)
+            .map_err(|_| ProtError::Extension("build auth request error"))?;
+
+        let mut connect_url = self.url.clone();
+        if connect_url.port.is_none() {
+            connect_url.port = Some(if connect_url.scheme.is_https() { 443 } else { 80 });
+        }
+        let connect = connect_url
+            .get_connect_url()
+            .ok_or(ProtError::Extension("get url error"))?;
+        auth.headers_mut().insert(HeaderName::HOST, connect.clone());
+        let connect_timeout = proxy_timeout.as_ref().and_then(|t| t.connect_timeout);
+        let stream = HealthCheck::connect_timeout_bind(&connect, connect_timeout, None).await?;
+        let client = if connect_url.scheme.is_https() {
+            Client::builder()
+                .timeout_layer(proxy_timeout)
+                .url(connect_url)?
+                .connect_tls_by_stream(stream)
+                .await?
+        } else {
+            Client::builder()
+                .timeout_layer(proxy_timeout)
+                .connect_by_stream(stream)
+                .await?
+        };
+        let mut res = client.send_now(auth).await?;
+        res.body_mut().wait_all().await;
+        let status = res.status().as_u16();
+        let mut headers = vec![];
+        for name in self.copy.iter().map(|s| s.as_str()).chain(["WWW-Authenticate"]) {
+            if let Some(value) = res.headers().get_str_value(&name) {
+                headers.push((name.to_string(), value));
+            }
+        }
+        Ok(AuthDecision { status, headers })
+    }
+}
+
+impl PartialEq for ConfigAuthRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+impl FromStr for ConfigAuthRequest {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut url = None;
+        let mut method = None;
+        let mut body = false;
+        let mut copy = vec![];
+        let mut cache = None;
+        let mut key = None;
+        for v in s.split_whitespace() {
+            let (k, value) = match v.split_once('=') {
+                Some(kv) => kv,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("未知的auth_request配置:{}", v),
+                    ))
+                }
+            };
+            let err = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("未知的auth_request配置:{}", v),
+                )
+            };
+            match k {
+                "url" => url = Some(Url::try_from(value).map_err(|_| err())?),
+                "method" => method = Some(value.parse::<Method>().map_err(|_| err())?),
+                "body" => {
+                    body = match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(err()),
+                    }
+                }
+                "copy" => {
+                    copy = value
+                        .split(',')
+                        .filter(|h| !h.is_empty())
+                        .map(|h| h.to_string())
+                        .collect()
+                }
+                "cache" => cache = Some(ConfigDuration::from_str(value)?.0),
+                "key" => key = Some(value.to_string()),
+                _ => return Err(err()),
+            }
+        }
+        let url = url.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "auth_request需配置鉴权服务的url",
+        ))?;
+        let mut auth = Self::new(url);
+        auth.method = method;
+        auth.body = body;
+        auth.copy = copy;
+        auth.cache = cache;
+        auth.key = key;
+        Ok(auth)
+    }
+}
+
+impl Display for ConfigAuthRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("url={}", self.url))?;
+        if let Some(method) = &self.method {
+            f.write_fmt(format_args!(" method={}", method))?;
+        }
+        f.write_fmt(format_args!(" body={}", if self.body { "on" } else { "off" }))?;
+        if !self.copy.is_empty() {
+            f.write_fmt(format_args!(" copy={}", self.copy.join(",")))?;
+        }
+        if let Some(cache) = &self.cache {
+            f.write_fmt(format_args!(" cache={}", ConfigDuration::new(*cache)))?;
+        }
+        if let Some(key) = &self.key {
+            f.write_fmt(format_args!(" key={}", key))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use tokio::io::AsyncWriteExt;
+    use webparse::Request;
+    use wenmeng::Body;
+
+    use super::ConfigAuthRequest;
+    use crate::test_util::run_upstream;
+
+    /// 模拟鉴权服务, 带`Authorization: good`的请求通过, 其它返回401
+    async fn run_auth(hits: Arc<AtomicUsize>) -> SocketAddr {
+        run_upstream(move |mut stream, head| {
+            hits.fetch_add(1, Ordering::Relaxed);
+            async move {
+                let head = head.to_lowercase();
+                let res = if head.contains("x-user:") {
+                    // 客户端伪造的头不应发往鉴权服务
+                    "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n"
+                } else if head.contains("authorization: good") && head.contains("x-original-uri: /api?a=1") {
+                    "HTTP/1.1 200 OK\r\nX-User: tom\r\nX-Other: 1\r\nContent-Length: 0\r\n\r\n"
+                } else if head.contains("authorization: anon") {
+                    "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+                } else {
+                    "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic\r\nContent-Length: 0\r\n\r\n"
+                };
+                let _ = stream.write_all(res.as_bytes()).await;
+            }
+        })
+        .await
+    }
+
+    fn build_request(token: &str) -> Request<Body> {
+        build_request_url(token, "/api?a=1")
+    }
+
+    fn build_request_url(token: &str, url: &str) -> Request<Body> {
+        Request::builder()
+            .url(url)
+            .header("Authorization", token.to_string())
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn allow_deny_copy() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = run_auth(hits.clone()).await;
+        let auth = format!("url=http://{}/auth copy=X-User cache=60s", addr)
+            .parse::<ConfigAuthRequest>()
+            .unwrap();
+        assert_eq!(
+            format!("{}", auth),
+            format!("url=http://{}/auth body=off copy=X-User cache=1min", addr)
+        );
+        assert!("method=GET".parse::<ConfigAuthRequest>().is_err());
+
+        // 通过后复制配置的头, 未配置的头不复制
+        let mut req = build_request("good");
+        assert!(auth.check(&mut req, &None, None).await.unwrap().is_none());
+        assert_eq!(req.headers().get_str_value(&"X-User"), Some("tom".to_string()));
+        assert!(req.headers().get_str_value(&"X-Other").is_none());
+
+        let mut req = build_request("bad");
+        let res = auth.check(&mut req, &None, None).await.unwrap().unwrap();
+        assert_eq!(res.status().as_u16(), 401);
+        assert_eq!(
+            res.headers().get_str_value(&"WWW-Authenticate"),
+            Some("Basic".to_string())
+        );
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+
+        // 相同的键命中缓存, 不再请求鉴权服务
+        let mut req = build_request("good");
+        assert!(auth.check(&mut req, &None, None).await.unwrap().is_none());
+        assert_eq!(req.headers().get_str_value(&"X-User"), Some("tom".to_string()));
+        let mut req = build_request("bad");
+        assert!(auth.check(&mut req, &None, None).await.unwrap().is_some());
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+
+        // 不同的uri不共用缓存
+        let mut req = build_request_url("good", "/admin");
+        let res = auth.check(&mut req, &None, None).await.unwrap().unwrap();
+        assert_eq!(res.status().as_u16(), 401);
+        assert_eq!(hits.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn forged_copy_header_removed() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = run_auth(hits.clone()).await;
+        let auth = format!("url=http://{}/auth copy=X-User", addr)
+            .parse::<ConfigAuthRequest>()
+            .unwrap();
+
+        // 鉴权服务未返回X-User时, 客户端伪造的X-User不能传给上游
+        let mut req = build_request("anon");
+        req.headers_mut().insert("x-user", "admin");
+        assert!(auth.check(&mut req, &None, None).await.unwrap().is_none());
+        assert!(req.headers().get_str_value(&"X-User").is_none());
+
+        // 鉴权服务返回时使用鉴权服务的值
+        let mut req = build_request("good");
+        req.headers_mut().insert("X-User", "admin");
+        assert!(auth.check(&mut req, &None, None).await.unwrap().is_none());
+        assert_eq!(req.headers().get_str_value(&"X-User"), Some("tom".to_string()));
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/20 10:12:41
+
+use std::{fmt::Display, io, net::SocketAddr, str::FromStr};
+
+use webparse::Request;
+
+/// 上游的负载均衡策略
+///
+/// * `random` 按权重随机选择, 默认值
+/// * `uri_hash` 按请求的路径做一致性哈希, 相同的路径选择同一个上游, 适用于缓存类的上游
+/// * `uri_hash query` 同上, 路径之外还包含查询参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConfigBalance {
+    #[default]
+    Random,
+    UriHash { query: bool },
+}
+
+impl ConfigBalance {
+    /// 用于一致性哈希的键, 随机选择时为空
+    pub fn hash_key<T: webparse::Serialize>(&self, req: &Request<T>) -> Option<String> {
+        match self {
+            ConfigBalance::Random => None,
+            ConfigBalance::UriHash { query } => {
+                let url = req.url();
+                match &url.query {
+                    Some(q) if *query => Some(format!("{}?{}", url.path, q)),
+                    _ => Some(url.path.clone()),
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for ConfigBalance {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = s.split_whitespace().collect::<Vec<_>>();
+        match &vals[..] {
+            ["random"] => Ok(ConfigBalance::Random),
+            ["uri_hash"] => Ok(ConfigBalance::UriHash { query: false }),
+            ["uri_hash", "query"] => Ok(ConfigBalance::UriHash { query: true }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("balance的值无效:{}", s),
+            )),
+        }
+    }
+}
+
+impl Display for ConfigBalance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigBalance::Random => f.write_str("random"),
+            ConfigBalance::UriHash { query: false } => f.write_str("uri_hash"),
+            ConfigBalance::UriHash { query: true } => f.write_str("uri_hash query"),
+        }
+    }
+}
+
+/// 一致性哈希环, 每个上游按权重放置虚拟节点, 增减上游时仅影响相邻部分的键
+#[derive(Debug, Default)]
+pub struct HashRing {
+    /// 虚拟节点的哈希值及对应上游的下标, 按哈希值排序
+    nodes: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    /// 按地址计算节点位置, 配置顺序变化不影响映射
+    pub fn new(servers: &[(SocketAddr, u16)]) -> Self {
+        let mut nodes = vec![];
+        for (index, (addr, weight)) in servers.iter().enumerate() {
+            for i in 0..(*weight).max(1) {
+                nodes.push((Self::hash(format!("{}#{}", addr, i).as_bytes()), index));
+            }
+        }
+        nodes.sort_unstable();
+        Self { nodes }
+    }
+
+    /// 从键的位置顺时针查找第一个可用的上游
+    pub fn get<F: Fn(usize) -> bool>(&self, key: &str, available: F) -> Option<usize> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let hash = Self::hash(key.as_bytes());
+        let start = self.nodes.partition_point(|n| n.0 < hash);
+        (0..self.nodes.len())
+            .map(|i| self.nodes[(start + i) % self.nodes.len()].1)
+            .find(|index| available(*index))
+    }
+
+    /// FNV-1a, 再混合高低位使分布更均匀
+    fn hash(data: &[u8]) -> u64 {
+        let mut h = 0xcbf29ce484222325u64;
+        for b in data {
+            h ^= *b as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51afd7ed558ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+        h ^ (h >> 33)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use webparse::Request;
+
+    use super::{ConfigBalance, HashRing};
+
+    fn servers(n: u16) -> Vec<(SocketAddr, u16)> {
+        (0..n)
+            .map(|i| (format!("10.0.0.{}:80", i + 1).parse().unwrap(), 100))
+            .collect()
+    }
+
+    #[test]
+    fn parse_balance() {
+        for s in ["random", "uri_hash", "uri_hash query"] {
+            assert_eq!(s.parse::<ConfigBalance>().unwrap().to_string(), s);
+        }
+        assert!("uri_hash path".parse::<ConfigBalance>().is_err());
+
+        let req = Request::builder().url("/img/a.png?v=1").body(()).unwrap();
+        let key = ConfigBalance::UriHash { query: false }.hash_key(&req);
+        assert_eq!(key.as_deref(), Some("/img/a.png"));
+        let key = ConfigBalance::UriHash { query: true }.hash_key(&req);
+        assert_eq!(key.as_deref(), Some("/img/a.png?v=1"));
+        assert_eq!(ConfigBalance::Random.hash_key(&req), None);
+    }
+
+    #[test]
+    fn consistent_remap() {
+        let all = servers(4);
+        let ring = HashRing::new(&all);
+        let keys = (0..2000).map(|i| format!("/static/{}.js", i)).collect::<Vec<_>>();
+        let before = keys
+            .iter()
+            .map(|k| all[ring.get(k, |_| true).unwrap()].0)
+            .collect::<Vec<_>>();
+        // 相同的路径始终选择同一个上游, 且各上游均有分配
+        for (k, addr) in keys.iter().zip(&before) {
+            assert_eq!(all[ring.get(k, |_| true).unwrap()].0, *addr);
+        }
+        for (addr, _) in &all {
+            assert!(before.iter().filter(|a| *a == addr).count() > 2000 / 4 / 2);
+        }
+
+        // 移除一个上游, 仅原本属于该上游的路径被重新分配
+        let removed = all[1].0;
+        let rest = all.iter().filter(|s| s.0 != removed).cloned().collect::<Vec<_>>();
+        let ring = HashRing::new(&rest);
+        let mut moved = 0;
+        for (k, addr) in keys.iter().zip(&before) {
+            let now = rest[ring.get(k, |_| true).unwrap()].0;
+            if *addr == removed {
+                moved += 1;
+            } else {
+                assert_eq!(now, *addr, "{}", k);
+            }
+        }
+        assert!(moved < 2000 / 4 * 2, "{}", moved);
+
+        // 不可用的上游跳过, 结果与移除该上游一致
+        let full = HashRing::new(&all);
+        for k in &keys {
+            let skip = all[full.get(k, |i| all[i].0 != removed).unwrap()].0;
+            assert_eq!(skip, rest[ring.get(k, |_| true).unwrap()].0);
+        }
+    }
+}
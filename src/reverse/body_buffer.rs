@@ -0,0 +1,413 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/06 10:05:48
+
+use std::{
+    io,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::Poll,
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncWriteExt, ReadBuf},
+    sync::mpsc::{channel, Sender},
+};
+use webparse::{Binary, BinaryMut, HeaderName, Request};
+use wenmeng::Body;
+
+use crate::{ConfigBodyBuffer, ConfigBodyPeek};
+
+/// 每次读取body的大小
+pub(crate) const READ_BUFFER: usize = 16 * 1024;
+
+/// 临时文件的序号, 防止同一进程内的文件名冲突
+static SPILL_INDEX: AtomicU64 = AtomicU64::new(0);
+
+/// 写入磁盘的临时文件, 不再使用时删除
+struct SpillFile {
+    path: PathBuf,
+    len: u64,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// 预读缓存的请求body, 可重复生成新的body用于重试或者流量复制
+#[derive(Clone)]
+pub struct BodyBuffer {
+    memory: Binary,
+    file: Option<Arc<SpillFile>>,
+}
+
+/// 预读的结果
+pub enum BufferResult {
+    /// 已全部缓存
+    Buffered(BodyBuffer),
+    /// 超出缓存的大小, 请求的body已还原, 不可重试
+    Pass,
+    /// 超出缓存大小且配置为拒绝, 需返回`413`
+    Reject,
+}
+
+/// 按body匹配location时预读的请求body开头, 保存在请求的extensions中
+#[derive(Clone)]
+pub struct BodyPeek(pub Binary);
+
+/// 读取body的数据, 返回0表示已结束
+pub(crate) async fn read_body_data(body: &mut Body, data: &mut [u8]) -> io::Result<usize> {
+    std::future::poll_fn(|cx| {
+        let mut buf = ReadBuf::new(data);
+        match Pin::new(&mut *body).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) if !buf.filled().is_empty() => Poll::Ready(Ok(buf.filled().len())),
+            // 无数据时Body同样返回Ready, 未结束时已注册唤醒
+            Poll::Ready(Ok(())) if !body.is_end() => Poll::Pending,
+            Poll::Ready(ret) => Poll::Ready(ret.map(|_| 0)),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// 将body的数据转发到sender中, is_end表示是否为最后的数据
+pub(crate) async fn forward_body(body: &mut Body, sender: &Sender<(bool, Binary)>, is_end: bool) -> io::Result<()> {
+    let mut data = vec![0u8; READ_BUFFER];
+    loop {
+        let n = read_body_data(body, &mut data).await?;
+        if n == 0 {
+            break;
+        }
+        if sender.send((false, Binary::from(data[..n].to_vec()))).await.is_err() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+    }
+    if is_end {
+        let _ = sender.send((true, Binary::new())).await;
+    }
+    Ok(())
+}
+
+impl BodyPeek {
+    /// 预读请求body的开头, 预读的数据与剩余的数据还原成body, 已预读过时不再处理
+    /// 无body或者带压缩的body不做预读, 此时以空数据进行匹配
+    pub async fn peek_request(req: &mut Request<Body>, config: &ConfigBodyPeek) -> io::Result<()> {
+        if req.extensions().get::<BodyPeek>().is_some() {
+            return Ok(());
+        }
+        let mut peek = BinaryMut::new();
+        if BodyBuffer::is_need_buffer(req) {
+            let size = config.size as usize;
+            let mut data = vec![0u8; size];
+            let mut is_end = false;
+            let read = async {
+                while peek.len() < size {
+                    let n = read_body_data(req.body_mut(), &mut data[..size - peek.len()]).await?;
+                    if n == 0 {
+                        is_end = true;
+                        break;
+                    }
+                    peek.put_slice(&data[..n]);
+                }
+                io::Result::Ok(())
+            };
+            // 超时后以已读取的部分进行匹配
+            if let Ok(ret) = tokio::time::timeout(config.timeout, read).await {
+                ret?;
+            }
+            if is_end {
+                req.headers_mut().remove(&HeaderName::TRANSFER_ENCODING);
+                req.headers_mut()
+                    .insert(HeaderName::CONTENT_LENGTH, peek.len().to_string());
+                *req.body_mut() = Body::new_binary(peek.clone());
+            } else {
+                let (sender, receiver) = channel(10);
+                let mut rest = std::mem::replace(
+                    req.body_mut(),
+                    Body::new(receiver, peek.clone(), false),
+                );
+                tokio::spawn(async move { forward_body(&mut rest, &sender, true).await });
+            }
+        }
+        log::trace!("预读请求body的{}字节用于匹配location", peek.len());
+        req.extensions_mut().insert(BodyPeek(peek.freeze()));
+        Ok(())
+    }
+}
+
+impl BodyBuffer {
+    /// 是否需要预读, 无body或者带压缩的body不做处理
+    pub fn is_need_buffer<T: webparse::Serialize>(req: &Request<T>) -> bool {
+        (req.get_body_len() > 0 || req.headers().is_chunked())
+            && !req.headers().contains(&HeaderName::CONTENT_ENCODING)
+    }
+
+    /// 预读请求的body, 超出内存大小的部分写入临时文件
+    pub async fn read_request(
+        req: &mut Request<Body>,
+        config: &ConfigBodyBuffer,
+    ) -> io::Result<BufferResult> {
+        let len = req.get_body_len();
+        // 已知长度超出限制时不做读取
+        if len > 0 && len as u64 > config.size {
+            return Ok(Self::over_limit(config));
+        }
+        let mut memory = BinaryMut::new();
+        let mut file: Option<(File, SpillFile)> = None;
+        let mut total = 0u64;
+        let mut data = vec![0u8; READ_BUFFER];
+        loop {
+            let n = read_body_data(req.body_mut(), &mut data).await?;
+            if n == 0 {
+                break;
+            }
+            total += n as u64;
+            if total > config.size {
+                let buffer = Self::finish(memory, file).await?;
+                if config.reject {
+                    return Ok(BufferResult::Reject);
+                }
+                // 将已读取的数据及剩余的数据还原成body
+                let (sender, receiver) = channel(10);
+                let mut rest = std::mem::replace(
+                    req.body_mut(),
+                    Body::new(receiver, BinaryMut::new(), false),
+                );
+                let now = data[..n].to_vec();
+                tokio::spawn(async move {
+                    let mut body = buffer.to_body().await?;
+                    forward_body(&mut body, &sender, false).await?;
+                    let _ = sender.send((false, Binary::from(now))).await;
+                    forward_body(&mut rest, &sender, true).await
+                });
+                return Ok(BufferResult::Pass);
+            }
+            match &mut file {
+                Some((f, spill)) => {
+                    f.write_all(&data[..n]).await?;
+                    spill.len += n as u64;
+                }
+                None if total > config.memory => {
+                    let spill = SpillFile {
+                        path: std::env::temp_dir().join(format!(
+                            "wmproxy_body_{}_{}.tmp",
+                            std::process::id(),
+                            SPILL_INDEX.fetch_add(1, Ordering::Relaxed)
+                        )),
+                        len: n as u64,
+                    };
+                    let mut f = File::create(&spill.path).await?;
+                    f.write_all(&data[..n]).await?;
+                    file = Some((f, spill));
+                }
+                None => {
+                    memory.put_slice(&data[..n]);
+                }
+            }
+        }
+        let buffer = Self::finish(memory, file).await?;
+        // 缓存后的body长度已知, 统一以Content-Length发送
+        req.headers_mut().remove(&HeaderName::TRANSFER_ENCODING);
+        req.headers_mut()
+            .insert(HeaderName::CONTENT_LENGTH, buffer.len().to_string());
+        *req.body_mut() = buffer.to_body().await?;
+        Ok(BufferResult::Buffered(buffer))
+    }
+
+    fn over_limit(config: &ConfigBodyBuffer) -> BufferResult {
+        if config.reject {
+            BufferResult::Reject
+        } else {
+            BufferResult::Pass
+        }
+    }
+
+    async fn finish(memory: BinaryMut, file: Option<(File, SpillFile)>) -> io::Result<Self> {
+        let file = match file {
+            Some((mut f, spill)) => {
+                f.flush().await?;
+                Some(Arc::new(spill))
+            }
+            None => None,
+        };
+        Ok(Self {
+            memory: memory.freeze(),
+            file,
+        })
+    }
+
+    /// 缓存的总长度
+    pub fn len(&self) -> u64 {
+        self.memory.len() as u64 + self.file.as_ref().map(|f| f.len).unwrap_or(0)
+    }
+
+    /// 是否有部分数据写入临时文件
+    pub fn is_spill(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// 生成新的body, 每次调用均从头读取
+    pub async fn to_body(&self) -> io::Result<Body> {
+        let spill = match &self.file {
+            Some(spill) => spill.clone(),
+            None => return Ok(Body::new_binary(BinaryMut::from(self.memory.to_vec()))),
+        };
+        let mut file = File::open(&spill.path).await?;
+        let (sender, receiver) = channel(10);
+        tokio::spawn(async move {
+            // 持有临时文件, 防止读取完毕前被删除
+            let spill = spill;
+            let mut left = spill.len;
+            let mut data = vec![0u8; READ_BUFFER];
+            while left > 0 {
+                let n = tokio::io::AsyncReadExt::read(&mut file, &mut data).await?;
+                if n == 0 {
+                    break;
+                }
+                left = left.saturating_sub(n as u64);
+                let _ = sender.send((left == 0, Binary::from(data[..n].to_vec()))).await;
+            }
+            io::Result::Ok(())
+        });
+        Ok(Body::new(
+            receiver,
+            BinaryMut::from(self.memory.to_vec()),
+            false,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::{BinaryMut, Request};
+    use wenmeng::Body;
+
+    use super::{read_body_data, BodyBuffer, BodyPeek, BufferResult};
+    use crate::{ConfigBodyBuffer, ConfigBodyPeek};
+
+    fn build_request(len: usize) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .url("/upload")
+            .header("Content-Length", len.to_string())
+            .body(Body::new_binary(BinaryMut::from(vec![b'a'; len])))
+            .unwrap()
+    }
+
+    async fn read_all(mut body: Body) -> Vec<u8> {
+        let mut all = vec![];
+        let mut data = vec![0u8; 1024];
+        loop {
+            let n = read_body_data(&mut body, &mut data).await.unwrap();
+            if n == 0 {
+                return all;
+            }
+            all.extend_from_slice(&data[..n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn spill_threshold() {
+        let config = "size=64k memory=4k over=reject"
+            .parse::<ConfigBodyBuffer>()
+            .unwrap();
+        assert_eq!(format!("{}", config), "size=64k memory=4k over=reject");
+
+        // 刚好达到内存大小时不写入文件
+        let mut req = build_request(4096);
+        let buffer = match BodyBuffer::read_request(&mut req, &config).await.unwrap() {
+            BufferResult::Buffered(buffer) => buffer,
+            _ => unreachable!(),
+        };
+        assert!(!buffer.is_spill());
+        assert_eq!(buffer.len(), 4096);
+
+        // 超出内存大小的部分写入临时文件, 可多次读取
+        let mut req = build_request(40000);
+        let buffer = match BodyBuffer::read_request(&mut req, &config).await.unwrap() {
+            BufferResult::Buffered(buffer) => buffer,
+            _ => unreachable!(),
+        };
+        assert!(buffer.is_spill());
+        assert_eq!(buffer.len(), 40000);
+        let path = buffer.file.as_ref().unwrap().path.clone();
+        for _ in 0..2 {
+            assert_eq!(read_all(buffer.to_body().await.unwrap()).await, vec![b'a'; 40000]);
+        }
+        assert_eq!(
+            read_all(std::mem::replace(req.body_mut(), Body::empty())).await.len(),
+            40000
+        );
+        drop(buffer);
+        assert!(!path.exists());
+
+        let mut req = build_request(64 * 1024 + 1);
+        assert!(matches!(
+            BodyBuffer::read_request(&mut req, &config).await.unwrap(),
+            BufferResult::Reject
+        ));
+    }
+
+    #[tokio::test]
+    async fn over_limit_pass() {
+        let config = "size=8k memory=1k".parse::<ConfigBodyBuffer>().unwrap();
+        // 未知长度的body在读取中超出限制, 已读取的数据需还原
+        let mut req = Request::builder()
+            .method("POST")
+            .url("/upload")
+            .header("Transfer-Encoding", "chunked")
+            .body(Body::new_binary(BinaryMut::from(vec![b'b'; 20000])))
+            .unwrap();
+        assert!(matches!(
+            BodyBuffer::read_request(&mut req, &config).await.unwrap(),
+            BufferResult::Pass
+        ));
+        let body = std::mem::replace(req.body_mut(), Body::empty());
+        assert_eq!(read_all(body).await, vec![b'b'; 20000]);
+    }
+
+    #[tokio::test]
+    async fn peek_keep_body() {
+        let config = "size=1k".parse::<ConfigBodyPeek>().unwrap();
+        assert_eq!(format!("{}", config), "size=1024 timeout=1s");
+        assert!("size=128k".parse::<ConfigBodyPeek>().is_err());
+        assert!("len=1k".parse::<ConfigBodyPeek>().is_err());
+
+        // 超出预读大小时, 预读的部分与剩余的部分需完整转发
+        let data = (0..3000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let mut req = Request::builder()
+            .method("POST")
+            .url("/api")
+            .header("Content-Length", data.len().to_string())
+            .body(Body::new_binary(BinaryMut::from(data.clone())))
+            .unwrap();
+        BodyPeek::peek_request(&mut req, &config).await.unwrap();
+        let peek = req.extensions().get::<BodyPeek>().unwrap().0.clone();
+        assert_eq!(&peek[..], &data[..1024]);
+        // 已预读过的不再重复读取
+        BodyPeek::peek_request(&mut req, &config).await.unwrap();
+        let body = std::mem::replace(req.body_mut(), Body::empty());
+        assert_eq!(read_all(body).await, data);
+
+        let mut req = build_request(10);
+        BodyPeek::peek_request(&mut req, &config).await.unwrap();
+        assert_eq!(req.extensions().get::<BodyPeek>().unwrap().0.len(), 10);
+        let body = std::mem::replace(req.body_mut(), Body::empty());
+        assert_eq!(read_all(body).await, vec![b'a'; 10]);
+    }
+}
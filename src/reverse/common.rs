@@ -11,16 +11,19 @@
 // Created Date: 2023/11/03 05:01:37
 
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
 
-use crate::{ConfigDuration, ConfigLog, ConfigRate, IpSets};
+use crate::{ConfigBodyBuffer, ConfigDuration, ConfigForwarded, ConfigHeaderLimit, ConfigLog, ConfigRate, IpSets};
 use crate::{DisplayFromStrOrNumber};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
-use webparse::Url;
-use wenmeng::RateLimitLayer;
+use webparse::{HeaderName, Request, Response, Url, Version};
+use wenmeng::{Body, RateLimitLayer};
 use wenmeng::TimeoutLayer;
 
-use super::{LimitReq, Matcher};
+use super::{ConfigCompress, ConfigMultipart, ConfigRejectPage, LimitReq, Matcher};
 
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -47,6 +50,12 @@ pub struct CommonConfig {
     #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
     pub proxy_timeout: Option<ConfigDuration>,
 
+    /// 复用的上游连接最多处理的请求数, 超过后将关闭该连接
+    pub keepalive_max_requests: Option<usize>,
+    /// 复用的上游连接最长存活时间, 超过后将关闭该连接
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    pub keepalive_max_lifetime: Option<ConfigDuration>,
+
     #[serde(default = "HashMap::new")]
     pub log_format: HashMap<String, String>,
     #[serde(default = "HashMap::new")]
@@ -69,8 +78,79 @@ pub struct CommonConfig {
     #[serde(default = "HashMap::new")]
     #[serde_as(as = "HashMap<_, DisplayFromStr>")]
     pub match_names: HashMap<String, Matcher>,
+
+    /// 返回的`Server`头, 默认为`wmproxy`
+    /// 配置为`off`则移除该头, 配置为`upstream`则保留上游返回的值
+    pub server_header: Option<String>,
+    /// 代理的名称, 配置后将在请求及返回中追加`Via: 1.1 {via}`
+    pub via: Option<String>,
+    /// 是否移除上游返回的`X-Powered-By`头
+    pub hide_powered_by: Option<bool>,
+    /// 请求头的个数及大小限制, 如`count=128 size=16k total=64k`, 超出时返回431
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub header_limit: Option<ConfigHeaderLimit>,
+    /// `Expect: 100-continue`的处理方式, 默认为`relay`转发上游返回的`100 Continue`,
+    /// 配置为`auto`则由代理直接返回`100 Continue`, 用于不支持该头的上游
+    pub expect_continue: Option<String>,
+    /// 请求body的预读缓存, 如`size=1m memory=64k over=pass`, 缓存后才可重试带body的请求
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub body_buffer: Option<ConfigBodyBuffer>,
+    /// `multipart/form-data`请求body的限制, 如`part=1m total=10m names=file types=image/*`,
+    /// 转发时按流检查, 超出限制时立即返回`413`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub multipart_limit: Option<ConfigMultipart>,
+    /// 连接或请求上游失败时, 最多再尝试其它上游的次数
+    pub proxy_next_upstream_tries: Option<usize>,
+    /// 是否允许重试POST等非幂等的请求, 带body时需配置body_buffer
+    pub retry_non_idempotent: Option<bool>,
+    /// 复用的上游连接已被上游关闭时, 重新建立连接并重发请求, 默认开启
+    /// 仅重发不带body的请求, 非幂等的请求需同时开启retry_non_idempotent, 关闭时返回503
+    pub proxy_reuse_retry: Option<bool>,
+    /// 上游返回中不转发给客户端的头, 如`["X-Internal-Trace"]`, 逐跳的头默认不转发
+    pub hide_headers: Option<Vec<String>>,
+    /// 允许转发给客户端的头, 可覆盖默认不转发的头
+    pub pass_headers: Option<Vec<String>>,
+    /// 信任的代理地址, 如`10.0.0.0/8 127.0.0.1`, 在http或server中配置, 仅信任来自这些地址的`X-Forwarded-For`及`Forwarded`,
+    /// 从右往左跳过信任的地址得到真实的客户端IP, 作为`{client_ip}`用于访问控制/限流/日志
+    /// 来自其它地址的`X-Forwarded-*`及`Forwarded`将被移除, 未配置时不做处理
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub trusted_proxies: Option<IpSets>,
+    /// 携带真实客户端IP的头, 如`CF-Connecting-IP`, 仅信任来自`trusted_proxies`的请求,
+    /// 值为合法的IP时作为`{client_ip}`, 优先于`X-Forwarded-For`及`Forwarded`
+    pub real_ip_header: Option<String>,
+    /// 转发给上游时添加的代理头, 可选`xff`, `forwarded`(RFC 7239), `both`, 如`both by=_edge`, 未配置时不添加
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub forwarded_headers: Option<ConfigForwarded>,
+    /// 返回的压缩配置, 如`gzip=6 br=5 prefer=br,gzip busy=64`, 配置为`off`则不压缩
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub compress: Option<ConfigCompress>,
+    /// 代理的websocket连接两个方向均无消息超过该时间时关闭
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub ws_idle_timeout: Option<ConfigDuration>,
+    /// 限流, 访问控制, websocket连接数及过载拒绝请求时的返回, 如`429=html/limit.html 403=html/deny.html retry_after=30s`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub reject_page: Option<ConfigRejectPage>,
 }
 
+/// 默认不转发给客户端的逐跳的头, Transfer-Encoding由返回的body长度决定, 不在此处处理
+const DEFAULT_HIDE_HEADERS: [&str; 7] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+
 impl CommonConfig {
     pub fn new() -> Self {
         Self {
@@ -87,6 +167,9 @@ impl CommonConfig {
             proxy_read_timeout: None,
             proxy_write_timeout: None,
 
+            keepalive_max_requests: None,
+            keepalive_max_lifetime: None,
+
             log_format: HashMap::new(),
             log_names: HashMap::new(),
 
@@ -101,6 +184,25 @@ impl CommonConfig {
             proxy_url: None,
             
             match_names: HashMap::new(),
+
+            server_header: None,
+            via: None,
+            hide_powered_by: None,
+            header_limit: None,
+            expect_continue: None,
+            body_buffer: None,
+            multipart_limit: None,
+            proxy_next_upstream_tries: None,
+            retry_non_idempotent: None,
+            proxy_reuse_retry: None,
+            hide_headers: None,
+            pass_headers: None,
+            trusted_proxies: None,
+            real_ip_header: None,
+            forwarded_headers: None,
+            compress: None,
+            ws_idle_timeout: None,
+            reject_page: None,
         }
     }
 
@@ -124,6 +226,12 @@ impl CommonConfig {
         if self.client_timeout.is_none() && parent.client_timeout.is_some() {
             self.client_timeout = parent.client_timeout.clone();
         }
+        if self.keepalive_max_requests.is_none() {
+            self.keepalive_max_requests = parent.keepalive_max_requests;
+        }
+        if self.keepalive_max_lifetime.is_none() {
+            self.keepalive_max_lifetime = parent.keepalive_max_lifetime.clone();
+        }
         for h in &parent.log_names {
             self.log_names.insert(h.0.clone(), h.1.clone());
         }
@@ -153,6 +261,61 @@ impl CommonConfig {
                 self.match_names.insert(p.0.clone(), p.1.clone());
             }
         }
+
+        if self.server_header.is_none() {
+            self.server_header = parent.server_header.clone();
+        }
+        if self.via.is_none() {
+            self.via = parent.via.clone();
+        }
+        if self.hide_powered_by.is_none() {
+            self.hide_powered_by = parent.hide_powered_by;
+        }
+        if self.header_limit.is_none() {
+            self.header_limit = parent.header_limit.clone();
+        }
+        if self.expect_continue.is_none() {
+            self.expect_continue = parent.expect_continue.clone();
+        }
+        if self.body_buffer.is_none() {
+            self.body_buffer = parent.body_buffer.clone();
+        }
+        if self.multipart_limit.is_none() {
+            self.multipart_limit = parent.multipart_limit.clone();
+        }
+        if self.proxy_next_upstream_tries.is_none() {
+            self.proxy_next_upstream_tries = parent.proxy_next_upstream_tries;
+        }
+        if self.retry_non_idempotent.is_none() {
+            self.retry_non_idempotent = parent.retry_non_idempotent;
+        }
+        if self.proxy_reuse_retry.is_none() {
+            self.proxy_reuse_retry = parent.proxy_reuse_retry;
+        }
+        if self.hide_headers.is_none() {
+            self.hide_headers = parent.hide_headers.clone();
+        }
+        if self.pass_headers.is_none() {
+            self.pass_headers = parent.pass_headers.clone();
+        }
+        if self.trusted_proxies.is_none() {
+            self.trusted_proxies = parent.trusted_proxies.clone();
+        }
+        if self.real_ip_header.is_none() {
+            self.real_ip_header = parent.real_ip_header.clone();
+        }
+        if self.forwarded_headers.is_none() {
+            self.forwarded_headers = parent.forwarded_headers.clone();
+        }
+        if self.compress.is_none() {
+            self.compress = parent.compress.clone();
+        }
+        if self.ws_idle_timeout.is_none() {
+            self.ws_idle_timeout = parent.ws_idle_timeout.clone();
+        }
+        if self.reject_page.is_none() {
+            self.reject_page = parent.reject_page.clone();
+        }
     }
 
     pub fn pre_deal(&mut self) {
@@ -162,6 +325,269 @@ impl CommonConfig {
         
     }
 
+    /// 计算复用连接的淘汰时间, 减去最多10%的随机抖动, 防止连接同时被淘汰
+    pub fn calc_keepalive_retire(&self) -> Option<Instant> {
+        let lifetime = self.keepalive_max_lifetime.as_ref()?.0;
+        let jitter = lifetime.mul_f64(rand::thread_rng().gen_range(0.0..0.1));
+        Some(Instant::now() + lifetime - jitter)
+    }
+
+    /// 校验请求头是否超出限制, 超出时返回431的响应, 未配置时使用默认的限制
+    pub fn check_header_limit<T: webparse::Serialize>(
+        &self,
+        req: &Request<T>,
+    ) -> Option<Response<Body>> {
+        let pass = match &self.header_limit {
+            Some(limit) => limit.check(req),
+            None => ConfigHeaderLimit::default().check(req),
+        };
+        if pass {
+            return None;
+        }
+        Some(
+            Response::text()
+                .status(431)
+                .body("Request Header Fields Too Large")
+                .unwrap()
+                .into_type(),
+        )
+    }
+
+    /// 从右往左跳过信任的代理, 第一个不被信任的地址即为客户端, 均被信任时取最左边的地址
+    /// 遇到无法解析的地址时停止, 取其右边的地址
+    fn walk_forwarded(trusted: &IpSets, peer: IpAddr, forwarded: &[IpAddr]) -> IpAddr {
+        let mut client = peer;
+        for ip in forwarded.iter().rev() {
+            if !trusted.contains(&client) {
+                break;
+            }
+            client = *ip;
+        }
+        client
+    }
+
+    fn parse_forwarded(value: &str) -> Vec<IpAddr> {
+        let mut ips = vec![];
+        // 多个同名的头合并时以`;`分隔
+        for v in value.split([',', ';']).map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            let ip = v
+                .parse::<IpAddr>()
+                .or_else(|_| v.parse::<SocketAddr>().map(|a| a.ip()));
+            match ip {
+                Ok(ip) => ips.push(ip),
+                // 无法解析的地址左边的内容均不可信
+                Err(_) => ips.clear(),
+            }
+        }
+        ips
+    }
+
+    /// 从配置的头中取真实的客户端IP, 值不是合法的IP时忽略
+    fn parse_real_ip(value: &str) -> Option<IpAddr> {
+        let value = value.trim();
+        value
+            .parse::<IpAddr>()
+            .or_else(|_| value.parse::<SocketAddr>().map(|a| a.ip()))
+            .ok()
+    }
+
+    /// 客户端请求的协议, 未知时为http
+    fn forwarded_proto<T: webparse::Serialize>(req: &Request<T>) -> String {
+        match req.scheme().as_str() {
+            "" => "http".to_string(),
+            proto => proto.to_string(),
+        }
+    }
+
+    /// 按信任的代理处理`X-Forwarded-For`及`Forwarded`, 重新计算`{client_ip}`, 两者同时存在时以`Forwarded`为准,
+    /// 并提供`{forwarded_for}`, `{forwarded}`为追加了当前代理的值, 用于转发给上游
+    ///
+    /// 配置了`real_ip_header`且请求来自信任的代理时, 以该头的值作为`{client_ip}`
+    ///
+    /// 未配置信任的代理时保留原有的头, 不重新计算`{client_ip}`
+    pub fn resolve_client_ip<T: webparse::Serialize>(&self, req: &mut Request<T>) {
+        let peer = match req
+            .headers()
+            .system_get("{client_ip}")
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+        {
+            Some(peer) => peer,
+            None => return,
+        };
+        let xff = HeaderName::from_static("X-Forwarded-For");
+        let fwd = HeaderName::from_static("Forwarded");
+        let is_trusted = match &self.trusted_proxies {
+            Some(trusted) => trusted.contains(&peer),
+            None => true,
+        };
+        if !is_trusted {
+            // 不被信任的地址发来的头不可信, 直接移除
+            for name in [
+                "X-Forwarded-For",
+                "Forwarded",
+                "X-Forwarded-Proto",
+                "X-Forwarded-Host",
+            ] {
+                req.headers_mut().remove(&name);
+            }
+            if let Some(name) = &self.real_ip_header {
+                req.headers_mut().remove(name);
+            }
+        }
+        let real_ip = match (&self.trusted_proxies, &self.real_ip_header) {
+            (Some(_), Some(name)) if is_trusted => req
+                .headers()
+                .get_str_value(name)
+                .and_then(|v| Self::parse_real_ip(&v)),
+            _ => None,
+        };
+        let exist_xff = req.headers().get_str_value(&xff);
+        let exist_fwd = req.headers().get_str_value(&fwd);
+        let chain = match &exist_fwd {
+            Some(v) => ConfigForwarded::parse_for(v).join(", "),
+            None => exist_xff.clone().unwrap_or_default(),
+        };
+        let client = match (real_ip, &self.trusted_proxies) {
+            (Some(ip), _) => ip,
+            (None, Some(trusted)) if !chain.is_empty() => {
+                Self::walk_forwarded(trusted, peer, &Self::parse_forwarded(&chain))
+            }
+            _ => peer,
+        };
+        // 只存在其中一种时, 由另一种转换生成, 使发往上游的两种头一致
+        let prefix_xff = exist_xff.unwrap_or(chain);
+        let prefix_fwd = exist_fwd.unwrap_or_else(|| {
+            prefix_xff
+                .split([',', ';'])
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .map(|v| format!("for={}", ConfigForwarded::node(v)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
+        let proto = Self::forwarded_proto(req);
+        let host = req.headers().get_str_value(&HeaderName::HOST);
+        let element = self
+            .forwarded_headers
+            .clone()
+            .unwrap_or_default()
+            .element(&peer.to_string(), &proto, host.as_deref());
+        let join = |prefix: String, value: String| {
+            if prefix.is_empty() {
+                value
+            } else {
+                format!("{}, {}", prefix, value)
+            }
+        };
+        let headers = req.headers_mut();
+        headers.system_insert("{client_ip}".to_string(), client.to_string());
+        headers.system_insert("{forwarded_for}".to_string(), join(prefix_xff, peer.to_string()));
+        headers.system_insert("{forwarded}".to_string(), join(prefix_fwd, element));
+    }
+
+    /// 按配置添加发往上游的代理头, 值来源于`{forwarded_for}`, `{forwarded}`,
+    /// 信任的代理已传入的`X-Forwarded-Proto`, `X-Forwarded-Host`保持不变
+    pub fn set_forwarded_headers<T: webparse::Serialize>(&self, req: &mut Request<T>) {
+        let config = match &self.forwarded_headers {
+            Some(config) => config,
+            None => return,
+        };
+        let proto = Self::forwarded_proto(req);
+        let host = req.headers().get_str_value(&HeaderName::HOST);
+        let headers = req.headers_mut();
+        if config.xff {
+            if let Some(value) = headers.system_get("{forwarded_for}").cloned() {
+                headers.insert("X-Forwarded-For", value);
+            }
+            if !headers.contains(&"X-Forwarded-Proto") {
+                headers.insert("X-Forwarded-Proto", proto);
+            }
+            if let Some(host) = host {
+                if !headers.contains(&"X-Forwarded-Host") {
+                    headers.insert("X-Forwarded-Host", host);
+                }
+            }
+        }
+        if config.forwarded {
+            if let Some(value) = headers.system_get("{forwarded}").cloned() {
+                headers.insert("Forwarded", value);
+            }
+        }
+    }
+
+    /// 生成追加的`Via`值, 如`1.1 wmproxy`
+    fn via_value(version: Version, via: &str, exist: Option<String>) -> String {
+        let proto = version.as_str().trim_start_matches("HTTP/");
+        match exist {
+            Some(exist) if !exist.is_empty() => format!("{}, {} {}", exist, proto, via),
+            _ => format!("{} {}", proto, via),
+        }
+    }
+
+    /// 转发给上游的请求中追加`Via`头
+    pub fn rewrite_request_via<T: webparse::Serialize>(&self, req: &mut Request<T>) {
+        if let Some(via) = &self.via {
+            let exist = req.headers().get_str_value(&HeaderName::VIA);
+            let value = Self::via_value(req.version(), via, exist);
+            req.headers_mut().insert(HeaderName::VIA, value);
+        }
+    }
+
+    /// 按配置处理返回的`Server`, `Via`及`X-Powered-By`头
+    /// 按配置的级别及算法压缩返回, 未配置时使用默认的配置
+    pub fn compress_response<T: webparse::Serialize>(&self, req: &Request<T>, res: &mut Response<Body>) {
+        match &self.compress {
+            Some(compress) => compress.apply(req, res),
+            None => ConfigCompress::default().apply(req, res),
+        }
+    }
+
+    pub fn rewrite_response_server<T: webparse::Serialize>(&self, res: &mut Response<T>) {
+        match self.server_header.as_deref() {
+            None => {
+                res.headers_mut().insert(HeaderName::SERVER, "wmproxy");
+            }
+            Some("off") => {
+                res.headers_mut().remove(&HeaderName::SERVER);
+            }
+            Some("upstream") => {}
+            Some(value) => {
+                res.headers_mut().insert(HeaderName::SERVER, value.to_string());
+            }
+        }
+        if self.hide_powered_by == Some(true) {
+            res.headers_mut().remove(&"X-Powered-By");
+        }
+        if let Some(via) = &self.via {
+            let exist = res.headers().get_str_value(&HeaderName::VIA);
+            let value = Self::via_value(res.version(), via, exist);
+            res.headers_mut().insert(HeaderName::VIA, value);
+        }
+    }
+
+    /// 移除上游返回中不转发给客户端的头, 协议升级的返回保留逐跳的头
+    pub fn hide_response_headers<T: webparse::Serialize>(&self, res: &mut Response<T>) {
+        let contains = |list: &Option<Vec<String>>, name: &str| {
+            list.as_ref()
+                .map(|l| l.iter().any(|v| v.eq_ignore_ascii_case(name)))
+                .unwrap_or(false)
+        };
+        let upgrade = res.status().as_u16() == 101;
+        let hides = res
+            .headers()
+            .iter()
+            .map(|(name, _)| name.name().to_string())
+            .filter(|name| {
+                let default = !upgrade
+                    && DEFAULT_HIDE_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name));
+                (default || contains(&self.hide_headers, name)) && !contains(&self.pass_headers, name)
+            })
+            .collect::<Vec<String>>();
+        for name in hides {
+            res.headers_mut().remove(&name);
+        }
+    }
+
     pub fn get_rate_limit(&self) -> Option<RateLimitLayer> {
         if self.rate_limit.is_some() {
             return Some(RateLimitLayer::new(self.rate_limit.clone().unwrap().0));
@@ -241,3 +667,257 @@ impl CommonConfig {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::CommonConfig;
+    use webparse::{HeaderName, Request, Response};
+
+    fn build_response() -> Response<String> {
+        Response::builder()
+            .header("Server", "nginx/1.25")
+            .header("X-Powered-By", "PHP/8.2")
+            .body(String::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn server_header() {
+        let mut comm = CommonConfig::new();
+        let mut res = build_response();
+        comm.rewrite_response_server(&mut res);
+        assert_eq!(res.headers().get_str_value(&HeaderName::SERVER), Some("wmproxy".to_string()));
+        assert!(res.headers().get_str_value(&"X-Powered-By").is_some());
+
+        comm.server_header = Some("edge".to_string());
+        comm.hide_powered_by = Some(true);
+        let mut res = build_response();
+        comm.rewrite_response_server(&mut res);
+        assert_eq!(res.headers().get_str_value(&HeaderName::SERVER), Some("edge".to_string()));
+        assert!(res.headers().get_str_value(&"X-Powered-By").is_none());
+
+        comm.server_header = Some("off".to_string());
+        let mut res = build_response();
+        comm.rewrite_response_server(&mut res);
+        assert!(res.headers().get_str_value(&HeaderName::SERVER).is_none());
+
+        comm.server_header = Some("upstream".to_string());
+        let mut res = build_response();
+        comm.rewrite_response_server(&mut res);
+        assert_eq!(res.headers().get_str_value(&HeaderName::SERVER), Some("nginx/1.25".to_string()));
+    }
+
+    #[test]
+    fn via_header() {
+        let mut comm = CommonConfig::new();
+        comm.via = Some("wmproxy".to_string());
+        let mut req = Request::builder()
+            .url("http://127.0.0.1/")
+            .header("Via", "1.0 fred")
+            .body(String::new())
+            .unwrap();
+        comm.rewrite_request_via(&mut req);
+        assert_eq!(
+            req.headers().get_str_value(&HeaderName::VIA),
+            Some("1.0 fred, 1.1 wmproxy".to_string())
+        );
+
+        let mut res = build_response();
+        comm.rewrite_response_server(&mut res);
+        assert_eq!(res.headers().get_str_value(&HeaderName::VIA), Some("1.1 wmproxy".to_string()));
+    }
+
+    #[test]
+    fn hide_and_pass_headers() {
+        let build = |status: u16| {
+            Response::builder()
+                .status(status)
+                .header("X-Internal-Trace", "abc")
+                .header("Keep-Alive", "timeout=5")
+                .header("Upgrade", "websocket")
+                .header("Content-Type", "text/plain")
+                .body(String::new())
+                .unwrap()
+        };
+        // 默认仅移除逐跳的头
+        let mut comm = CommonConfig::new();
+        let mut res = build(200);
+        comm.hide_response_headers(&mut res);
+        assert!(res.headers().get_str_value(&"Keep-Alive").is_none());
+        assert!(res.headers().get_str_value(&"Upgrade").is_none());
+        assert!(res.headers().get_str_value(&"X-Internal-Trace").is_some());
+        assert!(res.headers().get_str_value(&"Content-Type").is_some());
+
+        // 协议升级的返回保留逐跳的头
+        let mut res = build(101);
+        comm.hide_response_headers(&mut res);
+        assert!(res.headers().get_str_value(&"Upgrade").is_some());
+
+        comm.hide_headers = Some(vec!["x-internal-trace".to_string()]);
+        comm.pass_headers = Some(vec!["Keep-Alive".to_string()]);
+        let mut child = CommonConfig::new();
+        child.copy_from_parent(&comm);
+        let mut res = build(200);
+        child.hide_response_headers(&mut res);
+        assert!(res.headers().get_str_value(&"X-Internal-Trace").is_none());
+        assert_eq!(res.headers().get_str_value(&"Keep-Alive"), Some("timeout=5".to_string()));
+        assert!(res.headers().get_str_value(&"Upgrade").is_none());
+    }
+
+    fn forwarded_request(peer: &str, xff: Option<&str>) -> Request<String> {
+        let mut builder = Request::builder().url("http://127.0.0.1/");
+        if let Some(xff) = xff {
+            builder = builder.header("X-Forwarded-For", xff.to_string());
+        }
+        let mut req = builder.body(String::new()).unwrap();
+        req.headers_mut()
+            .system_insert("{client_ip}".to_string(), peer.to_string());
+        req
+    }
+
+    fn client_ip(req: &Request<String>) -> (String, String) {
+        (
+            req.headers().system_get("{client_ip}").cloned().unwrap(),
+            req.headers().system_get("{forwarded_for}").cloned().unwrap(),
+        )
+    }
+
+    #[test]
+    fn trusted_forwarded_chain() {
+        let mut comm = CommonConfig::new();
+        comm.trusted_proxies = Some("10.0.0.0/8 127.0.0.1".parse().unwrap());
+
+        // 从右往左跳过信任的代理
+        for (xff, client) in [
+            ("1.1.1.1, 2.2.2.2, 10.0.0.2", "2.2.2.2"),
+            ("1.1.1.1,10.0.0.3 , 10.0.0.2", "1.1.1.1"),
+            ("10.0.0.4, 10.0.0.3", "10.0.0.4"),
+            ("1.1.1.1, 3.3.3.3:8080", "3.3.3.3"),
+            ("1.1.1.1, unknown, 10.0.0.2", "10.0.0.2"),
+            ("[2001:db8::1]:443", "2001:db8::1"),
+        ] {
+            let mut req = forwarded_request("127.0.0.1", Some(xff));
+            comm.resolve_client_ip(&mut req);
+            assert_eq!(client_ip(&req), (client.to_string(), format!("{}, 127.0.0.1", xff)));
+            assert_eq!(req.headers().get_str_value(&"X-Forwarded-For"), Some(xff.to_string()));
+        }
+
+        let mut req = forwarded_request("10.0.0.9", None);
+        comm.resolve_client_ip(&mut req);
+        assert_eq!(client_ip(&req), ("10.0.0.9".to_string(), "10.0.0.9".to_string()));
+    }
+
+    #[test]
+    fn untrusted_forwarded_strip() {
+        let mut comm = CommonConfig::new();
+        let mut req = forwarded_request("8.8.8.8", Some("1.1.1.1"));
+        // 未配置时不做处理
+        comm.resolve_client_ip(&mut req);
+        assert_eq!(req.headers().system_get("{client_ip}"), Some(&"8.8.8.8".to_string()));
+        assert!(req.headers().get_str_value(&"X-Forwarded-For").is_some());
+
+        comm.trusted_proxies = Some("10.0.0.0/8".parse().unwrap());
+        comm.resolve_client_ip(&mut req);
+        assert_eq!(client_ip(&req), ("8.8.8.8".to_string(), "8.8.8.8".to_string()));
+        assert!(req.headers().get_str_value(&"X-Forwarded-For").is_none());
+    }
+
+    #[test]
+    fn real_ip_header() {
+        let mut comm = CommonConfig::new();
+        comm.trusted_proxies = Some("10.0.0.0/8".parse().unwrap());
+        comm.real_ip_header = Some("CF-Connecting-IP".to_string());
+
+        // 来自信任的代理时以配置的头为准, 优先于X-Forwarded-For
+        let mut req = forwarded_request("10.0.0.2", Some("1.1.1.1"));
+        req.headers_mut().insert("cf-connecting-ip", " 2001:db8::1 ");
+        comm.resolve_client_ip(&mut req);
+        assert_eq!(client_ip(&req), ("2001:db8::1".to_string(), "1.1.1.1, 10.0.0.2".to_string()));
+
+        // 无法解析的值被忽略, 按X-Forwarded-For计算
+        let mut req = forwarded_request("10.0.0.2", Some("1.1.1.1"));
+        req.headers_mut().insert("CF-Connecting-IP", "unknown");
+        comm.resolve_client_ip(&mut req);
+        assert_eq!(client_ip(&req).0, "1.1.1.1");
+
+        // 不被信任的来源忽略并移除该头
+        let mut req = forwarded_request("8.8.8.8", None);
+        req.headers_mut().insert("CF-Connecting-IP", "2.2.2.2");
+        comm.resolve_client_ip(&mut req);
+        assert_eq!(client_ip(&req).0, "8.8.8.8");
+        assert!(req.headers().get_str_value(&"CF-Connecting-IP").is_none());
+
+        // 未配置信任的代理时不使用
+        comm.trusted_proxies = None;
+        let mut req = forwarded_request("8.8.8.8", None);
+        req.headers_mut().insert("CF-Connecting-IP", "2.2.2.2");
+        comm.resolve_client_ip(&mut req);
+        assert_eq!(client_ip(&req).0, "8.8.8.8");
+    }
+
+    #[test]
+    fn forwarded_multi_hop() {
+        let mut comm = CommonConfig::new();
+        comm.trusted_proxies = Some("10.0.0.0/8 127.0.0.1".parse().unwrap());
+        let mut req = forwarded_request("127.0.0.1", None);
+        let value = "for=1.1.1.1;proto=https, for=\"[2001:db8::1]:4711\", for=10.0.0.2;by=_lb";
+        req.headers_mut().insert("Forwarded", value);
+        comm.resolve_client_ip(&mut req);
+        assert_eq!(
+            client_ip(&req),
+            (
+                "2001:db8::1".to_string(),
+                "1.1.1.1, [2001:db8::1]:4711, 10.0.0.2, 127.0.0.1".to_string()
+            )
+        );
+        assert_eq!(
+            req.headers().system_get("{forwarded}").cloned().unwrap(),
+            format!("{}, for=127.0.0.1;host=\"127.0.0.1:80\";proto=http", value)
+        );
+
+        // 同时存在时以Forwarded为准, 转发的X-Forwarded-For仍追加在原有的值之后
+        let mut req = forwarded_request("10.0.0.3", Some("6.6.6.6, 10.0.0.2"));
+        req.headers_mut().insert("Forwarded", "for=5.5.5.5, for=10.0.0.2");
+        comm.resolve_client_ip(&mut req);
+        assert_eq!(
+            client_ip(&req),
+            ("5.5.5.5".to_string(), "6.6.6.6, 10.0.0.2, 10.0.0.3".to_string())
+        );
+
+        // 不被信任的地址发来的Forwarded被移除
+        let mut req = forwarded_request("8.8.8.8", None);
+        req.headers_mut().insert("Forwarded", "for=1.1.1.1");
+        req.headers_mut().insert("X-Forwarded-Proto", "https");
+        comm.resolve_client_ip(&mut req);
+        assert_eq!(client_ip(&req).0, "8.8.8.8");
+        assert!(req.headers().get_str_value(&"Forwarded").is_none());
+        assert!(req.headers().get_str_value(&"X-Forwarded-Proto").is_none());
+    }
+
+    #[test]
+    fn emit_forwarded_headers() {
+        let mut comm = CommonConfig::new();
+        comm.trusted_proxies = Some("10.0.0.0/8".parse().unwrap());
+        comm.forwarded_headers = Some("both by=_edge".parse().unwrap());
+        // 仅有X-Forwarded-For时, 转换生成Forwarded
+        let mut req = forwarded_request("10.0.0.2", Some("1.1.1.1, 2001:db8::1"));
+        req.headers_mut().insert("Host", "example.com:8080");
+        comm.resolve_client_ip(&mut req);
+        comm.set_forwarded_headers(&mut req);
+        let get = |name: &'static str| req.headers().get_str_value(&name).unwrap();
+        assert_eq!(get("X-Forwarded-For"), "1.1.1.1, 2001:db8::1, 10.0.0.2");
+        assert_eq!(get("X-Forwarded-Proto"), "http");
+        assert_eq!(get("X-Forwarded-Host"), "example.com:8080");
+        assert_eq!(
+            get("Forwarded"),
+            "for=1.1.1.1, for=\"[2001:db8::1]\", for=10.0.0.2;by=_edge;host=\"example.com:8080\";proto=http"
+        );
+
+        comm.forwarded_headers = Some("xff".parse().unwrap());
+        let mut req = forwarded_request("10.0.0.2", None);
+        comm.resolve_client_ip(&mut req);
+        comm.set_forwarded_headers(&mut req);
+        assert_eq!(req.headers().get_str_value(&"X-Forwarded-For"), Some("10.0.0.2".to_string()));
+        assert!(req.headers().get_str_value(&"Forwarded").is_none());
+    }
+}
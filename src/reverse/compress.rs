@@ -0,0 +1,486 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/22 10:05:37
+
+use std::{
+    fmt::Display,
+    io::{self, Write},
+    str::FromStr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use brotli::CompressorWriter;
+use flate2::{
+    write::{DeflateEncoder, GzEncoder},
+    Compression,
+};
+use tokio::sync::mpsc::channel;
+use webparse::{Binary, BinaryMut, HeaderName, Method, Request, Response};
+use wenmeng::{Body, Consts};
+
+use super::body_buffer::{read_body_data, READ_BUFFER};
+
+/// 正在压缩的返回数
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+/// 负载过高时降为最快级别压缩的次数
+static DEGRADED: AtomicU64 = AtomicU64::new(0);
+/// 负载过高时跳过压缩的次数
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+/// 与wenmeng一致, 已完整的body超过该大小才压缩
+const MIN_COMPRESS_SIZE: usize = 1024;
+/// 负载过高时使用的最快级别
+const FAST_LEVEL: u32 = 1;
+
+/// 支持的压缩算法, zstd暂不支持
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    fn method(&self) -> i8 {
+        match self {
+            Encoding::Gzip => Consts::COMPRESS_METHOD_GZIP,
+            Encoding::Deflate => Consts::COMPRESS_METHOD_DEFLATE,
+            Encoding::Brotli => Consts::COMPRESS_METHOD_BROTLI,
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Encoding::Gzip),
+            "deflate" => Ok(Encoding::Deflate),
+            "br" => Ok(Encoding::Brotli),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("不支持的压缩算法:{}", s),
+            )),
+        }
+    }
+}
+
+/// 返回的压缩配置, 如`gzip=6 br=5 prefer=br,gzip busy=64`
+///
+/// * `gzip`/`deflate` 压缩级别1-9, 默认6
+/// * `br` 压缩级别0-11, 默认5
+/// * `prefer` 客户端的q值相同时优先的算法, 未列出的算法不使用, 默认`gzip,br,deflate`
+/// * `busy` 同时压缩的返回数超过该值时以最快级别压缩, 超过两倍时不压缩, 默认为CPU数的2倍, 0为不限制
+/// * `off` 不压缩返回
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigCompress {
+    pub enable: bool,
+    pub gzip: u32,
+    pub deflate: u32,
+    pub br: u32,
+    pub prefer: Vec<Encoding>,
+    pub busy: Option<usize>,
+}
+
+impl Default for ConfigCompress {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            gzip: 6,
+            deflate: 6,
+            br: 5,
+            prefer: vec![Encoding::Gzip, Encoding::Brotli, Encoding::Deflate],
+            busy: None,
+        }
+    }
+}
+
+impl ConfigCompress {
+    pub fn active_count() -> usize {
+        ACTIVE.load(Ordering::Relaxed)
+    }
+
+    pub fn degrade_count() -> u64 {
+        DEGRADED.load(Ordering::Relaxed)
+    }
+
+    pub fn skip_count() -> u64 {
+        SKIPPED.load(Ordering::Relaxed)
+    }
+
+    pub fn level(&self, encoding: Encoding) -> u32 {
+        match encoding {
+            Encoding::Gzip => self.gzip,
+            Encoding::Deflate => self.deflate,
+            Encoding::Brotli => self.br,
+        }
+    }
+
+    fn busy(&self) -> usize {
+        self.busy.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+                * 2
+        })
+    }
+
+    /// 按客户端`Accept-Encoding`的q值选择算法, q值相同时按prefer的顺序
+    pub fn negotiate(&self, accept: &str) -> Option<Encoding> {
+        let mut accepts = vec![];
+        for v in accept.split(',') {
+            let mut parts = v.split(';');
+            let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let mut q = 1.0f32;
+            for p in parts {
+                if let Some((key, value)) = p.split_once('=') {
+                    if key.trim().eq_ignore_ascii_case("q") {
+                        q = value.trim().parse().unwrap_or(0.0);
+                    }
+                }
+            }
+            accepts.push((name, q));
+        }
+        let quality = |name: &str| {
+            accepts
+                .iter()
+                .find(|(n, _)| n == name)
+                .or_else(|| accepts.iter().find(|(n, _)| n == "*"))
+                .map(|(_, q)| *q)
+                .unwrap_or(0.0)
+        };
+        let mut best = None;
+        let mut best_q = 0.0;
+        for encoding in &self.prefer {
+            let q = quality(encoding.name());
+            if q > best_q {
+                best = Some(*encoding);
+                best_q = q;
+            }
+        }
+        best
+    }
+
+    /// 按配置压缩返回的body, 不适合压缩时保持原样, 且阻止后续以默认的级别压缩
+    pub fn apply<T: webparse::Serialize>(&self, req: &Request<T>, res: &mut Response<Body>) {
+        // 与wenmeng自动压缩的条件一致, 已指定编码或已知长度的不做处理
+        if res.get_body_len() != 0 || res.headers().contains(&HeaderName::CONTENT_ENCODING) {
+            return;
+        }
+        let status = res.status().as_u16();
+        if *req.method() == Method::Head || status < 200 || status == 204 || status == 304 {
+            return;
+        }
+        if res.body().is_end() && res.body_mut().origin_len() <= MIN_COMPRESS_SIZE {
+            return;
+        }
+        let encoding = match req.headers().get_str_value(&HeaderName::ACCEPT_ENCODING) {
+            Some(accept) if self.enable => self.negotiate(&accept),
+            _ => None,
+        };
+        match encoding {
+            Some(encoding) => self.start(encoding, res),
+            None => Self::disable(res),
+        }
+    }
+
+    /// 以选定的算法压缩返回的body, 负载过高时降级或不压缩
+    pub fn start(&self, encoding: Encoding, res: &mut Response<Body>) {
+        let mut level = self.level(encoding);
+        let busy = self.busy();
+        let active = ACTIVE.load(Ordering::Relaxed);
+        if busy > 0 && active >= busy * 2 {
+            SKIPPED.fetch_add(1, Ordering::Relaxed);
+            Self::disable(res);
+            return;
+        } else if busy > 0 && active >= busy {
+            DEGRADED.fetch_add(1, Ordering::Relaxed);
+            level = FAST_LEVEL;
+        }
+        Self::compress(encoding, level, res);
+    }
+
+    /// 以空的编码阻止wenmeng自动压缩
+    fn disable(res: &mut Response<Body>) {
+        res.headers_mut().insert(HeaderName::CONTENT_ENCODING, "");
+    }
+
+    fn compress(encoding: Encoding, level: u32, res: &mut Response<Body>) {
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = channel(10);
+        let mut body = std::mem::replace(
+            res.body_mut(),
+            Body::new(receiver, BinaryMut::new(), false),
+        );
+        // 数据已按该编码压缩, 发送时原样输出
+        res.body_mut().set_origin_compress_method(encoding.method());
+        res.body_mut().add_compress_method(encoding.method());
+        res.headers_mut()
+            .insert(HeaderName::CONTENT_ENCODING, encoding.name());
+        tokio::spawn(async move {
+            let mut encoder = Encoder::new(encoding, level);
+            let mut data = vec![0u8; READ_BUFFER];
+            let finish = loop {
+                let n = match read_body_data(&mut body, &mut data).await {
+                    Ok(0) => break true,
+                    Ok(n) => n,
+                    Err(_) => break false,
+                };
+                // 每次读取后输出, 流式的返回可及时发送
+                let out = match encoder.write(&data[..n]) {
+                    Ok(out) => out,
+                    Err(_) => break false,
+                };
+                if !out.is_empty() && sender.send((false, Binary::from(out))).await.is_err() {
+                    break false;
+                }
+            };
+            if finish {
+                if let Ok(out) = encoder.finish() {
+                    let _ = sender.send((true, Binary::from(out))).await;
+                }
+            }
+            ACTIVE.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(DeflateEncoder<Vec<u8>>),
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding, level: u32) -> Self {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(vec![], Compression::new(level))),
+            Encoding::Deflate => {
+                Encoder::Deflate(DeflateEncoder::new(vec![], Compression::new(level)))
+            }
+            Encoding::Brotli => {
+                Encoder::Brotli(Box::new(CompressorWriter::new(vec![], 4096, level, 22)))
+            }
+        }
+    }
+
+    /// 写入数据并取出已压缩的部分
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let out = match self {
+            Encoder::Gzip(e) => {
+                e.write_all(data)?;
+                e.flush()?;
+                e.get_mut()
+            }
+            Encoder::Deflate(e) => {
+                e.write_all(data)?;
+                e.flush()?;
+                e.get_mut()
+            }
+            Encoder::Brotli(e) => {
+                e.write_all(data)?;
+                e.flush()?;
+                e.get_mut()
+            }
+        };
+        Ok(std::mem::take(out))
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Deflate(e) => e.finish(),
+            Encoder::Brotli(e) => Ok(e.into_inner()),
+        }
+    }
+}
+
+impl FromStr for ConfigCompress {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = ConfigCompress::default();
+        for v in s.split_whitespace() {
+            let err = || io::Error::new(io::ErrorKind::InvalidInput, format!("错误的compress配置:{}", v));
+            let (key, value) = v.split_once('=').unwrap_or((v, ""));
+            let level = |max: u32| value.parse::<u32>().ok().filter(|l| *l <= max).ok_or_else(err);
+            match key {
+                "off" => config.enable = false,
+                "gzip" => config.gzip = level(9)?.max(1),
+                "deflate" => config.deflate = level(9)?.max(1),
+                "br" => config.br = level(11)?,
+                "prefer" => {
+                    config.prefer = vec![];
+                    for name in value.split(',') {
+                        let encoding = name.parse::<Encoding>()?;
+                        if !config.prefer.contains(&encoding) {
+                            config.prefer.push(encoding);
+                        }
+                    }
+                }
+                "busy" => config.busy = Some(value.parse().map_err(|_| err())?),
+                _ => return Err(err()),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl Display for ConfigCompress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.enable {
+            return f.write_str("off");
+        }
+        let prefer = self.prefer.iter().map(|e| e.name()).collect::<Vec<_>>();
+        write!(
+            f,
+            "gzip={} deflate={} br={} prefer={}",
+            self.gzip,
+            self.deflate,
+            self.br,
+            prefer.join(",")
+        )?;
+        if let Some(busy) = self.busy {
+            write!(f, " busy={}", busy)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use tokio::sync::mpsc::channel;
+    use webparse::{Binary, BinaryMut, HeaderName, Request, Response};
+    use wenmeng::Body;
+
+    use super::{ConfigCompress, Encoding};
+    use crate::reverse::body_buffer::read_body_data;
+
+    #[test]
+    fn parse_compress() {
+        let config = "gzip=3 br=9 prefer=br,gzip busy=8".parse::<ConfigCompress>().unwrap();
+        assert_eq!(config.level(Encoding::Gzip), 3);
+        assert_eq!(config.level(Encoding::Brotli), 9);
+        assert_eq!(config.prefer, vec![Encoding::Brotli, Encoding::Gzip]);
+        assert_eq!(config.to_string().parse::<ConfigCompress>().unwrap(), config);
+        assert!(!"off".parse::<ConfigCompress>().unwrap().enable);
+        assert!("gzip=10".parse::<ConfigCompress>().is_err());
+        assert!("br=12".parse::<ConfigCompress>().is_err());
+        assert!("prefer=zstd,gzip".parse::<ConfigCompress>().is_err());
+    }
+
+    #[test]
+    fn negotiate() {
+        let config = "prefer=br,gzip,deflate".parse::<ConfigCompress>().unwrap();
+        // q值相同时按服务端的顺序
+        assert_eq!(config.negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        // 客户端的q值优先
+        assert_eq!(config.negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(config.negotiate("br;q=0, gzip;q=0.2"), Some(Encoding::Gzip));
+        assert_eq!(config.negotiate("*;q=0.5, gzip;q=0.8"), Some(Encoding::Gzip));
+        assert_eq!(config.negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(config.negotiate("identity"), None);
+        assert_eq!(config.negotiate("br;q=0, *;q=0"), None);
+        // 未列出的算法不使用
+        let config = "prefer=gzip".parse::<ConfigCompress>().unwrap();
+        assert_eq!(config.negotiate("br"), None);
+        assert_eq!(config.negotiate("br, gzip;q=0.1"), Some(Encoding::Gzip));
+    }
+
+    async fn compress_data(config: &ConfigCompress, data: &[u8]) -> (String, Vec<u8>) {
+        let req = Request::builder()
+            .url("/")
+            .header(HeaderName::ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+        // 以流的方式返回, 与上游chunked的返回一致
+        let (sender, receiver) = channel(1);
+        sender.send((true, Binary::from(data.to_vec()))).await.unwrap();
+        let mut res = Response::builder()
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap();
+        config.apply(&req, &mut res);
+        let encoding = res
+            .headers()
+            .get_str_value(&HeaderName::CONTENT_ENCODING)
+            .unwrap_or_default();
+        let mut out = vec![];
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let n = read_body_data(res.body_mut(), &mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        (encoding, out)
+    }
+
+    #[tokio::test]
+    async fn compress_level() {
+        let data = (0..20000)
+            .map(|i| format!("{} {} ", i % 97, i * 7 % 1013))
+            .collect::<String>();
+        let fast = "gzip=1 busy=0".parse::<ConfigCompress>().unwrap();
+        let best = "gzip=9 busy=0".parse::<ConfigCompress>().unwrap();
+        let (encoding, fast_out) = compress_data(&fast, data.as_bytes()).await;
+        assert_eq!(encoding, "gzip");
+        let (_, best_out) = compress_data(&best, data.as_bytes()).await;
+        assert!(best_out.len() < fast_out.len(), "{} {}", best_out.len(), fast_out.len());
+        for out in [fast_out, best_out] {
+            let mut decoded = String::new();
+            GzDecoder::new(&out[..]).read_to_string(&mut decoded).unwrap();
+            assert_eq!(decoded, data);
+        }
+
+        // 关闭时不压缩, 且阻止默认的压缩
+        let off = "off".parse::<ConfigCompress>().unwrap();
+        let (encoding, out) = compress_data(&off, data.as_bytes()).await;
+        assert_eq!((encoding.as_str(), out.len()), ("", data.len()));
+    }
+
+    #[tokio::test]
+    async fn busy_guard() {
+        let config = "busy=1".parse::<ConfigCompress>().unwrap();
+        let req = Request::builder()
+            .url("/")
+            .header(HeaderName::ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+        // 未结束的返回一直占用压缩的名额
+        let (_sender, receiver) = channel(1);
+        let mut res = Response::builder()
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap();
+        config.apply(&req, &mut res);
+        assert!(ConfigCompress::active_count() >= 1);
+
+        let before = ConfigCompress::degrade_count() + ConfigCompress::skip_count();
+        let (_sender, receiver) = channel(1);
+        let mut res = Response::builder()
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap();
+        config.apply(&req, &mut res);
+        assert!(ConfigCompress::degrade_count() + ConfigCompress::skip_count() > before);
+    }
+}
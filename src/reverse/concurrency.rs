@@ -0,0 +1,148 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/08 16:05:12
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// 所有location当前正在处理的请求数
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// 所有location当前排队等待的请求数
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+/// 因排队已满被拒绝的请求数
+static REJECTED: AtomicUsize = AtomicUsize::new(0);
+
+/// location的并发请求限制, 超过并发数的请求排队等待, 排队已满时拒绝
+#[derive(Debug)]
+pub struct ConcurrencyLimit {
+    semaphore: Arc<Semaphore>,
+    /// 最大的排队数
+    queue_len: usize,
+    /// 当前的排队数
+    queued: AtomicUsize,
+}
+
+/// 占用的并发名额, 释放时归还
+#[derive(Debug)]
+pub struct ConcurrencyPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max: usize, queue_len: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+            queue_len,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// 获取并发名额, 无空闲名额时排队等待, 排队已满返回None
+    pub async fn acquire(&self) -> Option<ConcurrencyPermit> {
+        let permit = match self.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if self.queued.fetch_add(1, Ordering::Relaxed) >= self.queue_len {
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+                    REJECTED.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                QUEUED.fetch_add(1, Ordering::Relaxed);
+                let _guard = QueueGuard(&self.queued);
+                self.semaphore.clone().acquire_owned().await.ok()?
+            }
+        };
+        IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        Some(ConcurrencyPermit { _permit: permit })
+    }
+
+    /// 当前排队的请求数
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// 所有location当前正在处理的请求数
+    pub fn in_flight_total() -> usize {
+        IN_FLIGHT.load(Ordering::Relaxed)
+    }
+
+    /// 所有location当前排队等待的请求数
+    pub fn queued_total() -> usize {
+        QUEUED.load(Ordering::Relaxed)
+    }
+
+    /// 因排队已满被拒绝的请求数
+    pub fn rejected_total() -> usize {
+        REJECTED.load(Ordering::Relaxed)
+    }
+}
+
+/// 离开排队时减少计数, 等待中的请求被取消时同样生效
+struct QueueGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+        QUEUED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::ConcurrencyLimit;
+    use crate::reverse::LocationConfig;
+
+    #[tokio::test]
+    async fn limit_and_queue() {
+        let limit = Arc::new(ConcurrencyLimit::new(2, 1));
+        let first = limit.acquire().await.unwrap();
+        let _second = limit.acquire().await.unwrap();
+
+        // 第三个请求进入排队
+        let clone = limit.clone();
+        let queued = tokio::spawn(async move { clone.acquire().await.is_some() });
+        while limit.queued() != 1 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        // N+queue+1个请求被拒绝
+        assert!(limit.acquire().await.is_none());
+        assert!(ConcurrencyLimit::rejected_total() >= 1);
+
+        // 释放名额后排队的请求继续处理
+        drop(first);
+        assert!(queued.await.unwrap());
+        assert_eq!(limit.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn location_reject_response() {
+        let mut location = LocationConfig::new();
+        location.max_concurrent_requests = Some(1);
+        location.retry_after = Some("5s".parse().unwrap());
+        location.init_concurrency();
+        let _permit = location.acquire_concurrency().await.unwrap();
+        let res = location.acquire_concurrency().await.unwrap_err();
+        assert_eq!(res.status().as_u16(), 503);
+        assert_eq!(res.headers().get_str_value(&"Retry-After"), Some("5".to_string()));
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/25 10:36:20
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use lazy_static::lazy_static;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+lazy_static! {
+    // 各上游地址建立连接的限制, 按地址区分, 重新加载配置后保留
+    static ref LIMITS: Mutex<HashMap<SocketAddr, Arc<ConnectState>>> = Mutex::new(HashMap::new());
+}
+
+struct ConnectState {
+    semaphore: Arc<Semaphore>,
+    /// 创建时的并发数, 配置变化后重新创建
+    max: usize,
+    /// 当前等待建立连接的数量
+    pending: AtomicUsize,
+    /// 因排队已满放弃的连接数
+    rejected: AtomicU64,
+}
+
+/// 单个上游地址同时建立连接(含TLS握手)的限制, 超过的请求排队等待, 防止突发流量时同时向冷启动的上游发起大量握手
+pub struct ConnectLimit {
+    addr: SocketAddr,
+    max: usize,
+    /// 最大的排队数
+    queue_len: usize,
+}
+
+/// 占用的建立连接名额, 握手完成后释放
+pub struct ConnectPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ConnectLimit {
+    pub fn new(addr: SocketAddr, max: usize, queue_len: usize) -> Self {
+        Self {
+            addr,
+            max: max.max(1),
+            queue_len,
+        }
+    }
+
+    fn state(&self) -> Arc<ConnectState> {
+        let mut limits = LIMITS.lock().unwrap_or_else(|e| e.into_inner());
+        let state = limits.entry(self.addr).or_insert_with(|| Self::new_state(self.max));
+        if state.max != self.max {
+            // 已在等待的连接仍按原来的限制完成
+            *state = Self::new_state(self.max);
+        }
+        state.clone()
+    }
+
+    fn new_state(max: usize) -> Arc<ConnectState> {
+        Arc::new(ConnectState {
+            semaphore: Arc::new(Semaphore::new(max)),
+            max,
+            pending: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        })
+    }
+
+    /// 获取建立连接的名额, 无空闲名额时排队等待, 排队已满返回None
+    pub async fn acquire(&self) -> Option<ConnectPermit> {
+        let state = self.state();
+        let permit = match state.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if state.pending.fetch_add(1, Ordering::Relaxed) >= self.queue_len {
+                    state.pending.fetch_sub(1, Ordering::Relaxed);
+                    state.rejected.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                let _guard = PendingGuard(&state.pending);
+                state.semaphore.clone().acquire_owned().await.ok()?
+            }
+        };
+        Some(ConnectPermit { _permit: permit })
+    }
+
+    /// 各上游地址当前等待建立连接的数量及因排队已满放弃的连接数, 按地址排序
+    pub fn list() -> Vec<(SocketAddr, usize, u64)> {
+        let limits = LIMITS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut list = limits
+            .iter()
+            .map(|(addr, state)| {
+                (
+                    *addr,
+                    state.pending.load(Ordering::Relaxed),
+                    state.rejected.load(Ordering::Relaxed),
+                )
+            })
+            .collect::<Vec<_>>();
+        list.sort();
+        list
+    }
+}
+
+/// 离开排队时减少计数, 等待中的请求被取消时同样生效
+struct PendingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use super::ConnectLimit;
+
+    fn pending(addr: SocketAddr) -> (usize, u64) {
+        ConnectLimit::list()
+            .into_iter()
+            .find(|(a, _, _)| *a == addr)
+            .map(|(_, p, r)| (p, r))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn limit_and_queue() {
+        let addr: SocketAddr = "127.0.0.2:1".parse().unwrap();
+        let limit = Arc::new(ConnectLimit::new(addr, 1, 1));
+        let first = limit.acquire().await.unwrap();
+
+        let clone = limit.clone();
+        let queued = tokio::spawn(async move { clone.acquire().await.is_some() });
+        while pending(addr).0 != 1 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        // 排队已满时放弃
+        assert!(limit.acquire().await.is_none());
+        assert_eq!(pending(addr), (1, 1));
+
+        drop(first);
+        assert!(queued.await.unwrap());
+        assert_eq!(pending(addr), (0, 1));
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/09 17:42:18
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Write},
+    io,
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use webparse::{BinaryMut, Buf, HeaderMap, HeaderName, Request, Response};
+use wenmeng::{Body, ProtResult};
+
+use crate::ConfigSize;
+
+lazy_static! {
+    /// 控制端运行时修改的开关, key为location的rule, `*`表示所有的location
+    static ref DUMP_SWITCH: RwLock<HashMap<String, bool>> = RwLock::new(HashMap::new());
+}
+
+/// 默认脱敏的请求及返回头
+const DEFAULT_REDACT: [&str; 4] = ["Authorization", "Proxy-Authorization", "Cookie", "Set-Cookie"];
+
+/// 调试用的请求及返回的完整输出, 与访问日志不同, 仅在排查问题时临时开启
+///
+/// 配置格式为`body=1k redact=Authorization,Cookie log=debug enable=false`
+/// * body: body的预览上限, 仅预览长度已知且不超过上限的未压缩body, 默认为0不输出
+/// * redact: 需脱敏的头, 默认为Authorization, Proxy-Authorization, Cookie, Set-Cookie
+/// * log: 写入的日志名称, 需在log_names中配置, 默认写入trace日志
+/// * enable: 是否开启, 默认开启, 可通过控制端`/debug-dump?rule=/api&enable=true`在运行时修改
+///
+/// 未配置的location也可通过控制端开启, 此时使用默认的配置
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigDebugDump {
+    pub body: usize,
+    pub redact: Vec<String>,
+    pub log: Option<String>,
+    pub enable: bool,
+}
+
+/// 请求在各阶段的耗时, 仅在开启时存在于请求的extensions中
+#[derive(Debug)]
+pub struct DumpTimer {
+    start: Instant,
+    last: Instant,
+    stages: Vec<(&'static str, Duration)>,
+}
+
+impl Default for ConfigDebugDump {
+    fn default() -> Self {
+        Self {
+            body: 0,
+            redact: DEFAULT_REDACT.iter().map(|v| v.to_string()).collect(),
+            log: None,
+            enable: true,
+        }
+    }
+}
+
+impl ConfigDebugDump {
+    /// 获取location当前生效的配置, 控制端的开关优先于配置
+    pub fn active(config: &Option<ConfigDebugDump>, rule: &str) -> Option<ConfigDebugDump> {
+        let switch = DUMP_SWITCH.read().ok().and_then(|s| {
+            s.get(rule).or_else(|| s.get("*")).cloned()
+        });
+        match (config, switch) {
+            (_, Some(false)) => None,
+            (Some(c), Some(true)) => Some(ConfigDebugDump { enable: true, ..c.clone() }),
+            (None, Some(true)) => Some(ConfigDebugDump::default()),
+            (Some(c), None) if c.enable => Some(c.clone()),
+            _ => None,
+        }
+    }
+
+    /// 运行时修改开关, rule为None时作用于所有的location
+    pub fn set_enable(rule: Option<&str>, enable: Option<bool>) {
+        if let Ok(mut switch) = DUMP_SWITCH.write() {
+            let key = rule.unwrap_or("*").to_string();
+            match enable {
+                Some(enable) => switch.insert(key, enable),
+                None => switch.remove(&key),
+            };
+        }
+    }
+
+    /// 控制端修改的开关列表
+    pub fn switch_list() -> HashMap<String, bool> {
+        DUMP_SWITCH.read().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    /// 开始记录, 输出请求的内容并返回之前的计时
+    pub async fn start(&self, rule: &str, req: &mut Request<Body>) -> Option<DumpTimer> {
+        let data = self.request_text(rule, req).await;
+        self.output(&data);
+        let now = Instant::now();
+        req.extensions_mut().insert(DumpTimer {
+            start: now,
+            last: now,
+            stages: vec![],
+        })
+    }
+
+    /// 结束记录, 输出返回的内容及各阶段的耗时, 并恢复之前的计时
+    pub async fn finish(
+        &self,
+        rule: &str,
+        req: &mut Request<Body>,
+        res: &mut ProtResult<Response<Body>>,
+        prev: Option<DumpTimer>,
+    ) {
+        let timer = req.extensions_mut().remove::<DumpTimer>();
+        if let Some(prev) = prev {
+            req.extensions_mut().insert(prev);
+        }
+        let data = self.response_text(rule, req, res, timer).await;
+        self.output(&data);
+    }
+
+    async fn request_text(&self, rule: &str, req: &mut Request<Body>) -> String {
+        let mut data = format!("debug dump [{}] {} {} {:?}\n", rule, req.method(), req.url(), req.version());
+        self.write_headers(&mut data, req.headers());
+        // 等待100 Continue的请求读取body将阻塞
+        if !req.headers().contains(&"Expect") {
+            let len = self.preview_len(req.headers());
+            self.write_body(&mut data, len, req.body_mut()).await;
+        }
+        data
+    }
+
+    async fn response_text(
+        &self,
+        rule: &str,
+        req: &Request<Body>,
+        res: &mut ProtResult<Response<Body>>,
+        timer: Option<DumpTimer>,
+    ) -> String {
+        let mut data = format!("debug dump [{}] {} {}", rule, req.method(), req.url());
+        match res {
+            Ok(res) => {
+                let _ = writeln!(data, " -> {} {:?}", res.status(), res.version());
+                self.write_headers(&mut data, res.headers());
+                let len = self.preview_len(res.headers());
+                self.write_body(&mut data, len, res.body_mut()).await;
+            }
+            Err(e) => {
+                let _ = writeln!(data, " -> error {:?}", e);
+            }
+        }
+        if let Some(mut timer) = timer {
+            timer.mark("response");
+            data.push_str("timing:");
+            for (stage, cost) in &timer.stages {
+                let _ = write!(data, " {}={:.3}ms", stage, cost.as_secs_f64() * 1000.0);
+            }
+            let _ = write!(data, " total={:.3}ms", timer.start.elapsed().as_secs_f64() * 1000.0);
+        }
+        data
+    }
+
+    fn write_headers(&self, data: &mut String, headers: &HeaderMap) {
+        for (name, value) in headers.iter() {
+            if self.redact.iter().any(|r| r.eq_ignore_ascii_case(name.name())) {
+                let _ = writeln!(data, "{}: <redacted>", name);
+            } else {
+                let _ = writeln!(data, "{}: {}", name, value);
+            }
+        }
+    }
+
+    /// 可预览的body长度, 仅预览长度已知且不超过上限的未压缩body, 以免影响流式的转发
+    fn preview_len(&self, headers: &HeaderMap) -> Result<usize, &'static str> {
+        if headers.contains(&HeaderName::CONTENT_ENCODING) {
+            return Err("encoded");
+        }
+        let len = headers
+            .get_str_value(&HeaderName::CONTENT_LENGTH)
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .ok_or("unknown length")?;
+        if len > self.body {
+            return Err("over preview size");
+        }
+        Ok(len)
+    }
+
+    /// 读取完整的body用于预览, 并以读取的内容重新构造body
+    async fn write_body(&self, data: &mut String, len: Result<usize, &'static str>, body: &mut Body) {
+        if self.body == 0 {
+            return;
+        }
+        match len {
+            Ok(0) => {}
+            Ok(_) => {
+                let mut buffer = BinaryMut::new();
+                let _ = body.read_all(&mut buffer).await;
+                let _ = writeln!(
+                    data,
+                    "body({} bytes): {}",
+                    buffer.remaining(),
+                    String::from_utf8_lossy(buffer.chunk())
+                );
+                *body = Body::new_binary(buffer);
+            }
+            Err(reason) => {
+                let _ = writeln!(data, "body: <{}>", reason);
+            }
+        }
+    }
+
+    fn output(&self, data: &str) {
+        match &self.log {
+            Some(name) => log::info!(target: name, "{}", data),
+            None => log::trace!("{}", data),
+        }
+    }
+}
+
+impl DumpTimer {
+    /// 标记阶段结束, 耗时为与上一阶段的间隔
+    pub fn mark(&mut self, stage: &'static str) {
+        let now = Instant::now();
+        self.stages.push((stage, now - self.last));
+        self.last = now;
+    }
+
+    /// 请求开启了记录时标记阶段
+    pub fn mark_request(req: &mut Request<Body>, stage: &'static str) {
+        if let Some(timer) = req.extensions_mut().get_mut::<DumpTimer>() {
+            timer.mark(stage);
+        }
+    }
+}
+
+impl FromStr for ConfigDebugDump {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = ConfigDebugDump::default();
+        for v in s.split_whitespace() {
+            let (key, value) = match v.split_once('=') {
+                Some(kv) => kv,
+                None => (v, ""),
+            };
+            match key {
+                "body" => {
+                    let size = value.parse::<ConfigSize>().map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidInput, format!("错误的body长度:{}", v))
+                    })?;
+                    config.body = size.0 as usize;
+                }
+                "redact" => {
+                    config.redact = value
+                        .split(',')
+                        .map(|v| v.trim())
+                        .filter(|v| !v.is_empty())
+                        .map(|v| v.to_string())
+                        .collect();
+                }
+                "log" if !value.is_empty() => config.log = Some(value.to_string()),
+                "enable" => config.enable = value != "false" && value != "0",
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("未知的debug_dump配置:{}", v),
+                    ))
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl Display for ConfigDebugDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "body={} redact={}", self.body, self.redact.join(","))?;
+        if let Some(log) = &self.log {
+            write!(f, " log={}", log)?;
+        }
+        if !self.enable {
+            f.write_str(" enable=false")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::{BinaryMut, Buf, Request, Response};
+    use wenmeng::Body;
+
+    use super::{ConfigDebugDump, DumpTimer};
+
+    #[tokio::test]
+    async fn dump_and_redact() {
+        let config = "body=64 redact=Authorization,X-Token,Set-Cookie log=debug".parse::<ConfigDebugDump>().unwrap();
+        assert_eq!(config.body, 64);
+        assert_eq!(config.log, Some("debug".to_string()));
+        assert_eq!(config.to_string().parse::<ConfigDebugDump>().unwrap(), config);
+        assert!("unknown=1".parse::<ConfigDebugDump>().is_err());
+
+        let mut req = Request::builder()
+            .method("POST")
+            .url("/api/user")
+            .header("authorization", "Bearer secret")
+            .header("X-Token", "token")
+            .header("Accept", "text/html")
+            .header("Content-Length", "11")
+            .body(Body::new_text("hello world".to_string()))
+            .unwrap();
+        let text = config.request_text("/api", &mut req).await;
+        assert!(text.contains("POST /api/user"));
+        assert!(!text.contains("secret") && !text.contains(": token"));
+        assert!(text.contains("text/html"));
+        assert!(text.contains("body(11 bytes): hello world"));
+        // 预览后body仍可正常读取
+        let mut buffer = BinaryMut::new();
+        req.body_mut().read_all(&mut buffer).await;
+        assert_eq!(buffer.chunk(), b"hello world");
+
+        let prev = config.start("/api", &mut req).await;
+        assert!(prev.is_none());
+        DumpTimer::mark_request(&mut req, "upstream");
+        let res = Response::builder()
+            .header("Set-Cookie", "sid=1")
+            .header("Transfer-Encoding", "chunked")
+            .body(Body::empty())
+            .unwrap();
+        let timer = req.extensions_mut().remove::<DumpTimer>();
+        let text = config.response_text("/api", &req, &mut Ok(res), timer).await;
+        assert!(text.contains("-> 200"));
+        assert!(text.to_lowercase().contains("set-cookie: <redacted>"));
+        assert!(text.contains("body: <unknown length>"));
+        assert!(text.contains("upstream=") && text.contains("response=") && text.contains("total="));
+    }
+
+    #[test]
+    fn runtime_switch() {
+        let rule = "/debug_switch_test";
+        assert!(ConfigDebugDump::active(&None, rule).is_none());
+        let disabled = Some("enable=false".parse::<ConfigDebugDump>().unwrap());
+        assert!(ConfigDebugDump::active(&disabled, rule).is_none());
+
+        ConfigDebugDump::set_enable(Some(rule), Some(true));
+        assert_eq!(ConfigDebugDump::active(&None, rule), Some(ConfigDebugDump::default()));
+        assert!(ConfigDebugDump::active(&disabled, rule).unwrap().enable);
+        ConfigDebugDump::set_enable(Some(rule), Some(false));
+        assert!(ConfigDebugDump::active(&Some(ConfigDebugDump::default()), rule).is_none());
+        ConfigDebugDump::set_enable(Some(rule), None);
+        assert!(ConfigDebugDump::active(&None, rule).is_none());
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/02 09:45:31
+
+use std::{
+    fmt::Display,
+    io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
+/// 流量复制的总开关, 可通过控制端`/duplicate?enable=false`紧急停止
+static DUPLICATE_ENABLE: AtomicBool = AtomicBool::new(true);
+/// 关闭总开关时通知所有进行中的复制请求中止
+static DUPLICATE_STOP: Notify = Notify::const_new();
+
+/// 压测用的流量复制
+/// 将每个请求额外复制`times`份发往同一组主上游, 返回的数据直接丢弃, 用真实的流量模型对后端做容量测试
+/// 与镜像不同, 镜像是将流量发往另外的后端, 此处的目标为当前location的上游
+///
+/// 配置格式为`times=3 rate=100`, rate为每秒最多复制的请求数, 默认为100, 超出的部分不再复制
+/// 带body的请求不做复制, 复制的请求会附带`X-Wmproxy-Duplicate: 1`头以便后端区分
+#[derive(Debug, Clone)]
+pub struct ConfigDuplicate {
+    /// 每个请求额外复制的份数
+    pub times: u32,
+    /// 每秒最多复制的请求数
+    pub rate: u32,
+    /// 当前秒的开始时间及已复制的数量, 克隆后共享
+    window: Arc<Mutex<(Instant, u32)>>,
+}
+
+impl ConfigDuplicate {
+    /// 单个请求最多复制的份数
+    pub const MAX_TIMES: u32 = 100;
+    /// 默认每秒最多复制的请求数
+    pub const DEFAULT_RATE: u32 = 100;
+
+    pub fn new(times: u32, rate: u32) -> Self {
+        Self {
+            times,
+            rate,
+            window: Arc::new(Mutex::new((Instant::now(), 0))),
+        }
+    }
+
+    pub fn is_enable() -> bool {
+        DUPLICATE_ENABLE.load(Ordering::Relaxed)
+    }
+
+    /// 关闭时同时中止所有进行中的复制请求
+    pub fn set_enable(enable: bool) {
+        DUPLICATE_ENABLE.store(enable, Ordering::Relaxed);
+        if !enable {
+            DUPLICATE_STOP.notify_waiters();
+        }
+    }
+
+    /// 等待总开关被关闭
+    pub async fn wait_stop() {
+        let notified = DUPLICATE_STOP.notified();
+        if !Self::is_enable() {
+            return;
+        }
+        notified.await
+    }
+
+    /// 获取本次请求可复制的份数, 受总开关及每秒速率限制
+    pub fn acquire(&self) -> u32 {
+        if !Self::is_enable() {
+            return 0;
+        }
+        let mut window = match self.window.lock() {
+            Ok(w) => w,
+            Err(_) => return 0,
+        };
+        if window.0.elapsed() >= Duration::from_secs(1) {
+            *window = (Instant::now(), 0);
+        }
+        let times = self.times.min(self.rate.saturating_sub(window.1));
+        window.1 += times;
+        times
+    }
+}
+
+impl PartialEq for ConfigDuplicate {
+    fn eq(&self, other: &Self) -> bool {
+        self.times == other.times && self.rate == other.rate
+    }
+}
+
+impl FromStr for ConfigDuplicate {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut times = None;
+        let mut rate = Self::DEFAULT_RATE;
+        for v in s.split_whitespace() {
+            let kv = v.split('=').collect::<Vec<&str>>();
+            let value = if kv.len() == 2 {
+                kv[1].trim_end_matches("r/s").parse::<u32>().ok()
+            } else {
+                None
+            };
+            match (kv[0], value) {
+                ("times", Some(v)) => times = Some(v),
+                ("rate", Some(v)) => rate = v,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("未知的duplicate配置:{}", v),
+                    ))
+                }
+            }
+        }
+        let times = times.unwrap_or(0);
+        if times == 0 || times > Self::MAX_TIMES || rate == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "duplicate的times需在1-100之间, 且rate需大于0",
+            ));
+        }
+        Ok(Self::new(times, rate))
+    }
+}
+
+impl Display for ConfigDuplicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("times={} rate={}", self.times, self.rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigDuplicate;
+
+    #[test]
+    fn rate_capped() {
+        let dup = "times=3 rate=10".parse::<ConfigDuplicate>().unwrap();
+        assert_eq!(format!("{}", dup), "times=3 rate=10");
+        assert!("times=0".parse::<ConfigDuplicate>().is_err());
+        assert!("times=3 rate=0".parse::<ConfigDuplicate>().is_err());
+
+        let shared = dup.clone();
+        assert_eq!(dup.acquire(), 3);
+        assert_eq!(shared.acquire(), 3);
+        assert_eq!(dup.acquire(), 3);
+        // 超过每秒的上限后只复制剩余的份数
+        assert_eq!(dup.acquire(), 1);
+        assert_eq!(dup.acquire(), 0);
+    }
+
+    #[tokio::test]
+    async fn stop_wakes_inflight() {
+        let mut waiter = Box::pin(ConfigDuplicate::wait_stop());
+        let wait = std::time::Duration::from_millis(20);
+        assert!(tokio::time::timeout(wait, &mut waiter).await.is_err());
+        // 直接发送通知, 不改变全局开关以免影响其它测试
+        super::DUPLICATE_STOP.notify_waiters();
+        assert!(tokio::time::timeout(wait, &mut waiter).await.is_ok());
+    }
+}
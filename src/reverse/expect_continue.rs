@@ -0,0 +1,451 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/05 10:18:42
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll, Waker},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc::{channel, Sender},
+};
+use webparse::{Binary, BinaryMut, Request};
+use wenmeng::Body;
+
+/// 返回给客户端的临时响应
+const CONTINUE_RESPONSE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// 每次读取body的大小
+const PUMP_BUFFER: usize = 16 * 1024;
+
+/// 客户端连接的读写, 在http处理请求期间由代理直接读写客户端连接
+trait ClientIo: Send {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>>;
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>>;
+    fn clone_box(&self) -> Box<dyn ClientIo>;
+}
+
+struct SharedIo<T>(Arc<Mutex<T>>);
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> ClientIo for SharedIo<T> {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.0.lock() {
+            Ok(mut io) => Pin::new(&mut *io).poll_read(cx, buf),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.0.lock() {
+            Ok(mut io) => Pin::new(&mut *io).poll_write(cx, buf),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn ClientIo> {
+        Box::new(SharedIo(self.0.clone()))
+    }
+}
+
+/// 待读取的body, 读取后发给上游的请求
+struct Pump {
+    sender: Sender<(bool, Binary)>,
+    left: usize,
+}
+
+#[derive(Default)]
+struct InnerNotify {
+    /// 客户端的连接
+    io: Option<Box<dyn ClientIo>>,
+    /// 等待发送`100 Continue`后读取body
+    pump: Option<Pump>,
+    /// 是否正在读取body, 此时http不能读取客户端连接
+    pumping: bool,
+    /// 已读取的body, 需重新交给http解析
+    replay: Vec<u8>,
+    /// 客户端连接读取时的唤醒
+    waker: Option<Waker>,
+    /// 是否等待上游的`100 Continue`
+    expecting: bool,
+}
+
+/// `Expect: 100-continue`的处理
+///
+/// http在处理请求期间不会读取客户端的body, 发送`100 Continue`后由代理读取body并转发给上游,
+/// 读取过的数据再交还给http解析, 保证连接上的数据完整
+/// 默认等待上游返回的`100 Continue`再转给客户端, 配置`expect_continue = "auto"`或者上游为https时,
+/// 由代理直接返回, 仅支持带`Content-Length`的请求
+#[derive(Clone, Default)]
+pub struct ContinueNotify {
+    inner: Arc<Mutex<InnerNotify>>,
+}
+
+impl ContinueNotify {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 是否为需要处理的请求, 仅处理HTTP/1.1
+    pub fn is_expect<T: webparse::Serialize>(req: &Request<T>) -> bool {
+        req.version() == webparse::Version::Http11
+            && req
+                .headers()
+                .get_str_value(&"Expect")
+                .map(|v| v.eq_ignore_ascii_case("100-continue"))
+                .unwrap_or(false)
+    }
+
+    /// 替换请求的body, 由代理读取客户端的body, relay为true时等待上游的`100 Continue`
+    pub fn prepare(&self, req: &mut Request<Body>, relay: bool) {
+        let len = req
+            .headers()
+            .get_str_value(&"Content-Length")
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        if len == 0 || req.headers().get_str_value(&"Transfer-Encoding").is_some() {
+            req.headers_mut().remove(&"Expect");
+            return;
+        }
+        let (sender, receiver) = channel(10);
+        let origin = std::mem::replace(
+            req.body_mut(),
+            Body::new(receiver, BinaryMut::new(), false),
+        );
+        // 原body的数据为代理交还的数据, 直接丢弃
+        tokio::spawn(Self::drain_body(origin));
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.pump = Some(Pump { sender, left: len });
+            inner.expecting = relay;
+        }
+        if !relay {
+            req.headers_mut().remove(&"Expect");
+            self.start();
+        }
+    }
+
+    async fn drain_body(mut body: Body) {
+        let mut data = vec![0u8; PUMP_BUFFER];
+        std::future::poll_fn(|cx| loop {
+            let mut buf = ReadBuf::new(&mut data);
+            match Pin::new(&mut body).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) if !buf.filled().is_empty() => continue,
+                // 无数据时Body同样返回Ready, 未结束时已注册唤醒
+                Poll::Ready(Ok(())) if !body.is_end() => return Poll::Pending,
+                _ => return Poll::Ready(()),
+            }
+        })
+        .await
+    }
+
+    /// 向客户端写入`100 Continue`并开始读取body
+    pub fn start(&self) {
+        let (io, pump) = match self.inner.lock() {
+            Ok(mut inner) => {
+                inner.expecting = false;
+                match (inner.io.as_ref().map(|io| io.clone_box()), inner.pump.take()) {
+                    (Some(io), Some(pump)) => {
+                        inner.pumping = true;
+                        (io, pump)
+                    }
+                    _ => return,
+                }
+            }
+            Err(_) => return,
+        };
+        let notify = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = notify.pump_body(io, pump).await {
+                log::trace!("读取100-continue的body失败:{:?}", e);
+            }
+            notify.finish();
+        });
+    }
+
+    /// 上游未返回`100 Continue`即返回最终响应, 客户端的body不再读取
+    pub fn cancel(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.expecting = false;
+            inner.pump = None;
+        }
+    }
+
+    /// 是否仍在等待发送`100 Continue`, 此时客户端的状态未知, 连接应关闭
+    pub fn is_waiting(&self) -> bool {
+        self.inner.lock().map(|i| i.pump.is_some()).unwrap_or(false)
+    }
+
+    fn is_expecting(&self) -> bool {
+        self.inner.lock().map(|i| i.expecting).unwrap_or(false)
+    }
+
+    async fn pump_body(&self, mut io: Box<dyn ClientIo>, mut pump: Pump) -> io::Result<()> {
+        let mut pos = 0;
+        while pos < CONTINUE_RESPONSE.len() {
+            let n = std::future::poll_fn(|cx| io.poll_write(cx, &CONTINUE_RESPONSE[pos..])).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            pos += n;
+        }
+        while pump.left > 0 {
+            let mut data = vec![0u8; pump.left.min(PUMP_BUFFER)];
+            let n = std::future::poll_fn(|cx| {
+                let mut buf = ReadBuf::new(&mut data);
+                let ready = io.poll_read(cx, &mut buf);
+                ready.map_ok(|_| buf.filled().len())
+            })
+            .await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            data.truncate(n);
+            pump.left -= n;
+            if let Ok(mut inner) = self.inner.lock() {
+                inner.replay.extend_from_slice(&data);
+            }
+            // 上游不再接收时仍需读取完, 保证连接上的数据完整
+            let _ = pump.sender.send((pump.left == 0, Binary::from(data))).await;
+        }
+        Ok(())
+    }
+
+    fn finish(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.pumping = false;
+            if let Some(waker) = inner.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// 客户端的连接, 代理读取过的body将在此交还给http解析
+pub struct ContinueStream<T> {
+    io: Arc<Mutex<T>>,
+    notify: ContinueNotify,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> ContinueStream<T> {
+    pub fn new(io: T, notify: ContinueNotify) -> Self {
+        let io = Arc::new(Mutex::new(io));
+        if let Ok(mut inner) = notify.inner.lock() {
+            inner.io = Some(Box::new(SharedIo(io.clone())));
+        }
+        Self { io, notify }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> ContinueStream<T> {
+    fn with_io<R>(&self, f: impl FnOnce(Pin<&mut T>) -> Poll<io::Result<R>>) -> Poll<io::Result<R>> {
+        match self.io.lock() {
+            Ok(mut io) => f(Pin::new(&mut *io)),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for ContinueStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Ok(mut inner) = self.notify.inner.lock() {
+            if !inner.replay.is_empty() {
+                let n = inner.replay.len().min(buf.remaining());
+                buf.put_slice(&inner.replay[..n]);
+                inner.replay.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+            // 代理读取body期间等待读取完毕
+            if inner.pumping {
+                inner.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        self.with_io(|io| io.poll_read(cx, buf))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ContinueStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.with_io(|io| io.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with_io(|io| io.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with_io(|io| io.poll_shutdown(cx))
+    }
+}
+
+/// 上游的连接, 等待期间读取到的`100 Continue`将转给客户端, 不再交由http解析
+pub struct UpstreamContinue<T> {
+    io: T,
+    notify: ContinueNotify,
+    /// 等待期间读取到的数据
+    head: Vec<u8>,
+    /// 已解析完毕待交给上层的数据
+    left: Vec<u8>,
+}
+
+impl<T> UpstreamContinue<T> {
+    pub fn new(io: T, notify: ContinueNotify) -> Self {
+        Self {
+            io,
+            notify,
+            head: vec![],
+            left: vec![],
+        }
+    }
+
+    /// 解析等待期间的数据, 返回是否已结束等待
+    fn deal_head(&mut self) -> bool {
+        let end = match self.head.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None => return false,
+        };
+        let is_continue = self.head.starts_with(b"HTTP/1.")
+            && (self.head.get(8..13) == Some(b" 100 ".as_slice())
+                || self.head.get(8..14) == Some(b" 100\r\n".as_slice()));
+        if is_continue {
+            self.notify.start();
+            self.head.drain(..end);
+        } else {
+            self.notify.cancel();
+        }
+        self.left = std::mem::take(&mut self.head);
+        true
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for UpstreamContinue<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.left.is_empty() && this.notify.is_expecting() {
+            let mut data = [0u8; 4096];
+            let mut read = ReadBuf::new(&mut data);
+            ready!(Pin::new(&mut this.io).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                this.notify.cancel();
+                this.left = std::mem::take(&mut this.head);
+                break;
+            }
+            this.head.extend_from_slice(read.filled());
+            this.deal_head();
+        }
+        if !this.left.is_empty() {
+            let n = this.left.len().min(buf.remaining());
+            buf.put_slice(&this.left[..n]);
+            this.left.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for UpstreamContinue<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::test_util::{connect_http, load_http, read_head, run_upstream};
+
+    const BODY_LEN: usize = 256 * 1024;
+
+    async fn upload(mode: &str, relay: bool) {
+        // 模拟上游, relay为true时支持`100 Continue`, 返回收到的body长度
+        let upstream = run_upstream(move |mut stream, head| async move {
+            assert_eq!(head.to_lowercase().contains("expect: 100-continue"), relay);
+            if relay {
+                stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await.unwrap();
+            }
+            let mut body = vec![0u8; BODY_LEN];
+            stream.read_exact(&mut body).await.unwrap();
+            let data = format!("{}", body.len());
+            let res = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", data.len(), data);
+            stream.write_all(res.as_bytes()).await.unwrap();
+        })
+        .await;
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            expect_continue = "{}"
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}"
+            "#,
+            mode, upstream
+        );
+        let mut client = connect_http(load_http(&config).convert_server_config()).await;
+
+        let req = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nExpect: 100-continue\r\n\r\n",
+            BODY_LEN
+        );
+        client.write_all(req.as_bytes()).await.unwrap();
+        // 收到`100 Continue`后才发送body
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("http/1.1 100 continue"));
+        client.write_all(&vec![b'a'; BODY_LEN]).await.unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("http/1.1 200"));
+        let len = format!("{}", BODY_LEN);
+        let mut body = vec![0u8; len.len()];
+        client.read_exact(&mut body).await.unwrap();
+        assert_eq!(body, len.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn relay_continue() {
+        upload("relay", true).await;
+    }
+
+    #[tokio::test]
+    async fn auto_continue() {
+        upload("auto", false).await;
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/17 15:20:44
+
+use std::{collections::HashMap, fmt::Display, io, str::FromStr, sync::RwLock, time::Duration};
+
+use lazy_static::lazy_static;
+use rand::Rng;
+use tokio::sync::mpsc::channel;
+use webparse::{Binary, BinaryMut, Response};
+use wenmeng::Body;
+
+use super::body_buffer::{read_body_data, READ_BUFFER};
+use crate::{ConfigDuration, ConfigSize};
+
+lazy_static! {
+    /// 控制端运行时修改的开关, key为location的rule, `*`表示所有的location
+    static ref FAULT_SWITCH: RwLock<HashMap<String, bool>> = RwLock::new(HashMap::new());
+}
+
+/// 注入故障时在返回中添加的头, 标明该返回经过了故障注入
+const FAULT_HEADER: &str = "X-Fault-Injected";
+
+/// 测试用的故障注入, 用于验证客户端的重试及超时, 切勿在正式环境中开启
+///
+/// 配置格式为`delay=500ms:10% abort=503:5% rate=64k:50% enable=false`, 概率不填时为100%
+/// * delay: 按概率在请求上游前增加的延迟
+/// * abort: 按概率直接返回的错误状态码, 不再请求上游
+/// * rate: 按概率限制返回body的速度, 单位为每秒的字节数
+/// * enable: 是否开启, 默认不开启, 可通过控制端`/fault-inject?rule=/api&enable=true`在运行时修改
+///
+/// 注入了故障的返回带有`X-Fault-Injected`头, 值为注入的故障类型
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConfigFault {
+    pub delay: Option<(Duration, f64)>,
+    pub abort: Option<(u16, f64)>,
+    pub rate: Option<(u64, f64)>,
+    pub enable: bool,
+}
+
+/// 按百分比的概率判定是否命中
+fn hit(percent: f64) -> bool {
+    percent >= 100.0 || (percent > 0.0 && rand::thread_rng().gen_bool(percent / 100.0))
+}
+
+/// 解析`value:percent%`的形式, 概率不填时为100%
+fn split_percent(value: &str) -> io::Result<(&str, f64)> {
+    let Some((value, percent)) = value.split_once(':') else {
+        return Ok((value, 100.0));
+    };
+    let percent = percent
+        .strip_suffix('%')
+        .and_then(|p| p.trim().parse::<f64>().ok())
+        .filter(|p| (0.0..=100.0).contains(p))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("错误的fault概率:{}", percent)))?;
+    Ok((value, percent))
+}
+
+impl ConfigFault {
+    /// 获取location当前生效的配置, 控制端的开关优先于配置, 未配置故障的location不做处理
+    pub fn active(config: &Option<ConfigFault>, rule: &str) -> Option<ConfigFault> {
+        let config = config.as_ref()?;
+        let switch = FAULT_SWITCH
+            .read()
+            .ok()
+            .and_then(|s| s.get(rule).or_else(|| s.get("*")).cloned());
+        match switch {
+            Some(true) => Some(config.clone()),
+            Some(false) => None,
+            None if config.enable => Some(config.clone()),
+            None => None,
+        }
+    }
+
+    /// 运行时修改开关, rule为None时作用于所有的location
+    pub fn set_enable(rule: Option<&str>, enable: Option<bool>) {
+        if let Ok(mut switch) = FAULT_SWITCH.write() {
+            let key = rule.unwrap_or("*").to_string();
+            log::warn!("故障注入的开关[{}]修改为{:?}", key, enable);
+            match enable {
+                Some(enable) => switch.insert(key, enable),
+                None => switch.remove(&key),
+            };
+        }
+    }
+
+    /// 控制端修改的开关列表
+    pub fn switch_list() -> HashMap<String, bool> {
+        FAULT_SWITCH.read().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    /// 请求上游前注入延迟或错误, 返回已注入的故障及直接返回的内容
+    pub async fn inject_request(&self) -> (Vec<&'static str>, Option<Response<Body>>) {
+        let mut injected = vec![];
+        if let Some((delay, percent)) = self.delay {
+            if hit(percent) {
+                injected.push("delay");
+                tokio::time::sleep(delay).await;
+            }
+        }
+        if let Some((status, percent)) = self.abort {
+            if hit(percent) {
+                injected.push("abort");
+                let res = Response::text()
+                    .status(status)
+                    .header(FAULT_HEADER, injected.join(","))
+                    .body("fault injected")
+                    .unwrap()
+                    .into_type();
+                return (injected, Some(res));
+            }
+        }
+        (injected, None)
+    }
+
+    /// 按概率限制返回body的速度, 并标记已注入的故障
+    pub fn inject_response(&self, mut injected: Vec<&'static str>, res: &mut Response<Body>) {
+        if let Some((rate, percent)) = self.rate {
+            let has_body = res.get_body_len() > 0 || res.headers().is_chunked();
+            if has_body && hit(percent) {
+                injected.push("rate");
+                Self::throttle(rate, res);
+            }
+        }
+        if !injected.is_empty() {
+            res.headers_mut().insert(FAULT_HEADER, injected.join(","));
+        }
+    }
+
+    /// 以每秒rate字节的速度转发返回的body
+    fn throttle(rate: u64, res: &mut Response<Body>) {
+        let (sender, receiver) = channel(10);
+        let mut body = std::mem::replace(
+            res.body_mut(),
+            Body::new(receiver, BinaryMut::new(), false),
+        );
+        tokio::spawn(async move {
+            // 每次发送约0.1秒的数据量
+            let mut data = vec![0u8; (rate as usize / 10).clamp(1, READ_BUFFER)];
+            loop {
+                let n = match read_body_data(&mut body, &mut data).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(_) => return,
+                };
+                if sender.send((false, Binary::from(data[..n].to_vec()))).await.is_err() {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_secs_f64(n as f64 / rate as f64)).await;
+            }
+            let _ = sender.send((true, Binary::new())).await;
+        });
+    }
+}
+
+impl FromStr for ConfigFault {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = ConfigFault::default();
+        for v in s.split_whitespace() {
+            let err = || io::Error::new(io::ErrorKind::InvalidInput, format!("错误的fault配置:{}", v));
+            let (key, value) = v.split_once('=').unwrap_or((v, ""));
+            match key {
+                "delay" => {
+                    let (value, percent) = split_percent(value)?;
+                    config.delay = Some((ConfigDuration::from_str(value)?.0, percent));
+                }
+                "abort" => {
+                    let (value, percent) = split_percent(value)?;
+                    let status = value
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|s| (400..600).contains(s))
+                        .ok_or_else(err)?;
+                    config.abort = Some((status, percent));
+                }
+                "rate" => {
+                    let (value, percent) = split_percent(value)?;
+                    let rate = ConfigSize::from_str(value)?.0;
+                    if rate == 0 {
+                        return Err(err());
+                    }
+                    config.rate = Some((rate, percent));
+                }
+                "enable" => config.enable = value != "false" && value != "0",
+                _ => return Err(err()),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl Display for ConfigFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut values = vec![];
+        if let Some((delay, percent)) = self.delay {
+            values.push(format!("delay={}:{}%", ConfigDuration::new(delay), percent));
+        }
+        if let Some((status, percent)) = self.abort {
+            values.push(format!("abort={}:{}%", status, percent));
+        }
+        if let Some((rate, percent)) = self.rate {
+            values.push(format!("rate={}:{}%", ConfigSize::new(rate), percent));
+        }
+        values.push(format!("enable={}", self.enable));
+        f.write_str(&values.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use webparse::{BinaryMut, Response};
+    use wenmeng::Body;
+
+    use super::ConfigFault;
+    use crate::reverse::body_buffer::read_body_data;
+
+    #[test]
+    fn parse_fault() {
+        let config = "delay=500ms:10% abort=503:5% rate=64k enable"
+            .parse::<ConfigFault>()
+            .unwrap();
+        assert_eq!(config.delay, Some((Duration::from_millis(500), 10.0)));
+        assert_eq!(config.abort, Some((503, 5.0)));
+        assert_eq!(config.rate, Some((64 * 1024, 100.0)));
+        assert!(config.enable);
+        assert_eq!(format!("{}", config).parse::<ConfigFault>().unwrap(), config);
+        assert!(!"abort=503".parse::<ConfigFault>().unwrap().enable);
+        for s in ["abort=200", "delay=1s:120%", "rate=0", "drop=1"] {
+            assert!(s.parse::<ConfigFault>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn runtime_switch() {
+        let config = Some("abort=503 enable=false".parse::<ConfigFault>().unwrap());
+        assert!(ConfigFault::active(&config, "/fault-a").is_none());
+        assert!(ConfigFault::active(&None, "/fault-a").is_none());
+        ConfigFault::set_enable(Some("/fault-a"), Some(true));
+        assert!(ConfigFault::active(&config, "/fault-a").is_some());
+        assert!(ConfigFault::active(&config, "/fault-b").is_none());
+        ConfigFault::set_enable(Some("/fault-a"), None);
+        assert!(ConfigFault::active(&config, "/fault-a").is_none());
+    }
+
+    #[tokio::test]
+    async fn inject_abort_and_rate() {
+        let config = "delay=50ms abort=503:0% rate=1k".parse::<ConfigFault>().unwrap();
+        let start = Instant::now();
+        let (injected, res) = config.inject_request().await;
+        assert!(res.is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let mut res = Response::builder()
+            .header("Content-Length", "300")
+            .body(Body::new_binary(BinaryMut::from(vec![b'a'; 300])))
+            .unwrap();
+        config.inject_response(injected, &mut res);
+        assert_eq!(
+            res.headers().get_str_value(&"X-Fault-Injected"),
+            Some("delay,rate".to_string())
+        );
+        let start = Instant::now();
+        let mut body = res.into_body();
+        let (mut len, mut data) = (0, [0u8; 1024]);
+        loop {
+            match read_body_data(&mut body, &mut data).await.unwrap() {
+                0 => break,
+                n => len += n,
+            }
+        }
+        assert_eq!(len, 300);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        let config = "abort=502".parse::<ConfigFault>().unwrap();
+        let res = config.inject_request().await.1.unwrap();
+        assert_eq!(res.status().as_u16(), 502);
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/09 10:16:40
+
+use webparse::{HeaderName, Method, Request, Response, Version};
+use wenmeng::Body;
+
+/// HTTP/2中禁止出现的连接相关的头
+const CONNECTION_HEADERS: [&str; 5] = [
+    "Transfer-Encoding",
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Upgrade",
+];
+
+/// 请求及返回的body长度(Content-Length/Transfer-Encoding)的规范化处理
+///
+/// 客户端与上游的HTTP版本可能不同, 转发时需按对端的版本重新确定body的长度,
+/// 同时拒绝长度定义有歧义的请求, 防止前后端解析不一致导致的请求走私
+pub struct Framing;
+
+impl Framing {
+    /// 校验请求的长度定义, 存在歧义时返回400并关闭连接
+    pub fn check_request<T>(req: &Request<T>) -> Option<Response<Body>>
+    where
+        T: webparse::Serialize,
+    {
+        match Self::check_headers(req) {
+            Ok(()) => None,
+            Err(reason) => {
+                log::warn!("拒绝长度定义有歧义的请求:{} {}", req.path(), reason);
+                Some(
+                    Response::text()
+                        .status(400)
+                        .header(HeaderName::CONNECTION, "close")
+                        .body(reason)
+                        .unwrap()
+                        .into_type(),
+                )
+            }
+        }
+    }
+
+    pub fn check_headers<T>(req: &Request<T>) -> Result<(), &'static str>
+    where
+        T: webparse::Serialize,
+    {
+        let headers = req.headers();
+        let length = headers.get_str_value(&HeaderName::CONTENT_LENGTH);
+        let encoding = headers.get_str_value(&HeaderName::TRANSFER_ENCODING);
+        if let Some(length) = &length {
+            let length = length.trim();
+            if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+                return Err("invalid content-length");
+            }
+        }
+        if let Some(encoding) = &encoding {
+            // CL.TE及TE.CL, 前后端各取其一时将解析出不同的请求
+            if length.is_some() {
+                return Err("both content-length and transfer-encoding");
+            }
+            if req.version() == Version::Http10 {
+                return Err("transfer-encoding in http/1.0");
+            }
+            // 最后的编码必须为chunked, 拒绝`xchunked`或`chunked, identity`等混淆的值
+            let last = encoding.rsplit(',').next().unwrap_or("").trim();
+            if !last.eq_ignore_ascii_case("chunked") {
+                return Err("invalid transfer-encoding");
+            }
+        }
+        Ok(())
+    }
+
+    /// 转发给上游前的处理, HTTP/2的请求移除连接相关的头
+    pub fn normalize_request(req: &mut Request<Body>) {
+        if !req.version().is_http2() {
+            return;
+        }
+        for name in CONNECTION_HEADERS {
+            req.headers_mut().remove(&name);
+        }
+        // HTTP/2中的TE头仅允许trailers
+        if let Some(te) = req.headers().get_str_value(&"TE") {
+            if !te.trim().eq_ignore_ascii_case("trailers") {
+                req.headers_mut().remove(&"TE");
+            }
+        }
+    }
+
+    /// 返回给客户端前按客户端的版本重新确定body的长度
+    pub fn normalize_response(version: Version, method: &Method, res: &mut Response<Body>) {
+        let headers = res.headers_mut();
+        // 两者同时存在时以Transfer-Encoding为准
+        if headers.is_chunked() {
+            headers.remove(&HeaderName::CONTENT_LENGTH);
+        }
+        if version.is_http2() {
+            for name in CONNECTION_HEADERS {
+                headers.remove(&name);
+            }
+            return;
+        }
+        if version == Version::Http10 {
+            // HTTP/1.0不支持chunked, 以关闭连接作为body的结束
+            if headers.is_chunked() {
+                headers.remove(&HeaderName::TRANSFER_ENCODING);
+                headers.insert(HeaderName::CONNECTION, "close");
+            }
+            return;
+        }
+        // 来自HTTP/2上游的返回可能未指定长度, 需以chunked返回给HTTP/1.1的客户端
+        let status = res.status().as_u16();
+        let no_body = *method == Method::Head || status < 200 || status == 204 || status == 304;
+        if no_body || res.body().is_end() {
+            return;
+        }
+        let headers = res.headers_mut();
+        if !headers.contains(&HeaderName::CONTENT_LENGTH) && !headers.is_chunked() {
+            headers.insert(HeaderName::TRANSFER_ENCODING, "chunked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use webparse::{Method, Response, Version};
+    use wenmeng::Body;
+
+    use super::Framing;
+    use crate::test_util::{connect_http, load_http, read_head};
+
+    const CONFIG: &str = r#"
+    [[server]]
+    bind_addr = "127.0.0.1:0"
+    bind_ssl = ""
+    up_name = "localhost"
+    [[server.location]]
+    rule = "/"
+    static_response = "ok"
+    "#;
+
+    async fn request(req: &str) -> String {
+        let mut client = connect_http(load_http(CONFIG).convert_server_config()).await;
+        client.write_all(req.as_bytes()).await.unwrap();
+        read_head(&mut client).await
+    }
+
+    #[tokio::test]
+    async fn reject_smuggling() {
+        // CL.TE
+        let head = request("POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nG").await;
+        assert!(head.starts_with("http/1.1 400"));
+        assert!(head.contains("connection: close"));
+        // TE.CL
+        let head = request("POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n5c\r\nGPOST / HTTP/1.1\r\n\r\n0\r\n\r\n").await;
+        assert!(head.starts_with("http/1.1 400"));
+        // 混淆的Transfer-Encoding
+        let head = request("POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: xchunked\r\n\r\n0\r\n\r\n").await;
+        assert!(head.starts_with("http/1.1 400"));
+        let head = request("POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: +3\r\n\r\nabc").await;
+        assert!(head.starts_with("http/1.1 400"));
+
+        // 正常的chunked请求
+        let head = request("POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n").await;
+        assert!(head.starts_with("http/1.1 200"));
+    }
+
+    #[test]
+    fn normalize_response() {
+        let build = || {
+            Response::builder()
+                .header("Transfer-Encoding", "chunked")
+                .header("Content-Length", "10")
+                .header("Connection", "keep-alive")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let mut res = build();
+        Framing::normalize_response(Version::Http2, &Method::Get, &mut res);
+        assert!(!res.headers().contains(&"Transfer-Encoding"));
+        assert!(!res.headers().contains(&"Content-Length"));
+        assert!(!res.headers().contains(&"Connection"));
+
+        let mut res = build();
+        Framing::normalize_response(Version::Http10, &Method::Get, &mut res);
+        assert!(!res.headers().contains(&"Transfer-Encoding"));
+        assert_eq!(res.headers().get_str_value(&"Connection"), Some("close".to_string()));
+
+        let mut res = build();
+        Framing::normalize_response(Version::Http11, &Method::Get, &mut res);
+        assert!(res.headers().is_chunked());
+        assert!(!res.headers().contains(&"Content-Length"));
+    }
+}
@@ -12,18 +12,16 @@
 
 use std::{
     collections::{HashMap, HashSet},
-    fs::File,
-    io::{self, BufReader},
     net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::Instant,
 };
 
-use crate::{data::LimitReqData, Helper, ProxyResult};
+use crate::{data::{CertData, HandshakeData, LimitReqData, ShutdownStream, TagData}, CertLoader, ConfigDuration, Helper, MetricsRegistry, ProxyResult, SelfSigned};
 use async_trait::async_trait;
 use console::Style;
 use rustls::{
     crypto::ring::sign::any_supported_type,
-    pki_types::{CertificateDer, PrivateKeyDer},
     server::ResolvesServerCertUsingSni,
     sign::CertifiedKey,
 };
@@ -31,21 +29,25 @@ use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::TcpListener,
+    net::{TcpListener, TcpStream},
     sync::mpsc::{Receiver, Sender},
 };
-use tokio_rustls::TlsAcceptor;
-use webparse::{Request, Response};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use webparse::{HeaderName, Request, Response, Version};
 use wenmeng::{
     Body, HttpTrait, Middleware, ProtError, ProtResult, RecvRequest, RecvResponse, Server,
+    TimeoutLayer,
 };
 
 use super::{
-    common::CommonConfig, limit_req::LimitReqZone, ws::ServerWsOperate, LimitReqMiddleware,
-    LocationConfig, ServerConfig, UpstreamConfig,
+    common::CommonConfig, limit_req::LimitReqZone, BodyBuffer, BodyPeek, ws::ServerWsOperate, ContinueNotify,
+    CacheLookup, ConfigDebugDump, ConfigFault, ConfigProxyCache, ContinueStream, ConfigRejectPage, DumpTimer, Framing, InternalRedirect, LimitReqMiddleware, LocationConfig, PipelineNotify, PipelineStream, ServerConfig, UpstreamConfig,
 };
 use async_recursion::async_recursion;
 
+/// detect_tls等待客户端发送首个字节的最长时间
+const DETECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
 struct Operate {
     inner: InnerHttpOper,
 }
@@ -53,7 +55,16 @@ struct Operate {
 #[async_trait]
 impl HttpTrait for Operate {
     async fn operate(&mut self, req: &mut RecvRequest) -> ProtResult<RecvResponse> {
-        HttpConfig::operate(req, &mut self.inner).await
+        let mut res = match self.inner.check_keepalive(req) {
+            Some(res) => res,
+            None => {
+                let mut res = HttpConfig::operate(req, &mut self.inner).await?;
+                self.inner.deal_keepalive(req, &mut res);
+                res
+            }
+        };
+        self.inner.deal_pipeline(req, &mut res);
+        Ok(res)
     }
 
     async fn middle_operate(
@@ -67,23 +78,161 @@ impl HttpTrait for Operate {
     }
 
     fn is_continue_next(&self) -> bool {
-        true
+        !self.inner.closing
     }
 }
 
 struct InnerHttpOper {
     pub servers: Vec<Arc<ServerConfig>>,
-    pub cache_sender:
-        HashMap<LocationConfig, (Sender<Request<Body>>, Receiver<ProtResult<Response<Body>>>)>,
+    pub cache_sender: HashMap<LocationConfig, CacheClient>,
+    /// 该连接的`100 Continue`处理
+    pub continue_notify: ContinueNotify,
+    /// 客户端TLS握手的JA3指纹, 以`{ja3}`提供给header及日志
+    pub ja3: Option<String>,
+    /// 客户端TLS握手中的SNI, 以`{ssl_sni}`提供给header及日志
+    pub sni: Option<String>,
+    /// 该连接已处理的请求数
+    pub requests: usize,
+    /// 返回当前请求后关闭该连接
+    pub closing: bool,
+    /// HTTP/1.x下一个请求的读取控制
+    pub pipeline: PipelineNotify,
+}
+
+/// 复用的上游连接
+struct CacheClient {
+    sender: Sender<Request<Body>>,
+    receiver: Receiver<ProtResult<Response<Body>>>,
+    /// 已处理的请求数
+    requests: usize,
+    /// 到达该时间后将淘汰该连接
+    retire_at: Option<Instant>,
+}
+
+impl CacheClient {
+    pub fn new(
+        sender: Sender<Request<Body>>,
+        receiver: Receiver<ProtResult<Response<Body>>>,
+        comm: &CommonConfig,
+    ) -> Self {
+        Self {
+            sender,
+            receiver,
+            requests: 1,
+            retire_at: comm.calc_keepalive_retire(),
+        }
+    }
+
+    /// 复用时的错误是否为上游已关闭该连接
+    pub fn is_closed_error(e: &ProtError) -> bool {
+        e.is_io() || matches!(e, ProtError::Extension("close by server"))
+    }
+
+    /// 是否达到最大请求数或者最长存活时间
+    pub fn is_retire(&self, comm: &CommonConfig) -> bool {
+        if let Some(max) = comm.keepalive_max_requests {
+            if self.requests >= max {
+                return true;
+            }
+        }
+        if let Some(retire) = &self.retire_at {
+            if &Instant::now() >= retire {
+                return true;
+            }
+        }
+        false
+    }
 }
 
 impl InnerHttpOper {
-    pub fn new(http: Vec<Arc<ServerConfig>>) -> Self {
+    pub fn new(
+        http: Vec<Arc<ServerConfig>>,
+        continue_notify: ContinueNotify,
+        pipeline: PipelineNotify,
+    ) -> Self {
         Self {
             servers: http,
             cache_sender: HashMap::new(),
+            continue_notify,
+            ja3: None,
+            sni: None,
+            requests: 0,
+            closing: false,
+            pipeline,
         }
     }
+
+    /// 超过keepalive_requests的请求不再处理, 返回后关闭连接, 客户端未遵循`Connection: close`时出现
+    fn check_keepalive(&mut self, req: &RecvRequest) -> Option<RecvResponse> {
+        if req.version().is_http2() {
+            return None;
+        }
+        self.requests += 1;
+        let max = self.servers[0].keepalive_requests?;
+        if self.requests <= max {
+            return None;
+        }
+        log::trace!("客户端连接的请求数超过keepalive_requests={}, 关闭连接", max);
+        self.closing = true;
+        Response::builder()
+            .status(503)
+            .header(HeaderName::CONNECTION, "close")
+            .header(HeaderName::CONTENT_LENGTH, "0")
+            .body(())
+            .ok()
+            .map(|r| r.into_type())
+    }
+
+    /// 按keepalive_timeout及keepalive_requests设置返回的`Connection`及`Keep-Alive`
+    fn deal_keepalive(&mut self, req: &RecvRequest, res: &mut RecvResponse) {
+        let keep_alive = match req.version() {
+            Version::Http10 => req.is_keep_alive(),
+            Version::Http11 => !req.headers().is_contains(&HeaderName::CONNECTION, b"close"),
+            _ => false,
+        };
+        if !keep_alive {
+            return;
+        }
+        let server = &self.servers[0];
+        let left = server.keepalive_requests.map(|max| max.saturating_sub(self.requests));
+        if left == Some(0) {
+            res.headers_mut().insert(HeaderName::CONNECTION, "close");
+            // 返回内容已完整时可立即关闭, 否则由客户端按`Connection: close`关闭
+            if res.body().is_end() {
+                self.closing = true;
+            }
+            return;
+        }
+        if res.headers().is_contains(&HeaderName::CONNECTION, b"close") {
+            return;
+        }
+        if let Some(timeout) = &server.keepalive_timeout {
+            let value = match left {
+                Some(left) => format!("timeout={}, max={}", Self::ceil_secs(timeout), left),
+                None => format!("timeout={}", Self::ceil_secs(timeout)),
+            };
+            res.headers_mut().insert("Keep-Alive", value);
+        }
+    }
+
+    /// 当前请求处理完毕后允许读取下一个请求, 后续数据不再处理时返回后关闭连接
+    fn deal_pipeline(&mut self, req: &RecvRequest, res: &mut RecvResponse) {
+        if req.version().is_http2() {
+            return;
+        }
+        if self.pipeline.is_held() {
+            res.headers_mut().insert(HeaderName::CONNECTION, "close");
+            if res.body().is_end() {
+                self.closing = true;
+            }
+        }
+        self.pipeline.finish(self.servers[0].pipelining, res);
+    }
+
+    /// Keep-Alive中的timeout以秒为单位, 不足1秒的向上取整
+    fn ceil_secs(timeout: &ConfigDuration) -> u64 {
+        (timeout.0.as_millis() as u64).div_ceil(1000)
+    }
 }
 
 #[serde_as]
@@ -118,6 +267,21 @@ impl HttpConfig {
             self.comm.log_format.insert("main".to_string(), "{d(%Y-%m-%d %H:%M:%S)} {client_ip} {l} {url} path:{path} query:{query} host:{host} status: {status} {up_status} referer: {referer} user_agent: {user_agent} cookie: {cookie}".to_string());
         }
         self.copy_to_child();
+        for s in &self.server {
+            if (s.ja3_allow.is_some() || s.ja3_deny.is_some()) && !cfg!(feature = "ja3") {
+                return Err(ProtError::Extension("ja3_allow|ja3_deny需要开启ja3特性"));
+            }
+            s.http2_settings()?;
+            for u in &s.upstream {
+                u.load_tls()?;
+                u.http2_settings()?;
+            }
+            for l in &s.location {
+                if !["on", "off", "passthrough"].contains(&&*l.ws_compression) {
+                    return Err(ProtError::Extension("ws_compression仅可配置on|off|passthrough"));
+                }
+            }
+        }
         for (k, zone) in &self.limit_req_zone {
             LimitReqData::cache(k.to_string(), zone.limit, zone.rate.nums, zone.rate.per)?;
         }
@@ -135,55 +299,10 @@ impl HttpConfig {
         }
     }
 
-    fn load_certs(path: &Option<String>) -> io::Result<Vec<CertificateDer<'static>>> {
-        if let Some(path) = path {
-            match File::open(&path) {
-                Ok(file) => {
-                    let mut reader = BufReader::new(file);
-                    let certs = rustls_pemfile::certs(&mut reader);
-                    Ok(certs.into_iter().collect::<Result<Vec<_>, _>>()?)
-                }
-                Err(e) => {
-                    log::warn!("加载公钥{}出错，错误内容:{:?}", path, e);
-                    return Err(e);
-                }
-            }
-        } else {
-            Err(io::Error::new(io::ErrorKind::Other, "unknow certs"))
-        }
-    }
-
-    fn load_keys(path: &Option<String>) -> io::Result<PrivateKeyDer<'static>> {
-        let mut keys = if let Some(path) = path {
-            match File::open(&path) {
-                Ok(file) => {
-                    let mut reader = BufReader::new(file);
-                    rustls_pemfile::rsa_private_keys(&mut reader).collect::<Result<Vec<_>, _>>()?
-                }
-                Err(e) => {
-                    log::warn!("加载私钥{}出错，错误内容:{:?}", path, e);
-                    return Err(e);
-                }
-            }
-        } else {
-            return Err(io::Error::new(io::ErrorKind::Other, "unknow keys"));
-        };
-
-        match keys.len() {
-            0 => Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("No RSA private key found"),
-            )),
-            1 => Ok(PrivateKeyDer::from(keys.remove(0))),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("More than one RSA private key found"),
-            )),
-        }
-    }
-
+    /// 绑定所有的监听, strict为false时跳过绑定失败的地址
     pub async fn bind(
         &mut self,
+        strict: bool,
     ) -> ProxyResult<(Option<TlsAcceptor>, Vec<bool>, Vec<TcpListener>)> {
         let mut listeners = vec![];
         let mut tlss = vec![];
@@ -193,11 +312,28 @@ impl HttpConfig {
         let mut one_key = None;
         let mut one_cert = None;
         let is_single = self.server.len() == 1;
-        for value in &self.server.clone() {
+        for (i, value) in self.server.clone().iter().enumerate() {
             let mut is_ssl = false;
-            if value.cert.is_some() && value.key.is_some() {
-                let key = Self::load_keys(&value.key)?;
-                let cert = Self::load_certs(&value.cert)?;
+            let is_auto = SelfSigned::is_auto(&value.cert);
+            let is_pkcs12 = value.cert.as_ref().map(|c| CertLoader::is_pkcs12(c)).unwrap_or(false);
+            if is_auto || (value.cert.is_some() && (value.key.is_some() || is_pkcs12)) {
+                let (cert, key) = if is_auto {
+                    let mut domains = vec![];
+                    if let Some(d) = &value.comm.domain {
+                        domains.push(d.clone());
+                    }
+                    if !value.up_name.is_empty() {
+                        domains.push(value.up_name.clone());
+                    }
+                    SelfSigned::generate(&domains)?
+                } else {
+                    CertLoader::load(
+                        value.cert.as_deref().unwrap_or_default(),
+                        value.key.as_deref(),
+                        &value.key_password,
+                    )?
+                };
+                CertData::register(&cert);
                 if is_single {
                     one_key = Some(key.clone_key());
                     one_cert = Some(cert.clone());
@@ -213,19 +349,24 @@ impl HttpConfig {
                 }
                 is_ssl = true;
             }
-            for v in &value.bind_addr.0 {
+            for (j, v) in value.bind_addr.0.iter().enumerate() {
                 if bind_addr_set.contains(&v) {
                     continue;
                 }
                 bind_addr_set.insert(v);
                 let url = format!("http://{}", v);
                 log::info!("HTTP服务：{}，提供http处理及转发功能。", Style::new().blink().green().apply_to(url));
-                let listener = Helper::bind(v).await?;
-                listeners.push(listener);
-                tlss.push(false);
+                if let Some(listener) = Helper::bind_listener(v, value.bind_addr.device(v), strict).await? {
+                    // 端口为0时记录系统分配的端口, 接收连接时按端口匹配server
+                    if v.port() == 0 {
+                        self.server[i].bind_addr.0[j] = listener.local_addr()?;
+                    }
+                    listeners.push(listener);
+                    tlss.push(false);
+                }
             }
 
-            for v in &value.bind_ssl.0 {
+            for (j, v) in value.bind_ssl.0.iter().enumerate() {
                 if bind_addr_set.contains(&v) {
                     continue;
                 }
@@ -235,9 +376,13 @@ impl HttpConfig {
                 }
                 let url = format!("https://{}", v);
                 log::info!("HTTPs服务：{}，提供https处理及转发功能。", Style::new().blink().green().apply_to(url));
-                let listener = Helper::bind(v).await?;
-                listeners.push(listener);
-                tlss.push(is_ssl);
+                if let Some(listener) = Helper::bind_listener(v, value.bind_ssl.device(v), strict).await? {
+                    if v.port() == 0 {
+                        self.server[i].bind_ssl.0[j] = listener.local_addr()?;
+                    }
+                    listeners.push(listener);
+                    tlss.push(is_ssl);
+                }
             }
         }
 
@@ -263,10 +408,7 @@ impl HttpConfig {
     async fn deal_match_location(
         req: &mut Request<Body>,
         // 缓存客户端请求
-        cache: &mut HashMap<
-            LocationConfig,
-            (Sender<Request<Body>>, Receiver<ProtResult<Response<Body>>>),
-        >,
+        cache: &mut HashMap<LocationConfig, CacheClient>,
         // 该Server的配置选项
         server: Arc<ServerConfig>,
         // 已处理的匹配路由
@@ -275,6 +417,9 @@ impl HttpConfig {
         try_deals: &mut HashSet<usize>,
     ) -> ProtResult<Response<Body>> {
         let path = req.path().clone();
+        if server.location.iter().any(|l| l.rule.is_match_body()) {
+            BodyPeek::peek_request(req, &server.body_peek.clone().unwrap_or_default()).await?;
+        }
         let mut l = None;
         let mut now = usize::MAX;
         for idx in 0..server.location.len() {
@@ -295,11 +440,59 @@ impl HttpConfig {
         }
 
         let l = l.unwrap();
+        let rule = l.rule.to_string();
+        // 测试用的故障注入, 未开启时不做处理
+        let fault = ConfigFault::active(&l.fault, &rule);
+        let mut injected = vec![];
+        if let Some(fault) = &fault {
+            let (list, res) = fault.inject_request().await;
+            if let Some(res) = res {
+                return Ok(res);
+            }
+            injected = list;
+        }
+        let mut res = match ConfigDebugDump::active(&l.debug_dump, &rule) {
+            Some(dump) => {
+                let prev = dump.start(&rule, req).await;
+                let mut res =
+                    Self::deal_location(req, cache, server.clone(), l, now, deals, try_deals).await;
+                dump.finish(&rule, req, &mut res, prev).await;
+                res
+            }
+            None => Self::deal_location(req, cache, server.clone(), l, now, deals, try_deals).await,
+        };
+        if let (Some(fault), Ok(res)) = (&fault, &mut res) {
+            fault.inject_response(injected, res);
+        }
+        if let (Some(transform), Ok(res)) = (&l.transform, &mut res) {
+            transform.apply(req, res).await;
+        }
+        res
+    }
+
+    /// 处理匹配到的location
+    async fn deal_location(
+        req: &mut Request<Body>,
+        cache: &mut HashMap<LocationConfig, CacheClient>,
+        server: Arc<ServerConfig>,
+        l: &LocationConfig,
+        now: usize,
+        deals: &mut HashSet<usize>,
+        try_deals: &mut HashSet<usize>,
+    ) -> ProtResult<Response<Body>> {
+        // 内部location不允许客户端直接访问
+        if l.internal && !InternalRedirect::is_internal(req) {
+            return Ok(Response::status404()
+                .body("unknow location to deal")
+                .unwrap()
+                .into_type());
+        }
         if let Some(limit_req) = &l.comm.limit_req {
-            if let Some(res) = LimitReqMiddleware::new(limit_req.clone())
+            if let Some(mut res) = LimitReqMiddleware::new(limit_req.clone())
                 .process_request(req)
                 .await?
             {
+                ConfigRejectPage::apply_option(&l.comm.reject_page, req, &mut res).await;
                 return Ok(res);
             }
         }
@@ -308,21 +501,34 @@ impl HttpConfig {
                 let ip = ip
                     .parse::<IpAddr>()
                     .map_err(|_| ProtError::Extension("client ip error"))?;
-                if let Some(allow) = &l.comm.allow_ip {
-                    if !allow.contains(&ip) {
-                        return Ok(Response::status503()
-                            .body("now allow ip")
-                            .unwrap()
-                            .into_type());
-                    }
-                }
-                if let Some(deny) = &l.comm.deny_ip {
-                    if deny.contains(&ip) {
-                        return Ok(Response::status503().body("deny ip").unwrap().into_type());
-                    }
+                let body = match (&l.comm.allow_ip, &l.comm.deny_ip) {
+                    (Some(allow), _) if !allow.contains(&ip) => Some("now allow ip"),
+                    (_, Some(deny)) if deny.contains(&ip) => Some("deny ip"),
+                    _ => None,
+                };
+                if let Some(body) = body {
+                    let mut res = Response::text().status(403).body(body).unwrap().into_type();
+                    ConfigRejectPage::apply_option(&l.comm.reject_page, req, &mut res).await;
+                    return Ok(res);
                 }
             }
         }
+        if let Some(res) = l.check_method(req) {
+            return Ok(res);
+        }
+
+        // 鉴权需要body时先处理`100 Continue`, 否则鉴权通过后才让客户端发送body
+        let auth_body = l.auth_request.as_ref().map(|a| a.body).unwrap_or(false);
+        if auth_body {
+            l.deal_expect_continue(req);
+        }
+        if let Some(res) = l.check_auth_request(req).await? {
+            return Ok(res);
+        }
+        if !auth_body {
+            l.deal_expect_continue(req);
+        }
+        DumpTimer::mark_request(req, "check");
 
         // 判定该try是否处理过, 防止死循环
         if !try_deals.contains(&now) && l.try_paths.is_some() {
@@ -351,70 +557,230 @@ impl HttpConfig {
                 .into_type());
         } else {
             deals.insert(now);
-            let clone = l.clone_only_hash();
-            if cache.contains_key(&clone) {
-                let mut cache_client = cache.remove(&clone).unwrap();
-                if !cache_client.0.is_closed() {
-                    let _send = cache_client.0.send(req.replace_clone(Body::empty())).await;
-                    match cache_client.1.recv().await {
-                        Some(res) => {
-                            if let Ok(r) = &res {
-                                log::trace!("复用连接收到Response {}", r.status());
-                                cache.insert(clone, cache_client);
+            match (&l.proxy_cache, &l.comm.proxy_url) {
+                (Some(proxy_cache), Some(_)) => Self::deal_proxy_cache(req, cache, l, proxy_cache).await,
+                _ => Self::deal_upstream(req, cache, l).await,
+            }
+        }
+    }
+
+    /// 先查找缓存, 过期时在后台更新, 上游失败时以过期的缓存返回
+    async fn deal_proxy_cache(
+        req: &mut Request<Body>,
+        cache: &mut HashMap<LocationConfig, CacheClient>,
+        l: &LocationConfig,
+        proxy_cache: &ConfigProxyCache,
+    ) -> ProtResult<Response<Body>> {
+        let key = match ConfigProxyCache::cache_key(req) {
+            Some(key) => key,
+            None => return Self::deal_upstream(req, cache, l).await,
+        };
+        match proxy_cache.lookup(&key) {
+            CacheLookup::Hit(res) => return Ok(res),
+            CacheLookup::Revalidate(res) => {
+                l.spawn_cache_revalidate(proxy_cache, req, key);
+                return Ok(res);
+            }
+            CacheLookup::Miss => {}
+        }
+        let res = Self::deal_upstream(req, cache, l).await;
+        proxy_cache.finish(&key, res).await
+    }
+
+    /// 经复用的连接或新建的连接请求上游
+    async fn deal_upstream(
+        req: &mut Request<Body>,
+        cache: &mut HashMap<LocationConfig, CacheClient>,
+        l: &LocationConfig,
+    ) -> ProtResult<Response<Body>> {
+        // 持有并发名额直到收到响应
+        let _permit = match l.acquire_concurrency().await {
+            Ok(permit) => permit,
+            Err(mut res) => {
+                ConfigRejectPage::apply_option(&l.comm.reject_page, req, &mut res).await;
+                return Ok(res);
+            }
+        };
+        DumpTimer::mark_request(req, "queue");
+        let clone = l.clone_only_hash();
+        if let Some(mut cache_client) = cache.remove(&clone) {
+            // 让出一次, 使连接的协程先处理上游在空闲时发送的关闭
+            tokio::task::yield_now().await;
+            if cache_client.sender.is_closed() {
+                log::trace!("复用连接已被上游关闭, 重新建立连接");
+            } else {
+                // 上游在空闲时关闭连接, 与发送请求同时发生时请求未被处理, 不带body的请求可安全重发
+                let replay = l.comm.proxy_reuse_retry != Some(false)
+                    && l.can_retry(req, BodyBuffer::is_need_buffer(req), &None);
+                let res = match cache_client.sender.send(req.replace_clone(Body::empty())).await {
+                    Err(_) => None,
+                    Ok(_) => match &l.proxy_read_header_timeout {
+                        Some(timeout) => {
+                            match tokio::time::timeout(timeout.0, cache_client.receiver.recv()).await {
+                                Ok(res) => res,
+                                // 未返回的响应无法再区分, 不再复用该连接
+                                Err(_) => return Ok(LocationConfig::header_timeout_response()),
                             }
-                            return res;
                         }
-                        None => {
-                            log::trace!("复用连接收到空消息,关闭复用连接");
-                            return Ok(Response::status503()
-                                .body("意外的服务端关闭连接")
-                                .unwrap()
-                                .into_type());
+                        None => cache_client.receiver.recv().await,
+                    },
+                };
+                match res {
+                    Some(Err(e)) if CacheClient::is_closed_error(&e) && replay => {
+                        log::trace!("复用连接发生错误:{:?}, 重新建立连接并重发请求", e);
+                    }
+                    Some(mut res) => {
+                        if let Ok(r) = &mut res {
+                            l.comm.hide_response_headers(r);
+                            log::trace!("复用连接收到Response {}", r.status());
+                            cache_client.requests += 1;
+                            // 超过最大请求数或存活时间的连接不再放回, 下次将重新建立连接
+                            if !cache_client.is_retire(&l.comm) {
+                                cache.insert(clone, cache_client);
+                            } else {
+                                log::trace!("复用连接已达到淘汰条件,关闭复用连接");
+                            }
                         }
+                        return res;
+                    }
+                    None if replay => {
+                        log::trace!("复用连接已被上游关闭, 重新建立连接并重发请求");
+                    }
+                    None => {
+                        log::trace!("复用连接收到空消息,关闭复用连接");
+                        return Ok(Response::status503()
+                            .body("意外的服务端关闭连接")
+                            .unwrap()
+                            .into_type());
                     }
                 }
-            } else {
-                let (res, sender, receiver) = l.deal_request(req).await?;
-                if sender.is_some() && receiver.is_some() {
-                    cache.insert(clone, (sender.unwrap(), receiver.unwrap()));
-                }
-                return Ok(res);
             }
         }
-
-        return Ok(Response::status404()
-            .body("unknow location to deal")
-            .unwrap()
-            .into_type());
+        let (res, sender, receiver) = l.deal_request(req).await?;
+        if let (Some(sender), Some(receiver)) = (sender, receiver) {
+            let client = CacheClient::new(sender, receiver, &l.comm);
+            if !client.is_retire(&l.comm) {
+                cache.insert(clone, client);
+            }
+        }
+        Ok(res)
     }
 
     async fn inner_operate_by_http(
         req: &mut Request<Body>,
-        cache: &mut HashMap<
-            LocationConfig,
-            (Sender<Request<Body>>, Receiver<ProtResult<Response<Body>>>),
-        >,
+        cache: &mut HashMap<LocationConfig, CacheClient>,
         servers: Vec<Arc<ServerConfig>>,
     ) -> ProtResult<Response<Body>> {
         let server_len = servers.len();
         let host = req.get_host().unwrap_or(String::new());
         // 不管有没有匹配, 都执行最后一个
-        for (index, s) in servers.iter().enumerate() {
-            if s.up_name == host || host.is_empty() || index == server_len - 1 {
-                return Self::deal_match_location(
-                    req,
-                    cache,
-                    s.clone(),
-                    &mut HashSet::new(),
-                    &mut HashSet::new(),
-                )
-                .await;
+        let server = servers
+            .iter()
+            .enumerate()
+            .find(|(index, s)| s.up_name == host || host.is_empty() || *index == server_len - 1)
+            .map(|(_, s)| s.clone());
+        let start = Instant::now();
+        let mut res = match Self::deal_server(req, cache, server.clone()).await {
+            Ok(res) => res,
+            Err(e) => Self::error_response(e),
+        };
+        MetricsRegistry::observe(MetricsRegistry::REQUEST_DURATION, start.elapsed());
+        // 所有的返回均在此处理Server等头, 包括本地生成的错误返回
+        match &server {
+            Some(s) => s.comm.rewrite_response_server(&mut res),
+            None => {
+                res.headers_mut().insert(HeaderName::SERVER, "wmproxy");
             }
         }
-        return Ok(Response::status503()
-            .body("unknow location")
-            .unwrap()
-            .into_type());
+        Ok(res)
+    }
+
+    async fn deal_server(
+        req: &mut Request<Body>,
+        cache: &mut HashMap<LocationConfig, CacheClient>,
+        server: Option<Arc<ServerConfig>>,
+    ) -> ProtResult<Response<Body>> {
+        if let Some(res) = Framing::check_request(req) {
+            return Ok(res);
+        }
+        let s = match server {
+            Some(s) => s,
+            None => {
+                return Ok(Response::status503()
+                    .body("unknow location")
+                    .unwrap()
+                    .into_type())
+            }
+        };
+        if let Some(res) = s.comm.check_header_limit(req) {
+            return Ok(res);
+        }
+        if let Some(tag) = &s.tag {
+            req.headers_mut().system_insert("{server_tag}".to_string(), tag.clone());
+            TagData::add_request(tag);
+        }
+        s.comm.rewrite_request_via(req);
+        s.comm.resolve_client_ip(req);
+        s.rewrite_sni_header(req);
+        if let Some(mut res) = s.deal_local_request(req) {
+            s.comm.compress_response(req, &mut res);
+            return Ok(res);
+        }
+        // 持有准入名额直到收到响应
+        let _admit = match &s.admission_state {
+            Some(admission) => match admission.admit(req) {
+                Ok(guard) => Some(guard),
+                Err(res) => {
+                    let mut res = *res;
+                    ConfigRejectPage::apply_option(&s.comm.reject_page, req, &mut res).await;
+                    return Ok(res);
+                }
+            },
+            None => None,
+        };
+        let mut res = Self::deal_match_location(
+            req,
+            cache,
+            s.clone(),
+            &mut HashSet::new(),
+            &mut HashSet::new(),
+        )
+        .await?;
+        // 上游指定了内部跳转, 由内部location返回内容
+        if let Some(redirect) = InternalRedirect::take(req, &mut res) {
+            log::trace!("内部跳转到:{}", redirect.uri);
+            redirect.apply(req);
+            let internal = Self::deal_match_location(
+                req,
+                cache,
+                s.clone(),
+                &mut HashSet::new(),
+                &mut HashSet::new(),
+            )
+            .await?;
+            res = InternalRedirect::merge(res, internal);
+        }
+        s.comm.compress_response(req, &mut res);
+        Framing::normalize_response(req.version(), req.method(), &mut res);
+        Ok(res)
+    }
+
+    /// 处理过程中发生错误时返回给客户端的内容
+    fn error_response(e: ProtError) -> Response<Body> {
+        log::trace!("处理HTTP服务发生错误: {:?}", e);
+        let (is_timeout, is_client) = e.is_read_timeout();
+        if is_timeout && !is_client {
+            Response::text()
+                .status(408)
+                .body("operate timeout")
+                .unwrap()
+                .into_type()
+        } else {
+            Response::status500()
+                .body("server inner error")
+                .unwrap()
+                .into_type()
+        }
     }
 
     async fn inner_operate(
@@ -422,6 +788,13 @@ impl HttpConfig {
         data: &mut InnerHttpOper,
     ) -> ProtResult<Response<Body>> {
         let servers = data.servers.clone();
+        req.extensions_mut().insert(data.continue_notify.clone());
+        if let Some(ja3) = &data.ja3 {
+            req.headers_mut().system_insert("{ja3}".to_string(), ja3.clone());
+        }
+        if let Some(sni) = &data.sni {
+            req.headers_mut().system_insert("{ssl_sni}".to_string(), sni.clone());
+        }
         return Self::inner_operate_by_http(req, &mut data.cache_sender, servers).await;
     }
 
@@ -430,26 +803,7 @@ impl HttpConfig {
         data: &mut InnerHttpOper,
     ) -> ProtResult<Response<Body>> {
         // body的内容可能重新解密又再重新再加过密, 后续可考虑直接做数据
-        match Self::inner_operate(req, data).await {
-            Ok(mut value) => {
-                value.headers_mut().insert("server", "wmproxy");
-                Ok(value)
-            }
-            Err(e) => {
-                log::trace!("处理HTTP服务发生错误: {:?}", e);
-                let (is_timeout, is_client) = e.is_read_timeout();
-                if is_timeout && !is_client {
-                    Ok(Response::text()
-                        .status(408)
-                        .body("operate timeout")?
-                        .into_type())
-                } else {
-                    Ok(Response::status500()
-                        .body("server inner error")?
-                        .into_type())
-                }
-            }
-        }
+        Self::inner_operate(req, data).await
     }
 
     pub fn convert_server_config(&self) -> Vec<Arc<ServerConfig>> {
@@ -465,19 +819,134 @@ impl HttpConfig {
         inbound: T,
         addr: SocketAddr,
     ) -> ProxyResult<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
+    {
+        Self::process_with_tls(servers, inbound, addr, None, None).await
+    }
+
+    /// 处理http端口接收的连接, 配置protocol_check时先检查请求行, 明显不是HTTP的连接直接关闭
+    pub async fn accept_http(
+        servers: Vec<Arc<ServerConfig>>,
+        conn: ShutdownStream<TcpStream>,
+        addr: SocketAddr,
+    ) {
+        let check = servers.iter().map(|s| s.protocol_check).max().unwrap_or_default();
+        if !check.peek(conn.get_ref()).await {
+            log::debug!("反向代理:{}发送的数据不是HTTP请求, 断开连接", addr);
+            return;
+        }
+        let _ = Self::process(servers, conn, addr).await;
+    }
+
+    /// 处理https端口接收的连接, 配置detect_tls时非TLS的连接按http处理
+    pub async fn accept_tls(
+        servers: Vec<Arc<ServerConfig>>,
+        conn: ShutdownStream<TcpStream>,
+        addr: SocketAddr,
+        tls_accept: TlsAcceptor,
+    ) {
+        if servers.iter().any(|s| s.detect_tls) {
+            match Self::peek_tls(conn.get_ref()).await {
+                Some(true) => {}
+                Some(false) => {
+                    Self::accept_http(servers, conn, addr).await;
+                    return;
+                }
+                None => return,
+            }
+        }
+        // 仅在有server用到时才计算指纹, 避免每个连接都等待完整的ClientHello
+        #[cfg(feature = "ja3")]
+        let ja3 = if servers.iter().any(|s| s.need_ja3()) {
+            let ja3 = super::Ja3::peek(conn.get_ref()).await;
+            if !super::Ja3::is_allow(ja3.as_ref(), &servers) {
+                log::info!("反向代理:{}的JA3指纹{:?}不被允许, 断开连接", addr, ja3.as_ref().map(|j| &j.hash));
+                return;
+            }
+            ja3.map(|j| j.hash)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "ja3"))]
+        let ja3 = None;
+        let stream = {
+            let _permit = match HandshakeData::acquire().await {
+                Some(permit) => permit,
+                None => {
+                    log::info!("反向代理:{}等待TLS握手的连接过多, 断开连接", addr);
+                    return;
+                }
+            };
+            tls_accept.accept(conn).await
+        };
+        if let Ok(stream) = stream {
+            let _ = Self::process_tls(servers, stream, addr, ja3).await;
+        }
+    }
+
+    /// 以peek的方式读取首个字节, TLS的握手记录以0x16开头, 连接关闭或超时返回None
+    async fn peek_tls(stream: &TcpStream) -> Option<bool> {
+        let mut buf = [0u8; 1];
+        match tokio::time::timeout(DETECT_TIMEOUT, stream.peek(&mut buf)).await {
+            Ok(Ok(1)) => Some(buf[0] == 0x16),
+            _ => None,
+        }
+    }
+
+    /// 处理TLS握手完成的连接, 按SNI选择server, ja3为TLS握手前计算的客户端指纹
+    pub async fn process_tls<T>(
+        servers: Vec<Arc<ServerConfig>>,
+        inbound: TlsStream<T>,
+        addr: SocketAddr,
+        ja3: Option<String>,
+    ) -> ProxyResult<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
+    {
+        let sni = inbound.get_ref().1.server_name().map(|s| s.to_string());
+        if let Some(name) = &sni {
+            if let Some(s) = servers.iter().find(|s| &s.up_name == name) {
+                return Self::process_with_tls(vec![s.clone()], inbound, addr, ja3, sni).await;
+            }
+        }
+        Self::process_with_tls(servers, inbound, addr, ja3, sni).await
+    }
+
+    async fn process_with_tls<T>(
+        servers: Vec<Arc<ServerConfig>>,
+        inbound: T,
+        addr: SocketAddr,
+        ja3: Option<String>,
+        sni: Option<String>,
+    ) -> ProxyResult<()>
     where
         T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
     {
         if servers.is_empty() {
             return Err(crate::ProxyError::Extension("unknown server"));
         }
-        let oper = InnerHttpOper::new(servers.clone());
+        let notify = ContinueNotify::new();
+        let pipeline = PipelineNotify::new();
+        let inbound = ContinueStream::new(PipelineStream::new(inbound, pipeline.clone()), notify.clone());
+        let mut oper = InnerHttpOper::new(servers.clone(), notify, pipeline);
+        oper.ja3 = ja3;
+        oper.sni = sni;
         tokio::spawn(async move {
-            let timeout = oper.servers[0].comm.build_client_timeout();
+            let mut timeout = oper.servers[0].comm.build_client_timeout();
+            if let Some(ka) = &oper.servers[0].keepalive_timeout {
+                timeout
+                    .get_or_insert_with(TimeoutLayer::new)
+                    .set_ka_timeout(Some(ka.0));
+            }
             let mut server = Server::builder()
                 .addr(addr)
                 .timeout_layer(timeout)
                 .stream(inbound);
+            // 同端口的多个server取第一个的HTTP/2参数
+            if let Some(settings) = oper.servers[0].http2_settings().ok().flatten() {
+                server.set_http2_builder(settings.builder());
+            }
             // 设置HTTP回调
             server.set_callback_http(Box::new(Operate { inner: oper }));
             // 设置websocket回调,客户端有可能升级到websocket协议
@@ -502,3 +971,636 @@ impl HttpConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::HttpConfig;
+    use crate::test_util::{connect_http, load_http, read_full_response, read_head, run_upstream};
+
+    async fn start(keepalive: &str) -> DuplexStream {
+        start_with(keepalive, "[[server.location]]\nrule = \"/\"\nstatic_response = \"ok\"").await
+    }
+
+    async fn start_with(server: &str, location: &str) -> DuplexStream {
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            {}
+            {}
+            "#,
+            server, location
+        );
+        connect_http(load_http(&config).convert_server_config()).await
+    }
+
+    /// 发送请求并读取完整的返回, 返回小写的返回头
+    async fn request(client: &mut DuplexStream) -> String {
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(client).await;
+        assert!(head.contains("content-length: 2"));
+        let mut body = [0u8; 2];
+        client.read_exact(&mut body).await.unwrap();
+        head
+    }
+
+    async fn wait_close(client: &mut DuplexStream, wait: Duration) -> bool {
+        let mut b = [0u8; 1];
+        matches!(tokio::time::timeout(wait, client.read(&mut b)).await, Ok(Ok(0)))
+    }
+
+    #[tokio::test]
+    async fn keepalive_requests() {
+        let mut client = start("keepalive_timeout = 5\nkeepalive_requests = 2").await;
+        let head = request(&mut client).await;
+        assert!(head.contains("keep-alive: timeout=5, max=1"));
+        let head = request(&mut client).await;
+        assert!(head.contains("connection: close"));
+        assert!(!head.contains("keep-alive:"));
+        assert!(wait_close(&mut client, Duration::from_secs(2)).await);
+    }
+
+    #[tokio::test]
+    async fn keepalive_idle_close() {
+        let mut client = start("keepalive_timeout = \"200ms\"").await;
+        let head = request(&mut client).await;
+        assert!(head.contains("keep-alive: timeout=1"));
+        assert!(wait_close(&mut client, Duration::from_secs(3)).await);
+
+        // 未配置时保持连接
+        let mut client = start("").await;
+        let head = request(&mut client).await;
+        assert!(!head.contains("keep-alive:"));
+        assert!(!wait_close(&mut client, Duration::from_millis(500)).await);
+        request(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn reuse_closed_upstream() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        // race为上游读取了复用连接上的请求后关闭, 即空闲关闭与发送请求同时发生
+        for race in [false, true] {
+            let accepts = Arc::new(AtomicUsize::new(0));
+            let count = accepts.clone();
+            let addr = run_upstream(move |mut stream, _| {
+                count.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .await;
+                    if race {
+                        let _ = stream.read(&mut [0u8; 1024]).await;
+                    }
+                }
+            })
+            .await;
+            let location = format!("[[server.location]]\nrule = \"/\"\nproxy_url = \"http://{}\"", addr);
+            let mut client = start_with("", &location).await;
+            request(&mut client).await;
+            if !race {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            // 复用的连接已被关闭, 重新建立连接后正常返回
+            let head = tokio::time::timeout(Duration::from_secs(2), request(&mut client))
+                .await
+                .unwrap();
+            assert!(head.starts_with("http/1.1 200"), "{}", head);
+            assert_eq!(accepts.load(Ordering::Relaxed), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn route_by_body() {
+        let mut client = start_with(
+            "body_peek = \"size=16 timeout=200ms\"",
+            r#"
+            [[server.location]]
+            rule = { path = "/", body = '^\{"service":"a"' }
+            static_response = "aa"
+            [[server.location]]
+            rule = "/"
+            static_response = "bb"
+            "#,
+        )
+        .await;
+        let post = |body: &'static str| {
+            format!(
+                "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        };
+        for (body, expect) in [
+            (r#"{"service":"a","data":"0123456789"}"#, "aa"),
+            (r#"{"service":"b"}"#, "bb"),
+        ] {
+            client.write_all(post(body).as_bytes()).await.unwrap();
+            let head = read_response(&mut client).await;
+            assert!(head.ends_with(expect), "{}", head);
+        }
+        // 无body的请求以空数据匹配
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_response(&mut client).await.ends_with("bb"));
+
+        // body未在超时内到达时以已读取的部分匹配
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 20\r\n\r\n{\"service\":\"a\"")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        client.write_all(b"}abcd").await.unwrap();
+        assert!(read_response(&mut client).await.ends_with("aa"));
+    }
+
+    #[tokio::test]
+    async fn health_path_before_location() {
+        let mut client = start_with(
+            "health_path = \"/healthz\"",
+            r#"
+            [[server.location]]
+            rule = "/"
+            static_response = "bb"
+            "#,
+        )
+        .await;
+        client
+            .write_all(b"GET /healthz?from=lb HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_response(&mut client).await.ends_with("ok"));
+        client
+            .write_all(b"GET /healthz/a HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_response(&mut client).await.ends_with("bb"));
+    }
+
+    #[tokio::test]
+    async fn options_asterisk() {
+        let location = "[[server.location]]\nrule = \"/\"\nstatic_response = \"bb\"";
+        let mut client = start_with("", location).await;
+        client
+            .write_all(b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(head.contains("allow: get, head, post, put, delete, options, patch"), "{}", head);
+
+        // 仅OPTIONS方法直接返回, 其它的按location处理
+        client
+            .write_all(b"OPTIONS / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let res = read_response(&mut client).await;
+        assert!(!res.contains("allow:") && res.ends_with("bb"), "{}", res);
+        client
+            .write_all(b"GET * HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut client).await;
+        assert!(!head.contains("allow:"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn server_header_on_local_errors() {
+        let server = "server_header = \"edge\"\nheader_limit = \"count=4\"";
+        let location = "[[server.location]]\nrule = \"/\"\nstatic_response = \"ok\"";
+
+        // 请求头超出限制的431
+        let mut client = start_with(server, location).await;
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nX-A: 1\r\nX-B: 1\r\nX-C: 1\r\nX-D: 1\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("http/1.1 431"), "{}", head);
+        assert!(head.contains("server: edge"), "{}", head);
+
+        // 长度定义有歧义的400
+        let mut client = start_with(server, location).await;
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("http/1.1 400"), "{}", head);
+        assert!(head.contains("server: edge"), "{}", head);
+
+        // 正常的返回
+        let mut client = start_with(server, location).await;
+        let head = request(&mut client).await;
+        assert!(head.contains("server: edge"), "{}", head);
+    }
+
+    /// 读取返回头及长度为2的body
+    async fn read_response(client: &mut DuplexStream) -> String {
+        let head = read_head(client).await;
+        let mut body = [0u8; 2];
+        client.read_exact(&mut body).await.unwrap();
+        format!("{}{}", head, String::from_utf8_lossy(&body))
+    }
+
+    #[tokio::test]
+    async fn sni_header() {
+        use std::sync::Arc;
+
+        use rustls::{pki_types::ServerName, ClientConfig, RootCertStore, ServerConfig};
+        use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+        // 以收到的请求头作为body返回的上游
+        let addr = run_upstream(|mut stream, head| async move {
+            let res = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", head.len());
+            stream.write_all(res.as_bytes()).await.unwrap();
+            stream.write_all(head.as_bytes()).await.unwrap();
+        })
+        .await;
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            sni_header = "X-Forwarded-SNI"
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}"
+            "#,
+            addr
+        );
+        let servers = load_http(&config).convert_server_config();
+
+        let (certs, key) = crate::SelfSigned::generate(&["tenant.example".to_string()]).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(certs[0].clone()).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .unwrap(),
+        ));
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        // 以IP连接时客户端不发送SNI
+        for (name, sni) in [("tenant.example", Some("tenant.example")), ("127.0.0.1", None)] {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let (acceptor, servers) = (acceptor.clone(), servers.clone());
+            tokio::spawn(async move {
+                let stream = acceptor.accept(server).await.unwrap();
+                HttpConfig::process_tls(servers, stream, "127.0.0.1:1234".parse().unwrap(), None)
+                    .await
+                    .unwrap();
+            });
+            let domain = ServerName::try_from(name).unwrap();
+            let mut stream = connector.connect(domain, client).await.unwrap();
+            // 与Host不同, 且客户端传入的同名头将被替换
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: other.example\r\nX-Forwarded-SNI: evil\r\n\r\n")
+                .await
+                .unwrap();
+            let mut head = vec![];
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut b = [0u8; 1];
+                stream.read_exact(&mut b).await.unwrap();
+                head.push(b[0]);
+            }
+            let head = String::from_utf8(head).unwrap().to_lowercase();
+            let len = head
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length: "))
+                .map(|l| l.trim().parse::<usize>().unwrap())
+                .unwrap();
+            let mut body = vec![0u8; len];
+            stream.read_exact(&mut body).await.unwrap();
+            let body = String::from_utf8(body).unwrap().to_lowercase();
+            assert!(body.contains("host: other.example"), "{}", body);
+            assert!(!body.contains("evil"), "{}", body);
+            match sni {
+                Some(sni) => assert!(body.contains(&format!("x-forwarded-sni: {}", sni)), "{}", body),
+                None => assert!(!body.contains("x-forwarded-sni"), "{}", body),
+            }
+        }
+    }
+
+    const PIPELINE_LOCATIONS: &str = r#"
+        [[server.location]]
+        rule = "/a"
+        static_response = "aa"
+        [[server.location]]
+        rule = "/b"
+        static_response = "bb"
+        [[server.location]]
+        rule = "/"
+        static_response = "cc"
+        "#;
+
+    #[tokio::test]
+    async fn pipelined_requests() {
+        for server in ["", "pipelining = \"on\""] {
+            let mut client = start_with(server, PIPELINE_LOCATIONS).await;
+            // 同一次写入多个请求, 依次处理且按顺序返回
+            client
+                .write_all(b"GET /a HTTP/1.1\r\nHost: localhost\r\n\r\nPOST /b HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhelloGET /c HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            for body in ["aa", "bb", "cc"] {
+                let res = tokio::time::timeout(Duration::from_secs(2), read_response(&mut client))
+                    .await
+                    .unwrap();
+                assert!(res.ends_with(body), "{} {}", server, res);
+            }
+            // 分多次写入的chunked请求
+            client
+                .write_all(b"POST /b HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel")
+                .await
+                .unwrap();
+            client
+                .write_all(b"lo\r\n0\r\n\r\nGET /a HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            for body in ["bb", "aa"] {
+                let res = tokio::time::timeout(Duration::from_secs(2), read_response(&mut client))
+                    .await
+                    .unwrap();
+                assert!(res.ends_with(body), "{} {}", server, res);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pipelined_smuggling() {
+        let cases: [&[u8]; 3] = [
+            // CL.TE, 返回400且不再处理后续的数据
+            b"POST /a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /b HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            // 不带body的方法中的chunked
+            b"GET /a HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\nGET /b HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            // `Connection: close`后的请求
+            b"GET /a HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\nGET /b HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        ];
+        for data in cases {
+            let mut client = start_with("", PIPELINE_LOCATIONS).await;
+            client.write_all(data).await.unwrap();
+            let head = tokio::time::timeout(Duration::from_secs(2), read_head(&mut client))
+                .await
+                .unwrap();
+            assert!(head.contains("connection: close"), "{}", head);
+            let len = head
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length: "))
+                .map(|l| l.trim().parse::<usize>().unwrap())
+                .unwrap();
+            let mut body = vec![0u8; len];
+            client.read_exact(&mut body).await.unwrap();
+            assert_ne!(body, b"bb");
+            assert!(wait_close(&mut client, Duration::from_secs(2)).await, "{}", head);
+        }
+    }
+
+    #[tokio::test]
+    async fn detect_tls_same_port() {
+        use std::sync::Arc;
+
+        use rustls::{pki_types::ServerName, ClientConfig, RootCertStore, ServerConfig};
+        use tokio::{
+            io::AsyncRead,
+            net::{TcpListener, TcpStream},
+        };
+        use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+        use crate::data::ShutdownStream;
+
+        async fn read_all<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+            let mut data = vec![];
+            let mut buf = [0u8; 1024];
+            let read = async {
+                while !data.ends_with(b"ok") {
+                    match stream.read(&mut buf).await {
+                        Ok(n) if n > 0 => data.extend_from_slice(&buf[..n]),
+                        _ => break,
+                    }
+                }
+            };
+            let _ = tokio::time::timeout(Duration::from_secs(2), read).await;
+            String::from_utf8_lossy(&data).to_string()
+        }
+
+        let (certs, key) = crate::SelfSigned::generate(&[]).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(certs[0].clone()).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .unwrap(),
+        ));
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        for detect in [true, false] {
+            let config = format!(
+                r#"
+                [[server]]
+                bind_addr = ""
+                bind_ssl = "127.0.0.1:0"
+                up_name = "localhost"
+                detect_tls = {}
+                [[server.location]]
+                rule = "/"
+                static_response = "ok"
+                "#,
+                detect
+            );
+            let servers = load_http(&config).convert_server_config();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                while let Ok((conn, addr)) = listener.accept().await {
+                    let conn = ShutdownStream::new(conn);
+                    tokio::spawn(HttpConfig::accept_tls(servers.clone(), conn, addr, acceptor.clone()));
+                }
+            });
+
+            // 同一端口的TLS连接
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let domain = ServerName::try_from("localhost").unwrap();
+            let mut tls = connector.connect(domain, stream).await.unwrap();
+            tls.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+            let res = read_all(&mut tls).await;
+            assert!(res.starts_with("HTTP/1.1 200") && res.ends_with("ok"), "{}", res);
+
+            // 明文的http连接, 未开启时按TLS握手处理失败
+            let mut plain = TcpStream::connect(addr).await.unwrap();
+            plain.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+            let res = read_all(&mut plain).await;
+            assert_eq!(res.starts_with("HTTP/1.1 200") && res.ends_with("ok"), detect, "{}", res);
+        }
+    }
+
+    /// 读取一个HTTP/2的帧, 返回类型、标志、流及内容
+    async fn read_frame(client: &mut DuplexStream) -> (u8, u8, u32, Vec<u8>) {
+        let mut head = [0u8; 9];
+        client.read_exact(&mut head).await.unwrap();
+        let mut payload = vec![0u8; u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        let stream_id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]);
+        (head[3], head[4], stream_id, payload)
+    }
+
+    fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        data.extend_from_slice(&[kind, flags]);
+        data.extend_from_slice(&stream_id.to_be_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[tokio::test]
+    async fn http2_settings() {
+        let mut client = start_with(
+            "http2_max_concurrent_streams = 1\nhttp2_initial_window_size = 1048576",
+            "[[server.location]]\nrule = \"/\"\nstatic_response = \"ok\"",
+        )
+        .await;
+        client.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").await.unwrap();
+        client.write_all(&frame(0x4, 0, 0, &[])).await.unwrap();
+        let (kind, _, _, payload) = read_frame(&mut client).await;
+        assert_eq!(kind, 0x4);
+        let values = payload
+            .chunks(6)
+            .map(|c| (u16::from_be_bytes([c[0], c[1]]), u32::from_be_bytes([c[2], c[3], c[4], c[5]])))
+            .collect::<Vec<_>>();
+        assert!(values.contains(&(0x3, 1)), "{:?}", values);
+        assert!(values.contains(&(0x4, 1048576)), "{:?}", values);
+        client.write_all(&frame(0x4, 0x1, 0, &[])).await.unwrap();
+
+        // GET / 及 :authority localhost, 同时打开的第二个流超出限制
+        let mut get = vec![0x82, 0x86, 0x84, 0x01, 9];
+        get.extend_from_slice(b"localhost");
+        let mut data = frame(0x1, 0x5, 1, &get);
+        data.extend(frame(0x1, 0x5, 3, &get));
+        client.write_all(&data).await.unwrap();
+        let (mut refused, mut answered) = (false, false);
+        while !refused || !answered {
+            match read_frame(&mut client).await {
+                (0x3, _, 3, code) => refused = code == 7u32.to_be_bytes(),
+                (0x1, _, 1, _) => answered = true,
+                (_, _, id, _) => assert_ne!(id, 3),
+            }
+        }
+        assert!(refused);
+
+        // 收到的数据过半后归还连接的窗口
+        let mut post = get.clone();
+        post[0] = 0x83;
+        let mut data = frame(0x1, 0x4, 5, &post);
+        for _ in 0..3 {
+            data.extend(frame(0x0, 0, 5, &[b'a'; 12000]));
+        }
+        client.write_all(&data).await.unwrap();
+        loop {
+            let (kind, _, id, payload) = read_frame(&mut client).await;
+            if kind == 0x8 && id == 0 {
+                assert_eq!(payload, 36000u32.to_be_bytes());
+                break;
+            }
+        }
+
+        // 超出通告长度的帧关闭连接
+        client.write_all(&frame(0x0, 0, 5, &[b'a'; 16385])).await.unwrap();
+        let mut rest = vec![];
+        let read = client.read_to_end(&mut rest);
+        assert!(tokio::time::timeout(Duration::from_secs(5), read).await.is_ok());
+
+        let config = r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            http2_max_frame_size = 1024
+        "#;
+        let mut http = toml::from_str::<HttpConfig>(config).unwrap();
+        assert!(http.after_load_option().is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_page() {
+        let dir = std::env::temp_dir().join(format!("wmproxy_reject_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let limit = dir.join("limit.html");
+        let deny = dir.join("deny.json");
+        std::fs::write(&limit, "<h1>slow down {client_ip}</h1>").unwrap();
+        std::fs::write(&deny, r#"{{"error":"denied","path":"{path}"}}"#).unwrap();
+        let config = format!(
+            r#"
+            [limit_req_zone]
+            reject_page = "{{client_ip}} limit=10m rate=1r/min"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            reject_page = "429={} 403={} retry_after=30s header=X-Reject:{{client_ip}}"
+            [[server.location]]
+            rule = "/limit"
+            limit_req = "zone=reject_page brust=0"
+            static_response = "ok"
+            [[server.location]]
+            rule = "/deny"
+            deny_ip = "127.0.0.1"
+            static_response = "ok"
+            "#,
+            limit.display(),
+            deny.display()
+        );
+        let mut client = connect_http(load_http(&config).convert_server_config()).await;
+
+        let mut limited = None;
+        for _ in 0..5 {
+            client
+                .write_all(b"GET /limit HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let (head, body) = read_full_response(&mut client).await;
+            if head.starts_with("http/1.1 429") {
+                limited = Some((head, body));
+                break;
+            }
+        }
+        let (head, body) = limited.unwrap();
+        assert!(head.contains("retry-after: 30"), "{}", head);
+        assert!(head.contains("x-reject: 127.0.0.1"), "{}", head);
+        assert!(head.contains("content-type: text/html"), "{}", head);
+        assert_eq!(body, "<h1>slow down 127.0.0.1</h1>");
+
+        client
+            .write_all(b"GET /deny HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, body) = read_full_response(&mut client).await;
+        assert!(head.starts_with("http/1.1 403"), "{}", head);
+        assert!(!head.contains("retry-after"), "{}", head);
+        assert!(head.contains("content-type: application/json"), "{}", head);
+        assert_eq!(body, r#"{"error":"denied","path":"/deny"}"#);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
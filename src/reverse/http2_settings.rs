@@ -0,0 +1,88 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/25 16:52:08
+
+use std::io;
+
+use wenmeng::Builder;
+
+/// HTTP/2连接的参数, 未配置的项保持wenmeng的默认值
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Http2Settings {
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: Option<u32>,
+    pub max_frame_size: Option<u32>,
+}
+
+impl Http2Settings {
+    /// 按RFC 9113的取值范围检查, 均未配置时返回None
+    pub fn new(
+        max_concurrent_streams: Option<u32>,
+        initial_window_size: Option<u32>,
+        max_frame_size: Option<u32>,
+    ) -> io::Result<Option<Self>> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        if max_concurrent_streams == Some(0) {
+            return invalid("http2_max_concurrent_streams不能为0".to_string());
+        }
+        if let Some(size) = initial_window_size.filter(|s| *s > i32::MAX as u32) {
+            return invalid(format!("http2_initial_window_size不能超过2147483647:{}", size));
+        }
+        if let Some(size) = max_frame_size.filter(|s| !(16384..=16777215).contains(s)) {
+            return invalid(format!("http2_max_frame_size需在16384到16777215之间:{}", size));
+        }
+        let settings = Self {
+            max_concurrent_streams,
+            initial_window_size,
+            max_frame_size,
+        };
+        Ok((settings != Self::default()).then_some(settings))
+    }
+
+    /// 生成连接使用的参数, 在SETTINGS帧中通告对端, 并按此限制接收的流数、数据及帧长度
+    pub fn builder(&self) -> Builder {
+        let mut builder = Builder::new();
+        if let Some(max) = self.max_concurrent_streams {
+            builder = builder.max_concurrent_streams(max);
+        }
+        if let Some(size) = self.initial_window_size {
+            builder = builder.initial_window_size(size);
+        }
+        if let Some(size) = self.max_frame_size {
+            builder = builder.max_frame_size(size);
+        }
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Http2Settings;
+
+    #[test]
+    fn check_range() {
+        assert_eq!(Http2Settings::new(None, None, None).unwrap(), None);
+        assert!(Http2Settings::new(Some(0), None, None).is_err());
+        assert!(Http2Settings::new(None, Some(1 << 31), None).is_err());
+        assert!(Http2Settings::new(None, None, Some(16383)).is_err());
+        assert!(Http2Settings::new(None, None, Some(1 << 24)).is_err());
+        assert!(Http2Settings::new(Some(1), Some(i32::MAX as u32), Some(16384)).unwrap().is_some());
+    }
+
+    #[test]
+    fn builder_settings() {
+        let settings = Http2Settings::new(Some(10), Some(1 << 20), None).unwrap().unwrap();
+        let builder = settings.builder();
+        assert_eq!(builder.settings.max_concurrent_streams(), Some(10));
+        assert_eq!(builder.settings.initial_window_size(), Some(1 << 20));
+        assert_eq!(builder.settings.max_frame_size(), None);
+    }
+}
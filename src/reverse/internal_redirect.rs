@@ -0,0 +1,156 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/08 09:12:36
+
+use webparse::{HeaderName, Request, Response};
+use wenmeng::Body;
+
+/// 上游通过该头指定内部的location, 由代理返回对应的内容
+pub const X_ACCEL_REDIRECT: &str = "X-Accel-Redirect";
+
+/// 内部跳转的标记, 存在时的请求才可访问`internal`的location
+#[derive(Debug, Clone)]
+pub struct InternalRedirect {
+    /// 上游返回的跳转地址
+    pub uri: String,
+}
+
+impl InternalRedirect {
+    /// 是否为内部跳转的请求
+    pub fn is_internal<T>(req: &Request<T>) -> bool
+    where
+        T: webparse::Serialize,
+    {
+        req.extensions().get::<InternalRedirect>().is_some()
+    }
+
+    /// 取出上游返回的跳转地址, 内部跳转的请求不再跳转, 防止循环
+    pub fn take(req: &Request<Body>, res: &mut Response<Body>) -> Option<Self> {
+        let uri = res.headers_mut().remove(&X_ACCEL_REDIRECT)?;
+        let uri = uri.to_string();
+        if Self::is_internal(req) || !uri.starts_with("/") {
+            return None;
+        }
+        Some(Self { uri })
+    }
+
+    /// 将请求改写为内部跳转的地址
+    pub fn apply(&self, req: &mut Request<Body>) {
+        let path = match self.uri.split_once('?') {
+            Some((path, _)) => path.to_string(),
+            None => self.uri.clone(),
+        };
+        req.set_path(path);
+        *req.body_mut() = Body::empty();
+        req.extensions_mut().insert(self.clone());
+    }
+
+    /// 以内部location的内容为body, 保留上游的状态码及返回头
+    pub fn merge(upstream: Response<Body>, mut res: Response<Body>) -> Response<Body> {
+        // 内部location未正常返回内容或为范围请求及缓存命中时以其为准
+        if res.status().as_u16() != 200 {
+            return res;
+        }
+        *res.status_mut() = upstream.status();
+        for (name, value) in upstream.headers().iter() {
+            let skip = [
+                HeaderName::CONTENT_LENGTH,
+                HeaderName::TRANSFER_ENCODING,
+                HeaderName::CONTENT_ENCODING,
+                HeaderName::CONTENT_RANGE,
+                HeaderName::CONNECTION,
+            ];
+            if skip.contains(name) {
+                continue;
+            }
+            res.headers_mut().insert(name.clone(), value.clone());
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::test_util::{connect_http, load_http, read_head, run_upstream};
+
+    async fn request(config: &str, path: &str) -> (String, String) {
+        let mut client = connect_http(load_http(config).convert_server_config()).await;
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        client.write_all(req.as_bytes()).await.unwrap();
+        let head = read_head(&mut client).await;
+        let len = head
+            .lines()
+            .find_map(|l| l.strip_prefix("content-length: "))
+            .map(|l| l.trim().parse::<usize>().unwrap());
+        let body = match len {
+            Some(len) => {
+                let mut body = vec![0u8; len];
+                client.read_exact(&mut body).await.unwrap();
+                body
+            }
+            // 文件服务以chunked返回, 读取到结束块为止
+            None => {
+                let mut body = vec![];
+                while !body.ends_with(b"0\r\n\r\n") {
+                    let mut b = [0u8; 1];
+                    assert_eq!(client.read(&mut b).await.unwrap(), 1);
+                    body.push(b[0]);
+                }
+                body
+            }
+        };
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn redirect_to_internal() {
+        let root = std::env::temp_dir().join(format!("wmproxy_internal_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("file.txt"), "file content").unwrap();
+        // 模拟鉴权后的上游, 返回内部跳转的地址
+        let upstream = run_upstream(|mut stream, _| async move {
+            let res = "HTTP/1.1 200 OK\r\nX-Accel-Redirect: /protected/file.txt\r\nContent-Disposition: attachment\r\nContent-Length: 7\r\n\r\nignored";
+            stream.write_all(res.as_bytes()).await.unwrap();
+        })
+        .await;
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            [[server.location]]
+            rule = "/protected"
+            internal = true
+            file_server = {{ root = "{}", prefix = "/protected" }}
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}"
+            "#,
+            root.to_string_lossy().replace('\\', "/"),
+            upstream
+        );
+
+        // 客户端直接访问内部location返回404
+        let (head, _) = request(&config, "/protected/file.txt").await;
+        assert!(head.starts_with("http/1.1 404"));
+
+        // 上游返回跳转后由文件服务返回内容, 并保留上游的返回头
+        let (head, body) = request(&config, "/download").await;
+        assert!(head.starts_with("http/1.1 200"));
+        assert!(head.contains("content-disposition: attachment"));
+        assert!(!head.contains("x-accel-redirect"));
+        assert!(body.contains("file content"));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
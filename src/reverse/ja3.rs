@@ -0,0 +1,446 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/14 10:21:36
+
+use std::{collections::HashSet, fmt::Display, io, str::FromStr, sync::Arc};
+
+use super::ServerConfig;
+
+/// 首次peek的缓冲大小, 多数ClientHello在此以内, 不足时再加倍扩大
+#[cfg(feature = "ja3")]
+const INIT_HELLO_SIZE: usize = 2048;
+/// ClientHello最多读取的字节数, 超出时放弃计算指纹
+#[cfg(feature = "ja3")]
+const MAX_HELLO_SIZE: usize = 16 * 1024;
+/// 等待ClientHello完整到达的最长时间
+#[cfg(feature = "ja3")]
+const PEEK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// 解析ClientHello的结果
+#[cfg(feature = "ja3")]
+#[derive(Debug, PartialEq, Eq)]
+enum HelloState {
+    /// 数据未完整, 需继续等待
+    Partial,
+    /// 非TLS握手或格式错误
+    Invalid,
+    Done(Ja3),
+}
+
+/// 客户端TLS握手的JA3指纹
+///
+/// 由ClientHello中的`版本,加密套件,扩展,椭圆曲线,椭圆曲线点格式`组成, 各项以`-`连接,
+/// 忽略GREASE值(RFC 8701), 哈希值为该字符串的md5
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ja3 {
+    pub text: String,
+    pub hash: String,
+    /// ClientHello中的SNI, 用于在握手前确定对应的server
+    pub server_name: Option<String>,
+}
+
+impl Ja3 {
+    /// 在TLS握手前以peek的方式读取ClientHello, 不消耗连接中的数据, 超时或非TLS的连接返回None
+    #[cfg(feature = "ja3")]
+    pub async fn peek(stream: &tokio::net::TcpStream) -> Option<Ja3> {
+        let mut buf = vec![0u8; INIT_HELLO_SIZE];
+        let mut last = 0;
+        let work = async {
+            loop {
+                let n = stream.peek(&mut buf).await.ok()?;
+                if n == 0 {
+                    return None;
+                }
+                match Self::parse(&buf[..n]) {
+                    HelloState::Done(ja3) => return Some(ja3),
+                    HelloState::Invalid => return None,
+                    HelloState::Partial if n == MAX_HELLO_SIZE => return None,
+                    HelloState::Partial if n == buf.len() => {
+                        buf.resize((n * 2).min(MAX_HELLO_SIZE), 0);
+                        continue;
+                    }
+                    HelloState::Partial => {}
+                }
+                // peek在有数据时立即返回, 无新数据时稍作等待
+                if n == last {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+                last = n;
+            }
+        };
+        tokio::time::timeout(PEEK_TIMEOUT, work).await.ok().flatten()
+    }
+
+    /// 该指纹是否允许连接, 优先使用SNI对应的server的配置, 未匹配时需满足所有server的配置
+    pub fn is_allow(ja3: Option<&Ja3>, servers: &[Arc<ServerConfig>]) -> bool {
+        let matched = ja3
+            .and_then(|j| j.server_name.as_ref())
+            .and_then(|name| servers.iter().find(|s| &s.up_name == name));
+        let check = |s: &Arc<ServerConfig>| {
+            let hash = ja3.map(|j| &*j.hash);
+            if let (Some(deny), Some(hash)) = (&s.ja3_deny, hash) {
+                if deny.contains(hash) {
+                    return false;
+                }
+            }
+            match &s.ja3_allow {
+                // 未能计算指纹时无法确认, 配置了白名单则拒绝
+                Some(allow) => hash.map(|h| allow.contains(h)).unwrap_or(false),
+                None => true,
+            }
+        };
+        match matched {
+            Some(s) => check(s),
+            None => servers.iter().all(check),
+        }
+    }
+
+    #[cfg(feature = "ja3")]
+    fn parse(data: &[u8]) -> HelloState {
+        // ClientHello可能分在多个record中, 先合并握手消息
+        let mut hs = vec![];
+        let mut offset = 0;
+        loop {
+            if hs.len() >= 4 {
+                let len = read_u24(&hs[1..4]);
+                if hs[0] != 1 {
+                    return HelloState::Invalid;
+                }
+                if hs.len() >= 4 + len {
+                    return match Self::parse_hello(&hs[4..4 + len]) {
+                        Some(ja3) => HelloState::Done(ja3),
+                        None => HelloState::Invalid,
+                    };
+                }
+            }
+            if data.len() < offset + 5 {
+                return HelloState::Partial;
+            }
+            if data[offset] != 0x16 || data[offset + 1] != 3 {
+                return HelloState::Invalid;
+            }
+            let len = read_u16(&data[offset + 3..]) as usize;
+            if data.len() < offset + 5 + len {
+                return HelloState::Partial;
+            }
+            hs.extend_from_slice(&data[offset + 5..offset + 5 + len]);
+            offset += 5 + len;
+        }
+    }
+
+    #[cfg(feature = "ja3")]
+    fn parse_hello(data: &[u8]) -> Option<Ja3> {
+        let mut reader = Reader { data, pos: 0 };
+        let version = reader.u16()?;
+        reader.skip(32)?;
+        let len = reader.u8()? as usize;
+        reader.skip(len)?;
+        let len = reader.u16()? as usize;
+        let ciphers = reader.take(len)?;
+        let len = reader.u8()? as usize;
+        reader.skip(len)?;
+
+        let mut extensions = vec![];
+        let mut curves = vec![];
+        let mut formats = vec![];
+        let mut server_name = None;
+        // 无扩展时ClientHello到此结束
+        if reader.pos < data.len() {
+            let len = reader.u16()? as usize;
+            let mut ext_reader = Reader { data: reader.take(len)?, pos: 0 };
+            while ext_reader.pos < ext_reader.data.len() {
+                let ext = ext_reader.u16()?;
+                let len = ext_reader.u16()? as usize;
+                let value = ext_reader.take(len)?;
+                if is_grease(ext) {
+                    continue;
+                }
+                extensions.push(ext);
+                match ext {
+                    0x0000 => server_name = parse_server_name(value),
+                    0x000a => {
+                        let mut r = Reader { data: value, pos: 0 };
+                        let len = r.u16()? as usize;
+                        curves = u16_list(r.take(len)?);
+                    }
+                    0x000b => {
+                        let mut r = Reader { data: value, pos: 0 };
+                        let len = r.u8()? as usize;
+                        formats = r.take(len)?.iter().map(|v| *v as u16).collect();
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let text = format!(
+            "{},{},{},{},{}",
+            version,
+            join(&u16_list(ciphers)),
+            join(&extensions),
+            join(&curves),
+            join(&formats)
+        );
+        let hash = format!("{:x}", md5::compute(text.as_bytes()));
+        Some(Ja3 {
+            text,
+            hash,
+            server_name,
+        })
+    }
+}
+
+#[cfg(feature = "ja3")]
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+#[cfg(feature = "ja3")]
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.pos + len > self.data.len() {
+            return None;
+        }
+        let v = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Some(v)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|v| v[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(read_u16)
+    }
+}
+
+#[cfg(feature = "ja3")]
+fn read_u16(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+#[cfg(feature = "ja3")]
+fn read_u24(data: &[u8]) -> usize {
+    ((data[0] as usize) << 16) | ((data[1] as usize) << 8) | data[2] as usize
+}
+
+/// GREASE的值为0x0a0a, 0x1a1a ... 0xfafa
+#[cfg(feature = "ja3")]
+fn is_grease(v: u16) -> bool {
+    v & 0x0f0f == 0x0a0a && v >> 8 == v & 0xff
+}
+
+#[cfg(feature = "ja3")]
+fn u16_list(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(read_u16)
+        .filter(|v| !is_grease(*v))
+        .collect()
+}
+
+#[cfg(feature = "ja3")]
+fn join(list: &[u16]) -> String {
+    list.iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// 取server_name扩展中的host_name
+#[cfg(feature = "ja3")]
+fn parse_server_name(data: &[u8]) -> Option<String> {
+    let mut r = Reader { data, pos: 0 };
+    let len = r.u16()? as usize;
+    let mut list = Reader { data: r.take(len)?, pos: 0 };
+    while list.pos < list.data.len() {
+        let kind = list.u8()?;
+        let len = list.u16()? as usize;
+        let name = list.take(len)?;
+        if kind == 0 {
+            return String::from_utf8(name.to_vec()).ok();
+        }
+    }
+    None
+}
+
+/// JA3指纹的列表, 以空格或`,`分隔的md5值, 如`e7d705a3286e19ea42f587b344ee6865 6734f37431670b3ab4292b8f60f29984`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigJa3Set {
+    set: HashSet<String>,
+    /// 原始的配置顺序, 用于输出
+    origin: Vec<String>,
+}
+
+impl ConfigJa3Set {
+    pub fn contains(&self, hash: &str) -> bool {
+        self.set.contains(hash)
+    }
+}
+
+impl FromStr for ConfigJa3Set {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut set = HashSet::new();
+        let mut origin = vec![];
+        for v in s.split(|c: char| c == ',' || c.is_whitespace()) {
+            if v.is_empty() {
+                continue;
+            }
+            if v.len() != 32 || !v.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("JA3指纹需为32位的md5值:{}", v),
+                ));
+            }
+            let v = v.to_ascii_lowercase();
+            if set.insert(v.clone()) {
+                origin.push(v);
+            }
+        }
+        if set.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "JA3指纹列表不能为空"));
+        }
+        Ok(Self { set, origin })
+    }
+}
+
+impl Display for ConfigJa3Set {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.origin.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{ConfigJa3Set, Ja3};
+    #[cfg(feature = "ja3")]
+    use super::HelloState;
+    use crate::{reverse::ServerConfig, WrapVecAddr};
+
+    /// 手工构造的ClientHello, 包含GREASE的加密套件/扩展/曲线
+    #[cfg(feature = "ja3")]
+    const CLIENT_HELLO: [&str; 4] = [
+        "1603010079010000750303000102030405060708090a0b0c0d0e0f1011121314",
+        "15161718191a1b1c1d1e1f00000a0a0a13011302c02bc02f010000421a1a0000",
+        "00000014001200000f7777772e6578616d706c652e636f6d000a000a00082a2a",
+        "001d00170018000b00020100001000050003026832002b00050403040303",
+    ];
+    const HELLO_JA3: &str = "771,4865-4866-49195-49199,0-10-11-16-43,29-23-24,0";
+    const HELLO_HASH: &str = "b323795c687b467fe9a70a9514e05285";
+
+    #[cfg(feature = "ja3")]
+    fn hello_bytes() -> Vec<u8> {
+        let hex = CLIENT_HELLO.concat();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[cfg(feature = "ja3")]
+    #[test]
+    fn parse_client_hello() {
+        let data = hello_bytes();
+        let ja3 = match Ja3::parse(&data) {
+            HelloState::Done(ja3) => ja3,
+            v => panic!("unexpected {:?}", v),
+        };
+        assert_eq!(ja3.text, HELLO_JA3);
+        assert_eq!(ja3.hash, HELLO_HASH);
+        assert_eq!(ja3.server_name.as_deref(), Some("www.example.com"));
+
+        assert_eq!(Ja3::parse(&data[..60]), HelloState::Partial);
+        assert_eq!(Ja3::parse(b"GET / HTTP/1.1\r\n"), HelloState::Invalid);
+
+        // 拆分成两个record
+        let hs = &data[5..];
+        let mut split = vec![0x16, 3, 1, 0, 20];
+        split.extend_from_slice(&hs[..20]);
+        split.extend_from_slice(&[0x16, 3, 1, 0, (hs.len() - 20) as u8]);
+        split.extend_from_slice(&hs[20..]);
+        assert_eq!(Ja3::parse(&split), HelloState::Done(ja3));
+    }
+
+    #[test]
+    fn allow_deny() {
+        let set = format!("{} ,0123456789ABCDEF0123456789abcdef", HELLO_HASH)
+            .parse::<ConfigJa3Set>()
+            .unwrap();
+        assert_eq!(
+            format!("{}", set),
+            format!("{} 0123456789abcdef0123456789abcdef", HELLO_HASH)
+        );
+        assert!("abc".parse::<ConfigJa3Set>().is_err());
+        assert!(" ".parse::<ConfigJa3Set>().is_err());
+
+        let ja3 = Ja3 {
+            text: HELLO_JA3.to_string(),
+            hash: HELLO_HASH.to_string(),
+            server_name: Some("www.example.com".to_string()),
+        };
+        let mut deny = ServerConfig::new_ssl(WrapVecAddr::empty());
+        deny.up_name = "www.example.com".to_string();
+        deny.ja3_deny = Some(set.clone());
+        let mut allow = ServerConfig::new_ssl(WrapVecAddr::empty());
+        allow.up_name = "api.example.com".to_string();
+        allow.ja3_allow = Some("0123456789abcdef0123456789abcdef".parse().unwrap());
+
+        // 按SNI匹配到的server处理
+        assert!(!Ja3::is_allow(Some(&ja3), &[Arc::new(deny.clone())]));
+        let mut other = ja3.clone();
+        other.server_name = Some("api.example.com".to_string());
+        other.hash = "0123456789abcdef0123456789abcdef".to_string();
+        assert!(Ja3::is_allow(Some(&other), &[Arc::new(deny.clone()), Arc::new(allow.clone())]));
+        // 无法计算指纹时, 配置了白名单则拒绝
+        assert!(!Ja3::is_allow(None, &[Arc::new(allow)]));
+        assert!(Ja3::is_allow(None, &[Arc::new(deny.clone())]));
+
+        // 仅在用到指纹的server上计算
+        assert!(deny.need_ja3());
+        let mut plain = ServerConfig::new_ssl(WrapVecAddr::empty());
+        assert!(!plain.need_ja3());
+        plain.headers.push("proxy X-Ja3 {ja3}".parse().unwrap());
+        assert!(plain.need_ja3());
+    }
+
+    #[cfg(feature = "ja3")]
+    #[tokio::test]
+    async fn peek_split_hello() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data = hello_bytes();
+        let send = data.clone();
+        tokio::spawn(async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(&send[..50]).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            client.write_all(&send[50..]).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let ja3 = Ja3::peek(&stream).await.unwrap();
+        assert_eq!(ja3.hash, HELLO_HASH);
+        // peek不消耗数据, 握手仍可读取完整的ClientHello
+        let mut buf = vec![0; data.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+    }
+}
@@ -76,7 +76,7 @@ impl Middleware for LimitReqMiddleware {
                 LimitResult::Ok => return Ok(None),
                 LimitResult::Refuse => {
                     return Ok(Some(
-                        Response::text().status(404).body("limit req")?.into_type(),
+                        Response::text().status(429).body("limit req")?.into_type(),
                     ));
                 }
                 LimitResult::Delay(delay) => {
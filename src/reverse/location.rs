@@ -10,17 +10,28 @@
 // -----
 // Created Date: 2023/10/18 02:31:52
 
-use std::{collections::HashMap, hash::Hash, net::SocketAddr};
+use std::{collections::HashMap, future::Future, hash::Hash, net::SocketAddr, sync::Arc, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
-use tokio::sync::mpsc::{Receiver, Sender};
-use webparse::{HeaderName, Request, Response, Scheme, Url};
-use wenmeng::{Body, Client, ProtError, ProtResult, RecvRequest};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::mpsc::{Receiver, Sender},
+};
+use webparse::{HeaderName, Method, Request, Response, Scheme, Url};
+use wenmeng::{Body, Client, MaybeHttpsStream, ProtError, ProtResult, RecvRequest, TimeoutLayer};
 
-use crate::{ConfigHeader, FileServer, HealthCheck, Helper, StaticResponse};
+use crate::{ConfigDscp, ConfigDuration, ConfigHeader, ConfigSize, ConfigUpstreamProxy, FileServer, HealthCheck, Helper, MethodSets, MetricsRegistry, StaticResponse};
 
-use super::{common::CommonConfig, ReverseHelper, TryPathsConfig, UpstreamConfig, Matcher, string_or_struct};
+use super::{common::CommonConfig, WsLimit, BodyBuffer, ConcurrencyLimit, ConfigDebugDump, ConfigFault, ConcurrencyPermit, Framing, SseBridge, BufferResult, ConfigAuthRequest, ConfigDuplicate, ConfigProxyCache, ConfigProxyHost, ConfigStatusMap, ConfigTransform, ConfigUpstreamTls, ConnectLimit, ConnectPermit, ContinueNotify, Http2Settings, UpstreamContinue, MultipartLimit, ReverseHelper, TryPathsConfig, UpstreamConfig, UpstreamFailure, Matcher, string_or_struct};
+
+/// 默认先读完body再返回的上游响应大小
+const DEFAULT_MIN_STREAM_SIZE: u64 = 16 * 1024;
+
+fn default_ws_compression() -> String {
+    "passthrough".to_string()
+}
 
 /// 负载均衡中的location匹配，将匹配合适的处理逻辑
 #[serde_as]
@@ -44,6 +55,21 @@ pub struct LocationConfig {
     #[serde(default)]
     pub is_ws: bool,
 
+    /// 内部location, 仅可由上游返回的`X-Accel-Redirect`访问, 客户端直接访问返回404
+    #[serde(default)]
+    pub internal: bool,
+
+    /// websocket的压缩(permessage-deflate)处理方式, 可选on|off|passthrough, 默认passthrough
+    /// passthrough将客户端的压缩协商转发给上游, off不压缩, on由代理与客户端协商压缩, 与上游之间不压缩
+    #[serde(default = "default_ws_compression")]
+    pub ws_compression: String,
+
+    /// 将上游的websocket桥接成SSE, 如`ws://127.0.0.1:8081/chat`
+    /// GET请求返回上游消息的事件流, POST请求将body作为消息发往上游
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub sse_bridge: Option<Url>,
+
     pub root: Option<String>,
     #[serde(default = "Vec::new")]
     pub upstream: Vec<UpstreamConfig>,
@@ -51,6 +77,83 @@ pub struct LocationConfig {
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub try_paths: Option<TryPathsConfig>,
 
+    /// 压测用的流量复制, 如`times=3 rate=100`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub duplicate: Option<ConfigDuplicate>,
+
+    /// 允许的请求方法, 如`GET HEAD POST`, 其它方法将返回405
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub allowed_methods: Option<MethodSets>,
+    /// 禁止的请求方法, 如`TRACE TRACK`, 将返回405
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub denied_methods: Option<MethodSets>,
+
+    /// 外部鉴权的子请求, 如`url=http://127.0.0.1:8000/auth copy=X-User cache=30s`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub auth_request: Option<ConfigAuthRequest>,
+
+    /// 调试用的请求及返回的完整输出, 如`body=1k redact=Authorization,Cookie`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub debug_dump: Option<ConfigDebugDump>,
+
+    /// 测试用的故障注入, 如`delay=500ms:10% abort=503:5% rate=64k:50%`, 默认不开启
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub fault: Option<ConfigFault>,
+
+    /// 上游返回状态码的改写, 如`500,502=503:html/maintain.html 401=404`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub status_map: Option<ConfigStatusMap>,
+
+    /// 返回内容的转换, 如`minify_css max_size=1m`, 转换器需先注册, 范围请求及已压缩的返回不做转换
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub transform: Option<ConfigTransform>,
+
+    /// 缓存上游的返回, 上游失败时以过期的缓存返回, 如`valid=5min stale_if_error=1h stale_while_revalidate=30s`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub proxy_cache: Option<ConfigProxyCache>,
+
+    /// 发往上游的Host头, 可选preserve|upstream|固定的值, 默认保留客户端的Host
+    /// 上游为https时SNI与发往上游的Host一致
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub proxy_set_host: Option<ConfigProxyHost>,
+
+    /// 最大的并发请求数, 超过时排队等待
+    pub max_concurrent_requests: Option<usize>,
+    /// 最大的排队数, 排队已满返回503
+    #[serde(default)]
+    pub queue_len: usize,
+    /// 排队已满返回503时的`Retry-After`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub retry_after: Option<ConfigDuration>,
+    #[serde(skip)]
+    pub concurrency: Option<Arc<ConcurrencyLimit>>,
+
+    /// 该location最大的websocket连接数, 超过时升级请求返回503, 与max_concurrent_requests相互独立
+    #[serde(default)]
+    pub ws_max_connections: Option<usize>,
+    #[serde(skip)]
+    pub ws_limit: Option<Arc<WsLimit>>,
+
+    /// 发送请求后等待上游返回响应头的超时时间, 超时返回504, 不影响body的读取超时
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub proxy_read_header_timeout: Option<ConfigDuration>,
+    /// 上游返回的Content-Length不超过该值时先读完body再返回给客户端, 尽早释放上游连接,
+    /// 超过或未知长度时按流转发, 默认`16k`, 配置为`0`时全部按流转发
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub proxy_min_stream_size: Option<ConfigSize>,
+
     #[serde(flatten)]
     #[serde(default = "CommonConfig::new")]
     pub comm: CommonConfig,
@@ -87,9 +190,30 @@ impl LocationConfig {
             method: None,
             up_name: None,
             is_ws: false,
+            internal: false,
+            ws_compression: default_ws_compression(),
+            sse_bridge: None,
             root: None,
             upstream: vec![],
             try_paths: None,
+            duplicate: None,
+            allowed_methods: None,
+            denied_methods: None,
+            auth_request: None,
+            debug_dump: None,
+            fault: None,
+            status_map: None,
+            transform: None,
+            proxy_cache: None,
+            proxy_set_host: None,
+            max_concurrent_requests: None,
+            queue_len: 0,
+            retry_after: None,
+            concurrency: None,
+            ws_max_connections: None,
+            ws_limit: None,
+            proxy_read_header_timeout: None,
+            proxy_min_stream_size: None,
             comm: CommonConfig::new(),
         }
     }
@@ -99,17 +223,152 @@ impl LocationConfig {
             method: self.method.clone(),
             up_name: self.up_name.clone(),
             is_ws: self.is_ws,
+            internal: self.internal,
+            ws_compression: self.ws_compression.clone(),
+            sse_bridge: None,
             file_server: None,
             static_response: None,
             headers: vec![],
             try_paths: None,
+            duplicate: None,
+            allowed_methods: None,
+            denied_methods: None,
+            auth_request: None,
+            debug_dump: None,
+            fault: None,
+            status_map: None,
+            transform: None,
+            proxy_cache: None,
+            proxy_set_host: None,
+            max_concurrent_requests: None,
+            queue_len: 0,
+            retry_after: None,
+            concurrency: None,
+            ws_max_connections: None,
+            ws_limit: None,
+            proxy_read_header_timeout: None,
+            proxy_min_stream_size: None,
             root: None,
             upstream: vec![],
             comm: CommonConfig::new(),
         }
     }
 
+    /// 校验请求方法是否允许, 不允许时返回带`Allow`头的405
+    pub fn check_method<T: webparse::Serialize>(&self, req: &Request<T>) -> Option<Response<Body>> {
+        if self.allowed_methods.is_none() && self.denied_methods.is_none() {
+            return None;
+        }
+        let method = req.method();
+        let allowed = self
+            .allowed_methods
+            .as_ref()
+            .map(|a| a.contains(method))
+            .unwrap_or(true);
+        let denied = self
+            .denied_methods
+            .as_ref()
+            .map(|d| d.contains(method))
+            .unwrap_or(false);
+        if allowed && !denied {
+            return None;
+        }
+        let base = match &self.allowed_methods {
+            Some(a) => a.0.clone(),
+            None => MethodSets::COMMON.iter().map(|m| m.to_string()).collect(),
+        };
+        let list = base
+            .into_iter()
+            .filter(|m| {
+                !self
+                    .denied_methods
+                    .as_ref()
+                    .map(|d| d.contains_str(m))
+                    .unwrap_or(false)
+            })
+            .collect::<Vec<String>>();
+        Some(
+            Response::text()
+                .status(405)
+                .header(HeaderName::ALLOW, MethodSets(list).to_string())
+                .body("Method Not Allowed")
+                .unwrap()
+                .into_type(),
+        )
+    }
+
+    /// 处理`Expect: 100-continue`, 可转发时等待上游的`100 Continue`, 否则由代理直接返回
+    pub fn deal_expect_continue(&self, req: &mut Request<Body>) {
+        let url = match &self.comm.proxy_url {
+            Some(url) => url,
+            None => return,
+        };
+        if !ContinueNotify::is_expect(req) {
+            return;
+        }
+        let notify = match req.extensions().get::<ContinueNotify>() {
+            Some(notify) => notify.clone(),
+            None => return,
+        };
+        // https的上游无法在明文中识别临时响应, 由代理直接返回
+        let is_http = url.scheme.is_http() || (url.scheme == Scheme::None && req.scheme().is_http());
+        // 预读body时需先读取body才请求上游, 由代理直接返回
+        let relay = self.comm.expect_continue.as_deref() != Some("auto")
+            && is_http
+            && self.comm.body_buffer.is_none();
+        notify.prepare(req, relay);
+    }
+
+    /// 向外部鉴权服务校验请求, 未通过时返回需直接返回客户端的响应
+    pub async fn check_auth_request(&self, req: &mut Request<Body>) -> ProtResult<Option<Response<Body>>> {
+        match &self.auth_request {
+            Some(auth) => {
+                let mut res = auth
+                    .check(req, &self.comm.body_buffer, self.comm.build_proxy_timeout())
+                    .await?;
+                // 客户端未发送的body无法再读取, 关闭该连接
+                if let Some(res) = &mut res {
+                    if ContinueNotify::is_expect(req) {
+                        res.headers_mut().insert(HeaderName::CONNECTION, "close");
+                    }
+                }
+                Ok(res)
+            }
+            None => Ok(None),
+        }
+    }
+
     /// 当本地限制方法时,优先匹配方法,在进行路径的匹配
+    /// 根据配置创建并发及websocket连接数限制, 重载配置时将重新创建
+    pub fn init_concurrency(&mut self) {
+        self.concurrency = self
+            .max_concurrent_requests
+            .map(|max| Arc::new(ConcurrencyLimit::new(max, self.queue_len)));
+        self.ws_limit = self.ws_max_connections.map(|max| Arc::new(WsLimit::new(max)));
+    }
+
+    /// 获取并发名额, 处理完请求前需持有, 排队已满时返回503
+    pub async fn acquire_concurrency(&self) -> Result<Option<ConcurrencyPermit>, Response<Body>> {
+        let limit = match &self.concurrency {
+            Some(limit) => limit,
+            None => return Ok(None),
+        };
+        match limit.acquire().await {
+            Some(permit) => Ok(Some(permit)),
+            None => {
+                let mut res = Response::status503()
+                    .body("too many concurrent requests")
+                    .unwrap()
+                    .into_type();
+                if let Some(retry) = &self.retry_after {
+                    res.headers_mut()
+                        .insert("Retry-After", retry.0.as_secs().to_string());
+                }
+                Err(res)
+            }
+        }
+    }
+
     pub fn is_match_rule(&self, path: &String, req: &RecvRequest) -> bool {
         match self.rule.is_match_rule(path, req) {
             Err(_) => false,
@@ -118,22 +377,155 @@ impl LocationConfig {
         
     }
 
-    async fn deal_client(
+    /// 等待上游返回响应头超时时的504
+    pub fn header_timeout_response() -> Response<Body> {
+        Response::text()
+            .status(504)
+            .body("Gateway Timeout")
+            .unwrap()
+            .into_type()
+    }
+
+    async fn deal_client<T>(
         req: &mut Request<Body>,
-        client: Client,
+        client: Client<T>,
+        header_timeout: Option<ConfigDuration>,
     ) -> ProtResult<(
         Response<Body>,
         Option<Sender<Request<Body>>>,
         Option<Receiver<ProtResult<Response<Body>>>>,
-    )> {
+    )>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         println!("处理客户端!!!!");
         let (mut recv, sender) = client.send2(req.replace_clone(Body::empty())).await?;
-        match recv.recv().await {
+        let res = match header_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout.0, recv.recv()).await {
+                Ok(res) => res,
+                Err(_) => {
+                    log::trace!("等待上游响应头超过{}, 返回504", timeout);
+                    return Ok((Self::header_timeout_response(), None, None));
+                }
+            },
+            None => recv.recv().await,
+        };
+        match res {
             Some(res) => Ok((res?, Some(sender), Some(recv))),
             None => Err(ProtError::Extension("already close by other")),
         }
     }
 
+    /// 按配置复制请求发往主上游, 返回的数据直接丢弃
+    fn spawn_duplicate(
+        &self,
+        duplicate: &ConfigDuplicate,
+        req: &Request<Body>,
+        url: &Url,
+        domain: &str,
+        local_bind: &Option<String>,
+        buffer: &Option<BodyBuffer>,
+    ) {
+        // 带body的请求需要缓存数据才能复制
+        if buffer.is_none() && (req.get_body_len() > 0 || req.headers().is_chunked()) {
+            return;
+        }
+        for _ in 0..duplicate.acquire() {
+            let mut url = url.clone();
+            // 每份复制的请求重新做负载均衡
+            if let Some(addr) = ReverseHelper::get_upstream_addr_by_req(&self.upstream, domain, req) {
+                url.domain = Some(addr.ip().to_string());
+                url.port = Some(addr.port());
+            }
+            let mut dup = Request::new_by_parts(req.parts().clone()).into(Body::empty()).0;
+            dup.headers_mut().insert("X-Wmproxy-Duplicate", "1");
+            let proxy_timeout = self.comm.build_proxy_timeout();
+            let local_bind = local_bind.clone();
+            let upstream_proxy = ReverseHelper::get_upstream_proxy(&self.upstream, domain);
+            let buffer = buffer.clone();
+            tokio::spawn(async move {
+                if let Some(buffer) = buffer {
+                    match buffer.to_body().await {
+                        Ok(body) => *dup.body_mut() = body,
+                        Err(_) => return,
+                    }
+                }
+                tokio::select! {
+                    r = Self::send_duplicate(dup, url, proxy_timeout, local_bind, upstream_proxy) => {
+                        if let Err(e) = r {
+                            log::trace!("复制请求发送失败:{:?}", e);
+                        }
+                    }
+                    _ = ConfigDuplicate::wait_stop() => {
+                        log::trace!("流量复制已停止, 中止进行中的复制请求");
+                    }
+                }
+            });
+        }
+    }
+
+    /// 在后台重新请求上游, 以更新已过期的缓存
+    pub fn spawn_cache_revalidate(&self, proxy_cache: &ConfigProxyCache, req: &Request<Body>, key: String) {
+        let url = match &self.comm.proxy_url {
+            Some(url) => url.clone(),
+            None => return,
+        };
+        let location = self.clone();
+        let proxy_cache = proxy_cache.clone();
+        let mut req = Request::new_by_parts(req.parts().clone()).into(Body::empty()).0;
+        tokio::spawn(async move {
+            let res = location.deal_reverse_proxy(&mut req, &url).await.map(|r| r.0);
+            proxy_cache.finish_revalidate(&key, res).await;
+        });
+    }
+
+    async fn send_duplicate(
+        req: Request<Body>,
+        url: Url,
+        proxy_timeout: Option<TimeoutLayer>,
+        local_bind: Option<String>,
+        upstream_proxy: Option<ConfigUpstreamProxy>,
+    ) -> ProtResult<()> {
+        let connect_timeout = proxy_timeout.as_ref().and_then(|t| t.connect_timeout);
+        let connect = url
+            .get_connect_url()
+            .ok_or(ProtError::Extension("get url error"))?;
+        let stream = HealthCheck::connect_upstream(
+            &connect,
+            connect_timeout,
+            local_bind.as_deref(),
+            upstream_proxy.as_ref(),
+        )
+        .await?;
+        let client = if url.scheme.is_http() {
+            Client::builder()
+                .timeout_layer(proxy_timeout)
+                .connect_by_stream(stream)
+                .await?
+        } else {
+            Client::builder()
+                .timeout_layer(proxy_timeout)
+                .url(url)?
+                .connect_tls_by_stream(stream)
+                .await?
+        };
+        let mut res = client.send_now(req).await?;
+        res.body_mut().wait_all().await;
+        Ok(())
+    }
+
+    /// 是否可以重试其它上游, 带body的请求需已缓存
+    pub(crate) fn can_retry(&self, req: &Request<Body>, has_body: bool, buffer: &Option<BodyBuffer>) -> bool {
+        if has_body && buffer.is_none() {
+            return false;
+        }
+        let idempotent = matches!(
+            req.method(),
+            Method::Get | Method::Head | Method::Put | Method::Delete | Method::Options | Method::Trace
+        );
+        idempotent || self.comm.retry_non_idempotent.unwrap_or(false)
+    }
+
     pub async fn deal_reverse_proxy(
         &self,
         req: &mut Request<Body>,
@@ -143,47 +535,354 @@ impl LocationConfig {
         Option<Sender<Request<Body>>>,
         Option<Receiver<ProtResult<Response<Body>>>>,
     )> {
-        let mut url = url.clone();
+        let origin = url;
         let domain = url.domain.clone().unwrap();
+        let has_body = BodyBuffer::is_need_buffer(req);
+        // 鉴权时可能已缓存了body
+        let mut buffer = req.extensions().get::<BodyBuffer>().cloned();
+        if let Some(config) = &self.comm.body_buffer {
+            if has_body && buffer.is_none() {
+                match BodyBuffer::read_request(req, config).await? {
+                    BufferResult::Buffered(b) => {
+                        log::trace!("请求body已缓存{}字节, 写入临时文件:{}", b.len(), b.is_spill());
+                        buffer = Some(b);
+                    }
+                    BufferResult::Pass => {}
+                    BufferResult::Reject => {
+                        let res = Response::text()
+                            .status(413)
+                            .body("Payload Too Large")
+                            .unwrap()
+                            .into_type();
+                        return Ok((res, None, None));
+                    }
+                }
+            }
+        }
+        // 上游不支持chunked时, 缓存body以计算Content-Length
+        if let Some(config) = ReverseHelper::get_upstream_dechunk(&self.upstream, &domain) {
+            if req.headers().is_chunked() {
+                let result = if has_body {
+                    BodyBuffer::read_request(req, &config).await?
+                } else {
+                    // 带压缩的body无法在缓存后保持原编码
+                    BufferResult::Pass
+                };
+                let reject = match result {
+                    BufferResult::Buffered(b) => {
+                        log::trace!("上游不支持chunked, 请求body已缓存{}字节", b.len());
+                        buffer = Some(b);
+                        None
+                    }
+                    BufferResult::Pass => Some((411, "Length Required")),
+                    BufferResult::Reject => Some((413, "Payload Too Large")),
+                };
+                if let Some((status, reason)) = reject {
+                    // 剩余的body未读取, 不再复用该连接
+                    let res = Response::text()
+                        .status(status)
+                        .header(HeaderName::CONNECTION, "close")
+                        .body(reason)
+                        .unwrap()
+                        .into_type();
+                    return Ok((res, None, None));
+                }
+            }
+        }
+        let tries = if self.can_retry(req, has_body, &buffer) {
+            self.comm.proxy_next_upstream_tries.unwrap_or(0)
+        } else {
+            0
+        };
+        let budget = ReverseHelper::get_upstream_retry_budget(&self.upstream, &domain);
+        if let Some(budget) = &budget {
+            budget.deposit();
+        }
+        self.comm.set_forwarded_headers(req);
+        Framing::normalize_request(req);
+        // 每次重试可能选中不同的上游, 以原始的请求头重新追加
+        let origin_headers = req.headers().clone();
+        let mut index = 0;
+        loop {
+            if index > 0 {
+                *req.headers_mut() = origin_headers.clone();
+            }
+            let mut url = origin.clone();
+            // 每次重试重新做负载均衡
+            let addr = ReverseHelper::get_upstream_addr_by_req(&self.upstream, &domain, req);
+            if let Some(addr) = addr {
+                url.domain = Some(addr.ip().to_string());
+                url.port = Some(addr.port());
+            }
+            if url.scheme == Scheme::None {
+                url.scheme = req.scheme().clone();
+            }
+            let proxy_host = self.proxy_set_host.clone().unwrap_or_default();
+            if let Some(host) = proxy_host.host_value(req, origin, &url) {
+                req.headers_mut().insert(HeaderName::HOST, host);
+            }
+            // 改写后的请求头同样需要校验, 防止转发超大的头给上游
+            if let Some(res) = self.comm.check_header_limit(req) {
+                return Ok((res, None, None));
+            }
+            let headers = ConfigHeader::merge(
+                &self.headers,
+                ReverseHelper::get_upstream_headers(&self.upstream, &domain),
+            );
+            Helper::rewrite_request(req, &headers);
+            let local_bind = ReverseHelper::get_upstream_local_bind(&self.upstream, &domain);
+            if index == 0 {
+                if let Some(duplicate) = &self.duplicate {
+                    self.spawn_duplicate(duplicate, req, &url, &domain, &local_bind, &buffer);
+                }
+            }
+            // 等待上游返回头期间计入该地址的处理数
+            let _in_flight = url
+                .get_connect_url()
+                .and_then(|c| c.parse::<SocketAddr>().ok())
+                .map(HealthCheck::track);
+            let upstream_proxy = ReverseHelper::get_upstream_proxy(&self.upstream, &domain);
+            let dscp = ReverseHelper::get_upstream_dscp(&self.upstream, &domain);
+            let tls_fallback = ReverseHelper::get_upstream_tls_fallback(&self.upstream, &domain);
+            let tls = ReverseHelper::get_upstream_tls(&self.upstream, &domain, addr);
+            let connect_limit = ReverseHelper::get_upstream_connect_limit(&self.upstream, &domain, addr);
+            let http2 = ReverseHelper::get_upstream_http2_settings(&self.upstream, &domain);
+            match self
+                .send_upstream(
+                    req, &url, &local_bind, &upstream_proxy, &dscp, &tls, tls_fallback, &connect_limit, &http2,
+                )
+                .await
+            {
+                Ok(mut res) => {
+                    self.comm.hide_response_headers(&mut res.0);
+                    // 上游未等待body即返回, 客户端可能仍会发送body, 关闭该连接
+                    if let Some(notify) = req.extensions().get::<ContinueNotify>() {
+                        if notify.is_waiting() {
+                            notify.cancel();
+                            res.0.headers_mut().insert(HeaderName::CONNECTION, "close");
+                        }
+                    }
+                    Helper::rewrite_response(&mut res.0, &headers);
+                    if let Some(status_map) = &self.status_map {
+                        // 替换了返回内容, 上游剩余的数据无法再读取, 不再复用该连接
+                        if status_map.apply(&mut res.0).await {
+                            res.1 = None;
+                            res.2 = None;
+                        }
+                    }
+                    self.buffer_small_response(req.method(), &mut res.0).await;
+                    return Ok(res);
+                }
+                // 重试预算不足时不再重试, 防止大量的重试压垮上游
+                Err(e) if index < tries && budget.as_ref().map(|b| b.acquire()).unwrap_or(true) => {
+                    log::trace!("请求上游{:?}失败:{:?}, 尝试其它上游", url.get_connect_url(), e);
+                    index += 1;
+                    if let Some(buffer) = &buffer {
+                        *req.body_mut() = buffer.to_body().await?;
+                    }
+                }
+                // 连接上游失败时返回502, 具体的原因仅记录在日志中
+                Err(e) => match UpstreamFailure::from_error(&e) {
+                    Some(failure) => return Ok((failure.response(), None, None)),
+                    None => return Err(e),
+                },
+            }
+        }
+    }
 
-        if let Some(addr) = ReverseHelper::get_upstream_addr(&self.upstream, &*domain) {
-            url.domain = Some(addr.ip().to_string());
-            url.port = Some(addr.port());
+    /// 较小的返回先读完body, 上游连接可立即处理下一个请求, 不受客户端读取速度的影响
+    async fn buffer_small_response(&self, method: &Method, res: &mut Response<Body>) {
+        let limit = self
+            .proxy_min_stream_size
+            .as_ref()
+            .map(|s| s.0)
+            .unwrap_or(DEFAULT_MIN_STREAM_SIZE);
+        // HEAD及无body的返回可能带有Content-Length, 但不会有数据
+        let status = res.status().as_u16();
+        if *method == Method::Head || status < 200 || status == 204 || status == 304 {
+            return;
         }
-        if url.scheme == Scheme::None {
-            url.scheme = req.scheme().clone();
+        let len = res.get_body_len();
+        if len > 0 && len as u64 <= limit {
+            // 保持上游的编码, 防止读取时被解压后仍按原编码返回
+            let origin = res.body().get_origin_compress();
+            res.body_mut().add_compress_method(origin);
+            res.body_mut().wait_all().await;
         }
-        if let Some(connect) = url.get_connect_url() {
-            req.headers_mut().insert(HeaderName::HOST, connect.clone());
+    }
+
+    /// 连接上游, 失败时在日志中记录具体的原因
+    async fn connect_upstream(
+        connect: &str,
+        connect_timeout: Option<Duration>,
+        local_bind: &Option<String>,
+        upstream_proxy: &Option<ConfigUpstreamProxy>,
+    ) -> ProtResult<TcpStream> {
+        let start = std::time::Instant::now();
+        match HealthCheck::connect_upstream(
+            connect,
+            connect_timeout,
+            local_bind.as_deref(),
+            upstream_proxy.as_ref(),
+        )
+        .await
+        {
+            Ok(stream) => {
+                MetricsRegistry::observe(MetricsRegistry::UPSTREAM_CONNECT_DURATION, start.elapsed());
+                Ok(stream)
+            }
+            Err(e) => {
+                let failure = UpstreamFailure::from_connect(&e);
+                log::warn!("请求上游{}失败, {}:{:?}", connect, failure, e);
+                Err(failure.to_error())
+            }
+        }
+    }
+
+    /// 获取建立连接的名额, 排队等待同样计入连接超时, 排队已满时返回503
+    async fn acquire_connect(
+        connect: &str,
+        connect_limit: &Option<ConnectLimit>,
+        connect_timeout: Option<Duration>,
+    ) -> ProtResult<Option<ConnectPermit>> {
+        let Some(limit) = connect_limit else {
+            return Ok(None);
+        };
+        let permit = match connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, limit.acquire())
+                .await
+                .map_err(|_| UpstreamFailure::Timeout.to_error())?,
+            None => limit.acquire().await,
+        };
+        match permit {
+            Some(permit) => Ok(Some(permit)),
+            None => {
+                log::warn!("请求上游{}失败, {}", connect, UpstreamFailure::Busy);
+                Err(UpstreamFailure::Busy.to_error())
+            }
         }
+    }
+
+    /// 与上游的TLS握手同样计入连接超时
+    async fn handshake_timeout<T, E: Into<ProtError>>(
+        connect_timeout: Option<Duration>,
+        handshake: impl Future<Output = Result<T, E>>,
+    ) -> ProtResult<T> {
+        match connect_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, handshake).await {
+                Ok(result) => result.map_err(Into::into),
+                Err(_) => Err(UpstreamFailure::Timeout.to_error()),
+            },
+            None => handshake.await.map_err(Into::into),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn send_upstream(
+        &self,
+        req: &mut Request<Body>,
+        url: &Url,
+        local_bind: &Option<String>,
+        upstream_proxy: &Option<ConfigUpstreamProxy>,
+        dscp: &Option<ConfigDscp>,
+        tls: &Option<ConfigUpstreamTls>,
+        tls_fallback: bool,
+        connect_limit: &Option<ConnectLimit>,
+        http2: &Option<Http2Settings>,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
         let proxy_timeout = self.comm.build_proxy_timeout();
 
         let mut connect_timeout = None;
         if proxy_timeout.is_some() {
             connect_timeout = proxy_timeout.as_ref().unwrap().connect_timeout.clone();
         }
-        let stream = match url.get_connect_url() {
-            Some(connect) => HealthCheck::connect_timeout(&connect, connect_timeout).await?,
+        let connect = match url.get_connect_url() {
+            Some(connect) => connect,
             None => {
                 return Err(ProtError::Extension("get url error"));
             }
         };
-        let mut res = if url.scheme.is_http() {
-            let client = Client::builder()
-                .timeout_layer(proxy_timeout)
-                .connect_by_stream(stream)
-                .await?;
-            Self::deal_client(req, client).await?
-        } else {
-            let client = Client::builder()
-                .timeout_layer(proxy_timeout)
-                .url(url.clone())?
-                .connect_tls_by_stream(stream)
-                .await?;
-            Self::deal_client(req, client).await?
+        // 建立连接及TLS握手期间占用该上游地址的名额
+        let permit = Self::acquire_connect(&connect, connect_limit, connect_timeout).await?;
+        let stream =
+            Self::connect_upstream(&connect, connect_timeout, local_bind, upstream_proxy).await?;
+        ConfigDscp::apply_option(dscp, &stream);
+        if url.scheme.is_http() {
+            drop(permit);
+            return self.send_http(req, stream, proxy_timeout).await;
+        }
+        // SNI与发往上游的Host一致, 为空时取连接的地址
+        let host = req.headers().get_str_value(&HeaderName::HOST).unwrap_or_default();
+        let mut builder = Client::builder().timeout_layer(proxy_timeout).url(url.clone())?;
+        if let Some(http2) = http2 {
+            builder = builder.http2_builder(http2.builder());
+        }
+        let header_timeout = self.proxy_read_header_timeout.clone();
+        let e = match tls {
+            // 选中的上游单独配置了TLS时, 按其CA及客户端证书握手
+            Some(tls) => {
+                let handshake = tls.connect(stream, ConfigProxyHost::server_name(&host));
+                let result = Self::handshake_timeout(connect_timeout, handshake).await;
+                drop(permit);
+                match result {
+                    Ok((outbound, h2)) => {
+                        let builder = if h2 { builder.http2_only(true) } else { builder.http2(true) };
+                        let client = Client::new(builder.value(), MaybeHttpsStream::Https(outbound));
+                        return Self::deal_client(req, client, header_timeout).await;
+                    }
+                    Err(e) => e,
+                }
+            }
+            None => {
+                let handshake =
+                    builder.connect_tls_by_stream_with_domain(stream, ConfigProxyHost::server_name(&host));
+                let result = Self::handshake_timeout(connect_timeout, handshake).await;
+                drop(permit);
+                match result {
+                    Ok(client) => return Self::deal_client(req, client, header_timeout).await,
+                    Err(e) => e,
+                }
+            }
         };
-        Helper::rewrite_response(&mut res.0, &self.headers);
-        Ok(res)
+        let failure = UpstreamFailure::from_error(&e).unwrap_or(UpstreamFailure::Tls);
+        log::warn!("请求上游{}失败, {}:{:?}", connect, failure, e);
+        if failure != UpstreamFailure::Tls || !tls_fallback {
+            return Err(failure.to_error());
+        }
+        log::warn!("上游{}配置了tls_fallback_plain, 改用明文连接, 数据将不再加密", connect);
+        let stream =
+            Self::connect_upstream(&connect, connect_timeout, local_bind, upstream_proxy).await?;
+        ConfigDscp::apply_option(dscp, &stream);
+        self.send_http(req, stream, self.comm.build_proxy_timeout()).await
+    }
+
+    async fn send_http(
+        &self,
+        req: &mut Request<Body>,
+        stream: TcpStream,
+        proxy_timeout: Option<TimeoutLayer>,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
+        let builder = Client::builder().timeout_layer(proxy_timeout);
+        match req.extensions().get::<ContinueNotify>().cloned() {
+            // 上游返回的`100 Continue`将转给客户端
+            Some(notify) => {
+                let stream = UpstreamContinue::new(stream, notify);
+                let client = Client::new(builder.value(), MaybeHttpsStream::Http(stream));
+                Self::deal_client(req, client, self.proxy_read_header_timeout.clone()).await
+            }
+            None => {
+                let client = builder.connect_by_stream(stream).await?;
+                Self::deal_client(req, client, self.proxy_read_header_timeout.clone()).await
+            }
+        }
     }
 
     pub async fn deal_request(
@@ -203,8 +902,27 @@ impl LocationConfig {
             let res = static_reponse.deal_request(req).await?;
             return Ok((res, None, None));
         }
+        if let Some(url) = &self.sse_bridge {
+            let res = SseBridge::deal_request(url, req).await?;
+            return Ok((res, None, None));
+        }
         if let Some(reverse) = &self.comm.proxy_url {
-            return self.deal_reverse_proxy(req, reverse).await;
+            let violation = match &self.comm.multipart_limit {
+                Some(config) => match MultipartLimit::limit_request(req, config) {
+                    Ok(violation) => violation,
+                    Err(e) => return Ok((e.response(), None, None)),
+                },
+                None => None,
+            };
+            let Some(mut violation) = violation else {
+                return self.deal_reverse_proxy(req, reverse).await;
+            };
+            // 转发中超出限制时不再等待上游, 直接返回给客户端
+            tokio::select! {
+                biased;
+                Ok(e) = &mut violation => return Ok((e.response(), None, None)),
+                res = self.deal_reverse_proxy(req, reverse) => return res,
+            }
         }
         return Err(ProtError::Extension("unknow data"));
     }
@@ -246,3 +964,585 @@ impl LocationConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::LocationConfig;
+    use crate::{
+        reverse::UpstreamFailure,
+        test_util::{connect_http, load_http, read_head, run_upstream},
+    };
+    use webparse::{HeaderName, Method, Request, Response};
+    use wenmeng::Body;
+
+    fn build_request(method: Method) -> Request<String> {
+        Request::builder()
+            .method(method)
+            .url("/api")
+            .body(String::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn method_allow_deny() {
+        let mut location = LocationConfig::new();
+        assert!(location.check_method(&build_request(Method::Trace)).is_none());
+
+        location.allowed_methods = Some("get, head post".parse().unwrap());
+        assert!(location.check_method(&build_request(Method::Get)).is_none());
+        let res = location.check_method(&build_request(Method::Put)).unwrap();
+        assert_eq!(res.status().as_u16(), 405);
+        assert_eq!(
+            res.headers().get_str_value(&HeaderName::ALLOW),
+            Some("GET, HEAD, POST".to_string())
+        );
+
+        // 仅配置禁止列表时, Allow头为常用方法中未被禁止的部分
+        location.allowed_methods = None;
+        location.denied_methods = Some("TRACE TRACK DELETE".parse().unwrap());
+        assert!(location.check_method(&build_request(Method::Post)).is_none());
+        let track = Method::Extension("TRACK".to_string());
+        let res = location.check_method(&build_request(track)).unwrap();
+        assert_eq!(res.status().as_u16(), 405);
+        assert_eq!(
+            res.headers().get_str_value(&HeaderName::ALLOW),
+            Some("GET, HEAD, POST, PUT, OPTIONS, PATCH".to_string())
+        );
+    }
+
+    /// 以收到的请求头作为body返回的上游
+    async fn run_head_upstream() -> SocketAddr {
+        run_upstream(|mut stream, head| async move {
+            let head = head.to_lowercase();
+            let res = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", head.len(), head);
+            stream.write_all(res.as_bytes()).await.unwrap();
+        })
+        .await
+    }
+
+    /// 读取请求但不返回的上游
+    async fn run_silent_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n) = stream.read(&mut buf).await {
+                        if n == 0 {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn multipart_limit_mid_stream() {
+        let addr = run_silent_upstream().await;
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            multipart_limit = "part=1k types=image/*"
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}"
+            "#,
+            addr
+        );
+        let mut client = connect_http(load_http(&config).convert_server_config()).await;
+        let part = "--xyz\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.png\"\r\nContent-Type: image/png\r\n\r\n";
+        let req = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=xyz\r\nContent-Length: 100000\r\n\r\n{}",
+            part
+        );
+        client.write_all(req.as_bytes()).await.unwrap();
+        client.write_all(&[b'a'; 2048]).await.unwrap();
+        // body未发送完, 超出part的限制后即返回413
+        let head = tokio::time::timeout(std::time::Duration::from_secs(2), read_head(&mut client))
+            .await
+            .unwrap();
+        assert!(head.starts_with("http/1.1 413"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn upstream_headers() {
+        let (a, b) = (run_head_upstream().await, run_head_upstream().await);
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            [[server.upstream]]
+            name = "api-a"
+            headers = ["proxy X-Api-Key key-a", "X-Served a"]
+            server = [{{ addr = "{}" }}]
+            [[server.upstream]]
+            name = "api-b"
+            headers = ["proxy X-Api-Key key-b"]
+            server = [{{ addr = "{}" }}]
+            [[server.location]]
+            rule = "/a"
+            proxy_url = "http://api-a"
+            headers = ["proxy X-Api-Key key-loc", "proxy X-Location loc", "X-Served loc"]
+            [[server.location]]
+            rule = "/b"
+            proxy_url = "http://api-b"
+            headers = ["X-Served loc"]
+            "#,
+            a, b
+        );
+        let servers = load_http(&config).convert_server_config();
+        for (path, key, served) in [("/a", "key-a", "a"), ("/b", "key-b", "loc")] {
+            let mut client = connect_http(servers.clone()).await;
+            let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            client.write_all(req.as_bytes()).await.unwrap();
+            let head = read_head(&mut client).await;
+            let len = head
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length: "))
+                .map(|l| l.trim().parse::<usize>().unwrap())
+                .unwrap();
+            let mut body = vec![0u8; len];
+            client.read_exact(&mut body).await.unwrap();
+            let body = String::from_utf8(body).unwrap();
+
+            // 上游的头覆盖location中同名的头, 其余的location头保留
+            assert_eq!(body.matches("x-api-key:").count(), 1, "{}", body);
+            assert!(body.contains(&format!("x-api-key: {}", key)), "{}", body);
+            assert_eq!(body.contains("x-location: loc"), path == "/a");
+            assert!(head.contains(&format!("x-served: {}\r\n", served)), "{}", head);
+        }
+    }
+
+    #[tokio::test]
+    async fn read_header_timeout() {
+        let addr = run_silent_upstream().await;
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}"
+            proxy_read_timeout = "10s"
+            proxy_read_header_timeout = "200ms"
+            "#,
+            addr
+        );
+        let mut client = connect_http(load_http(&config).convert_server_config()).await;
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        // 上游连接正常但未返回响应头, 在读取超时前返回504
+        let head = tokio::time::timeout(std::time::Duration::from_secs(2), read_head(&mut client))
+            .await
+            .unwrap();
+        assert!(head.starts_with("http/1.1 504"), "{}", head);
+    }
+
+    /// 先返回一半的body, 暂停后再返回剩余部分的上游
+    async fn run_slow_body_upstream(len: usize) -> SocketAddr {
+        run_upstream(move |mut stream, _| async move {
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", len);
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(&vec![b'a'; len / 2]).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            let _ = stream.write_all(&vec![b'a'; len - len / 2]).await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn min_stream_size() {
+        use std::time::Duration;
+        for (len, buffered) in [(1000, true), (64 * 1024, false)] {
+            let addr = run_slow_body_upstream(len).await;
+            let config = format!(
+                r#"
+                [[server]]
+                bind_addr = "127.0.0.1:0"
+                bind_ssl = ""
+                up_name = "localhost"
+                [[server.location]]
+                rule = "/"
+                proxy_url = "http://{}"
+                proxy_min_stream_size = "4k"
+                "#,
+                addr
+            );
+            let mut client = connect_http(load_http(&config).convert_server_config()).await;
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            // 小于阈值时等上游body读完才返回头, 大于阈值时头立即返回
+            let early = tokio::time::timeout(Duration::from_millis(250), read_head(&mut client)).await;
+            assert_eq!(early.is_err(), buffered, "len {}", len);
+            if buffered {
+                let head = tokio::time::timeout(Duration::from_secs(2), read_head(&mut client))
+                    .await
+                    .unwrap();
+                assert!(head.contains(&format!("content-length: {}", len)), "{}", head);
+            }
+            let mut body = vec![0u8; len];
+            tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut body))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(body.iter().all(|b| *b == b'a'));
+        }
+    }
+
+    #[tokio::test]
+    async fn min_stream_size_encoded() {
+        use std::io::Write;
+        let mut writer = brotli::CompressorWriter::new(vec![], 4096, 5, 22);
+        writer.write_all("hello world ".repeat(100).as_bytes()).unwrap();
+        let encoded = writer.into_inner();
+        let data = encoded.clone();
+        let addr = run_upstream(move |mut stream, _| {
+            let data = data.clone();
+            async move {
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Encoding: br\r\nContent-Length: {}\r\n\r\n",
+                    data.len()
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&data).await.unwrap();
+            }
+        })
+        .await;
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}"
+            "#,
+            addr
+        );
+        let mut client = connect_http(load_http(&config).convert_server_config()).await;
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: br\r\n\r\n")
+            .await
+            .unwrap();
+        // 缓存的压缩数据原样返回
+        let head = tokio::time::timeout(std::time::Duration::from_secs(2), read_head(&mut client))
+            .await
+            .unwrap();
+        assert!(head.contains("content-encoding: br"), "{}", head);
+        assert!(head.contains(&format!("content-length: {}", encoded.len())), "{}", head);
+        let mut body = vec![0u8; encoded.len()];
+        tokio::time::timeout(std::time::Duration::from_secs(2), client.read_exact(&mut body))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body, encoded);
+    }
+
+    /// TLS的握手返回400并关闭, 明文的请求返回200的上游
+    async fn run_plain_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut first = [0u8; 1];
+                    if stream.read_exact(&mut first).await.is_err() {
+                        return;
+                    }
+                    if first[0] == 0x16 {
+                        let res = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+                        let _ = stream.write_all(res.as_bytes()).await;
+                        return;
+                    }
+                    read_head(&mut stream).await;
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nplain")
+                        .await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn proxy_response(upstream: &str, proxy_url: &str) -> Response<Body> {
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            {}
+            [[server.location]]
+            rule = "/"
+            proxy_url = "{}"
+            proxy_connect_timeout = "300ms"
+            "#,
+            upstream, proxy_url
+        );
+        let servers = load_http(&config).convert_server_config();
+        let mut req = Request::builder()
+            .url("/")
+            .header("Host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let (res, _, _) = tokio::time::timeout(
+            std::time::Duration::from_secs(2),
+            servers[0].location[0].deal_request(&mut req),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn upstream_failure_bad_gateway() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let plain = run_plain_upstream().await;
+        let silent = run_silent_upstream().await;
+        for (proxy_url, failure, message) in [
+            (format!("http://{}", closed), UpstreamFailure::Refused, "上游拒绝连接"),
+            (format!("https://{}", plain), UpstreamFailure::Tls, "上游TLS握手失败"),
+            (format!("https://{}", silent), UpstreamFailure::Timeout, "连接上游超时"),
+        ] {
+            let mut res = proxy_response("", &proxy_url).await;
+            assert_eq!(res.status().as_u16(), 502, "{}", proxy_url);
+            // 日志中区分失败的原因, 返回给客户端的内容不包含细节
+            let found = res.extensions().get::<UpstreamFailure>().cloned();
+            assert_eq!(found, Some(failure));
+            assert_eq!(failure.to_string(), message);
+            let mut body = webparse::BinaryMut::new();
+            res.body_mut().read_all(&mut body).await.unwrap();
+            assert_eq!(body.as_slice(), b"Bad Gateway");
+        }
+    }
+
+    #[tokio::test]
+    async fn tls_fallback_plain() {
+        let plain = run_plain_upstream().await;
+        let upstream = |fallback: bool| {
+            format!(
+                "[[server.upstream]]\nname = \"backend\"\ntls_fallback_plain = {}\nserver = [{{ addr = \"{}\" }}]",
+                fallback, plain
+            )
+        };
+        // 未开启时握手失败返回502
+        let res = proxy_response(&upstream(false), "https://backend").await;
+        assert_eq!(res.status().as_u16(), 502);
+
+        // 开启后改用明文连接
+        let mut res = proxy_response(&upstream(true), "https://backend").await;
+        assert_eq!(res.status().as_u16(), 200);
+        let mut body = webparse::BinaryMut::new();
+        res.body_mut().read_all(&mut body).await.unwrap();
+        assert_eq!(body.as_slice(), b"plain");
+    }
+
+    /// 以自签名证书提供https服务的上游, 返回其地址及证书的PEM文件
+    async fn run_tls_upstream(name: &str) -> (SocketAddr, String) {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        use tokio_rustls::TlsAcceptor;
+
+        let (certs, key) = crate::SelfSigned::generate(&[]).unwrap();
+        let pem = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            STANDARD.encode(&certs[0])
+        );
+        let path = std::env::temp_dir().join(format!("wmproxy_upstream_{}_{}.pem", name, std::process::id()));
+        std::fs::write(&path, pem).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(
+            rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .unwrap(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let body = name.to_string();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    read_head(&mut stream).await;
+                    let res = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                    let _ = stream.write_all(res.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        (addr, path.to_string_lossy().to_string())
+    }
+
+    #[tokio::test]
+    async fn upstream_tls_per_server() {
+        let (a, ca_a) = run_tls_upstream("a").await;
+        let (b, ca_b) = run_tls_upstream("b").await;
+        let upstream = |ca_a: &str, ca_b: &str| {
+            format!(
+                "[[server.upstream]]\nname = \"backend\"\nserver = [{{ addr = \"{}\", tls = \"ca={}\" }}, {{ addr = \"{}\", tls = \"ca={}\" }}]",
+                a, ca_a, b, ca_b
+            )
+        };
+        // 负载均衡到两个CA不同的上游, 均按各自的CA校验
+        let config = upstream(&ca_a, &ca_b);
+        let mut bodys = HashSet::new();
+        for _ in 0..20 {
+            let mut res = proxy_response(&config, "https://backend").await;
+            assert_eq!(res.status().as_u16(), 200);
+            let mut body = webparse::BinaryMut::new();
+            res.body_mut().read_all(&mut body).await.unwrap();
+            bodys.insert(String::from_utf8_lossy(body.as_slice()).to_string());
+        }
+        assert_eq!(bodys, HashSet::from(["a".to_string(), "b".to_string()]));
+
+        // CA不匹配时握手失败
+        let config = upstream(&ca_b, &ca_a);
+        for _ in 0..4 {
+            let res = proxy_response(&config, "https://backend").await;
+            assert_eq!(res.status().as_u16(), 502);
+        }
+    }
+
+    /// 仅支持HTTP/1.0的上游, 收到chunked的body返回400, 否则返回收到的body长度
+    async fn run_http10_upstream() -> SocketAddr {
+        run_upstream(|mut stream, head| async move {
+            let head = head.to_lowercase();
+            let len = head
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length: "))
+                .map(|l| l.trim().parse::<usize>().unwrap());
+            let res = match len {
+                Some(len) if !head.contains("transfer-encoding") => {
+                    let mut body = vec![0u8; len];
+                    stream.read_exact(&mut body).await.unwrap();
+                    format!("HTTP/1.0 200 OK\r\nContent-Length: 4\r\n\r\n{}", len)
+                }
+                _ => "HTTP/1.0 400 Bad Request\r\n\r\n".to_string(),
+            };
+            let _ = stream.write_all(res.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn dechunk_http10_upstream() {
+        let addr = run_http10_upstream().await;
+        for (over, len, expect) in [
+            ("reject", 3000, "http/1.1 200"),
+            ("reject", 5000, "http/1.1 413"),
+            ("pass", 5000, "http/1.1 411"),
+        ] {
+            let config = format!(
+                r#"
+                [[server]]
+                bind_addr = "127.0.0.1:0"
+                bind_ssl = ""
+                up_name = "localhost"
+                [[server.upstream]]
+                name = "legacy"
+                dechunk = "size=4k memory=1k over={}"
+                server = [{{ addr = "{}" }}]
+                [[server.location]]
+                rule = "/"
+                proxy_url = "http://legacy"
+                "#,
+                over, addr
+            );
+            let mut client = connect_http(load_http(&config).convert_server_config()).await;
+            let mut req = b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            for chunk in vec![b'a'; len].chunks(1000) {
+                req.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                req.extend_from_slice(chunk);
+                req.extend_from_slice(b"\r\n");
+            }
+            req.extend_from_slice(b"0\r\n\r\n");
+            client.write_all(&req).await.unwrap();
+            let head = tokio::time::timeout(std::time::Duration::from_secs(2), read_head(&mut client))
+                .await
+                .unwrap();
+            assert!(head.starts_with(expect), "{}", head);
+            if len <= 4096 {
+                let mut body = [0u8; 4];
+                client.read_exact(&mut body).await.unwrap();
+                assert_eq!(&body, b"3000");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_limit_per_upstream() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        // 握手期间保持连接一段时间后关闭的上游, 记录同时建立中的连接数
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (current, max) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (c, m) = (current.clone(), max.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (c, m) = (c.clone(), m.clone());
+                tokio::spawn(async move {
+                    m.fetch_max(c.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+                    c.fetch_sub(1, Ordering::SeqCst);
+                    drop(stream);
+                });
+            }
+        });
+        let run = |pending: &str| {
+            let upstream = format!(
+                "[[server.upstream]]\nname = \"backend\"\nmax_conns_per_upstream = 2\n{}\nserver = [{{ addr = \"{}\" }}]",
+                pending, addr
+            );
+            let tasks = (0..6)
+                .map(|_| {
+                    let upstream = upstream.clone();
+                    tokio::spawn(async move { proxy_response(&upstream, "https://backend").await.status().as_u16() })
+                })
+                .collect::<Vec<_>>();
+            async move {
+                let mut status = vec![];
+                for task in tasks {
+                    status.push(task.await.unwrap());
+                }
+                status.sort();
+                status
+            }
+        };
+        // 同时建立的连接不超过配置的数量, 其余的排队等待
+        assert_eq!(run("").await, vec![502; 6]);
+        assert_eq!(max.load(Ordering::SeqCst), 2);
+
+        // 排队已满时返回503
+        let status = run("max_pending_connects = 1").await;
+        assert_eq!(status.iter().filter(|s| **s == 502).count(), 3, "{:?}", status);
+        assert_eq!(status.iter().filter(|s| **s == 503).count(), 3, "{:?}", status);
+        assert_eq!(max.load(Ordering::SeqCst), 2);
+    }
+}
@@ -25,10 +25,20 @@ use wenmeng::{RecvRequest, ProtResult, ProtError};
 
 use crate::{Helper, IpSets};
 
+use super::BodyPeek;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MatchMethod(pub HashSet<Method>);
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MatchScheme(pub HashSet<Scheme>);
+/// 类型的匹配, 多个类型以空格做间隔, 任意一个匹配即可
+/// 如`application/grpc application/json`, 支持`text/*`前缀匹配, 忽略`;`后的参数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchContentType(pub Vec<String>);
+/// 请求body开头的匹配, 以正则匹配预读的body, 如`^\{"service":"order"`
+/// 预读的大小及超时由server中的body_peek配置, 未配置时预读1k
+#[derive(Debug, Clone)]
+pub struct MatchBody(regex::bytes::Regex);
 
 /// location匹配，将根据该类的匹配信息进行是否匹配
 #[serde_as]
@@ -44,6 +54,15 @@ pub struct Matcher {
     method: Option<MatchMethod>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     scheme: Option<MatchScheme>,
+    /// 匹配请求头中的`Content-Type`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    content_type: Option<MatchContentType>,
+    /// 匹配请求头中的`Accept`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    accept: Option<MatchContentType>,
+    /// 匹配预读的请求body
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    body: Option<MatchBody>,
 }
 
 impl Matcher {
@@ -77,7 +96,13 @@ impl Matcher {
         "/".to_string()
     }
 
-    /// 当本地限制方法时,优先匹配方法,在进行路径的匹配
+    /// 是否需要预读请求的body进行匹配
+    pub fn is_match_body(&self) -> bool {
+        self.body.is_some()
+    }
+
+    /// 所有配置的条件均满足才算匹配, 依次为path, method, scheme, host, client_ip, content_type, accept, body
+    /// 多个location均满足时, 按配置的先后顺序取第一个
     pub fn is_match_rule(&self, path: &String, req: &RecvRequest) -> ProtResult<bool>  {
         if let Some(p) = &self.path {
             let mut is_match = false;
@@ -128,10 +153,103 @@ impl Matcher {
             }
         }
 
+        if let Some(c) = &self.content_type {
+            match req.headers().get_str_value(&"Content-Type") {
+                Some(v) if c.is_match(&v) => {}
+                _ => return Ok(false),
+            }
+        }
+
+        if let Some(a) = &self.accept {
+            match req.headers().get_str_value(&"Accept") {
+                Some(v) if a.is_match(&v) => {}
+                _ => return Ok(false),
+            }
+        }
+
+        if let Some(b) = &self.body {
+            match req.extensions().get::<BodyPeek>() {
+                Some(peek) if b.0.is_match(&peek.0) => {}
+                _ => return Ok(false),
+            }
+        }
+
         Ok(true)
     }
 }
 
+impl MatchContentType {
+    fn is_match_one(rule: &str, value: &str) -> bool {
+        if rule == "*/*" || rule == value {
+            return true;
+        }
+        if let Some(prefix) = rule.strip_suffix("/*") {
+            return value.split('/').next() == Some(prefix);
+        }
+        false
+    }
+
+    /// 请求头的值可能为`application/json; charset=utf-8`或者`text/html, */*;q=0.8`
+    pub fn is_match(&self, value: &str) -> bool {
+        for v in value.split(',') {
+            let v = v.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+            if v.is_empty() {
+                continue;
+            }
+            if self.0.iter().any(|r| Self::is_match_one(r, &v)) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl FromStr for MatchContentType {
+    type Err = WebError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = s
+            .split_whitespace()
+            .map(|v| v.to_ascii_lowercase())
+            .collect::<Vec<String>>();
+        if vals.is_empty() {
+            return Err(WebError::Extension("content type is empty"));
+        }
+        Ok(Self(vals))
+    }
+}
+
+impl Display for MatchContentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0.join(" "))
+    }
+}
+
+impl FromStr for MatchBody {
+    type Err = WebError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match regex::bytes::Regex::new(s) {
+            Ok(re) => Ok(Self(re)),
+            Err(_) => Err(WebError::Extension("body regex error")),
+        }
+    }
+}
+
+impl Display for MatchBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0.as_str())
+    }
+}
+
+impl PartialEq for MatchBody {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for MatchBody {}
+
 impl FromStr for MatchMethod {
     type Err = WebError;
 
@@ -187,6 +305,9 @@ impl Default for Matcher {
             host: Default::default(),
             method: Default::default(),
             scheme: Default::default(),
+            content_type: Default::default(),
+            accept: Default::default(),
+            body: Default::default(),
         }
     }
 }
@@ -210,6 +331,61 @@ impl Display for Matcher {
         if let Some(p) = &self.host {
             f.write_str(&*p)?;
         }
+        if let Some(c) = &self.content_type {
+            f.write_fmt(format_args!(" content_type:{}", c))?;
+        }
+        if let Some(a) = &self.accept {
+            f.write_fmt(format_args!(" accept:{}", a))?;
+        }
+        if let Some(b) = &self.body {
+            f.write_fmt(format_args!(" body:{}", b))?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use webparse::Request;
+    use wenmeng::Body;
+
+    use crate::reverse::LocationConfig;
+
+    fn build_location(content_type: &str, up: &str) -> LocationConfig {
+        let value = format!(
+            "rule = {{ path = \"/*\", content_type = \"{}\" }}\nproxy_url = \"http://{}\"",
+            content_type, up
+        );
+        toml::from_str(&value).unwrap()
+    }
+
+    fn find_up(locations: &Vec<LocationConfig>, content_type: &str) -> Option<String> {
+        let req = Request::builder()
+            .url("http://127.0.0.1/helloworld.Greeter/SayHello")
+            .header("Content-Type", content_type.to_string())
+            .body(Body::empty())
+            .unwrap();
+        let path = req.path().clone();
+        for l in locations {
+            if l.is_match_rule(&path, &req) {
+                return l.comm.proxy_url.as_ref().and_then(|u| u.domain.clone());
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn route_by_content_type() {
+        let locations = vec![
+            build_location("application/grpc application/grpc+proto", "grpc"),
+            build_location("application/json", "json"),
+        ];
+        assert_eq!(find_up(&locations, "application/grpc"), Some("grpc".to_string()));
+        assert_eq!(find_up(&locations, "application/grpc+proto"), Some("grpc".to_string()));
+        assert_eq!(
+            find_up(&locations, "application/json; charset=utf-8"),
+            Some("json".to_string())
+        );
+        assert_eq!(find_up(&locations, "text/html"), None);
+    }
+}
@@ -10,28 +10,87 @@
 // -----
 // Created Date: 2023/10/16 04:28:22
 
+mod admission;
+mod auth_request;
+mod balance;
+mod body_buffer;
 mod common;
+mod compress;
+mod concurrency;
+mod connect_limit;
+mod debug_dump;
+mod duplicate;
+mod expect_continue;
+mod fault;
+mod framing;
 mod http;
+mod http2_settings;
+mod internal_redirect;
+// 未开启ja3特性时仅使用其中的配置类型
+#[cfg_attr(not(feature = "ja3"), allow(dead_code))]
+mod ja3;
 mod limit_req;
 mod location;
 mod matcher;
+mod multipart;
+mod pipeline;
+mod protocol_check;
+mod proxy_cache;
+mod reject_page;
+mod proxy_host;
+mod retry_budget;
 mod reverse_helper;
 mod server;
+mod sse_bridge;
+mod status_map;
 mod stream;
+mod transform;
 mod try_paths;
 mod upstream;
+mod upstream_failure;
+mod upstream_tls;
 mod ws;
 
+pub use admission::{Admission, ConfigAdmission};
+pub use auth_request::ConfigAuthRequest;
+pub use balance::{ConfigBalance, HashRing};
+pub use body_buffer::{BodyBuffer, BodyPeek, BufferResult};
 pub use common::CommonConfig;
+pub use compress::{ConfigCompress, Encoding};
+pub use concurrency::{ConcurrencyLimit, ConcurrencyPermit};
+pub use connect_limit::{ConnectLimit, ConnectPermit};
+pub use debug_dump::{ConfigDebugDump, DumpTimer};
+pub use duplicate::ConfigDuplicate;
+pub use expect_continue::{ContinueNotify, ContinueStream, UpstreamContinue};
+pub use fault::ConfigFault;
+pub use framing::Framing;
 pub use http::HttpConfig;
+pub use http2_settings::Http2Settings;
+pub use internal_redirect::InternalRedirect;
+pub use ja3::ConfigJa3Set;
+#[cfg(feature = "ja3")]
+pub use ja3::Ja3;
 pub use limit_req::{LimitReq, LimitReqMiddleware};
 pub use location::LocationConfig;
 pub use matcher::Matcher;
+pub use multipart::{ConfigMultipart, MultipartLimit};
+pub use pipeline::{ConfigPipeline, PipelineNotify, PipelineStream};
+pub use protocol_check::ConfigProtocolCheck;
+pub use proxy_cache::{CacheLookup, ConfigProxyCache};
+pub use reject_page::ConfigRejectPage;
+pub use proxy_host::ConfigProxyHost;
+pub use retry_budget::{ConfigRetryBudget, RetryBudget};
 pub use reverse_helper::ReverseHelper;
 pub use server::ServerConfig;
+pub use sse_bridge::SseBridge;
+pub use status_map::ConfigStatusMap;
 pub use stream::{StreamConfig, StreamUdp};
+pub use transform::ConfigTransform;
 pub use try_paths::TryPathsConfig;
 pub use upstream::UpstreamConfig;
+pub use upstream_failure::UpstreamFailure;
+pub use upstream_tls::ConfigUpstreamTls;
+pub use ws::WsLimit;
 
 use std::{
     fmt::{self},
@@ -0,0 +1,487 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/17 09:36:20
+
+use std::{fmt::Display, io, str::FromStr};
+
+use tokio::sync::{mpsc::channel, oneshot};
+use webparse::{Binary, BinaryMut, HeaderName, Request, Response};
+use wenmeng::Body;
+
+use super::body_buffer::{read_body_data, READ_BUFFER};
+use crate::ConfigSize;
+
+/// 单个part的头的最大长度
+const MAX_PART_HEADER: usize = 8 * 1024;
+
+/// `multipart/form-data`请求body的限制, 按流解析multipart的分隔, 不缓存整个上传的内容
+///
+/// 配置格式为`part=1m total=10m names=avatar,doc types=image/*,application/pdf`
+/// * part: 单个part的内容的最大字节数
+/// * total: 整个body的最大字节数
+/// * names: 允许的字段名, 未配置时不做限制
+/// * types: 允许的文件类型, 仅检查带filename的part, 未带Content-Type时视为`application/octet-stream`
+///
+/// 超出大小时返回`413`, 字段名不允许时返回`400`, 文件类型不允许时返回`415`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConfigMultipart {
+    pub part: Option<u64>,
+    pub total: Option<u64>,
+    pub names: Vec<String>,
+    pub types: Vec<String>,
+}
+
+/// 不符合限制的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultipartError {
+    PartTooLarge,
+    TotalTooLarge,
+    NameNotAllowed(String),
+    TypeNotAllowed(String),
+    /// 无boundary或者格式错误
+    Malformed,
+}
+
+enum State {
+    /// 第一个分隔符之前的内容
+    Preamble,
+    /// 分隔符之后, 判断是下一个part还是结束
+    Boundary,
+    Headers,
+    /// part的内容, 记录已读取的字节数
+    Body(u64),
+    End,
+}
+
+/// 按流解析multipart的body, 每次读取到数据时检查是否超出限制
+pub struct MultipartLimit {
+    config: ConfigMultipart,
+    /// part之间的分隔符, 为`\r\n--boundary`
+    delimiter: Vec<u8>,
+    state: State,
+    /// 待解析的数据, 仅保留可能为分隔符或者头的部分
+    buf: Vec<u8>,
+    total: u64,
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|w| w == needle)
+}
+
+/// 取头中的参数, 如`form-data; name="file"`中的name
+fn get_param(value: &str, key: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|p| {
+        let (k, v) = p.split_once('=')?;
+        if !k.trim().eq_ignore_ascii_case(key) {
+            return None;
+        }
+        Some(v.trim().trim_matches('"').to_string())
+    })
+}
+
+impl ConfigMultipart {
+    /// 类型是否允许, 支持`image/*`的形式
+    pub fn is_type_allow(&self, ty: &str) -> bool {
+        if self.types.is_empty() {
+            return true;
+        }
+        let ty = ty.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        self.types.iter().any(|t| match t.strip_suffix("/*") {
+            _ if t == "*" || t == "*/*" => true,
+            Some(major) => ty.split('/').next() == Some(major) && ty.contains('/'),
+            None => *t == ty,
+        })
+    }
+}
+
+impl FromStr for ConfigMultipart {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        let split = |v: &str| {
+            v.split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect::<Vec<String>>()
+        };
+        for v in s.split_whitespace() {
+            let kv = v.split('=').map(|k| k.trim()).collect::<Vec<&str>>();
+            if kv.len() != 2 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("未知的multipart_limit配置:{}", v),
+                ));
+            }
+            match kv[0] {
+                "part" => config.part = Some(ConfigSize::from_str(kv[1])?.0),
+                "total" => config.total = Some(ConfigSize::from_str(kv[1])?.0),
+                "names" => config.names = split(kv[1]),
+                "types" => {
+                    config.types = split(kv[1]).into_iter().map(|t| t.to_ascii_lowercase()).collect()
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("未知的multipart_limit配置:{}", v),
+                    ))
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl Display for ConfigMultipart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut values = vec![];
+        if let Some(part) = self.part {
+            values.push(format!("part={}", ConfigSize::new(part)));
+        }
+        if let Some(total) = self.total {
+            values.push(format!("total={}", ConfigSize::new(total)));
+        }
+        if !self.names.is_empty() {
+            values.push(format!("names={}", self.names.join(",")));
+        }
+        if !self.types.is_empty() {
+            values.push(format!("types={}", self.types.join(",")));
+        }
+        f.write_str(&values.join(" "))
+    }
+}
+
+impl MultipartError {
+    /// 拒绝时返回给客户端的内容, 客户端可能仍在发送body, 关闭该连接
+    pub fn response(&self) -> Response<Body> {
+        let (status, text) = match self {
+            Self::PartTooLarge | Self::TotalTooLarge => (413, "Payload Too Large"),
+            Self::TypeNotAllowed(_) => (415, "Unsupported Media Type"),
+            Self::NameNotAllowed(_) | Self::Malformed => (400, "Bad Request"),
+        };
+        Response::text()
+            .status(status)
+            .header(HeaderName::CONNECTION, "close")
+            .body(text)
+            .unwrap()
+            .into_type()
+    }
+}
+
+impl MultipartLimit {
+    pub fn new(boundary: &str, config: ConfigMultipart) -> Self {
+        Self {
+            config,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            state: State::Preamble,
+            buf: vec![],
+            total: 0,
+        }
+    }
+
+    /// 取`multipart/form-data`的boundary, 非multipart的请求返回None
+    pub fn boundary<T: webparse::Serialize>(req: &Request<T>) -> Option<Result<String, MultipartError>> {
+        let value = req.headers().get_str_value(&HeaderName::CONTENT_TYPE)?;
+        let ty = value.split(';').next().unwrap_or_default().trim();
+        if !ty.eq_ignore_ascii_case("multipart/form-data") {
+            return None;
+        }
+        Some(
+            get_param(&value, "boundary")
+                .filter(|b| !b.is_empty() && b.len() <= 70)
+                .ok_or(MultipartError::Malformed),
+        )
+    }
+
+    fn check_part_size(&self, size: u64) -> Result<(), MultipartError> {
+        match self.config.part {
+            Some(part) if size > part => Err(MultipartError::PartTooLarge),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_headers(&self, head: &[u8]) -> Result<(), MultipartError> {
+        let head = String::from_utf8_lossy(head);
+        let mut disposition = None;
+        let mut content_type = None;
+        for line in head.split("\r\n") {
+            let Some((k, v)) = line.split_once(':') else {
+                continue;
+            };
+            if k.trim().eq_ignore_ascii_case("content-disposition") {
+                disposition = Some(v.trim().to_string());
+            } else if k.trim().eq_ignore_ascii_case("content-type") {
+                content_type = Some(v.trim().to_string());
+            }
+        }
+        let name = disposition.as_ref().and_then(|d| get_param(d, "name"));
+        if !self.config.names.is_empty() {
+            let name = name.unwrap_or_default();
+            if !self.config.names.contains(&name) {
+                return Err(MultipartError::NameNotAllowed(name));
+            }
+        }
+        let is_file = disposition
+            .as_ref()
+            .map(|d| get_param(d, "filename").is_some() || get_param(d, "filename*").is_some())
+            .unwrap_or(false);
+        if is_file {
+            let ty = content_type.unwrap_or("application/octet-stream".to_string());
+            if !self.config.is_type_allow(&ty) {
+                return Err(MultipartError::TypeNotAllowed(ty));
+            }
+        }
+        Ok(())
+    }
+
+    /// 解析新读取到的数据, 超出限制时返回错误
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), MultipartError> {
+        self.total += data.len() as u64;
+        if let Some(total) = self.config.total {
+            if self.total > total {
+                return Err(MultipartError::TotalTooLarge);
+            }
+        }
+        if let State::End = self.state {
+            return Ok(());
+        }
+        self.buf.extend_from_slice(data);
+        let len = self.delimiter.len();
+        loop {
+            match self.state {
+                State::Preamble => match find(&self.buf, &self.delimiter[2..]) {
+                    Some(i) => {
+                        self.buf.drain(..i + len - 2);
+                        self.state = State::Boundary;
+                    }
+                    None => {
+                        let keep = self.buf.len().saturating_sub(len - 3);
+                        self.buf.drain(..keep);
+                        return Ok(());
+                    }
+                },
+                State::Boundary => {
+                    // 分隔符之后允许有空白
+                    let space = self.buf.iter().take_while(|c| **c == b' ' || **c == b'\t').count();
+                    self.buf.drain(..space);
+                    if self.buf.len() < 2 {
+                        return Ok(());
+                    }
+                    if self.buf.starts_with(b"--") {
+                        self.buf.clear();
+                        self.state = State::End;
+                        return Ok(());
+                    }
+                    if !self.buf.starts_with(b"\r\n") {
+                        return Err(MultipartError::Malformed);
+                    }
+                    self.buf.drain(..2);
+                    self.state = State::Headers;
+                }
+                State::Headers => {
+                    let end = if self.buf.starts_with(b"\r\n") {
+                        Some(0)
+                    } else {
+                        find(&self.buf, b"\r\n\r\n").map(|i| i + 2)
+                    };
+                    match end {
+                        Some(i) => {
+                            self.check_headers(&self.buf[..i])?;
+                            self.buf.drain(..i + 2);
+                            self.state = State::Body(0);
+                        }
+                        None if self.buf.len() > MAX_PART_HEADER => {
+                            return Err(MultipartError::Malformed)
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                State::Body(size) => match find(&self.buf, &self.delimiter) {
+                    Some(i) => {
+                        self.check_part_size(size + i as u64)?;
+                        self.buf.drain(..i + len);
+                        self.state = State::Boundary;
+                    }
+                    None => {
+                        // 保留可能为分隔符开头的部分
+                        let done = self.buf.len().saturating_sub(len - 1);
+                        let size = size + done as u64;
+                        self.check_part_size(size)?;
+                        self.buf.drain(..done);
+                        self.state = State::Body(size);
+                        return Ok(());
+                    }
+                },
+                State::End => return Ok(()),
+            }
+        }
+    }
+
+    /// 替换请求的body, 边转发边检查, 超出限制时停止转发并通过返回的Receiver通知
+    ///
+    /// 非multipart的请求返回None, 已知长度超出限制或者无boundary时直接返回错误
+    pub fn limit_request(
+        req: &mut Request<Body>,
+        config: &ConfigMultipart,
+    ) -> Result<Option<oneshot::Receiver<MultipartError>>, MultipartError> {
+        let boundary = match Self::boundary(req) {
+            Some(boundary) => boundary?,
+            None => return Ok(None),
+        };
+        let len = req.get_body_len();
+        if let Some(total) = config.total {
+            if len > 0 && len as u64 > total {
+                return Err(MultipartError::TotalTooLarge);
+            }
+        }
+        let mut limit = Self::new(&boundary, config.clone());
+        let (sender, receiver) = channel(10);
+        let (err_sender, err_receiver) = oneshot::channel();
+        let mut body = std::mem::replace(
+            req.body_mut(),
+            Body::new(receiver, BinaryMut::new(), false),
+        );
+        tokio::spawn(async move {
+            let mut data = vec![0u8; READ_BUFFER];
+            loop {
+                let n = match read_body_data(&mut body, &mut data).await {
+                    Ok(n) => n,
+                    Err(_) => return,
+                };
+                if n == 0 {
+                    let _ = sender.send((true, Binary::new())).await;
+                    return;
+                }
+                if let Err(e) = limit.feed(&data[..n]) {
+                    log::warn!("multipart的请求body超出限制:{:?}, 停止转发", e);
+                    let _ = err_sender.send(e);
+                    return;
+                }
+                if sender.send((false, Binary::from(data[..n].to_vec()))).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Some(err_receiver))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::{BinaryMut, Request};
+    use wenmeng::Body;
+
+    use super::{ConfigMultipart, MultipartError, MultipartLimit};
+
+    const BOUNDARY: &str = "----wmproxy7MA4YWxk";
+
+    fn part(name: &str, filename: Option<&str>, ty: Option<&str>, data: &[u8]) -> Vec<u8> {
+        let mut head = format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"", BOUNDARY, name);
+        if let Some(filename) = filename {
+            head += &format!("; filename=\"{}\"", filename);
+        }
+        if let Some(ty) = ty {
+            head += &format!("\r\nContent-Type: {}", ty);
+        }
+        let mut value = format!("{}\r\n\r\n", head).into_bytes();
+        value.extend_from_slice(data);
+        value.extend_from_slice(b"\r\n");
+        value
+    }
+
+    fn check(config: &str, body: &[u8], chunk: usize) -> Result<(), MultipartError> {
+        let mut limit = MultipartLimit::new(BOUNDARY, config.parse().unwrap());
+        for data in body.chunks(chunk) {
+            limit.feed(data)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = "part=1k total=10m names=avatar,doc types=image/*,application/pdf"
+            .parse::<ConfigMultipart>()
+            .unwrap();
+        assert_eq!(config.part, Some(1024));
+        assert_eq!(config.names, vec!["avatar", "doc"]);
+        assert_eq!(format!("{}", config).parse::<ConfigMultipart>().unwrap(), config);
+        assert!(config.is_type_allow("image/png"));
+        assert!(config.is_type_allow("Application/PDF; charset=binary"));
+        assert!(!config.is_type_allow("text/html"));
+        assert!("part=1k size".parse::<ConfigMultipart>().is_err());
+    }
+
+    #[test]
+    fn part_limit_mid_stream() {
+        let mut body = part("desc", None, None, b"hello");
+        body.extend(part("avatar", Some("a.png"), Some("image/png"), &vec![b'-'; 1000]));
+        let end = format!("--{}--\r\n", BOUNDARY);
+        body.extend_from_slice(end.as_bytes());
+        // 分隔符被拆分在不同的数据块中
+        for chunk in [1, 7, 64, 4096] {
+            assert_eq!(check("part=1000", &body, chunk), Ok(()), "{}", chunk);
+            assert_eq!(check("total=2k types=image/*", &body, chunk), Ok(()));
+        }
+
+        let mut body = part("desc", None, None, b"hello");
+        body.extend_from_slice(
+            format!("--{}\r\nContent-Disposition: form-data; name=\"avatar\"\r\n\r\n", BOUNDARY).as_bytes(),
+        );
+        let mut limit = MultipartLimit::new(BOUNDARY, "part=1000".parse().unwrap());
+        limit.feed(&body).unwrap();
+        // part未结束时, 超出大小即返回错误
+        limit.feed(&vec![b'a'; 900]).unwrap();
+        assert_eq!(limit.feed(&vec![b'a'; 200]), Err(MultipartError::PartTooLarge));
+    }
+
+    #[test]
+    fn name_and_type() {
+        let body = part("avatar", Some("a.html"), None, b"<html>");
+        assert_eq!(
+            check("types=image/*", &body, 16),
+            Err(MultipartError::TypeNotAllowed("application/octet-stream".to_string()))
+        );
+        let body = part("other", None, Some("text/html"), b"<html>");
+        assert_eq!(check("types=image/*", &body, 16), Ok(()));
+        assert_eq!(
+            check("names=avatar", &body, 16),
+            Err(MultipartError::NameNotAllowed("other".to_string()))
+        );
+        assert_eq!(check("total=10", &body, 16), Err(MultipartError::TotalTooLarge));
+    }
+
+    #[tokio::test]
+    async fn limit_request_stream() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        let mut req = Request::builder()
+            .method("POST")
+            .url("/upload")
+            .header("Content-Type", format!("multipart/form-data; boundary=\"{}\"", BOUNDARY))
+            .header("Content-Length", "100000")
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap();
+        let config = "part=1k".parse().unwrap();
+        let mut notify = MultipartLimit::limit_request(&mut req, &config).unwrap().unwrap();
+
+        let mut head = part("avatar", Some("a.png"), Some("image/png"), b"");
+        head.truncate(head.len() - 2);
+        sender.send((false, head.into())).await.unwrap();
+        sender.send((false, vec![b'a'; 800].into())).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(notify.try_recv().is_err());
+        // 未发送完body即可得到超出限制的通知
+        sender.send((false, vec![b'a'; 800].into())).await.unwrap();
+        let err = tokio::time::timeout(std::time::Duration::from_secs(1), notify).await;
+        let err = err.unwrap().unwrap();
+        assert_eq!(err, MultipartError::PartTooLarge);
+        assert_eq!(err.response().status().as_u16(), 413);
+    }
+}
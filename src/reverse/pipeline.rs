@@ -0,0 +1,460 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/22 10:26:47
+
+use std::{
+    fmt::Display,
+    io,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll, Waker},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc::channel,
+};
+use webparse::{Binary, BinaryMut, HeaderName, Method, Request, Response, Version};
+use wenmeng::Body;
+
+use super::{
+    body_buffer::{read_body_data, READ_BUFFER},
+    Framing,
+};
+
+/// 拒绝的走私请求数
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// 请求头的最大长度, 超出时不再解析, 交由http处理
+const MAX_HEAD: usize = 64 * 1024;
+
+/// HTTP/1.1管线化(pipelining)的处理方式, 请求始终按顺序处理, 按顺序返回
+///
+/// * `off` 默认值, 上一个请求返回完毕后才读取下一个请求
+/// * `on` 上一个请求处理完毕即读取下一个请求, 流式的返回可能仍在发送中
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConfigPipeline {
+    #[default]
+    Off,
+    On,
+}
+
+impl FromStr for ConfigPipeline {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.trim().to_ascii_lowercase() {
+            "off" => Ok(ConfigPipeline::Off),
+            "on" => Ok(ConfigPipeline::On),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("pipelining的值无效:{}", s),
+            )),
+        }
+    }
+}
+
+impl Display for ConfigPipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigPipeline::Off => f.write_str("off"),
+            ConfigPipeline::On => f.write_str("on"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct InnerPipeline {
+    /// 已完整读取的请求数
+    read: u64,
+    /// 已处理完毕的请求数
+    done: u64,
+    /// 后续的数据不再交给http解析, 返回后关闭连接
+    held: bool,
+    waker: Option<Waker>,
+}
+
+/// 同一连接上请求的读取控制, 当前请求处理完毕后才交出下一个请求的数据
+#[derive(Clone, Default)]
+pub struct PipelineNotify {
+    inner: Arc<Mutex<InnerPipeline>>,
+}
+
+impl PipelineNotify {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rejected_total() -> u64 {
+        REJECTED.load(Ordering::Relaxed)
+    }
+
+    /// 后续的数据是否已不再处理, 此时应关闭连接
+    pub fn is_held(&self) -> bool {
+        self.inner.lock().map(|i| i.held).unwrap_or(true)
+    }
+
+    /// 当前请求已处理完毕, 按配置立即或者等返回的body结束后读取下一个请求
+    pub fn finish(&self, mode: ConfigPipeline, res: &mut Response<Body>) {
+        if mode == ConfigPipeline::On || res.body().is_end() {
+            self.release();
+            return;
+        }
+        let (sender, receiver) = channel(10);
+        let mut body = std::mem::replace(
+            res.body_mut(),
+            Body::new(receiver, BinaryMut::new(), false),
+        );
+        // 读取到的数据已按原body的编码处理, 发送时原样输出
+        let method = body.get_now_compress();
+        res.body_mut().set_origin_compress_method(method);
+        res.body_mut().add_compress_method(method);
+        let notify = self.clone();
+        tokio::spawn(async move {
+            let mut data = vec![0u8; READ_BUFFER];
+            loop {
+                let n = match read_body_data(&mut body, &mut data).await {
+                    Ok(0) => {
+                        let _ = sender.send((true, Binary::new())).await;
+                        break;
+                    }
+                    Ok(n) => n,
+                    Err(_) => break,
+                };
+                if sender.send((false, Binary::from(data[..n].to_vec()))).await.is_err() {
+                    break;
+                }
+            }
+            notify.release();
+        });
+    }
+
+    fn release(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.done += 1;
+            if let Some(waker) = inner.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// 请求边界的解析状态, 与http解析请求的方式保持一致
+enum Scan {
+    /// 读取请求头
+    Head(Vec<u8>),
+    /// 固定长度的body
+    Fixed(usize),
+    /// chunked的长度行
+    ChunkSize(Vec<u8>),
+    /// chunk的数据, 含结尾的CRLF
+    ChunkData(usize),
+    /// 最后一个chunk后的trailer
+    Trailer(Vec<u8>),
+    /// 不再区分请求, 如升级协议, HTTP/2或者读取至连接关闭的body
+    Passthrough,
+}
+
+/// 客户端的连接, 仅在当前请求处理完毕后交出下一个请求的数据
+///
+/// 同一次读取中收到多个请求时http仅解析第一个, 此处按请求的边界拆分后依次交出
+/// 长度定义有歧义的请求及`Connection: close`后的数据不再处理, 防止前后端对请求边界的理解不一致
+pub struct PipelineStream<T> {
+    io: T,
+    notify: PipelineNotify,
+    scan: Scan,
+    /// 已读取未交出的数据
+    buf: Vec<u8>,
+    /// 当前请求结束后不再交出数据
+    hold_after: bool,
+}
+
+impl<T> PipelineStream<T> {
+    pub fn new(io: T, notify: PipelineNotify) -> Self {
+        Self {
+            io,
+            notify,
+            scan: Scan::Head(vec![]),
+            buf: vec![],
+            hold_after: false,
+        }
+    }
+
+    /// 解析data中属于当前请求的部分, 返回长度及当前请求是否已结束
+    fn scan(&mut self, data: &[u8]) -> (usize, bool) {
+        let mut pos = 0;
+        while pos < data.len() {
+            let left = &data[pos..];
+            match &mut self.scan {
+                Scan::Passthrough => return (data.len(), false),
+                Scan::Head(head) => {
+                    let old = head.len();
+                    head.extend_from_slice(left);
+                    let mut request = Request::new();
+                    match request.parse_buffer(&mut BinaryMut::from(&head[..])) {
+                        Ok(size) if !request.is_partial() => {
+                            pos += size - old;
+                            let next = self.after_head(&request);
+                            let end = matches!(next, Scan::Head(_));
+                            self.scan = next;
+                            if end {
+                                return (pos, true);
+                            }
+                        }
+                        Err(e) if !e.is_partial() => self.scan = Scan::Passthrough,
+                        _ if head.len() > MAX_HEAD => self.scan = Scan::Passthrough,
+                        _ => return (data.len(), false),
+                    }
+                }
+                Scan::Fixed(size) => {
+                    let n = left.len().min(*size);
+                    *size -= n;
+                    pos += n;
+                    if *size == 0 {
+                        self.scan = Scan::Head(vec![]);
+                        return (pos, true);
+                    }
+                }
+                Scan::ChunkSize(line) => {
+                    let (n, full) = Self::read_line(line, left);
+                    pos += n;
+                    if full {
+                        let text = String::from_utf8_lossy(line);
+                        let size = text.split(';').next().unwrap_or("").trim();
+                        self.scan = match usize::from_str_radix(size, 16) {
+                            Ok(0) => Scan::Trailer(vec![]),
+                            Ok(size) => Scan::ChunkData(size.saturating_add(2)),
+                            Err(_) => Scan::Passthrough,
+                        };
+                    }
+                }
+                Scan::ChunkData(size) => {
+                    let n = left.len().min(*size);
+                    *size -= n;
+                    pos += n;
+                    if *size == 0 {
+                        self.scan = Scan::ChunkSize(vec![]);
+                    }
+                }
+                Scan::Trailer(line) => {
+                    let (n, full) = Self::read_line(line, left);
+                    pos += n;
+                    if full {
+                        if line.iter().all(|b| *b == b'\r' || *b == b'\n') {
+                            self.scan = Scan::Head(vec![]);
+                            return (pos, true);
+                        }
+                        line.clear();
+                    }
+                }
+            }
+        }
+        (pos, false)
+    }
+
+    /// 读取一行, 返回使用的长度及是否已读取到行尾
+    fn read_line(line: &mut Vec<u8>, data: &[u8]) -> (usize, bool) {
+        match data.iter().position(|b| *b == b'\n') {
+            Some(i) => {
+                line.extend_from_slice(&data[..=i]);
+                (i + 1, true)
+            }
+            None => {
+                line.extend_from_slice(data);
+                (data.len(), false)
+            }
+        }
+    }
+
+    /// 请求头解析完毕后确定body的读取方式
+    fn after_head<B: webparse::Serialize>(&mut self, req: &Request<B>) -> Scan {
+        let headers = req.headers();
+        if req.method() == &Method::Connect || headers.get_upgrade_protocol().is_some() {
+            return Scan::Passthrough;
+        }
+        let chunked = headers.get_option_value(&HeaderName::TRANSFER_ENCODING).is_some();
+        // 长度有歧义的请求将返回400, 不带body的方法中的chunked不会被当作body解析
+        let reason = match Framing::check_headers(req) {
+            Err(reason) => Some(reason),
+            Ok(()) if chunked && req.method().is_nobody() => Some("transfer-encoding without body"),
+            Ok(()) => None,
+        };
+        if let Some(reason) = reason {
+            log::warn!("拒绝可能的请求走私:{} {}", req.path(), reason);
+            REJECTED.fetch_add(1, Ordering::Relaxed);
+            self.hold_after = true;
+            return Scan::Head(vec![]);
+        }
+        let close = match req.version() {
+            Version::Http10 => !req.is_keep_alive(),
+            _ => headers.is_contains(&HeaderName::CONNECTION, b"close"),
+        };
+        if close {
+            self.hold_after = true;
+        }
+        let len = req.get_body_len();
+        if len > 0 {
+            Scan::Fixed(len as usize)
+        } else if req.method().is_nobody() {
+            Scan::Head(vec![])
+        } else if chunked {
+            Scan::ChunkSize(vec![])
+        } else {
+            Scan::Passthrough
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for PipelineStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if let Ok(mut inner) = this.notify.inner.lock() {
+                if inner.held || inner.read > inner.done {
+                    inner.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+            if this.buf.is_empty() {
+                if let Scan::Passthrough = this.scan {
+                    return Pin::new(&mut this.io).poll_read(cx, buf);
+                }
+                let mut data = vec![0u8; buf.remaining().max(1)];
+                let mut read = ReadBuf::new(&mut data);
+                ready!(Pin::new(&mut this.io).poll_read(cx, &mut read))?;
+                if read.filled().is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                this.buf = read.filled().to_vec();
+            }
+            let len = this.buf.len().min(buf.remaining());
+            let data = this.buf[..len].to_vec();
+            let (n, end) = this.scan(&data);
+            buf.put_slice(&data[..n]);
+            this.buf.drain(..n);
+            if end {
+                if let Ok(mut inner) = this.notify.inner.lock() {
+                    inner.read += 1;
+                    inner.held = this.hold_after;
+                }
+            }
+            if n > 0 || len == 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for PipelineStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+        sync::mpsc::channel,
+        time::timeout,
+    };
+    use webparse::{Binary, BinaryMut, Response};
+    use wenmeng::Body;
+
+    use super::{ConfigPipeline, PipelineNotify, PipelineStream};
+
+    async fn next(stream: &mut PipelineStream<DuplexStream>) -> Option<String> {
+        let mut buf = [0u8; 1024];
+        match timeout(Duration::from_millis(100), stream.read(&mut buf)).await {
+            Ok(n) => Some(String::from_utf8_lossy(&buf[..n.unwrap()]).to_string()),
+            Err(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn split_requests() {
+        assert_eq!("on".parse::<ConfigPipeline>().unwrap(), ConfigPipeline::On);
+        assert_eq!(ConfigPipeline::default().to_string(), "off");
+        assert!("auto".parse::<ConfigPipeline>().is_err());
+
+        let (mut client, server) = duplex(1024);
+        let notify = PipelineNotify::new();
+        let mut stream = PipelineStream::new(server, notify.clone());
+        let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;ext=1\r\nabc\r\n0\r\nX-Sum: 1\r\n\r\n";
+        let fixed = "PUT / HTTP/1.1\r\nContent-Length: 4\r\n\r\nGET ";
+        let last = "GET /b HTTP/1.1\r\n\r\n";
+        client
+            .write_all(format!("{}{}{}", chunked, fixed, last).as_bytes())
+            .await
+            .unwrap();
+        let mut res = Response::builder().body(Body::empty()).unwrap();
+        for req in [chunked, fixed, last] {
+            assert_eq!(next(&mut stream).await.as_deref(), Some(req));
+            // 当前请求处理完毕前不交出下一个请求
+            assert_eq!(next(&mut stream).await, None);
+            notify.finish(ConfigPipeline::Off, &mut res);
+        }
+        assert!(!notify.is_held());
+    }
+
+    #[tokio::test]
+    async fn release_after_body() {
+        let (mut client, server) = duplex(1024);
+        let notify = PipelineNotify::new();
+        let mut stream = PipelineStream::new(server, notify.clone());
+        client
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(next(&mut stream).await.as_deref(), Some("GET /a HTTP/1.1\r\n\r\n"));
+
+        // 默认等返回的body结束后才读取下一个请求
+        let (sender, receiver) = channel(10);
+        let mut res = Response::builder()
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap();
+        notify.finish(ConfigPipeline::Off, &mut res);
+        sender.send((false, Binary::from("a"))).await.unwrap();
+        assert_eq!(next(&mut stream).await, None);
+        sender.send((true, Binary::from("b"))).await.unwrap();
+        assert_eq!(next(&mut stream).await.as_deref(), Some("GET /b HTTP/1.1\r\n\r\n"));
+
+        // 配置on时处理完毕即读取
+        let (_sender, receiver) = channel(10);
+        let mut res = Response::builder()
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap();
+        notify.finish(ConfigPipeline::On, &mut res);
+        assert_eq!(next(&mut stream).await.as_deref(), Some("GET /c HTTP/1.1\r\n\r\n"));
+    }
+}
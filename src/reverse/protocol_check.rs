@@ -0,0 +1,197 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/24 09:42:17
+
+use std::{fmt::Display, io, str::FromStr, time::Duration};
+
+use tokio::net::TcpStream;
+
+/// 请求方法的最大长度, 超过时认为不是HTTP
+const MAX_METHOD_LEN: usize = 16;
+/// 等待客户端发送请求行的最长时间
+const PEEK_TIMEOUT: Duration = Duration::from_secs(10);
+/// 严格模式下允许的请求方法, `PRI`为HTTP/2的连接前言
+const METHODS: [&[u8]; 10] = [
+    b"GET", b"POST", b"HEAD", b"PUT", b"DELETE", b"OPTIONS", b"PATCH", b"TRACE", b"CONNECT", b"PRI",
+];
+
+/// http端口接收连接时对首个请求行的检查, 明显不是HTTP的连接直接关闭, 减少扫描器等占用的资源
+/// `off`不检查, `lenient`仅要求以合法的token加空格开头, `strict`要求为已知的请求方法
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConfigProtocolCheck {
+    #[default]
+    Off,
+    Lenient,
+    Strict,
+}
+
+impl ConfigProtocolCheck {
+    /// 检查已收到的数据, 数据不足以判断时返回None
+    pub fn check(&self, data: &[u8]) -> Option<bool> {
+        if *self == ConfigProtocolCheck::Off {
+            return Some(true);
+        }
+        let len = data.iter().take(MAX_METHOD_LEN + 1).position(|c| *c == b' ');
+        let method = match len {
+            Some(0) => return Some(false),
+            Some(len) => &data[..len],
+            None if data.len() > MAX_METHOD_LEN => return Some(false),
+            None => data,
+        };
+        // 方法中仅允许大写字母, 兼容扩展的方法如`PROPFIND`
+        if !method.iter().all(|c| c.is_ascii_uppercase()) {
+            return Some(false);
+        }
+        match (self, len) {
+            (ConfigProtocolCheck::Strict, Some(_)) => Some(METHODS.contains(&method)),
+            (ConfigProtocolCheck::Strict, None) if !METHODS.iter().any(|m| m.starts_with(method)) => {
+                Some(false)
+            }
+            (_, Some(_)) => Some(true),
+            (_, None) => None,
+        }
+    }
+
+    /// 以peek的方式读取请求行的开头, 不消耗连接中的数据, 连接关闭或超时返回false
+    pub async fn peek(&self, stream: &TcpStream) -> bool {
+        if *self == ConfigProtocolCheck::Off {
+            return true;
+        }
+        let mut buf = [0u8; MAX_METHOD_LEN + 1];
+        let mut last = 0;
+        let work = async {
+            loop {
+                let n = stream.peek(&mut buf).await.ok()?;
+                if n == 0 {
+                    return None;
+                }
+                if let Some(pass) = self.check(&buf[..n]) {
+                    return Some(pass);
+                }
+                // peek在有数据时立即返回, 无新数据时稍作等待
+                if n == last {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                last = n;
+            }
+        };
+        tokio::time::timeout(PEEK_TIMEOUT, work)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or(false)
+    }
+}
+
+impl FromStr for ConfigProtocolCheck {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.trim().to_ascii_lowercase() {
+            "off" => Ok(ConfigProtocolCheck::Off),
+            "lenient" => Ok(ConfigProtocolCheck::Lenient),
+            "strict" => Ok(ConfigProtocolCheck::Strict),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("protocol_check的值无效:{}", s),
+            )),
+        }
+    }
+}
+
+impl Display for ConfigProtocolCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigProtocolCheck::Off => f.write_str("off"),
+            ConfigProtocolCheck::Lenient => f.write_str("lenient"),
+            ConfigProtocolCheck::Strict => f.write_str("strict"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::ConfigProtocolCheck;
+    use crate::{data::ShutdownStream, reverse::HttpConfig};
+
+    #[test]
+    fn check_request_line() {
+        let lenient = ConfigProtocolCheck::Lenient;
+        let strict = ConfigProtocolCheck::Strict;
+        for (data, l, s) in [
+            (&b"GET / HTTP/1.1\r\n"[..], Some(true), Some(true)),
+            (b"PRI * HTTP/2.0", Some(true), Some(true)),
+            (b"PROPFIND /a HTTP/1.1", Some(true), Some(false)),
+            (b"GE", None, None),
+            (b"GX", None, Some(false)),
+            (b"\x16\x03\x01\x02\x00", Some(false), Some(false)),
+            (b"get / HTTP/1.1", Some(false), Some(false)),
+            (b" GET /", Some(false), Some(false)),
+            (b"AAAAAAAAAAAAAAAAAAAAAAAA", Some(false), Some(false)),
+        ] {
+            assert_eq!(lenient.check(data), l, "{:?}", data);
+            assert_eq!(strict.check(data), s, "{:?}", data);
+        }
+        assert_eq!(ConfigProtocolCheck::Off.check(b"\x00\x01"), Some(true));
+        assert_eq!("Strict".parse::<ConfigProtocolCheck>().unwrap(), strict);
+        assert!("on".parse::<ConfigProtocolCheck>().is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_non_http() {
+        let config = r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            protocol_check = "strict"
+            [[server.location]]
+            rule = "/"
+            static_response = "ok"
+        "#;
+        let mut http = toml::from_str::<HttpConfig>(config).unwrap();
+        http.after_load_option().unwrap();
+        let servers = http.convert_server_config();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((conn, addr)) = listener.accept().await {
+                tokio::spawn(HttpConfig::accept_http(servers.clone(), ShutdownStream::new(conn), addr));
+            }
+        });
+
+        let mut valid = TcpStream::connect(addr).await.unwrap();
+        valid
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        valid.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200") && res.ends_with("ok"), "{}", res);
+
+        // 非HTTP的数据立即关闭连接, 不等待解析超时
+        let mut garbage = TcpStream::connect(addr).await.unwrap();
+        garbage.write_all(b"\x16\x03\x01\x00\xa5\x01\x00\x00\xa1\x03\x03").await.unwrap();
+        let mut buf = vec![];
+        let n = tokio::time::timeout(Duration::from_secs(1), garbage.read_to_end(&mut buf))
+            .await
+            .unwrap()
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+    }
+}
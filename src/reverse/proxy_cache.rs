@@ -0,0 +1,533 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/28 10:12:36
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::channel;
+use webparse::{BinaryMut, HeaderMap, HeaderName, Method, Request, Response, Version};
+use wenmeng::{Body, ProtResult};
+
+use super::body_buffer::{forward_body, read_body_data, READ_BUFFER};
+use crate::{ConfigDuration, ConfigSize};
+
+/// 默认缓存的最大body大小
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024;
+/// 默认最多缓存的条目数
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+/// 返回过期的缓存时附带的头
+const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+
+/// 已缓存的上游返回
+#[derive(Debug)]
+struct CacheEntry {
+    status: u16,
+    version: Version,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    stored: Instant,
+    /// 未过期的时间
+    fresh: Duration,
+    stale_if_error: Duration,
+    stale_while_revalidate: Duration,
+    /// 已在后台重新请求上游, 期间不再重复发起
+    revalidating: bool,
+}
+
+impl CacheEntry {
+    fn response(&self, stale: bool) -> Response<Body> {
+        let mut binary = BinaryMut::with_capacity(self.body.len());
+        binary.put_slice(&self.body);
+        let mut res = Response::builder()
+            .status(self.status)
+            .version(self.version)
+            .body(Body::new_binary(binary))
+            .unwrap();
+        *res.headers_mut() = self.headers.clone();
+        res.headers_mut()
+            .insert(HeaderName::AGE, self.stored.elapsed().as_secs().to_string());
+        if stale {
+            res.headers_mut().insert(HeaderName::WARNING, STALE_WARNING);
+        }
+        res
+    }
+
+    /// 超过该时间后不再以任何方式使用
+    fn max_age(&self) -> Duration {
+        self.fresh + self.stale_if_error.max(self.stale_while_revalidate)
+    }
+}
+
+/// 查找缓存的结果
+pub enum CacheLookup {
+    /// 直接返回缓存, 包括已在后台更新中的过期缓存
+    Hit(Response<Body>),
+    /// 过期但未超过stale_while_revalidate, 返回缓存并需在后台重新请求上游
+    Revalidate(Response<Body>),
+    /// 需请求上游
+    Miss,
+}
+
+/// location中上游返回的缓存, 如`valid=5min stale_if_error=1h stale_while_revalidate=30s`
+///
+/// 仅缓存GET请求的200返回, 以Host及请求的路径为键, 以下情况不做缓存:
+/// 请求带Authorization或Cookie, 返回带Set-Cookie或`Cache-Control`含no-store|no-cache|private,
+/// 已压缩的返回, 带`Vary`(未压缩返回的`Vary: Accept-Encoding`除外)的返回, 超过max_size(默认1m)的body
+///
+/// 返回中`Cache-Control`的`s-maxage`|`max-age`优先于valid, `stale-if-error`|`stale-while-revalidate`不超过配置的值
+/// * `stale_if_error` 上游失败或返回5xx时, 过期未超过该时间的缓存附带`Warning: 110`返回
+/// * `stale_while_revalidate` 过期未超过该时间时直接返回缓存, 同时在后台重新请求上游进行更新
+#[derive(Debug, Clone)]
+pub struct ConfigProxyCache {
+    pub valid: Duration,
+    pub stale_if_error: Duration,
+    pub stale_while_revalidate: Duration,
+    pub max_size: u64,
+    pub max_entries: usize,
+    /// 克隆后共享
+    entries: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+impl ConfigProxyCache {
+    /// 可使用缓存的请求对应的键
+    pub fn cache_key(req: &Request<Body>) -> Option<String> {
+        if *req.method() != Method::Get {
+            return None;
+        }
+        let headers = req.headers();
+        if headers.contains(&HeaderName::AUTHORIZATION) || headers.contains(&HeaderName::COOKIE) {
+            return None;
+        }
+        if let Some(control) = headers.get_str_value(&HeaderName::CACHE_CONTROL) {
+            if Self::has_directive(&control, "no-store") || Self::has_directive(&control, "no-cache") {
+                return None;
+            }
+        }
+        Some(format!("{}{}", req.get_host().unwrap_or_default(), req.path()))
+    }
+
+    fn has_directive(control: &str, name: &str) -> bool {
+        control
+            .split(',')
+            .any(|v| v.trim().split('=').next().unwrap_or_default().eq_ignore_ascii_case(name))
+    }
+
+    fn directive_secs(control: &str, name: &str) -> Option<Duration> {
+        control.split(',').find_map(|v| {
+            let (k, v) = v.trim().split_once('=')?;
+            if !k.trim().eq_ignore_ascii_case(name) {
+                return None;
+            }
+            v.trim().trim_matches('"').parse::<u64>().ok().map(Duration::from_secs)
+        })
+    }
+
+    pub fn lookup(&self, key: &str) -> CacheLookup {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return CacheLookup::Miss,
+        };
+        let entry = match entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return CacheLookup::Miss,
+        };
+        let age = entry.stored.elapsed();
+        if age < entry.fresh {
+            return CacheLookup::Hit(entry.response(false));
+        }
+        if age < entry.fresh + entry.stale_while_revalidate {
+            if entry.revalidating {
+                return CacheLookup::Hit(entry.response(true));
+            }
+            entry.revalidating = true;
+            return CacheLookup::Revalidate(entry.response(true));
+        }
+        if age >= entry.max_age() {
+            entries.remove(key);
+        }
+        CacheLookup::Miss
+    }
+
+    /// 处理上游的返回, 失败或5xx时尝试以过期的缓存返回, 可缓存的返回存入缓存
+    pub async fn finish(&self, key: &str, res: ProtResult<Response<Body>>) -> ProtResult<Response<Body>> {
+        let is_error = match &res {
+            Ok(res) => res.status().is_server_error(),
+            Err(_) => true,
+        };
+        if is_error {
+            if let Some(stale) = self.stale_if_error(key) {
+                log::trace!("上游返回错误, 以过期的缓存返回:{}", key);
+                return Ok(stale);
+            }
+            return res;
+        }
+        let mut res = res?;
+        self.store(key, &mut res).await;
+        Ok(res)
+    }
+
+    fn stale_if_error(&self, key: &str) -> Option<Response<Body>> {
+        let entries = self.entries.lock().ok()?;
+        let entry = entries.get(key)?;
+        if entry.stored.elapsed() < entry.fresh + entry.stale_if_error {
+            Some(entry.response(true))
+        } else {
+            None
+        }
+    }
+
+    /// 后台更新结束, 未能存入缓存时保留原有的缓存, 以便之后再次更新
+    pub async fn finish_revalidate(&self, key: &str, res: ProtResult<Response<Body>>) {
+        if let Ok(mut res) = res {
+            if !res.status().is_server_error() && self.store(key, &mut res).await {
+                return;
+            }
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            if let Some(entry) = entries.get_mut(key) {
+                entry.revalidating = false;
+            }
+        }
+    }
+
+    /// 读取完整的body并存入缓存, 不可缓存时原样返回
+    pub async fn store(&self, key: &str, res: &mut Response<Body>) -> bool {
+        if res.status().as_u16() != 200 || res.headers().contains(&HeaderName::SET_COOKIE) {
+            return false;
+        }
+        let encoded = res
+            .headers()
+            .get_str_value(&HeaderName::CONTENT_ENCODING)
+            .map(|e| !e.eq_ignore_ascii_case("identity"))
+            .unwrap_or(false);
+        if encoded {
+            return false;
+        }
+        // 键中不含请求头, 按其它请求头区分的返回不能缓存
+        if let Some(vary) = res.headers().get_str_value(&HeaderName::VARY) {
+            if !vary
+                .split(',')
+                .all(|v| v.trim().is_empty() || v.trim().eq_ignore_ascii_case("accept-encoding"))
+            {
+                return false;
+            }
+        }
+        let control = res
+            .headers()
+            .get_str_value(&HeaderName::CACHE_CONTROL)
+            .unwrap_or_default();
+        if ["no-store", "no-cache", "private"]
+            .iter()
+            .any(|d| Self::has_directive(&control, d))
+        {
+            return false;
+        }
+        let len = res.get_body_len();
+        if len > 0 && len as u64 > self.max_size {
+            return false;
+        }
+        let mut data = BinaryMut::new();
+        let mut buf = vec![0u8; READ_BUFFER];
+        loop {
+            match read_body_data(res.body_mut(), &mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    data.put_slice(&buf[..n]);
+                }
+                Err(e) => {
+                    log::warn!("读取待缓存的body失败:{:?}", e);
+                    *res.body_mut() = Body::new_binary(data);
+                    return false;
+                }
+            }
+            if data.len() as u64 > self.max_size {
+                log::trace!("body超过{}字节, 不做缓存", self.max_size);
+                let (sender, receiver) = channel(10);
+                let mut rest = std::mem::replace(res.body_mut(), Body::new(receiver, data, false));
+                tokio::spawn(async move { forward_body(&mut rest, &sender, true).await });
+                return false;
+            }
+        }
+        let body = data.as_slice().to_vec();
+        *res.body_mut() = Body::new_binary(data);
+
+        let fresh = Self::directive_secs(&control, "s-maxage")
+            .or_else(|| Self::directive_secs(&control, "max-age"))
+            .unwrap_or(self.valid);
+        let limit = |name: &str, max: Duration| Self::directive_secs(&control, name).unwrap_or(max).min(max);
+        let mut headers = res.headers().clone();
+        for name in [HeaderName::CONNECTION, HeaderName::TRANSFER_ENCODING] {
+            headers.remove(&name);
+        }
+        headers.remove(&"Keep-Alive");
+        headers.insert(HeaderName::CONTENT_LENGTH, body.len());
+        let entry = CacheEntry {
+            status: 200,
+            version: res.version(),
+            headers,
+            body,
+            stored: Instant::now(),
+            fresh,
+            stale_if_error: limit("stale-if-error", self.stale_if_error),
+            stale_while_revalidate: limit("stale-while-revalidate", self.stale_while_revalidate),
+            revalidating: false,
+        };
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+        if entries.len() >= self.max_entries && !entries.contains_key(key) {
+            entries.retain(|_, e| e.stored.elapsed() < e.max_age());
+            // 仍已满时淘汰最早存入的
+            if entries.len() >= self.max_entries {
+                let oldest = entries.iter().min_by_key(|(_, e)| e.stored).map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key.to_string(), entry);
+        true
+    }
+}
+
+impl PartialEq for ConfigProxyCache {
+    fn eq(&self, other: &Self) -> bool {
+        self.valid == other.valid
+            && self.stale_if_error == other.stale_if_error
+            && self.stale_while_revalidate == other.stale_while_revalidate
+            && self.max_size == other.max_size
+            && self.max_entries == other.max_entries
+    }
+}
+
+impl FromStr for ConfigProxyCache {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cache = Self {
+            valid: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+            max_size: DEFAULT_MAX_SIZE,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        };
+        for v in s.split_whitespace() {
+            let (k, value) = v.split_once('=').unwrap_or((v, ""));
+            match k {
+                "valid" => cache.valid = value.parse::<ConfigDuration>()?.0,
+                "stale_if_error" => cache.stale_if_error = value.parse::<ConfigDuration>()?.0,
+                "stale_while_revalidate" => {
+                    cache.stale_while_revalidate = value.parse::<ConfigDuration>()?.0
+                }
+                "max_size" => cache.max_size = value.parse::<ConfigSize>()?.0,
+                "max_entries" => {
+                    cache.max_entries = value.parse::<usize>().map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidInput, format!("未知的proxy_cache配置:{}", v))
+                    })?
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("未知的proxy_cache配置:{}", v),
+                    ))
+                }
+            }
+        }
+        if cache.max_entries == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "proxy_cache的max_entries需大于0",
+            ));
+        }
+        Ok(cache)
+    }
+}
+
+impl Display for ConfigProxyCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "valid={} stale_if_error={} stale_while_revalidate={} max_size={} max_entries={}",
+            ConfigDuration(self.valid),
+            ConfigDuration(self.stale_if_error),
+            ConfigDuration(self.stale_while_revalidate),
+            ConfigSize(self.max_size),
+            self.max_entries
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    use super::ConfigProxyCache;
+    use crate::test_util::{connect_http, load_http, read_full_response, run_upstream};
+
+    /// 第n次请求返回`v{n}`, fail为true后返回502
+    async fn start(cache: &str, hits: Arc<AtomicUsize>, fail: Arc<AtomicUsize>) -> DuplexStream {
+        start_with(cache, "", hits, fail).await
+    }
+
+    /// 同start, 200的返回附带额外的头
+    async fn start_with(
+        cache: &str,
+        extra: &'static str,
+        hits: Arc<AtomicUsize>,
+        fail: Arc<AtomicUsize>,
+    ) -> DuplexStream {
+        let addr = run_upstream(move |mut stream, _| {
+            let n = hits.fetch_add(1, Ordering::Relaxed) + 1;
+            let fail = fail.load(Ordering::Relaxed) > 0;
+            async move {
+                let res = if fail {
+                    "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndown".to_string()
+                } else {
+                    format!("HTTP/1.1 200 OK\r\n{}Content-Length: 2\r\nConnection: close\r\n\r\nv{}", extra, n)
+                };
+                let _ = stream.write_all(res.as_bytes()).await;
+            }
+        })
+        .await;
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}"
+            proxy_cache = "{}"
+            "#,
+            addr, cache
+        );
+        connect_http(load_http(&config).convert_server_config()).await
+    }
+
+    async fn get(client: &mut DuplexStream) -> (String, String) {
+        client
+            .write_all(b"GET /a?b=1 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(2), read_full_response(client))
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_config() {
+        let cache = "valid=5min stale_if_error=1h".parse::<ConfigProxyCache>().unwrap();
+        assert_eq!(
+            format!("{}", cache),
+            "valid=5min stale_if_error=1h stale_while_revalidate=0s max_size=1024k max_entries=1000"
+        );
+        assert_eq!(format!("{}", cache).parse::<ConfigProxyCache>().unwrap(), cache);
+        assert!("valid=5min unknown=1".parse::<ConfigProxyCache>().is_err());
+        assert!("max_entries=0".parse::<ConfigProxyCache>().is_err());
+
+        assert!(ConfigProxyCache::has_directive("public, No-Cache", "no-cache"));
+        assert!(!ConfigProxyCache::has_directive("no-cache-x", "no-cache"));
+        assert_eq!(
+            ConfigProxyCache::directive_secs("max-age=60, stale-if-error=\"30\"", "stale-if-error"),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[tokio::test]
+    async fn serve_stale_on_502() {
+        let (hits, fail) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut client = start("valid=100ms stale_if_error=1h", hits.clone(), fail.clone()).await;
+        let (head, body) = get(&mut client).await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert_eq!(body, "v1");
+        // 未过期时不请求上游
+        let (head, body) = get(&mut client).await;
+        assert_eq!(body, "v1");
+        assert!(!head.contains("warning:"), "{}", head);
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        fail.store(1, Ordering::Relaxed);
+        let (head, body) = get(&mut client).await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(head.contains("warning: 110"), "{}", head);
+        assert!(!head.contains("connection: close"), "{}", head);
+        assert_eq!(body, "v1");
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+
+        // 未开启stale_if_error时返回上游的错误
+        let (hits, fail) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut client = start("valid=100ms", hits, fail.clone()).await;
+        assert_eq!(get(&mut client).await.1, "v1");
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        fail.store(1, Ordering::Relaxed);
+        let (head, body) = get(&mut client).await;
+        assert!(head.starts_with("http/1.1 502"), "{}", head);
+        assert_eq!(body, "down");
+    }
+
+    #[tokio::test]
+    async fn background_revalidation() {
+        let (hits, fail) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut client = start("valid=100ms stale_while_revalidate=1h", hits.clone(), fail).await;
+        assert_eq!(get(&mut client).await.1, "v1");
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        // 过期的缓存立即返回, 同时在后台更新
+        let (head, body) = get(&mut client).await;
+        assert!(head.contains("warning: 110"), "{}", head);
+        assert_eq!(body, "v1");
+        let wait = async {
+            while hits.load(Ordering::Relaxed) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), wait).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (head, body) = get(&mut client).await;
+        assert!(!head.contains("warning:"), "{}", head);
+        assert_eq!(body, "v2");
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+    }
+    #[tokio::test]
+    async fn skip_vary() {
+        // 按Accept-Language区分的返回不缓存
+        let (hits, fail) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut client = start_with("valid=1h", "Vary: Accept-Language\r\n", hits.clone(), fail).await;
+        assert_eq!(get(&mut client).await.1, "v1");
+        assert_eq!(get(&mut client).await.1, "v2");
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+
+        // 未压缩的返回仅按Accept-Encoding区分时仍可缓存
+        let (hits, fail) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut client = start_with("valid=1h", "Vary: Accept-Encoding\r\n", hits.clone(), fail).await;
+        assert_eq!(get(&mut client).await.1, "v1");
+        assert_eq!(get(&mut client).await.1, "v1");
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+    }
+}
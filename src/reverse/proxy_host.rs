@@ -0,0 +1,165 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/13 09:52:41
+
+use std::{fmt::Display, io, str::FromStr};
+
+use webparse::{HeaderName, Request, Url};
+
+/// 发往上游的`Host`头, 上游按虚拟主机区分时需与上游的配置一致
+///
+/// * `preserve` 保留客户端的Host, 默认值, 客户端未携带时同`upstream`
+/// * `upstream` 使用proxy_url中的主机名, 若为upstream的名称则使用负载均衡选中的地址
+/// * 其它值则固定为该值, 如`api.example.com`
+///
+/// 上游为https时, TLS的SNI取发往上游的Host中的主机名部分, 与Host保持一致
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ConfigProxyHost {
+    #[default]
+    Preserve,
+    Upstream,
+    Literal(String),
+}
+
+impl ConfigProxyHost {
+    /// 计算发往上游的Host, origin为配置的proxy_url, url为负载均衡后实际连接的地址
+    pub fn host_value<T: webparse::Serialize>(&self, req: &Request<T>, origin: &Url, url: &Url) -> Option<String> {
+        match self {
+            ConfigProxyHost::Preserve => req
+                .headers()
+                .get_str_value(&HeaderName::HOST)
+                .or_else(|| req.headers().get_str_value(&":authority"))
+                .or_else(|| Self::upstream_host(origin, url)),
+            ConfigProxyHost::Upstream => Self::upstream_host(origin, url),
+            ConfigProxyHost::Literal(host) => Some(host.clone()),
+        }
+    }
+
+    fn upstream_host(origin: &Url, url: &Url) -> Option<String> {
+        // proxy_url为upstream的名称, 名称本身不是可用的主机名
+        if origin.domain != url.domain {
+            return url.get_connect_url();
+        }
+        let domain = url.domain.clone()?;
+        let default_port = if url.scheme.is_https() { 443 } else { 80 };
+        match origin.port {
+            Some(port) if port != default_port => Some(format!("{}:{}", domain, port)),
+            _ => Some(domain),
+        }
+    }
+
+    /// Host中的主机名部分, 用于上游TLS的SNI
+    pub fn server_name(host: &str) -> &str {
+        if let Some(v) = host.strip_prefix('[') {
+            return v.split(']').next().unwrap_or(v);
+        }
+        match host.rsplit_once(':') {
+            Some((name, port)) if !name.contains(':') && port.parse::<u16>().is_ok() => name,
+            _ => host,
+        }
+    }
+}
+
+impl FromStr for ConfigProxyHost {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s {
+            "" => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "proxy_set_host不能为空",
+            )),
+            "preserve" => Ok(ConfigProxyHost::Preserve),
+            "upstream" => Ok(ConfigProxyHost::Upstream),
+            _ if s.contains(char::is_whitespace) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("proxy_set_host的值无效:{}", s),
+            )),
+            _ => Ok(ConfigProxyHost::Literal(s.to_string())),
+        }
+    }
+}
+
+impl Display for ConfigProxyHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigProxyHost::Preserve => f.write_str("preserve"),
+            ConfigProxyHost::Upstream => f.write_str("upstream"),
+            ConfigProxyHost::Literal(host) => f.write_str(host),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::{Request, Url};
+
+    use super::ConfigProxyHost;
+
+    fn build_request(host: Option<&'static str>) -> Request<String> {
+        let mut builder = Request::builder().url("/api");
+        if let Some(host) = host {
+            builder = builder.header("Host", host);
+        }
+        builder.body(String::new()).unwrap()
+    }
+
+    #[test]
+    fn host_value() {
+        let origin = "http://backend.internal:8080".parse::<Url>().unwrap();
+        let req = build_request(Some("www.example.com"));
+
+        let preserve = "preserve".parse::<ConfigProxyHost>().unwrap();
+        assert_eq!(preserve, ConfigProxyHost::default());
+        assert_eq!(
+            preserve.host_value(&req, &origin, &origin),
+            Some("www.example.com".to_string())
+        );
+        // 未携带Host时使用上游的主机名
+        assert_eq!(
+            preserve.host_value(&build_request(None), &origin, &origin),
+            Some("backend.internal:8080".to_string())
+        );
+
+        let upstream = "upstream".parse::<ConfigProxyHost>().unwrap();
+        assert_eq!(
+            upstream.host_value(&req, &origin, &origin),
+            Some("backend.internal:8080".to_string())
+        );
+        let origin_default = "https://backend.internal".parse::<Url>().unwrap();
+        assert_eq!(
+            upstream.host_value(&req, &origin_default, &origin_default),
+            Some("backend.internal".to_string())
+        );
+        // proxy_url为upstream的名称时使用选中的地址
+        let group = "http://server".parse::<Url>().unwrap();
+        let mut select = group.clone();
+        select.domain = Some("127.0.0.1".to_string());
+        select.port = Some(8081);
+        assert_eq!(
+            upstream.host_value(&req, &group, &select),
+            Some("127.0.0.1:8081".to_string())
+        );
+
+        let literal = "api.example.com".parse::<ConfigProxyHost>().unwrap();
+        assert_eq!(format!("{}", literal), "api.example.com");
+        assert_eq!(
+            literal.host_value(&req, &origin, &origin),
+            Some("api.example.com".to_string())
+        );
+        assert!("a b".parse::<ConfigProxyHost>().is_err());
+
+        assert_eq!(ConfigProxyHost::server_name("www.example.com:8443"), "www.example.com");
+        assert_eq!(ConfigProxyHost::server_name("www.example.com"), "www.example.com");
+        assert_eq!(ConfigProxyHost::server_name("[::1]:443"), "::1");
+    }
+}
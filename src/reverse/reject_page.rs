@@ -0,0 +1,159 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/26 09:18:40
+
+use std::{fmt::Display, io, str::FromStr};
+
+use webparse::{BinaryMut, HeaderName};
+use wenmeng::{Body, RecvRequest, RecvResponse};
+
+use crate::{ConfigDuration, Helper};
+
+use super::ConfigStatusMap;
+
+/// 代理拒绝请求(限流, 访问控制, 连接数及过载)时的返回内容, 未配置时为默认的状态码及简单的文本
+///
+/// 配置格式为空格分隔的`状态码=页面文件`, 多个状态码可用`,`分隔, `*`匹配其它的状态码,
+/// `retry_after=30s`设置429及503返回的`Retry-After`, `header=名称:值`追加返回头(值中不能含空格),
+/// 如`429=html/limit.html 403,503=html/deny.json retry_after=30s header=X-Reject:{client_ip}`
+/// 页面及头的值与`static_response`一样支持`{client_ip}`, `{path}`等变量, 页面中的`{`及`}`需写为`{{`及`}}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigRejectPage {
+    /// 状态码对应的页面, 状态码为空的为`*`
+    pages: Vec<(Vec<u16>, String)>,
+    retry_after: Option<ConfigDuration>,
+    headers: Vec<(String, String)>,
+}
+
+impl ConfigRejectPage {
+    fn page(&self, status: u16) -> Option<&String> {
+        self.pages
+            .iter()
+            .find(|(list, _)| list.contains(&status))
+            .or_else(|| self.pages.iter().find(|(list, _)| list.is_empty()))
+            .map(|(_, page)| page)
+    }
+
+    /// 按配置替换拒绝请求的返回, 页面读取失败时保留默认的内容
+    pub async fn apply(&self, req: &RecvRequest, res: &mut RecvResponse) {
+        let status = res.status().as_u16();
+        if let Some(retry) = &self.retry_after {
+            if status == 429 || status == 503 {
+                let secs = retry.0.as_secs().max(1);
+                res.headers_mut().insert("Retry-After", secs.to_string());
+            }
+        }
+        for (key, value) in &self.headers {
+            res.headers_mut().insert(key.clone(), Helper::format_req(req, value));
+        }
+        let Some(page) = self.page(status) else {
+            return;
+        };
+        let data = match tokio::fs::read(page).await {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("读取拒绝请求的页面{}失败, 保留默认的返回内容: {:?}", page, e);
+                return;
+            }
+        };
+        let data = Helper::format_req(req, &String::from_utf8_lossy(&data));
+        let headers = res.headers_mut();
+        headers.insert(HeaderName::CONTENT_TYPE, ConfigStatusMap::content_type(page));
+        headers.insert(HeaderName::CONTENT_LENGTH, data.len());
+        let mut binary = BinaryMut::with_capacity(data.len());
+        binary.put_slice(data.as_bytes());
+        *res.body_mut() = Body::new_binary(binary);
+    }
+
+    /// 未配置时保持默认的返回
+    pub async fn apply_option(page: &Option<ConfigRejectPage>, req: &RecvRequest, res: &mut RecvResponse) {
+        if let Some(page) = page {
+            page.apply(req, res).await;
+        }
+    }
+}
+
+impl FromStr for ConfigRejectPage {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |v: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("错误的reject_page配置:{}", v));
+        let mut page = ConfigRejectPage {
+            pages: vec![],
+            retry_after: None,
+            headers: vec![],
+        };
+        for v in s.split_whitespace() {
+            let (key, value) = v.split_once('=').filter(|(_, value)| !value.is_empty()).ok_or_else(|| err(v))?;
+            match key {
+                "retry_after" => page.retry_after = Some(value.parse().map_err(|_| err(v))?),
+                "header" => {
+                    let (name, value) = value.split_once(':').filter(|(name, _)| !name.is_empty()).ok_or_else(|| err(v))?;
+                    page.headers.push((name.to_string(), value.to_string()));
+                }
+                "*" => page.pages.push((vec![], value.to_string())),
+                _ => {
+                    let list = key
+                        .split(',')
+                        .map(|status| match status.parse::<u16>() {
+                            Ok(status) if (400..=599).contains(&status) => Ok(status),
+                            _ => Err(err(v)),
+                        })
+                        .collect::<io::Result<Vec<_>>>()?;
+                    page.pages.push((list, value.to_string()));
+                }
+            }
+        }
+        if page.pages.is_empty() && page.retry_after.is_none() && page.headers.is_empty() {
+            return Err(err(s));
+        }
+        Ok(page)
+    }
+}
+
+impl Display for ConfigRejectPage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut values = vec![];
+        for (list, page) in &self.pages {
+            let key = match list.is_empty() {
+                true => "*".to_string(),
+                false => list.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(","),
+            };
+            values.push(format!("{}={}", key, page));
+        }
+        if let Some(retry) = &self.retry_after {
+            values.push(format!("retry_after={}", retry));
+        }
+        for (name, value) in &self.headers {
+            values.push(format!("header={}:{}", name, value));
+        }
+        f.write_str(&values.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigRejectPage;
+
+    #[test]
+    fn parse_reject_page() {
+        let page = "429=html/limit.html 403,503=html/deny.json *=html/reject.html retry_after=30s header=X-Reject:{client_ip}"
+            .parse::<ConfigRejectPage>()
+            .unwrap();
+        assert_eq!(page.page(429).map(|s| &**s), Some("html/limit.html"));
+        assert_eq!(page.page(503).map(|s| &**s), Some("html/deny.json"));
+        assert_eq!(page.page(404).map(|s| &**s), Some("html/reject.html"));
+        assert_eq!(page.to_string().parse::<ConfigRejectPage>().unwrap(), page);
+        assert!("200=a.html".parse::<ConfigRejectPage>().is_err());
+        assert!("header=:a".parse::<ConfigRejectPage>().is_err());
+        assert!("".parse::<ConfigRejectPage>().is_err());
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/24 15:12:36
+
+use std::{collections::HashMap, fmt::Display, io, str::FromStr, sync::Mutex};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    // 各上游的重试令牌, 按上游的名字区分, 重新加载配置后保留
+    static ref BUDGETS: Mutex<HashMap<String, BudgetState>> = Mutex::new(HashMap::new());
+}
+
+/// 默认最多累积的重试数
+const DEFAULT_BURST: u32 = 10;
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    tokens: f64,
+    exhausted: u64,
+}
+
+/// 上游的重试预算, 如`10% burst=10`, 防止部分故障时大量的重试压垮上游
+///
+/// 每个请求存入`percent`个令牌, 每次重试消耗一个, 最多累积`burst`个(默认10), 令牌不足时不再重试
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfigRetryBudget {
+    percent: u32,
+    burst: u32,
+}
+
+impl FromStr for ConfigRetryBudget {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("retry_budget的值无效:{}", s),
+            )
+        };
+        let mut percent = None;
+        let mut burst = DEFAULT_BURST;
+        for v in s.split_whitespace() {
+            if let Some(p) = v.strip_suffix('%') {
+                percent = Some(p.parse::<u32>().map_err(|_| err())?);
+            } else if let Some(b) = v.strip_prefix("burst=") {
+                burst = b.parse::<u32>().map_err(|_| err())?;
+            } else {
+                return Err(err());
+            }
+        }
+        match percent {
+            Some(percent) if percent <= 100 && burst > 0 => Ok(Self { percent, burst }),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl Display for ConfigRetryBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}%", self.percent)?;
+        if self.burst != DEFAULT_BURST {
+            write!(f, " burst={}", self.burst)?;
+        }
+        Ok(())
+    }
+}
+
+/// 单个上游的重试预算
+pub struct RetryBudget {
+    name: String,
+    config: ConfigRetryBudget,
+}
+
+impl RetryBudget {
+    pub fn new(name: String, config: ConfigRetryBudget) -> Self {
+        Self { name, config }
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut BudgetState) -> R) -> R {
+        let mut budgets = BUDGETS.lock().unwrap_or_else(|e| e.into_inner());
+        let state = budgets.entry(self.name.clone()).or_insert_with(|| BudgetState {
+            tokens: self.config.burst as f64,
+            exhausted: 0,
+        });
+        f(state)
+    }
+
+    /// 收到新的请求时存入令牌
+    pub fn deposit(&self) {
+        let burst = self.config.burst as f64;
+        let percent = self.config.percent as f64 / 100.0;
+        self.with_state(|state| state.tokens = (state.tokens + percent).min(burst));
+    }
+
+    /// 消耗一次重试的令牌, 不足时返回false
+    pub fn acquire(&self) -> bool {
+        self.with_state(|state| {
+            if state.tokens >= 1.0 {
+                state.tokens -= 1.0;
+                true
+            } else {
+                state.exhausted += 1;
+                false
+            }
+        })
+    }
+
+    /// 各上游当前可用的重试数及因预算不足放弃的重试数, 按名字排序
+    pub fn list() -> Vec<(String, u64, u64)> {
+        let budgets = BUDGETS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut list = budgets
+            .iter()
+            .map(|(name, state)| (name.clone(), state.tokens as u64, state.exhausted))
+            .collect::<Vec<_>>();
+        list.sort();
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::net::TcpListener;
+    use webparse::Request;
+    use wenmeng::Body;
+
+    use super::{ConfigRetryBudget, RetryBudget};
+    use crate::{control::MetricsRegistry, reverse::HttpConfig};
+
+    /// 接收连接后立即关闭的上游, 记录连接的次数
+    async fn run_reset_upstream(count: Arc<AtomicUsize>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                count.fetch_add(1, Ordering::Relaxed);
+                drop(stream);
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn parse_budget() {
+        for s in ["10%", "20% burst=5"] {
+            assert_eq!(s.parse::<ConfigRetryBudget>().unwrap().to_string(), s);
+        }
+        assert_eq!("burst=10 10%".parse::<ConfigRetryBudget>().unwrap().to_string(), "10%");
+        for s in ["", "burst=5", "120%", "10% burst=0", "10% max=3"] {
+            assert!(s.parse::<ConfigRetryBudget>().is_err(), "{}", s);
+        }
+    }
+
+    #[tokio::test]
+    async fn budget_exhausted() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let (a, b) = (
+            run_reset_upstream(count.clone()).await,
+            run_reset_upstream(count.clone()).await,
+        );
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.upstream]]
+            name = "budget-test"
+            retry_budget = "10% burst=2"
+            server = [{{ addr = "{}" }}, {{ addr = "{}" }}]
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://budget-test"
+            proxy_next_upstream_tries = 1
+            "#,
+            a, b
+        );
+        let mut http = toml::from_str::<HttpConfig>(&config).unwrap();
+        http.after_load_option().unwrap();
+        let servers = http.convert_server_config();
+        let location = &servers[0].location[0];
+        for _ in 0..5 {
+            let mut req = Request::builder()
+                .url("/")
+                .header("Host", "localhost")
+                .body(Body::empty())
+                .unwrap();
+            assert!(location.deal_request(&mut req).await.is_err());
+        }
+        // 前两个请求消耗完预算各重试一次, 之后的请求不再重试
+        assert_eq!(count.load(Ordering::Relaxed), 2 + 2 + 1 + 1 + 1);
+        let state = RetryBudget::list().into_iter().find(|b| b.0 == "budget-test").unwrap();
+        assert_eq!((state.1, state.2), (0, 3));
+
+        let list = MetricsRegistry::collect(0, 0);
+        assert!(list.iter().any(|m| m.name == "retry_budget_exhausted_total"
+            && m.labels == vec![("upstream", "budget-test".to_string())]
+            && m.value == 3));
+    }
+}
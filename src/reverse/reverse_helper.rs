@@ -12,9 +12,11 @@
 
 use std::{net::SocketAddr, sync::Arc};
 
+use webparse::Request;
 use wenmeng::{RecvRequest};
 
-use super::{UpstreamConfig, ServerConfig, LocationConfig};
+use super::{UpstreamConfig, ServerConfig, LocationConfig, RetryBudget, ConfigUpstreamTls, ConnectLimit, Http2Settings};
+use crate::{ConfigBodyBuffer, ConfigDscp, ConfigHeader, ConfigUpstreamProxy};
 
 
 pub struct ReverseHelper;
@@ -31,8 +33,140 @@ impl ReverseHelper {
         }
         return None;
     }
+
+    /// 同get_upstream_addr, 按上游配置的负载均衡策略结合请求选择地址
+    pub fn get_upstream_addr_by_req<T: webparse::Serialize>(
+        upstream: &Vec<UpstreamConfig>,
+        name: &str,
+        req: &Request<T>,
+    ) -> Option<SocketAddr> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.get_server_addr_by_req(req);
+            }
+        }
+        None
+    }
+
+    /// 获取上游连接时绑定的本地地址, 匹配规则与`get_upstream_addr`一致
+    pub fn get_upstream_local_bind(upstream: &Vec<UpstreamConfig>, name: &str) -> Option<String> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.local_bind.clone();
+            }
+        }
+        None
+    }
+
+    /// 获取连接上游时经过的出口代理, 匹配规则与`get_upstream_addr`一致
+    pub fn get_upstream_proxy(upstream: &Vec<UpstreamConfig>, name: &str) -> Option<ConfigUpstreamProxy> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.upstream_proxy.clone();
+            }
+        }
+        None
+    }
+
+    /// 获取连接上游的DSCP/ToS标记, 匹配规则与`get_upstream_addr`一致
+    pub fn get_upstream_dscp(upstream: &Vec<UpstreamConfig>, name: &str) -> Option<ConfigDscp> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.dscp;
+            }
+        }
+        None
+    }
     
+    /// 获取TLS握手失败时是否改用明文连接, 匹配规则与`get_upstream_addr`一致
+    pub fn get_upstream_tls_fallback(upstream: &Vec<UpstreamConfig>, name: &str) -> bool {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.tls_fallback_plain;
+            }
+        }
+        false
+    }
+
+    /// 获取选中的上游地址使用的TLS配置, 匹配规则与`get_upstream_addr`一致
+    pub fn get_upstream_tls(
+        upstream: &Vec<UpstreamConfig>,
+        name: &str,
+        addr: Option<SocketAddr>,
+    ) -> Option<ConfigUpstreamTls> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.get_tls(addr);
+            }
+        }
+        None
+    }
+
+    /// 获取选中的上游地址建立连接的限制, 匹配规则与`get_upstream_addr`一致
+    pub fn get_upstream_connect_limit(
+        upstream: &Vec<UpstreamConfig>,
+        name: &str,
+        addr: Option<SocketAddr>,
+    ) -> Option<ConnectLimit> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.get_connect_limit(addr);
+            }
+        }
+        None
+    }
+
+    /// 获取与上游的HTTP/2连接的参数, 匹配规则与`get_upstream_addr`一致
+    pub fn get_upstream_http2_settings(upstream: &Vec<UpstreamConfig>, name: &str) -> Option<Http2Settings> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.http2_settings().ok().flatten();
+            }
+        }
+        None
+    }
+
+    /// 获取上游缓存chunked请求body的配置, 匹配规则与`get_upstream_addr`一致
+    pub fn get_upstream_dechunk(upstream: &Vec<UpstreamConfig>, name: &str) -> Option<ConfigBodyBuffer> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.dechunk.clone();
+            }
+        }
+        None
+    }
+
+    /// 获取上游的重试预算, 匹配规则与`get_upstream_addr`一致
+    pub fn get_upstream_retry_budget(upstream: &Vec<UpstreamConfig>, name: &str) -> Option<RetryBudget> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream
+                    .retry_budget
+                    .map(|config| RetryBudget::new(stream.name.clone(), config));
+            }
+        }
+        None
+    }
+
+    /// 获取选中上游时追加的头, 匹配规则与`get_upstream_addr`一致
+    pub fn get_upstream_headers<'a>(upstream: &'a Vec<UpstreamConfig>, name: &str) -> &'a [ConfigHeader] {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return &stream.headers;
+            }
+        }
+        &[]
+    }
+
     pub fn get_location_by_req<'a>(servers: &'a Vec<Arc<ServerConfig>>, req: &RecvRequest) -> Option<&'a LocationConfig> {
+        Self::get_server_location_by_req(servers, req).map(|(_, l)| l)
+    }
+
+    /// 同get_location_by_req, 同时返回location所在的server
+    pub fn get_server_location_by_req<'a>(
+        servers: &'a [Arc<ServerConfig>],
+        req: &RecvRequest,
+    ) -> Option<(&'a Arc<ServerConfig>, &'a LocationConfig)> {
         let server_len = servers.len();
         let host = req.get_host().unwrap_or(String::new());
         // 不管有没有匹配, 都执行最后一个
@@ -41,7 +175,7 @@ impl ReverseHelper {
                 let path = req.path().clone();
                 for idx in 0..s.location.len() {
                     if s.location[idx].is_match_rule(&path, req) {
-                        return Some(&s.location[idx]);
+                        return Some((s, &s.location[idx]));
                     }
                 }
             }
@@ -10,16 +10,17 @@
 // -----
 // Created Date: 2023/10/18 02:32:15
 
-use std::{collections::HashMap, net::{SocketAddr, ToSocketAddrs}};
+use std::{collections::HashMap, io, net::{SocketAddr, ToSocketAddrs}, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
-use wenmeng::ProtResult;
+use webparse::{HeaderName, Method, Request, Response};
+use wenmeng::{Body, ProtResult};
 
 
-use crate::{ConfigHeader, WrapVecAddr};
+use crate::{ConfigBodyPeek, ConfigDscp, ConfigDuration, ConfigHeader, ConfigPortMap, ConfigUpstreamProxy, DisplayFromStrOrNumber, MethodSets, WrapVecAddr};
 
-use super::{LocationConfig, UpstreamConfig, common::CommonConfig, ReverseHelper};
+use super::{Admission, WsLimit, ConfigAdmission, LocationConfig, UpstreamConfig, common::CommonConfig, ReverseHelper, ConfigJa3Set, ConfigPipeline, ConfigProtocolCheck, Http2Settings};
 
 fn default_bind_mode() -> String {
     "tcp".to_string()
@@ -40,12 +41,120 @@ pub struct ServerConfig {
     
     #[serde(default = "default_up_name")]
     pub up_name: String,
+    /// 服务的标签, 以`{server_tag}`提供给日志, 并作为metrics的`server_tag`标签
+    /// 用于多个业务共用同一个进程时按业务区分日志及指标
+    #[serde(default)]
+    pub tag: Option<String>,
     pub root: Option<String>,
+    /// 证书路径, 配置为`auto`时自动生成自签名证书(仅用于测试), 此时无需配置key
+    /// 以`.p12`或`.pfx`结尾时为包含私钥的PKCS#12证书, 同样无需配置key
     pub cert: Option<String>,
     pub key: Option<String>,
+    /// 加密私钥或PKCS#12证书的密码, 以`env:`开头时读取相应的环境变量, 如`env:WMPROXY_KEY_PASSWORD`
+    /// 未配置时读取环境变量`WMPROXY_KEY_PASSWORD`
+    #[serde(default)]
+    pub key_password: Option<String>,
 
     #[serde(default = "default_bind_mode")]
     pub bind_mode: String,
+
+    /// 透明代理, 连接上游时以客户端的源地址发起连接, 仅stream的tcp转发有效
+    /// 需开启`tproxy`特性, 且进程拥有CAP_NET_ADMIN权限
+    #[serde(default)]
+    pub transparent: bool,
+
+    /// 监听端口到上游端口的映射, 如`offset=1000`或`9000=80 9001=8080`, 仅stream有效
+    /// 配合端口范围的bind_addr, 每个监听端口转发到上游相应的端口
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub port_map: Option<ConfigPortMap>,
+
+    /// 客户端连接后先发送的欢迎数据, 如SMTP的`220 ...\r\n`, 仅stream的tcp转发有效
+    #[serde(default)]
+    pub banner: Option<String>,
+
+    /// 收到客户端的首个数据后才连接上游, 避免端口扫描等探测占用上游的连接, 仅stream的tcp转发有效
+    /// 客户端未发送数据即关闭时不连接上游, 配置了client_timeout时等待超时后关闭, 不适用于需上游先发送数据的协议
+    #[serde(default)]
+    pub proxy_connect_on_first_byte: bool,
+
+    /// TLS握手前计算客户端的JA3指纹, 在列表中的将直接断开连接, 仅https有效, 需开启`ja3`特性
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub ja3_deny: Option<ConfigJa3Set>,
+
+    /// 仅允许列表中的JA3指纹连接, 无法计算指纹的连接也将断开, 仅https有效, 需开启`ja3`特性
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub ja3_allow: Option<ConfigJa3Set>,
+
+    /// 将客户端TLS握手中的SNI以该头转发给上游, 如`X-Forwarded-SNI`, 未携带SNI时移除客户端传入的同名头, 仅https有效
+    /// SNI同时以`{ssl_sni}`提供给header及日志
+    #[serde(default)]
+    pub sni_header: Option<String>,
+
+    /// 该server最大的websocket连接数, 超过时升级请求返回503, 与location中的配置同时生效
+    #[serde(default)]
+    pub ws_max_connections: Option<usize>,
+    #[serde(skip)]
+    pub ws_limit: Option<Arc<WsLimit>>,
+
+    /// 客户端连接的空闲超时, 超时后关闭该连接, 配置后返回`Keep-Alive: timeout=N`, 优先于client_ka_timeout
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub keepalive_timeout: Option<ConfigDuration>,
+
+    /// 客户端的每个连接最多处理的请求数, 最后一个请求返回`Connection: close`, 仅HTTP/1.x有效
+    #[serde(default)]
+    pub keepalive_requests: Option<usize>,
+
+    /// bind_ssl的端口同时接收http的连接, 按客户端发送的首个字节区分是否为TLS
+    /// 需等待客户端发送数据后才能开始处理, 适用于开发测试等简单场景
+    #[serde(default)]
+    pub detect_tls: bool,
+
+    /// 接收http连接时检查首个请求行, 明显不是HTTP的连接直接关闭, 可选`off`, `lenient`, `strict`, 默认为`off`
+    /// `lenient`要求以大写的方法加空格开头, `strict`要求为已知的请求方法, 同端口的多个server取最严格的配置
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub protocol_check: ConfigProtocolCheck,
+
+    /// HTTP/1.1管线化的处理方式, 默认`off`为上一个请求返回完毕后才读取下一个请求, `on`为处理完毕即读取
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub pipelining: ConfigPipeline,
+
+    /// location的rule中配置了body时预读请求body的大小及等待时间, 默认为`size=1k timeout=1s`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub body_peek: Option<ConfigBodyPeek>,
+
+    /// 接收的客户端连接的DSCP/ToS标记, 如`ef`或`tos=0x10`, 同端口的多个server取第一个配置的值
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub dscp: Option<ConfigDscp>,
+
+    /// 健康检查的路径, 如`/healthz`, 不经过location匹配直接返回200, 供负载均衡及监控探测
+    #[serde(default)]
+    pub health_path: Option<String>,
+
+    /// 过载时的准入控制, 如`max=200 reserve=20 latency=500ms priority=X-Priority high=/api/pay`
+    /// 过载时新到达的普通请求返回503, 高优先级的请求使用保留的名额
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub admission: Option<ConfigAdmission>,
+    #[serde(skip)]
+    pub admission_state: Option<Arc<Admission>>,
+
+    /// HTTP/2连接允许客户端同时打开的流数, 未配置时不限制
+    #[serde(default)]
+    pub http2_max_concurrent_streams: Option<u32>,
+    /// HTTP/2连接中每个流的初始接收窗口, 默认为65535, 最大为2147483647
+    #[serde(default)]
+    pub http2_initial_window_size: Option<u32>,
+    /// HTTP/2连接允许接收的最大帧长度, 范围为16384到16777215
+    #[serde(default)]
+    pub http2_max_frame_size: Option<u32>,
     
     #[serde_as(as = "Vec<DisplayFromStr>")]
     #[serde(default = "Vec::new")]
@@ -66,10 +175,34 @@ impl ServerConfig {
             bind_addr,
             bind_ssl: WrapVecAddr::empty(),
             up_name: default_up_name(),
+            tag: None,
             root: None,
             cert: None,
             key: None,
+            key_password: None,
             bind_mode: default_bind_mode(),
+            transparent: false,
+            port_map: None,
+            banner: None,
+            proxy_connect_on_first_byte: false,
+            ja3_deny: None,
+            ja3_allow: None,
+            sni_header: None,
+            ws_max_connections: None,
+            ws_limit: None,
+            keepalive_timeout: None,
+            keepalive_requests: None,
+            detect_tls: false,
+            protocol_check: ConfigProtocolCheck::Off,
+            pipelining: ConfigPipeline::Off,
+            body_peek: None,
+            dscp: None,
+            health_path: None,
+            admission: None,
+            admission_state: None,
+            http2_max_concurrent_streams: None,
+            http2_initial_window_size: None,
+            http2_max_frame_size: None,
             headers: vec![],
             location: vec![],
             upstream: vec![],
@@ -82,10 +215,34 @@ impl ServerConfig {
             bind_addr: WrapVecAddr::empty(),
             bind_ssl,
             up_name: default_up_name(),
+            tag: None,
             root: None,
             cert: None,
             key: None,
+            key_password: None,
             bind_mode: default_bind_mode(),
+            transparent: false,
+            port_map: None,
+            banner: None,
+            proxy_connect_on_first_byte: false,
+            ja3_deny: None,
+            ja3_allow: None,
+            sni_header: None,
+            ws_max_connections: None,
+            ws_limit: None,
+            keepalive_timeout: None,
+            keepalive_requests: None,
+            detect_tls: false,
+            protocol_check: ConfigProtocolCheck::Off,
+            pipelining: ConfigPipeline::Off,
+            body_peek: None,
+            dscp: None,
+            health_path: None,
+            admission: None,
+            admission_state: None,
+            http2_max_concurrent_streams: None,
+            http2_initial_window_size: None,
+            http2_max_frame_size: None,
             headers: vec![],
             location: vec![],
             upstream: vec![],
@@ -94,9 +251,12 @@ impl ServerConfig {
     }
     /// 将配置参数提前共享给子级
     pub fn copy_to_child(&mut self) {
+        self.admission_state = self.admission.clone().map(|a| Arc::new(Admission::new(a)));
+        self.ws_limit = self.ws_max_connections.map(|max| Arc::new(WsLimit::new(max)));
         for l in &mut self.location {
             l.comm.copy_from_parent(&self.comm);
             l.comm.pre_deal();
+            l.init_concurrency();
             if let Some(n) = l.rule.get_match_name() {
                 if l.comm.match_names.contains_key(&n) {
                     l.rule = l.comm.match_names[&n].clone();
@@ -131,6 +291,16 @@ impl ServerConfig {
         }
     }
 
+    /// 是否需要在TLS握手前计算JA3指纹, 配置了ja3_allow|ja3_deny或在header及日志中使用了`{ja3}`
+    pub fn need_ja3(&self) -> bool {
+        let use_ja3 = |headers: &[ConfigHeader]| headers.iter().any(|h| h.val.contains("{ja3}"));
+        self.ja3_allow.is_some()
+            || self.ja3_deny.is_some()
+            || use_ja3(&self.headers)
+            || self.location.iter().any(|l| use_ja3(&l.headers))
+            || self.comm.log_format.values().any(|f| f.contains("{ja3}"))
+    }
+
     pub fn build_url(&self, addr: &SocketAddr) -> String {
         if self.comm.proxy_url.is_some() {
             let mut url = self.comm.proxy_url.clone().unwrap();
@@ -142,6 +312,71 @@ impl ServerConfig {
         }
     }
 
+    /// 获取上游连接时绑定的本地地址
+    pub fn get_local_bind(&self) -> Option<String> {
+        let name = self
+            .comm
+            .proxy_url
+            .as_ref()
+            .and_then(|u| u.domain.clone())
+            .unwrap_or(self.up_name.clone());
+        ReverseHelper::get_upstream_local_bind(&self.upstream, &name)
+    }
+
+    /// 获取连接上游时经过的出口代理
+    pub fn get_upstream_proxy(&self) -> Option<ConfigUpstreamProxy> {
+        let name = self
+            .comm
+            .proxy_url
+            .as_ref()
+            .and_then(|u| u.domain.clone())
+            .unwrap_or(self.up_name.clone());
+        ReverseHelper::get_upstream_proxy(&self.upstream, &name)
+    }
+
+    /// 设置转发给上游的SNI头, 客户端传入的同名头不可信, 始终以握手中的SNI为准
+    pub fn rewrite_sni_header(&self, req: &mut Request<Body>) {
+        let Some(name) = &self.sni_header else {
+            return;
+        };
+        let sni = req.headers().system_get("{ssl_sni}").cloned();
+        req.headers_mut().remove(name);
+        if let Some(sni) = sni {
+            req.headers_mut().insert(name.clone(), sni);
+        }
+    }
+
+    /// 无需经过location匹配直接返回的请求, 如`OPTIONS *`及健康检查的路径
+    /// 健康检查的路径不包含query, 如`/healthz?from=lb`同样匹配
+    pub fn deal_local_request(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        if req.method() == &Method::Options && req.path() == "*" {
+            log::trace!("OPTIONS *请求, 直接返回支持的方法");
+            let allow = MethodSets(MethodSets::COMMON.iter().map(|m| m.to_string()).collect());
+            let res = Response::builder()
+                .status(200)
+                .header(HeaderName::ALLOW, allow.to_string())
+                .body(Body::empty())
+                .unwrap()
+                .into_type();
+            return Some(res);
+        }
+        if self.health_path.as_ref() == Some(&req.url().path) {
+            return Some(Response::text().status(200).body("ok").unwrap().into_type());
+        }
+        None
+    }
+
+    /// 获取连接上游的DSCP/ToS标记
+    pub fn get_upstream_dscp(&self) -> Option<ConfigDscp> {
+        let name = self
+            .comm
+            .proxy_url
+            .as_ref()
+            .and_then(|u| u.domain.clone())
+            .unwrap_or(self.up_name.clone());
+        ReverseHelper::get_upstream_dscp(&self.upstream, &name)
+    }
+
     pub fn get_addr_domain(&self) -> ProtResult<(Option<SocketAddr>, Option<String>)> {
         let mut domain = self.comm.domain.clone();
         let mut addr = None;
@@ -167,4 +402,29 @@ impl ServerConfig {
         }
         Ok((addr, domain))
     }
+
+    /// HTTP/2连接的参数, 取值范围在加载配置时已检查
+    pub fn http2_settings(&self) -> std::io::Result<Option<Http2Settings>> {
+        Http2Settings::new(
+            self.http2_max_concurrent_streams,
+            self.http2_initial_window_size,
+            self.http2_max_frame_size,
+        )
+    }
+
+    /// 按监听端口映射上游的端口, 未配置port_map时保持不变
+    pub fn map_upstream_port(&self, local_port: u16, mut addr: SocketAddr) -> io::Result<SocketAddr> {
+        if let Some(port_map) = &self.port_map {
+            match port_map.map(local_port) {
+                Some(port) => addr.set_port(port),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("端口{}映射后超出端口范围", local_port),
+                    ))
+                }
+            }
+        }
+        Ok(addr)
+    }
 }
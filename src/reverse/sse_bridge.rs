@@ -0,0 +1,317 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/10 09:41:17
+
+use std::{collections::HashMap, sync::RwLock};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use lazy_static::lazy_static;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use webparse::{ws::OwnedMessage, Binary, BinaryMut, Buf, HeaderName, Method, Request, Response, Url};
+use wenmeng::{Body, Client, ProtResult};
+
+use super::ws::ClientWsOperate;
+
+/// 客户端发送的单条消息的最大长度
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+lazy_static! {
+    // 当前的桥接会话, 客户端POST的消息通过会话id发往对应的websocket
+    static ref GLOBAL_SESSIONS: RwLock<HashMap<String, Sender<OwnedMessage>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// websocket到SSE的桥接, 用于无法使用websocket的客户端
+///
+/// * `GET`请求建立到上游的websocket连接, 以`text/event-stream`返回上游的消息,
+///   首个事件为`event: session`, 数据为会话id, 二进制消息以`event: binary`返回base64的数据
+/// * `POST ?session={id}`请求将body作为一条消息发往上游, 返回204
+pub struct SseBridge;
+
+impl SseBridge {
+    pub async fn deal_request(url: &Url, req: &mut Request<Body>) -> ProtResult<Response<Body>> {
+        match req.method() {
+            Method::Get => Self::open(url).await,
+            Method::Post => Self::send(req).await,
+            _ => Ok(Response::text()
+                .status(405)
+                .header(HeaderName::ALLOW, "GET, POST")
+                .body("Method Not Allowed")
+                .unwrap()
+                .into_type()),
+        }
+    }
+
+    /// 当前的会话数
+    pub fn session_count() -> usize {
+        GLOBAL_SESSIONS.read().unwrap().len()
+    }
+
+    /// 连接上游的websocket, 并以SSE的格式返回上游的消息
+    async fn open(url: &Url) -> ProtResult<Response<Body>> {
+        let mut client = Client::builder().url(url.clone())?.connect().await?;
+        let (serv_sender, serv_receiver) = channel::<OwnedMessage>(10);
+        let (cli_sender, cli_receiver) = channel::<OwnedMessage>(10);
+        client.set_callback_ws(Box::new(ClientWsOperate::new(serv_sender, cli_receiver)));
+        tokio::spawn(async move {
+            if let Err(e) = client.wait_ws_operate().await {
+                log::trace!("桥接的websocket连接结束:{:?}", e);
+            }
+        });
+
+        let id = format!("{:032x}", rand::random::<u128>());
+        GLOBAL_SESSIONS
+            .write()
+            .unwrap()
+            .insert(id.clone(), cli_sender.clone());
+        let (sender, receiver) = channel::<(bool, Binary)>(10);
+        tokio::spawn(Self::relay(id, serv_receiver, cli_sender, sender));
+        Ok(Response::builder()
+            .header(HeaderName::CONTENT_TYPE, "text/event-stream")
+            .header(HeaderName::CACHE_CONTROL, "no-cache")
+            .header(HeaderName::TRANSFER_ENCODING, "chunked")
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap())
+    }
+
+    /// 将上游的消息转成SSE的事件, 任一端关闭时结束会话
+    async fn relay(
+        id: String,
+        mut serv_receiver: Receiver<OwnedMessage>,
+        cli_sender: Sender<OwnedMessage>,
+        sender: Sender<(bool, Binary)>,
+    ) {
+        let mut event = Some(format!("event: session\ndata: {}\n\n", id));
+        loop {
+            if let Some(e) = event.take() {
+                if sender.send((false, Binary::from(e.into_bytes()))).await.is_err() {
+                    break;
+                }
+            }
+            tokio::select! {
+                msg = serv_receiver.recv() => {
+                    event = match msg {
+                        Some(OwnedMessage::Text(text)) => Some(Self::encode_event(None, &text)),
+                        Some(OwnedMessage::Binary(data)) => {
+                            Some(Self::encode_event(Some("binary"), &BASE64_STANDARD.encode(data)))
+                        }
+                        Some(OwnedMessage::Close(_)) | None => break,
+                        Some(_) => None,
+                    };
+                }
+                _ = sender.closed() => {
+                    let _ = cli_sender.send(OwnedMessage::Close(None)).await;
+                    break;
+                }
+            }
+        }
+        GLOBAL_SESSIONS.write().unwrap().remove(&id);
+        let _ = sender.send((true, Binary::new())).await;
+        log::trace!("桥接会话{}结束", id);
+    }
+
+    /// 生成SSE的事件, 多行的数据每行以`data: `开头
+    fn encode_event(name: Option<&str>, data: &str) -> String {
+        let mut event = String::new();
+        if let Some(name) = name {
+            event.push_str(&format!("event: {}\n", name));
+        }
+        for line in data.split('\n') {
+            event.push_str(&format!("data: {}\n", line));
+        }
+        event.push('\n');
+        event
+    }
+
+    /// 将客户端POST的body作为一条消息发往上游
+    async fn send(req: &mut Request<Body>) -> ProtResult<Response<Body>> {
+        let id = req
+            .url()
+            .query
+            .as_ref()
+            .and_then(|q| {
+                q.split('&')
+                    .find_map(|kv| kv.strip_prefix("session="))
+                    .map(|v| v.to_string())
+            })
+            .unwrap_or_default();
+        let sender = GLOBAL_SESSIONS.read().unwrap().get(&id).cloned();
+        let sender = match sender {
+            Some(sender) => sender,
+            None => {
+                return Ok(Response::status404()
+                    .body("unknow session")
+                    .unwrap()
+                    .into_type())
+            }
+        };
+        let too_large = || {
+            Response::text()
+                .status(413)
+                .body("Payload Too Large")
+                .unwrap()
+                .into_type()
+        };
+        if req.get_body_len() > MAX_MESSAGE_SIZE as isize {
+            return Ok(too_large());
+        }
+        let mut buffer = BinaryMut::new();
+        req.body_mut().read_all(&mut buffer).await;
+        if buffer.remaining() > MAX_MESSAGE_SIZE {
+            return Ok(too_large());
+        }
+        let data = buffer.chunk().to_vec();
+        let msg = match String::from_utf8(data) {
+            Ok(text) => OwnedMessage::Text(text),
+            Err(e) => OwnedMessage::Binary(e.into_bytes()),
+        };
+        if sender.send(msg).await.is_err() {
+            return Ok(Response::status404()
+                .body("session closed")
+                .unwrap()
+                .into_type());
+        }
+        Ok(Response::text()
+            .status(204)
+            .body("")
+            .unwrap()
+            .into_type())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use wenmeng::ws::WsHandshake;
+
+    use super::SseBridge;
+    use crate::{
+        reverse::HttpConfig,
+        test_util::{connect_http, load_http, read_head, run_upstream},
+    };
+
+    /// 读取chunked的事件流直到包含指定的内容
+    async fn read_until(io: &mut DuplexStream, events: &mut String, expect: &str) {
+        while !events.contains(expect) {
+            let mut line = vec![];
+            while !line.ends_with(b"\r\n") {
+                line.push(io.read_u8().await.unwrap());
+            }
+            // 块结束的换行可能与下一个块一起发送
+            let line = String::from_utf8(line).unwrap();
+            if line.trim().is_empty() {
+                continue;
+            }
+            let len = usize::from_str_radix(line.trim(), 16).unwrap();
+            let mut data = vec![0u8; len];
+            io.read_exact(&mut data).await.unwrap();
+            events.push_str(std::str::from_utf8(&data).unwrap());
+        }
+    }
+
+    /// 模拟上游的websocket服务, 先发送一条消息, 之后将收到的消息加上`echo:`返回
+    async fn run_ws_upstream() -> u16 {
+        let addr = run_upstream(|mut stream, head| async move {
+            let key = head
+                .lines()
+                .find_map(|l| l.strip_prefix("Sec-WebSocket-Key: "))
+                .unwrap()
+                .trim()
+                .to_string();
+            let res = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                WsHandshake::build_accept(&key).unwrap()
+            );
+            stream.write_all(res.as_bytes()).await.unwrap();
+            // 等待客户端切换到websocket后再发送消息
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            let hello = b"hello\nworld";
+            stream.write_all(&[0x81, hello.len() as u8]).await.unwrap();
+            stream.write_all(hello).await.unwrap();
+            loop {
+                let mut head = [0u8; 2];
+                stream.read_exact(&mut head).await.unwrap();
+                let len = (head[1] & 0x7f) as usize;
+                let mut mask = [0u8; 4];
+                stream.read_exact(&mut mask).await.unwrap();
+                let mut payload = vec![0u8; len];
+                stream.read_exact(&mut payload).await.unwrap();
+                for (i, b) in payload.iter_mut().enumerate() {
+                    *b ^= mask[i % 4];
+                }
+                if head[0] & 0x0f != 1 {
+                    continue;
+                }
+                let mut echo = b"echo:".to_vec();
+                echo.extend(payload);
+                stream.write_all(&[0x81, echo.len() as u8]).await.unwrap();
+                stream.write_all(&echo).await.unwrap();
+            }
+        })
+        .await;
+        addr.port()
+    }
+
+    async fn connect(http: &HttpConfig, req: &str) -> DuplexStream {
+        let mut client = connect_http(http.convert_server_config()).await;
+        client.write_all(req.as_bytes()).await.unwrap();
+        client
+    }
+
+    #[tokio::test]
+    async fn relay_both_directions() {
+        let port = run_ws_upstream().await;
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            [[server.location]]
+            rule = "/events"
+            sse_bridge = "ws://127.0.0.1:{}/chat"
+            "#,
+            port
+        );
+        let http = load_http(&config);
+
+        // 上游的消息以事件流返回, 多行的消息拆分成多个data
+        let mut sse = connect(&http, "GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let head = read_head(&mut sse).await;
+        assert!(head.starts_with("http/1.1 200"));
+        assert!(head.contains("content-type: text/event-stream"));
+        let mut events = String::new();
+        read_until(&mut sse, &mut events, "data: world\n\n").await;
+        assert!(events.contains("data: hello\ndata: world\n\n"));
+        let id = events
+            .strip_prefix("event: session\ndata: ")
+            .and_then(|e| e.split('\n').next())
+            .unwrap()
+            .to_string();
+        assert!(SseBridge::session_count() >= 1);
+
+        // 客户端POST的消息发往上游
+        let req = format!(
+            "POST /events?session={} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nping",
+            id
+        );
+        let mut post = connect(&http, &req).await;
+        assert!(read_head(&mut post).await.starts_with("http/1.1 204"));
+        read_until(&mut sse, &mut events, "data: echo:ping\n\n").await;
+
+        let mut post = connect(
+            &http,
+            "POST /events?session=unknown HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nping",
+        )
+        .await;
+        assert!(read_head(&mut post).await.starts_with("http/1.1 404"));
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/12 10:16:35
+
+use std::{collections::HashMap, fmt::Display, io, path::Path, str::FromStr};
+
+use webparse::{BinaryMut, HeaderName, Response, StatusCode};
+use wenmeng::Body;
+
+/// 单个状态码的改写
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StatusTarget {
+    status: u16,
+    /// 替换返回内容的错误页文件
+    page: Option<String>,
+}
+
+/// 上游返回状态码的改写, 在收到上游的返回后, 发往客户端前处理, 未配置的状态码保持不变
+///
+/// 配置格式为空格分隔的`上游状态码=返回状态码[:错误页]`, 多个上游状态码可用`,`分隔,
+/// 如`500,502=503:html/maintain.html 401=404`, 配置了错误页时将丢弃上游的内容, 替换为该文件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigStatusMap {
+    map: HashMap<u16, StatusTarget>,
+    /// 原始的配置顺序, 用于输出
+    origin: Vec<(Vec<u16>, StatusTarget)>,
+}
+
+impl ConfigStatusMap {
+    /// 改写返回的状态码, 替换了返回内容时返回true, 此时上游的连接不可复用
+    pub async fn apply(&self, res: &mut Response<Body>) -> bool {
+        let target = match self.map.get(&res.status().as_u16()) {
+            Some(target) => target,
+            None => return false,
+        };
+        log::trace!("上游返回状态码{}改写为{}", res.status(), target.status);
+        if let Ok(status) = StatusCode::from_u16(target.status) {
+            *res.status_mut() = status;
+        }
+        let page = match &target.page {
+            Some(page) => page,
+            None => return false,
+        };
+        let data = match tokio::fs::read(page).await {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("读取错误页{}失败, 保留上游的返回内容: {:?}", page, e);
+                return false;
+            }
+        };
+        let headers = res.headers_mut();
+        headers.remove(&HeaderName::CONTENT_ENCODING);
+        headers.remove(&HeaderName::TRANSFER_ENCODING);
+        headers.remove(&HeaderName::ETAG);
+        headers.remove(&HeaderName::LAST_MODIFIED);
+        headers.insert(HeaderName::CONTENT_TYPE, Self::content_type(page));
+        headers.insert(HeaderName::CONTENT_LENGTH, data.len());
+        let mut binary = BinaryMut::with_capacity(data.len());
+        binary.put_slice(&data);
+        *res.body_mut() = Body::new_binary(binary);
+        true
+    }
+
+    pub(crate) fn content_type(page: &str) -> &'static str {
+        let ext = Path::new(page)
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        match &*ext {
+            "html" | "htm" => "text/html; charset=utf-8",
+            "json" => "application/json; charset=utf-8",
+            _ => "text/plain; charset=utf-8",
+        }
+    }
+
+    fn parse_status(v: &str, s: &str) -> io::Result<u16> {
+        match v.parse::<u16>() {
+            Ok(status) if (100..=999).contains(&status) => Ok(status),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("status_map中的状态码无效:{}", s),
+            )),
+        }
+    }
+}
+
+impl FromStr for ConfigStatusMap {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = HashMap::new();
+        let mut origin = vec![];
+        for v in s.split_whitespace() {
+            let (from, to) = v.split_once('=').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("未知的status_map配置:{}", v),
+                )
+            })?;
+            let (to, page) = match to.split_once(':') {
+                Some((to, page)) if !page.is_empty() => (to, Some(page.to_string())),
+                _ => (to, None),
+            };
+            let target = StatusTarget {
+                status: Self::parse_status(to, v)?,
+                page,
+            };
+            let mut list = vec![];
+            for from in from.split(',') {
+                let from = Self::parse_status(from, v)?;
+                map.insert(from, target.clone());
+                list.push(from);
+            }
+            origin.push((list, target));
+        }
+        if map.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "status_map不能为空"));
+        }
+        Ok(Self { map, origin })
+    }
+}
+
+impl Display for ConfigStatusMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = self
+            .origin
+            .iter()
+            .map(|(from, target)| {
+                let from = from.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",");
+                match &target.page {
+                    Some(page) => format!("{}={}:{}", from, target.status, page),
+                    None => format!("{}={}", from, target.status),
+                }
+            })
+            .collect::<Vec<_>>();
+        f.write_str(&list.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::Response;
+    use wenmeng::Body;
+
+    use super::ConfigStatusMap;
+
+    fn build_response(status: u16) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .header("Content-Length", "8")
+            .body("upstream")
+            .unwrap()
+            .into_type()
+    }
+
+    #[tokio::test]
+    async fn simple_remap() {
+        let map = "500,502=503 401=404".parse::<ConfigStatusMap>().unwrap();
+        assert_eq!(format!("{}", map), "500,502=503 401=404");
+        assert!("500".parse::<ConfigStatusMap>().is_err());
+        assert!("500=abc".parse::<ConfigStatusMap>().is_err());
+
+        for (from, to) in [(500, 503), (502, 503), (401, 404), (200, 200), (404, 404)] {
+            let mut res = build_response(from);
+            assert!(!map.apply(&mut res).await);
+            assert_eq!(res.status().as_u16(), to);
+        }
+    }
+
+    #[tokio::test]
+    async fn remap_with_page() {
+        let path = std::env::temp_dir().join(format!("wmproxy_maintain_{}.html", std::process::id()));
+        std::fs::write(&path, "<h1>maintain</h1>").unwrap();
+        let map = format!("500=503:{}", path.display())
+            .parse::<ConfigStatusMap>()
+            .unwrap();
+        let mut res = build_response(500);
+        assert!(map.apply(&mut res).await);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(res.status().as_u16(), 503);
+        assert_eq!(
+            res.headers().get_str_value(&"Content-Type").unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(res.headers().get_body_len(), 17);
+        let mut body = webparse::BinaryMut::new();
+        res.body_mut().read_all(&mut body).await.unwrap();
+        assert_eq!(body.as_slice(), b"<h1>maintain</h1>");
+
+        // 错误页不存在时仅改写状态码
+        let mut res = build_response(500);
+        assert!(!map.apply(&mut res).await);
+        assert_eq!(res.status().as_u16(), 503);
+    }
+}
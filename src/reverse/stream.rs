@@ -23,7 +23,7 @@ use futures_core::Stream;
 
 use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{copy_bidirectional, AsyncRead, AsyncWrite, Interest, ReadBuf},
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest, ReadBuf},
     net::{TcpListener, UdpSocket},
     sync::{
         mpsc::{channel, Receiver, Sender},
@@ -35,7 +35,7 @@ use tokio_util::sync::PollSender;
 use webparse::{BinaryMut, Buf, BufMut};
 use wenmeng::plugins::{StreamToWs, WsToStream};
 
-use crate::{HealthCheck, Helper, ProxyError, ProxyResult};
+use crate::{ConfigDscp, HealthCheck, Helper, ProxyError, ProxyResult};
 
 use super::{ServerConfig, UpstreamConfig};
 
@@ -64,25 +64,36 @@ impl StreamConfig {
     }
 
     /// stream的绑定，按bind_mode区分出udp或者是tcp，返回相应的列表
-    pub async fn bind(&mut self) -> ProxyResult<(Vec<TcpListener>, Vec<StreamUdp>)> {
+    /// strict为false时跳过绑定失败的地址
+    pub async fn bind(&mut self, strict: bool) -> ProxyResult<(Vec<TcpListener>, Vec<StreamUdp>)> {
         let mut listeners = vec![];
         let mut udp_listeners = vec![];
         let mut bind_port = HashSet::new();
-        for value in &self.server.clone() {
-            for v in &value.bind_addr.0 {
+        for (i, value) in self.server.clone().iter().enumerate() {
+            if value.transparent && !cfg!(all(feature = "tproxy", target_os = "linux")) {
+                return Err(ProxyError::Extension("透明代理需要在linux下开启tproxy特性"));
+            }
+            for (j, v) in value.bind_addr.0.iter().enumerate() {
                 if bind_port.contains(&v.port()) {
                     continue;
                 }
                 bind_port.insert(v.port());
                 if value.bind_mode == "udp" {
                     log::info!("负载均衡,stream：{:?}，提供stream中的udp转发功能。", v);
-                    let listener = Helper::bind_upd(v).await?;
-                    udp_listeners.push(StreamUdp::new(listener, value.clone()));
+                    match Helper::bind_upd(v, value.bind_addr.device(v)).await {
+                        Ok(listener) => udp_listeners.push(StreamUdp::new(listener, value.clone())),
+                        Err(e) => Helper::bind_failed(v, e, strict)?,
+                    }
                 } else {
                     log::info!("负载均衡,stream：{:?}，提供stream中的tcp转发功能。", v);
 
-                    let listener = Helper::bind(v).await?;
-                    listeners.push(listener);
+                    if let Some(listener) = Helper::bind_listener(v, value.bind_addr.device(v), strict).await? {
+                        // 端口为0时记录系统分配的端口, 接收连接时按端口匹配server
+                        if v.port() == 0 {
+                            self.server[i].bind_addr.0[j] = listener.local_addr()?;
+                        }
+                        listeners.push(listener);
+                    }
                 }
             }
         }
@@ -94,46 +105,108 @@ impl StreamConfig {
         data: Arc<Mutex<StreamConfig>>,
         local_addr: SocketAddr,
         mut inbound: T,
-        _addr: SocketAddr,
+        addr: SocketAddr,
     ) -> ProxyResult<()>
     where
         T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
     {
-        let value = data.lock().await;
-        for (_, s) in value.server.iter().enumerate() {
-            if s.bind_addr.contains(local_addr.port()) {
-                let (addr, domain) = s.get_addr_domain()?;
-                if addr.is_none() {
-                    return Err(ProxyError::Extension("unknow addr"));
+        let client_addr = addr;
+        // 复制匹配的配置后释放锁, 避免等待客户端数据或转发时阻塞其它连接
+        let server = {
+            let value = data.lock().await;
+            value
+                .server
+                .iter()
+                .find(|s| s.bind_addr.contains(local_addr.port()))
+                .cloned()
+        };
+        if let Some(s) = server {
+            let (addr, domain) = s.get_addr_domain()?;
+            if addr.is_none() {
+                return Err(ProxyError::Extension("unknow addr"));
+            }
+            let addr = s.map_upstream_port(local_addr.port(), addr.unwrap())?;
+            if s.bind_mode == "ws2tcp" {
+                let mut ws_to_stream = WsToStream::new(inbound, addr)?;
+                if let Some(domain) = domain {
+                    ws_to_stream.set_domain(domain);
                 }
-                let addr = addr.unwrap();
-                if s.bind_mode == "ws2tcp" {
-                    let mut ws_to_stream = WsToStream::new(inbound, addr)?;
-                    if domain.is_some() {
-                        ws_to_stream.set_domain(domain.unwrap());
-                    }
-                    let _ = ws_to_stream.copy_bidirectional().await;
-                } else if s.bind_mode == "tcp2ws" {
-                    let mut stream_to_ws = StreamToWs::new(inbound, format!("ws://{}", addr))?;
-                    if domain.is_some() {
-                        stream_to_ws.set_domain(domain.unwrap());
-                    }
-                    let _ = stream_to_ws.copy_bidirectional().await;
-                } else if s.bind_mode == "tcp2wss" {
-                    let mut stream_to_ws = StreamToWs::new(inbound, format!("wss://{}", addr))?;
-                    if domain.is_some() {
-                        stream_to_ws.set_domain(domain.unwrap());
-                    }
-                    let _ = stream_to_ws.copy_bidirectional().await;
+                let _ = ws_to_stream.copy_bidirectional().await;
+            } else if s.bind_mode == "tcp2ws" {
+                let mut stream_to_ws = StreamToWs::new(inbound, format!("ws://{}", addr))?;
+                if let Some(domain) = domain {
+                    stream_to_ws.set_domain(domain);
+                }
+                let _ = stream_to_ws.copy_bidirectional().await;
+            } else if s.bind_mode == "tcp2wss" {
+                let mut stream_to_ws = StreamToWs::new(inbound, format!("wss://{}", addr))?;
+                if let Some(domain) = domain {
+                    stream_to_ws.set_domain(domain);
+                }
+                let _ = stream_to_ws.copy_bidirectional().await;
+            } else {
+                let first = match Self::before_connect(&s, &mut inbound).await? {
+                    Some(first) => first,
+                    None => return Ok(()),
+                };
+                let connect_timeout = s.comm.proxy_connect_timeout.as_ref().map(|t| t.0);
+                if s.transparent {
+                    let mut connect =
+                        HealthCheck::connect_transparent(&addr, client_addr, connect_timeout).await?;
+                    connect.write_all(&first).await?;
+                    copy_bidirectional(&mut inbound, &mut connect).await?;
                 } else {
-                    let mut connect = HealthCheck::connect(&addr).await?;
+                    let local_bind = s.get_local_bind();
+                    let upstream_proxy = s.get_upstream_proxy();
+                    let mut connect = HealthCheck::connect_upstream(
+                        &addr.to_string(),
+                        connect_timeout,
+                        local_bind.as_deref(),
+                        upstream_proxy.as_ref(),
+                    )
+                    .await?;
+                    ConfigDscp::apply_option(&s.get_upstream_dscp(), &connect);
+                    let _in_flight = HealthCheck::track(addr);
+                    connect.write_all(&first).await?;
                     copy_bidirectional(&mut inbound, &mut connect).await?;
                 }
-                break;
             }
         }
         Ok(())
     }
+
+    /// 连接上游前的处理, 发送配置的banner, 及按需等待客户端的首个数据
+    /// 返回需先发往上游的数据, 客户端在发送数据前关闭时返回None, 此时不连接上游
+    async fn before_connect<T>(s: &ServerConfig, inbound: &mut T) -> ProxyResult<Option<Vec<u8>>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        if let Some(banner) = &s.banner {
+            inbound.write_all(banner.as_bytes()).await?;
+            inbound.flush().await?;
+        }
+        if !s.proxy_connect_on_first_byte {
+            return Ok(Some(vec![]));
+        }
+        let mut buf = vec![0u8; 4096];
+        let read = inbound.read(&mut buf);
+        let size = match &s.comm.client_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout.0, read).await {
+                Ok(size) => size?,
+                Err(_) => {
+                    log::trace!("等待客户端的首个数据超时({:?}), 不连接上游", timeout.0);
+                    return Ok(None);
+                }
+            },
+            None => read.await?,
+        };
+        if size == 0 {
+            log::trace!("客户端未发送数据即关闭, 不连接上游");
+            return Ok(None);
+        }
+        buf.truncate(size);
+        Ok(Some(buf))
+    }
 }
 
 struct InnerUdp {
@@ -272,7 +345,8 @@ impl StreamUdp {
             return Err(crate::ProxyError::Extension("当前负载地址不存在"));
         }
 
-        let remote_addr = remote_addr.unwrap();
+        let local_port = self.local_addr()?.port();
+        let remote_addr = self.server.map_upstream_port(local_port, remote_addr.unwrap())?;
         let (sender, receiver) = channel(10);
         let mut timeout = Duration::new(60, 0);
         if self.server.comm.client_timeout.is_some() {
@@ -396,3 +470,109 @@ impl Stream for StreamUdp {
         self.poll_read(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::Mutex,
+    };
+
+    use super::StreamConfig;
+
+    /// 模拟上游, 返回自身的标记
+    async fn run_upstream(tag: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(tag.as_bytes()).await.unwrap();
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn port_range_forward() {
+        let first = run_upstream("first").await;
+        let second = run_upstream("second").await;
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:19100-19101"
+            bind_ssl = ""
+            proxy_url = "http://127.0.0.1:1"
+            port_map = "19100={} 19101={}"
+            "#,
+            first, second
+        );
+        let mut stream = toml::from_str::<StreamConfig>(&config).unwrap();
+        stream.copy_to_child();
+        assert_eq!(stream.server[0].bind_addr.0.len(), 2);
+        let data = Arc::new(Mutex::new(stream));
+
+        for (port, tag) in [(19101, "second"), (19100, "first")] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let local_addr = format!("127.0.0.1:{}", port).parse().unwrap();
+            let client_addr = "127.0.0.1:1234".parse().unwrap();
+            tokio::spawn(StreamConfig::process(data.clone(), local_addr, server, client_addr));
+            let mut buf = vec![0u8; tag.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, tag.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn deferred_connect_and_banner() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:19102"
+            bind_ssl = ""
+            proxy_url = "tcp://127.0.0.1:{}"
+            banner = "220 wmproxy ready\r\n"
+            proxy_connect_on_first_byte = true
+            "#,
+            port
+        );
+        let stream = toml::from_str::<StreamConfig>(&config).unwrap();
+        let data = Arc::new(Mutex::new(stream));
+        let local_addr: std::net::SocketAddr = "127.0.0.1:19102".parse().unwrap();
+        let client_addr: std::net::SocketAddr = "127.0.0.1:1234".parse().unwrap();
+
+        // 客户端仅接收banner后关闭, 不连接上游
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(StreamConfig::process(data.clone(), local_addr, server, client_addr));
+        let mut buf = vec![0u8; 19];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"220 wmproxy ready\r\n");
+        let accept = tokio::time::timeout(Duration::from_millis(200), listener.accept()).await;
+        assert!(accept.is_err());
+        drop(client);
+        handle.await.unwrap().unwrap();
+        let accept = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+        assert!(accept.is_err());
+
+        // 收到首个数据后连接上游, 并将该数据发往上游
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(StreamConfig::process(data.clone(), local_addr, server, client_addr));
+        let mut buf = vec![0u8; 19];
+        client.read_exact(&mut buf).await.unwrap();
+        client.write_all(b"HELO").await.unwrap();
+        let (mut upstream, _) = tokio::time::timeout(Duration::from_secs(1), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut buf = vec![0u8; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"HELO");
+        upstream.write_all(b"250").await.unwrap();
+        let mut buf = vec![0u8; 3];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"250");
+    }
+}
@@ -0,0 +1,304 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/24 09:41:27
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use lazy_static::lazy_static;
+use tokio::sync::mpsc::channel;
+use webparse::{BinaryMut, HeaderName, Method, Request, Response};
+use wenmeng::Body;
+
+use super::body_buffer::{forward_body, read_body_data, READ_BUFFER};
+use crate::ConfigSize;
+
+lazy_static! {
+    // 已注册的转换器, 按名字在配置中引用
+    static ref TRANSFORMS: RwLock<HashMap<String, Arc<dyn BodyTransform>>> = RwLock::new(HashMap::new());
+}
+
+/// 默认最大转换的body大小, 超过时按原样转发
+const DEFAULT_MAX_SIZE: u64 = 2 * 1024 * 1024;
+
+/// 返回内容的转换器, 如缩放图片或精简css, 以完整的body进行转换
+pub trait BodyTransform: Send + Sync {
+    /// 是否处理该Content-Type的返回
+    fn is_match(&self, content_type: &str) -> bool;
+    /// 转换body, 失败时按原样返回
+    fn transform(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// location中返回内容的转换, 如`uppercase max_size=1m`, 多个转换器按顺序执行
+///
+/// 转换器需先通过`ConfigTransform::register`注册, 以下情况不做转换:
+/// 范围请求及206, 已压缩的返回, 超过max_size(默认2m)的body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigTransform {
+    names: Vec<String>,
+    max_size: u64,
+}
+
+impl ConfigTransform {
+    /// 注册转换器, 同名的转换器将被替换
+    pub fn register(name: &str, transform: Arc<dyn BodyTransform>) {
+        if let Ok(mut transforms) = TRANSFORMS.write() {
+            transforms.insert(name.to_string(), transform);
+        }
+    }
+
+    /// 该返回需执行的转换器, 未注册的名字忽略
+    fn matched(&self, content_type: &str) -> Vec<Arc<dyn BodyTransform>> {
+        let transforms = match TRANSFORMS.read() {
+            Ok(transforms) => transforms,
+            Err(_) => return vec![],
+        };
+        self.names
+            .iter()
+            .filter_map(|name| transforms.get(name))
+            .filter(|t| t.is_match(content_type))
+            .cloned()
+            .collect()
+    }
+
+    fn is_skip(req: &Request<Body>, res: &Response<Body>) -> bool {
+        let status = res.status().as_u16();
+        if *req.method() == Method::Head || status < 200 || status == 204 || status == 206 || status == 304 {
+            return true;
+        }
+        if req.headers().contains(&HeaderName::RANGE) || res.headers().contains(&HeaderName::CONTENT_RANGE) {
+            return true;
+        }
+        res.headers()
+            .get_str_value(&HeaderName::CONTENT_ENCODING)
+            .map(|e| !e.eq_ignore_ascii_case("identity"))
+            .unwrap_or(false)
+    }
+
+    /// 转换返回的内容并重新计算`Content-Length`, 未知长度的body读取超过max_size时还原成流转发
+    pub async fn apply(&self, req: &Request<Body>, res: &mut Response<Body>) {
+        if Self::is_skip(req, res) {
+            return;
+        }
+        let content_type = res
+            .headers()
+            .get_str_value(&HeaderName::CONTENT_TYPE)
+            .unwrap_or_default();
+        let transforms = self.matched(&content_type);
+        if transforms.is_empty() {
+            return;
+        }
+        let len = res.get_body_len();
+        if len > 0 && len as u64 > self.max_size {
+            return;
+        }
+        let mut data = BinaryMut::new();
+        let mut buf = vec![0u8; READ_BUFFER];
+        loop {
+            match read_body_data(res.body_mut(), &mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    data.put_slice(&buf[..n]);
+                }
+                Err(e) => {
+                    log::warn!("读取待转换的body失败:{:?}", e);
+                    *res.body_mut() = Body::new_binary(data);
+                    return;
+                }
+            }
+            if data.len() as u64 > self.max_size {
+                log::trace!("body超过{}字节, 不做转换", self.max_size);
+                let (sender, receiver) = channel(10);
+                let mut rest = std::mem::replace(res.body_mut(), Body::new(receiver, data, false));
+                tokio::spawn(async move { forward_body(&mut rest, &sender, true).await });
+                return;
+            }
+        }
+        let mut value = data.as_slice().to_vec();
+        for transform in transforms {
+            match transform.transform(&value) {
+                Ok(v) => value = v,
+                Err(e) => {
+                    log::warn!("转换{}的返回内容失败, 按原样返回:{:?}", req.url(), e);
+                    value = data.as_slice().to_vec();
+                    break;
+                }
+            }
+        }
+        let headers = res.headers_mut();
+        headers.remove(&HeaderName::TRANSFER_ENCODING);
+        headers.remove(&HeaderName::ETAG);
+        headers.insert(HeaderName::CONTENT_LENGTH, value.len());
+        let mut binary = BinaryMut::with_capacity(value.len());
+        binary.put_slice(&value);
+        *res.body_mut() = Body::new_binary(binary);
+    }
+}
+
+impl FromStr for ConfigTransform {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut names = vec![];
+        let mut max_size = DEFAULT_MAX_SIZE;
+        for v in s.split_whitespace() {
+            match v.strip_prefix("max_size=") {
+                Some(size) => max_size = size.parse::<ConfigSize>()?.0,
+                None => names.push(v.to_string()),
+            }
+        }
+        if names.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("transform未配置转换器:{}", s),
+            ));
+        }
+        Ok(Self { names, max_size })
+    }
+}
+
+impl Display for ConfigTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.names.join(" "))?;
+        if self.max_size != DEFAULT_MAX_SIZE {
+            write!(f, " max_size={}", ConfigSize(self.max_size))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::mpsc::channel,
+    };
+    use webparse::{Binary, BinaryMut, HeaderName, Request, Response};
+    use wenmeng::Body;
+
+    use super::{BodyTransform, ConfigTransform};
+    use crate::test_util::{connect_http, load_http};
+
+    struct Uppercase;
+
+    impl BodyTransform for Uppercase {
+        fn is_match(&self, content_type: &str) -> bool {
+            content_type.starts_with("text/plain")
+        }
+
+        fn transform(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(data.to_ascii_uppercase())
+        }
+    }
+
+    fn build_request(range: bool) -> Request<Body> {
+        let mut builder = Request::builder().url("/a.txt");
+        if range {
+            builder = builder.header("Range", "bytes=0-1");
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    /// 未知长度, 按流返回的body
+    fn stream_response(parts: &[&'static str]) -> Response<Body> {
+        let (sender, receiver) = channel(10);
+        let parts = parts.to_vec();
+        tokio::spawn(async move {
+            for part in parts {
+                let _ = sender.send((false, Binary::from_static(part.as_bytes()))).await;
+            }
+            let _ = sender.send((true, Binary::new())).await;
+        });
+        Response::builder()
+            .header("Content-Type", "text/plain")
+            .header("Transfer-Encoding", "chunked")
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap()
+    }
+
+    async fn read_body(res: &mut Response<Body>) -> String {
+        let mut body = BinaryMut::new();
+        res.body_mut().read_all(&mut body).await.unwrap();
+        String::from_utf8_lossy(body.as_slice()).to_string()
+    }
+
+    #[tokio::test]
+    async fn uppercase_transform() {
+        ConfigTransform::register("uppercase", Arc::new(Uppercase));
+        let config = "uppercase".parse::<ConfigTransform>().unwrap();
+        assert_eq!(config.to_string(), "uppercase");
+        let small = "uppercase max_size=8".parse::<ConfigTransform>().unwrap();
+        assert_eq!(small.to_string(), "uppercase max_size=8");
+        assert!("max_size=1m".parse::<ConfigTransform>().is_err());
+
+        // 按流返回的body读取完毕后转换, 重新计算长度
+        let mut res = stream_response(&["hello ", "world"]);
+        config.apply(&build_request(false), &mut res).await;
+        assert_eq!(res.headers().get_body_len(), 11);
+        assert!(!res.headers().contains(&HeaderName::TRANSFER_ENCODING));
+        assert_eq!(read_body(&mut res).await, "HELLO WORLD");
+
+        // 超过max_size时按原样转发全部的数据
+        let mut res = stream_response(&["hello ", "world"]);
+        small.apply(&build_request(false), &mut res).await;
+        assert_eq!(read_body(&mut res).await, "hello world");
+
+        // 范围请求及不匹配的类型不做转换
+        let mut res = Response::text().body("hello").unwrap().into_type();
+        config.apply(&build_request(true), &mut res).await;
+        assert_eq!(read_body(&mut res).await, "hello");
+        let mut res = Response::builder()
+            .header("Content-Type", "image/png")
+            .body("hello")
+            .unwrap()
+            .into_type();
+        config.apply(&build_request(false), &mut res).await;
+        assert_eq!(read_body(&mut res).await, "hello");
+    }
+
+    #[tokio::test]
+    async fn transform_location() {
+        ConfigTransform::register("uppercase", Arc::new(Uppercase));
+        let config = r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            static_response = "hello transform"
+            transform = "uppercase"
+        "#;
+        let mut client = connect_http(load_http(config).convert_server_config()).await;
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !String::from_utf8_lossy(&data).ends_with("HELLO TRANSFORM") {
+            let n = tokio::time::timeout(std::time::Duration::from_secs(2), client.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0);
+            data.extend_from_slice(&buf[..n]);
+        }
+        let data = String::from_utf8_lossy(&data).to_lowercase();
+        assert!(data.contains("content-length: 15\r\n"), "{}", data);
+    }
+}
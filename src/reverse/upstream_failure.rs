@@ -0,0 +1,90 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/23 10:21:06
+
+use std::{fmt::Display, io};
+
+use webparse::Response;
+use wenmeng::{Body, ProtError};
+
+/// 连接上游失败的原因, 仅记录在日志中, 客户端返回502, 排队已满时返回503
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamFailure {
+    /// 连接被拒绝或不可达
+    Refused,
+    /// TLS握手失败, 如证书无效或上游不是TLS服务
+    Tls,
+    /// 建立连接或TLS握手超时
+    Timeout,
+    /// 等待建立连接的排队已满
+    Busy,
+}
+
+impl UpstreamFailure {
+    const REFUSED: &'static str = "upstream connection refused";
+    const TLS: &'static str = "upstream tls handshake failed";
+    const TIMEOUT: &'static str = "upstream connect timeout";
+    const BUSY: &'static str = "upstream connect queue full";
+
+    /// 按建立连接时的错误分类
+    pub fn from_connect(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => UpstreamFailure::Timeout,
+            _ => UpstreamFailure::Refused,
+        }
+    }
+
+    /// 转成返回的错误, 重试或返回502时以此识别
+    pub fn to_error(self) -> ProtError {
+        ProtError::Extension(match self {
+            UpstreamFailure::Refused => Self::REFUSED,
+            UpstreamFailure::Tls => Self::TLS,
+            UpstreamFailure::Timeout => Self::TIMEOUT,
+            UpstreamFailure::Busy => Self::BUSY,
+        })
+    }
+
+    pub fn from_error(e: &ProtError) -> Option<Self> {
+        match e {
+            ProtError::Extension(Self::REFUSED) => Some(UpstreamFailure::Refused),
+            ProtError::Extension(Self::TLS) => Some(UpstreamFailure::Tls),
+            ProtError::Extension(Self::TIMEOUT) => Some(UpstreamFailure::Timeout),
+            ProtError::Extension(Self::BUSY) => Some(UpstreamFailure::Busy),
+            _ => None,
+        }
+    }
+
+    /// 返回给客户端的502或503, 不包含失败的细节, 失败原因写入extensions供日志使用
+    pub fn response(&self) -> Response<Body> {
+        let (status, reason) = match self {
+            UpstreamFailure::Busy => (503, "Service Unavailable"),
+            _ => (502, "Bad Gateway"),
+        };
+        let mut res = Response::text()
+            .status(status)
+            .body(reason)
+            .unwrap()
+            .into_type();
+        res.extensions_mut().insert(*self);
+        res
+    }
+}
+
+impl Display for UpstreamFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamFailure::Refused => f.write_str("上游拒绝连接"),
+            UpstreamFailure::Tls => f.write_str("上游TLS握手失败"),
+            UpstreamFailure::Timeout => f.write_str("连接上游超时"),
+            UpstreamFailure::Busy => f.write_str("等待连接上游的排队已满"),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/24 14:16:52
+
+use std::{
+    fmt::Display,
+    fs,
+    io::{self, BufReader},
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
+
+use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use wenmeng::ClientOption;
+
+use crate::CertLoader;
+
+/// 连接上游的TLS配置, 如`ca=certs/ca.pem cert=certs/client.pem key=certs/client.key sni=api.internal`
+/// * `ca` 校验上游证书的CA, 未配置时使用内置的根证书
+/// * `cert`, `key` 上游要求双向认证时提供的客户端证书, `.p12`或`.pfx`的证书无需配置key
+/// * `sni` 握手时的SNI及校验的域名, 未配置时与发往上游的Host一致
+#[derive(Debug, Clone, Default)]
+pub struct ConfigUpstreamTls {
+    pub ca: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub sni: Option<String>,
+    /// 首次使用时加载的证书, 多个连接共用
+    config: Arc<OnceLock<Arc<ClientConfig>>>,
+}
+
+impl ConfigUpstreamTls {
+    fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+
+    fn build(&self) -> io::Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca {
+            Some(ca) => {
+                let data = fs::read(ca)
+                    .map_err(|e| Self::invalid(format!("加载上游的CA{}失败: {}", ca, e)))?;
+                for cert in rustls_pemfile::certs(&mut BufReader::new(&data[..])) {
+                    roots.add(cert?).map_err(|e| Self::invalid(format!("CA{}无效: {}", ca, e)))?;
+                }
+                if roots.is_empty() {
+                    return Err(Self::invalid(format!("CA{}中未找到证书", ca)));
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let mut config = match &self.cert {
+            Some(cert) => {
+                let (certs, key) = CertLoader::load(cert, self.key.as_deref(), &None)?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .map_err(|e| Self::invalid(format!("客户端证书{}无效: {}", cert, e)))?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![b"http/1.1".to_vec(), ClientOption::H2_PROTOCOL.to_vec()];
+        Ok(config)
+    }
+
+    /// 加载证书, 配置错误时在启动时即返回错误
+    pub fn load(&self) -> io::Result<Arc<ClientConfig>> {
+        if let Some(config) = self.config.get() {
+            return Ok(config.clone());
+        }
+        let config = Arc::new(self.build()?);
+        Ok(self.config.get_or_init(|| config).clone())
+    }
+
+    /// 与上游TLS握手, host为未配置sni时使用的域名, 返回握手后的连接及上游是否选择了h2
+    pub async fn connect(&self, stream: TcpStream, host: &str) -> io::Result<(TlsStream<TcpStream>, bool)> {
+        let name = self.sni.as_deref().unwrap_or(host).to_string();
+        let domain = ServerName::try_from(name).map_err(|_| Self::invalid("invalid dnsname"))?;
+        let outbound = TlsConnector::from(self.load()?).connect(domain, stream).await?;
+        let h2 = outbound.get_ref().1.alpn_protocol() == Some(&ClientOption::H2_PROTOCOL);
+        Ok((outbound, h2))
+    }
+}
+
+impl PartialEq for ConfigUpstreamTls {
+    fn eq(&self, other: &Self) -> bool {
+        self.ca == other.ca && self.cert == other.cert && self.key == other.key && self.sni == other.sni
+    }
+}
+
+impl FromStr for ConfigUpstreamTls {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tls = ConfigUpstreamTls::default();
+        for v in s.split_whitespace() {
+            let (key, value) = v
+                .split_once('=')
+                .ok_or_else(|| Self::invalid(format!("上游的tls配置无效:{}", v)))?;
+            let value = Some(value.to_string());
+            match key {
+                "ca" => tls.ca = value,
+                "cert" => tls.cert = value,
+                "key" => tls.key = value,
+                "sni" => tls.sni = value,
+                _ => return Err(Self::invalid(format!("上游的tls配置无效:{}", v))),
+            }
+        }
+        if tls.key.is_some() && tls.cert.is_none() {
+            return Err(Self::invalid("上游的tls配置了key但未配置cert"));
+        }
+        Ok(tls)
+    }
+}
+
+impl Display for ConfigUpstreamTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut values = vec![];
+        for (key, value) in [
+            ("ca", &self.ca),
+            ("cert", &self.cert),
+            ("key", &self.key),
+            ("sni", &self.sni),
+        ] {
+            if let Some(value) = value {
+                values.push(format!("{}={}", key, value));
+            }
+        }
+        f.write_str(&values.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigUpstreamTls;
+
+    #[test]
+    fn parse_tls() {
+        let tls = "ca=certs/ca.pem cert=certs/client.pem key=certs/client.key sni=api.internal"
+            .parse::<ConfigUpstreamTls>()
+            .unwrap();
+        assert_eq!(tls.ca.as_deref(), Some("certs/ca.pem"));
+        assert_eq!(tls.sni.as_deref(), Some("api.internal"));
+        assert_eq!(tls.to_string().parse::<ConfigUpstreamTls>().unwrap(), tls);
+        assert!("ca".parse::<ConfigUpstreamTls>().is_err());
+        assert!("key=a.key".parse::<ConfigUpstreamTls>().is_err());
+        assert!("ca=/not/exist.pem".parse::<ConfigUpstreamTls>().unwrap().load().is_err());
+    }
+}
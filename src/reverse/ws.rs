@@ -10,27 +10,163 @@
 // -----
 // Created Date: 2023/10/18 02:32:23
 
-use std::sync::Arc;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
 
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use webparse::ws::{CloseData, OwnedMessage};
+use webparse::{
+    ws::{CloseData, OwnedMessage},
+    Response,
+};
 use wenmeng::{
-    ws::{WsHandshake, WsOption, WsTrait},
-    Client, ProtError, ProtResult,
+    ws::{WsDeflateConfig, WsHandshake, WsOption, WsTrait},
+    Client, ProtError, ProtResult, RecvRequest, RecvResponse,
 };
 
-use super::{ReverseHelper, ServerConfig};
+use super::{ConfigRejectPage, ReverseHelper, ServerConfig};
+
+/// 所有代理中的websocket连接数
+static WS_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+/// 因超过连接数被拒绝的websocket升级数
+static WS_REJECTED: AtomicUsize = AtomicUsize::new(0);
+
+/// websocket的连接数限制, 与HTTP的请求数限制相互独立
+#[derive(Debug)]
+pub struct WsLimit {
+    max: usize,
+    count: AtomicUsize,
+}
+
+/// 占用的websocket连接名额, 连接关闭时归还
+#[derive(Debug)]
+pub struct WsPermit {
+    limit: Option<Arc<WsLimit>>,
+}
+
+impl Drop for WsPermit {
+    fn drop(&mut self) {
+        if let Some(limit) = &self.limit {
+            limit.count.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl WsLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// 获取连接名额, 超过最大连接数返回None
+    pub fn try_acquire(self: &Arc<Self>) -> Option<WsPermit> {
+        if self.count.fetch_add(1, Ordering::Relaxed) >= self.max {
+            self.count.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(WsPermit {
+            limit: Some(self.clone()),
+        })
+    }
+
+    /// 当前的连接数
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// 所有代理中的websocket连接数
+    pub fn connection_total() -> usize {
+        WS_CONNECTIONS.load(Ordering::Relaxed)
+    }
+
+    /// 因超过连接数被拒绝的websocket升级数
+    pub fn rejected_total() -> usize {
+        WS_REJECTED.load(Ordering::Relaxed)
+    }
+}
+
+/// 计入全局的连接数, 连接关闭时减少
+struct WsConnGuard;
+
+impl WsConnGuard {
+    fn new() -> Self {
+        WS_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for WsConnGuard {
+    fn drop(&mut self) {
+        WS_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 pub struct ServerWsOperate {
     inner: InnerWsOper,
     sender: Option<Sender<OwnedMessage>>,
+    /// 发往客户端的消息, 空闲超时时发送关闭
+    client_sender: Option<Sender<OwnedMessage>>,
+    /// server及location的连接名额
+    permits: Vec<WsPermit>,
+    _conn: Option<WsConnGuard>,
+    /// 任一方向最后收到消息的时间
+    last_active: Arc<Mutex<Instant>>,
+    idle_timeout: Option<Duration>,
+    /// 已因空闲发送关闭
+    idle_closed: bool,
 }
 
 #[async_trait]
 impl WsTrait for ServerWsOperate {
+    /// 升级前检查连接数, 超过server或location的ws_max_connections时返回503
+    /// ws_compression为on时由代理与客户端协商permessage-deflate
+    async fn on_request(&mut self, req: &RecvRequest) -> ProtResult<RecvResponse> {
+        let mut compression = false;
+        if let Some((server, location)) =
+            ReverseHelper::get_server_location_by_req(&self.inner.servers, req)
+        {
+            compression = location.ws_compression == "on";
+            if location.is_ws {
+                for limit in [&server.ws_limit, &location.ws_limit].into_iter().flatten() {
+                    match limit.try_acquire() {
+                        Some(permit) => self.permits.push(permit),
+                        None => {
+                            self.permits.clear();
+                            WS_REJECTED.fetch_add(1, Ordering::Relaxed);
+                            let mut res = Response::status503()
+                                .body("too many websocket connections")
+                                .unwrap()
+                                .into_type();
+                            ConfigRejectPage::apply_option(&location.comm.reject_page, req, &mut res).await;
+                            return Ok(res);
+                        }
+                    }
+                }
+            }
+        }
+        let mut res = WsHandshake::build_request(req)?;
+        if compression && res.status() == 101 {
+            if let Some(config) = req
+                .headers()
+                .get_str_value(&"Sec-WebSocket-Extensions")
+                .and_then(|v| WsDeflateConfig::negotiate(&v))
+            {
+                res.headers_mut()
+                    .insert("Sec-WebSocket-Extensions", config.to_header());
+            }
+        }
+        Ok(res)
+    }
+
     /// 握手完成后之后的回调,服务端返回了Response之后就认为握手成功
     async fn on_open(&mut self, shake: WsHandshake) -> ProtResult<Option<WsOption>> {
         if shake.request.is_none() {
@@ -43,6 +179,12 @@ impl WsTrait for ServerWsOperate {
             if !location.is_ws {
                 return Err(ProtError::Extension("Not Support Ws"));
             }
+            self._conn = Some(WsConnGuard::new());
+            self.idle_timeout = location.comm.ws_idle_timeout.as_ref().map(|t| t.0);
+            if let Some(timeout) = self.idle_timeout {
+                // 以超时时间的1/4检查, 空闲超过超时时间后关闭
+                option.set_interval((timeout / 4).max(Duration::from_millis(10)));
+            }
             if let Ok((url, domain)) = location.get_reverse_url() {
                 println!("connect url = {}, domain = {:?}", url, domain);
                 let mut client = Client::builder()
@@ -54,15 +196,22 @@ impl WsTrait for ServerWsOperate {
                 let (cli_sender, cli_receiver) = channel::<OwnedMessage>(10);
                 option.set_receiver(serv_receiver);
                 self.sender = Some(cli_sender);
+                self.client_sender = Some(serv_sender.clone());
 
                 client.set_callback_ws(Box::new(ClientWsOperate {
                     sender: Some(serv_sender),
                     receiver: Some(cli_receiver),
+                    last_active: self.last_active.clone(),
                 }));
 
+                let mut req = shake.request.unwrap();
+                if location.ws_compression != "passthrough" {
+                    // off不压缩, on由代理与客户端之间压缩, 均不再与上游协商
+                    req.headers_mut().remove(&"Sec-WebSocket-Extensions");
+                }
                 tokio::spawn(async move {
                     if let Err(e) = client
-                        .wait_ws_operate_with_req(shake.request.unwrap())
+                        .wait_ws_operate_with_req(req)
                         .await
                     {
                         println!("error = {:?}", e);
@@ -84,6 +233,7 @@ impl WsTrait for ServerWsOperate {
 
     /// 收到来在远端的ping消息, 默认返回pong消息
     async fn on_ping(&mut self, val: Vec<u8>) -> ProtResult<Option<OwnedMessage>> {
+        touch(&self.last_active);
         if let Some(s) = &self.sender {
             s.send(OwnedMessage::Ping(val.clone())).await?;
         }
@@ -92,6 +242,7 @@ impl WsTrait for ServerWsOperate {
 
     /// 收到来在远端的pong消息, 默认不做任何处理, 可自定义处理如ttl等
     async fn on_pong(&mut self, val: Vec<u8>) -> ProtResult<()> {
+        touch(&self.last_active);
         if let Some(s) = &self.sender {
             let _ = s.send(OwnedMessage::Pong(val)).await?;
         }
@@ -100,11 +251,42 @@ impl WsTrait for ServerWsOperate {
 
     /// 收到来在远端的message消息, 必须覆写该函数
     async fn on_message(&mut self, msg: OwnedMessage) -> ProtResult<()> {
+        touch(&self.last_active);
         if let Some(s) = &self.sender {
             s.send(msg).await?;
         }
         Ok(())
     }
+
+    /// 两个方向均无消息超过ws_idle_timeout时向两端发送关闭, 再次超时仍未断开则直接断开
+    async fn on_interval(&mut self, _option: &mut Option<WsOption>) -> ProtResult<()> {
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+        if self.last_active.lock().unwrap().elapsed() < timeout {
+            return Ok(());
+        }
+        if self.idle_closed {
+            return Err(ProtError::Extension("websocket idle timeout"));
+        }
+        log::trace!("websocket空闲超过{:?}, 关闭连接", timeout);
+        self.idle_closed = true;
+        *self.last_active.lock().unwrap() = Instant::now();
+        let close = Some(CloseData::new(1001u16, "idle timeout".to_string()));
+        if let Some(s) = &self.sender {
+            let _ = s.send(OwnedMessage::Close(close.clone())).await;
+        }
+        if let Some(s) = &self.client_sender {
+            let _ = s.send(OwnedMessage::Close(close)).await;
+        }
+        Ok(())
+    }
+}
+
+/// 记录最后收到消息的时间
+fn touch(last_active: &Mutex<Instant>) {
+    *last_active.lock().unwrap() = Instant::now();
 }
 
 struct InnerWsOper {
@@ -122,6 +304,12 @@ impl ServerWsOperate {
         Self {
             inner: InnerWsOper::new(http),
             sender: None,
+            client_sender: None,
+            permits: vec![],
+            _conn: None,
+            last_active: Arc::new(Mutex::new(Instant::now())),
+            idle_timeout: None,
+            idle_closed: false,
         }
     }
 }
@@ -129,6 +317,18 @@ impl ServerWsOperate {
 pub struct ClientWsOperate {
     sender: Option<Sender<OwnedMessage>>,
     receiver: Option<Receiver<OwnedMessage>>,
+    /// 与服务端共享的最后收到消息的时间
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl ClientWsOperate {
+    pub fn new(sender: Sender<OwnedMessage>, receiver: Receiver<OwnedMessage>) -> Self {
+        Self {
+            sender: Some(sender),
+            receiver: Some(receiver),
+            last_active: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
 }
 
 #[async_trait]
@@ -149,6 +349,7 @@ impl WsTrait for ClientWsOperate {
 
     /// 收到来在远端的ping消息, 默认返回pong消息
     async fn on_ping(&mut self, val: Vec<u8>) -> ProtResult<Option<OwnedMessage>> {
+        touch(&self.last_active);
         if let Some(s) = &self.sender {
             s.send(OwnedMessage::Ping(val)).await?;
         }
@@ -157,6 +358,7 @@ impl WsTrait for ClientWsOperate {
 
     /// 收到来在远端的pong消息, 默认不做任何处理, 可自定义处理如ttl等
     async fn on_pong(&mut self, val: Vec<u8>) -> ProtResult<()> {
+        touch(&self.last_active);
         if let Some(s) = &self.sender {
             let _ = s.send(OwnedMessage::Pong(val)).await?;
         }
@@ -165,9 +367,308 @@ impl WsTrait for ClientWsOperate {
 
     /// 收到来在远端的message消息, 必须覆写该函数
     async fn on_message(&mut self, msg: OwnedMessage) -> ProtResult<()> {
+        touch(&self.last_active);
         if let Some(s) = &self.sender {
             s.send(msg).await?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::TcpListener,
+        sync::mpsc::{channel, Receiver},
+    };
+    use async_trait::async_trait;
+    use tokio::sync::mpsc::Sender;
+    use webparse::{
+        ws::{DataFrame, DataFrameable, OwnedMessage},
+        BinaryMut, BinaryRef, Buf, WebError,
+    };
+    use wenmeng::{
+        ws::{WsDeflateConfig, WsHandshake, WsOption, WsTrait},
+        ProtResult, RecvRequest, RecvResponse, Server,
+    };
+
+    use super::WsLimit;
+    use crate::{
+        reverse::HttpConfig,
+        test_util::{connect_http, load_http, read_head, run_upstream},
+    };
+
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    /// 完成握手后不发送任何消息的websocket上游, 连接断开时通知
+    async fn run_silent_ws_upstream() -> (SocketAddr, Receiver<()>) {
+        let (sender, receiver) = channel(10);
+        let addr = run_upstream(move |mut stream, head| {
+            let sender = sender.clone();
+            async move {
+                // 代理转发客户端的Sec-WebSocket-Key
+                assert!(head.contains(KEY), "{}", head);
+                let res = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    WsHandshake::build_accept(KEY).unwrap()
+                );
+                stream.write_all(res.as_bytes()).await.unwrap();
+                let mut buf = [0u8; 1024];
+                while let Ok(n) = stream.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
+                let _ = sender.send(()).await;
+            }
+        })
+        .await;
+        (addr, receiver)
+    }
+
+    /// 回显消息的websocket上游, 记录收到的压缩协商, deflate时同意客户端的压缩协商
+    struct EchoWs {
+        offers: Sender<Option<String>>,
+        deflate: bool,
+        sender: Option<Sender<OwnedMessage>>,
+    }
+
+    #[async_trait]
+    impl WsTrait for EchoWs {
+        async fn on_request(&mut self, req: &RecvRequest) -> ProtResult<RecvResponse> {
+            let offer = req.headers().get_str_value(&"Sec-WebSocket-Extensions");
+            let _ = self.offers.send(offer.clone()).await;
+            let mut res = WsHandshake::build_request(req)?;
+            if let Some(config) = offer.and_then(|v| WsDeflateConfig::negotiate(&v)) {
+                if self.deflate {
+                    res.headers_mut()
+                        .insert("Sec-WebSocket-Extensions", config.to_header());
+                }
+            }
+            Ok(res)
+        }
+
+        async fn on_open(&mut self, shake: WsHandshake) -> ProtResult<Option<WsOption>> {
+            self.sender = Some(shake.sender);
+            Ok(None)
+        }
+
+        async fn on_message(&mut self, msg: OwnedMessage) -> ProtResult<()> {
+            self.sender.as_ref().unwrap().send(msg).await?;
+            Ok(())
+        }
+    }
+
+    async fn run_echo_ws_upstream(deflate: bool) -> (SocketAddr, Receiver<Option<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (offers, receiver) = channel(10);
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let offers = offers.clone();
+                tokio::spawn(async move {
+                    let mut server = Server::new(stream, Some(addr));
+                    server.set_callback_ws(Box::new(EchoWs {
+                        offers,
+                        deflate,
+                        sender: None,
+                    }));
+                    let _ = server.incoming().await;
+                });
+            }
+        });
+        (addr, receiver)
+    }
+
+    /// 读取一个完整的websocket帧
+    async fn read_frame(io: &mut DuplexStream) -> DataFrame {
+        let mut data = vec![];
+        loop {
+            match DataFrame::read_dataframe(&mut BinaryRef::from(&data[..]), false) {
+                Ok(frame) => return frame,
+                Err(WebError::Io(_)) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+            let mut buf = [0u8; 1024];
+            let n = tokio::time::timeout(Duration::from_secs(2), io.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0);
+            data.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    async fn write_frame<F: DataFrameable>(io: &mut DuplexStream, frame: &F) {
+        let mut buf = BinaryMut::new();
+        frame.write_to(&mut buf, Some([1, 2, 3, 4])).unwrap();
+        io.write_all(buf.chunk()).await.unwrap();
+    }
+
+    async fn ws_connect_with(http: &HttpConfig, extra: &str) -> (DuplexStream, String) {
+        let mut client = connect_http(http.convert_server_config()).await;
+        let req = format!(
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+            KEY, extra
+        );
+        client.write_all(req.as_bytes()).await.unwrap();
+        let head = tokio::time::timeout(Duration::from_secs(2), read_head(&mut client))
+            .await
+            .unwrap();
+        (client, head)
+    }
+
+    const OFFER: &str = "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n";
+
+    #[tokio::test]
+    async fn ws_compression_passthrough() {
+        // 默认将压缩协商转发给上游, 上游同意时由代理解压, 与客户端之间不压缩
+        let (addr, mut offers) = run_echo_ws_upstream(true).await;
+        let http = build_http(addr, "");
+        let (mut client, head) = ws_connect_with(&http, OFFER).await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+        assert!(!head.contains("permessage-deflate"), "{}", head);
+        let offer = offers.recv().await.unwrap().unwrap();
+        assert!(offer.contains("permessage-deflate"), "{}", offer);
+
+        let text = "{\"message\":\"hello\"}".repeat(10);
+        write_frame(&mut client, &OwnedMessage::Text(text.clone())).await;
+        let frame = read_frame(&mut client).await;
+        assert!(!frame.reserved[0]);
+        assert_eq!(frame.data, text.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn ws_compression_off() {
+        let (addr, mut offers) = run_echo_ws_upstream(true).await;
+        let http = build_http(addr, "ws_compression = \"off\"");
+        let (mut client, head) = ws_connect_with(&http, OFFER).await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+        assert!(!head.contains("permessage-deflate"), "{}", head);
+        assert_eq!(offers.recv().await.unwrap(), None);
+
+        write_frame(&mut client, &OwnedMessage::Text("hello".to_string())).await;
+        let frame = read_frame(&mut client).await;
+        assert!(!frame.reserved[0]);
+        assert_eq!(frame.data, b"hello");
+    }
+
+    #[tokio::test]
+    async fn ws_compression_on() {
+        // 上游不支持压缩, 由代理与客户端协商并压缩及解压
+        let (addr, mut offers) = run_echo_ws_upstream(false).await;
+        let http = build_http(addr, "ws_compression = \"on\"");
+        let (mut client, head) = ws_connect_with(
+            &http,
+            "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits; server_no_context_takeover\r\n",
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+        let ext = head
+            .lines()
+            .find_map(|l| l.strip_prefix("sec-websocket-extensions:"))
+            .unwrap();
+        let config = WsDeflateConfig::from_response(ext.trim()).unwrap();
+        assert!(config.server_no_context_takeover);
+        assert_eq!(offers.recv().await.unwrap(), None);
+
+        let (mut deflater, mut inflater) = config.split(true);
+        let text = "{\"message\":\"hello\"}".repeat(10);
+        for _ in 0..2 {
+            let frame = deflater
+                .deflate_message(&OwnedMessage::Text(text.clone()))
+                .unwrap()
+                .unwrap();
+            write_frame(&mut client, &frame).await;
+            let frame = read_frame(&mut client).await;
+            assert!(frame.reserved[0]);
+            assert!(frame.data.len() < text.len());
+            let frame = inflater.inflate_frames(vec![frame]).unwrap();
+            assert_eq!(frame.data, text.as_bytes());
+        }
+
+        // 客户端未请求压缩时不协商
+        let (_, head) = ws_connect_with(&http, "").await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+        assert!(!head.contains("permessage-deflate"), "{}", head);
+    }
+
+    async fn ws_connect(http: &HttpConfig) -> (DuplexStream, String) {
+        ws_connect_with(http, "").await
+    }
+
+    fn build_http(addr: SocketAddr, extra: &str) -> HttpConfig {
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "localhost"
+            [[server.upstream]]
+            name = "ws"
+            server = [{{ addr = "{}" }}]
+            [[server.location]]
+            rule = "/ws"
+            is_ws = true
+            proxy_url = "http://ws"
+            {}
+            "#,
+            addr, extra
+        );
+        load_http(&config)
+    }
+
+    #[tokio::test]
+    async fn ws_max_connections() {
+        let (addr, _closed) = run_silent_ws_upstream().await;
+        let http = build_http(addr, "ws_max_connections = 1");
+        let (first, head) = ws_connect(&http).await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+
+        // 超过连接数时升级前返回503
+        let rejected = WsLimit::rejected_total();
+        let (_, head) = ws_connect(&http).await;
+        assert!(head.starts_with("http/1.1 503"), "{}", head);
+        assert!(WsLimit::rejected_total() > rejected);
+
+        // 连接关闭后归还名额
+        drop(first);
+        let limit = http.server[0].location[0].ws_limit.clone().unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while limit.count() != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let (_, head) = ws_connect(&http).await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn ws_idle_timeout() {
+        let (addr, mut closed) = run_silent_ws_upstream().await;
+        let http = build_http(addr, "ws_idle_timeout = \"300ms\"");
+        let (mut client, head) = ws_connect(&http).await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+
+        // 未超时前保持连接
+        let mut buf = [0u8; 64];
+        let read = tokio::time::timeout(Duration::from_millis(100), client.read(&mut buf)).await;
+        assert!(read.is_err());
+
+        // 两个方向均无消息, 超时后客户端及上游的连接均关闭
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while client.read(&mut buf).await.unwrap_or(0) != 0 {}
+        })
+        .await
+        .unwrap();
+        tokio::time::timeout(Duration::from_secs(2), closed.recv())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/29 02:41:08
+
+use std::io;
+
+use chrono::{Datelike, Duration, Utc};
+use rcgen::{date_time_ymd, CertificateParams, DnType, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+/// 自签名证书, 仅用于本地开发测试, 切勿用于生产环境
+/// 启动时在内存中生成ECDSA P-256的证书, 证书包含localhost及配置的域名
+pub struct SelfSigned;
+
+impl SelfSigned {
+    /// 配置中证书填写该值表示自动生成自签名证书
+    pub const AUTO: &'static str = "auto";
+
+    pub fn is_auto(cert: &Option<String>) -> bool {
+        cert.as_ref().map(|c| c == Self::AUTO).unwrap_or(false)
+    }
+
+    fn to_err<E: std::fmt::Display>(err: E) -> io::Error {
+        io::Error::other(format!("generate self signed cert failed: {}", err))
+    }
+
+    /// 生成包含`localhost`及`domains`的自签名证书
+    pub fn generate(
+        domains: &[String],
+    ) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        Self::build("wmproxy self signed", domains, 365)
+    }
+
+    /// 生成指定CN及有效天数的自签名证书
+    pub(crate) fn build(
+        common_name: &str,
+        domains: &[String],
+        days: i64,
+    ) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+        for d in domains {
+            if !d.is_empty() && !names.contains(d) {
+                names.push(d.clone());
+            }
+        }
+
+        let mut params = CertificateParams::new(names.clone()).map_err(Self::to_err)?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        let now = Utc::now();
+        let not_before = now - Duration::days(1);
+        let not_after = now + Duration::days(days);
+        params.not_before = date_time_ymd(
+            not_before.year(),
+            not_before.month() as u8,
+            not_before.day() as u8,
+        );
+        params.not_after = date_time_ymd(
+            not_after.year(),
+            not_after.month() as u8,
+            not_after.day() as u8,
+        );
+        let key_pair = KeyPair::generate().map_err(Self::to_err)?;
+        let cert = params.self_signed(&key_pair).map_err(Self::to_err)?;
+
+        log::warn!(
+            "当前使用自动生成的自签名证书({})，仅用于本地测试，请勿用于生产环境",
+            names.join(",")
+        );
+        Ok((
+            vec![cert.der().clone()],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der())),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustls::{pki_types::ServerName, ClientConfig, RootCertStore, ServerConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::SelfSigned;
+
+    #[tokio::test]
+    async fn handshake_with_self_signed() {
+        let (certs, key) = SelfSigned::generate(&["soft.wm-proxy.com".to_string()]).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(certs[0].clone()).unwrap();
+        let server = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let acceptor = TlsAcceptor::from(Arc::new(server));
+        tokio::spawn(async move {
+            let mut stream = acceptor.accept(server_io).await.unwrap();
+            stream.write_all(b"ok").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+        let connector = TlsConnector::from(Arc::new(client));
+        let domain = ServerName::try_from("soft.wm-proxy.com").unwrap();
+        let mut stream = connector.connect(domain, client_io).await.unwrap();
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ok");
+    }
+}
@@ -10,6 +10,7 @@
 // -----
 // Created Date: 2023/09/25 10:08:56
 
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use std::{collections::HashMap, io};
@@ -26,10 +27,51 @@ use webparse::{BinaryMut, Buf};
 
 use crate::proxy::ProxyServer;
 use crate::{
-    HealthCheck, Helper, MappingConfig, ProtClose, ProtCreate, ProtFrame, ProxyConfig, ProxyResult,
-    TransStream, VirtualStream,
+    prot::ProtMapping, CloseCode, ConfigDscp, FrameScheduler, HealthCheck, Helper, LocalPool, MappingConfig, ProtClose, ProtCreate, ProtFrame, ProtFrameHeader, ProxyConfig, ProxyResult,
+    SockMapGuard, SockMaps, TransStream, VirtualStream, WritePressure,
 };
 
+/// 映射较多时等待服务端告知是否支持压缩的最长时间
+const MAPPING_COMPRESS_WAIT: Duration = Duration::from_secs(1);
+
+/// 重连服务端的退避策略, 每次失败后间隔翻倍, 不超过最大间隔
+struct Backoff {
+    base: Duration,
+    max: Duration,
+    max_attempts: u32,
+    /// 连续失败的次数
+    failures: u32,
+}
+
+impl Backoff {
+    fn new(option: &ProxyConfig) -> Self {
+        Self {
+            base: option.reconnect_base(),
+            max: option.reconnect_max(),
+            max_attempts: option.max_reconnect_attempts,
+            failures: 0,
+        }
+    }
+
+    /// 下次重连前等待的时长, 超过最大次数时返回None
+    fn next_delay(&mut self) -> Option<Duration> {
+        if self.max_attempts > 0 && self.failures >= self.max_attempts {
+            return None;
+        }
+        let delay = self
+            .base
+            .saturating_mul(1u32 << self.failures.min(16))
+            .min(self.max);
+        self.failures += 1;
+        Some(delay)
+    }
+
+    /// 连接成功后重新计算
+    fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
 /// 中心客户端
 /// 负责与服务端建立连接，断开后自动再重连
 pub struct CenterClient {
@@ -49,6 +91,8 @@ pub struct CenterClient {
     tls_stream: Option<TlsStream<TcpStream>>,
     /// 绑定的下一个sock_map映射，为单数
     next_id: u32,
+    /// 正在使用的sock_map, 分配时跳过
+    sock_maps: SockMaps,
 
     /// 发送Create，并将绑定的Sender发到做绑定
     sender_work: Sender<(ProtCreate, Sender<ProtFrame>)>,
@@ -59,9 +103,61 @@ pub struct CenterClient {
     sender: Sender<ProtFrame>,
     /// 接收协议数据，并转发到服务端。
     receiver: Option<Receiver<ProtFrame>>,
+
+    /// 热加载时发送新的映射, 由工作协程对比后通知服务端
+    mapping_sender: Sender<Vec<MappingConfig>>,
+    /// 接收新的映射，开始服务时这值move到工作协程中
+    mapping_receiver: Option<Receiver<Vec<MappingConfig>>>,
 }
 
 impl CenterClient {
+    /// 连接本地服务, 配置了proxy_protocol时先发送原始客户端的地址
+    async fn connect_local(
+        addr: &SocketAddr,
+        proxy_protocol: bool,
+        client_addr: Option<SocketAddr>,
+    ) -> io::Result<TcpStream> {
+        let mut tcp = HealthCheck::connect(addr).await?;
+        if proxy_protocol {
+            let header = Helper::build_proxy_protocol(client_addr, *addr);
+            tcp.write_all(header.as_bytes()).await?;
+        }
+        Ok(tcp)
+    }
+
+    /// 连接本地服务并与隧道中的流双向转发, 开启pool_local时优先复用空闲的连接, 结束后放回
+    async fn trans_local(
+        domain: SocketAddr,
+        proxy_protocol: bool,
+        pool_local: bool,
+        client_addr: Option<SocketAddr>,
+        sock_map: u64,
+        sender: Sender<ProtFrame>,
+        virtual_receiver: Receiver<ProtFrame>,
+    ) {
+        let pooled = if pool_local { LocalPool::take(&domain) } else { None };
+        let tcp = match pooled {
+            Some(tcp) => Ok(tcp),
+            None => Self::connect_local(&domain, proxy_protocol, client_addr).await,
+        };
+        match tcp {
+            Ok(tcp) => {
+                let trans = TransStream::new(tcp, sock_map, sender, virtual_receiver);
+                if pool_local {
+                    if let (_, Some(tcp)) = trans.copy_wait_reuse().await {
+                        LocalPool::put(domain, tcp);
+                    }
+                } else {
+                    let _ = trans.copy_wait().await;
+                }
+            }
+            Err(e) => {
+                log::trace!("连接地址:{}，发生错误：{:?}", domain, e);
+                let _ = sender.send(ProtFrame::new_close(sock_map)).await;
+            }
+        }
+    }
+
     pub fn new(
         option: ProxyConfig,
         server_addr: String,
@@ -71,6 +167,7 @@ impl CenterClient {
     ) -> Self {
         let (sender, receiver) = channel::<ProtFrame>(100);
         let (sender_work, receiver_work) = channel::<(ProtCreate, Sender<ProtFrame>)>(10);
+        let (mapping_sender, mapping_receiver) = channel::<Vec<MappingConfig>>(10);
 
         Self {
             option,
@@ -81,22 +178,48 @@ impl CenterClient {
             stream: None,
             tls_stream: None,
             next_id: 1,
+            sock_maps: SockMaps::new(),
 
             sender_work,
             receiver_work: Some(receiver_work),
             sender,
             receiver: Some(receiver),
+            mapping_sender,
+            mapping_receiver: Some(mapping_receiver),
+        }
+    }
+
+    /// 热加载时更新映射, 已连接时仅通知服务端变更的映射, 未变更的映射上已建立的流不受影响
+    pub fn update_mappings(&mut self, mappings: Vec<MappingConfig>) {
+        if self.mappings == mappings {
+            return;
         }
+        self.mappings = mappings.clone();
+        if let Err(e) = self.mapping_sender.try_send(mappings) {
+            log::warn!("更新内网映射失败:{:?}", e);
+        }
+    }
+
+    /// 对比新旧映射, 返回被移除的映射名称, 及映射是否有变化
+    fn diff_mappings(old: &[MappingConfig], new: &[MappingConfig]) -> (Vec<String>, bool) {
+        let removed = old
+            .iter()
+            .filter(|m| !new.iter().any(|n| n.name == m.name))
+            .map(|m| m.name.clone())
+            .collect::<Vec<_>>();
+        (removed, old != new)
     }
 
     async fn inner_connect(
         tls_client: Option<Arc<rustls::ClientConfig>>,
         server_addr: String,
         domain: Option<String>,
+        dscp: Option<ConfigDscp>,
     ) -> ProxyResult<(Option<TcpStream>, Option<TlsStream<TcpStream>>)> {
         if tls_client.is_some() {
             let connector = TlsConnector::from(tls_client.unwrap());
             let stream = HealthCheck::connect(&server_addr).await?;
+            ConfigDscp::apply_option(&dscp, &stream);
             // 这里的域名只为认证设置
             let domain =
                 rustls::pki_types::ServerName::try_from(domain.unwrap_or("soft.wm-proxy.com".to_string()))
@@ -106,6 +229,7 @@ impl CenterClient {
             Ok((None, Some(outbound)))
         } else {
             let outbound = HealthCheck::connect(&server_addr).await?;
+            ConfigDscp::apply_option(&dscp, &outbound);
             Ok((Some(outbound), None))
         }
     }
@@ -115,6 +239,7 @@ impl CenterClient {
             self.tls_client.clone(),
             self.server_addr.clone(),
             self.domain.clone(),
+            self.option.dscp,
         )
         .await?;
         self.stream = stream;
@@ -128,18 +253,28 @@ impl CenterClient {
         sender: &mut Sender<ProtFrame>,
         receiver_work: &mut Receiver<(ProtCreate, Sender<ProtFrame>)>,
         receiver: &mut Receiver<ProtFrame>,
+        mapping_receiver: &mut Receiver<Vec<MappingConfig>>,
         mappings: &mut Vec<MappingConfig>,
-    ) -> ProxyResult<()>
+    ) -> ProxyResult<CloseCode>
     where
         T: AsyncRead + AsyncWrite + Unpin,
     {
         let mut map = HashMap::<u64, Sender<ProtFrame>>::new();
+        // 服务端发起的流对应的映射名称, 映射被移除时关闭相应的流
+        let mut stream_names = HashMap::<u64, String>::new();
+        // 服务端是否支持压缩的映射
+        let mut compress = false;
+        let mut mapping_closed = false;
         let mut read_buf = BinaryMut::new();
         let mut write_buf = BinaryMut::new();
+        let mut scheduler = FrameScheduler::new();
+        let mut pressure = WritePressure::new(option.write_pressure.clone().unwrap_or_default());
         let (mut reader, mut writer) = split(stream);
         let mut vec = Vec::with_capacity(4096);
         vec.resize(4096, 0);
         let is_closed;
+        // 服务端告知的关闭原因, 决定是否再重连
+        let mut close_code = CloseCode::Normal;
         if option.username.is_some() && option.password.is_some() {
             ProtFrame::new_token(
                 option.username.clone().unwrap(),
@@ -147,26 +282,72 @@ impl CenterClient {
             )
             .encode(&mut write_buf)?;
         }
+        // 映射较多时等待服务端告知是否支持压缩, 超时未收到则以不压缩的方式发送
+        let mut mapping_deadline = None;
         if mappings.len() > 0 {
-            ProtFrame::new_mapping(0, mappings.clone()).encode(&mut write_buf)?;
+            let mut cache = BinaryMut::new();
+            ProtFrame::new_mapping(0, mappings.clone()).encode(&mut cache)?;
+            if cache.remaining() > ProtMapping::COMPRESS_THRESHOLD + ProtFrameHeader::FRAME_HEADER_BYTES {
+                mapping_deadline = Some(tokio::time::Instant::now() + MAPPING_COMPRESS_WAIT);
+            } else {
+                write_buf.put_slice(cache.chunk());
+            }
         }
         loop {
+            // 按优先级将待发送的数据放入写入缓冲
+            scheduler.fill(&mut write_buf);
+            // 对端过慢时待写入的数据持续堆积, 超出限制时暂停接收新的数据
+            let paused = pressure.update(write_buf.remaining() + scheduler.queued_bytes());
             let _ = tokio::select! {
                 // 严格的顺序流
                 biased;
                 // 新的流建立，这里接收Create并进行绑定
                 r = receiver_work.recv() => {
                     if let Some((create, sender)) = r {
-                        map.insert(create.sock_map(), sender);
+                        SockMaps::insert_map(&mut map, create.sock_map(), sender);
                         let _ = create.encode(&mut write_buf);
                     }
                 }
                 // 数据的接收，并将数据写入给远程端
-                r = receiver.recv() => {
+                r = receiver.recv(), if !paused => {
                     if let Some(p) = r {
-                        let _ = p.encode(&mut write_buf);
+                        scheduler.push(p);
+                    }
+                }
+                // 热加载后的映射, 仅在有变化时重新发送, 并关闭已移除的映射上的流
+                r = mapping_receiver.recv(), if !mapping_closed => {
+                    match r {
+                        Some(new) => {
+                            let (removed, changed) = Self::diff_mappings(mappings, &new);
+                            *mappings = new;
+                            if changed {
+                                log::info!("内网映射已变更, 共{}个映射, 移除{:?}", mappings.len(), removed);
+                                stream_names.retain(|sock_map, name| {
+                                    if !removed.contains(name) {
+                                        return true;
+                                    }
+                                    if let Some(sender) = map.remove(sock_map) {
+                                        let _ = sender.try_send(ProtFrame::new_close(*sock_map));
+                                    }
+                                    let _ = ProtFrame::new_close(*sock_map).encode(&mut write_buf);
+                                    false
+                                });
+                                // 等待服务端告知是否支持压缩时, 到期后发送最新的映射
+                                if mapping_deadline.is_none() {
+                                    let mut mapping = ProtMapping::new(0, mappings.clone());
+                                    mapping.set_compress(compress);
+                                    mapping.encode(&mut write_buf)?;
+                                }
+                            }
+                        }
+                        None => mapping_closed = true,
                     }
                 }
+                _ = tokio::time::sleep_until(mapping_deadline.unwrap_or_else(tokio::time::Instant::now)), if mapping_deadline.is_some() => {
+                    log::trace!("服务端未告知支持压缩, 以不压缩的方式发送映射");
+                    mapping_deadline = None;
+                    ProtFrame::new_mapping(0, mappings.clone()).encode(&mut write_buf)?;
+                }
                 // 数据的等待读取，一旦流可读则触发，读到0则关闭主动关闭所有连接
                 r = reader.read(&mut vec) => {
                     match r {
@@ -219,7 +400,14 @@ impl CenterClient {
                                     continue;
                                 }
                                 let (virtual_sender, virtual_receiver) = channel::<ProtFrame>(10);
-                                map.insert(p.sock_map(), virtual_sender);
+                                if !SockMaps::insert_map(&mut map, p.sock_map(), virtual_sender) {
+                                    continue;
+                                }
+                                scheduler.set_priority(p.sock_map(), mapping.as_ref().unwrap().priority);
+                                if stream_names.len() > map.len() {
+                                    stream_names.retain(|sock_map, _| map.contains_key(sock_map));
+                                }
+                                stream_names.insert(p.sock_map(), mapping.as_ref().unwrap().name.clone());
 
                                 if mapping.as_ref().unwrap().is_proxy() {
                                     let stream = VirtualStream::new(
@@ -230,8 +418,7 @@ impl CenterClient {
 
                                     let proxy_server = ProxyServer::new(
                                         option.flag,
-                                        option.username.clone(),
-                                        option.password.clone(),
+                                        option.build_auth(),
                                         option.udp_bind.clone(),
                                         Some(mapping.as_ref().unwrap().headers.clone()),
                                     );
@@ -247,30 +434,22 @@ impl CenterClient {
                                     }
 
                                     let domain = mapping.as_ref().unwrap().local_addr.unwrap();
+                                    let proxy_protocol = mapping.as_ref().unwrap().proxy_protocol;
+                                    let pool_local = mapping.as_ref().unwrap().pool_local && !proxy_protocol;
+                                    let client_addr = *p.client_addr();
                                     let sock_map = p.sock_map();
                                     let sender = sender.clone();
                                     tokio::spawn(async move {
-                                        match HealthCheck::connect(&domain).await {
-                                            Ok(tcp) => {
-                                                let trans = TransStream::new(
-                                                    tcp,
-                                                    sock_map,
-                                                    sender,
-                                                    virtual_receiver,
-                                                );
-                                                let _ = trans.copy_wait().await;
-                                            }
-                                            Err(e) => {
-                                                log::trace!(
-                                                    "连接地址:{}，发生错误：{:?}",
-                                                    domain,
-                                                    e
-                                                );
-                                                let _ = sender
-                                                    .send(ProtFrame::new_close(sock_map))
-                                                    .await;
-                                            }
-                                        }
+                                        Self::trans_local(
+                                            domain,
+                                            proxy_protocol,
+                                            pool_local,
+                                            client_addr,
+                                            sock_map,
+                                            sender,
+                                            virtual_receiver,
+                                        )
+                                        .await;
                                     });
                                 }
                             }
@@ -281,12 +460,21 @@ impl CenterClient {
                             }
                             ProtFrame::Close(p) => {
                                 if p.sock_map() == 0 {
-                                    log::warn!("客户端被服务端关闭:{}", p.reason());
+                                    log::warn!("客户端被服务端关闭:{:?} {}", p.code(), p.reason());
+                                    close_code = p.code();
                                 } else if let Some(sender) = map.get(&p.sock_map()) {
+                                    stream_names.remove(&p.sock_map());
                                     let _ = sender.try_send(ProtFrame::Close(p));
                                 }
                             }
-                            ProtFrame::Mapping(_) => {}
+                            ProtFrame::Mapping(p) => {
+                                compress = compress || p.is_compress_ack();
+                                if p.is_compress_ack() && mapping_deadline.take().is_some() {
+                                    let mut mapping = ProtMapping::new(0, mappings.clone());
+                                    mapping.set_compress(true);
+                                    mapping.encode(&mut write_buf)?;
+                                }
+                            }
                             ProtFrame::Token(_) => todo!(),
                         }
                     }
@@ -304,7 +492,7 @@ impl CenterClient {
                 let _ = v.1.try_send(ProtFrame::Close(ProtClose::new(v.0)));
             }
         }
-        Ok(())
+        Ok(close_code)
     }
 
     pub async fn serve(&mut self) -> ProxyResult<()> {
@@ -318,42 +506,61 @@ impl CenterClient {
         let mut client_sender = self.sender.clone();
         let mut client_receiver = self.receiver.take().unwrap();
         let mut receiver_work = self.receiver_work.take().unwrap();
+        let mut mapping_receiver = self.mapping_receiver.take().unwrap();
         let mut mappings = self.mappings.clone();
         tokio::spawn(async move {
             let mut stream = stream;
             let mut tls_stream = tls_stream;
+            let mut backoff = Backoff::new(&option);
             loop {
+                let mut close_code = CloseCode::Normal;
                 if stream.is_some() {
-                    let _ = Self::inner_serve(
+                    close_code = Self::inner_serve(
                         &option,
                         stream.take().unwrap(),
                         &mut client_sender,
                         &mut receiver_work,
                         &mut client_receiver,
+                        &mut mapping_receiver,
                         &mut mappings,
                     )
-                    .await;
-                    tokio::time::sleep(Duration::from_millis(1000)).await;
+                    .await
+                    .unwrap_or(CloseCode::Normal);
+                    backoff.reset();
                 } else if tls_stream.is_some() {
-                    let _ = Self::inner_serve(
+                    close_code = Self::inner_serve(
                         &option,
                         tls_stream.take().unwrap(),
                         &mut client_sender,
                         &mut receiver_work,
                         &mut client_receiver,
+                        &mut mapping_receiver,
                         &mut mappings,
                     )
-                    .await;
-                    tokio::time::sleep(Duration::from_millis(1000)).await;
+                    .await
+                    .unwrap_or(CloseCode::Normal);
+                    backoff.reset();
                 };
-                match Self::inner_connect(tls_client.clone(), server.clone(), domain.clone()).await
+                // 如认证失败, 重连也无法成功
+                if !close_code.is_reconnect() {
+                    log::error!("服务端{}拒绝连接:{:?}, 不再重连", server, close_code);
+                    break;
+                }
+                match backoff.next_delay() {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => {
+                        log::error!("连续重连服务端{}失败{}次, 不再重连", server, backoff.failures);
+                        break;
+                    }
+                }
+                match Self::inner_connect(tls_client.clone(), server.clone(), domain.clone(), option.dscp).await
                 {
                     Ok((s, tls)) => {
                         stream = s;
                         tls_stream = tls;
                     }
-                    Err(_err) => {
-                        tokio::time::sleep(Duration::from_millis(1000)).await;
+                    Err(err) => {
+                        log::warn!("重连服务端{}失败:{:?}", server, err);
                     }
                 }
             }
@@ -362,17 +569,20 @@ impl CenterClient {
         Ok(())
     }
 
-    fn calc_next_id(&mut self) -> u64 {
-        let id = self.next_id;
-        self.next_id = self.next_id.wrapping_add(2);
-        Helper::calc_sock_map(self.option.server_id, id)
+    /// 分配未在使用的sock_map, 流结束后释放, 超出上限时返回None
+    fn calc_next_id(&mut self) -> Option<SockMapGuard> {
+        self.sock_maps.alloc(self.option.server_id, &mut self.next_id)
     }
 
     pub async fn deal_new_stream<T>(&mut self, inbound: T) -> ProxyResult<()>
     where
         T: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
     {
-        let id = self.calc_next_id();
+        let guard = match self.calc_next_id() {
+            Some(guard) => guard,
+            None => return Ok(()),
+        };
+        let id = guard.sock_map();
         let sender = self.sender.clone();
         let (stream_sender, stream_receiver) = channel::<ProtFrame>(10);
         let _ = self
@@ -380,9 +590,297 @@ impl CenterClient {
             .send((ProtCreate::new(id, None), stream_sender))
             .await;
         tokio::spawn(async move {
+            let _guard = guard;
             let trans = TransStream::new(inbound, id, sender, stream_receiver);
             let _ = trans.copy_wait().await;
         });
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bpaf::Parser;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc::channel,
+    };
+    use webparse::BinaryMut;
+
+    use super::{Backoff, CenterClient};
+    use crate::{option::proxy_config, Helper, LocalPool, ProtCreate, ProtFrame};
+
+    #[test]
+    fn reconnect_backoff() {
+        let args = [
+            "--reconnect-base",
+            "2s",
+            "--reconnect-max",
+            "5s",
+            "--max-reconnect-attempts",
+            "4",
+        ];
+        let option = proxy_config().to_options().run_inner(&args[..]).unwrap();
+        option.check_reconnect().unwrap();
+        let mut backoff = Backoff::new(&option);
+        let delays = (0..5).map(|_| backoff.next_delay()).collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_secs(2)),
+                Some(Duration::from_secs(4)),
+                Some(Duration::from_secs(5)),
+                Some(Duration::from_secs(5)),
+                None
+            ]
+        );
+        // 连接成功后从初始间隔重新开始
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some(Duration::from_secs(2)));
+
+        // 默认无限次重连, 且最大间隔不小于初始间隔
+        let option = proxy_config()
+            .to_options()
+            .run_inner(&["--reconnect-base", "1min"][..])
+            .unwrap();
+        option.check_reconnect().unwrap();
+        let mut backoff = Backoff::new(&option);
+        for _ in 0..100 {
+            assert_eq!(backoff.next_delay(), Some(Duration::from_secs(60)));
+        }
+
+        let option = proxy_config()
+            .to_options()
+            .run_inner(&["--reconnect-base", "10s", "--reconnect-max", "5s"][..])
+            .unwrap();
+        assert!(option.check_reconnect().is_err());
+    }
+
+    #[tokio::test]
+    async fn local_sees_client_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+
+        // 服务端将原始客户端的地址带入Create, 经过隧道传给客户端
+        let mut create = ProtCreate::new(1, Some("tcp".to_string()));
+        create.set_client_addr(Some("198.51.100.20:40000".parse().unwrap()));
+        let mut buf = BinaryMut::new();
+        ProtFrame::Create(create).encode(&mut buf).unwrap();
+        let client_addr = match Helper::decode_frame(&mut buf).unwrap().unwrap() {
+            ProtFrame::Create(p) => *p.client_addr(),
+            _ => unreachable!(),
+        };
+
+        let _tcp = CenterClient::connect_local(&local, true, client_addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut data = vec![0u8; 128];
+        let n = stream.read(&mut data).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&data[..n]),
+            format!("PROXY TCP4 198.51.100.20 127.0.0.1 40000 {}\r\n", local.port())
+        );
+
+        assert_eq!(
+            Helper::build_proxy_protocol(None, local),
+            "PROXY UNKNOWN\r\n"
+        );
+        assert_eq!(
+            Helper::build_proxy_protocol(Some("[2001:db8::1]:443".parse().unwrap()), local),
+            format!("PROXY TCP6 2001:db8::1 ::ffff:127.0.0.1 443 {}\r\n", local.port())
+        );
+    }
+
+    #[tokio::test]
+    async fn reuse_local_conn() {
+        // 本地的回显服务, 记录建立的连接数
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let (accept_sender, mut accept_receiver) = channel::<()>(10);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = accept_sender.send(()).await;
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    loop {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => stream.write_all(&buf[..n]).await.unwrap(),
+                        }
+                    }
+                });
+            }
+        });
+
+        let reuse = LocalPool::reuse_count();
+        for sock_map in 1..=3u64 {
+            let (sender, mut receiver) = channel::<ProtFrame>(10);
+            let (virtual_sender, virtual_receiver) = channel::<ProtFrame>(10);
+            let handle = tokio::spawn(CenterClient::trans_local(
+                local,
+                false,
+                true,
+                None,
+                sock_map,
+                sender,
+                virtual_receiver,
+            ));
+            virtual_sender
+                .send(ProtFrame::new_data(sock_map, b"ping".to_vec()))
+                .await
+                .unwrap();
+            match receiver.recv().await.unwrap() {
+                ProtFrame::Data(d) => assert_eq!(d.data(), b"ping"),
+                _ => unreachable!(),
+            }
+            // 服务端关闭隧道中的流, 连接放回池中
+            virtual_sender.send(ProtFrame::new_close(sock_map)).await.unwrap();
+            handle.await.unwrap();
+        }
+        assert_eq!(LocalPool::reuse_count() - reuse, 2);
+        accept_receiver.recv().await.unwrap();
+        assert!(accept_receiver.try_recv().is_err());
+        assert!(LocalPool::take(&local).is_some());
+    }
+
+    #[tokio::test]
+    async fn skip_live_sock_map() {
+        let option = proxy_config().to_options().run_inner(&[][..] as &[&str]).unwrap();
+        let mut client = CenterClient::new(option, "127.0.0.1:1".to_string(), None, None, vec![]);
+        let mut receiver_work = client.receiver_work.take().unwrap();
+
+        // 长期存活的流占用了1
+        let (live, live_peer) = tokio::io::duplex(64);
+        client.deal_new_stream(live).await.unwrap();
+        let (create, live_sender) = receiver_work.recv().await.unwrap();
+        assert_eq!(create.sock_map(), 1);
+
+        // id回绕后跳过仍在使用的1
+        client.next_id = u32::MAX;
+        let mut peers = vec![];
+        let mut senders = vec![];
+        for expect in [u32::MAX as u64, 3] {
+            let (stream, peer) = tokio::io::duplex(64);
+            peers.push(peer);
+            client.deal_new_stream(stream).await.unwrap();
+            let (create, sender) = receiver_work.recv().await.unwrap();
+            assert_eq!(create.sock_map(), expect);
+            senders.push(sender);
+        }
+
+        // 流结束后释放该id
+        drop(live_peer);
+        drop(live_sender);
+        for _ in 0..50 {
+            if client.sock_maps.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(client.sock_maps.len(), 2);
+        client.next_id = 1;
+        let (stream, _peer) = tokio::io::duplex(64);
+        client.deal_new_stream(stream).await.unwrap();
+        assert_eq!(receiver_work.recv().await.unwrap().0.sock_map(), 1);
+    }
+
+    /// 模拟服务端读取隧道中的下一个帧
+    async fn read_frame<T: tokio::io::AsyncRead + Unpin>(stream: &mut T, buf: &mut BinaryMut) -> ProtFrame {
+        use webparse::BufMut;
+        let mut data = [0u8; 1024];
+        loop {
+            if let Some(frame) = Helper::decode_frame(buf).unwrap() {
+                return frame;
+            }
+            let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut data))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0);
+            buf.put_slice(&data[..n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn reload_add_mapping() {
+        use crate::MappingConfig;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n) = stream.read(&mut buf).await {
+                        if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        let mapping = |name: &str| {
+            let mut m = MappingConfig::new(name.to_string(), "tcp".to_string(), String::new(), vec![]);
+            m.local_addr = Some(local);
+            m
+        };
+        let names = |frame: ProtFrame| match frame {
+            ProtFrame::Mapping(p) => p.into_mappings().into_iter().map(|m| m.name).collect::<Vec<_>>(),
+            _ => unreachable!(),
+        };
+
+        let option = proxy_config().to_options().run_inner(&[][..] as &[&str]).unwrap();
+        let mut client = CenterClient::new(option.clone(), "127.0.0.1:1".to_string(), None, None, vec![mapping("a")]);
+        let (tunnel, mut server) = tokio::io::duplex(64 * 1024);
+        let (mut sender, mut receiver) = channel::<ProtFrame>(10);
+        let mut receiver_work = client.receiver_work.take().unwrap();
+        let mut mapping_receiver = client.mapping_receiver.take().unwrap();
+        let mut mappings = client.mappings.clone();
+        tokio::spawn(async move {
+            let _ = CenterClient::inner_serve(
+                &option,
+                tunnel,
+                &mut sender,
+                &mut receiver_work,
+                &mut receiver,
+                &mut mapping_receiver,
+                &mut mappings,
+            )
+            .await;
+        });
+        let mut buf = BinaryMut::new();
+        assert_eq!(names(read_frame(&mut server, &mut buf).await), vec!["a"]);
+
+        // 映射a上建立的流
+        let mut write = BinaryMut::new();
+        ProtFrame::Create(ProtCreate::new(2, Some("a".to_string()))).encode(&mut write).unwrap();
+        ProtFrame::new_data(2, b"ping".to_vec()).encode(&mut write).unwrap();
+        server.write_all(write.as_slice()).await.unwrap();
+        match read_frame(&mut server, &mut buf).await {
+            ProtFrame::Data(d) => assert_eq!(d.data(), b"ping"),
+            _ => unreachable!(),
+        }
+
+        // 热加载新增映射b, 仅发送新的映射, a上的流保持
+        client.update_mappings(vec![mapping("a"), mapping("b")]);
+        assert_eq!(names(read_frame(&mut server, &mut buf).await), vec!["a", "b"]);
+        let mut write = BinaryMut::new();
+        ProtFrame::new_data(2, b"pong".to_vec()).encode(&mut write).unwrap();
+        server.write_all(write.as_slice()).await.unwrap();
+        match read_frame(&mut server, &mut buf).await {
+            ProtFrame::Data(d) => assert_eq!(d.data(), b"pong"),
+            _ => unreachable!(),
+        }
+
+        // 移除映射a时关闭其上的流
+        client.update_mappings(vec![mapping("b")]);
+        match read_frame(&mut server, &mut buf).await {
+            ProtFrame::Close(p) => assert_eq!(p.sock_map(), 2),
+            _ => unreachable!(),
+        }
+        assert_eq!(names(read_frame(&mut server, &mut buf).await), vec!["b"]);
+    }
+}
@@ -10,7 +10,14 @@
 // -----
 // Created Date: 2023/09/25 10:08:56
 
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use tokio::{
     io::{split, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
@@ -29,12 +36,19 @@ use webparse::BinaryMut;
 use webparse::Buf;
 
 use crate::{
-    prot::{ProtClose, ProtFrame},
+    data::HandshakeData,
+    prot::{CloseCode, ProtClose, ProtFrame, ProtMapping},
     proxy::ProxyServer,
     trans::{TransHttp, TransTcp},
-    Helper, MappingConfig, ProtCreate, ProxyConfig, ProxyResult, VirtualStream,
+    FrameScheduler, Helper, MappingConfig, ProtCreate, ProxyConfig, ProxyResult, SockMapGuard, SockMaps,
+    VirtualStream,
 };
 
+/// 当前已连接的内网穿透客户端数
+static TUNNEL_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+/// 超出max_tunnels而被拒绝的连接数
+static TUNNEL_REJECT: AtomicU64 = AtomicU64::new(0);
+
 /// 中心服务端
 /// 接受中心客户端的连接，并且将信息处理或者转发
 pub struct CenterServer {
@@ -52,6 +66,8 @@ pub struct CenterServer {
     receiver_work: Option<Receiver<(ProtCreate, Sender<ProtFrame>)>>,
     /// 绑定的下一个sock_map映射，为双数
     next_id: u32,
+    /// 正在使用的sock_map, 分配时跳过
+    sock_maps: SockMaps,
     /// 内网映射的相关消息, 需要读写分离需加锁
     mappings: Arc<RwLock<Vec<MappingConfig>>>,
 }
@@ -67,6 +83,7 @@ impl CenterServer {
             sender_work,
             receiver_work: Some(receiver_work),
             next_id: 2,
+            sock_maps: SockMaps::new(),
             mappings: Arc::new(RwLock::new(vec![])),
         }
     }
@@ -83,10 +100,9 @@ impl CenterServer {
         self.sender.is_closed()
     }
 
-    pub fn calc_next_id(&mut self) -> u64 {
-        let id = self.next_id;
-        self.next_id = self.next_id.wrapping_add(2);
-        Helper::calc_sock_map(self.option.server_id, id)
+    /// 分配未在使用的sock_map, 流结束后释放, 超出上限时返回None
+    pub fn calc_next_id(&mut self) -> Option<SockMapGuard> {
+        self.sock_maps.alloc(self.option.server_id, &mut self.next_id)
     }
 
     pub async fn inner_serve<T>(
@@ -103,6 +119,7 @@ impl CenterServer {
         let mut map = HashMap::<u64, Sender<ProtFrame>>::new();
         let mut read_buf = BinaryMut::new();
         let mut write_buf = BinaryMut::new();
+        let mut scheduler = FrameScheduler::new();
         let mut verify_succ = option.username.is_none() && option.password.is_none();
 
         let (mut reader, mut writer) = split(stream);
@@ -110,21 +127,31 @@ impl CenterServer {
         vec.resize(4096, 0);
         let is_closed;
         let mut is_ready_shutdown = false;
+        // 告知客户端支持压缩的Mapping, 旧版本的客户端将忽略该消息
+        ProtFrame::Mapping(ProtMapping::new_compress_ack()).encode(&mut write_buf)?;
         loop {
+            // 按优先级将待发送的数据放入写入缓冲
+            scheduler.fill(&mut write_buf);
             let _ = tokio::select! {
                 // 严格的顺序流
                 biased;
                 // 新的流建立，这里接收Create并进行绑定
                 r = receiver_work.recv() => {
                     if let Some((create, sender)) = r {
-                        map.insert(create.sock_map(), sender);
+                        SockMaps::insert_map(&mut map, create.sock_map(), sender);
+                        if let Some(domain) = create.domain() {
+                            let guard = mappings.read().await;
+                            if let Some(m) = guard.iter().find(|m| &m.domain == domain || &m.name == domain) {
+                                scheduler.set_priority(create.sock_map(), m.priority);
+                            }
+                        }
                         let _ = create.encode(&mut write_buf);
                     }
                 }
                 // 数据的接收，并将数据写入给远程端
                 r = receiver.recv() => {
                     if let Some(p) = r {
-                        let _ = p.encode(&mut write_buf);
+                        scheduler.push(p);
                     }
                 }
                 // 数据的等待读取，一旦流可读则触发，读到0则关闭主动关闭所有连接
@@ -179,7 +206,7 @@ impl CenterServer {
                             _ => {}
                         }
                         if !verify_succ {
-                            ProtFrame::new_close_reason(0, "not verify so close".to_string())
+                            ProtFrame::new_close_code(0, CloseCode::AuthFailed, "not verify so close".to_string())
                                 .encode(&mut write_buf)?;
                             is_ready_shutdown = true;
                             break;
@@ -187,7 +214,9 @@ impl CenterServer {
                         match p {
                             ProtFrame::Create(p) => {
                                 let (virtual_sender, virtual_receiver) = channel::<ProtFrame>(10);
-                                map.insert(p.sock_map(), virtual_sender);
+                                if !SockMaps::insert_map(&mut map, p.sock_map(), virtual_sender) {
+                                    continue;
+                                }
                                 let stream = VirtualStream::new(
                                     p.sock_map(),
                                     sender.clone(),
@@ -196,8 +225,7 @@ impl CenterServer {
 
                                 let proxy_server = ProxyServer::new(
                                     option.flag,
-                                    option.username.clone(),
-                                    option.password.clone(),
+                                    option.build_auth(),
                                     option.udp_bind.clone(),
                                     None,
                                 );
@@ -211,6 +239,7 @@ impl CenterServer {
                                     let _ = sender.send(p).await;
                                 }
                             }
+                            ProtFrame::Mapping(p) if p.is_compress_ack() => {}
                             ProtFrame::Mapping(p) => {
                                 let mut guard = mappings.write().await;
                                 *guard = p.into_mappings();
@@ -249,24 +278,59 @@ impl CenterServer {
         let receiver_work = self.receiver_work.take().unwrap();
         let mapping = self.mappings.clone();
         tokio::spawn(async move {
+            TUNNEL_ACTIVE.fetch_add(1, Ordering::Relaxed);
             let _ =
                 Self::inner_serve(stream, option, sender, receiver, receiver_work, mapping).await;
+            TUNNEL_ACTIVE.fetch_sub(1, Ordering::Relaxed);
         });
         Ok(())
     }
 
+    /// 已连接的客户端数超出上限, 告知客户端后关闭, 客户端稍后将重连
+    pub fn reject<T>(stream: T, addr: SocketAddr)
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        TUNNEL_REJECT.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(async move {
+            let mut stream = stream;
+            let mut write_buf = BinaryMut::new();
+            let close = ProtFrame::new_close_code(0, CloseCode::QuotaExceeded, "too many tunnels".to_string());
+            if close.encode(&mut write_buf).is_ok() {
+                let _ = stream.write_all(write_buf.chunk()).await;
+            }
+            let _ = stream.shutdown().await;
+            log::trace!("已拒绝来自{}的内网穿透连接", addr);
+        });
+    }
+
+    /// 所有中心服务端当前已连接的客户端数
+    pub fn tunnel_count() -> usize {
+        TUNNEL_ACTIVE.load(Ordering::Relaxed)
+    }
+
+    /// 超出上限而被拒绝的连接总数
+    pub fn reject_count() -> u64 {
+        TUNNEL_REJECT.load(Ordering::Relaxed)
+    }
+
     pub async fn server_new_http(
         &mut self,
         stream: TcpStream,
         addr: SocketAddr,
     ) -> ProxyResult<()> {
+        let guard = match self.calc_next_id() {
+            Some(guard) => guard,
+            None => return Ok(()),
+        };
         let trans = TransHttp::new(
             self.sender(),
             self.sender_work(),
-            self.calc_next_id(),
+            guard.sock_map(),
             self.mappings.clone(),
         );
         tokio::spawn(async move {
+            let _guard = guard;
             if let Err(e) = trans.process(stream, addr).await {
                 log::warn!("内网穿透:Http转发时发生错误:{:?}", e);
             }
@@ -280,14 +344,29 @@ impl CenterServer {
         addr: SocketAddr,
         accept: TlsAcceptor,
     ) -> ProxyResult<()> {
+        let guard = match self.calc_next_id() {
+            Some(guard) => guard,
+            None => return Ok(()),
+        };
         let trans = TransHttp::new(
             self.sender(),
             self.sender_work(),
-            self.calc_next_id(),
+            guard.sock_map(),
             self.mappings.clone(),
         );
         tokio::spawn(async move {
-            match accept.accept(stream).await {
+            let _guard = guard;
+            let stream = {
+                let _permit = match HandshakeData::acquire().await {
+                    Some(permit) => permit,
+                    None => {
+                        log::info!("内网穿透:{}等待Https握手的连接过多, 断开连接", addr);
+                        return;
+                    }
+                };
+                accept.accept(stream).await
+            };
+            match stream {
                 Ok(tls_stream) => {
                     if let Err(e) = trans.process(tls_stream, addr).await {
                         log::warn!("内网穿透:修理Https转发时发生错误:{:?}", e);
@@ -301,14 +380,20 @@ impl CenterServer {
         return Ok(());
     }
 
-    pub async fn server_new_tcp(&mut self, stream: TcpStream) -> ProxyResult<()> {
-        let trans = TransTcp::new(
+    pub async fn server_new_tcp(&mut self, stream: TcpStream, addr: SocketAddr) -> ProxyResult<()> {
+        let guard = match self.calc_next_id() {
+            Some(guard) => guard,
+            None => return Ok(()),
+        };
+        let mut trans = TransTcp::new(
             self.sender(),
             self.sender_work(),
-            self.calc_next_id(),
+            guard.sock_map(),
             self.mappings.clone(),
         );
+        trans.set_client_addr(addr);
         tokio::spawn(async move {
+            let _guard = guard;
             if let Err(e) = trans.process(stream, "tcp").await {
                 log::warn!("内网穿透:转发Tcp转发时发生错误:{:?}", e);
             }
@@ -316,16 +401,22 @@ impl CenterServer {
         return Ok(());
     }
 
-    pub async fn server_new_prxoy(&mut self, stream: TcpStream) -> ProxyResult<()> {
+    pub async fn server_new_prxoy(&mut self, stream: TcpStream, addr: SocketAddr) -> ProxyResult<()> {
         // 创建一个tcp的转发数据流，服务端不处理数据，仅做数据映射
         // 服务端也无法连上内网的数据，此处处理数据也没有任何意义
-        let trans = TransTcp::new(
+        let guard = match self.calc_next_id() {
+            Some(guard) => guard,
+            None => return Ok(()),
+        };
+        let mut trans = TransTcp::new(
             self.sender(),
             self.sender_work(),
-            self.calc_next_id(),
+            guard.sock_map(),
             self.mappings.clone(),
         );
+        trans.set_client_addr(addr);
         tokio::spawn(async move {
+            let _guard = guard;
             if let Err(e) = trans.process(stream, "proxy").await {
                 log::warn!("内网穿透:转发Proxy转发时发生错误:{:?}", e);
             }
@@ -0,0 +1,169 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/01 10:15:22
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use webparse::{BinaryMut, Buf};
+
+use crate::{ProtFrame, ProtFrameHeader};
+
+/// 步长调度的基数, 权重越大每次增加的步长越小
+const STRIDE: u64 = 1 << 20;
+
+/// 单个优先级的等待队列
+struct PriorityQueue {
+    /// 当前的行程值, 每次取出数据后增加`STRIDE / 权重`
+    pass: u64,
+    frames: VecDeque<ProtFrame>,
+}
+
+/// 隧道写入的优先级调度
+/// 按sock_map对应映射的优先级分成多个队列, 同一个sock_map的数据始终在同一队列保证顺序
+/// 采用步长调度, 权重为优先级+1, 拥塞时高优先级获得更多写入机会且低优先级不会饿死
+#[derive(Default)]
+pub struct FrameScheduler {
+    /// sock_map对应的优先级, 未配置的为0
+    priorities: HashMap<u64, u8>,
+    queues: BTreeMap<u8, PriorityQueue>,
+    /// 最近一次取出数据时的行程值, 新激活的队列从此开始计算
+    pass: u64,
+    /// 队列中待写入的字节数
+    queued: usize,
+}
+
+impl FrameScheduler {
+    /// 写入缓冲低于该值时才从队列中取数据, 超出的部分留在队列中参与调度
+    pub const WRITE_WATERMARK: usize = 16 * 1024;
+
+    pub fn new() -> Self {
+        Self {
+            priorities: HashMap::new(),
+            queues: BTreeMap::new(),
+            pass: 0,
+            queued: 0,
+        }
+    }
+
+    pub fn set_priority(&mut self, sock_map: u64, priority: u8) {
+        if priority == 0 {
+            self.priorities.remove(&sock_map);
+        } else {
+            self.priorities.insert(sock_map, priority);
+        }
+    }
+
+    fn get_priority(&self, sock_map: u64) -> u8 {
+        // sock_map为0的为自身的控制消息, 始终最优先
+        if sock_map == 0 {
+            return u8::MAX;
+        }
+        self.priorities.get(&sock_map).cloned().unwrap_or(0)
+    }
+
+    fn frame_len(frame: &ProtFrame) -> usize {
+        match frame {
+            ProtFrame::Data(d) => ProtFrameHeader::FRAME_HEADER_BYTES + d.data().len(),
+            _ => ProtFrameHeader::FRAME_HEADER_BYTES,
+        }
+    }
+
+    pub fn push(&mut self, frame: ProtFrame) {
+        self.queued += Self::frame_len(&frame);
+        let sock_map = frame.sock_map();
+        let priority = self.get_priority(sock_map);
+        if frame.is_close() && !frame.is_half_close() {
+            self.priorities.remove(&sock_map);
+        }
+        let pass = self.pass;
+        let queue = self.queues.entry(priority).or_insert(PriorityQueue {
+            pass,
+            frames: VecDeque::new(),
+        });
+        if queue.frames.is_empty() {
+            // 空闲期间不累积写入机会, 防止重新激活后占满带宽
+            queue.pass = queue.pass.max(pass);
+        }
+        queue.frames.push_back(frame);
+    }
+
+    pub fn pop(&mut self) -> Option<ProtFrame> {
+        let mut select: Option<(u8, u64)> = None;
+        // 行程值相同时优先级高的先写
+        for (priority, queue) in self.queues.iter().rev() {
+            if queue.frames.is_empty() {
+                continue;
+            }
+            if select.map(|(_, pass)| queue.pass < pass).unwrap_or(true) {
+                select = Some((*priority, queue.pass));
+            }
+        }
+        let (priority, pass) = select?;
+        let queue = self.queues.get_mut(&priority)?;
+        self.pass = pass;
+        queue.pass = pass + STRIDE / (priority as u64 + 1);
+        let frame = queue.frames.pop_front()?;
+        self.queued = self.queued.saturating_sub(Self::frame_len(&frame));
+        Some(frame)
+    }
+
+    /// 队列中待写入的字节数
+    pub fn queued_bytes(&self) -> usize {
+        self.queued
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.values().all(|q| q.frames.is_empty())
+    }
+
+    /// 按调度顺序将数据写入缓冲, 直到缓冲达到水位线
+    pub fn fill(&mut self, write_buf: &mut BinaryMut) {
+        while write_buf.remaining() < Self::WRITE_WATERMARK {
+            match self.pop() {
+                Some(p) => {
+                    let _ = p.encode(write_buf);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameScheduler;
+    use crate::ProtFrame;
+
+    #[test]
+    fn weighted_order() {
+        let mut scheduler = FrameScheduler::new();
+        scheduler.set_priority(3, 3);
+        for _ in 0..8 {
+            scheduler.push(ProtFrame::new_data(1, vec![0u8; 10]));
+        }
+        for _ in 0..8 {
+            scheduler.push(ProtFrame::new_data(3, vec![0u8; 10]));
+        }
+        scheduler.push(ProtFrame::new_close(1));
+
+        let mut order = vec![];
+        while let Some(p) = scheduler.pop() {
+            order.push((p.sock_map(), p.is_close()));
+        }
+        assert!(scheduler.is_empty());
+        assert_eq!(scheduler.queued_bytes(), 0);
+        // 权重4:1, 前5次中高优先级占4次
+        assert_eq!(order[..5].iter().filter(|o| o.0 == 3).count(), 4);
+        // 低优先级不会饿死, 且同一sock_map的关闭消息在数据之后
+        assert_eq!(order.last(), Some(&(1, true)));
+        assert_eq!(order.iter().filter(|o| o.0 == 1).count(), 9);
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/11 09:42:18
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use tokio::{io::ReadBuf, net::TcpStream};
+
+/// 每个本地地址最多保留的空闲连接数
+const MAX_IDLE_PER_ADDR: usize = 16;
+/// 空闲连接的最长保留时间, 超过后不再复用
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref IDLE_CONNS: Mutex<HashMap<SocketAddr, Vec<(TcpStream, Instant)>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// 复用空闲连接的次数
+static REUSE_COUNT: AtomicU64 = AtomicU64::new(0);
+/// 无可用的空闲连接, 新建连接的次数
+static MISS_COUNT: AtomicU64 = AtomicU64::new(0);
+/// 空闲连接失效被丢弃的次数
+static STALE_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// 内网映射客户端连接本地服务的连接池, 按本地地址保存空闲的连接
+///
+/// 仅适用于如HTTP keep-alive这类连接可承载多次独立请求的协议, 需在映射中配置`pool_local`开启.
+/// 隧道中的流被服务端关闭且本地无待读写的数据时放回池中, 取出时检查连接是否已被本地服务关闭,
+/// 或仍有上次未读取的数据, 满足任一条件则丢弃
+pub struct LocalPool;
+
+impl LocalPool {
+    /// 取出可用的空闲连接, 不存在时返回None由调用方新建
+    pub fn take(addr: &SocketAddr) -> Option<TcpStream> {
+        let mut guard = IDLE_CONNS.lock().ok()?;
+        if let Some(list) = guard.get_mut(addr) {
+            while let Some((tcp, idle_at)) = list.pop() {
+                if idle_at.elapsed() < IDLE_TIMEOUT && Self::is_alive(&tcp) {
+                    REUSE_COUNT.fetch_add(1, Ordering::Relaxed);
+                    return Some(tcp);
+                }
+                STALE_COUNT.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        MISS_COUNT.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    /// 放回空闲的连接, 超过上限时直接关闭
+    pub fn put(addr: SocketAddr, tcp: TcpStream) {
+        if let Ok(mut guard) = IDLE_CONNS.lock() {
+            let list = guard.entry(addr).or_default();
+            list.retain(|(_, idle_at)| idle_at.elapsed() < IDLE_TIMEOUT);
+            if list.len() < MAX_IDLE_PER_ADDR {
+                list.push((tcp, Instant::now()));
+            }
+        }
+    }
+
+    /// 连接未关闭且没有未读取的数据, 无数据可读时peek返回Pending
+    fn is_alive(tcp: &TcpStream) -> bool {
+        let mut data = [0u8; 1];
+        let mut buf = ReadBuf::new(&mut data);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        matches!(tcp.poll_peek(&mut cx, &mut buf), Poll::Pending)
+    }
+
+    pub fn reuse_count() -> u64 {
+        REUSE_COUNT.load(Ordering::Relaxed)
+    }
+
+    pub fn miss_count() -> u64 {
+        MISS_COUNT.load(Ordering::Relaxed)
+    }
+
+    pub fn stale_count() -> u64 {
+        STALE_COUNT.load(Ordering::Relaxed)
+    }
+
+    /// 当前池中的空闲连接数
+    pub fn idle_count() -> usize {
+        IDLE_CONNS
+            .lock()
+            .map(|g| g.values().map(|v| v.len()).sum())
+            .unwrap_or(0)
+    }
+}
@@ -13,11 +13,21 @@
 mod center_client;
 mod center_server;
 mod center_trans;
+mod frame_scheduler;
+mod local_pool;
+mod sock_map;
+mod stream_stats;
 mod trans_stream;
 mod virtual_stream;
+mod write_pressure;
 
 pub use center_client::CenterClient;
 pub use center_server::CenterServer;
 pub use center_trans::CenterTrans;
+pub use frame_scheduler::FrameScheduler;
+pub use local_pool::LocalPool;
+pub use sock_map::{SockMapGuard, SockMaps};
+pub use stream_stats::{CloseReason, StatsCallback, StreamStats};
 pub use trans_stream::TransStream;
 pub use virtual_stream::VirtualStream;
+pub use write_pressure::WritePressure;
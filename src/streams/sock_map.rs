@@ -0,0 +1,184 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/15 16:08:25
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::mpsc::Sender;
+
+use crate::{Helper, ProtFrame};
+
+/// 隧道中的流数量达到该值后, 插入时清理已结束的流
+const EVICT_LEN: usize = 1024;
+
+/// 隧道中正在使用的sock_map, 分配时跳过仍在使用的id
+///
+/// id每次加2且会回绕, 极长时间存活的流在回绕后可能与新分配的id相同, 导致数据串流,
+/// 分配时跳过仍在使用的id, 并限制同时存在的流的数量
+#[derive(Clone)]
+pub struct SockMaps {
+    used: Arc<Mutex<HashSet<u64>>>,
+    limit: usize,
+}
+
+/// 分配的sock_map, 流结束时释放
+pub struct SockMapGuard {
+    sock_map: u64,
+    used: Arc<Mutex<HashSet<u64>>>,
+}
+
+impl SockMaps {
+    /// 同时存在的流的数量上限
+    pub const DEFAULT_LIMIT: usize = 100_000;
+
+    pub fn new() -> Self {
+        Self::with_limit(Self::DEFAULT_LIMIT)
+    }
+
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            used: Arc::new(Mutex::new(HashSet::new())),
+            limit,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.used.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 从next_id开始分配未使用的id, 超出上限时返回None
+    pub fn alloc(&self, server_id: u32, next_id: &mut u32) -> Option<SockMapGuard> {
+        let mut used = self.used.lock().unwrap();
+        if used.len() >= self.limit {
+            log::warn!("隧道中同时存在的流超过上限{}, 拒绝新的流", self.limit);
+            return None;
+        }
+        loop {
+            let id = *next_id;
+            *next_id = next_id.wrapping_add(2);
+            // 0为控制消息所用
+            if id == 0 {
+                continue;
+            }
+            let sock_map = Helper::calc_sock_map(server_id, id);
+            if used.insert(sock_map) {
+                return Some(SockMapGuard {
+                    sock_map,
+                    used: self.used.clone(),
+                });
+            }
+            log::trace!("sock_map:{}仍在使用中, 跳过该id", sock_map);
+        }
+    }
+
+    /// 插入隧道中的流, 已存在且未结束的流不被替换, 返回是否插入成功
+    /// 数量较多时清理已结束的流, 防止长期运行时无限增长
+    pub fn insert_map(
+        map: &mut HashMap<u64, Sender<ProtFrame>>,
+        sock_map: u64,
+        sender: Sender<ProtFrame>,
+    ) -> bool {
+        if let Some(old) = map.get(&sock_map) {
+            if !old.is_closed() {
+                log::warn!("sock_map:{}仍在使用中, 忽略重复的流", sock_map);
+                return false;
+            }
+        }
+        if map.len() >= EVICT_LEN && map.len().is_power_of_two() {
+            map.retain(|_, s| !s.is_closed());
+        }
+        map.insert(sock_map, sender);
+        true
+    }
+}
+
+impl Default for SockMaps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SockMapGuard {
+    pub fn sock_map(&self) -> u64 {
+        self.sock_map
+    }
+}
+
+impl Drop for SockMapGuard {
+    fn drop(&mut self) {
+        self.used.lock().unwrap().remove(&self.sock_map);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::sync::mpsc::channel;
+
+    use super::{SockMaps, EVICT_LEN};
+    use crate::{Helper, ProtFrame};
+
+    #[test]
+    fn wraparound_skip_used() {
+        let maps = SockMaps::with_limit(3);
+        let mut next_id = 1;
+        let live = maps.alloc(0, &mut next_id).unwrap();
+        assert_eq!(live.sock_map(), 1);
+
+        // 回绕后跳过仍在使用的1
+        next_id = u32::MAX;
+        let last = maps.alloc(0, &mut next_id).unwrap();
+        assert_eq!(last.sock_map(), u32::MAX as u64);
+        let skip = maps.alloc(0, &mut next_id).unwrap();
+        assert_eq!(skip.sock_map(), 3);
+        assert!(maps.alloc(0, &mut next_id).is_none());
+
+        // 流结束后可再次分配
+        drop(live);
+        next_id = 1;
+        assert_eq!(maps.alloc(0, &mut next_id).unwrap().sock_map(), 1);
+        assert_eq!(maps.len(), 2);
+
+        // 服务端为双数, 回绕时跳过控制消息的0
+        let mut next_id = u32::MAX - 1;
+        let maps = SockMaps::new();
+        let a = maps.alloc(1, &mut next_id).unwrap();
+        let b = maps.alloc(1, &mut next_id).unwrap();
+        assert_eq!(a.sock_map(), Helper::calc_sock_map(1, u32::MAX - 1));
+        assert_eq!(b.sock_map(), Helper::calc_sock_map(1, 2));
+    }
+
+    #[test]
+    fn insert_and_evict() {
+        let mut map = HashMap::new();
+        let (live, _live_receiver) = channel::<ProtFrame>(1);
+        assert!(SockMaps::insert_map(&mut map, 1, live.clone()));
+        assert!(!SockMaps::insert_map(&mut map, 1, live.clone()));
+
+        for i in 0..EVICT_LEN as u64 {
+            let (closed, _) = channel::<ProtFrame>(1);
+            SockMaps::insert_map(&mut map, i + 2, closed);
+        }
+        // 已结束的流被清理, 使用中的保留
+        assert!(map.len() < EVICT_LEN);
+        assert!(map.contains_key(&1));
+        let (other, _other_receiver) = channel::<ProtFrame>(1);
+        assert!(SockMaps::insert_map(&mut map, 3, other));
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/08 14:20:45
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+/// 流关闭的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// 本端读取结束或者被释放
+    Local,
+    /// 中心端通知关闭
+    Remote,
+    /// 被控制端强制关闭
+    Cancelled,
+    /// 读写出错
+    Error(io::ErrorKind),
+}
+
+/// 流关闭时的统计数据, 可用于记录指标或者审计日志
+#[derive(Debug, Clone)]
+pub struct StreamStats {
+    /// 从流中读取的字节数
+    pub bytes_in: u64,
+    /// 写入到流中的字节数
+    pub bytes_out: u64,
+    /// 从创建到关闭的时长
+    pub duration: Duration,
+    /// 关闭的原因
+    pub close_reason: CloseReason,
+}
+
+/// 流关闭时的回调
+pub type StatsCallback = Box<dyn FnOnce(StreamStats) + Send>;
+
+/// 流运行中的统计
+pub(crate) struct StatsCounter {
+    start: Instant,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub close_reason: Option<CloseReason>,
+    callback: Option<StatsCallback>,
+}
+
+impl StatsCounter {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            bytes_in: 0,
+            bytes_out: 0,
+            close_reason: None,
+            callback: None,
+        }
+    }
+
+    pub fn set_callback(&mut self, callback: StatsCallback) {
+        self.callback = Some(callback);
+    }
+
+    /// 仅记录首次关闭的原因
+    pub fn close(&mut self, reason: CloseReason) {
+        if self.close_reason.is_none() {
+            self.close_reason = Some(reason);
+        }
+    }
+
+    /// 当前的统计数据, 未关闭时原因为Local
+    pub fn stats(&self) -> StreamStats {
+        StreamStats {
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            duration: self.start.elapsed(),
+            close_reason: self.close_reason.clone().unwrap_or(CloseReason::Local),
+        }
+    }
+
+    /// 结束统计, 如有回调则通知
+    pub fn finish(&mut self) -> StreamStats {
+        let stats = self.stats();
+        if let Some(callback) = self.callback.take() {
+            callback(stats.clone());
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::mpsc::channel,
+    };
+
+    use super::{CloseReason, StreamStats};
+    use crate::{ProtFrame, TransStream, VirtualStream};
+
+    /// 读取中心端收到的数据直到指定的长度
+    async fn recv_data(receiver: &mut tokio::sync::mpsc::Receiver<ProtFrame>, len: usize) {
+        let mut size = 0;
+        while size < len {
+            match receiver.recv().await.unwrap() {
+                ProtFrame::Data(d) => size += d.data().len(),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(size, len);
+    }
+
+    #[tokio::test]
+    async fn trans_stream_stats() {
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let (in_sender, mut in_receiver) = channel(10);
+        let (out_sender, out_receiver) = channel(10);
+        let mut trans = TransStream::new(stream, 1, in_sender, out_receiver);
+        let notify = Arc::new(Mutex::new(None::<StreamStats>));
+        let clone = notify.clone();
+        trans.set_stats_callback(move |stats| *clone.lock().unwrap() = Some(stats));
+        let handle = tokio::spawn(trans.copy_wait_with_stats());
+
+        client.write_all(&[b'a'; 300]).await.unwrap();
+        recv_data(&mut in_receiver, 300).await;
+        out_sender.send(ProtFrame::new_data(1, vec![b'b'; 120])).await.unwrap();
+        let mut buf = [0u8; 120];
+        client.read_exact(&mut buf).await.unwrap();
+        drop(client);
+        // 本地读取结束后通知对端半关闭, 等待对端关闭
+        assert!(in_receiver.recv().await.unwrap().is_half_close());
+        out_sender.send(ProtFrame::new_close(1)).await.unwrap();
+
+        let (ret, stats) = handle.await.unwrap();
+        assert!(ret.is_ok());
+        assert_eq!(stats.bytes_in, 300);
+        assert_eq!(stats.bytes_out, 120);
+        assert_eq!(stats.close_reason, CloseReason::Local);
+        let notify = notify.lock().unwrap().clone().unwrap();
+        assert_eq!(notify.bytes_in, 300);
+        assert_eq!(notify.bytes_out, 120);
+    }
+
+    #[tokio::test]
+    async fn virtual_stream_stats() {
+        let (sender, mut center_receiver) = channel(10);
+        let (center_sender, receiver) = channel(10);
+        let mut stream = VirtualStream::new(2, sender, receiver);
+        let notify = Arc::new(Mutex::new(None::<StreamStats>));
+        let clone = notify.clone();
+        stream.set_stats_callback(move |stats| *clone.lock().unwrap() = Some(stats));
+
+        stream.write_all(&[b'a'; 70]).await.unwrap();
+        recv_data(&mut center_receiver, 70).await;
+        center_sender.send(ProtFrame::new_data(2, vec![b'b'; 30])).await.unwrap();
+        center_sender.send(ProtFrame::new_close(2)).await.unwrap();
+        let mut data = vec![];
+        stream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data.len(), 30);
+        assert_eq!(stream.stats().close_reason, CloseReason::Remote);
+        drop(stream);
+
+        let notify = notify.lock().unwrap().clone().unwrap();
+        assert_eq!(notify.bytes_in, 30);
+        assert_eq!(notify.bytes_out, 70);
+        assert_eq!(notify.close_reason, CloseReason::Remote);
+    }
+}
@@ -23,7 +23,9 @@ use tokio::{
 };
 use webparse::{BinaryMut, Buf, BufMut};
 
-use crate::ProtFrame;
+use crate::{data::{ConnData, ConnGuard}, CloseCode, ProtFrame};
+
+use super::stream_stats::{CloseReason, StatsCounter, StreamStats};
 
 /// 转发流量端
 /// 提供与中心端绑定的读出写入功能
@@ -43,6 +45,12 @@ where
     in_sender: Sender<ProtFrame>,
     // 收到中心端的写入请求，转成write
     out_receiver: Receiver<ProtFrame>,
+    // 注册到全局的连接句柄，可通过控制端强制关闭
+    guard: ConnGuard,
+    // 读写的字节数及时长统计
+    counter: StatsCounter,
+    // 对端半关闭后已关闭写方向, 该流不可再复用
+    half_closed: bool,
 }
 
 impl<T> TransStream<T>
@@ -62,9 +70,25 @@ where
             write: BinaryMut::new(),
             in_sender,
             out_receiver,
+            guard: ConnData::register("trans", id),
+            counter: StatsCounter::new(),
+            half_closed: false,
         }
     }
 
+    /// 设置流关闭时的回调, 可获取该流的读写统计
+    pub fn set_stats_callback<F>(&mut self, callback: F)
+    where
+        F: FnOnce(StreamStats) + Send + 'static,
+    {
+        self.counter.set_callback(Box::new(callback));
+    }
+
+    /// 在全局连接注册表中的id
+    pub fn conn_id(&self) -> u64 {
+        self.guard.id()
+    }
+
     pub fn reader_mut(&mut self) -> &mut BinaryMut {
         &mut self.read
     }
@@ -73,23 +97,47 @@ where
         &mut self.read
     }
 
-    async fn inner_copy_wait(mut self) -> Result<(), std::io::Error> {
+    /// 一方读取结束时仅关闭另一方的写方向, 剩余方向的数据继续传输, 两个方向均结束后才关闭
+    async fn inner_copy_wait(&mut self, counter: &mut StatsCounter, half_close: bool) -> Result<(), std::io::Error> {
         let mut buf = Vec::with_capacity(20480);
         buf.resize(20480, 0);
         let mut link = LinkedList::<ProtFrame>::new();
-        let (mut reader, mut writer) = split(self.stream);
+        let token = self.guard.token().clone();
+        // 本地已读取结束, 对端已半关闭, 对端已完全关闭
+        let (mut local_eof, mut remote_eof, mut remote_closed) = (false, false, false);
+        let (mut reader, mut writer) = split(&mut self.stream);
         loop {
             // 有剩余数据，优先转化成Prot，因为数据可能从外部直接带入
             if self.read.has_remaining() {
+                counter.bytes_in += self.read.remaining() as u64;
                 link.push_back(ProtFrame::new_data(self.id, self.read.chunk().to_vec()));
                 self.read.clear();
             }
+            if !self.write.has_remaining() {
+                if remote_closed {
+                    return Ok(());
+                }
+                // 对端的数据已全部写入, 发送FIN而不是直接断开连接
+                if remote_eof && !self.half_closed {
+                    self.half_closed = true;
+                    let _ = writer.shutdown().await;
+                }
+            }
+            if local_eof && self.half_closed && link.is_empty() {
+                return Ok(());
+            }
 
             tokio::select! {
-                n = reader.read(&mut buf) => {
+                _ = token.cancelled() => {
+                    counter.close(CloseReason::Cancelled);
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "force closed"))
+                }
+                n = reader.read(&mut buf), if !local_eof => {
                     let n = n?;
                     if n == 0 {
-                        return Ok(())
+                        counter.close(CloseReason::Local);
+                        local_eof = true;
+                        link.push_back(ProtFrame::new_close_code(self.id, CloseCode::HalfClose, String::new()));
                     } else {
                         self.read.put_slice(&buf[..n]);
                     }
@@ -97,6 +145,7 @@ where
                 r = writer.write(self.write.chunk()), if self.write.has_remaining() => {
                     match r {
                         Ok(n) => {
+                            counter.bytes_out += n as u64;
                             self.write.advance(n);
                             if !self.write.has_remaining() {
                                 self.write.clear();
@@ -105,10 +154,15 @@ where
                         Err(e) => return Err(e),
                     }
                 }
-                r = self.out_receiver.recv() => {
+                r = self.out_receiver.recv(), if !remote_closed => {
                     if let Some(v) = r {
-                        if v.is_close() || v.is_create() {
-                            return Ok(())
+                        if v.is_half_close() && half_close {
+                            counter.close(CloseReason::Remote);
+                            remote_eof = true;
+                        } else if v.is_close() || v.is_create() {
+                            // 已收到的数据写完后再关闭
+                            counter.close(CloseReason::Remote);
+                            remote_closed = true;
                         } else if v.is_data() {
                             match v {
                                 ProtFrame::Data(d) => {
@@ -136,11 +190,40 @@ where
     }
 
     pub async fn copy_wait(self) -> Result<(), std::io::Error> {
+        self.copy_wait_with_stats().await.0
+    }
+
+    /// 同copy_wait, 并返回该流的读写统计
+    pub async fn copy_wait_with_stats(mut self) -> (Result<(), std::io::Error>, StreamStats) {
+        self.copy_wait_finish(true).await
+    }
+
+    /// 同copy_wait, 被中心端关闭且无未转发的数据时返回原始的流, 以便后续复用
+    /// 对端的半关闭视为关闭, 不关闭该流的写方向
+    pub async fn copy_wait_reuse(mut self) -> (Result<(), std::io::Error>, Option<T>) {
+        let (ret, stats) = self.copy_wait_finish(false).await;
+        if ret.is_ok()
+            && stats.close_reason == CloseReason::Remote
+            && !self.half_closed
+            && !self.read.has_remaining()
+            && !self.write.has_remaining()
+        {
+            return (ret, Some(self.stream));
+        }
+        (ret, None)
+    }
+
+    async fn copy_wait_finish(&mut self, half_close: bool) -> (Result<(), std::io::Error>, StreamStats) {
         let sender = self.in_sender.clone();
         let id = self.id;
-        let ret = self.inner_copy_wait().await;
+        let mut counter = std::mem::replace(&mut self.counter, StatsCounter::new());
+        let ret = self.inner_copy_wait(&mut counter, half_close).await;
         let _ = sender.send(ProtFrame::new_close(id)).await;
-        ret
+        if let Err(e) = &ret {
+            counter.close(CloseReason::Error(e.kind()));
+        }
+        let stats = counter.finish();
+        (ret, stats)
     }
 
     pub fn stream_read(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<usize>> {
@@ -208,3 +291,45 @@ where
         Pin::new(&mut self.stream).poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::mpsc::channel,
+    };
+
+    use super::TransStream;
+
+    #[tokio::test]
+    async fn half_close() {
+        // 客户端及上游分别经两端的TransStream转发
+        let (mut client, client_side) = tokio::io::duplex(1024);
+        let (mut backend, backend_side) = tokio::io::duplex(1024);
+        let (a_sender, a_receiver) = channel(10);
+        let (b_sender, b_receiver) = channel(10);
+        let a = tokio::spawn(TransStream::new(client_side, 1, a_sender, b_receiver).copy_wait());
+        let b = tokio::spawn(TransStream::new(backend_side, 1, b_sender, a_receiver).copy_wait());
+
+        client.write_all(b"request").await.unwrap();
+        let mut buf = [0u8; 7];
+        backend.read_exact(&mut buf).await.unwrap();
+        // 上游返回后半关闭, 客户端读取到结束而不是连接被重置
+        backend.write_all(b"response").await.unwrap();
+        backend.shutdown().await.unwrap();
+        let mut data = vec![];
+        client.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"response");
+
+        // 另一方向仍可继续传输
+        client.write_all(b"more data").await.unwrap();
+        let mut buf = [0u8; 9];
+        backend.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"more data");
+
+        client.shutdown().await.unwrap();
+        assert_eq!(backend.read(&mut buf).await.unwrap(), 0);
+        assert!(a.await.unwrap().is_ok());
+        assert!(b.await.unwrap().is_ok());
+    }
+}
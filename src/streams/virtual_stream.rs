@@ -11,17 +11,21 @@
 // Created Date: 2023/09/25 05:43:21
 
 use std::{
+    future::Future,
     pin::Pin,
     task::{ready, Poll},
 };
-use tokio_util::sync::PollSender;
+use tokio_util::sync::{PollSender, WaitForCancellationFutureOwned};
 
 use tokio::{io::{AsyncRead, AsyncWrite}, sync::mpsc::{Sender, Receiver}};
 use webparse::{BinaryMut, Buf};
 
+use crate::data::{ConnData, ConnGuard};
 use crate::prot::ProtData;
 use crate::{prot::ProtFrame};
 
+use super::stream_stats::{CloseReason, StatsCounter, StreamStats};
+
 /// 虚拟端
 /// 虚拟出一个流连接，并实现AsyncRead及AsyncRead，可以和流一样正常操作
 pub struct VirtualStream
@@ -36,19 +40,48 @@ pub struct VirtualStream
     read: BinaryMut,
     // 写的数据缓存，直接写入到stream下，从ProtFrame转化而来
     write: BinaryMut,
+    // 注册到全局的连接句柄，可通过控制端强制关闭
+    guard: ConnGuard,
+    // 等待强制关闭的信号
+    cancelled: Pin<Box<WaitForCancellationFutureOwned>>,
+    // 读写的字节数及时长统计
+    counter: StatsCounter,
 }
 
 impl VirtualStream
 {
     pub fn new(id: u64, sender: Sender<ProtFrame>, receiver: Receiver<ProtFrame>) -> Self {
+        let guard = ConnData::register("virtual", id);
+        let cancelled = Box::pin(guard.token().clone().cancelled_owned());
         Self {
             id,
             sender: PollSender::new(sender),
             receiver,
             read: BinaryMut::new(),
             write: BinaryMut::new(),
+            guard,
+            cancelled,
+            counter: StatsCounter::new(),
         }
     }
+
+    /// 设置流释放时的回调, 可获取该流的读写统计
+    pub fn set_stats_callback<F>(&mut self, callback: F)
+    where
+        F: FnOnce(StreamStats) + Send + 'static,
+    {
+        self.counter.set_callback(Box::new(callback));
+    }
+
+    /// 当前的读写统计
+    pub fn stats(&self) -> StreamStats {
+        self.counter.stats()
+    }
+
+    /// 在全局连接注册表中的id
+    pub fn conn_id(&self) -> u64 {
+        self.guard.id()
+    }
 }
 
 impl AsyncRead for VirtualStream
@@ -59,11 +92,17 @@ impl AsyncRead for VirtualStream
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
+        // 被控制端强制关闭，直接返回读取结束
+        if self.cancelled.as_mut().poll(cx).is_ready() {
+            self.counter.close(CloseReason::Cancelled);
+            return Poll::Ready(Ok(()));
+        }
         loop {
             match self.receiver.poll_recv(cx) {
                 Poll::Ready(value) => {
                     if let Some(v) = value {
                         if v.is_close() || v.is_create() {
+                            self.counter.close(CloseReason::Remote);
                             return Poll::Ready(Ok(()))
                         } else if v.is_data() {
                             match v {
@@ -74,6 +113,7 @@ impl AsyncRead for VirtualStream
                             }
                         }
                     } else {
+                        self.counter.close(CloseReason::Remote);
                         return Poll::Ready(Ok(()))
                     }
                 },
@@ -88,6 +128,7 @@ impl AsyncRead for VirtualStream
                 let copy = std::cmp::min(self.read.remaining(), buf.remaining());
                 buf.put_slice(&self.read.chunk()[..copy]);
                 self.read.advance(copy);
+                self.counter.bytes_in += copy as u64;
                 return Poll::Ready(Ok(()));
             }
         }
@@ -104,6 +145,13 @@ impl AsyncWrite for VirtualStream
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        if self.guard.is_closed() {
+            self.counter.close(CloseReason::Cancelled);
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "force closed",
+            )));
+        }
         self.write.put_slice(buf);
         if let Err(_) = ready!(self.sender.poll_reserve(cx)) {
             return Poll::Pending;
@@ -113,6 +161,7 @@ impl AsyncWrite for VirtualStream
         if let Ok(_) = self.sender.send_item(ProtFrame::Data(ProtData::new(id, data))) {
             self.write.clear();
         }
+        self.counter.bytes_out += buf.len() as u64;
         Poll::Ready(Ok(buf.len()))
     }
 
@@ -140,3 +189,9 @@ impl AsyncWrite for VirtualStream
         Poll::Ready(Ok(()))
     }
 }
+
+impl Drop for VirtualStream {
+    fn drop(&mut self) {
+        self.counter.finish();
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/07 10:02:51
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use crate::ConfigWritePressure;
+
+/// 所有隧道待写入数据的最高值
+static HIGH_WATER: AtomicU64 = AtomicU64::new(0);
+/// 持续积压触发告警的次数
+static WARN_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// 隧道写入的积压记录, 每次循环时根据待写入的数据量更新
+pub struct WritePressure {
+    config: ConfigWritePressure,
+    /// 本次积压期间的最高值
+    high_water: usize,
+    /// 开始超过告警阈值的时间
+    over_since: Option<Instant>,
+    /// 本次积压是否已告警
+    warned: bool,
+    /// 是否已暂停接收新的数据
+    paused: bool,
+}
+
+impl WritePressure {
+    pub fn new(config: ConfigWritePressure) -> Self {
+        Self {
+            config,
+            high_water: 0,
+            over_since: None,
+            warned: false,
+            paused: false,
+        }
+    }
+
+    /// 记录当前待写入的数据量, 返回是否需要暂停接收新的数据
+    pub fn update(&mut self, pending: usize) -> bool {
+        self.update_at(pending, Instant::now())
+    }
+
+    fn update_at(&mut self, pending: usize, now: Instant) -> bool {
+        self.high_water = self.high_water.max(pending);
+        HIGH_WATER.fetch_max(pending as u64, Ordering::Relaxed);
+        if pending > self.config.warn {
+            let since = *self.over_since.get_or_insert(now);
+            if !self.warned && now - since >= self.config.sustain {
+                self.warned = true;
+                WARN_COUNT.fetch_add(1, Ordering::Relaxed);
+                log::warn!(
+                    "隧道写入持续积压{:?}, 当前待写入:{}字节, 最高:{}字节, 对端可能过慢",
+                    now - since,
+                    pending,
+                    self.high_water
+                );
+            }
+        } else if let Some(since) = self.over_since.take() {
+            if self.warned {
+                log::info!(
+                    "隧道写入积压已恢复, 持续{:?}, 最高:{}字节",
+                    now - since,
+                    self.high_water
+                );
+            }
+            self.warned = false;
+            self.high_water = pending;
+        }
+
+        // 超过限制后暂停, 降到一半以下再恢复, 防止频繁切换
+        if let Some(limit) = self.config.limit {
+            if pending > limit {
+                self.paused = true;
+            } else if pending <= limit / 2 {
+                self.paused = false;
+            }
+        }
+        self.paused
+    }
+
+    /// 所有隧道待写入数据的最高值
+    pub fn high_water() -> u64 {
+        HIGH_WATER.load(Ordering::Relaxed)
+    }
+
+    /// 持续积压触发告警的次数
+    pub fn warn_count() -> u64 {
+        WARN_COUNT.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::WritePressure;
+    use crate::ConfigWritePressure;
+
+    #[test]
+    fn sustained_and_pause() {
+        let config = "warn=2k sustain=2s limit=8k"
+            .parse::<ConfigWritePressure>()
+            .unwrap();
+        assert_eq!(format!("{}", config), "warn=2k sustain=2s limit=8k");
+
+        let mut pressure = WritePressure::new(config);
+        let now = Instant::now();
+        assert!(!pressure.update_at(3000, now));
+        // 短暂的积压不告警
+        assert!(!pressure.update_at(3000, now + Duration::from_secs(1)));
+        assert!(!pressure.warned);
+        assert!(!pressure.update_at(4000, now + Duration::from_secs(2)));
+        assert!(pressure.warned);
+        assert_eq!(pressure.high_water, 4000);
+
+        // 超过限制后暂停, 降到一半以下才恢复
+        assert!(pressure.update_at(9000, now + Duration::from_secs(3)));
+        assert!(pressure.update_at(5000, now + Duration::from_secs(3)));
+        assert!(!pressure.update_at(1000, now + Duration::from_secs(4)));
+        assert!(!pressure.warned);
+        assert!(pressure.over_since.is_none());
+        assert!(WritePressure::high_water() >= 9000);
+        assert!(WritePressure::warn_count() >= 1);
+    }
+}
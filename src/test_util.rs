@@ -0,0 +1,368 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/03/25 15:40:18
+
+//! 集成测试用的辅助工具, 需开启`test-util`特性
+//!
+//! 在进程内启动wmproxy及上游服务, 监听地址可配置为`127.0.0.1:0`, 启动后通过[`TestAddrs`]获取实际的端口
+//! 单元测试中同样使用此处的上游及读取请求头等工具
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{channel, Sender},
+        Mutex,
+    },
+    task::JoinHandle,
+};
+use webparse::{BinaryMut, Request, Response};
+use wenmeng::{Body, Client, HttpTrait, ProtResult, RecvRequest, RecvResponse, Server};
+
+use crate::{
+    reverse::{HttpConfig, ServerConfig},
+    CenterState, ConfigOption, ControlServer, ProxyResult, WMCore,
+};
+
+/// 解析toml格式的配置并做加载后的检查, 与`-c`指定的配置文件一致
+pub fn load_toml(config: &str) -> std::io::Result<ConfigOption> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+    let mut option = toml::from_str::<ConfigOption>(config).map_err(|e| invalid(e.to_string()))?;
+    option.after_load_option().map_err(|e| invalid(e.to_string()))?;
+    Ok(option)
+}
+
+/// 服务启动后各监听的实际地址
+#[derive(Debug, Clone, Default)]
+pub struct TestAddrs {
+    /// 反向代理http及https的监听, 顺序与配置中的绑定顺序一致
+    pub http: Vec<SocketAddr>,
+    /// 负载均衡中tcp的监听
+    pub stream: Vec<SocketAddr>,
+    /// 代理的监听
+    pub proxy: Option<SocketAddr>,
+    /// 内网穿透中心服务的监听
+    pub center: Option<SocketAddr>,
+    pub map_http: Option<SocketAddr>,
+    pub map_https: Option<SocketAddr>,
+    pub map_tcp: Option<SocketAddr>,
+    pub map_proxy: Option<SocketAddr>,
+}
+
+impl TestAddrs {
+    fn from_core(core: &WMCore, last: &TestAddrs) -> Self {
+        let addr = |l: &Option<TcpListener>| l.as_ref().and_then(|l| l.local_addr().ok());
+        let list = |l: &Vec<TcpListener>| l.iter().filter_map(|l| l.local_addr().ok()).collect();
+        Self {
+            http: list(&core.http_listeners),
+            stream: list(&core.stream_listeners),
+            // 热加载保留内网穿透时, 相关的监听由旧服务移交
+            proxy: addr(&core.client_listener).or(last.proxy),
+            center: addr(&core.center_listener).or(last.center),
+            map_http: addr(&core.map_http_listener).or(last.map_http),
+            map_https: addr(&core.map_https_listener).or(last.map_https),
+            map_tcp: addr(&core.map_tcp_listener).or(last.map_tcp),
+            map_proxy: addr(&core.map_proxy_listener).or(last.map_proxy),
+        }
+    }
+}
+
+/// 进程内运行的wmproxy, 热加载及关闭与控制端的`/reload`及`/stop`一致
+pub struct TestProxy {
+    option: ConfigOption,
+    addrs: TestAddrs,
+    close: Option<Sender<()>>,
+    /// 当前服务退出时移交内网穿透状态的位置
+    handover: Arc<Mutex<Option<Sender<CenterState>>>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl TestProxy {
+    /// 按配置启动服务, 返回时所有的监听已绑定完成
+    pub async fn start(option: ConfigOption) -> ProxyResult<Self> {
+        let mut proxy = Self {
+            option: option.clone(),
+            addrs: TestAddrs::default(),
+            close: None,
+            handover: Arc::new(Mutex::new(None)),
+            task: None,
+        };
+        proxy.spawn(option, false).await?;
+        Ok(proxy)
+    }
+
+    async fn spawn(&mut self, option: ConfigOption, keep_center: bool) -> ProxyResult<()> {
+        let mut core = WMCore::new(option);
+        if keep_center {
+            let (center_sender, center_receiver) = channel::<CenterState>(1);
+            *self.handover.lock().await = Some(center_sender);
+            core.set_center_receiver(center_receiver);
+        }
+        // 绑定失败时返回错误, 原有的服务继续运行
+        core.ready_serve().await?;
+        self.addrs = TestAddrs::from_core(&core, &self.addrs);
+        let (close, receiver_close) = channel::<()>(1);
+        let sender_close = self.close.replace(close);
+        let handover = Arc::new(Mutex::new(None));
+        self.handover = handover.clone();
+        self.task = Some(tokio::spawn(async move {
+            // 新的服务启动后通知旧的服务关闭
+            if let Err(e) = core.run_serve(receiver_close, sender_close).await {
+                log::info!("测试服务退出:{:?}", e);
+            }
+            if let Some(center_sender) = handover.lock().await.take() {
+                let _ = center_sender.send(core.take_center()).await;
+            }
+        }));
+        Ok(())
+    }
+
+    /// 启动后各监听的实际地址
+    pub fn addrs(&self) -> &TestAddrs {
+        &self.addrs
+    }
+
+    /// 当前的配置
+    pub fn option(&self) -> &ConfigOption {
+        &self.option
+    }
+
+    /// 以新的配置热加载, 需保持原有的端口时配置中应使用[`TestProxy::addrs`]中的地址
+    pub async fn reload(&mut self, option: ConfigOption) -> ProxyResult<()> {
+        let keep_center = ControlServer::is_same_center(&self.option, &option);
+        self.spawn(option.clone(), keep_center).await?;
+        self.option = option;
+        Ok(())
+    }
+
+    /// 关闭服务, 等待停止监听后返回
+    pub async fn stop(mut self) {
+        if let Some(close) = self.close.take() {
+            let _ = close.send(()).await;
+        }
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+/// 进程内的上游服务, 释放时停止监听
+pub struct TestBackend {
+    addr: SocketAddr,
+    requests: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for TestBackend {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct BackendOperate {
+    name: String,
+    requests: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl HttpTrait for BackendOperate {
+    async fn operate(&mut self, req: &mut RecvRequest) -> ProtResult<RecvResponse> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let mut body = BinaryMut::new();
+        if req.get_body_len() > 0 || req.headers().is_chunked() {
+            let _ = req.body_mut().read_all(&mut body).await;
+        }
+        let res = Response::builder()
+            .status(200)
+            .header("X-Backend", self.name.clone())
+            .header("X-Method", req.method().as_str().to_string())
+            .header("X-Uri", req.path().clone())
+            .body(Body::new_binary(body))
+            .map_err(|_| wenmeng::ProtError::Extension("build response error"))?;
+        Ok(res)
+    }
+}
+
+impl TestBackend {
+    /// tcp的echo服务, 原样返回收到的数据
+    pub async fn echo() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = requests.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                count.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n) = stream.read(&mut buf).await {
+                        if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        Ok(Self { addr, requests, task })
+    }
+
+    /// http服务, 返回200及收到的body, 头`X-Backend`为name, `X-Method`及`X-Uri`为请求的方法及路径
+    pub async fn http(name: &str) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (count, name) = (requests.clone(), name.to_string());
+        let task = tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let operate = BackendOperate {
+                    name: name.clone(),
+                    requests: count.clone(),
+                };
+                tokio::spawn(async move {
+                    let mut server = Server::new(stream, Some(addr));
+                    server.set_callback_http(Box::new(operate));
+                    let _ = server.incoming().await;
+                });
+            }
+        });
+        Ok(Self { addr, requests, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// echo服务为收到的连接数, http服务为收到的请求数
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+}
+
+/// 等待地址可以建立连接, 超时返回false
+pub async fn wait_ready(addr: SocketAddr, timeout: Duration) -> bool {
+    let work = async {
+        while TcpStream::connect(addr).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    tokio::time::timeout(timeout, work).await.is_ok()
+}
+
+/// 以新的连接发送请求到addr, 返回响应及读取完的body
+pub async fn send_request(addr: SocketAddr, req: Request<Body>) -> ProtResult<(Response<Body>, Vec<u8>)> {
+    let stream = TcpStream::connect(addr).await?;
+    let client = Client::builder().http2(false).connect_by_stream(stream).await?;
+    let mut res = client.send_now(req).await?;
+    let mut body = BinaryMut::new();
+    // 无body时读取将一直等待
+    if res.get_body_len() > 0 || res.headers().is_chunked() {
+        let _ = res.body_mut().read_all(&mut body).await;
+    }
+    Ok((res, body.as_slice().to_vec()))
+}
+
+/// 发送GET请求, 返回状态码及body
+pub async fn http_get(addr: SocketAddr, host: &str, path: &str) -> ProtResult<(u16, String)> {
+    let req = Request::builder()
+        .method("GET")
+        .url(path.to_string())
+        .header("Host", host.to_string())
+        .body(Body::empty())
+        .map_err(|_| wenmeng::ProtError::Extension("build request error"))?;
+    let (res, body) = send_request(addr, req).await?;
+    Ok((res.status().as_u16(), String::from_utf8_lossy(&body).to_string()))
+}
+
+/// 经tcp发送数据并读取相同长度的返回, 用于验证echo的转发
+pub async fn tcp_echo(addr: SocketAddr, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(data).await?;
+    let mut buf = vec![0u8; data.len()];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// 读取到空行为止的头部, 包含结尾的空行, 连接提前关闭时panic
+pub async fn read_raw_head<T: AsyncRead + Unpin>(io: &mut T) -> String {
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut b = [0u8; 1];
+        assert_eq!(io.read(&mut b).await.unwrap(), 1, "{}", String::from_utf8_lossy(&head));
+        head.push(b[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+/// 读取头部并转为小写, 便于不区分大小写地匹配
+pub async fn read_head<T: AsyncRead + Unpin>(io: &mut T) -> String {
+    read_raw_head(io).await.to_lowercase()
+}
+
+/// 读取小写的返回头及按content-length读取body
+pub async fn read_full_response<T: AsyncRead + Unpin>(io: &mut T) -> (String, String) {
+    let head = read_head(io).await;
+    let len = head
+        .lines()
+        .find_map(|l| l.strip_prefix("content-length: "))
+        .map(|l| l.trim().parse::<usize>().unwrap())
+        .unwrap_or(0);
+    let mut body = vec![0u8; len];
+    io.read_exact(&mut body).await.unwrap();
+    (head, String::from_utf8(body).unwrap())
+}
+
+/// 解析toml格式的反向代理配置并做加载后的检查
+pub fn load_http(config: &str) -> HttpConfig {
+    let mut http = toml::from_str::<HttpConfig>(config).unwrap();
+    http.after_load_option().unwrap();
+    http
+}
+
+/// 以内存中的连接接入反向代理, 返回客户端的一端, 客户端的地址为`127.0.0.1:1234`
+pub async fn connect_http(servers: Vec<Arc<ServerConfig>>) -> DuplexStream {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    HttpConfig::process(servers, server, "127.0.0.1:1234".parse().unwrap())
+        .await
+        .unwrap();
+    client
+}
+
+/// 模拟的上游, 每个连接读取请求头后交由handle处理, 请求头保留原始的大小写
+pub async fn run_upstream<F, R>(handle: F) -> SocketAddr
+where
+    F: Fn(TcpStream, String) -> R + Send + Sync + 'static,
+    R: Future<Output = ()> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = Arc::new(handle);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                let head = read_raw_head(&mut stream).await;
+                handle(stream, head).await;
+            });
+        }
+    });
+    addr
+}
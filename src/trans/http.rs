@@ -54,6 +54,7 @@ struct HttpOper {
     pub sock_map: u64,
     pub mappings: Arc<RwLock<Vec<MappingConfig>>>,
     pub http_map: Option<MappingConfig>,
+    pub client_addr: SocketAddr,
 }
 
 impl TransHttp {
@@ -109,8 +110,9 @@ impl TransHttp {
                 oper.http_map = config;
             }
 
-            let create =
+            let mut create =
                 ProtCreate::new(oper.sock_map, Some(req.get_host().unwrap_or(String::new())));
+            create.set_client_addr(Some(oper.client_addr));
             let _ = oper.sender_work.send((create, sender.unwrap())).await;
         }
 
@@ -153,6 +155,7 @@ impl TransHttp {
             sock_map: self.sock_map,
             mappings: self.mappings.clone(),
             http_map: None,
+            client_addr: addr,
         };
         let mut server = Server::new(inbound, Some(addr));
         tokio::spawn(async move {
@@ -10,7 +10,7 @@
 // -----
 // Created Date: 2023/10/07 09:40:42
 
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use tokio::{
     io::{AsyncRead, AsyncWrite},
@@ -24,6 +24,7 @@ pub struct TransTcp {
     sender_work: Sender<(ProtCreate, Sender<ProtFrame>)>,
     sock_map: u64,
     mappings: Arc<RwLock<Vec<MappingConfig>>>,
+    client_addr: Option<SocketAddr>,
 }
 
 impl TransTcp {
@@ -38,9 +39,15 @@ impl TransTcp {
             sender_work,
             sock_map,
             mappings,
+            client_addr: None,
         }
     }
 
+    /// 原始客户端的地址, 将通过Create传给客户端
+    pub fn set_client_addr(&mut self, addr: SocketAddr) {
+        self.client_addr = Some(addr);
+    }
+
     pub async fn process<T>(self, inbound: T, mode: &str) -> Result<(), ProxyError<T>>
     where
         T: AsyncRead + AsyncWrite + Unpin,
@@ -65,7 +72,8 @@ impl TransTcp {
         };
 
         // 通知客户端数据进行连接的建立，客户端的tcp配置只能存在有且只有一个，要不然无法确定转发源
-        let create = ProtCreate::new(self.sock_map, Some(domain));
+        let mut create = ProtCreate::new(self.sock_map, Some(domain));
+        create.set_client_addr(self.client_addr);
         let (stream_sender, stream_receiver) = channel::<ProtFrame>(10);
         let _ = self.sender_work.send((create, stream_sender)).await;
         
@@ -30,12 +30,29 @@ use tokio::{
 use tokio_rustls::{rustls, TlsAcceptor};
 
 use crate::{
+    data::{CertData, ConnLimitData, HandshakeData, ShutdownStream},
     option::ConfigOption,
     proxy::ProxyServer,
     reverse::{HttpConfig, ServerConfig, StreamConfig, StreamUdp},
-    ActiveHealth, CenterClient, CenterServer, CenterTrans, Helper, OneHealth, ProxyResult,
+    ActiveHealth, CenterClient, ConfigDscp, CenterServer, CenterTrans, Helper, OneHealth, ProxyResult, SocketBuffer, TcpFastOpen,
 };
 
+/// 内网穿透的监听及已连接的会话, 热加载时若proxy的配置未变更, 由旧服务移交给新服务,
+/// 已连接的客户端无需重连
+pub struct CenterState {
+    pub center_client: Option<CenterClient>,
+    pub center_servers: Vec<CenterServer>,
+    pub proxy_accept: Option<TlsAcceptor>,
+    pub proxy_client: Option<Arc<ClientConfig>>,
+    pub client_listener: Option<TcpListener>,
+    pub center_listener: Option<TcpListener>,
+    pub map_http_listener: Option<TcpListener>,
+    pub map_https_listener: Option<TcpListener>,
+    pub map_tcp_listener: Option<TcpListener>,
+    pub map_proxy_listener: Option<TcpListener>,
+    pub map_accept: Option<TlsAcceptor>,
+}
+
 /// 核心处理类
 pub struct WMCore {
     pub option: ConfigOption,
@@ -61,6 +78,9 @@ pub struct WMCore {
     pub stream_config: Option<Arc<Mutex<StreamConfig>>>,
     pub stream_listeners: Vec<TcpListener>,
     pub stream_udp_listeners: Vec<StreamUdp>,
+
+    /// 等待旧服务移交的内网穿透状态, 存在时启动时不再重新绑定
+    center_receiver: Option<Receiver<CenterState>>,
 }
 
 impl WMCore {
@@ -89,25 +109,101 @@ impl WMCore {
             stream_config: None,
             stream_listeners: vec![],
             stream_udp_listeners: vec![],
+
+            center_receiver: None,
+        }
+    }
+
+    /// 热加载时设置, 启动后通知旧服务关闭, 并接收旧服务移交的内网穿透状态
+    pub fn set_center_receiver(&mut self, receiver: Receiver<CenterState>) {
+        self.center_receiver = Some(receiver);
+    }
+
+    /// 取出内网穿透的状态, 服务退出后移交给热加载后的服务
+    pub fn take_center(&mut self) -> CenterState {
+        self.clear_close_servers();
+        CenterState {
+            center_client: self.center_client.take(),
+            center_servers: std::mem::take(&mut self.center_servers),
+            proxy_accept: self.proxy_accept.take(),
+            proxy_client: self.proxy_client.take(),
+            client_listener: self.client_listener.take(),
+            center_listener: self.center_listener.take(),
+            map_http_listener: self.map_http_listener.take(),
+            map_https_listener: self.map_https_listener.take(),
+            map_tcp_listener: self.map_tcp_listener.take(),
+            map_proxy_listener: self.map_proxy_listener.take(),
+            map_accept: self.map_accept.take(),
         }
     }
 
+    fn restore_center(&mut self, state: CenterState) {
+        self.center_client = state.center_client;
+        if let (Some(client), Some(proxy)) = (&mut self.center_client, &self.option.proxy) {
+            client.update_mappings(proxy.mappings.clone());
+        }
+        self.center_servers = state.center_servers;
+        self.proxy_accept = state.proxy_accept;
+        self.proxy_client = state.proxy_client;
+        self.client_listener = state.client_listener;
+        self.center_listener = state.center_listener;
+        self.map_http_listener = state.map_http_listener;
+        self.map_https_listener = state.map_https_listener;
+        self.map_tcp_listener = state.map_tcp_listener;
+        self.map_proxy_listener = state.map_proxy_listener;
+        self.map_accept = state.map_accept;
+    }
+
+    async fn bind_center(&mut self) -> ProxyResult<()> {
+        let strict = self.option.strict_bind;
+        if let Some(option) = &mut self.option.proxy {
+            (
+                self.proxy_accept,
+                self.proxy_client,
+                self.client_listener,
+                self.center_listener,
+                self.center_client,
+            ) = option.bind(strict).await?;
+        }
+
+        if let Some(option) = &mut self.option.proxy {
+            (
+                self.map_http_listener,
+                self.map_https_listener,
+                self.map_tcp_listener,
+                self.map_proxy_listener,
+                self.map_accept,
+            ) = option.bind_map(strict).await?;
+        }
+        Ok(())
+    }
+
     /// 来自中心端的连接, 如果存在上级则无条件转发到上级
     /// 如果不传在上级, 则构建中心服处理该请求
     async fn deal_center_stream<T>(
         &mut self,
         inbound: T,
-        _addr: SocketAddr,
+        addr: SocketAddr,
         tls_client: Option<Arc<rustls::ClientConfig>>,
     ) -> ProxyResult<()>
     where
         T: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
     {
+        self.clear_close_servers();
+        let tunnels = self.center_servers.len();
         if let Some(option) = &mut self.option.proxy {
             if let Some(server) = option.server.clone() {
                 let mut server = CenterTrans::new(server, option.domain.clone(), tls_client);
                 return server.serve(inbound).await;
             } else {
+                // 为0时视为不限制
+                if let Some(max) = option.max_tunnels.filter(|m| *m > 0) {
+                    if tunnels >= max {
+                        log::warn!("内网穿透已连接{}个客户端, 达到上限, 拒绝来自{}的连接", tunnels, addr);
+                        CenterServer::reject(inbound, addr);
+                        return Ok(());
+                    }
+                }
                 let server = CenterServer::new(option.clone());
                 self.center_servers.push(server);
                 return self.center_servers.last_mut().unwrap().serve(inbound).await;
@@ -129,8 +225,7 @@ impl WMCore {
         if let Some(option) = &mut self.option.proxy {
             let proxy_server = ProxyServer::new(
                 option.flag,
-                option.username.clone(),
-                option.password.clone(),
+                option.build_auth(),
                 option.udp_bind.clone(),
                 None,
             );
@@ -207,26 +302,27 @@ impl WMCore {
     }
 
     pub async fn ready_serve(&mut self) -> ProxyResult<()> {
-        if let Some(option) = &mut self.option.proxy {
-            (
-                self.proxy_accept,
-                self.proxy_client,
-                self.client_listener,
-                self.center_listener,
-                self.center_client,
-            ) = option.bind().await?;
+        ConnLimitData::set_config(self.option.conn_limit.clone());
+        HandshakeData::set_config(self.option.max_concurrent_handshakes, self.option.handshake_queue_len);
+        TcpFastOpen::set_config(self.option.tcp_fastopen, self.option.tcp_fastopen_connect);
+        SocketBuffer::set_config(
+            self.option.recv_buffer_size.clone(),
+            self.option.send_buffer_size.clone(),
+        );
+        if self.center_receiver.is_none() {
+            self.bind_center().await?;
         }
 
-        if let Some(option) = &mut self.option.proxy {
-            (
-                self.map_http_listener,
-                self.map_https_listener,
-                self.map_tcp_listener,
-                self.map_proxy_listener,
-                self.map_accept,
-            ) = option.bind_map().await?;
+        let strict = self.option.strict_bind;
+        if let Some(http) = &mut self.option.http {
+            (self.http_accept, self.http_tlss, self.http_listeners) = http.bind(strict).await?;
         }
 
+        if let Some(stream) = &mut self.option.stream {
+            (self.stream_listeners, self.stream_udp_listeners) = stream.bind(strict).await?;
+        }
+
+        // 绑定后端口为0的地址已替换为实际的端口
         self.http_servers = self
             .option
             .http
@@ -237,14 +333,8 @@ impl WMCore {
         self.stream_config = Some(Arc::new(Mutex::new(
             self.option.stream.clone().unwrap_or(StreamConfig::new()),
         )));
-
-        if let Some(http) = &mut self.option.http {
-            (self.http_accept, self.http_tlss, self.http_listeners) = http.bind().await?;
-        }
-
-        if let Some(stream) = &mut self.option.stream {
-            (self.stream_listeners, self.stream_udp_listeners) = stream.bind().await?;
-        }
+        CertData::set_config(self.option.cert_expiry_warn.as_ref().map(|d| d.0));
+        CertData::spawn_check();
         Ok(())
     }
 
@@ -256,29 +346,40 @@ impl WMCore {
         if let Some(sender) = sender_close.take() {
             let _ = sender.send(()).await;
         }
+        if let Some(mut receiver) = self.center_receiver.take() {
+            match receiver.recv().await {
+                Some(state) => {
+                    log::info!("热加载: 保留内网穿透的{}个已连接的会话", state.center_servers.len());
+                    self.restore_center(state);
+                }
+                // 旧服务未能移交, 重新绑定
+                None => self.bind_center().await?,
+            }
+        }
         self.do_start_health_check().await?;
         loop {
             tokio::select! {
                 Some((inbound, addr)) = Self::tcp_listen_work(&self.center_listener) => {
                     log::trace!("中心代理收到客户端连接: {}->{}", addr, self.center_listener.as_ref().unwrap().local_addr()?);
+                    ConfigDscp::apply_option(&self.option.proxy.as_ref().and_then(|p| p.dscp), &inbound);
                     if let Some(a) = self.proxy_accept.clone() {
                         let inbound = a.accept(inbound).await;
                         // 获取的流跟正常内容一样读写, 在内部实现了自动加解密
                         match inbound {
                             Ok(inbound) => {
-                                let _ = self.deal_center_stream(inbound, addr, self.proxy_client.clone()).await;
+                                let _ = self.deal_center_stream(ShutdownStream::new(inbound), addr, self.proxy_client.clone()).await;
                             }
                             Err(e) => {
                                 log::warn!("接收来自下级代理的连接失败, 原因为: {:?}", e);
                             }
                         }
                     } else {
-                        let _ = self.deal_center_stream(inbound, addr, self.proxy_client.clone()).await;
+                        let _ = self.deal_center_stream(ShutdownStream::new(inbound), addr, self.proxy_client.clone()).await;
                     };
                 }
                 Some((inbound, addr)) = Self::tcp_listen_work(&self.client_listener) => {
                     log::trace!("代理收到客户端连接: {}->{}", addr, self.client_listener.as_ref().unwrap().local_addr()?);
-                    let _ = self.deal_client_stream(inbound, addr).await;
+                    let _ = self.deal_client_stream(ShutdownStream::new(inbound), addr).await;
                 }
                 Some((inbound, addr)) = Self::tcp_listen_work(&self.map_http_listener) => {
                     log::trace!("内网穿透:Http收到客户端连接: {}->{}", addr, self.map_http_listener.as_ref().unwrap().local_addr()?);
@@ -307,23 +408,13 @@ impl WMCore {
                             }
                             local_servers.push(s.clone());
                         }
+                        ConfigDscp::apply_option(&local_servers.iter().find_map(|s| s.dscp), &conn);
+                        let conn = ShutdownStream::new(conn);
                         if self.http_tlss[index] {
                             let tls_accept = self.http_accept.clone().unwrap();
-                            tokio::spawn(async move {
-                                if let Ok(stream) = tls_accept.accept(conn).await {
-                                    let data = stream.get_ref();
-                                    let up_name = data.1.server_name().clone().map(|s| s.to_string());
-                                    for s in &local_servers {
-                                        if up_name.is_some() && &s.up_name == up_name.as_ref().unwrap() {
-                                            let _ = HttpConfig::process(vec![s.clone()], stream, addr).await;
-                                            return;
-                                        }
-                                    }
-                                    let _ = HttpConfig::process(local_servers, stream, addr).await;
-                                }
-                            });
+                            tokio::spawn(HttpConfig::accept_tls(local_servers, conn, addr, tls_accept));
                         } else {
-                            let _ = HttpConfig::process(local_servers, conn, addr).await;
+                            tokio::spawn(HttpConfig::accept_http(local_servers, conn, addr));
                         }
                     }
                 }
@@ -332,6 +423,11 @@ impl WMCore {
                         log::trace!("反向代理:{}收到客户端连接: {}->{}", "stream", addr, self.stream_listeners[index].local_addr()?);
                         let data = self.stream_config.clone();
                         let local_addr = self.stream_listeners[index].local_addr()?;
+                        if let Some(stream) = &self.option.stream {
+                            let dscp = stream.server.iter().find(|s| s.bind_addr.contains(local_addr.port())).and_then(|s| s.dscp);
+                            ConfigDscp::apply_option(&dscp, &conn);
+                        }
+                        let conn = ShutdownStream::new(conn);
                         tokio::spawn(async move {
                             let _ = StreamConfig::process(data.unwrap(), local_addr, conn, addr).await;
                         });
@@ -410,12 +506,12 @@ impl WMCore {
     pub async fn server_new_tcp(
         &mut self,
         stream: TcpStream,
-        _addr: SocketAddr,
+        addr: SocketAddr,
     ) -> ProxyResult<()> {
         self.clear_close_servers();
         for server in &mut self.center_servers {
             if !server.is_close() {
-                return server.server_new_tcp(stream).await;
+                return server.server_new_tcp(stream, addr).await;
             }
         }
         log::warn!("未发现任何tcp服务器，但收到tcp的内网穿透，请检查配置");
@@ -425,15 +521,60 @@ impl WMCore {
     pub async fn server_new_proxy(
         &mut self,
         stream: TcpStream,
-        _addr: SocketAddr,
+        addr: SocketAddr,
     ) -> ProxyResult<()> {
         self.clear_close_servers();
         for server in &mut self.center_servers {
             if !server.is_close() {
-                return server.server_new_prxoy(stream).await;
+                return server.server_new_prxoy(stream, addr).await;
             }
         }
         log::warn!("未发现任何tcp服务器，但收到tcp的内网穿透，请检查配置");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::WMCore;
+    use crate::ConfigOption;
+
+    fn build_core(strict: bool, busy: std::net::SocketAddr) -> WMCore {
+        let config = format!(
+            r#"
+            disable_control = true
+            strict_bind = {}
+            [http]
+            [[http.server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[http.server]]
+            bind_addr = "{}"
+            bind_ssl = ""
+            "#,
+            strict, busy
+        );
+        let mut option = toml::from_str::<ConfigOption>(&config).unwrap();
+        option.http.as_mut().unwrap().after_load_option().unwrap();
+        WMCore::new(option)
+    }
+
+    #[tokio::test]
+    async fn strict_bind() {
+        // 未开启端口复用的监听占用该端口
+        let busy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = busy.local_addr().unwrap();
+
+        // 默认任一监听失败即启动失败, 错误中包含该地址
+        assert!(toml::from_str::<ConfigOption>("").unwrap().strict_bind);
+        let mut core = build_core(true, addr);
+        let err = core.ready_serve().await.unwrap_err();
+        assert!(format!("{:?}", err).contains(&addr.to_string()), "{:?}", err);
+
+        // 关闭后跳过失败的监听, 以成功绑定的监听继续运行
+        let mut core = build_core(false, addr);
+        core.ready_serve().await.unwrap();
+        assert_eq!(core.http_listeners.len(), 1);
+        assert_ne!(core.http_listeners[0].local_addr().unwrap(), addr);
+    }
+}
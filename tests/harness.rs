@@ -0,0 +1,75 @@
+#![deny(rust_2018_idioms)]
+
+/// 进程内启动wmproxy的测试工具
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use wmproxy::test_util::{http_get, load_toml, tcp_echo, wait_ready, TestBackend, TestProxy};
+    use wmproxy::ConfigOption;
+
+    fn reverse_option(bind: &str, backend: SocketAddr) -> ConfigOption {
+        load_toml(&format!(
+            r#"
+            [http]
+            [[http.server]]
+            bind_addr = "{}"
+            bind_ssl = ""
+            up_name = "localhost"
+            [[http.server.location]]
+            rule = "/"
+            proxy_url = "http://{}"
+            "#,
+            bind, backend
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn start_reload_stop() {
+        let (a, b) = (TestBackend::http("a").await.unwrap(), TestBackend::http("b").await.unwrap());
+        let mut proxy = TestProxy::start(reverse_option("127.0.0.1:0", a.addr()))
+            .await
+            .unwrap();
+        let addr = proxy.addrs().http[0];
+        assert_ne!(addr.port(), 0);
+        assert!(wait_ready(addr, Duration::from_secs(2)).await);
+        assert_eq!(http_get(addr, "localhost", "/").await.unwrap().0, 200);
+        assert_eq!(a.requests(), 1);
+
+        // 沿用实际的端口热加载到新的上游
+        let option = reverse_option(&addr.to_string(), b.addr());
+        proxy.reload(option).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(proxy.addrs().http, vec![addr]);
+        assert_eq!(http_get(addr, "localhost", "/").await.unwrap().0, 200);
+        assert_eq!((a.requests(), b.requests()), (1, 1));
+
+        proxy.stop().await;
+        assert!(http_get(addr, "localhost", "/").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stream_echo() {
+        let echo = TestBackend::echo().await.unwrap();
+        let option = load_toml(&format!(
+            r#"
+            [stream]
+            [[stream.upstream]]
+            name = "echo"
+            server = [{{ addr = "{}" }}]
+            [[stream.server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "echo"
+            "#,
+            echo.addr()
+        ))
+        .unwrap();
+        let proxy = TestProxy::start(option).await.unwrap();
+        let addr = proxy.addrs().stream[0];
+        assert_eq!(tcp_echo(addr, b"hello").await.unwrap(), b"hello");
+        assert_eq!(echo.requests(), 1);
+        proxy.stop().await;
+    }
+}
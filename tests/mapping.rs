@@ -9,13 +9,18 @@ mod tests {
         error::Error,
         io::{self},
         net::SocketAddr,
+        time::Duration,
     };
     use tokio::{
+        io::AsyncReadExt,
         net::{TcpListener, TcpStream},
         sync::mpsc::{channel, Sender},
     };
     use webparse::{BinaryMut, Buf, Request, Response, Version};
-    use wmproxy::{ConfigHeader, ConfigOption, MappingConfig, ProxyConfig, WMCore};
+    use wmproxy::{
+        CloseCode, ConfigHeader, ConfigOption, MappingConfig, ProtFrame, ProtFrameHeader,
+        ProxyConfig, WMCore,
+    };
 
     use wenmeng::{
         self, Body, Client, HttpTrait, ProtResult, RecvRequest, RecvResponse, Server,
@@ -270,4 +275,57 @@ mod tests {
             assert_eq!(res.version(), Version::Http2);
         }
     }
+
+    /// 读取首个帧, 连接在发送前关闭时返回None
+    async fn read_frame(stream: &mut TcpStream) -> Option<ProtFrame> {
+        let mut buf = BinaryMut::new();
+        let mut vec = vec![0u8; 1024];
+        loop {
+            let n = stream.read(&mut vec).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            buf.put_slice(&vec[..n]);
+            let mut copy = buf.clone();
+            if let Ok(header) = ProtFrameHeader::parse(&mut copy) {
+                return ProtFrame::parse(header, copy).ok();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn max_tunnels() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let proxy = ProxyConfig::builder()
+            .center_addr(addr)
+            .max_tunnels(Some(2))
+            .into_value()
+            .unwrap();
+        let (server_addr, _, _, _, _, _sender) = run_mapping_server(proxy).await.unwrap();
+
+        let mut first = TcpStream::connect(server_addr).await.unwrap();
+        assert!(read_frame(&mut first).await.unwrap().is_mapping());
+        let mut second = TcpStream::connect(server_addr).await.unwrap();
+        assert!(read_frame(&mut second).await.unwrap().is_mapping());
+
+        // 第三个连接超出上限, 收到关闭帧后被断开
+        let mut third = TcpStream::connect(server_addr).await.unwrap();
+        match read_frame(&mut third).await.unwrap() {
+            ProtFrame::Close(close) => assert_eq!(close.code(), CloseCode::QuotaExceeded),
+            _ => unreachable!(),
+        }
+        let mut vec = vec![0u8; 16];
+        assert_eq!(third.read(&mut vec).await.unwrap(), 0);
+
+        // 已连接的客户端不受影响
+        assert!(tokio::time::timeout(Duration::from_millis(100), second.read(&mut vec))
+            .await
+            .is_err());
+
+        // 断开一个后空出位置
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut fourth = TcpStream::connect(server_addr).await.unwrap();
+        assert!(read_frame(&mut fourth).await.unwrap().is_mapping());
+    }
 }
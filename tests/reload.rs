@@ -0,0 +1,119 @@
+#![deny(rust_2018_idioms)]
+
+/// 热加载时保留内网穿透的会话
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc::{channel, Receiver, Sender},
+    };
+    use wmproxy::{CenterState, ConfigOption, MappingConfig, ProxyConfig, WMCore};
+
+    /// 本地的echo服务, 内网穿透的目标
+    async fn run_echo() -> SocketAddr {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = server.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n) = stream.read(&mut buf).await {
+                        if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// 启动服务, 服务退出后将内网穿透的状态移交出去
+    fn spawn_core(
+        mut proxy: WMCore,
+        sender_close: Option<Sender<()>>,
+    ) -> (Sender<()>, Receiver<CenterState>) {
+        let (close, receiver_close) = channel::<()>(1);
+        let (handover, receiver) = channel::<CenterState>(1);
+        tokio::spawn(async move {
+            let _ = proxy.run_serve(receiver_close, sender_close).await;
+            let _ = handover.send(proxy.take_center()).await;
+        });
+        (close, receiver)
+    }
+
+    async fn echo(stream: &mut TcpStream, data: &[u8]) -> bool {
+        let mut buf = vec![0u8; data.len()];
+        stream.write_all(data).await.is_ok()
+            && matches!(
+                tokio::time::timeout(Duration::from_secs(2), stream.read_exact(&mut buf)).await,
+                Ok(Ok(_))
+            )
+            && buf == data
+    }
+
+    async fn connect_echo(addr: SocketAddr) -> TcpStream {
+        for _ in 0..50 {
+            if let Ok(mut stream) = TcpStream::connect(addr).await {
+                if echo(&mut stream, b"ping").await {
+                    return stream;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("内网穿透未能连通");
+    }
+
+    #[tokio::test]
+    async fn keep_tunnel() {
+        let echo_addr = run_echo().await;
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let proxy = ProxyConfig::builder()
+            .center_addr(addr)
+            .map_tcp_bind(Some(addr))
+            .into_value()
+            .unwrap();
+        let option = ConfigOption::new_by_proxy(proxy);
+
+        let mut center = WMCore::new(option.clone());
+        center.ready_serve().await.unwrap();
+        let center_addr = center.center_listener.as_ref().unwrap().local_addr().unwrap();
+        let tcp_addr = center.map_tcp_listener.as_ref().unwrap().local_addr().unwrap();
+        let (center_close, center_state) = spawn_core(center, None);
+
+        let mut mapping = MappingConfig::new(
+            "tcp".to_string(),
+            "tcp".to_string(),
+            "soft.wm-proxy.com".to_string(),
+            vec![],
+        );
+        mapping.local_addr = Some(echo_addr);
+        let client = ProxyConfig::builder()
+            .bind(addr)
+            .server(Some(format!("{}", center_addr)))
+            .mapping(mapping)
+            .into_value()
+            .unwrap();
+        let mut client = WMCore::new(ConfigOption::new_by_proxy(client));
+        client.ready_serve().await.unwrap();
+        let _client_close = spawn_core(client, None);
+
+        let mut stream = connect_echo(tcp_addr).await;
+
+        // 配置未变更的热加载, 新服务沿用旧服务的监听及会话
+        let mut reload = WMCore::new(option);
+        reload.set_center_receiver(center_state);
+        reload.ready_serve().await.unwrap();
+        assert!(reload.center_listener.is_none());
+        let _reload_close = spawn_core(reload, Some(center_close));
+
+        // 热加载前建立的连接仍可用, 新的连接也可通过原会话转发
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(echo(&mut stream, b"after reload").await);
+        let mut stream = TcpStream::connect(tcp_addr).await.unwrap();
+        assert!(echo(&mut stream, b"new stream").await);
+    }
+}